@@ -0,0 +1,114 @@
+use anyhow::{Context, Result};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+///Data types as `/schema` reports them
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub enum DataType {
+    Int,
+    Float,
+    String,
+    Boolean,
+    Timestamp,
+    Uuid,
+    Array(Box<DataType>),
+    Json,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ColumnSchema {
+    pub name: String,
+    pub data_type: DataType,
+    pub nullable: bool,
+    ///Filled by the database, like `id` or `timestamp`
+    pub system: bool,
+}
+
+///Response of `GET /schema`, reduced to what generating rows needs
+#[derive(Deserialize, Debug, Clone)]
+pub struct TableSchema {
+    pub columns: Vec<ColumnSchema>,
+}
+
+///Body of an insert
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct IndexParams {
+    pub fields: Vec<String>,
+    pub values: Vec<Value>,
+}
+
+///Fetches the table's schema from the server at base_url, e.g. `http://localhost:3030`
+pub fn fetch_schema(base_url: &str) -> Result<TableSchema> {
+    reqwest::blocking::get(format!("{}/schema", base_url))?
+        .error_for_status()?
+        .json()
+        .with_context(|| format!("{}/schema does not have the correct format", base_url))
+}
+
+///Insert with a random value for every column the client provides. System columns are left out,
+///nullable columns are null every now and then.
+pub fn generate_row<R: Rng>(schema: &TableSchema, rng: &mut R) -> IndexParams {
+    let mut fields = vec![];
+    let mut values = vec![];
+    for column in schema.columns.iter().filter(|column| !column.system) {
+        fields.push(column.name.clone());
+        values.push(match column.nullable && rng.gen_ratio(1, 4) {
+            true => Value::Null,
+            false => generate_value(&column.data_type, rng),
+        });
+    }
+    IndexParams { fields, values }
+}
+
+///Random value the server accepts for a column of the data type
+pub fn generate_value<R: Rng>(data_type: &DataType, rng: &mut R) -> Value {
+    match data_type {
+        DataType::Int => json!(rng.gen_range(-1_000_000i64..1_000_000)),
+        //Whole numbers would be serialized without a fraction and count as Int
+        DataType::Float => json!(rng.gen_range(-1_000_000..1_000_000) as f64 + 0.5),
+        DataType::String => {
+            let len = rng.gen_range(1..24);
+            json!((0..len).map(|_| rng.sample(rand::distributions::Alphanumeric) as char).collect::<String>())
+        }
+        DataType::Boolean => json!(rng.gen::<bool>()),
+        DataType::Timestamp => json!(rng.gen_range(0i64..4_102_444_800)),
+        DataType::Uuid => {
+            let hex = format!("{:032x}", rng.gen::<u128>());
+            json!(format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..]))
+        }
+        DataType::Array(inner) => Value::Array((0..rng.gen_range(0..4)).map(|_| generate_value(inner, rng)).collect()),
+        DataType::Json => json!({ "value": rng.gen_range(0i64..1000) }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+    use serde_json::Value;
+
+    use super::{generate_row, TableSchema};
+
+    #[test]
+    fn generated_rows_match_the_schema() {
+        let schema: TableSchema = serde_json::from_value(serde_json::json!({
+            "description": "Page views",
+            "columns": [
+                {"name": "id", "data_type": "Int", "nullable": false, "system": true, "inferred": false},
+                {"name": "url", "data_type": "String", "nullable": false, "system": false, "inferred": false},
+                {"name": "score", "data_type": "Float", "nullable": false, "system": false, "inferred": false},
+                {"name": "tags", "data_type": {"Array": "Uuid"}, "nullable": true, "system": false, "inferred": false}
+            ]
+        }))
+        .unwrap();
+        let mut rng = StdRng::seed_from_u64(7);
+        for _ in 0..100 {
+            let row = generate_row(&schema, &mut rng);
+            assert_eq!(row.fields, vec!["url", "score", "tags"]);
+            assert!(row.values[0].as_str().is_some_and(|url| !url.is_empty()));
+            assert!(row.values[1].is_f64());
+            assert!(row.values[2].is_null() || row.values[2].as_array().unwrap().iter().all(|uuid| uuid.as_str().unwrap().len() == 36));
+            assert!(!row.values.iter().take(2).any(Value::is_null));
+        }
+    }
+}
@@ -0,0 +1,2 @@
+///Random rows matching a table's schema, for integration tests which shouldn't break when the schema changes
+pub mod fixtures;
@@ -3,6 +3,7 @@ use std::fs;
 use anyhow::{Context, Result};
 use clap::Parser;
 use kafka::consumer::{Consumer, FetchOffset};
+use kafka_client::fixtures;
 use serde::Deserialize;
 
 #[derive(Debug, Parser)]
@@ -10,11 +11,25 @@ struct Cli {
     ///Defaults to localhost:9092
     #[arg(short, long)]
     kafka_broker: Option<String>,
-    #[arg(short = 't', long)]
-    kafka_topic: String,
+    #[arg(short = 't', long, required_unless_present = "generate_rows")]
+    kafka_topic: Option<String>,
     ///Path to Mapping File, e.g. mappings.json
-    #[arg(short, long)]
-    mapping_file_path: String,
+    #[arg(short, long, required_unless_present = "generate_rows")]
+    mapping_file_path: Option<String>,
+    ///Instead of consuming Kafka, print this many random inserts matching the server's schema, one per line
+    #[arg(long)]
+    generate_rows: Option<usize>,
+    ///Sent as x-api-key, so warenhaus attributes the inserted rows to it
+    #[arg(long)]
+    api_key: Option<String>,
+}
+
+///Where a Kafka message came from. warenhaus stores it with the row if the schema enables lineage.
+struct MessageOrigin<'a> {
+    topic: &'a str,
+    partition: i32,
+    offset: i64,
+    api_key: Option<&'a str>,
 }
 
 #[derive(Deserialize)]
@@ -33,12 +48,12 @@ fn load_mapping_file(mapping_file: &str) -> Result<Vec<Mapping>> {
     Ok(json)
 }
 
-fn insert_record(fields: Vec<String>, values: Vec<serde_json::Value>) -> Result<()> {
+fn insert_record(fields: Vec<String>, values: Vec<serde_json::Value>, origin: &MessageOrigin) -> Result<()> {
     let mut payload = serde_json::Map::new();
 
     let fields = fields
         .into_iter()
-        .map(|f| serde_json::Value::String(f))
+        .map(serde_json::Value::String)
         .collect();
 
     payload.insert("fields".to_string(), serde_json::Value::Array(fields));
@@ -46,14 +61,20 @@ fn insert_record(fields: Vec<String>, values: Vec<serde_json::Value>) -> Result<
     let payload = serde_json::Value::Object(payload);
 
     let client = reqwest::blocking::Client::new();
-    let _request = client
+    let mut request = client
         .post("http://localhost:3030/index")
-        .body(payload.to_string())
-        .send()?;
+        .header("x-warenhaus-source", "kafka")
+        .header("x-warenhaus-kafka-topic", origin.topic)
+        .header("x-warenhaus-kafka-partition", origin.partition.to_string())
+        .header("x-warenhaus-kafka-offset", origin.offset.to_string());
+    if let Some(api_key) = origin.api_key {
+        request = request.header("x-api-key", api_key);
+    }
+    let _request = request.body(payload.to_string()).send()?;
     Ok(())
 }
 
-fn map_value(json_str: &str, config: &Vec<Mapping>) -> Result<()> {
+fn map_value(json_str: &str, config: &Vec<Mapping>, origin: &MessageOrigin) -> Result<()> {
     let kafka_payload: serde_json::Value =
         serde_json::from_str(json_str)
         .with_context(|| format!("Failed to deserialize Kafka payload: {}", json_str))?;
@@ -72,7 +93,7 @@ fn map_value(json_str: &str, config: &Vec<Mapping>) -> Result<()> {
 
     if fields.len() == config.len() && values.len() == config.len() {
         println!("Validated mapping. Ready to insert");
-        match insert_record(fields, values.to_owned()) {
+        match insert_record(fields, values.to_owned(), origin) {
             Ok(()) => {}
             Err(err) => {
                 eprintln!("Failed to insert data: {}", err);
@@ -83,12 +104,18 @@ fn map_value(json_str: &str, config: &Vec<Mapping>) -> Result<()> {
     Ok(())
 }
 
-fn consume(consumer: &mut Consumer, configuration: Vec<Mapping>) {
+fn consume(consumer: &mut Consumer, configuration: Vec<Mapping>, api_key: Option<String>) {
     loop {
         for ms in consumer.poll().unwrap().iter() {
             for m in ms.messages() {
                 let str = String::from_utf8_lossy(m.value);
-                if let Err(err) = map_value(&str.to_string(), &configuration) {
+                let origin = MessageOrigin {
+                    topic: ms.topic(),
+                    partition: ms.partition(),
+                    offset: m.offset,
+                    api_key: api_key.as_deref(),
+                };
+                if let Err(err) = map_value(&str, &configuration, &origin) {
                     eprintln!("ERR: {}", err);
                 }
             }
@@ -98,16 +125,28 @@ fn consume(consumer: &mut Consumer, configuration: Vec<Mapping>) {
     }
 }
 
+fn generate_rows(count: usize) -> Result<()> {
+    let schema = fixtures::fetch_schema("http://localhost:3030")?;
+    let mut rng = rand::thread_rng();
+    for _ in 0..count {
+        println!("{}", serde_json::to_string(&fixtures::generate_row(&schema, &mut rng))?);
+    }
+    Ok(())
+}
+
 fn main() -> Result<()> {
     let cli_args = Cli::parse();
-    let mapping_configuration = load_mapping_file(&cli_args.mapping_file_path)?;
+    if let Some(count) = cli_args.generate_rows {
+        return generate_rows(count);
+    }
+    let mapping_configuration = load_mapping_file(&cli_args.mapping_file_path.unwrap_or_default())?;
     let mut consumer = Consumer::from_hosts(vec![cli_args
         .kafka_broker
         .unwrap_or("localhost:9092".to_owned())])
-    .with_topic(cli_args.kafka_topic)
+    .with_topic(cli_args.kafka_topic.unwrap_or_default())
     .with_fallback_offset(FetchOffset::Earliest)
     .create()
     .unwrap();
-    consume(&mut consumer, mapping_configuration);
+    consume(&mut consumer, mapping_configuration, cli_args.api_key);
     Ok(())
 }
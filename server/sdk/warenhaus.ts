@@ -0,0 +1,93 @@
+// warenhaus-abi: 1
+//
+// AssemblyScript helpers for warenhaus map functions and before insert hooks.
+// Import them with `import { Row } from "./warenhaus";`, the server provides this file when compiling.
+
+@external("env", "has_field")
+declare function has_field(field: string): bool;
+@external("env", "is_null")
+declare function is_null(field: string): bool;
+@external("env", "get_bool")
+declare function get_bool(field: string): bool;
+@external("env", "get_int")
+declare function get_int(field: string): i64;
+@external("env", "get_float")
+declare function get_float(field: string): f64;
+@external("env", "get_string")
+declare function get_string(field: string): string;
+@external("env", "set_bool")
+declare function set_bool(field: string, value: bool): void;
+@external("env", "set_int")
+declare function set_int(field: string, value: i64): void;
+@external("env", "set_float")
+declare function set_float(field: string, value: f64): void;
+@external("env", "set_string")
+declare function set_string(field: string, value: string): void;
+@external("env", "set_null")
+declare function set_null(field: string): void;
+@external("env", "reject")
+declare function reject(reason: string): void;
+
+// Version of the host functions this file wraps. Uploads naming another version get rejected.
+export const ABI_VERSION: i32 = 1;
+
+// The row a map function runs for. Getters return false, 0 or "" for null, unknown columns
+// and columns of another type, `has` and `isNull` tell these apart.
+export class Row {
+  // True if the row holds a value other than null in the column
+  has(field: string): bool {
+    return has_field(field);
+  }
+
+  // True if the row holds null in the column
+  isNull(field: string): bool {
+    return is_null(field);
+  }
+
+  getBool(field: string): bool {
+    return get_bool(field);
+  }
+
+  // Int and Timestamp columns
+  getInt(field: string): i64 {
+    return get_int(field);
+  }
+
+  // Float columns, Int values get converted
+  getFloat(field: string): f64 {
+    return get_float(field);
+  }
+
+  // String columns, and Uuid columns in their hyphenated form
+  getString(field: string): string {
+    return get_string(field);
+  }
+}
+
+// The row a before insert hook runs for. Only available in hooks.
+export class Insert extends Row {
+  setBool(field: string, value: bool): void {
+    set_bool(field, value);
+  }
+
+  setInt(field: string, value: i64): void {
+    set_int(field, value);
+  }
+
+  setFloat(field: string, value: f64): void {
+    set_float(field, value);
+  }
+
+  setString(field: string, value: string): void {
+    set_string(field, value);
+  }
+
+  setNull(field: string): void {
+    set_null(field);
+  }
+
+  // Rejects the insert, answering the client with the reason
+  reject(reason: string): void {
+    reject(reason);
+  }
+}
@@ -0,0 +1,423 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::storage::cell::Cell;
+use crate::storage::column_frame::ColumnFrame;
+use crate::storage::expression::Expression;
+use crate::storage::filter::QueryFilter;
+
+///Notifications which take longer than this count as failed and get sent again on the next evaluation
+const NOTIFY_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Error)]
+pub enum AlertError {
+    #[error("Unknown alert {0}")]
+    UnknownAlert(String),
+    #[error("Invalid alert rule: {0}")]
+    InvalidRule(String),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Aggregate {
+    ///Rows matching the filter
+    Count,
+    Sum,
+    Avg,
+    Min,
+    Max,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Condition {
+    ///Fires while the value is greater than the threshold
+    #[default]
+    Above,
+    ///Fires while the value is less than the threshold
+    Below,
+}
+
+///Where notifications of an alert go
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum AlertTarget {
+    ///Gets every `AlertNotification` POSTed as JSON
+    Webhook { url: String },
+    ///Slack incoming webhook, gets a message per notification
+    Slack { webhook_url: String },
+}
+
+impl AlertTarget {
+    fn url(&self) -> &str {
+        match self {
+            AlertTarget::Webhook { url } => url,
+            AlertTarget::Slack { webhook_url } => webhook_url,
+        }
+    }
+}
+
+///Aggregate over the rows of the last `window_secs` seconds, compared to a threshold every `interval_secs` seconds
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AlertRule {
+    ///Only rows for which the expression is true, in the syntax of the `where` query parameter
+    #[serde(rename = "where", default, skip_serializing_if = "Option::is_none")]
+    pub filter: Option<String>,
+    pub aggregate: Aggregate,
+    ///Numeric column the aggregate reads. Not needed for count.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub column: Option<String>,
+    #[serde(default)]
+    pub condition: Condition,
+    pub threshold: f64,
+    pub interval_secs: u64,
+    ///Rows with a timestamp this many seconds before the evaluation or later. Defaults to interval_secs.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub window_secs: Option<u64>,
+    pub target: AlertTarget,
+}
+
+impl AlertRule {
+    pub fn validate(&self) -> Result<(), AlertError> {
+        let invalid = |reason: String| Err(AlertError::InvalidRule(reason));
+        if self.interval_secs == 0 {
+            return invalid("interval_secs must be greater than 0".to_string());
+        }
+        if self.window_secs == Some(0) {
+            return invalid("window_secs must be greater than 0".to_string());
+        }
+        if self.aggregate != Aggregate::Count && self.column.is_none() {
+            return invalid(format!("{:?} requires a column", self.aggregate).to_lowercase());
+        }
+        if let Some(filter) = &self.filter {
+            Expression::parse(filter).map_err(|err| AlertError::InvalidRule(err.to_string()))?;
+        }
+        match Url::parse(self.target.url()) {
+            Ok(url) if url.scheme() == "http" || url.scheme() == "https" => Ok(()),
+            _ => invalid(format!("{} is not an http(s) URL", self.target.url())),
+        }
+    }
+
+    ///Rows an evaluation at `now` aggregates
+    pub fn query_filter(&self, now: u64) -> Result<QueryFilter, AlertError> {
+        let window = self.window_secs.unwrap_or(self.interval_secs);
+        Ok(QueryFilter {
+            from: Some(now.saturating_sub(window) as i64),
+            expression: self
+                .filter
+                .as_deref()
+                .map(Expression::parse)
+                .transpose()
+                .map_err(|err| AlertError::InvalidRule(err.to_string()))?,
+            ..Default::default()
+        })
+    }
+
+    ///Value of the aggregate over the rows. None if no row holds a number in the column, except for count and sum.
+    pub fn aggregate(&self, rows: &[ColumnFrame]) -> Option<f64> {
+        let column = self.column.as_deref().unwrap_or_default();
+        let values = rows.iter().filter_map(|row| match row.get(column) {
+            Some(Cell::Int(value)) => Some(*value as f64),
+            Some(Cell::Float(value)) => Some(*value),
+            _ => None,
+        });
+        match self.aggregate {
+            Aggregate::Count => Some(rows.len() as f64),
+            Aggregate::Sum => Some(values.sum()),
+            Aggregate::Avg => {
+                let (sum, count) = values.fold((0.0, 0), |(sum, count), value| (sum + value, count + 1));
+                (count > 0).then(|| sum / count as f64)
+            }
+            Aggregate::Min => values.reduce(f64::min),
+            Aggregate::Max => values.reduce(f64::max),
+        }
+    }
+
+    pub fn is_breached(&self, value: f64) -> bool {
+        match self.condition {
+            Condition::Above => value > self.threshold,
+            Condition::Below => value < self.threshold,
+        }
+    }
+}
+
+///Outcome of the latest evaluation
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct AlertState {
+    ///Whether the last notification sent was a firing one
+    pub firing: bool,
+    pub value: Option<f64>,
+    ///Unix timestamp of the latest evaluation
+    pub evaluated_at: Option<u64>,
+    ///Why the latest evaluation or notification failed
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AlertStatus {
+    Firing,
+    Resolved,
+}
+
+///Sent to the target whenever an alert starts or stops firing
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AlertNotification {
+    pub alert: String,
+    pub status: AlertStatus,
+    pub value: Option<f64>,
+    pub condition: Condition,
+    pub threshold: f64,
+    pub evaluated_at: u64,
+}
+
+impl AlertNotification {
+    fn slack_text(&self) -> String {
+        let value = self.value.map(|value| value.to_string()).unwrap_or_else(|| "no value".to_string());
+        let comparison = match self.condition {
+            Condition::Above => "above",
+            Condition::Below => "below",
+        };
+        match self.status {
+            AlertStatus::Firing => format!(
+                ":rotating_light: Alert {} is firing: {} is {} the threshold of {}",
+                self.alert, value, comparison, self.threshold
+            ),
+            AlertStatus::Resolved => format!(":white_check_mark: Alert {} resolved: {}", self.alert, value),
+        }
+    }
+}
+
+///POSTs the notification to the target
+pub async fn notify(target: &AlertTarget, notification: &AlertNotification) -> Result<(), reqwest::Error> {
+    let body = match target {
+        AlertTarget::Webhook { .. } => serde_json::to_string(notification).unwrap(),
+        AlertTarget::Slack { .. } => serde_json::json!({ "text": notification.slack_text() }).to_string(),
+    };
+    reqwest::Client::new()
+        .post(target.url())
+        .header("Content-Type", "application/json")
+        .body(body)
+        .timeout(NOTIFY_TIMEOUT)
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct StoredAlert {
+    rule: AlertRule,
+    #[serde(default)]
+    state: AlertState,
+}
+
+///Rule and state of an alert, for `GET /alerts`
+#[derive(Debug, Clone, Serialize)]
+pub struct AlertInfo {
+    pub name: String,
+    #[serde(flatten)]
+    pub rule: AlertRule,
+    pub state: AlertState,
+}
+
+///Alert rules of this node along with their state, stored in `alerts.json`. The state survives restarts,
+///so a firing alert doesn't page again after one.
+#[derive(Debug)]
+pub struct AlertStore {
+    path: PathBuf,
+    alerts: Mutex<BTreeMap<String, StoredAlert>>,
+}
+
+impl AlertStore {
+    pub fn file_path(db_root_path: &Path) -> PathBuf {
+        db_root_path.join("alerts.json")
+    }
+
+    pub fn new(db_root_path: &Path) -> Self {
+        Self {
+            path: AlertStore::file_path(db_root_path),
+            alerts: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    ///Continues with the stored rules, if there are any
+    pub fn load(&self) -> io::Result<()> {
+        let bytes = match fs::read(&self.path) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        *self.alerts.lock().unwrap() = serde_json::from_slice(&bytes)?;
+        Ok(())
+    }
+
+    fn persist(&self, alerts: &BTreeMap<String, StoredAlert>) -> io::Result<()> {
+        let json = serde_json::to_vec(alerts)?;
+        //Replace the file as a whole, so a crash can't leave half written rules behind
+        let tmp_path = self.path.with_extension("json.tmp");
+        fs::write(&tmp_path, json).and_then(|_| fs::rename(&tmp_path, &self.path))
+    }
+
+    pub fn list(&self) -> Vec<AlertInfo> {
+        self.alerts
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, alert)| AlertInfo { name: name.clone(), rule: alert.rule.clone(), state: alert.state.clone() })
+            .collect()
+    }
+
+    pub fn info(&self, name: &str) -> Option<AlertInfo> {
+        self.alerts
+            .lock()
+            .unwrap()
+            .get(name)
+            .map(|alert| AlertInfo { name: name.to_string(), rule: alert.rule.clone(), state: alert.state.clone() })
+    }
+
+    ///Creates or replaces the rule. Replaced rules start over as not firing.
+    pub fn put(&self, name: &str, rule: AlertRule) -> Result<(), AlertError> {
+        rule.validate()?;
+        let mut alerts = self.alerts.lock().unwrap();
+        alerts.insert(name.to_string(), StoredAlert { rule, state: AlertState::default() });
+        Ok(self.persist(&alerts)?)
+    }
+
+    pub fn remove(&self, name: &str) -> Result<(), AlertError> {
+        let mut alerts = self.alerts.lock().unwrap();
+        if alerts.remove(name).is_none() {
+            return Err(AlertError::UnknownAlert(name.to_string()));
+        }
+        Ok(self.persist(&alerts)?)
+    }
+
+    ///Rules whose interval passed since their latest evaluation
+    pub fn due(&self, now: u64) -> Vec<(String, AlertRule)> {
+        self.alerts
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, alert)| alert.state.evaluated_at.is_none_or(|evaluated_at| evaluated_at + alert.rule.interval_secs <= now))
+            .map(|(name, alert)| (name.clone(), alert.rule.clone()))
+            .collect()
+    }
+
+    ///Stores the outcome of an evaluation. Returns the notification to send if the alert starts or stops firing.
+    ///The alert only switches over once `notified` confirms the delivery, so failed notifications get sent again.
+    pub fn record(&self, name: &str, now: u64, outcome: Result<Option<f64>, String>) -> Option<AlertNotification> {
+        let mut alerts = self.alerts.lock().unwrap();
+        let alert = alerts.get_mut(name)?;
+        alert.state.evaluated_at = Some(now);
+        let notification = match outcome {
+            Ok(value) => {
+                alert.state.value = value;
+                alert.state.error = None;
+                let breached = value.is_some_and(|value| alert.rule.is_breached(value));
+                (breached != alert.state.firing).then(|| AlertNotification {
+                    alert: name.to_string(),
+                    status: if breached { AlertStatus::Firing } else { AlertStatus::Resolved },
+                    value,
+                    condition: alert.rule.condition,
+                    threshold: alert.rule.threshold,
+                    evaluated_at: now,
+                })
+            }
+            Err(error) => {
+                alert.state.error = Some(error);
+                None
+            }
+        };
+        if let Err(err) = self.persist(&alerts) {
+            tracing::warn!("Failed to persist the state of alert {}: {}", name, err);
+        }
+        notification
+    }
+
+    ///Switches the alert over once its notification got delivered, or keeps why it failed
+    pub fn notified(&self, notification: &AlertNotification, outcome: Result<(), String>) {
+        let mut alerts = self.alerts.lock().unwrap();
+        let Some(alert) = alerts.get_mut(&notification.alert) else {
+            return;
+        };
+        match outcome {
+            Ok(()) => alert.state.firing = notification.status == AlertStatus::Firing,
+            Err(error) => alert.state.error = Some(format!("Failed to notify {}: {}", alert.rule.target.url(), error)),
+        }
+        if let Err(err) = self.persist(&alerts) {
+            tracing::warn!("Failed to persist the state of alert {}: {}", notification.alert, err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::{Aggregate, AlertError, AlertRule, AlertStatus, AlertStore, AlertTarget, Condition};
+    use crate::storage::cell::Cell;
+    use crate::storage::column_frame::ColumnFrame;
+
+    fn errors_per_minute() -> AlertRule {
+        AlertRule {
+            filter: Some("level = 'error'".to_string()),
+            aggregate: Aggregate::Count,
+            column: None,
+            condition: Condition::Above,
+            threshold: 1.0,
+            interval_secs: 60,
+            window_secs: None,
+            target: AlertTarget::Webhook { url: "http://localhost:9000/hook".to_string() },
+        }
+    }
+
+    #[test]
+    fn alerts_notify_once_when_they_start_and_stop_firing() {
+        let root = TempDir::new().unwrap();
+        let store = AlertStore::new(root.path());
+        let invalid = AlertRule { aggregate: Aggregate::Avg, ..errors_per_minute() };
+        assert!(matches!(store.put("errors", invalid), Err(AlertError::InvalidRule(_))));
+        store.put("errors", errors_per_minute()).unwrap();
+        assert_eq!(errors_per_minute().query_filter(100).unwrap().from, Some(40));
+
+        let rows = (0..2)
+            .map(|latency| {
+                let mut row = ColumnFrame::new();
+                row.insert("latency", Cell::Int(latency));
+                row
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(errors_per_minute().aggregate(&rows), Some(2.0));
+        let avg = AlertRule { aggregate: Aggregate::Avg, column: Some("latency".to_string()), ..errors_per_minute() };
+        assert_eq!(avg.aggregate(&rows), Some(0.5));
+        assert_eq!(avg.aggregate(&[]), None);
+
+        assert_eq!(store.due(100).len(), 1);
+        let notification = store.record("errors", 100, Ok(Some(2.0))).unwrap();
+        assert_eq!(notification.status, AlertStatus::Firing);
+        assert!(store.due(159).is_empty());
+        //Undelivered notifications get sent again
+        store.notified(&notification, Err("connection refused".to_string()));
+        assert!(store.record("errors", 160, Ok(Some(3.0))).is_some());
+        store.notified(&notification, Ok(()));
+        assert!(store.record("errors", 220, Ok(Some(3.0))).is_none());
+
+        let store = AlertStore::new(root.path());
+        store.load().unwrap();
+        assert!(store.info("errors").unwrap().state.firing);
+        let notification = store.record("errors", 280, Ok(Some(0.0))).unwrap();
+        assert_eq!(notification.status, AlertStatus::Resolved);
+        store.remove("errors").unwrap();
+        assert!(matches!(store.remove("errors"), Err(AlertError::UnknownAlert(_))));
+    }
+}
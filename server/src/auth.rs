@@ -0,0 +1,387 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use openssl::bn::BigNum;
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Public};
+use openssl::rsa::Rsa;
+use openssl::sign::Verifier;
+use openssl::stack::Stack;
+use openssl::x509::store::{X509Store, X509StoreBuilder};
+use openssl::x509::{X509StoreContext, X509};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use tracing::{error, info};
+use warp::http::HeaderMap;
+
+use crate::config::{AuthConfig, ClientCertificateConfig, JwtConfig};
+
+pub const API_KEY_HEADER: &str = "x-api-key";
+///Fingerprint of the client's API key on inserts one node forwards to another, see `key_fingerprint`
+pub const API_KEY_FINGERPRINT_HEADER: &str = "x-warenhaus-api-key-fingerprint";
+pub const AUTHORIZATION_HEADER: &str = "authorization";
+///URL encoded PEM certificate of the client, like nginx's `$ssl_client_escaped_cert`
+pub const CLIENT_CERT_HEADER: &str = "x-client-cert";
+///Seconds a token's `exp` and `nbf` may be off, to allow for clocks which aren't quite in sync
+const CLOCK_SKEW_SECS: i64 = 60;
+
+#[derive(Debug, Error, PartialEq)]
+pub enum AuthError {
+    #[error("Missing credentials, expected the {0} header")]
+    MissingCredentials(&'static str),
+    #[error("Invalid credentials: {0}")]
+    InvalidCredentials(String),
+}
+
+fn invalid(reason: impl Into<String>) -> AuthError {
+    AuthError::InvalidCredentials(reason.into())
+}
+
+///Checks the credentials a request carries. Implementations must not block, as they run for every request.
+pub trait AuthProvider: Debug + Send + Sync {
+    ///Name of the caller the request's headers identify
+    fn authenticate(&self, headers: &HeaderMap) -> Result<String, AuthError>;
+}
+
+///Names an API key without giving it away: the first 64 bits of its SHA-256 in hex. Logs, usage counters,
+///the query audit and the `_api_key` lineage column only ever hold this, as the key itself is a credential.
+pub fn key_fingerprint(api_key: &str) -> String {
+    let digest = Sha256::digest(api_key.as_bytes());
+    format!("sha256:{}", digest[..8].iter().map(|byte| format!("{:02x}", byte)).collect::<String>())
+}
+
+fn header<'a>(headers: &'a HeaderMap, name: &'static str) -> Result<&'a str, AuthError> {
+    let value = headers.get(name).ok_or(AuthError::MissingCredentials(name))?;
+    value.to_str().map_err(|_| invalid(format!("{} isn't valid ASCII", name)))
+}
+
+///Accepts the requests carrying one of the keys in `x-api-key`. The key's fingerprint names the caller.
+#[derive(Debug)]
+pub struct ApiKeyProvider {
+    keys: Vec<String>,
+}
+
+impl ApiKeyProvider {
+    pub fn new(keys: Vec<String>) -> Self {
+        Self { keys }
+    }
+}
+
+impl AuthProvider for ApiKeyProvider {
+    fn authenticate(&self, headers: &HeaderMap) -> Result<String, AuthError> {
+        let api_key = header(headers, API_KEY_HEADER)?;
+        //Compared in constant time, so the response time doesn't give away how much of a key matched
+        let known = self
+            .keys
+            .iter()
+            .any(|key| key.len() == api_key.len() && openssl::memcmp::eq(key.as_bytes(), api_key.as_bytes()));
+        match known {
+            true => Ok(key_fingerprint(api_key)),
+            false => Err(invalid("Unknown API key")),
+        }
+    }
+}
+
+///Keys as an OIDC provider publishes them. Only RSA keys are used.
+#[derive(Debug, Deserialize)]
+pub struct Jwks {
+    keys: Vec<Jwk>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Jwk {
+    kty: String,
+    kid: Option<String>,
+    n: Option<String>,
+    e: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct JwtHeader {
+    alg: String,
+    kid: Option<String>,
+}
+
+///Accepts requests with a bearer token signed with RS256 by one of the provider's keys. Tokens have to carry
+///an `exp` claim, the `sub` claim names the caller.
+#[derive(Debug)]
+pub struct JwtProvider {
+    config: JwtConfig,
+    ///Signing keys of the last fetched JWKS by their id
+    keys: RwLock<HashMap<Option<String>, PKey<Public>>>,
+}
+
+fn decode(part: &str) -> Result<Vec<u8>, AuthError> {
+    URL_SAFE_NO_PAD.decode(part).map_err(|_| invalid("The token isn't base64url encoded"))
+}
+
+fn now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as i64
+}
+
+impl JwtProvider {
+    pub fn new(config: JwtConfig) -> Self {
+        Self {
+            config,
+            keys: RwLock::new(HashMap::new()),
+        }
+    }
+
+    ///Replaces the signing keys. Returns how many RSA keys the set holds.
+    pub fn install(&self, jwks: Jwks) -> anyhow::Result<usize> {
+        let mut keys = HashMap::new();
+        for jwk in jwks.keys.into_iter().filter(|jwk| jwk.kty == "RSA") {
+            let (Some(n), Some(e)) = (jwk.n, jwk.e) else {
+                continue;
+            };
+            let component = |value: &str| -> anyhow::Result<BigNum> {
+                Ok(BigNum::from_slice(&URL_SAFE_NO_PAD.decode(value.trim_end_matches('='))?)?)
+            };
+            let rsa = Rsa::from_public_components(component(&n)?, component(&e)?)?;
+            keys.insert(jwk.kid, PKey::from_rsa(rsa)?);
+        }
+        let count = keys.len();
+        *self.keys.write().unwrap() = keys;
+        Ok(count)
+    }
+
+    ///Fetches the signing keys from the JWKS URL
+    pub async fn refresh(&self) -> anyhow::Result<usize> {
+        let body = reqwest::get(&self.config.jwks_url).await?.error_for_status()?.text().await?;
+        self.install(serde_json::from_str(&body)?)
+    }
+
+    fn verify_signature(&self, header: &JwtHeader, signed: &str, signature: &[u8]) -> Result<(), AuthError> {
+        if header.alg != "RS256" {
+            return Err(invalid(format!("Unsupported algorithm {}, expected RS256", header.alg)));
+        }
+        let keys = self.keys.read().unwrap();
+        //Tokens without a key id are fine as long as there's no choice
+        let key = match &header.kid {
+            Some(_) => keys.get(&header.kid),
+            None if keys.len() == 1 => keys.values().next(),
+            None => None,
+        };
+        let key = key.ok_or_else(|| invalid("The token isn't signed by a known key"))?;
+        let verified = Verifier::new(MessageDigest::sha256(), key)
+            .and_then(|mut verifier| {
+                verifier.update(signed.as_bytes())?;
+                verifier.verify(signature)
+            })
+            .unwrap_or(false);
+        match verified {
+            true => Ok(()),
+            false => Err(invalid("The token's signature doesn't match")),
+        }
+    }
+
+    fn check_claims(&self, claims: &serde_json::Value) -> Result<String, AuthError> {
+        let now = now();
+        let expires_at = claims["exp"].as_f64().ok_or_else(|| invalid("The token has no exp claim"))?;
+        if (expires_at as i64) + CLOCK_SKEW_SECS < now {
+            return Err(invalid("The token expired"));
+        }
+        if claims["nbf"].as_f64().is_some_and(|not_before| not_before as i64 - CLOCK_SKEW_SECS > now) {
+            return Err(invalid("The token isn't valid yet"));
+        }
+        if let Some(issuer) = &self.config.issuer {
+            if claims["iss"].as_str() != Some(issuer) {
+                return Err(invalid("The token was issued by someone else"));
+            }
+        }
+        if let Some(audience) = &self.config.audience {
+            let listed = match &claims["aud"] {
+                serde_json::Value::String(aud) => aud == audience,
+                serde_json::Value::Array(auds) => auds.iter().any(|aud| aud.as_str() == Some(audience)),
+                _ => false,
+            };
+            if !listed {
+                return Err(invalid("The token is meant for another audience"));
+            }
+        }
+        claims["sub"]
+            .as_str()
+            .map(|sub| sub.to_string())
+            .ok_or_else(|| invalid("The token has no sub claim"))
+    }
+}
+
+impl AuthProvider for JwtProvider {
+    fn authenticate(&self, headers: &HeaderMap) -> Result<String, AuthError> {
+        let authorization = header(headers, AUTHORIZATION_HEADER)?;
+        let token = authorization
+            .strip_prefix("Bearer ")
+            .ok_or_else(|| invalid("Expected a bearer token"))?;
+        let [header, claims, signature] = token.split('.').collect::<Vec<_>>()[..] else {
+            return Err(invalid("The token isn't a JWT"));
+        };
+        let jwt_header: JwtHeader = serde_json::from_slice(&decode(header)?).map_err(|_| invalid("The token's header isn't valid"))?;
+        self.verify_signature(&jwt_header, &token[..header.len() + 1 + claims.len()], &decode(signature)?)?;
+        let claims = serde_json::from_slice(&decode(claims)?).map_err(|_| invalid("The token's claims aren't valid"))?;
+        self.check_claims(&claims)
+    }
+}
+
+///Accepts requests whose `x-client-cert` holds a certificate issued by one of the configured authorities.
+///The TLS terminating proxy in front of the server checks that the client holds the certificate's private key,
+///and has to drop the header from incoming requests. The certificate's common name names the caller.
+pub struct ClientCertificateProvider {
+    store: X509Store,
+}
+
+impl Debug for ClientCertificateProvider {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ClientCertificateProvider").finish_non_exhaustive()
+    }
+}
+
+impl ClientCertificateProvider {
+    pub fn new(ca_pem: &[u8]) -> anyhow::Result<Self> {
+        let mut builder = X509StoreBuilder::new()?;
+        for ca in X509::stack_from_pem(ca_pem)? {
+            builder.add_cert(ca)?;
+        }
+        Ok(Self { store: builder.build() })
+    }
+}
+
+impl AuthProvider for ClientCertificateProvider {
+    fn authenticate(&self, headers: &HeaderMap) -> Result<String, AuthError> {
+        let pem = percent_encoding::percent_decode_str(header(headers, CLIENT_CERT_HEADER)?).collect::<Vec<_>>();
+        let certificate = X509::from_pem(&pem).map_err(|_| invalid("The client certificate isn't PEM encoded"))?;
+        //Checks the issuer's signature as well as the validity period
+        let verified = Stack::new()
+            .and_then(|chain| {
+                let mut context = X509StoreContext::new()?;
+                context.init(&self.store, &certificate, &chain, |context| context.verify_cert())
+            })
+            .unwrap_or(false);
+        if !verified {
+            return Err(invalid("The client certificate isn't issued by a trusted authority"));
+        }
+        certificate
+            .subject_name()
+            .entries_by_nid(Nid::COMMONNAME)
+            .next()
+            .and_then(|entry| entry.data().as_utf8().ok())
+            .map(|common_name| common_name.to_string())
+            .ok_or_else(|| invalid("The client certificate has no common name"))
+    }
+}
+
+///Sets up the configured provider. JWT signing keys get fetched right away and refreshed in the background.
+pub async fn start(config: Option<&AuthConfig>) -> anyhow::Result<Option<Arc<dyn AuthProvider>>> {
+    let Some(config) = config else {
+        return Ok(None);
+    };
+    let provider: Arc<dyn AuthProvider> = match config {
+        AuthConfig::ApiKeys(keys) => Arc::new(ApiKeyProvider::new(keys.clone())),
+        AuthConfig::Jwt(jwt_config) => {
+            let provider = Arc::new(JwtProvider::new(jwt_config.clone()));
+            let refreshed = provider.clone();
+            tokio::spawn(async move {
+                let interval = Duration::from_secs(refreshed.config.refresh_secs.max(1));
+                loop {
+                    match refreshed.refresh().await {
+                        Ok(keys) => info!("Loaded {} signing keys from {}", keys, refreshed.config.jwks_url),
+                        Err(err) => error!("Failed to fetch signing keys from {}: {}", refreshed.config.jwks_url, err),
+                    }
+                    tokio::time::sleep(interval).await;
+                }
+            });
+            provider
+        }
+        AuthConfig::ClientCertificate(ClientCertificateConfig { ca_file }) => {
+            let ca_pem = std::fs::read(ca_file).with_context(|| format!("Failed to read {}", ca_file))?;
+            Arc::new(ClientCertificateProvider::new(&ca_pem)?)
+        }
+    };
+    info!("Authenticating requests with {}", config_name(config));
+    Ok(Some(provider))
+}
+
+fn config_name(config: &AuthConfig) -> &'static str {
+    match config {
+        AuthConfig::ApiKeys(_) => "API keys",
+        AuthConfig::Jwt(_) => "JWTs",
+        AuthConfig::ClientCertificate(_) => "client certificates",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    use base64::Engine;
+    use openssl::hash::MessageDigest;
+    use openssl::pkey::PKey;
+    use openssl::rsa::Rsa;
+    use openssl::sign::Signer;
+    use serde_json::json;
+    use warp::http::HeaderMap;
+
+    use super::{key_fingerprint, now, ApiKeyProvider, AuthError, AuthProvider, ClientCertificateProvider, JwtProvider};
+    use crate::config::JwtConfig;
+
+    fn headers(name: &'static str, value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, value.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn api_keys_have_to_be_known() {
+        let provider = ApiKeyProvider::new(vec!["secret".into()]);
+        let caller = provider.authenticate(&headers("x-api-key", "secret")).unwrap();
+        assert_eq!(caller, key_fingerprint("secret"));
+        assert_eq!(caller, "sha256:2bb80d537b1da3e3");
+        assert!(provider.authenticate(&headers("x-api-key", "secret2")).is_err());
+        assert_eq!(provider.authenticate(&HeaderMap::new()), Err(AuthError::MissingCredentials("x-api-key")));
+
+        let provider = ClientCertificateProvider::new(b"").unwrap();
+        assert!(provider.authenticate(&headers("x-client-cert", "not%20a%20certificate")).is_err());
+    }
+
+    #[test]
+    fn tokens_have_to_be_signed_by_a_known_key() {
+        let rsa = Rsa::generate(2048).unwrap();
+        let jwks = json!({"keys": [{
+            "kty": "RSA",
+            "kid": "k1",
+            "n": URL_SAFE_NO_PAD.encode(rsa.n().to_vec()),
+            "e": URL_SAFE_NO_PAD.encode(rsa.e().to_vec()),
+        }]});
+        let key = PKey::from_rsa(rsa).unwrap();
+        let sign = |kid: &str, claims: serde_json::Value| {
+            let header = URL_SAFE_NO_PAD.encode(json!({"alg": "RS256", "kid": kid}).to_string());
+            let signed = format!("{}.{}", header, URL_SAFE_NO_PAD.encode(claims.to_string()));
+            let mut signer = Signer::new(MessageDigest::sha256(), &key).unwrap();
+            signer.update(signed.as_bytes()).unwrap();
+            format!("Bearer {}.{}", signed, URL_SAFE_NO_PAD.encode(signer.sign_to_vec().unwrap()))
+        };
+        let provider = JwtProvider::new(JwtConfig {
+            jwks_url: "http://localhost/jwks.json".into(),
+            issuer: Some("https://issuer".into()),
+            audience: Some("warenhaus".into()),
+            refresh_secs: 60,
+        });
+        assert_eq!(provider.install(serde_json::from_value(jwks).unwrap()).unwrap(), 1);
+
+        let claims = json!({"sub": "team-a", "iss": "https://issuer", "aud": ["warenhaus"], "exp": now() + 60});
+        let token = sign("k1", claims.clone());
+        assert_eq!(provider.authenticate(&headers("authorization", &token)), Ok("team-a".into()));
+        assert!(provider.authenticate(&headers("authorization", &sign("k2", claims.clone()))).is_err());
+        assert!(provider.authenticate(&headers("authorization", &format!("{}x", token))).is_err());
+
+        let expired = json!({"sub": "team-a", "iss": "https://issuer", "aud": "warenhaus", "exp": now() - 3600});
+        assert!(provider.authenticate(&headers("authorization", &sign("k1", expired))).is_err());
+        let other_audience = json!({"sub": "team-a", "iss": "https://issuer", "aud": "other", "exp": now() + 60});
+        assert!(provider.authenticate(&headers("authorization", &sign("k1", other_audience))).is_err());
+    }
+}
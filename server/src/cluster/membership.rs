@@ -0,0 +1,382 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, instrument};
+
+use super::secret::{ClusterSecret, CLUSTER_SECRET_HEADER};
+use super::shard_router::ShardRouter;
+
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+///A member that hasn't increased its heartbeat for this long is considered suspect
+const SUSPECT_AFTER: Duration = Duration::from_secs(5);
+///A member that hasn't increased its heartbeat for this long is considered dead and leaves the ring
+const DEAD_AFTER: Duration = Duration::from_secs(15);
+///A dead member that hasn't sent a heartbeat for this long gets forgotten, unless it's a seed
+const FORGET_AFTER: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum MemberState {
+    Alive,
+    Suspect,
+    Dead,
+}
+
+///What nodes exchange on every gossip round: the latest heartbeat they know per node
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GossipEntry {
+    pub node: String,
+    pub heartbeat: u64,
+    ///Milliseconds since the epoch the node started at. A restarted node counts its heartbeats from zero again,
+    ///its higher incarnation tells they're newer than the ones of its previous run.
+    #[serde(default)]
+    pub incarnation: u64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct MemberView {
+    pub node: String,
+    pub heartbeat: u64,
+    pub state: MemberState,
+    ///Seconds since the heartbeat last increased. None if the node has never been heard from
+    pub last_seen_secs: Option<u64>,
+}
+
+#[derive(Debug)]
+struct Member {
+    incarnation: u64,
+    heartbeat: u64,
+    last_seen: Option<Instant>,
+}
+
+impl Member {
+    fn state(&self, now: Instant) -> MemberState {
+        match self.last_seen.map(|last_seen| now.duration_since(last_seen)) {
+            Some(elapsed) if elapsed < SUSPECT_AFTER => MemberState::Alive,
+            Some(elapsed) if elapsed < DEAD_AFTER => MemberState::Suspect,
+            _ => MemberState::Dead,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Membership {
+    local_node: String,
+    ///Seeds stay members even while they're dead, so a restarted seed gets contacted again
+    seeds: Vec<String>,
+    members: HashMap<String, Member>,
+    next_peer: usize,
+}
+
+impl Membership {
+    ///Starts out knowing only the seed nodes. Everything else is learned through gossip.
+    pub fn new(local_node: String, seeds: Vec<String>) -> Self {
+        let mut members = HashMap::new();
+        for seed in &seeds {
+            members.insert(
+                seed.to_string(),
+                Member {
+                    incarnation: 0,
+                    heartbeat: 0,
+                    last_seen: None,
+                },
+            );
+        }
+        let incarnation = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since_epoch| since_epoch.as_millis() as u64)
+            .unwrap_or_default();
+        members.insert(
+            local_node.to_string(),
+            Member {
+                incarnation,
+                heartbeat: 0,
+                last_seen: Some(Instant::now()),
+            },
+        );
+
+        Self {
+            local_node,
+            seeds,
+            members,
+            next_peer: 0,
+        }
+    }
+
+    fn beat(&mut self) {
+        let local = self.members.get_mut(&self.local_node).unwrap();
+        local.heartbeat += 1;
+        local.last_seen = Some(Instant::now());
+    }
+
+    ///Heartbeats of the members which aren't dead. Passing on the ones of dead members would bring them back
+    ///at peers which already forgot them.
+    pub fn digest(&self) -> Vec<GossipEntry> {
+        let now = Instant::now();
+        self.members
+            .iter()
+            .filter(|(_, member)| member.state(now) != MemberState::Dead)
+            .map(|(node, member)| GossipEntry {
+                node: node.to_string(),
+                heartbeat: member.heartbeat,
+                incarnation: member.incarnation,
+            })
+            .collect()
+    }
+
+    ///Takes over every heartbeat that is newer than the one we know about
+    pub fn merge(&mut self, digest: Vec<GossipEntry>) {
+        let now = Instant::now();
+        for entry in digest {
+            if entry.node == self.local_node {
+                continue;
+            }
+            let member = self.members.entry(entry.node.to_string()).or_insert_with(|| {
+                info!("Discovered new member {}", entry.node);
+                Member {
+                    incarnation: 0,
+                    heartbeat: 0,
+                    last_seen: None,
+                }
+            });
+            if (entry.incarnation, entry.heartbeat) > (member.incarnation, member.heartbeat) {
+                member.incarnation = entry.incarnation;
+                member.heartbeat = entry.heartbeat;
+                member.last_seen = Some(now);
+            }
+        }
+    }
+
+    ///Drops members which have been dead for longer than FORGET_AFTER, so the digest doesn't keep growing
+    fn forget_dead(&mut self) {
+        let now = Instant::now();
+        let seeds = &self.seeds;
+        self.members.retain(|node, member| {
+            let gone = member
+                .last_seen
+                .is_some_and(|last_seen| now.duration_since(last_seen) >= FORGET_AFTER);
+            if gone && !seeds.contains(node) {
+                info!("Forgetting member {}, it hasn't sent a heartbeat for {:?}", node, FORGET_AFTER);
+                return false;
+            }
+            true
+        });
+    }
+
+    ///Picks peers round robin, so every known member gets contacted eventually
+    fn next_peer(&mut self) -> Option<String> {
+        let mut peers = self
+            .members
+            .keys()
+            .filter(|node| *node != &self.local_node)
+            .cloned()
+            .collect::<Vec<_>>();
+        if peers.is_empty() {
+            return None;
+        }
+        peers.sort();
+        self.next_peer = (self.next_peer + 1) % peers.len();
+        Some(peers.swap_remove(self.next_peer))
+    }
+
+    pub fn alive_nodes(&self) -> Vec<String> {
+        let now = Instant::now();
+        self.members
+            .iter()
+            .filter(|(_, member)| member.state(now) != MemberState::Dead)
+            .map(|(node, _)| node.to_string())
+            .collect()
+    }
+
+    pub fn members(&self) -> Vec<MemberView> {
+        let now = Instant::now();
+        let mut members = self
+            .members
+            .iter()
+            .map(|(node, member)| MemberView {
+                node: node.to_string(),
+                heartbeat: member.heartbeat,
+                state: member.state(now),
+                last_seen_secs: member
+                    .last_seen
+                    .map(|last_seen| now.duration_since(last_seen).as_secs()),
+            })
+            .collect::<Vec<_>>();
+        members.sort_by(|a, b| a.node.cmp(&b.node));
+        members
+    }
+}
+
+async fn gossip_with(peer: &str, digest: Vec<GossipEntry>, secret: &ClusterSecret) -> Result<Vec<GossipEntry>, reqwest::Error> {
+    let body = reqwest::Client::new()
+        .post(format!("{}/cluster/gossip", peer))
+        .header("Content-Type", "application/json")
+        .header(CLUSTER_SECRET_HEADER, secret.value())
+        .body(serde_json::to_string(&digest).unwrap())
+        .timeout(HEARTBEAT_INTERVAL)
+        .send()
+        .await?
+        .text()
+        .await?;
+    Ok(serde_json::from_str(&body).unwrap_or_default())
+}
+
+///Heartbeats and gossips with one peer per interval. Keeps the shard router's ring in line
+///with the members that are still alive.
+#[instrument(skip_all)]
+pub async fn run_gossip(membership: Arc<Mutex<Membership>>, router: Arc<RwLock<ShardRouter>>, secret: ClusterSecret) {
+    let mut interval = tokio::time::interval(HEARTBEAT_INTERVAL);
+    loop {
+        interval.tick().await;
+
+        let (peer, digest) = {
+            let mut membership = membership.lock().unwrap();
+            membership.beat();
+            membership.forget_dead();
+            (membership.next_peer(), membership.digest())
+        };
+
+        if let Some(peer) = peer {
+            match gossip_with(&peer, digest, &secret).await {
+                Ok(peer_digest) => membership.lock().unwrap().merge(peer_digest),
+                Err(err) => debug!("Failed to gossip with {}: {}", peer, err),
+            }
+        }
+
+        update_ring(&membership.lock().unwrap(), &router);
+    }
+}
+
+///Brings the shard router's ring in line with the members that are still alive
+fn update_ring(membership: &Membership, router: &RwLock<ShardRouter>) {
+    let alive_nodes = membership.alive_nodes();
+    let mut router = router.write().unwrap();
+    if router.set_nodes(&alive_nodes) {
+        info!("Cluster membership changed. Shard ring now spans {:?}", alive_nodes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::RwLock;
+    use std::time::{Duration, Instant};
+
+    use serde_json::json;
+
+    use super::{update_ring, GossipEntry, MemberState, Membership, FORGET_AFTER};
+    use crate::cluster::shard_router::{Route, ShardRouter};
+    use crate::web::IndexParams;
+
+    fn entry(node: &str, incarnation: u64, heartbeat: u64) -> GossipEntry {
+        GossipEntry {
+            node: node.into(),
+            heartbeat,
+            incarnation,
+        }
+    }
+
+    fn state(membership: &Membership, node: &str) -> Option<MemberState> {
+        membership.members().into_iter().find(|member| member.node == node).map(|member| member.state)
+    }
+
+    ///Pretends the member's heartbeat last increased the given time ago
+    fn last_seen_ago(membership: &mut Membership, node: &str, ago: Duration) {
+        membership.members.get_mut(node).unwrap().last_seen = Some(Instant::now().checked_sub(ago).unwrap());
+    }
+
+    #[test]
+    fn merge_takes_over_newer_heartbeats_only() {
+        let mut membership = Membership::new("http://a".into(), vec!["http://b".into()]);
+        assert_eq!(state(&membership, "http://b"), Some(MemberState::Dead));
+
+        membership.merge(vec![entry("http://b", 1, 5)]);
+        assert_eq!(state(&membership, "http://b"), Some(MemberState::Alive));
+        last_seen_ago(&mut membership, "http://b", Duration::from_secs(6));
+        membership.merge(vec![entry("http://b", 1, 3), entry("http://b", 1, 5)]);
+        assert_eq!(state(&membership, "http://b"), Some(MemberState::Suspect));
+        assert_eq!(membership.members.get("http://b").unwrap().heartbeat, 5);
+
+        //Unknown nodes are discovered, gossip about the local node is ignored
+        membership.merge(vec![entry("http://c", 1, 1), entry("http://a", 1, 100)]);
+        assert_eq!(state(&membership, "http://c"), Some(MemberState::Alive));
+        assert_eq!(membership.members.get("http://a").unwrap().heartbeat, 0);
+    }
+
+    #[test]
+    fn silent_members_turn_suspect_then_dead_and_get_forgotten() {
+        let mut membership = Membership::new("http://a".into(), vec!["http://b".into()]);
+        membership.merge(vec![entry("http://b", 1, 1), entry("http://c", 1, 1)]);
+        let mut alive = membership.alive_nodes();
+        alive.sort();
+        assert_eq!(alive, ["http://a", "http://b", "http://c"]);
+
+        last_seen_ago(&mut membership, "http://c", Duration::from_secs(5));
+        assert_eq!(state(&membership, "http://c"), Some(MemberState::Suspect));
+        assert!(membership.alive_nodes().contains(&"http://c".to_string()));
+
+        last_seen_ago(&mut membership, "http://c", Duration::from_secs(15));
+        assert_eq!(state(&membership, "http://c"), Some(MemberState::Dead));
+        assert!(!membership.alive_nodes().contains(&"http://c".to_string()));
+        assert!(!membership.digest().iter().any(|entry| entry.node == "http://c"));
+        membership.forget_dead();
+        assert_eq!(state(&membership, "http://c"), Some(MemberState::Dead));
+
+        //Seeds are kept, so they get contacted again once they're back
+        last_seen_ago(&mut membership, "http://b", FORGET_AFTER);
+        last_seen_ago(&mut membership, "http://c", FORGET_AFTER);
+        membership.forget_dead();
+        assert_eq!(state(&membership, "http://b"), Some(MemberState::Dead));
+        assert_eq!(state(&membership, "http://c"), None);
+    }
+
+    #[test]
+    fn restarted_members_rejoin_with_their_new_incarnation() {
+        let mut membership = Membership::new("http://a".into(), vec![]);
+        membership.merge(vec![entry("http://b", 1, 50)]);
+        last_seen_ago(&mut membership, "http://b", Duration::from_secs(20));
+        assert_eq!(state(&membership, "http://b"), Some(MemberState::Dead));
+
+        //Older heartbeats of the previous run don't bring it back
+        membership.merge(vec![entry("http://b", 1, 50)]);
+        assert_eq!(state(&membership, "http://b"), Some(MemberState::Dead));
+        membership.merge(vec![entry("http://b", 2, 1)]);
+        assert_eq!(state(&membership, "http://b"), Some(MemberState::Alive));
+        assert!(membership.alive_nodes().contains(&"http://b".to_string()));
+    }
+
+    #[test]
+    fn ring_follows_the_alive_members() {
+        let mut membership = Membership::new("http://a".into(), vec![]);
+        let router = RwLock::new(ShardRouter::new("http://a".into(), Some("url".into()), vec!["http://a".into()]));
+        let urls = (0..200).map(|n| format!("https://example.com/{}", n)).collect::<Vec<_>>();
+        let routes = |router: &RwLock<ShardRouter>| {
+            let router = router.read().unwrap();
+            urls.iter()
+                .map(|url| {
+                    router.route(&IndexParams {
+                        fields: vec!["url".into()],
+                        values: vec![json!(url)],
+                    })
+                })
+                .collect::<Vec<_>>()
+        };
+
+        membership.merge(vec![entry("http://b", 1, 1), entry("http://c", 1, 1)]);
+        update_ring(&membership, &router);
+        let joined = routes(&router);
+        assert!(joined.contains(&Route::Remote("http://b".into())));
+        assert!(joined.contains(&Route::Remote("http://c".into())));
+
+        last_seen_ago(&mut membership, "http://b", Duration::from_secs(15));
+        update_ring(&membership, &router);
+        for (route, joined_route) in routes(&router).into_iter().zip(joined) {
+            match joined_route {
+                Route::Remote(node) if node == "http://b" => assert_ne!(route, Route::Remote(node)),
+                joined_route => assert_eq!(route, joined_route, "Key moved between nodes still alive"),
+            }
+        }
+    }
+}
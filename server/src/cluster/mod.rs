@@ -0,0 +1,5 @@
+pub mod membership;
+pub mod role;
+pub mod secret;
+pub mod seq_token;
+pub mod shard_router;
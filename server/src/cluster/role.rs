@@ -0,0 +1,33 @@
+use std::fmt::Display;
+use std::str::FromStr;
+
+///What a node serves, set with `NODE_ROLE`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum NodeRole {
+    ///Stores rows and answers queries
+    #[default]
+    Primary,
+    ///Only answers queries, from snapshots of a primary's data directory. See `ReplicaSnapshots`.
+    Query,
+}
+
+impl FromStr for NodeRole {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "primary" => Ok(NodeRole::Primary),
+            "query" => Ok(NodeRole::Query),
+            _ => Err(format!("Invalid node role {}. Expected primary or query", s)),
+        }
+    }
+}
+
+impl Display for NodeRole {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NodeRole::Primary => write!(f, "primary"),
+            NodeRole::Query => write!(f, "query"),
+        }
+    }
+}
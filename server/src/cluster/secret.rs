@@ -0,0 +1,53 @@
+use std::fmt::{self, Debug};
+
+use warp::http::HeaderMap;
+
+///Carries the cluster secret on requests nodes send each other
+pub const CLUSTER_SECRET_HEADER: &str = "x-warenhaus-cluster-secret";
+
+///Secret shared by all nodes of a cluster. Requests carrying it come from another node, which gossip requires.
+#[derive(Clone)]
+pub struct ClusterSecret(String);
+
+impl ClusterSecret {
+    pub fn new(secret: String) -> Self {
+        Self(secret)
+    }
+
+    pub fn value(&self) -> &str {
+        &self.0
+    }
+
+    ///Whether the request carries the secret. Compares in constant time, so timing doesn't reveal how much of it matched.
+    pub fn verify(&self, headers: &HeaderMap) -> bool {
+        let Some(value) = headers.get(CLUSTER_SECRET_HEADER) else {
+            return false;
+        };
+        value.len() == self.0.len() && openssl::memcmp::eq(value.as_bytes(), self.0.as_bytes())
+    }
+}
+
+impl Debug for ClusterSecret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ClusterSecret(..)")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use warp::http::HeaderMap;
+
+    use super::{ClusterSecret, CLUSTER_SECRET_HEADER};
+
+    #[test]
+    fn only_requests_carrying_the_secret_verify() {
+        let secret = ClusterSecret::new("s3cret".into());
+        let mut headers = HeaderMap::new();
+        assert!(!secret.verify(&headers));
+        headers.insert(CLUSTER_SECRET_HEADER, "s3cre".parse().unwrap());
+        assert!(!secret.verify(&headers));
+        headers.insert(CLUSTER_SECRET_HEADER, "s3cret".parse().unwrap());
+        assert!(secret.verify(&headers));
+        assert_eq!(format!("{:?}", secret), "ClusterSecret(..)");
+    }
+}
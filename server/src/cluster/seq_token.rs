@@ -0,0 +1,41 @@
+use std::fmt::Display;
+
+///Identifies a committed write: the commit sequence number on the node which stored it.
+///Serialized as `<seq>@<node>`, e.g. `17@http://node-a:3030`.
+#[derive(Debug, PartialEq)]
+pub struct SeqToken {
+    pub seq: i64,
+    pub node: String,
+}
+
+impl SeqToken {
+    pub fn parse(token: &str) -> Option<Self> {
+        let (seq, node) = token.split_once('@')?;
+        Some(Self {
+            seq: seq.parse().ok()?,
+            node: node.to_string(),
+        })
+    }
+}
+
+impl Display for SeqToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}@{}", self.seq, self.node)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SeqToken;
+
+    #[test]
+    fn tokens_round_trip_and_malformed_ones_get_rejected() {
+        let token = SeqToken::parse("17@http://node-a:3030").unwrap();
+        assert_eq!(token, SeqToken { seq: 17, node: "http://node-a:3030".into() });
+        assert_eq!(token.to_string(), "17@http://node-a:3030");
+
+        for malformed in ["", "17", "seventeen@http://node-a:3030", "@http://node-a:3030"] {
+            assert_eq!(SeqToken::parse(malformed), None, "{}", malformed);
+        }
+    }
+}
@@ -0,0 +1,143 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crc::{Crc, CRC_64_XZ};
+use tracing::{debug, instrument};
+
+use crate::web::IndexParams;
+
+///Number of points each node occupies on the hash ring. More points spread keys more evenly.
+const VIRTUAL_NODES_PER_NODE: usize = 64;
+const RING_HASH: Crc<u64> = Crc::<u64>::new(&CRC_64_XZ);
+
+#[derive(Debug, PartialEq)]
+pub enum Route {
+    Local,
+    Remote(String),
+}
+
+///Assigns incoming rows to nodes by hashing the value of the configured shard key column
+#[derive(Debug)]
+pub struct ShardRouter {
+    local_node: String,
+    shard_key: Option<String>,
+    ring: BTreeMap<u64, String>,
+}
+
+impl ShardRouter {
+    pub fn new(local_node: String, shard_key: Option<String>, nodes: Vec<String>) -> Self {
+        let mut router = Self {
+            local_node,
+            shard_key,
+            ring: BTreeMap::new(),
+        };
+        for node in nodes {
+            router.add_node(&node);
+        }
+        router
+    }
+
+    ///Places the node on the ring. Only keys hashing right before one of its virtual nodes move
+    ///over to the new node, all other keys keep their assignment.
+    #[instrument(skip(self))]
+    pub fn add_node(&mut self, node: &str) {
+        for replica in 0..VIRTUAL_NODES_PER_NODE {
+            let hash = RING_HASH.checksum(format!("{}#{}", node, replica).as_bytes());
+            self.ring.insert(hash, node.to_string());
+        }
+    }
+
+    pub fn local_node(&self) -> &str {
+        self.local_node.as_ref()
+    }
+
+    #[instrument(skip(self))]
+    pub fn remove_node(&mut self, node: &str) {
+        self.ring.retain(|_, ring_node| ring_node != node);
+    }
+
+    ///Brings the ring in line with the given nodes.
+    ///Returns true if nodes were added or removed.
+    pub fn set_nodes(&mut self, nodes: &[String]) -> bool {
+        let current = self.ring.values().cloned().collect::<BTreeSet<_>>();
+        let wanted = nodes.iter().cloned().collect::<BTreeSet<_>>();
+        for node in current.difference(&wanted) {
+            self.remove_node(node);
+        }
+        for node in wanted.difference(&current) {
+            self.add_node(node);
+        }
+        current != wanted
+    }
+
+    ///Returns the node responsible for the row.
+    ///Rows without a shard key value stay local, validation rejects them later on.
+    pub fn route(&self, params: &IndexParams) -> Route {
+        let Some(shard_key) = &self.shard_key else {
+            return Route::Local;
+        };
+
+        let key_value = params
+            .fields
+            .iter()
+            .position(|field| field == shard_key)
+            .and_then(|index| params.values.get(index));
+
+        let Some(key_value) = key_value else {
+            return Route::Local;
+        };
+
+        let hash = RING_HASH.checksum(key_value.to_string().as_bytes());
+        let node = self
+            .ring
+            .range(hash..)
+            .next()
+            .or_else(|| self.ring.iter().next())
+            .map(|(_, node)| node);
+
+        match node {
+            Some(node) if node != &self.local_node => {
+                debug!("Routing {} to {}", key_value, node);
+                Route::Remote(node.to_string())
+            }
+            _ => Route::Local,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{Route, ShardRouter};
+    use crate::web::IndexParams;
+
+    fn params(url: &str) -> IndexParams {
+        IndexParams {
+            fields: vec!["url".into()],
+            values: vec![json!(url)],
+        }
+    }
+
+    #[test]
+    fn route_everything_locally_without_shard_key() {
+        let router = ShardRouter::new("http://a".into(), None, vec!["http://a".into(), "http://b".into()]);
+        assert_eq!(router.route(&params("https://google.com")), Route::Local);
+    }
+
+    #[test]
+    fn adding_a_node_only_moves_keys_to_the_new_node() {
+        let nodes = vec!["http://a".to_string(), "http://b".to_string()];
+        let mut router = ShardRouter::new("http://a".into(), Some("url".into()), nodes);
+        let urls = (0..200).map(|n| format!("https://example.com/{}", n)).collect::<Vec<_>>();
+        let before = urls.iter().map(|url| router.route(&params(url))).collect::<Vec<_>>();
+
+        router.add_node("http://c");
+
+        for (url, previous_route) in urls.iter().zip(before) {
+            let route = router.route(&params(url));
+            if route != previous_route {
+                assert_eq!(route, Route::Remote("http://c".into()), "{} moved between existing nodes", url);
+            }
+        }
+    }
+}
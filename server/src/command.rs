@@ -1,21 +1,116 @@
-use tokio::sync::oneshot;
+use std::path::PathBuf;
+
+use tokio::sync::{mpsc, oneshot};
 
 use crate::{
-    query::wasm_error::WasmError,
-    storage::{ContainerError, column_frame::ColumnFrame},
+    metrics::StorageMetrics,
+    query::{map_result::MapResult, query_error::QueryError, wasm_error::WasmError},
+    storage::{BackfillReport, CommittedTransaction, Container, ContainerError, DistinctValues, RowPage, RowScan, TableSchema, cast::CastType, column_frame::ColumnFrame, column_stats::{ColumnHistogram, TableStats}, page_cursor::PageCursor, diagnostics::IdDiagnostics, filter::QueryFilter, lineage::Lineage, compaction::CompactionReport, retention::RetentionReport, warmup::LoadedColumns},
+    warnings::Warning,
     web::IndexParams,
 };
 
-pub type InsertResponder = oneshot::Sender<Result<(), ContainerError>>;
+///Write sequence number after storing the row, see `Container::write_seq`, along with warnings about its values
+pub type InsertResponder = oneshot::Sender<Result<(i64, Vec<Warning>), ContainerError>>;
+///Write sequence number after the update or delete, see `Container::write_seq`
+pub type UpdateResponder = oneshot::Sender<Result<i64, ContainerError>>;
+pub type DeleteResponder = oneshot::Sender<Result<i64, ContainerError>>;
+pub type RenameColumnResponder = oneshot::Sender<Result<(), ContainerError>>;
+pub type DropColumnResponder = oneshot::Sender<Result<(), ContainerError>>;
+pub type ValidateResponder = oneshot::Sender<Result<(), ContainerError>>;
+pub type TransactionResponder = oneshot::Sender<Result<CommittedTransaction, ContainerError>>;
+pub type DistinctResponder = oneshot::Sender<Result<DistinctValues, QueryError>>;
+pub type ScanRowsResponder = oneshot::Sender<Result<RowScan, QueryError>>;
+pub type PageRowsResponder = oneshot::Sender<Result<RowPage, QueryError>>;
+pub type LookupResponder = oneshot::Sender<Result<Vec<ColumnFrame>, QueryError>>;
+pub type FindByKeyResponder = oneshot::Sender<Result<Option<ColumnFrame>, QueryError>>;
+pub type CommittedSeqResponder = oneshot::Sender<i64>;
+pub type WalLenResponder = oneshot::Sender<u64>;
+pub type SnapshotResponder = oneshot::Sender<Result<PathBuf, ContainerError>>;
+pub type MetricsResponder = oneshot::Sender<StorageMetrics>;
+pub type SchemaResponder = oneshot::Sender<TableSchema>;
+pub type AppendRowsResponder = oneshot::Sender<Result<usize, ContainerError>>;
+pub type BackfillResponder = oneshot::Sender<Result<BackfillReport, ContainerError>>;
+pub type RetentionResponder = oneshot::Sender<Result<RetentionReport, ContainerError>>;
+pub type LastRetentionReportResponder = oneshot::Sender<Option<RetentionReport>>;
+pub type CompactionResponder = oneshot::Sender<Result<CompactionReport, ContainerError>>;
+pub type DiagnosticsResponder = oneshot::Sender<Result<IdDiagnostics, ContainerError>>;
+pub type StatsResponder = oneshot::Sender<Result<TableStats, ContainerError>>;
+pub type HistogramResponder = oneshot::Sender<Result<ColumnHistogram, ContainerError>>;
 pub type InsertMapFnResponder = oneshot::Sender<Result<(), WasmError>>;
-pub type ExecuteMapResponder = oneshot::Sender<Result<Vec<ColumnFrame>, WasmError>>;
+pub type ExecuteMapResponder = oneshot::Sender<Result<MapResult, QueryError>>;
+
+///When an insert gets acknowledged to the client
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum AckMode {
+    ///Once the write-ahead log record is synced to disk
+    Durable,
+    ///Once the row is committed, which may still be sitting in OS buffers
+    #[default]
+    Applied,
+    ///As soon as the insert is queued for the storage layer. Rejected rows only show up in the logs.
+    Received,
+}
+
+impl std::str::FromStr for AckMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "durable" => Ok(AckMode::Durable),
+            "applied" => Ok(AckMode::Applied),
+            "received" => Ok(AckMode::Received),
+            _ => Err(format!("Invalid ack mode {}. Expected durable, applied or received", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for AckMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AckMode::Durable => write!(f, "durable"),
+            AckMode::Applied => write!(f, "applied"),
+            AckMode::Received => write!(f, "received"),
+        }
+    }
+}
 
 #[derive(Debug)]
 pub enum Command {
+    ///Sent by the warm-up thread once the records stored before startup are read
+    WarmupDone {
+        result: std::io::Result<LoadedColumns>,
+    },
+    ///Sent by the replica sync of query nodes once a new snapshot is loaded. Answered once the old one got dropped.
+    ReplicaSynced {
+        container: Box<Container>,
+        responder: oneshot::Sender<()>,
+    },
     Index {
         params: IndexParams,
+        ack: AckMode,
+        lineage: Lineage,
         responder: InsertResponder,
     },
+    Validate {
+        params: IndexParams,
+        responder: ValidateResponder,
+    },
+    Update {
+        id: i64,
+        params: IndexParams,
+        responder: UpdateResponder,
+    },
+    Delete {
+        id: i64,
+        responder: DeleteResponder,
+    },
+    Transaction {
+        rows: Vec<IndexParams>,
+        ack: AckMode,
+        lineage: Lineage,
+        responder: TransactionResponder,
+    },
     AddMapFn {
         fn_name: String,
         source_code: String,
@@ -23,7 +118,189 @@ pub enum Command {
     },
     InvokeMap {
         fn_name: String,
+        ///Derived table to read from instead of the main table
+        table: Option<String>,
+        filter: QueryFilter,
         responder: ExecuteMapResponder,
     },
-    QueryRow { row: ColumnFrame }
+    ///Appends rows to a derived table, creating it first if necessary
+    AppendRows {
+        table: String,
+        rows: Vec<ColumnFrame>,
+        responder: AppendRowsResponder,
+    },
+    Distinct {
+        column: String,
+        limit: usize,
+        filter: QueryFilter,
+        cast: Option<CastType>,
+        responder: DistinctResponder,
+    },
+    ScanRows {
+        filter: QueryFilter,
+        responder: ScanRowsResponder,
+    },
+    PageRows {
+        filter: QueryFilter,
+        cursor: Option<PageCursor>,
+        limit: usize,
+        responder: PageRowsResponder,
+    },
+    Lookup {
+        column: String,
+        value: String,
+        responder: LookupResponder,
+    },
+    FindByKey {
+        value: String,
+        responder: FindByKeyResponder,
+    },
+    QueryRow { position: usize, row: ColumnFrame },
+    CommittedSeq {
+        responder: CommittedSeqResponder,
+    },
+    ///Length of the write-ahead log in between commands, so it doesn't include records a rollback may still cut off
+    WalLen {
+        responder: WalLenResponder,
+    },
+    ///Copies the table files into a new backup snapshot, see `Container::snapshot`
+    Snapshot {
+        responder: SnapshotResponder,
+    },
+    Metrics {
+        responder: MetricsResponder,
+    },
+    Schema {
+        responder: SchemaResponder,
+    },
+    Stats {
+        responder: StatsResponder,
+    },
+    Histogram {
+        column: String,
+        percentiles: Vec<f64>,
+        responder: HistogramResponder,
+    },
+    RenameColumn {
+        from: String,
+        to: String,
+        responder: RenameColumnResponder,
+    },
+    DropColumn {
+        name: String,
+        responder: DropColumnResponder,
+    },
+    Backfill {
+        responder: BackfillResponder,
+    },
+    Retention {
+        dry_run: bool,
+        responder: RetentionResponder,
+    },
+    LastRetentionReport {
+        responder: LastRetentionReportResponder,
+    },
+    ///Rewrites the column files without deleted rows, if there are at least min_deleted_rows
+    Compact {
+        min_deleted_rows: usize,
+        responder: CompactionResponder,
+    },
+    Diagnostics {
+        responder: DiagnosticsResponder,
+    },
+    ///Gives rows with duplicate ids new ids. Only runs in maintenance mode.
+    RepairIds {
+        responder: DiagnosticsResponder,
+    },
+}
+
+///Commands of the storage layer in two lanes. Inserts arrive in their own lane and go first, so a backlog of
+///queries doesn't add to their latency. After ingest_weight inserts in a row, a waiting command of the other
+///lane gets its turn, so bulk ingest can't starve queries either. Commands keep their order within a lane.
+#[derive(Debug)]
+pub struct CommandLanes {
+    ingest: mpsc::Receiver<Command>,
+    other: mpsc::Receiver<Command>,
+    ingest_weight: usize,
+    ///Inserts taken since the last command of the other lane
+    ingest_streak: usize,
+}
+
+impl CommandLanes {
+    pub fn new(ingest: mpsc::Receiver<Command>, other: mpsc::Receiver<Command>, ingest_weight: usize) -> Self {
+        Self {
+            ingest,
+            other,
+            ingest_weight: ingest_weight.max(1),
+            ingest_streak: 0,
+        }
+    }
+
+    ///Next command to run, None once both lanes are closed
+    pub async fn recv(&mut self) -> Option<Command> {
+        if self.ingest_streak >= self.ingest_weight {
+            self.ingest_streak = 0;
+            if let Ok(command) = self.other.try_recv() {
+                return Some(command);
+            }
+        }
+        tokio::select! {
+            biased;
+            Some(command) = self.ingest.recv() => {
+                self.ingest_streak += 1;
+                Some(command)
+            }
+            Some(command) = self.other.recv() => {
+                self.ingest_streak = 0;
+                Some(command)
+            }
+            else => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::{mpsc, oneshot};
+
+    use super::{AckMode, Command, CommandLanes};
+
+    fn committed_seq() -> Command {
+        Command::CommittedSeq { responder: oneshot::channel().0 }
+    }
+
+    fn metrics() -> Command {
+        Command::Metrics { responder: oneshot::channel().0 }
+    }
+
+    #[test]
+    fn ack_modes_round_trip_through_their_names() {
+        for ack in [AckMode::Durable, AckMode::Applied, AckMode::Received] {
+            assert_eq!(ack.to_string().parse::<AckMode>(), Ok(ack));
+        }
+        assert_eq!(AckMode::default(), AckMode::Applied);
+        assert!("Durable".parse::<AckMode>().is_err());
+        assert!("".parse::<AckMode>().is_err());
+    }
+
+    #[tokio::test]
+    async fn inserts_go_first_without_starving_the_other_lane() {
+        let (ingest_tx, ingest_rx) = mpsc::channel(16);
+        let (other_tx, other_rx) = mpsc::channel(16);
+        let mut lanes = CommandLanes::new(ingest_rx, other_rx, 2);
+        for _ in 0..2 {
+            other_tx.send(metrics()).await.unwrap();
+        }
+        for _ in 0..5 {
+            ingest_tx.send(committed_seq()).await.unwrap();
+        }
+        let mut order = vec![];
+        for _ in 0..7 {
+            order.push(matches!(lanes.recv().await, Some(Command::CommittedSeq { .. })));
+        }
+        assert_eq!(order, vec![true, true, false, true, true, false, true]);
+
+        drop((ingest_tx, other_tx));
+        assert!(lanes.recv().await.is_none());
+    }
 }
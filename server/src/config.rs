@@ -1,27 +1,286 @@
 use std::{fs::File, io::Read, path::Path};
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use tracing::{instrument, info};
 
+use crate::storage::column::DEFAULT_WRITE_BUFFER_SIZE;
+
 #[derive(Deserialize, Clone, Debug)]
 pub enum DataTypeConfig {
     Int,
     Float,
     String,
     Boolean,
+    ///Unix timestamps or RFC3339 strings, stored as seconds since the unix epoch
+    Timestamp,
+    ///Hyphenated UUID strings, stored as 16 bytes
+    Uuid,
+    ///JSON arrays of the inner type, e.g. `{"Array": "String"}`
+    Array(Box<DataTypeConfig>),
+    ///Arbitrary JSON values like nested objects, stored verbatim
+    Json,
+}
+
+///When buffered column records get written to disk
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+pub enum FlushPolicy {
+    ///After every committed row
+    #[default]
+    EveryCommit,
+    ///Only once a column's write buffer is full, and on shutdown
+    WhenFull,
+}
+
+///How a column's cells get written to its file, see `storage::block_encoder`
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub enum ColumnEncoding {
+    ///Written like Plain until a compaction picks the encoding taking the least space, see `storage::encoding_analyzer`
+    #[default]
+    Auto,
+    ///One record per cell
+    Plain,
+    ///Consecutive identical cells share a record. Only applies to Int and Boolean columns.
+    RunLength,
+    ///Consecutive cells get stored as differences to the previous one. Only applies to Int and Timestamp columns.
+    Delta,
+    ///Repeated cells refer to their first occurrence in a record. Only applies to String columns.
+    Dictionary,
+}
+
+///Compresses the records of a column file in blocks, see `storage::column::TAG_ZSTD_BLOCK`
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum ColumnCompression {
+    #[serde(alias = "zstd")]
+    Zstd,
+}
+
+///Pauses inserts before the volume holding the data directory runs full, see `storage::disk_pressure`
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct DiskPressureConfig {
+    ///Inserts get rejected while less than this many bytes are free
+    pub min_free_bytes: u64,
+    ///Inserts get turned away for a moment after syncing the write-ahead log took longer than this
+    pub max_sync_latency_ms: Option<u64>,
+}
+
+///Rewrites column files in the background, dropping deleted rows, see `storage::compaction`
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct CompactionConfig {
+    ///How often the table gets checked for deleted rows
+    pub interval_secs: u64,
+    ///Column files only get rewritten once at least this many rows got deleted, or replaced by updates
+    #[serde(default)]
+    pub min_deleted_rows: usize,
 }
 
-#[derive(Deserialize, Debug)]
+///Where column files get stored. The write-ahead log and all other files always stay on the local disk.
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+pub enum StorageBackendConfig {
+    ///In the table's data directory
+    #[default]
+    Local,
+    ///As segment objects in an S3 bucket, see `storage::s3_backend`
+    S3(S3Config),
+}
+
+///Credentials are read from `AWS_ACCESS_KEY_ID` and `AWS_SECRET_ACCESS_KEY`
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct S3Config {
+    pub bucket: String,
+    pub region: String,
+    ///Defaults to AWS. Set it to use an S3 compatible store, like MinIO.
+    pub endpoint: Option<String>,
+    ///Prepended to all object keys
+    #[serde(default)]
+    pub prefix: String,
+}
+
+///Uploads the write-ahead log to an S3 bucket as it grows, see `storage::wal_shipping`
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct WalShippingConfig {
+    #[serde(flatten)]
+    pub s3: S3Config,
+    ///How often the bytes appended to the log since the last upload get shipped
+    pub interval_secs: u64,
+}
+
+///Span of time whose rows share a segment, see `SchemaConfig::partition_by`
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum PartitionPeriod {
+    Hour,
+    Day,
+}
+
+impl PartitionPeriod {
+    ///Number of the UTC hour or day the unix timestamp falls into
+    pub fn period(&self, timestamp: i64) -> i64 {
+        match self {
+            PartitionPeriod::Hour => timestamp.div_euclid(60 * 60),
+            PartitionPeriod::Day => timestamp.div_euclid(24 * 60 * 60),
+        }
+    }
+}
+
+///How clients authenticate against the public endpoints, see `auth`
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub enum AuthConfig {
+    ///Clients send one of these keys in the `x-api-key` header
+    ApiKeys(Vec<String>),
+    ///Clients send a JWT signed by a key of an OIDC provider as bearer token
+    Jwt(JwtConfig),
+    ///A TLS terminating proxy verifies the client certificate and forwards it in the `x-client-cert` header
+    ClientCertificate(ClientCertificateConfig),
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct JwtConfig {
+    ///Where the provider publishes its signing keys, e.g. `https://accounts.google.com/.well-known/jwks.json`
+    pub jwks_url: String,
+    ///Tokens have to carry this `iss` claim if set
+    pub issuer: Option<String>,
+    ///Tokens have to list this `aud` claim if set
+    pub audience: Option<String>,
+    ///How often the keys get fetched again, to pick up rotated ones
+    #[serde(default = "default_jwks_refresh_secs")]
+    pub refresh_secs: u64,
+}
+
+fn default_jwks_refresh_secs() -> u64 {
+    15 * 60
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct ClientCertificateConfig {
+    ///PEM file of the certificate authorities client certificates have to be issued by
+    pub ca_file: String,
+}
+
+///Drops rows whose key column value already arrived within the window, to absorb redeliveries
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct DedupeConfig {
+    pub key_column: String,
+    pub window_secs: u64,
+}
+
+fn default_write_buffer_size() -> usize {
+    DEFAULT_WRITE_BUFFER_SIZE
+}
+
+#[derive(Deserialize, Clone, Debug)]
 pub struct SchemaConfig {
     pub columns: Vec<ColumnConfig>,
     ///Indicates wheter there should be an automatically generated timestamp column
-    pub add_timestamp_column: bool
+    pub add_timestamp_column: bool,
+    ///Reject inserts containing unknown fields by name, before checking the field count
+    #[serde(default)]
+    pub strict_fields: bool,
+    ///Write buffer size per column in bytes
+    #[serde(default = "default_write_buffer_size")]
+    pub write_buffer_size: usize,
+    #[serde(default)]
+    pub flush_policy: FlushPolicy,
+    ///Name of an uploaded module whose `before_insert` function runs for every row before validation.
+    ///It can enrich the row, or reject it.
+    pub before_insert_hook: Option<String>,
+    ///Column whose value decides which cluster node stores a row
+    pub shard_key: Option<String>,
+    ///Rows older than this many seconds get deleted. Requires the timestamp column.
+    pub retention_secs: Option<u64>,
+    ///How many seconds back `as_of` queries may reach by time. Unlimited if not set.
+    pub history_secs: Option<u64>,
+    ///Store where every row came from in hidden system columns, see `storage::lineage`
+    #[serde(default)]
+    pub lineage: bool,
+    #[serde(default)]
+    pub storage_backend: StorageBackendConfig,
+    ///Inserts with unknown fields add them as nullable columns, typed after their first value
+    #[serde(default)]
+    pub infer_schema: bool,
+    ///Order `/schema` lists the columns in. Columns which aren't listed follow in the order they got added.
+    #[serde(default)]
+    pub column_order: Vec<String>,
+    pub dedupe: Option<DedupeConfig>,
+    ///Inserts whose value in this column matches a stored row replace that row instead of adding one
+    pub unique_key: Option<String>,
+    ///Column identifying rows alongside the id. Its values have to be set and unique, and `GET /index/by-key/{value}`
+    ///finds rows by them.
+    pub primary_key: Option<String>,
+    ///Requests to the public endpoints without valid credentials get rejected. Open to everyone if not set.
+    pub auth: Option<AuthConfig>,
+    ///Query responses stop adding rows once they reach this many bytes, see `web::ResponseBudget`
+    pub max_response_bytes: Option<usize>,
+    pub disk_pressure: Option<DiskPressureConfig>,
+    pub compaction: Option<CompactionConfig>,
+    pub wal_shipping: Option<WalShippingConfig>,
+    ///Rows after which each column continues in a new segment file. Counting rows rather than bytes keeps
+    ///the segments of all columns aligned, so retention can drop whole segments. One file per column if not set.
+    pub segment_rows: Option<usize>,
+    ///Closes the active segment whenever a row's timestamp enters a new hour or day, so retention drops
+    ///and time range queries skip whole periods. Requires the timestamp column.
+    pub partition_by: Option<PartitionPeriod>,
+    ///Map the column files on load and decode cells on first access, instead of decoding all cells up front.
+    ///Only tables on the local disk can be mapped.
+    #[serde(default)]
+    pub mmap_reads: bool,
+    ///Only read the id column on startup. The other columns get read once a query, or anything else, first accesses their cells.
+    #[serde(default)]
+    pub lazy_columns: bool,
+    #[serde(flatten)]
+    pub docs: Docs,
 }
 
 #[derive(Deserialize, Clone, Debug)]
 pub struct ColumnConfig {
     pub name: String,
     pub data_type: DataTypeConfig,
+    ///Computed columns get filled by the before insert hook instead of the client
+    #[serde(default)]
+    pub computed: bool,
+    ///Nullable columns accept `null` and may be left out of inserts
+    #[serde(default)]
+    pub nullable: bool,
+    ///Value stored when an insert leaves out the column
+    pub default: Option<serde_json::Value>,
+    ///Uuid columns left out of an insert get a random UUID
+    #[serde(default)]
+    pub auto_generate: bool,
+    ///Filled by the server on every insert, like the timestamp column. Clients can't provide values for it.
+    pub server_computed: Option<ServerComputed>,
+    ///Keeps an index from the column's values to its rows, see `storage::column_index`
+    #[serde(default)]
+    pub indexed: bool,
+    ///Inserts and updates giving the column a value another row already holds get rejected
+    #[serde(default)]
+    pub unique: bool,
+    #[serde(default)]
+    pub encoding: ColumnEncoding,
+    pub compression: Option<ColumnCompression>,
+    #[serde(flatten)]
+    pub docs: Docs,
+}
+
+///Values the server computes for a column on insert, see `storage::server_columns`
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub enum ServerComputed {
+    ///UTC day of the row's timestamp as `YYYY-MM-DD`. Requires `add_timestamp_column`.
+    IngestDate,
+    ///Hex encoded SHA-256 hash over the row's values of these columns, in this order
+    RowHash(Vec<String>),
+}
+
+///Explains a table or column to consumers of `GET /schema`
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq)]
+pub struct Docs {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+}
+
+impl Docs {
+    pub fn is_empty(&self) -> bool {
+        self.description.is_none() && self.tags.is_empty()
+    }
 }
 
 #[derive(Debug)]
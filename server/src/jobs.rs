@@ -0,0 +1,281 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Display;
+use std::fs;
+use std::future::Future;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tracing::error;
+use warp::reply::Response;
+
+///Finished jobs beyond this many get dropped from the history, oldest first
+const MAX_JOB_HISTORY: usize = 500;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobKind {
+    ///Query running in the background, see `ResultStore`
+    Query,
+    ///Query writing its rows into a derived table
+    Materialize,
+    Backfill,
+    Retention,
+    Compaction,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    Running,
+    Done,
+    Failed,
+    Cancelled,
+    ///The server stopped while the job was running
+    Interrupted,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JobInfo {
+    pub id: String,
+    pub kind: JobKind,
+    pub description: String,
+    pub status: JobStatus,
+    pub started_at: u64,
+    pub finished_at: Option<u64>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Error)]
+pub enum JobError {
+    #[error("Unknown job {0}")]
+    UnknownJob(String),
+    #[error("Job {0} is not running")]
+    NotRunning(String),
+}
+
+///Tells whether the output of a job means it failed
+pub trait JobOutcome {
+    fn job_error(&self) -> Option<String>;
+}
+
+impl<T, E: Display> JobOutcome for Result<T, E> {
+    fn job_error(&self) -> Option<String> {
+        self.as_ref().err().map(|err| err.to_string())
+    }
+}
+
+impl JobOutcome for Response {
+    fn job_error(&self) -> Option<String> {
+        match self.status().is_success() {
+            true => None,
+            false => Some(self.status().to_string()),
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+///Long running operations of this node. The history gets written to `jobs.json` on every change,
+///so it survives restarts. Jobs which were running when the server stopped show up as interrupted.
+#[derive(Debug)]
+pub struct JobRegistry {
+    path: PathBuf,
+    jobs: Mutex<BTreeMap<String, JobInfo>>,
+    ///Dropping a running job's sender cancels it
+    cancellations: Mutex<HashMap<String, oneshot::Sender<()>>>,
+}
+
+impl JobRegistry {
+    pub fn file_path(db_root_path: &Path) -> PathBuf {
+        db_root_path.join("jobs.json")
+    }
+
+    pub fn new(db_root_path: &Path) -> Self {
+        Self {
+            path: JobRegistry::file_path(db_root_path),
+            jobs: Mutex::new(BTreeMap::new()),
+            cancellations: Mutex::new(HashMap::new()),
+        }
+    }
+
+    ///Continues the persisted history, if there is one
+    pub fn load(&self) -> io::Result<()> {
+        let bytes = match fs::read(&self.path) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        let mut jobs: BTreeMap<String, JobInfo> = serde_json::from_slice(&bytes)?;
+        for job in jobs.values_mut().filter(|job| job.status == JobStatus::Running) {
+            job.status = JobStatus::Interrupted;
+        }
+        *self.jobs.lock().unwrap() = jobs;
+        self.persist()
+    }
+
+    fn persist(&self) -> io::Result<()> {
+        let json = serde_json::to_vec(&*self.jobs.lock().unwrap())?;
+        //Replace the file as a whole, so a crash can't leave a half written history behind
+        let tmp_path = self.path.with_extension("json.tmp");
+        fs::write(&tmp_path, json).and_then(|_| fs::rename(&tmp_path, &self.path))
+    }
+
+    fn update(&self, id: &str, f: impl FnOnce(&mut JobInfo)) {
+        {
+            let mut jobs = self.jobs.lock().unwrap();
+            if let Some(job) = jobs.get_mut(id) {
+                f(job);
+            }
+            let finished = jobs.values().filter(|job| job.status != JobStatus::Running).count();
+            let expired = jobs
+                .values()
+                .filter(|job| job.status != JobStatus::Running)
+                .take(finished.saturating_sub(MAX_JOB_HISTORY))
+                .map(|job| job.id.clone())
+                .collect::<Vec<_>>();
+            for id in expired {
+                jobs.remove(&id);
+            }
+        }
+        if let Err(err) = self.persist() {
+            error!("Failed to persist job history: {}", err);
+        }
+    }
+
+    ///Registers a running job and returns its id. Ids sort by start time and end in 128 random bits, as the ids
+    ///of background queries name their results, so they mustn't be guessable.
+    pub fn start(&self, kind: JobKind, description: String) -> String {
+        let started_at = now();
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().subsec_nanos();
+        let id = format!("{:x}{:08x}{:032x}", started_at, nanos, rand::random::<u128>());
+        let job = JobInfo {
+            id: id.clone(),
+            kind,
+            description,
+            status: JobStatus::Running,
+            started_at,
+            finished_at: None,
+            error: None,
+        };
+        self.jobs.lock().unwrap().insert(id.clone(), job);
+        self.update(&id, |_| {});
+        id
+    }
+
+    ///Runs the work of a started job as its own task, and records how it went once it finishes.
+    ///The handle returns `None` if the job got cancelled.
+    pub fn run<T, F>(self: &Arc<Self>, id: &str, work: F) -> JoinHandle<Option<T>>
+    where
+        T: JobOutcome + Send + 'static,
+        F: Future<Output = T> + Send + 'static,
+    {
+        let (cancel_tx, cancel_rx) = oneshot::channel();
+        self.cancellations.lock().unwrap().insert(id.to_string(), cancel_tx);
+        let jobs = self.clone();
+        let job_id = id.to_string();
+        tokio::spawn(async move {
+            tokio::select! {
+                outcome = work => {
+                    jobs.finish(&job_id, outcome.job_error());
+                    Some(outcome)
+                }
+                _ = cancel_rx => None,
+            }
+        })
+    }
+
+    ///Starts a job and runs its work, see `start` and `run`
+    pub fn spawn<T, F>(self: &Arc<Self>, kind: JobKind, description: String, work: F) -> (String, JoinHandle<Option<T>>)
+    where
+        T: JobOutcome + Send + 'static,
+        F: Future<Output = T> + Send + 'static,
+    {
+        let id = self.start(kind, description);
+        let handle = self.run(&id, work);
+        (id, handle)
+    }
+
+    fn finish(&self, id: &str, error: Option<String>) {
+        self.cancellations.lock().unwrap().remove(id);
+        self.update(id, |job| {
+            if job.status != JobStatus::Running {
+                return;
+            }
+            job.status = match error {
+                Some(_) => JobStatus::Failed,
+                None => JobStatus::Done,
+            };
+            job.error = error;
+            job.finished_at = Some(now());
+        });
+    }
+
+    ///Stops waiting for the job. Work the storage layer already started still runs to completion.
+    pub fn cancel(&self, id: &str) -> Result<JobInfo, JobError> {
+        match self.info(id) {
+            None => return Err(JobError::UnknownJob(id.to_string())),
+            Some(job) if job.status != JobStatus::Running => return Err(JobError::NotRunning(id.to_string())),
+            Some(_) => {}
+        }
+        self.cancellations.lock().unwrap().remove(id);
+        self.update(id, |job| {
+            job.status = JobStatus::Cancelled;
+            job.finished_at = Some(now());
+        });
+        self.info(id).ok_or_else(|| JobError::UnknownJob(id.to_string()))
+    }
+
+    pub fn info(&self, id: &str) -> Option<JobInfo> {
+        self.jobs.lock().unwrap().get(id).cloned()
+    }
+
+    ///All jobs in the history, oldest first
+    pub fn list(&self) -> Vec<JobInfo> {
+        self.jobs.lock().unwrap().values().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::{JobKind, JobRegistry, JobStatus};
+
+    #[tokio::test]
+    async fn jobs_record_their_outcome_and_can_be_cancelled() {
+        let dir = tempfile::tempdir().unwrap();
+        let jobs = Arc::new(JobRegistry::new(dir.path()));
+
+        let (done, handle) = jobs.spawn(JobKind::Backfill, "backfill".into(), async { Ok::<_, String>(()) });
+        handle.await.unwrap().unwrap().unwrap();
+        let (failed, handle) = jobs.spawn(JobKind::Retention, "retention".into(), async { Err::<(), _>("disk full") });
+        handle.await.unwrap().unwrap().unwrap_err();
+        let (cancelled, handle) = jobs.spawn(JobKind::Query, "query".into(), async {
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            Ok::<_, String>(())
+        });
+        assert_eq!(jobs.cancel(&cancelled).unwrap().status, JobStatus::Cancelled);
+        assert!(handle.await.unwrap().is_none());
+        assert!(jobs.cancel(&cancelled).is_err());
+
+        assert_eq!(jobs.info(&done).unwrap().status, JobStatus::Done);
+        let failed = jobs.info(&failed).unwrap();
+        assert_eq!(failed.status, JobStatus::Failed);
+        assert_eq!(failed.error.as_deref(), Some("disk full"));
+
+        let running = jobs.start(JobKind::Materialize, "query into table".into());
+        let restarted = JobRegistry::new(dir.path());
+        restarted.load().unwrap();
+        assert_eq!(restarted.list().len(), 4);
+        assert_eq!(restarted.info(&running).unwrap().status, JobStatus::Interrupted);
+    }
+}
@@ -0,0 +1,213 @@
+use std::collections::HashSet;
+use std::future::Future;
+use std::time::Duration;
+
+use thiserror::Error;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tracing::{error, info, warn};
+
+#[derive(Debug, Error)]
+pub enum LifecycleError {
+    #[error("Subsystem {0} is registered twice")]
+    Duplicate(&'static str),
+    #[error("Subsystem {subsystem} depends on {dependency}, which isn't registered")]
+    UnknownDependency {
+        subsystem: &'static str,
+        dependency: &'static str,
+    },
+    #[error("Subsystems {} depend on each other", .0.join(", "))]
+    DependencyCycle(Vec<&'static str>),
+    #[error("Failed to start {subsystem}: {source}")]
+    StartFailed {
+        subsystem: &'static str,
+        source: anyhow::Error,
+    },
+}
+
+///Handed to every subsystem when it starts. Completes once the subsystem should stop.
+#[derive(Debug, Clone)]
+pub struct Shutdown(watch::Receiver<bool>);
+
+impl Shutdown {
+    pub async fn wait(&mut self) {
+        while !*self.0.borrow() {
+            //The sender only gets dropped along with the lifecycle, which stops everything as well
+            if self.0.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+type StartFn = Box<dyn FnOnce(Shutdown) -> anyhow::Result<JoinHandle<()>> + Send>;
+
+struct Subsystem {
+    name: &'static str,
+    dependencies: Vec<&'static str>,
+    start: StartFn,
+}
+
+///Starts the subsystems of a node, like the storage layer, the web listeners and background workers, after the
+///subsystems they depend on, and stops them in the reverse order. A subsystem which doesn't stop within
+///`shutdown_timeout` gets aborted, so one hanging subsystem can't keep the others from stopping.
+pub struct Lifecycle {
+    subsystems: Vec<Subsystem>,
+    shutdown_timeout: Duration,
+}
+
+///Subsystems which got started, in the order they started in
+pub struct Running {
+    started: Vec<(&'static str, watch::Sender<bool>, JoinHandle<()>)>,
+    shutdown_timeout: Duration,
+}
+
+impl Lifecycle {
+    pub fn new(shutdown_timeout: Duration) -> Self {
+        Self {
+            subsystems: Vec::new(),
+            shutdown_timeout,
+        }
+    }
+
+    ///Registers a subsystem. `start` runs once all of its dependencies got started and spawns the subsystem,
+    ///which stops once its `Shutdown` completes.
+    pub fn register<F>(&mut self, name: &'static str, dependencies: &[&'static str], start: F)
+    where
+        F: FnOnce(Shutdown) -> anyhow::Result<JoinHandle<()>> + Send + 'static,
+    {
+        self.subsystems.push(Subsystem {
+            name,
+            dependencies: dependencies.to_vec(),
+            start: Box::new(start),
+        });
+    }
+
+    ///Registers a background worker which runs until shutdown. It gets dropped at its next await point then.
+    pub fn register_worker<W>(&mut self, name: &'static str, dependencies: &[&'static str], worker: W)
+    where
+        W: Future<Output = ()> + Send + 'static,
+    {
+        self.register(name, dependencies, move |mut shutdown| {
+            Ok(tokio::spawn(async move {
+                tokio::select! {
+                    _ = worker => {},
+                    _ = shutdown.wait() => {},
+                }
+            }))
+        });
+    }
+
+    ///Orders the subsystems so every one comes after its dependencies, otherwise keeping the order they got registered in
+    fn startup_order(mut subsystems: Vec<Subsystem>) -> Result<Vec<Subsystem>, LifecycleError> {
+        let mut names = HashSet::new();
+        for subsystem in &subsystems {
+            if !names.insert(subsystem.name) {
+                return Err(LifecycleError::Duplicate(subsystem.name));
+            }
+        }
+        for subsystem in &subsystems {
+            if let Some(dependency) = subsystem.dependencies.iter().find(|dependency| !names.contains(*dependency)) {
+                return Err(LifecycleError::UnknownDependency {
+                    subsystem: subsystem.name,
+                    dependency,
+                });
+            }
+        }
+
+        let mut ordered: Vec<Subsystem> = Vec::with_capacity(subsystems.len());
+        while !subsystems.is_empty() {
+            let Some(next) = subsystems
+                .iter()
+                .position(|subsystem| subsystem.dependencies.iter().all(|dependency| ordered.iter().any(|started| started.name == *dependency)))
+            else {
+                return Err(LifecycleError::DependencyCycle(subsystems.iter().map(|subsystem| subsystem.name).collect()));
+            };
+            ordered.push(subsystems.remove(next));
+        }
+        Ok(ordered)
+    }
+
+    ///Starts all subsystems. If one of them fails to start, the ones started before it get stopped again.
+    pub async fn start(self) -> Result<Running, LifecycleError> {
+        let mut running = Running {
+            started: Vec::new(),
+            shutdown_timeout: self.shutdown_timeout,
+        };
+        for subsystem in Lifecycle::startup_order(self.subsystems)? {
+            let (shutdown_tx, shutdown_rx) = watch::channel(false);
+            match (subsystem.start)(Shutdown(shutdown_rx)) {
+                Ok(handle) => {
+                    info!("Started {}", subsystem.name);
+                    running.started.push((subsystem.name, shutdown_tx, handle));
+                }
+                Err(source) => {
+                    running.shutdown().await;
+                    return Err(LifecycleError::StartFailed {
+                        subsystem: subsystem.name,
+                        source,
+                    });
+                }
+            }
+        }
+        Ok(running)
+    }
+}
+
+impl Running {
+    ///Stops the subsystems one by one, the last one started first
+    pub async fn shutdown(self) {
+        for (name, shutdown_tx, mut handle) in self.started.into_iter().rev() {
+            let _ = shutdown_tx.send(true);
+            match tokio::time::timeout(self.shutdown_timeout, &mut handle).await {
+                Ok(Ok(())) => info!("Stopped {}", name),
+                Ok(Err(err)) if err.is_panic() => error!("{} panicked: {}", name, err),
+                Ok(Err(_)) => info!("Stopped {}", name),
+                Err(_) => {
+                    warn!("{} didn't stop within {:?}, aborting it", name, self.shutdown_timeout);
+                    handle.abort();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use super::{Lifecycle, LifecycleError};
+
+    #[tokio::test]
+    async fn subsystems_start_after_their_dependencies_and_stop_before_them() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let mut lifecycle = Lifecycle::new(Duration::from_millis(50));
+        for (name, dependencies) in [("web", &["storage"][..]), ("storage", &[][..]), ("compaction", &["storage"][..])] {
+            let events = events.clone();
+            lifecycle.register(name, dependencies, move |mut shutdown| {
+                events.lock().unwrap().push(format!("start {}", name));
+                Ok(tokio::spawn(async move {
+                    shutdown.wait().await;
+                    events.lock().unwrap().push(format!("stop {}", name));
+                }))
+            });
+        }
+        //Never stops by itself, nor when asked to
+        lifecycle.register("stuck", &["web"], |_| Ok(tokio::spawn(std::future::pending())));
+
+        lifecycle.start().await.unwrap().shutdown().await;
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec!["start storage", "start web", "start compaction", "stop compaction", "stop web", "stop storage"]
+        );
+
+        let mut lifecycle = Lifecycle::new(Duration::from_millis(50));
+        lifecycle.register_worker("retention", &["storage"], std::future::pending());
+        assert!(matches!(lifecycle.start().await, Err(LifecycleError::UnknownDependency { dependency: "storage", .. })));
+        let mut lifecycle = Lifecycle::new(Duration::from_millis(50));
+        lifecycle.register_worker("storage", &["web"], std::future::pending());
+        lifecycle.register_worker("web", &["storage"], std::future::pending());
+        assert!(matches!(lifecycle.start().await, Err(LifecycleError::DependencyCycle(_))));
+    }
+}
@@ -1,17 +1,78 @@
-use std::{path::{Path, PathBuf}, fs};
+use std::{net::SocketAddr, path::{Path, PathBuf}, fs, sync::{Arc, Mutex, RwLock, atomic::{AtomicBool, Ordering}}, time::{Duration, SystemTime, UNIX_EPOCH}};
 
-use crate::{storage::Container, query::code_runner::CodeRunner, command::Command};
+use crate::{storage::{Container, ContainerError, cell::Cell, s3_backend::S3Backend, wal_shipping::{self, WalShipper}, filter::{AsOf, FilterError}, data_dir_lock::DataDirLock, derived_tables::DerivedTables, migration, replica::ReplicaSnapshots, snapshot_restore, warmup::StartupTracker}, query::{abi::declared_columns, admission::QueryAdmission, code_runner::CodeRunner, wasm_error::WasmError, hook::BeforeInsertHook, instance_pool::{MapPools, DEFAULT_POOL_SIZE}, query_error::QueryError, map_result::MapResult}, command::{AckMode, Command, CommandLanes}, cluster::{shard_router::ShardRouter, membership::{Membership, self}, role::NodeRole, secret::ClusterSecret}, usage::UsageTracker, results::ResultStore, sessions::IngestSessions, jobs::{JobKind, JobRegistry}, schema_locks::{SchemaLocks, SchemaOperation}, query_audit::QueryAudit, alerts::AlertStore, lifecycle::{Lifecycle, Shutdown}, warnings::WarningCounters, web::NodeState};
 use anyhow::Context;
-use config::Configurator;
+use clap::{Parser, Subcommand};
+use config::{CompactionConfig, Configurator, SchemaConfig, StorageBackendConfig, WalShippingConfig};
 
-use tokio::sync::mpsc;
-use tracing::{error, debug, instrument, info};
+use tokio::sync::{mpsc, oneshot};
+use tracing::{error, debug, instrument, info, warn};
 
 mod storage;
 mod web;
 mod config;
 mod query;
 mod command;
+mod cluster;
+mod metrics;
+mod usage;
+mod results;
+mod sessions;
+mod jobs;
+mod schema_locks;
+mod query_audit;
+mod auth;
+mod alerts;
+mod lifecycle;
+mod warnings;
+
+///How often the retention policy gets enforced
+const RETENTION_INTERVAL: Duration = Duration::from_secs(60);
+///How often per API key usage counters get written to disk
+const USAGE_PERSIST_INTERVAL: Duration = Duration::from_secs(30);
+///How often expired results of background queries get deleted
+const RESULT_EXPIRY_INTERVAL: Duration = Duration::from_secs(60);
+///How often alert rules get checked for whether their interval passed
+const ALERT_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Parser)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Mode>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Mode {
+    ///Rebuilds column files, column_layout.json and the auto index, then exits
+    Repair {
+        ///Reconstruct everything from the write-ahead log
+        #[arg(long)]
+        from_wal: bool,
+    },
+    ///Restores the write-ahead log shipped to the bucket configured in wal_shipping and rebuilds the table from it,
+    ///or replaces the table with a snapshot taken by POST /admin/snapshot, then exits.
+    ///Restoring the shipped log requires a data directory without a write-ahead log.
+    Restore {
+        ///Download the log from the remote copy
+        #[arg(long)]
+        from_remote: bool,
+        ///Only restore what was shipped up to this point, as unix timestamp or RFC3339 date
+        #[arg(long, requires = "from_remote")]
+        until: Option<String>,
+        ///Snapshot directory to replace the table with, after checking its checksums and schema
+        #[arg(long, conflicts_with = "from_remote")]
+        from_snapshot: Option<PathBuf>,
+        ///Where to move the replaced table files to. Defaults to db.backup-<unix timestamp> next to the data directory.
+        #[arg(long, requires = "from_snapshot")]
+        backup: Option<PathBuf>,
+    },
+    ///Upgrades column files, the write-ahead log and metadata files to the current on-disk format, then exits
+    MigrateStorage {
+        ///Where to copy the data directory to before migrating. Defaults to db.backup-<unix timestamp> next to it.
+        #[arg(long)]
+        backup: Option<PathBuf>,
+    },
+}
 
 fn database_storage_root_path() -> PathBuf {
     let db_storage_base_path_str = std::env::var("DB_STORAGE_PATH").context("Missing DB_STORAGE_PATH environment variable").unwrap();
@@ -27,7 +88,303 @@ fn config_file_root_path() -> String {
     std::env::var("CONFIG_FILE_ROOT_PATH").context("Missing CONFIG_FILE_ROOT_PATH environment variable").unwrap()
 }
 
+///Base URL other nodes reach this node under
+fn node_url() -> String {
+    std::env::var("NODE_URL").unwrap_or_else(|_| "http://localhost:3030".into())
+}
+
+///Comma separated base URLs of the nodes to join the cluster through.
+///Further members are discovered via gossip.
+fn cluster_nodes() -> Vec<String> {
+    std::env::var("CLUSTER_NODES")
+        .map(|nodes| {
+            nodes
+                .split(',')
+                .map(|node| node.trim().to_string())
+                .filter(|node| !node.is_empty())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+///Secret shared by the nodes of a cluster, which they authenticate their gossip with
+fn cluster_secret() -> Option<ClusterSecret> {
+    std::env::var("CLUSTER_SECRET").ok().filter(|secret| !secret.is_empty()).map(ClusterSecret::new)
+}
+
+///Address the admin listener binds to. Keep it on localhost or an internal interface.
+fn admin_addr() -> SocketAddr {
+    std::env::var("ADMIN_ADDR")
+        .unwrap_or_else(|_| "127.0.0.1:3031".into())
+        .parse()
+        .context("ADMIN_ADDR is not a valid socket address")
+        .unwrap()
+}
+
+///How many queries may execute at the same time
+fn max_concurrent_queries() -> usize {
+    std::env::var("MAX_CONCURRENT_QUERIES").ok().and_then(|max| max.parse().ok()).unwrap_or(4)
+}
+
+///How many queries may wait for a free slot before further ones get rejected
+fn max_queued_queries() -> usize {
+    std::env::var("MAX_QUEUED_QUERIES").ok().and_then(|max| max.parse().ok()).unwrap_or(16)
+}
+
+///How many inserts the storage layer takes in a row before a waiting query or other command, see `CommandLanes`
+fn ingest_priority_weight() -> usize {
+    std::env::var("INGEST_PRIORITY_WEIGHT").ok().and_then(|weight| weight.parse().ok()).unwrap_or(8)
+}
+
+///How many instances of each map function are kept for reuse between rows and queries. 0 instantiates one per row.
+fn map_instance_pool_size() -> usize {
+    std::env::var("MAP_INSTANCE_POOL_SIZE").ok().and_then(|size| size.parse().ok()).unwrap_or(DEFAULT_POOL_SIZE)
+}
+
+///How long each subsystem gets to stop on shutdown before it gets aborted, see `Lifecycle`
+fn shutdown_timeout() -> Duration {
+    let secs = std::env::var("SHUTDOWN_TIMEOUT_SECS").ok().and_then(|secs| secs.parse().ok()).unwrap_or(30);
+    Duration::from_secs(secs)
+}
+
+///Repairs which rewrite stored data, like reassigning duplicate ids, are only allowed in maintenance mode
+fn maintenance_mode() -> bool {
+    std::env::var("MAINTENANCE_MODE").is_ok_and(|value| value == "true")
+}
+
+///Whether this node stores rows, or only answers queries
+fn node_role() -> NodeRole {
+    std::env::var("NODE_ROLE")
+        .map(|role| role.parse().map_err(anyhow::Error::msg).context("NODE_ROLE is not a valid role").unwrap())
+        .unwrap_or_default()
+}
+
+///Data directory of the primary which query nodes take their snapshots from
+fn replica_source_path() -> PathBuf {
+    let source = std::env::var("REPLICA_SOURCE_PATH").context("Query nodes need a REPLICA_SOURCE_PATH environment variable").unwrap();
+    PathBuf::from(source)
+}
+
+///How often query nodes take a new snapshot of the primary's table
+fn replica_sync_interval() -> Duration {
+    let secs = std::env::var("REPLICA_SYNC_SECS").ok().and_then(|secs| secs.parse().ok()).unwrap_or(30);
+    Duration::from_secs(secs)
+}
+
+///Replaces the table of a query node with a new snapshot once every `replica_sync_interval`.
+///A failed sync keeps serving the previous snapshot.
+async fn run_replica_sync(tx: mpsc::Sender<Command>, snapshots: ReplicaSnapshots, config: SchemaConfig) {
+    let snapshots = Arc::new(Mutex::new(snapshots));
+    let mut interval = tokio::time::interval(replica_sync_interval());
+    //The first tick completes right away, the initial snapshot is already loaded
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let sync_snapshots = snapshots.clone();
+        let sync_config = config.clone();
+        let result = tokio::task::spawn_blocking(move || {
+            let path = sync_snapshots.lock().unwrap().take()?;
+            Container::open_replica(&path, sync_config).map(|container| (path, container))
+        })
+        .await;
+        let (path, container) = match result {
+            Ok(Ok(synced)) => synced,
+            Ok(Err(err)) => {
+                error!("Failed to sync replica: {}", err);
+                continue;
+            }
+            Err(err) => {
+                error!("Replica sync panicked: {}", err);
+                continue;
+            }
+        };
+        let (resp_tx, resp_rx) = oneshot::channel();
+        if tx.send(Command::ReplicaSynced { container: Box::new(container), responder: resp_tx }).await.is_err() || resp_rx.await.is_err() {
+            error!("Failed to hand replica snapshot to the storage layer");
+            continue;
+        }
+        info!("Serving queries from {}", path.display());
+        snapshots.lock().unwrap().remove_all_but(&path);
+    }
+}
+
+///Enforces the retention policy once every RETENTION_INTERVAL. Every run is a job.
+async fn run_retention(tx: mpsc::Sender<Command>, jobs: Arc<JobRegistry>) {
+    let mut interval = tokio::time::interval(RETENTION_INTERVAL);
+    loop {
+        interval.tick().await;
+        let tx = tx.clone();
+        let (_, handle) = jobs.spawn(JobKind::Retention, "enforce retention policy".to_string(), async move {
+            let (resp_tx, resp_rx) = oneshot::channel();
+            tx.send(Command::Retention { dry_run: false, responder: resp_tx })
+                .await
+                .map_err(|err| err.to_string())?;
+            resp_rx.await.map_err(|err| err.to_string())?.map_err(|err| err.to_string())
+        });
+        if let Ok(Some(Ok(report))) = handle.await {
+            info!("Retention deleted {} rows ({} bytes)", report.rows, report.bytes);
+        }
+    }
+}
+
+///Uploads what got appended to the write-ahead log once every interval. Failed uploads get retried on the next run.
+///Only the log's length in between storage commands gets shipped, as a rollback cuts off what a command appended.
+async fn run_wal_shipping(tx: mpsc::Sender<Command>, root_path: PathBuf, config: WalShippingConfig) {
+    let shipper = tokio::task::spawn_blocking(move || {
+        let remote = S3Backend::new(&config.s3, &root_path)?;
+        WalShipper::new(&root_path, Arc::new(remote))
+    })
+    .await;
+    let shipper = match shipper {
+        Ok(Ok(shipper)) => {
+            info!("Shipping the write-ahead log, {} bytes got shipped before", shipper.manifest().shipped_len());
+            Arc::new(Mutex::new(shipper))
+        }
+        Ok(Err(err)) => {
+            error!("Failed to start shipping the write-ahead log: {}", err);
+            return;
+        }
+        Err(err) => {
+            error!("Starting to ship the write-ahead log panicked: {}", err);
+            return;
+        }
+    };
+    let mut interval = tokio::time::interval(Duration::from_secs(config.interval_secs));
+    loop {
+        interval.tick().await;
+        let (resp_tx, resp_rx) = oneshot::channel();
+        if tx.send(Command::WalLen { responder: resp_tx }).await.is_err() {
+            return;
+        }
+        let Ok(committed_len) = resp_rx.await else {
+            continue;
+        };
+        let run_shipper = shipper.clone();
+        match tokio::task::spawn_blocking(move || run_shipper.lock().unwrap().ship(committed_len)).await {
+            Ok(Ok(0)) => {}
+            Ok(Ok(bytes)) => debug!("Shipped {} bytes of the write-ahead log", bytes),
+            Ok(Err(err)) => warn!("Failed to ship the write-ahead log, retrying on the next run: {}", err),
+            Err(err) => error!("Shipping the write-ahead log panicked: {}", err),
+        }
+    }
+}
+
+///Compacts the column files once every interval, as long as enough rows got deleted. Every run is a job.
+///Runs get skipped while another operation holds the schema lock.
+async fn run_compaction(tx: mpsc::Sender<Command>, jobs: Arc<JobRegistry>, schema_locks: Arc<SchemaLocks>, config: CompactionConfig) {
+    let mut interval = tokio::time::interval(Duration::from_secs(config.interval_secs));
+    loop {
+        interval.tick().await;
+        let lock = match schema_locks.try_acquire(SchemaOperation::Compaction, "compact column files") {
+            Ok(lock) => lock,
+            Err(err) => {
+                info!("Skipping compaction: {}", err);
+                continue;
+            }
+        };
+        let tx = tx.clone();
+        let (_, handle) = jobs.spawn(JobKind::Compaction, "compact column files".to_string(), async move {
+            let _lock = lock;
+            let (resp_tx, resp_rx) = oneshot::channel();
+            tx.send(Command::Compact { min_deleted_rows: config.min_deleted_rows, responder: resp_tx })
+                .await
+                .map_err(|err| err.to_string())?;
+            resp_rx.await.map_err(|err| err.to_string())?.map_err(|err| err.to_string())
+        });
+        if let Ok(Some(Ok(report))) = handle.await {
+            if report.rows > 0 {
+                info!(
+                    "Compaction dropped {} rows, column files shrank from {} to {} bytes",
+                    report.rows, report.bytes_before, report.bytes_after
+                );
+            }
+        }
+    }
+}
+
+///Writes the usage counters to disk once every USAGE_PERSIST_INTERVAL, and a last time on shutdown
+async fn run_usage_persistence(usage: Arc<UsageTracker>, mut shutdown: Shutdown) {
+    let mut interval = tokio::time::interval(USAGE_PERSIST_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {},
+            _ = shutdown.wait() => break,
+        }
+        if let Err(err) = usage.persist() {
+            error!("Failed to persist usage counters: {}", err);
+        }
+    }
+    if let Err(err) = usage.persist() {
+        error!("Failed to persist usage counters on shutdown: {}", err);
+    }
+}
+
+///Deletes expired results of background queries and idle ingest sessions once every RESULT_EXPIRY_INTERVAL
+async fn run_result_expiry(results: Arc<ResultStore>, sessions: Arc<IngestSessions>) {
+    let mut interval = tokio::time::interval(RESULT_EXPIRY_INTERVAL);
+    loop {
+        interval.tick().await;
+        results.expire();
+        sessions.expire();
+    }
+}
+
+///Evaluates every alert rule once its interval passed, and notifies its target when it starts or stops firing
+async fn run_alerts(tx: mpsc::Sender<Command>, alerts: Arc<AlertStore>) {
+    let mut interval = tokio::time::interval(ALERT_CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        for (name, rule) in alerts.due(now) {
+            let outcome = async {
+                let filter = rule.query_filter(now).map_err(|err| err.to_string())?;
+                let (resp_tx, resp_rx) = oneshot::channel();
+                tx.send(Command::ScanRows { filter, responder: resp_tx })
+                    .await
+                    .map_err(|err| err.to_string())?;
+                let scan = resp_rx.await.map_err(|err| err.to_string())?.map_err(|err| err.to_string())?;
+                Ok(rule.aggregate(&scan.rows))
+            }
+            .await;
+            if let Err(err) = &outcome {
+                warn!("Failed to evaluate alert {}: {}", name, err);
+            }
+            let Some(notification) = alerts.record(&name, now, outcome) else {
+                continue;
+            };
+            info!("Alert {} is {:?} at {:?}", name, notification.status, notification.value);
+            let delivery = alerts::notify(&rule.target, &notification).await.map_err(|err| err.to_string());
+            if let Err(err) = &delivery {
+                warn!("Failed to notify about alert {}, retrying on the next evaluation: {}", name, err);
+            }
+            alerts.notified(&notification, delivery);
+        }
+    }
+}
+
 #[instrument]
+///Rebuilds the main table as it was at the point, for queries with `as_of`
+fn past_table(storage_manager: &Container, as_of: AsOf) -> Result<Container, QueryError> {
+    if !storage_manager.is_warm() {
+        return Err(ContainerError::WarmingUp.into());
+    }
+    storage_manager.check_as_of(as_of)?;
+    Ok(storage_manager.as_of(as_of)?)
+}
+
+///Logs the map functions reading columns which a rename or drop just took away. Queries reject them until they get uploaded again.
+fn warn_about_broken_map_fns(storage_manager: &Container) {
+    let Ok(code_runner) = CodeRunner::new(compiled_map_fn_path().into()) else {
+        return;
+    };
+    let schema_version = storage_manager.schema_version();
+    for fn_name in code_runner.map_fns().unwrap_or_default() {
+        if let Err(err) = code_runner.check_columns(&fn_name, &schema_version, |columns| storage_manager.missing_columns(columns)) {
+            warn!("Map function {} broke: {}", fn_name, err);
+        }
+    }
+}
+
 fn ensure_folders(root_path: &str) -> Result<(), std::io::Error> {
     let db_path = Path::new(root_path).join("db");
     if db_path.exists() {
@@ -43,44 +400,327 @@ fn ensure_folders(root_path: &str) -> Result<(), std::io::Error> {
 #[tokio::main]
 async fn main() -> anyhow::Result<()>{
     tracing_subscriber::fmt::init();
+
+    let (manager_tx, rx) = mpsc::channel(8192);
+    let (ingest_tx, ingest_rx) = mpsc::channel(8192);
+    let mut lanes = CommandLanes::new(ingest_rx, rx, ingest_priority_weight());
+    let usage = Arc::new(UsageTracker::new(&database_storage_root_path()));
+    let (signal_tx, mut signal_rx) = mpsc::channel(1);
+    let stopping = AtomicBool::new(false);
     ctrlc::set_handler(move || {
-        std::process::exit(0)
+        //A second Ctrl-C doesn't wait for the subsystems to stop
+        if stopping.swap(true, Ordering::SeqCst) {
+            std::process::exit(1)
+        }
+        let _ = signal_tx.try_send(());
     })
         .expect("Error setting Ctrl-C handler");
 
+    let cli = Cli::parse();
     let database_storage_path = database_storage_root_path();
+    //Held until the process exits
+    let _data_dir_lock = DataDirLock::acquire(&database_storage_path).context("Failed to lock the data directory")?;
+
+    if let Some(Mode::Repair { from_wal }) = cli.command {
+        if !from_wal {
+            anyhow::bail!("Repair currently only supports --from-wal");
+        }
+        let config = Configurator::new(&config_file_root_path())
+            .load()
+            .context("Failed to load ./schema.json")?;
+        let restored_rows = Container::repair_from_wal(&database_storage_path, &config.storage_backend)
+            .context("Failed to repair from write-ahead log")?;
+        info!("Restored {} rows from write-ahead log", restored_rows);
+        return Ok(());
+    }
+
+    if let Some(Mode::Restore { from_snapshot: Some(snapshot_path), backup, .. }) = &cli.command {
+        let config = Configurator::new(&config_file_root_path())
+            .load()
+            .context("Failed to load ./schema.json")?;
+        let backup_path = backup.clone().unwrap_or_else(|| {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+            database_storage_path.with_file_name(format!("db.backup-{}", now))
+        });
+        let report = snapshot_restore::restore_snapshot(snapshot_path, &database_storage_path, &config, &backup_path)
+            .context("Failed to restore the snapshot")?;
+        info!(
+            "Restored {} rows from {}. The {} replaced files are kept in {}",
+            report.rows,
+            snapshot_path.display(),
+            report.replaced.len(),
+            backup_path.display()
+        );
+        return Ok(());
+    }
+
+    if let Some(Mode::Restore { from_remote, until, .. }) = &cli.command {
+        if !from_remote {
+            anyhow::bail!("Restore needs either --from-remote or --from-snapshot");
+        }
+        let until = match until {
+            Some(until) => Some(
+                until
+                    .parse::<i64>()
+                    .ok()
+                    .or_else(|| Cell::parse_timestamp(until))
+                    .context("--until is neither a unix timestamp nor an RFC3339 date")?,
+            ),
+            None => None,
+        };
+        let config = Configurator::new(&config_file_root_path())
+            .load()
+            .context("Failed to load ./schema.json")?;
+        let shipping_config = config.wal_shipping.context("Restoring requires wal_shipping in ./schema.json")?;
+        let remote = S3Backend::new(&shipping_config.s3, &database_storage_path).context("Failed to connect to the remote copy")?;
+        let restored_bytes = wal_shipping::restore(&database_storage_path, &remote, until)
+            .context("Failed to restore the write-ahead log")?;
+        let restored_rows = Container::repair_from_wal(&database_storage_path, &config.storage_backend)
+            .context("Failed to rebuild the table from the restored write-ahead log")?;
+        info!("Restored {} rows from {} bytes of shipped write-ahead log", restored_rows, restored_bytes);
+        return Ok(());
+    }
+
+    if let Some(Mode::MigrateStorage { backup }) = cli.command {
+        let backup_path = backup.unwrap_or_else(|| {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+            database_storage_path.with_file_name(format!("db.backup-{}", now))
+        });
+        let report = migration::migrate_storage(&database_storage_path, &backup_path)
+            .context("Failed to migrate storage")?;
+        info!(
+            "Migrated {} files, {} were up to date. Originals are kept in {}",
+            report.migrated.len(),
+            report.up_to_date,
+            backup_path.display()
+        );
+        return Ok(());
+    }
 
-    let (manager_tx, mut rx) = mpsc::channel(8192);
     let web_tx = manager_tx.clone();
-    let mut all_workers = vec![];
+    let mut lifecycle = Lifecycle::new(shutdown_timeout());
 
     ensure_folders(&config_file_root_path())?;
+    usage.load().context("Failed to load usage counters")?;
+    let persisted_usage = usage.clone();
+    lifecycle.register("usage persistence", &[], move |shutdown| Ok(tokio::spawn(run_usage_persistence(persisted_usage, shutdown))));
+    let results = Arc::new(ResultStore::new(&database_storage_path));
+    results.reset().context("Failed to clear results of background queries")?;
+    let sessions = Arc::new(IngestSessions::new(&database_storage_path));
+    sessions.reset().context("Failed to clear rows of ingest sessions")?;
+    lifecycle.register_worker("result expiry", &[], run_result_expiry(results.clone(), sessions.clone()));
+    let jobs = Arc::new(JobRegistry::new(&database_storage_path));
+    jobs.load().context("Failed to load job history")?;
+    let schema_locks = Arc::new(SchemaLocks::default());
+    let audit = Arc::new(QueryAudit::new(&database_storage_path, Path::new(compiled_map_fn_path())));
+    let alerts = Arc::new(AlertStore::new(&database_storage_path));
+    alerts.load().context("Failed to load alert rules")?;
 
     let configurator = Configurator::new(&config_file_root_path());
     let config = configurator.load().context("Failed to load ./schema.json")?;
-    let url_manager = tokio::spawn(async move {
-        let mut storage_manager = Container::new(&database_storage_path, config).expect("failed to load container");
-        while let Some(command) = rx.recv().await {
+    let max_response_bytes = config.max_response_bytes;
+    let auth = auth::start(config.auth.as_ref()).await?;
+    let role = node_role();
+    //Query nodes read their table from a snapshot instead of the data directory
+    let table_path = match role {
+        NodeRole::Primary => database_storage_path.clone(),
+        NodeRole::Query => {
+            if config.storage_backend != StorageBackendConfig::Local {
+                anyhow::bail!("Query nodes only support the Local storage backend");
+            }
+            let mut snapshots = ReplicaSnapshots::new(&replica_source_path(), &database_storage_path);
+            snapshots.reset().context("Failed to clear replica snapshots")?;
+            let path = snapshots.take().context("Failed to take a snapshot of the primary")?;
+            lifecycle.register_worker("replica sync", &["storage"], run_replica_sync(manager_tx.clone(), snapshots, config.clone()));
+            path
+        }
+    };
+    info!("Starting as {} node", role);
+    let router = Arc::new(RwLock::new(ShardRouter::new(node_url(), config.shard_key.clone(), vec![node_url()])));
+    let cluster_secret = cluster_secret();
+    if cluster_secret.is_none() && !cluster_nodes().is_empty() {
+        anyhow::bail!("Joining a cluster via CLUSTER_NODES needs the CLUSTER_SECRET all nodes share");
+    }
+    let membership = Arc::new(Mutex::new(Membership::new(node_url(), cluster_nodes())));
+    if role == NodeRole::Primary && config.retention_secs.is_some() {
+        lifecycle.register_worker("retention", &["storage"], run_retention(manager_tx.clone(), jobs.clone()));
+    }
+    if let (NodeRole::Primary, Some(compaction_config)) = (role, config.compaction.clone()) {
+        lifecycle.register_worker("compaction", &["storage"], run_compaction(manager_tx.clone(), jobs.clone(), schema_locks.clone(), compaction_config));
+    }
+    if role == NodeRole::Primary {
+        lifecycle.register_worker("alerts", &["storage"], run_alerts(manager_tx.clone(), alerts.clone()));
+    }
+    if let (NodeRole::Primary, Some(shipping_config)) = (role, config.wal_shipping.clone()) {
+        lifecycle.register_worker("wal shipping", &["storage"], run_wal_shipping(manager_tx.clone(), database_storage_path.clone(), shipping_config));
+    }
+    let mut before_insert_hook = config
+        .before_insert_hook
+        .clone()
+        .map(|hook_name| BeforeInsertHook::new(hook_name, compiled_map_fn_path().into()));
+    let mut map_pools = MapPools::new(compiled_map_fn_path().into(), map_instance_pool_size());
+    let startup = Arc::new(StartupTracker::new());
+    let storage_startup = startup.clone();
+    let warnings = Arc::new(WarningCounters::default());
+    let storage_warnings = warnings.clone();
+    let warmup_tx = manager_tx.clone();
+    lifecycle.register("storage", &[], move |mut shutdown| Ok(tokio::spawn(async move {
+        //Inserts get accepted right away, while the stored records get read in the background
+        let (mut storage_manager, warmup_plan) = match role {
+            NodeRole::Primary => Container::open_cold(&table_path, config),
+            NodeRole::Query => Container::open_replica_cold(&table_path, config),
+        }
+        .expect("failed to load container");
+        let warmup_startup = storage_startup.clone();
+        tokio::task::spawn_blocking(move || {
+            let result = warmup_plan.run(&warmup_startup);
+            if warmup_tx.blocking_send(Command::WarmupDone { result }).is_err() {
+                error!("Failed to hand loaded columns to the storage layer");
+            }
+        });
+        let mut derived_tables = DerivedTables::new(&database_storage_path);
+        loop {
+            let command = tokio::select! {
+                command = lanes.recv() => command,
+                _ = shutdown.wait() => None,
+            };
+            let Some(command) = command else {
+                break;
+            };
             debug!("Received Command: {:?}", command);
             match command {
-                Command::Index { params, responder } => {
-                    if let Err(err) = storage_manager.index(params) {
+                //A replica sync already replaced the table the warm-up was loading
+                Command::WarmupDone { .. } if storage_manager.is_warm() => {},
+                Command::WarmupDone { result } => {
+                    let mut loaded = result.expect("failed to load columns");
+                    if role == NodeRole::Query {
+                        loaded.trim_to_shortest();
+                    }
+                    storage_manager.finish_warmup(loaded).expect("failed to install loaded columns");
+                    storage_startup.set_ready();
+                },
+                Command::ReplicaSynced { container, responder } => {
+                    storage_manager = *container;
+                    storage_startup.set_ready();
+                    if responder.send(()).is_err() {
+                        error!("Error while confirming replica sync");
+                    }
+                },
+                Command::Index { params, ack, lineage, responder } => {
+                    let hook_result = match before_insert_hook.as_mut() {
+                        Some(hook) => hook.apply(params),
+                        None => Ok(params),
+                    };
+                    let result = hook_result
+                        .and_then(|params| {
+                            let coerced = storage_manager.coerced_values(&params);
+                            storage_manager.index_with_lineage(params, &lineage).map(|_| (storage_manager.write_seq(), coerced))
+                        })
+                        .and_then(|(seq, coerced)| {
+                            if ack == AckMode::Durable {
+                                storage_manager.sync()?;
+                            }
+                            storage_warnings.record(&coerced);
+                            Ok((seq, coerced))
+                        });
+                    if let Err(err) = &result {
                         error!("{}", err);
-                        if let Err(_) = responder.send(Err(err)) {
-                            error!("Error while sending storage response");
+                        if ack == AckMode::Received {
+                            storage_manager.count_unacknowledged_failure(1);
                         }
-                    } else {
-                        if responder.send(Ok(())).is_err() {
-                            error!("Error while sending storage response");
+                    }
+                    //Clients which asked for ack=received don't wait for the result
+                    if responder.send(result).is_err() && ack != AckMode::Received {
+                        error!("Error while sending storage response");
+                    }
+                },
+                Command::Validate { params, responder } => {
+                    let hook_result = match before_insert_hook.as_mut() {
+                        Some(hook) => hook.apply(params),
+                        None => Ok(params),
+                    };
+                    if responder.send(hook_result.and_then(|params| storage_manager.validate(params))).is_err() {
+                        error!("Error while sending validation result");
+                    }
+                },
+                Command::Update { id, params, responder } => {
+                    let hook_result = match before_insert_hook.as_mut() {
+                        Some(hook) => hook.apply(params),
+                        None => Ok(params),
+                    };
+                    let result = hook_result
+                        .and_then(|params| storage_manager.update(id, params))
+                        .map(|_| storage_manager.write_seq());
+                    if let Err(err) = &result {
+                        error!("Failed to update row {}: {}", id, err);
+                    }
+                    if responder.send(result).is_err() {
+                        error!("Error while sending update response");
+                    }
+                },
+                Command::Delete { id, responder } => {
+                    let result = storage_manager.delete(id).map(|_| storage_manager.write_seq());
+                    if let Err(err) = &result {
+                        error!("Failed to delete row {}: {}", id, err);
+                    }
+                    if responder.send(result).is_err() {
+                        error!("Error while sending delete response");
+                    }
+                },
+                Command::Transaction { rows, ack, lineage, responder } => {
+                    let row_count = rows.len();
+                    let hook_result = match before_insert_hook.as_mut() {
+                        Some(hook) => rows
+                            .into_iter()
+                            .enumerate()
+                            .map(|(row, params)| {
+                                hook.apply(params).map_err(|err| ContainerError::TransactionAborted {
+                                    row,
+                                    source: Box::new(err),
+                                })
+                            })
+                            .collect::<Result<Vec<_>, _>>(),
+                        None => Ok(rows),
+                    };
+                    let result = hook_result
+                        .and_then(|rows| storage_manager.index_transaction_with_lineage(rows, &lineage))
+                        .and_then(|committed| {
+                            if ack == AckMode::Durable {
+                                storage_manager.sync()?;
+                            }
+                            storage_warnings.record(&committed.warnings);
+                            Ok(committed)
+                        });
+                    if let Err(err) = &result {
+                        error!("{}", err);
+                        if ack == AckMode::Received {
+                            storage_manager.count_unacknowledged_failure(row_count);
                         }
                     }
+                    if responder.send(result).is_err() && ack != AckMode::Received {
+                        error!("Error while sending transaction response");
+                    }
                 },
                 Command::AddMapFn {fn_name, source_code, responder } => {
                     debug!("Adding new Map Function: {}", fn_name);
+                    if let Some(hook) = before_insert_hook.as_mut() {
+                        hook.invalidate(&fn_name);
+                    }
+                    map_pools.invalidate(&fn_name);
     
                     let code_runner = CodeRunner::new(compiled_map_fn_path().into()).expect("Failed to instatiate Code pipeline");
 
-                    match code_runner.compile_and_store(&source_code, &fn_name) {
+                    //Checked against the table, so a function reading a renamed or dropped column fails on upload
+                    let missing = declared_columns(&source_code)
+                        .map(|columns| storage_manager.missing_columns(&columns))
+                        .unwrap_or_default();
+                    let result = match missing.is_empty() {
+                        true => code_runner
+                            .compile_and_store(&source_code, &fn_name)
+                            .and_then(|_| code_runner.record_schema_version(&fn_name, &storage_manager.schema_version())),
+                        false => Err(WasmError::MissingColumns(missing)),
+                    };
+                    match result {
                         Ok(()) => {
                             if responder.send(Ok(())).is_err() {
                                 error!("Error while sending wasm response");
@@ -93,30 +733,85 @@ async fn main() -> anyhow::Result<()>{
                         }
                     }
                 },
-                Command::InvokeMap { fn_name, responder } => {
+                Command::InvokeMap { fn_name, table, filter, responder } => {
                     debug!("Execute Map function: {}", fn_name);
                     let fn_name = fn_name.clone();
 
                     let code_runner = CodeRunner::new(compiled_map_fn_path().into()).expect("Failed to instatiate Code pipeline");
 
+                    let past;
+                    let source = match (&table, filter.as_of) {
+                        (Some(_), Some(_)) => Err(FilterError::AsOfDerivedTable.into()),
+                        (None, Some(as_of)) => match past_table(&storage_manager, as_of) {
+                            Ok(table) => {
+                                past = table;
+                                Ok(&past)
+                            }
+                            Err(err) => Err(err),
+                        },
+                        (None, None) if !storage_manager.is_warm() => Err(ContainerError::WarmingUp.into()),
+                        (None, None) => Ok(&storage_manager),
+                        (Some(table), None) => match derived_tables.get(&storage_manager, table) {
+                            Ok(Some(derived_table)) => Ok(derived_table),
+                            Ok(None) => Err(QueryError::UnknownTable(table.to_string())),
+                            Err(err) => Err(err.into()),
+                        },
+                    };
+                    let source = match source {
+                        Ok(source) => source,
+                        Err(err) => {
+                            if responder.send(Err(err)).is_err() {
+                                error!("Error while sending query response");
+                            }
+                            continue;
+                        }
+                    };
+
+                    if let Err(err) = code_runner.check_columns(&fn_name, &source.schema_version(), |columns| source.missing_columns(columns)) {
+                        if responder.send(Err(err.into())).is_err() {
+                            error!("Error while sending query response");
+                        }
+                        continue;
+                    }
+
+                    //Rows handed to the map function only hold the columns it reads, the selected ones get assembled in full afterwards
+                    let columns = code_runner.columns_read(&fn_name).unwrap_or_else(|err| {
+                        warn!("Failed to find the columns {} reads, passing all of them: {}", fn_name, err);
+                        None
+                    });
                     let (tx, mut rx) = mpsc::channel(10000);
 
-                    storage_manager.query(tx).await;
+                    if let Err(err) = source.query(tx, &filter, columns.as_deref()).await {
+                        if responder.send(Err(err.into())).is_err() {
+                            error!("Error while sending query response");
+                        }
+                        continue;
+                    }
                     debug!("Queried Storage Manager");
 
-                    let mut rows = vec!();
+                    let mut result = MapResult::default();
+                    let mut selected = vec![];
 
                     while let Some(payload) = rx.recv().await {
                         debug!("Received Storage Manager Callback");
+                        //Nobody waits for the result anymore, e.g. because its job got cancelled
+                        if responder.is_closed() {
+                            break;
+                        }
                         match payload {
-                            Command::QueryRow { row } => {
+                            Command::QueryRow { position, row } => {
                                 debug!("Running Code for {:?}", row);
-                                match code_runner.execute_map(&fn_name, row.clone()) {
+                                result.scanned += 1;
+                                match map_pools.run(&fn_name, row.clone()) {
                                     Ok(should_include_row) => if should_include_row {
-                                        rows.push(row);
+                                        match columns {
+                                            Some(_) => selected.push(position),
+                                            None => result.rows.push(row),
+                                        }
                                     },
                                     Err(err) => {
                                         error!("Error while trying to index row: {}", err);
+                                        result.record_error(row.get("id").and_then(|id| id.as_int()).copied(), &err);
                                     }
                                 }
                             },
@@ -126,20 +821,225 @@ async fn main() -> anyhow::Result<()>{
                         }
                     }
                     debug!("Received all rows");
-                    match responder.send(Ok(rows)) {
+                    if columns.is_some() {
+                        result.rows = source.rows(selected);
+                    }
+                    match responder.send(Ok(result)) {
                         Ok(()) => {},
                         Err(err) => {
                             error!("Failed to send rows: {:?}", err);
                         }
                     }
                 },
-                Command::QueryRow { row: _row } => panic!("Unexpected Code Reached: Command::QueryRow"),
+                Command::AppendRows { table, rows, responder } => {
+                    if responder.is_closed() {
+                        continue;
+                    }
+                    let result = derived_tables
+                        .get_or_create(&storage_manager, &table)
+                        .and_then(|derived_table| derived_table.append_rows(rows));
+                    if let Err(err) = &result {
+                        error!("Failed to write rows into {}: {}", table, err);
+                    }
+                    if responder.send(result).is_err() {
+                        error!("Error while sending append response");
+                    }
+                },
+                Command::Distinct { column, limit, filter, cast, responder } => {
+                    let result = match (filter.as_of, storage_manager.is_warm()) {
+                        (Some(as_of), _) => past_table(&storage_manager, as_of)
+                            .and_then(|past| past.distinct(&column, limit, &filter, cast).map_err(QueryError::from)),
+                        (None, true) => storage_manager.distinct(&column, limit, &filter, cast).map_err(QueryError::from),
+                        (None, false) => Err(ContainerError::WarmingUp.into()),
+                    };
+                    if responder.send(result).is_err() {
+                        error!("Error while sending distinct values");
+                    }
+                },
+                Command::ScanRows { filter, responder } => {
+                    let result = match (filter.as_of, storage_manager.is_warm()) {
+                        (Some(as_of), _) => past_table(&storage_manager, as_of)
+                            .and_then(|past| past.scan(&filter).map_err(QueryError::from)),
+                        (None, true) => storage_manager.scan(&filter).map_err(QueryError::from),
+                        (None, false) => Err(ContainerError::WarmingUp.into()),
+                    };
+                    if responder.send(result).is_err() {
+                        error!("Error while sending rows");
+                    }
+                },
+                Command::PageRows { filter, cursor, limit, responder } => {
+                    let result = match (filter.as_of, storage_manager.is_warm()) {
+                        (Some(_), _) => Err(FilterError::AsOfPage.into()),
+                        (None, true) => storage_manager.page(&filter, cursor, limit).map_err(QueryError::from),
+                        (None, false) => Err(ContainerError::WarmingUp.into()),
+                    };
+                    if responder.send(result).is_err() {
+                        error!("Error while sending page of rows");
+                    }
+                },
+                Command::Lookup { column, value, responder } => {
+                    let result = match storage_manager.is_warm() {
+                        true => storage_manager.lookup(&column, &value).map_err(QueryError::from),
+                        false => Err(ContainerError::WarmingUp.into()),
+                    };
+                    if responder.send(result).is_err() {
+                        error!("Error while sending looked up rows");
+                    }
+                },
+                Command::FindByKey { value, responder } => {
+                    let result = match storage_manager.is_warm() {
+                        true => storage_manager.find_by_key(&value).map_err(QueryError::from),
+                        false => Err(ContainerError::WarmingUp.into()),
+                    };
+                    if responder.send(result).is_err() {
+                        error!("Error while sending row found by key");
+                    }
+                },
+                Command::CommittedSeq { responder } => {
+                    if responder.send(storage_manager.write_seq()).is_err() {
+                        error!("Error while sending committed sequence");
+                    }
+                },
+                Command::WalLen { responder } => {
+                    if responder.send(storage_manager.wal_len()).is_err() {
+                        error!("Error while sending length of the write-ahead log");
+                    }
+                },
+                Command::Snapshot { responder } => {
+                    let result = storage_manager.snapshot();
+                    if let Err(err) = &result {
+                        error!("Snapshot failed: {}", err);
+                    }
+                    if responder.send(result).is_err() {
+                        error!("Error while sending snapshot path");
+                    }
+                },
+                Command::Metrics { responder } => {
+                    if responder.send(storage_manager.metrics()).is_err() {
+                        error!("Error while sending metrics");
+                    }
+                },
+                Command::Schema { responder } => {
+                    if responder.send(storage_manager.schema()).is_err() {
+                        error!("Error while sending schema");
+                    }
+                },
+                Command::RenameColumn { from, to, responder } => {
+                    let result = storage_manager.rename_column(&from, &to);
+                    match &result {
+                        Ok(()) => warn_about_broken_map_fns(&storage_manager),
+                        Err(err) => error!("Failed to rename column {} to {}: {}", from, to, err),
+                    }
+                    if responder.send(result).is_err() {
+                        error!("Error while sending rename response");
+                    }
+                },
+                Command::DropColumn { name, responder } => {
+                    let result = storage_manager.drop_column(&name);
+                    match &result {
+                        Ok(()) => warn_about_broken_map_fns(&storage_manager),
+                        Err(err) => error!("Failed to drop column {}: {}", name, err),
+                    }
+                    if responder.send(result).is_err() {
+                        error!("Error while sending drop response");
+                    }
+                },
+                Command::Backfill { responder } => {
+                    //Cancelled before it started
+                    if responder.is_closed() {
+                        continue;
+                    }
+                    let result = match before_insert_hook.as_mut() {
+                        Some(hook) => storage_manager.backfill_computed_columns(|params| hook.apply(params)),
+                        None => Err(ContainerError::HookFailed("No before insert hook configured".into())),
+                    };
+                    if let Err(err) = &result {
+                        error!("Backfill failed: {}", err);
+                    }
+                    if responder.send(result).is_err() {
+                        error!("Error while sending backfill response");
+                    }
+                },
+                Command::Retention { dry_run, responder } => {
+                    if responder.is_closed() {
+                        continue;
+                    }
+                    let result = storage_manager.retention(dry_run);
+                    if let Err(err) = &result {
+                        error!("Retention failed: {}", err);
+                    }
+                    if responder.send(result).is_err() {
+                        error!("Error while sending retention report");
+                    }
+                },
+                Command::LastRetentionReport { responder } => {
+                    if responder.send(storage_manager.last_retention_report()).is_err() {
+                        error!("Error while sending retention report");
+                    }
+                },
+                Command::Compact { min_deleted_rows, responder } => {
+                    if responder.is_closed() {
+                        continue;
+                    }
+                    let result = storage_manager.compact(min_deleted_rows);
+                    if let Err(err) = &result {
+                        error!("Compaction failed: {}", err);
+                    }
+                    if responder.send(result).is_err() {
+                        error!("Error while sending compaction report");
+                    }
+                },
+                Command::Stats { responder } => {
+                    if responder.send(storage_manager.stats()).is_err() {
+                        error!("Error while sending column statistics");
+                    }
+                },
+                Command::Histogram { column, percentiles, responder } => {
+                    if responder.send(storage_manager.histogram(&column, &percentiles)).is_err() {
+                        error!("Error while sending histogram");
+                    }
+                },
+                Command::Diagnostics { responder } => {
+                    if responder.send(storage_manager.id_diagnostics()).is_err() {
+                        error!("Error while sending diagnostics");
+                    }
+                },
+                Command::RepairIds { responder } => {
+                    let result = match maintenance_mode() {
+                        true => storage_manager.reassign_duplicate_ids(),
+                        false => Err(ContainerError::NotInMaintenanceMode),
+                    };
+                    if let Err(err) = &result {
+                        error!("Repairing ids failed: {}", err);
+                    }
+                    if responder.send(result).is_err() {
+                        error!("Error while sending diagnostics");
+                    }
+                },
+                Command::QueryRow { .. } => panic!("Unexpected Code Reached: Command::QueryRow"),
             }
         }
+        //Write out buffered column records before going down
+        if let Err(err) = storage_manager.flush().and_then(|_| derived_tables.flush()) {
+            error!("Failed to flush columns on shutdown: {}", err);
+        }
+    })));
+    //Query nodes don't own any keys, so they stay out of the hash ring. Without a secret, the node stays on its own.
+    if let (NodeRole::Primary, Some(secret)) = (role, cluster_secret.clone()) {
+        lifecycle.register_worker("gossip", &["web"], membership::run_gossip(membership.clone(), router.clone(), secret));
+    }
+
+    let admission = Arc::new(QueryAdmission::new(max_concurrent_queries(), max_queued_queries()));
+    //Stops first, so requests in flight still get answered by the storage layer and counted in the usage counters
+    lifecycle.register("web", &["storage", "usage persistence"], move |shutdown| {
+        let state = NodeState { role, usage, startup, results, jobs, schema_locks, sessions, audit, alerts, warnings, max_response_bytes, auth, cluster_secret, admission };
+        let listeners = web::web_handler(web_tx, ingest_tx, router, membership, state, admin_addr(), shutdown)?;
+        Ok(tokio::spawn(listeners))
     });
-    all_workers.push(url_manager);
 
-    web::web_handler(web_tx).await;
-    futures::future::join_all(all_workers).await;
+    let running = lifecycle.start().await?;
+    signal_rx.recv().await;
+    info!("Shutting down");
+    running.shutdown().await;
     Ok(())
 }
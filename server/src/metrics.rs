@@ -0,0 +1,62 @@
+use std::fmt::Write;
+
+use crate::query::admission::QueryAdmission;
+use crate::storage::upsert_conflicts::KeyConflicts;
+use crate::warnings::WarningCounters;
+
+///Snapshot of the storage actor's internals, rendered in the Prometheus text format
+#[derive(Debug)]
+pub struct StorageMetrics {
+    pub column_buffered_bytes: Vec<(String, usize)>,
+    ///Rows dropped by the dedupe window since startup
+    pub deduplicated_rows: u64,
+    ///Fresh inserts and conflicts per key column since startup
+    pub key_conflicts: Vec<(String, KeyConflicts)>,
+    ///Rows sent with ack=received which failed to get stored since startup
+    pub unacknowledged_failures: u64,
+}
+
+impl StorageMetrics {
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        writeln!(out, "# HELP warenhaus_column_buffered_bytes Bytes written to a column but not flushed to disk yet").unwrap();
+        writeln!(out, "# TYPE warenhaus_column_buffered_bytes gauge").unwrap();
+        for (column, bytes) in &self.column_buffered_bytes {
+            writeln!(out, "warenhaus_column_buffered_bytes{{column=\"{}\"}} {}", column, bytes).unwrap();
+        }
+        writeln!(out, "# HELP warenhaus_deduplicated_rows_total Inserted rows dropped as redeliveries by the dedupe window").unwrap();
+        writeln!(out, "# TYPE warenhaus_deduplicated_rows_total counter").unwrap();
+        writeln!(out, "warenhaus_deduplicated_rows_total {}", self.deduplicated_rows).unwrap();
+        writeln!(out, "# HELP warenhaus_key_inserts_total Inserted rows by key column and whether their key was already stored").unwrap();
+        writeln!(out, "# TYPE warenhaus_key_inserts_total counter").unwrap();
+        for (column, counts) in &self.key_conflicts {
+            writeln!(out, "warenhaus_key_inserts_total{{column=\"{}\",outcome=\"fresh\"}} {}", column, counts.fresh).unwrap();
+            writeln!(out, "warenhaus_key_inserts_total{{column=\"{}\",outcome=\"conflict\"}} {}", column, counts.conflicts).unwrap();
+        }
+        writeln!(out, "# HELP warenhaus_unacknowledged_failures_total Rows sent with ack=received which failed to get stored").unwrap();
+        writeln!(out, "# TYPE warenhaus_unacknowledged_failures_total counter").unwrap();
+        writeln!(out, "warenhaus_unacknowledged_failures_total {}", self.unacknowledged_failures).unwrap();
+        out
+    }
+}
+
+pub fn render_query_metrics(admission: &QueryAdmission) -> String {
+    let mut out = String::new();
+    writeln!(out, "# HELP warenhaus_query_slots_taken Query slots held by admitted queries, including those waiting for the storage layer").unwrap();
+    writeln!(out, "# TYPE warenhaus_query_slots_taken gauge").unwrap();
+    writeln!(out, "warenhaus_query_slots_taken {}", admission.slots_taken()).unwrap();
+    writeln!(out, "# HELP warenhaus_queries_queued Queries waiting for a free slot").unwrap();
+    writeln!(out, "# TYPE warenhaus_queries_queued gauge").unwrap();
+    writeln!(out, "warenhaus_queries_queued {}", admission.queued()).unwrap();
+    out
+}
+
+pub fn render_warning_metrics(warnings: &WarningCounters) -> String {
+    let mut out = String::new();
+    writeln!(out, "# HELP warenhaus_warnings_total Warnings returned along with successful responses, by kind").unwrap();
+    writeln!(out, "# TYPE warenhaus_warnings_total counter").unwrap();
+    for (kind, count) in warnings.counts() {
+        writeln!(out, "warenhaus_warnings_total{{kind=\"{}\"}} {}", kind.name(), count).unwrap();
+    }
+    out
+}
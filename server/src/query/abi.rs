@@ -0,0 +1,164 @@
+use anyhow::{anyhow, Result};
+use wasmtime::{Caller, Engine, Extern, Instance, Linker, Memory, Module, Store};
+
+use super::wasm_error::WasmError;
+
+///Version of the host functions modules can import. Bump it whenever one of them changes incompatibly.
+pub const ABI_VERSION: u32 = 1;
+///AssemblyScript helpers wrapping the host functions, served at `GET /sdk/assemblyscript.ts`
+pub const ASSEMBLYSCRIPT_SDK: &str = include_str!("../../sdk/warenhaus.ts");
+///File name uploaded modules import the SDK from, e.g. `import { Row } from "./warenhaus";`
+pub const SDK_MODULE_NAME: &str = "warenhaus.ts";
+///Names the ABI version source code was written against, like the first line of the SDK does
+const ABI_MARKER: &str = "warenhaus-abi:";
+///Lists the columns a map function reads, e.g. `// warenhaus-columns: url, points`
+const COLUMNS_MARKER: &str = "warenhaus-columns:";
+///Global every compiled module exports, holding the ABI version it was compiled against
+pub const ABI_VERSION_EXPORT: &str = "warenhaus_abi_version";
+
+///AssemblyScript stores a managed object's size in bytes right in front of the object
+const RT_SIZE_OFFSET: usize = 4;
+///Class id AssemblyScript assigns to `String`
+const STRING_CLASS_ID: i32 = 2;
+
+fn memory<T>(caller: &mut Caller<'_, T>) -> Result<Memory> {
+    match caller.get_export("memory") {
+        Some(Extern::Memory(memory)) => Ok(memory),
+        _ => Err(anyhow!("Module does not export its memory")),
+    }
+}
+
+///Reads an AssemblyScript string (UTF-16) the module passed to a host function
+pub fn read_string<T>(caller: &mut Caller<'_, T>, ptr: i32) -> Result<String> {
+    let memory = memory(caller)?;
+    let data = memory.data(&caller);
+    let ptr = ptr as usize;
+
+    let size_bytes = ptr
+        .checked_sub(RT_SIZE_OFFSET)
+        .and_then(|size_ptr| data.get(size_ptr..ptr))
+        .ok_or_else(|| anyhow!("String pointer {} out of bounds", ptr))?;
+    let size = u32::from_le_bytes(size_bytes.try_into().unwrap()) as usize;
+    let bytes = data
+        .get(ptr..ptr + size)
+        .ok_or_else(|| anyhow!("String at {} exceeds memory", ptr))?;
+
+    let utf16 = bytes
+        .chunks_exact(2)
+        .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+        .collect::<Vec<_>>();
+    Ok(String::from_utf16(&utf16)?)
+}
+
+///Allocates a new AssemblyScript string inside the module and returns its pointer.
+///Requires the module to be compiled with `--exportRuntime`.
+pub fn write_string<T>(caller: &mut Caller<'_, T>, value: &str) -> Result<i32> {
+    let bytes = value
+        .encode_utf16()
+        .flat_map(|unit| unit.to_le_bytes())
+        .collect::<Vec<_>>();
+
+    let new = caller
+        .get_export("__new")
+        .and_then(|export| export.into_func())
+        .ok_or_else(|| anyhow!("Module does not export __new. Was it compiled with --exportRuntime?"))?
+        .typed::<(i32, i32), i32>(&caller)?;
+    let ptr = new.call(&mut *caller, (bytes.len() as i32, STRING_CLASS_ID))?;
+
+    let memory = memory(caller)?;
+    memory.write(&mut *caller, ptr as usize, &bytes)?;
+    Ok(ptr)
+}
+
+///Rejects source code naming another ABI version than the server's, e.g. because it embeds an outdated copy of the SDK.
+///Source code not naming a version is accepted.
+pub fn check_abi_version(source: &str) -> Result<(), WasmError> {
+    for line in source.lines() {
+        let Some((_, version)) = line.split_once(ABI_MARKER) else {
+            continue;
+        };
+        let version = version.trim();
+        if version.parse::<u32>().ok() != Some(ABI_VERSION) {
+            return Err(WasmError::AbiMismatch(version.to_string()));
+        }
+    }
+    Ok(())
+}
+
+///Columns the source code declares it reads, see COLUMNS_MARKER. `None` if it doesn't declare any.
+pub fn declared_columns(source: &str) -> Option<Vec<String>> {
+    source.lines().find_map(|line| {
+        let (_, columns) = line.split_once(COLUMNS_MARKER)?;
+        Some(
+            columns
+                .split(',')
+                .map(|column| column.trim().to_string())
+                .filter(|column| !column.is_empty())
+                .collect(),
+        )
+    })
+}
+
+///Appended to uploaded code before compiling it, so the module states the ABI version it was compiled against
+pub fn abi_version_export() -> String {
+    format!("\nexport const {}: i32 = {};\n", ABI_VERSION_EXPORT, ABI_VERSION)
+}
+
+///Rejects instantiated modules compiled against another ABI version than the server's,
+///including modules compiled before they had to state one
+pub fn check_module_abi<T>(store: &mut Store<T>, instance: &Instance) -> Result<(), WasmError> {
+    let version = instance
+        .get_global(&mut *store, ABI_VERSION_EXPORT)
+        .and_then(|global| global.get(&mut *store).i32());
+    match version {
+        Some(version) if version == ABI_VERSION as i32 => Ok(()),
+        Some(version) => Err(WasmError::ModuleAbiMismatch(version.to_string())),
+        None => Err(WasmError::ModuleAbiMismatch("unknown".to_string())),
+    }
+}
+
+///Checks the ABI version of a freshly compiled module before it gets stored.
+///Its imports only trap, the module merely gets instantiated to read the version.
+pub fn check_compiled_abi(wat: &str) -> Result<(), WasmError> {
+    let compiler_error = |err: anyhow::Error| WasmError::CompilerError(err.to_string());
+    let engine = Engine::default();
+    let module = Module::new(&engine, wat).map_err(compiler_error)?;
+    let mut linker = Linker::new(&engine);
+    linker.define_unknown_imports_as_traps(&module).map_err(compiler_error)?;
+    let mut store = Store::new(&engine, ());
+    let instance = linker.instantiate(&mut store, &module).map_err(compiler_error)?;
+    check_module_abi(&mut store, &instance)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        abi_version_export, check_abi_version, check_compiled_abi, declared_columns, ABI_VERSION, ABI_VERSION_EXPORT,
+        ASSEMBLYSCRIPT_SDK,
+    };
+    use crate::query::wasm_error::WasmError;
+
+    #[test]
+    fn sources_naming_another_abi_version_are_rejected() {
+        check_abi_version(ASSEMBLYSCRIPT_SDK).unwrap();
+        check_abi_version("export function run(timestamp: i32): bool { return true; }").unwrap();
+        let outdated = format!("// warenhaus-abi: {}\n", ABI_VERSION + 1);
+        assert!(matches!(check_abi_version(&outdated), Err(WasmError::AbiMismatch(version)) if version == (ABI_VERSION + 1).to_string()));
+    }
+
+    #[test]
+    fn sources_declare_the_columns_they_read() {
+        assert_eq!(declared_columns("// warenhaus-columns: url, points\nexport function run(timestamp: i32): bool"), Some(vec!["url".to_string(), "points".to_string()]));
+        assert_eq!(declared_columns("// warenhaus-columns:"), Some(vec![]));
+        assert_eq!(declared_columns(ASSEMBLYSCRIPT_SDK), None);
+    }
+
+    #[test]
+    fn modules_compiled_against_another_abi_version_are_rejected() {
+        let module = |version: u32| format!(r#"(module (global (export "{}") i32 (i32.const {})))"#, ABI_VERSION_EXPORT, version);
+        check_compiled_abi(&module(ABI_VERSION)).unwrap();
+        assert!(matches!(check_compiled_abi(&module(ABI_VERSION + 1)), Err(WasmError::ModuleAbiMismatch(version)) if version == (ABI_VERSION + 1).to_string()));
+        assert!(matches!(check_compiled_abi("(module)"), Err(WasmError::ModuleAbiMismatch(version)) if version == "unknown"));
+        assert!(abi_version_export().contains(&format!("{}: i32 = {}", ABI_VERSION_EXPORT, ABI_VERSION)));
+    }
+}
@@ -0,0 +1,92 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use serde::Serialize;
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+///Returned when both all query slots and the wait queue are taken
+#[derive(Debug, Serialize)]
+pub struct QueueFull {
+    pub error: &'static str,
+    ///Query slots taken, see `QueryAdmission::slots_taken`
+    pub running: usize,
+    pub queued: usize,
+}
+
+///Caps how many queries run at the same time. Queries exceeding the cap wait for a slot,
+///unless the wait queue is full as well.
+#[derive(Debug)]
+pub struct QueryAdmission {
+    slots: Semaphore,
+    max_running: usize,
+    max_queued: usize,
+    queued: AtomicUsize,
+}
+
+impl QueryAdmission {
+    pub fn new(max_running: usize, max_queued: usize) -> Self {
+        Self {
+            slots: Semaphore::new(max_running),
+            max_running,
+            max_queued,
+            queued: AtomicUsize::new(0),
+        }
+    }
+
+    ///Waits for a query slot. The query holds it until the permit gets dropped.
+    pub async fn admit(&self) -> Result<SemaphorePermit<'_>, QueueFull> {
+        if let Ok(permit) = self.slots.try_acquire() {
+            return Ok(permit);
+        }
+
+        let queued = self.queued.fetch_add(1, Ordering::SeqCst);
+        if queued >= self.max_queued {
+            self.queued.fetch_sub(1, Ordering::SeqCst);
+            return Err(QueueFull {
+                error: "Too many concurrent queries",
+                running: self.slots_taken(),
+                queued,
+            });
+        }
+
+        let permit = self.slots.acquire().await.expect("Query slots are never closed");
+        self.queued.fetch_sub(1, Ordering::SeqCst);
+        Ok(permit)
+    }
+
+    ///Number of admitted queries holding a slot. Queries keep their slot while they wait for the storage layer,
+    ///so this includes queries which aren't executing yet.
+    pub fn slots_taken(&self) -> usize {
+        self.max_running - self.slots.available_permits()
+    }
+
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::QueryAdmission;
+
+    #[tokio::test]
+    async fn rejects_queries_once_the_queue_is_full_and_frees_slots_with_their_permits() {
+        let admission = QueryAdmission::new(1, 1);
+        let permit = admission.admit().await.unwrap();
+        assert_eq!(admission.slots_taken(), 1);
+
+        let waiting = admission.admit();
+        tokio::pin!(waiting);
+        assert!(futures::poll!(waiting.as_mut()).is_pending());
+        assert_eq!(admission.queued(), 1);
+
+        let full = admission.admit().await.unwrap_err();
+        assert_eq!((full.running, full.queued), (1, 1));
+        assert_eq!(admission.queued(), 1);
+
+        drop(permit);
+        let permit = waiting.await.unwrap();
+        assert_eq!((admission.slots_taken(), admission.queued()), (1, 0));
+        drop(permit);
+        assert_eq!(admission.slots_taken(), 0);
+    }
+}
@@ -1,16 +1,20 @@
 use std::{fs::File, io::Write, path::Path};
 
 use anyhow::{anyhow, Result};
-use tracing::{debug, error, log::warn};
+use tracing::error;
 use wasmtime::*;
 
 use crate::{
-    query::AssemblyScriptCompiler, storage::column_frame::ColumnFrame,
+    query::AssemblyScriptCompiler, storage::{cell::Cell, column_frame::ColumnFrame},
 };
-use chrono::{DateTime, NaiveDateTime, Utc, Local, NaiveDate};
+use chrono::{DateTime, NaiveDateTime, Local};
 
+use super::abi::{check_abi_version, check_compiled_abi, declared_columns, read_string, write_string};
 use super::wasm_error::WasmError;
 
+///Host functions reading the row's columns, see `link_row`
+const ROW_GETTERS: &[&str] = &["has_field", "is_null", "get_bool", "get_int", "get_float", "get_string"];
+
 #[derive(Debug)]
 pub struct CodeRunner {
     compiled_query_storage_path: String,
@@ -34,36 +38,110 @@ impl CodeRunner {
     }
 
     pub fn compile_and_store(&self, asm_script_code: &str, name: &str) -> Result<(), WasmError> {
+        check_abi_version(asm_script_code)?;
         let compiler = AssemblyScriptCompiler::new(self.asm_script_compiler_path.to_string());
-        let compiled_wat = match compiler.compile_to_wat(&asm_script_code) {
+        let compiled_wat = match compiler.compile_to_wat(asm_script_code) {
             Ok(compiled) => compiled,
             Err(err) => {
                 error!("Failed to compile {}: {}", name, err);
                 return Err(WasmError::CompilerError(err.to_string()));
             }
         };
+        check_compiled_abi(&compiled_wat)?;
 
         let mut compiled_file_path = Path::new(&self.compiled_query_storage_path).join(name);
         compiled_file_path.set_extension("wat");
         let mut file = File::create(compiled_file_path)?;
         file.write_all(compiled_wat.as_bytes())?;
 
+        self.store_columns_read(name, asm_script_code, &compiled_wat)
+    }
+
+    ///Writes the columns the map function reads to `<name>.columns.json`, see `columns_read`. Modules which don't declare
+    ///their columns get inspected here, so queries don't have to compile them to find out.
+    fn store_columns_read(&self, name: &str, asm_script_code: &str, compiled_wat: &str) -> Result<(), WasmError> {
+        let columns = match declared_columns(asm_script_code) {
+            Some(columns) => Some(columns),
+            None => {
+                let module = Module::new(&Engine::default(), compiled_wat).map_err(|err| WasmError::CompilerError(err.to_string()))?;
+                (!CodeRunner::reads_row(&module)).then(Vec::new)
+            }
+        };
+        let columns_file_path = self.columns_file_path(name);
+        match columns {
+            Some(columns) => std::fs::write(columns_file_path, serde_json::to_vec(&columns).map_err(std::io::Error::from)?)?,
+            None if columns_file_path.exists() => std::fs::remove_file(columns_file_path)?,
+            None => {}
+        }
         Ok(())
     }
 
-    ///runs a specific query for a single database row
-    ///Returns: boolean indicating if the row should be included in the result set
-    #[tracing::instrument]
-    pub fn execute_map(&self, function_name: &str, row: ColumnFrame) -> Result<bool> {
-        let base_path = Path::new(&self.compiled_query_storage_path);
-        let filename = base_path.join(format!("{}.wat", function_name));
+    fn columns_file_path(&self, name: &str) -> std::path::PathBuf {
+        Path::new(&self.compiled_query_storage_path).join(format!("{}.columns.json", name))
+    }
 
-        debug!("Loading wasm file {:?}", filename);
-        let engine = Engine::default();
-        let mut linker = Linker::new(&engine);
-        let module = Module::from_file(&engine, filename)?;
-        let mut store = Store::new(&engine, ());
+    fn schema_version_file_path(&self, name: &str) -> std::path::PathBuf {
+        Path::new(&self.compiled_query_storage_path).join(format!("{}.schema_version", name))
+    }
+
+    ///Remembers the version of the table's schema the map function's columns got checked against, see `check_columns`
+    pub fn record_schema_version(&self, name: &str, schema_version: &str) -> Result<(), WasmError> {
+        std::fs::write(self.schema_version_file_path(name), schema_version)?;
+        Ok(())
+    }
+
+    ///Checks the columns the map function declares still exist, unless the schema didn't change since the last check.
+    ///`missing` returns the columns of the list the table doesn't have.
+    pub fn check_columns<F>(&self, name: &str, schema_version: &str, missing: F) -> Result<(), WasmError>
+    where
+        F: Fn(&[String]) -> Vec<String>,
+    {
+        let checked_version = std::fs::read_to_string(self.schema_version_file_path(name)).ok();
+        if checked_version.as_deref() == Some(schema_version) {
+            return Ok(());
+        }
+        let columns_file_path = self.columns_file_path(name);
+        if columns_file_path.exists() {
+            let columns: Vec<String> = serde_json::from_slice(&std::fs::read(columns_file_path)?).map_err(std::io::Error::from)?;
+            let missing = missing(&columns);
+            if !missing.is_empty() {
+                return Err(WasmError::MissingColumns(missing));
+            }
+        }
+        self.record_schema_version(name, schema_version)
+    }
+
+    ///Names of all uploaded map functions
+    pub fn map_fns(&self) -> Result<Vec<String>, WasmError> {
+        let mut names = vec![];
+        for entry in std::fs::read_dir(&self.compiled_query_storage_path)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|extension| extension == "wat") {
+                names.extend(path.file_stem().map(|name| name.to_string_lossy().to_string()));
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
 
+    ///Columns the map function reads besides `id` and `timestamp`, so queries only assemble those.
+    ///Either declared in its source code, or none if the module doesn't import any of the row getters.
+    ///`None` if it may read any column. Recorded on upload, see `store_columns_read`.
+    pub fn columns_read(&self, function_name: &str) -> Result<Option<Vec<String>>> {
+        let columns_file_path = self.columns_file_path(function_name);
+        if !columns_file_path.exists() {
+            return Ok(None);
+        }
+        Ok(Some(serde_json::from_slice(&std::fs::read(columns_file_path)?)?))
+    }
+
+    fn reads_row(module: &Module) -> bool {
+        module.imports().any(|import| import.module() == "env" && ROW_GETTERS.contains(&import.name()))
+    }
+
+    ///Host functions map modules import, along with the getters for the columns of the row in the store
+    pub fn map_linker(engine: &Engine) -> Result<Linker<ColumnFrame>> {
+        let mut linker = Linker::new(engine);
         linker.allow_unknown_exports(true);
         linker.func_wrap("env", "log", |value: i32| {
             println!(">> {}", value);
@@ -74,22 +152,148 @@ impl CodeRunner {
             let dt: DateTime<Local> = Local::now();
             let result = ts.date() == dt.date_naive();
 
-            return result as i32
+            result as i32
         })?;
+        CodeRunner::link_row(&mut linker)?;
+        Ok(linker)
+    }
 
-        let instance = linker.instantiate(&mut store, &module)?;
-
-        let run = instance.get_typed_func::<i32, i32>(&mut store, "run").unwrap();
-
+    ///Timestamp map modules get passed to `run`. Fails for rows without an id or timestamp.
+    pub fn map_timestamp(row: &ColumnFrame) -> Result<i32> {
         let id_cell = row.get("id").ok_or_else(||anyhow!("Expected ID - found None"))?;
         let timestamp_cell = row.get("timestamp").ok_or_else(||anyhow!("Expected timestamp - Found None"))?;
-    
-        let id = id_cell.as_int().ok_or_else(|| anyhow!("Invalid Type for ID Cell: Was expecting i64"))?;
-        let timestamp = timestamp_cell.as_int().ok_or_else(|| anyhow!("Invalid Type for ID Cell: Was expecting i64"))?;
-
-        debug!("Calling function {} with {}", function_name, id);
-        let should_be_included = run.call(&mut store, (*timestamp as i32).to_owned())? != 0;
-        debug!("Call returned: {}", should_be_included);
-        Ok(should_be_included)
+
+        id_cell.as_int().ok_or_else(|| anyhow!("Invalid Type for ID Cell: Was expecting i64"))?;
+        let timestamp = *timestamp_cell.as_int().ok_or_else(|| anyhow!("Invalid Type for ID Cell: Was expecting i64"))?;
+        Ok(timestamp as i32)
+    }
+
+    ///Getters for the columns of the row. Missing columns, null and cells of another type read as
+    ///0, false or an empty string, `has_field` and `is_null` tell these cases apart.
+    fn link_row(linker: &mut Linker<ColumnFrame>) -> Result<()> {
+        linker.func_wrap("env", "has_field", |mut caller: Caller<'_, ColumnFrame>, field: i32| -> Result<i32> {
+            let field = read_string(&mut caller, field)?;
+            Ok(caller.data().get(&field).is_some_and(|cell| cell != &Cell::Null) as i32)
+        })?;
+        linker.func_wrap("env", "is_null", |mut caller: Caller<'_, ColumnFrame>, field: i32| -> Result<i32> {
+            let field = read_string(&mut caller, field)?;
+            Ok((caller.data().get(&field) == Some(&Cell::Null)) as i32)
+        })?;
+        linker.func_wrap("env", "get_bool", |mut caller: Caller<'_, ColumnFrame>, field: i32| -> Result<i32> {
+            let field = read_string(&mut caller, field)?;
+            Ok((caller.data().get(&field) == Some(&Cell::Boolean(true))) as i32)
+        })?;
+        linker.func_wrap("env", "get_int", |mut caller: Caller<'_, ColumnFrame>, field: i32| -> Result<i64> {
+            let field = read_string(&mut caller, field)?;
+            Ok(caller.data().get(&field).and_then(|cell| cell.as_int()).copied().unwrap_or_default())
+        })?;
+        linker.func_wrap("env", "get_float", |mut caller: Caller<'_, ColumnFrame>, field: i32| -> Result<f64> {
+            let field = read_string(&mut caller, field)?;
+            Ok(match caller.data().get(&field) {
+                Some(Cell::Float(value)) => *value,
+                Some(Cell::Int(value)) => *value as f64,
+                _ => 0.0,
+            })
+        })?;
+        linker.func_wrap("env", "get_string", |mut caller: Caller<'_, ColumnFrame>, field: i32| -> Result<i32> {
+            let field = read_string(&mut caller, field)?;
+            let value = match caller.data().get(&field) {
+                Some(Cell::String(value)) => value.clone(),
+                Some(Cell::Uuid(value)) => Cell::format_uuid(*value),
+                _ => String::new(),
+            };
+            write_string(&mut caller, &value)
+        })?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use wasmtime::{Engine, Module};
+
+    use super::CodeRunner;
+    use crate::query::instance_pool::MapPool;
+    use crate::query::wasm_error::WasmError;
+    use crate::storage::{cell::Cell, column_frame::ColumnFrame};
+
+    //Keeps active rows which have a score
+    const ACTIVE_WITH_SCORE_MAP: &str = r#"
+    (module
+        (import "env" "get_bool" (func $get_bool (param i32) (result i32)))
+        (import "env" "is_null" (func $is_null (param i32) (result i32)))
+        (memory (export "memory") 1)
+        (global (export "warenhaus_abi_version") i32 (i32.const 1))
+        ;; "active" at 16, "score" at 48
+        (data (i32.const 12) "\0c\00\00\00a\00c\00t\00i\00v\00e\00")
+        (data (i32.const 44) "\0a\00\00\00s\00c\00o\00r\00e\00")
+        (func (export "run") (param i32) (result i32)
+            (i32.and (call $get_bool (i32.const 16)) (i32.eqz (call $is_null (i32.const 48))))))
+    "#;
+
+    #[test]
+    fn map_functions_read_booleans_and_nulls() {
+        let engine = Engine::default();
+        let module = Module::new(&engine, ACTIVE_WITH_SCORE_MAP).unwrap();
+        let mut pool = MapPool::from_module(engine, module, 1).unwrap();
+        let row = |active: Option<bool>, score: Cell| {
+            let mut row = ColumnFrame::new();
+            row.insert("id", Cell::Int(1));
+            row.insert("timestamp", Cell::Timestamp(1677125260));
+            if let Some(active) = active {
+                row.insert("active", Cell::Boolean(active));
+            }
+            row.insert("score", score);
+            row
+        };
+
+        assert!(pool.run(row(Some(true), Cell::Int(3))).unwrap());
+        assert!(!pool.run(row(Some(true), Cell::Null)).unwrap());
+        assert!(!pool.run(row(Some(false), Cell::Int(3))).unwrap());
+        assert!(!pool.run(row(None, Cell::Int(3))).unwrap());
+    }
+
+    #[test]
+    fn modules_without_row_getters_only_read_the_timestamp() {
+        let engine = Engine::default();
+        assert!(CodeRunner::reads_row(&Module::new(&engine, ACTIVE_WITH_SCORE_MAP).unwrap()));
+        let timestamp_only = r#"(module (func (export "run") (param i32) (result i32) (i32.const 1)))"#;
+        assert!(!CodeRunner::reads_row(&Module::new(&engine, timestamp_only).unwrap()));
+    }
+
+    #[test]
+    fn columns_read_get_recorded_on_upload() {
+        let root = tempfile::tempdir().unwrap();
+        let code_runner = CodeRunner {
+            compiled_query_storage_path: root.path().to_string_lossy().to_string(),
+            asm_script_compiler_path: String::new(),
+        };
+        let timestamp_only = r#"(module (func (export "run") (param i32) (result i32) (i32.const 1)))"#;
+
+        code_runner.store_columns_read("top", "// warenhaus-columns: active, score", ACTIVE_WITH_SCORE_MAP).unwrap();
+        assert_eq!(code_runner.columns_read("top").unwrap(), Some(vec!["active".to_string(), "score".to_string()]));
+        code_runner.store_columns_read("top", "", ACTIVE_WITH_SCORE_MAP).unwrap();
+        assert_eq!(code_runner.columns_read("top").unwrap(), None);
+        code_runner.store_columns_read("top", "", timestamp_only).unwrap();
+        assert_eq!(code_runner.columns_read("top").unwrap(), Some(vec![]));
+    }
+
+    #[test]
+    fn columns_get_checked_again_once_the_schema_changed() {
+        let root = tempfile::tempdir().unwrap();
+        let code_runner = CodeRunner {
+            compiled_query_storage_path: root.path().to_string_lossy().to_string(),
+            asm_script_compiler_path: String::new(),
+        };
+        std::fs::write(root.path().join("top.wat"), ACTIVE_WITH_SCORE_MAP).unwrap();
+        std::fs::write(root.path().join("top.columns.json"), r#"["active", "score"]"#).unwrap();
+        assert_eq!(code_runner.map_fns().unwrap(), vec!["top"]);
+
+        code_runner.check_columns("top", "v1", |_| vec![]).unwrap();
+        //Unchanged schema, no need to look at the columns
+        code_runner.check_columns("top", "v1", |_| panic!("checked again")).unwrap();
+        let err = code_runner.check_columns("top", "v2", |_| vec!["score".into()]).unwrap_err();
+        assert!(matches!(err, WasmError::MissingColumns(columns) if columns == vec!["score"]));
+        code_runner.check_columns("top", "v3", |columns| columns.iter().filter(|c| *c == "gone").cloned().collect()).unwrap();
     }
 }
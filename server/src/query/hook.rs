@@ -0,0 +1,379 @@
+use std::{fmt::Debug, path::Path};
+
+use anyhow::Result;
+use tracing::{debug, error, info, instrument};
+use wasmtime::*;
+
+use crate::{storage::ContainerError, web::IndexParams};
+
+use super::abi::{check_module_abi, read_string, write_string};
+
+///Fuel a hook gets for every row, roughly one unit per executed instruction.
+///Runs out for hooks stuck in a loop, which would otherwise hold up all inserts.
+const FUEL_PER_ROW: u64 = 10_000_000;
+
+///Everything a hook invocation can see and change
+struct HookState {
+    params: IndexParams,
+    rejection: Option<String>,
+}
+
+impl HookState {
+    fn new(params: IndexParams) -> Self {
+        Self { params, rejection: None }
+    }
+
+    ///Null values count as absent, so `has_field` is false for them
+    fn get(&self, field: &str) -> Option<&serde_json::Value> {
+        self.params
+            .fields
+            .iter()
+            .position(|f| f == field)
+            .and_then(|index| self.params.values.get(index))
+            .filter(|value| !value.is_null())
+    }
+
+    ///True if the field is present and explicitly null
+    fn is_null(&self, field: &str) -> bool {
+        self.params
+            .fields
+            .iter()
+            .position(|f| f == field)
+            .and_then(|index| self.params.values.get(index))
+            .is_some_and(|value| value.is_null())
+    }
+
+    fn set(&mut self, field: String, value: serde_json::Value) {
+        match self.params.fields.iter().position(|f| f == &field) {
+            Some(index) => self.params.values[index] = value,
+            None => {
+                self.params.fields.push(field);
+                self.params.values.push(value);
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum HookOutcome {
+    Accept(IndexParams),
+    Reject(String),
+}
+
+///A compiled "before insert" module. It exports `before_insert(): bool` and accesses the row
+///through the host functions registered in `HookRunner::load`.
+///All rows run in the same instance, which only gets created again after a row failed.
+pub struct HookRunner {
+    engine: Engine,
+    module: Module,
+    linker: Linker<HookState>,
+    instance: Option<HookInstance>,
+}
+
+///Instance of the module, along with the store holding the row it currently runs for
+struct HookInstance {
+    store: Store<HookState>,
+    before_insert: TypedFunc<(), i32>,
+}
+
+impl Debug for HookRunner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HookRunner").finish_non_exhaustive()
+    }
+}
+
+impl HookRunner {
+    pub fn load(compiled_query_storage_path: &str, name: &str) -> Result<Self> {
+        let filename = Path::new(compiled_query_storage_path).join(format!("{}.wat", name));
+        let engine = HookRunner::engine()?;
+        let module = Module::from_file(&engine, filename)?;
+        HookRunner::from_module(engine, module, name)
+    }
+
+    ///Engine metering the fuel of the hooks it runs
+    fn engine() -> Result<Engine> {
+        let mut config = Config::new();
+        config.consume_fuel(true);
+        Engine::new(&config)
+    }
+
+    fn from_module(engine: Engine, module: Module, name: &str) -> Result<Self> {
+        let mut linker = Linker::new(&engine);
+        linker.allow_unknown_exports(true);
+
+        linker.func_wrap("env", "has_field", |mut caller: Caller<'_, HookState>, field: i32| -> Result<i32> {
+            let field = read_string(&mut caller, field)?;
+            Ok(caller.data().get(&field).is_some() as i32)
+        })?;
+        linker.func_wrap("env", "is_null", |mut caller: Caller<'_, HookState>, field: i32| -> Result<i32> {
+            let field = read_string(&mut caller, field)?;
+            Ok(caller.data().is_null(&field) as i32)
+        })?;
+        linker.func_wrap("env", "get_int", |mut caller: Caller<'_, HookState>, field: i32| -> Result<i64> {
+            let field = read_string(&mut caller, field)?;
+            Ok(caller.data().get(&field).and_then(|value| value.as_i64()).unwrap_or_default())
+        })?;
+        linker.func_wrap("env", "get_float", |mut caller: Caller<'_, HookState>, field: i32| -> Result<f64> {
+            let field = read_string(&mut caller, field)?;
+            Ok(caller.data().get(&field).and_then(|value| value.as_f64()).unwrap_or_default())
+        })?;
+        linker.func_wrap("env", "get_bool", |mut caller: Caller<'_, HookState>, field: i32| -> Result<i32> {
+            let field = read_string(&mut caller, field)?;
+            Ok(caller.data().get(&field).and_then(|value| value.as_bool()).unwrap_or_default() as i32)
+        })?;
+        linker.func_wrap("env", "get_string", |mut caller: Caller<'_, HookState>, field: i32| -> Result<i32> {
+            let field = read_string(&mut caller, field)?;
+            let value = caller
+                .data()
+                .get(&field)
+                .and_then(|value| value.as_str())
+                .unwrap_or_default()
+                .to_string();
+            write_string(&mut caller, &value)
+        })?;
+        linker.func_wrap("env", "set_int", |mut caller: Caller<'_, HookState>, field: i32, value: i64| -> Result<()> {
+            let field = read_string(&mut caller, field)?;
+            caller.data_mut().set(field, value.into());
+            Ok(())
+        })?;
+        linker.func_wrap("env", "set_float", |mut caller: Caller<'_, HookState>, field: i32, value: f64| -> Result<()> {
+            let field = read_string(&mut caller, field)?;
+            caller.data_mut().set(field, value.into());
+            Ok(())
+        })?;
+        linker.func_wrap("env", "set_bool", |mut caller: Caller<'_, HookState>, field: i32, value: i32| -> Result<()> {
+            let field = read_string(&mut caller, field)?;
+            caller.data_mut().set(field, (value != 0).into());
+            Ok(())
+        })?;
+        linker.func_wrap("env", "set_string", |mut caller: Caller<'_, HookState>, field: i32, value: i32| -> Result<()> {
+            let field = read_string(&mut caller, field)?;
+            let value = read_string(&mut caller, value)?;
+            caller.data_mut().set(field, value.into());
+            Ok(())
+        })?;
+        linker.func_wrap("env", "set_null", |mut caller: Caller<'_, HookState>, field: i32| -> Result<()> {
+            let field = read_string(&mut caller, field)?;
+            caller.data_mut().set(field, serde_json::Value::Null);
+            Ok(())
+        })?;
+        linker.func_wrap("env", "reject", |mut caller: Caller<'_, HookState>, reason: i32| -> Result<()> {
+            let reason = read_string(&mut caller, reason)?;
+            caller.data_mut().rejection = Some(reason);
+            Ok(())
+        })?;
+        let hook = name.to_string();
+        linker.func_wrap("env", "log", move |value: i32| {
+            debug!("Before insert hook {} logged {}", hook, value);
+        })?;
+
+        Ok(Self {
+            engine,
+            module,
+            linker,
+            instance: None,
+        })
+    }
+
+    fn instantiate(&self) -> Result<HookInstance> {
+        let mut store = Store::new(&self.engine, HookState::new(IndexParams { fields: vec![], values: vec![] }));
+        store.add_fuel(FUEL_PER_ROW)?;
+        let instance = self.linker.instantiate(&mut store, &self.module)?;
+        check_module_abi(&mut store, &instance)?;
+        let before_insert = instance.get_typed_func::<(), i32>(&mut store, "before_insert")?;
+        Ok(HookInstance { store, before_insert })
+    }
+
+    #[instrument(skip(self))]
+    pub fn run(&mut self, params: IndexParams) -> Result<HookOutcome> {
+        let mut instance = match self.instance.take() {
+            Some(instance) => instance,
+            None => self.instantiate()?,
+        };
+        let store = &mut instance.store;
+        *store.data_mut() = HookState::new(params);
+        //Tops the fuel up again, whatever the previous row left over
+        let remaining = store.consume_fuel(0)?;
+        store.add_fuel(FUEL_PER_ROW - remaining)?;
+        let accepted = match instance.before_insert.call(&mut *store, ()) {
+            Ok(accepted) => accepted != 0,
+            //A trap may leave the instance's memory in any state, the next row gets a new one
+            Err(err) if err.downcast_ref::<Trap>() == Some(&Trap::OutOfFuel) => {
+                return Err(anyhow::anyhow!("Ran out of fuel after {} instructions", FUEL_PER_ROW))
+            }
+            Err(err) => return Err(err),
+        };
+
+        let state = std::mem::replace(store.data_mut(), HookState::new(IndexParams { fields: vec![], values: vec![] }));
+        self.instance = Some(instance);
+        match state.rejection {
+            Some(reason) => Ok(HookOutcome::Reject(reason)),
+            None if !accepted => Ok(HookOutcome::Reject("Rejected by before insert hook".into())),
+            None => Ok(HookOutcome::Accept(state.params)),
+        }
+    }
+}
+
+///The table's configured before insert hook. The module gets loaded on first use and
+///reloaded after a new version was uploaded.
+#[derive(Debug)]
+pub struct BeforeInsertHook {
+    name: String,
+    compiled_query_storage_path: String,
+    runner: Option<HookRunner>,
+}
+
+impl BeforeInsertHook {
+    pub fn new(name: String, compiled_query_storage_path: String) -> Self {
+        Self {
+            name,
+            compiled_query_storage_path,
+            runner: None,
+        }
+    }
+
+    ///Drops the loaded module if it was replaced
+    pub fn invalidate(&mut self, fn_name: &str) {
+        if fn_name == self.name {
+            info!("Reloading before insert hook {} on next insert", fn_name);
+            self.runner = None;
+        }
+    }
+
+    ///Runs the hook. Returns the row as the hook left it, or why it got rejected.
+    #[instrument(skip(self))]
+    pub fn apply(&mut self, params: IndexParams) -> Result<IndexParams, ContainerError> {
+        if self.runner.is_none() {
+            let runner = HookRunner::load(&self.compiled_query_storage_path, &self.name).map_err(|err| {
+                error!("Failed to load before insert hook {}: {}", self.name, err);
+                ContainerError::HookFailed(err.to_string())
+            })?;
+            self.runner = Some(runner);
+        }
+
+        match self.runner.as_mut().unwrap().run(params) {
+            Ok(HookOutcome::Accept(params)) => {
+                debug!("Hook accepted {:?}", params);
+                Ok(params)
+            }
+            Ok(HookOutcome::Reject(reason)) => Err(ContainerError::RejectedByHook(reason)),
+            Err(err) => {
+                error!("Before insert hook {} failed: {}", self.name, err);
+                Err(ContainerError::HookFailed(err.to_string()))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use wasmtime::Module;
+
+    use super::{HookOutcome, HookRunner};
+    use crate::web::IndexParams;
+
+    //Mimics what AssemblyScript emits: strings are UTF-16 with their byte size stored in front
+    const DOUBLE_POINTS_HOOK: &str = r#"
+    (module
+        (import "env" "get_int" (func $get_int (param i32) (result i64)))
+        (import "env" "set_int" (func $set_int (param i32 i64)))
+        (import "env" "reject" (func $reject (param i32)))
+        (memory (export "memory") 1)
+        (global (export "warenhaus_abi_version") i32 (i32.const 1))
+        ;; "points" at 16, "double" at 48, "negative" at 80
+        (data (i32.const 12) "\0c\00\00\00p\00o\00i\00n\00t\00s\00")
+        (data (i32.const 44) "\0c\00\00\00d\00o\00u\00b\00l\00e\00")
+        (data (i32.const 76) "\10\00\00\00n\00e\00g\00a\00t\00i\00v\00e\00")
+        (func (export "before_insert") (result i32)
+            (if (i64.lt_s (call $get_int (i32.const 16)) (i64.const 0))
+                (then (call $reject (i32.const 80)) (return (i32.const 0))))
+            (call $set_int (i32.const 48) (i64.mul (call $get_int (i32.const 16)) (i64.const 2)))
+            (i32.const 1)))
+    "#;
+
+    //Counts the rows it ran for in a global, and loops forever once "points" is 0
+    const COUNTING_HOOK: &str = r#"
+    (module
+        (import "env" "get_int" (func $get_int (param i32) (result i64)))
+        (import "env" "set_int" (func $set_int (param i32 i64)))
+        (memory (export "memory") 1)
+        (global (export "warenhaus_abi_version") i32 (i32.const 1))
+        (global $rows (mut i64) (i64.const 0))
+        ;; "points" at 16, "rows" at 48
+        (data (i32.const 12) "\0c\00\00\00p\00o\00i\00n\00t\00s\00")
+        (data (i32.const 44) "\08\00\00\00r\00o\00w\00s\00")
+        (func (export "before_insert") (result i32)
+            (global.set $rows (i64.add (global.get $rows) (i64.const 1)))
+            (if (i64.eqz (call $get_int (i32.const 16)))
+                (then (loop $forever (br $forever))))
+            (call $set_int (i32.const 48) (global.get $rows))
+            (i32.const 1)))
+    "#;
+
+    fn runner_of(source: &str) -> HookRunner {
+        let engine = HookRunner::engine().unwrap();
+        let module = Module::new(&engine, source).unwrap();
+        HookRunner::from_module(engine, module, "hook").unwrap()
+    }
+
+    fn runner() -> HookRunner {
+        runner_of(DOUBLE_POINTS_HOOK)
+    }
+
+    fn rows_seen(runner: &mut HookRunner, points: i64) -> Option<serde_json::Value> {
+        let params = IndexParams {
+            fields: vec!["points".into()],
+            values: vec![json!(points)],
+        };
+        match runner.run(params).unwrap() {
+            HookOutcome::Accept(params) => params.values.get(1).cloned(),
+            outcome => panic!("Expected row to be accepted, got {:?}", outcome),
+        }
+    }
+
+    #[test]
+    fn rows_share_an_instance_until_one_runs_out_of_fuel() {
+        let mut runner = runner_of(COUNTING_HOOK);
+        assert_eq!(rows_seen(&mut runner, 1), Some(json!(1)));
+        assert_eq!(rows_seen(&mut runner, 1), Some(json!(2)));
+
+        let endless = IndexParams {
+            fields: vec!["points".into()],
+            values: vec![json!(0)],
+        };
+        let err = runner.run(endless).unwrap_err();
+        assert!(err.to_string().contains("fuel"), "{}", err);
+
+        assert_eq!(rows_seen(&mut runner, 1), Some(json!(1)));
+    }
+
+    #[test]
+    fn hook_enriches_row() {
+        let params = IndexParams {
+            fields: vec!["points".into()],
+            values: vec![json!(21)],
+        };
+
+        match runner().run(params).unwrap() {
+            HookOutcome::Accept(params) => {
+                assert_eq!(params.fields, vec!["points".to_string(), "double".to_string()]);
+                assert_eq!(params.values, vec![json!(21), json!(42)]);
+            }
+            outcome => panic!("Expected row to be accepted, got {:?}", outcome),
+        }
+    }
+
+    #[test]
+    fn hook_rejects_row_with_reason() {
+        let params = IndexParams {
+            fields: vec!["points".into()],
+            values: vec![json!(-1)],
+        };
+
+        match runner().run(params).unwrap() {
+            HookOutcome::Reject(reason) => assert_eq!(reason, "negative"),
+            outcome => panic!("Expected row to be rejected, got {:?}", outcome),
+        }
+    }
+}
@@ -0,0 +1,194 @@
+use std::{collections::HashMap, fmt::Debug, path::Path};
+
+use anyhow::Result;
+use tracing::{debug, info};
+use wasmtime::*;
+
+use crate::storage::column_frame::ColumnFrame;
+
+use super::abi::check_module_abi;
+use super::code_runner::CodeRunner;
+
+///Instances kept per map function when MAP_INSTANCE_POOL_SIZE isn't set
+pub const DEFAULT_POOL_SIZE: usize = 1;
+///Instances whose memory grew beyond this many bytes get dropped instead of reused,
+///so a module which leaks memory on every row can't grow without bound
+const MAX_POOLED_MEMORY: usize = 64 * 1024 * 1024;
+
+///An instantiated map module along with its store, reused for many rows
+struct MapInstance {
+    store: Store<ColumnFrame>,
+    run: TypedFunc<i32, i32>,
+    ///Exported mutable globals along with their values after instantiation
+    globals: Vec<(Global, Val)>,
+    memory: Option<Memory>,
+}
+
+impl MapInstance {
+    ///Drops the row and resets the exported globals. False if the instance shouldn't be reused.
+    fn reset(&mut self) -> Result<bool> {
+        *self.store.data_mut() = ColumnFrame::new();
+        for (global, value) in &self.globals {
+            global.set(&mut self.store, value.clone())?;
+        }
+        Ok(self.memory.is_none_or(|memory| memory.data_size(&self.store) <= MAX_POOLED_MEMORY))
+    }
+}
+
+///Instances of one map function, instantiated up front. Every row runs in an idle instance with the row swapped
+///into its store, so a scan doesn't pay for instantiating the module per row.
+pub struct MapPool {
+    engine: Engine,
+    module: Module,
+    linker: Linker<ColumnFrame>,
+    idle: Vec<MapInstance>,
+    size: usize,
+}
+
+impl Debug for MapPool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MapPool")
+            .field("idle", &self.idle.len())
+            .field("size", &self.size)
+            .finish_non_exhaustive()
+    }
+}
+
+impl MapPool {
+    pub fn load(compiled_query_storage_path: &str, name: &str, size: usize) -> Result<Self> {
+        let filename = Path::new(compiled_query_storage_path).join(format!("{}.wat", name));
+        let engine = Engine::default();
+        let module = Module::from_file(&engine, filename)?;
+        MapPool::from_module(engine, module, size)
+    }
+
+    pub fn from_module(engine: Engine, module: Module, size: usize) -> Result<Self> {
+        let linker = CodeRunner::map_linker(&engine)?;
+        let mut pool = Self {
+            engine,
+            module,
+            linker,
+            idle: vec![],
+            size,
+        };
+        for _ in 0..size {
+            let instance = pool.instantiate()?;
+            pool.idle.push(instance);
+        }
+        Ok(pool)
+    }
+
+    fn instantiate(&self) -> Result<MapInstance> {
+        let mut store = Store::new(&self.engine, ColumnFrame::new());
+        let instance = self.linker.instantiate(&mut store, &self.module)?;
+        check_module_abi(&mut store, &instance)?;
+        let run = instance.get_typed_func::<i32, i32>(&mut store, "run")?;
+        let globals = instance
+            .exports(&mut store)
+            .filter_map(|export| export.into_global())
+            .collect::<Vec<_>>();
+        let globals = globals
+            .into_iter()
+            .filter_map(|global| {
+                let mutable = global.ty(&store).mutability() == Mutability::Var;
+                mutable.then(|| (global, global.get(&mut store)))
+            })
+            .collect();
+        let memory = instance.get_memory(&mut store, "memory");
+        Ok(MapInstance {
+            store,
+            run,
+            globals,
+            memory,
+        })
+    }
+
+    ///Calls the module's `run(timestamp: i32): bool` for the row. The other columns of the row are available
+    ///through the host functions of `CodeRunner::map_linker`.
+    pub fn run(&mut self, row: ColumnFrame) -> Result<bool> {
+        let timestamp = CodeRunner::map_timestamp(&row)?;
+        let mut instance = match self.idle.pop() {
+            Some(instance) => instance,
+            None => self.instantiate()?,
+        };
+        *instance.store.data_mut() = row;
+        //A trap may leave the instance in any state, so it doesn't go back into the pool
+        let included = instance.run.call(&mut instance.store, timestamp)? != 0;
+        if instance.reset()? && self.idle.len() < self.size {
+            self.idle.push(instance);
+        }
+        Ok(included)
+    }
+}
+
+///Pools of the map functions queried so far. They're kept across queries until a new version of the function
+///gets uploaded.
+#[derive(Debug)]
+pub struct MapPools {
+    compiled_query_storage_path: String,
+    size: usize,
+    pools: HashMap<String, MapPool>,
+}
+
+impl MapPools {
+    pub fn new(compiled_query_storage_path: String, size: usize) -> Self {
+        Self {
+            compiled_query_storage_path,
+            size,
+            pools: HashMap::new(),
+        }
+    }
+
+    ///Drops the instances of the function if it was replaced
+    pub fn invalidate(&mut self, fn_name: &str) {
+        if self.pools.remove(fn_name).is_some() {
+            info!("Dropping pooled instances of map function {}", fn_name);
+        }
+    }
+
+    ///Runs the function for the row, instantiating its pool on first use
+    pub fn run(&mut self, fn_name: &str, row: ColumnFrame) -> Result<bool> {
+        if !self.pools.contains_key(fn_name) {
+            debug!("Instantiating {} instances of map function {}", self.size, fn_name);
+            let pool = MapPool::load(&self.compiled_query_storage_path, fn_name, self.size)?;
+            self.pools.insert(fn_name.to_string(), pool);
+        }
+        self.pools.get_mut(fn_name).unwrap().run(row)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use wasmtime::{Engine, Module};
+
+    use super::MapPool;
+    use crate::storage::{cell::Cell, column_frame::ColumnFrame};
+
+    //Counts its calls in an exported global and keeps rows only on the first call
+    const COUNTING_MAP: &str = r#"
+    (module
+        (global $calls (export "calls") (mut i32) (i32.const 0))
+        (global (export "warenhaus_abi_version") i32 (i32.const 1))
+        (func (export "run") (param i32) (result i32)
+            (global.set $calls (i32.add (global.get $calls) (i32.const 1)))
+            (i32.eq (global.get $calls) (i32.const 1))))
+    "#;
+
+    #[test]
+    fn pooled_instances_reset_their_globals_between_rows() {
+        let engine = Engine::default();
+        let module = Module::new(&engine, COUNTING_MAP).unwrap();
+        let mut pool = MapPool::from_module(engine, module, 1).unwrap();
+        let mut row = ColumnFrame::new();
+        row.insert("id", Cell::Int(1));
+        row.insert("timestamp", Cell::Timestamp(1677125260));
+
+        for _ in 0..3 {
+            assert!(pool.run(row.clone()).unwrap());
+            assert_eq!(pool.idle.len(), 1);
+        }
+        let mut without_timestamp = ColumnFrame::new();
+        without_timestamp.insert("id", Cell::Int(2));
+        assert!(pool.run(without_timestamp).is_err());
+    }
+}
@@ -0,0 +1,79 @@
+use serde::Serialize;
+use wasmtime::{Trap, WasmBacktrace};
+
+use crate::storage::column_frame::ColumnFrame;
+
+///Row ids kept per error. `count` still covers every failed row.
+const MAX_ROW_IDS_PER_ERROR: usize = 100;
+
+///Rows a map function selected, and the rows it failed on
+#[derive(Debug, Default)]
+pub struct MapResult {
+    pub rows: Vec<ColumnFrame>,
+    pub partial_errors: Vec<PartialError>,
+    ///Rows the map function got called with
+    pub scanned: usize,
+}
+
+///All rows a map function failed on for the same reason
+#[derive(Debug, Serialize)]
+pub struct PartialError {
+    pub reason: String,
+    ///Wasm backtrace of the first failed row
+    pub backtrace: Option<String>,
+    pub count: usize,
+    pub row_ids: Vec<i64>,
+}
+
+impl MapResult {
+    ///Adds the row to the error it failed with
+    pub fn record_error(&mut self, row_id: Option<i64>, err: &anyhow::Error) {
+        let reason = match err.downcast_ref::<Trap>() {
+            Some(trap) => trap.to_string(),
+            None => err.to_string(),
+        };
+
+        let index = match self.partial_errors.iter().position(|error| error.reason == reason) {
+            Some(index) => index,
+            None => {
+                self.partial_errors.push(PartialError {
+                    reason,
+                    backtrace: err.downcast_ref::<WasmBacktrace>().map(|backtrace| backtrace.to_string()),
+                    count: 0,
+                    row_ids: vec![],
+                });
+                self.partial_errors.len() - 1
+            }
+        };
+
+        let error = &mut self.partial_errors[index];
+        error.count += 1;
+        if let Some(row_id) = row_id {
+            if error.row_ids.len() < MAX_ROW_IDS_PER_ERROR {
+                error.row_ids.push(row_id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::anyhow;
+    use wasmtime::Trap;
+
+    use super::MapResult;
+
+    #[test]
+    fn errors_are_grouped_by_reason() {
+        let mut result = MapResult::default();
+        result.record_error(Some(1), &Trap::UnreachableCodeReached.into());
+        result.record_error(Some(2), &anyhow!("Expected ID - found None"));
+        result.record_error(Some(3), &Trap::UnreachableCodeReached.into());
+
+        assert_eq!(result.partial_errors.len(), 2);
+        assert_eq!(result.partial_errors[0].reason, "wasm trap: wasm `unreachable` instruction executed");
+        assert_eq!(result.partial_errors[0].count, 2);
+        assert_eq!(result.partial_errors[0].row_ids, vec![1, 3]);
+        assert_eq!(result.partial_errors[1].row_ids, vec![2]);
+    }
+}
@@ -3,7 +3,13 @@ use tracing::{error, info};
 
 use thiserror::Error;
 
+pub mod abi;
+pub mod admission;
 pub mod code_runner;
+pub mod hook;
+pub mod instance_pool;
+pub mod map_result;
+pub mod query_error;
 pub mod wasm_error;
 
 #[derive(Error, Debug)]
@@ -31,17 +37,19 @@ impl AssemblyScriptCompiler {
 
     #[tracing::instrument]
     pub fn compile_to_wat(&self, code: &str) -> Result<String, AssemblyCompilationError> {
-        let mut temp_file = tempfile::Builder::new()
-            .prefix("assemblyscript")
-            .suffix(".ts")
-            .tempfile()?;
-
-        temp_file.write_all(code.as_bytes())?;
-
-        let file_path = temp_file.path();
+        //The SDK goes right next to the code, so `import { Row } from "./warenhaus"` resolves.
+        //The appended export states the ABI version the module gets compiled against.
+        let dir = tempfile::Builder::new().prefix("assemblyscript").tempdir()?;
+        std::fs::write(dir.path().join(abi::SDK_MODULE_NAME), abi::ASSEMBLYSCRIPT_SDK)?;
+        let file_path = dir.path().join("module.ts");
+        let mut file = std::fs::File::create(&file_path)?;
+        file.write_all(code.as_bytes())?;
+        file.write_all(abi::abi_version_export().as_bytes())?;
 
         let asc_result = std::process::Command::new(&self.asm_script_compiler_path)
             .arg(&file_path)
+            //Exports __new, which host functions need to hand strings to the module
+            .arg("--exportRuntime")
             .output()?;
         info!("Compilation Status: {}", asc_result.status);
         if asc_result.status.code().unwrap_or_default() != 0 {
@@ -0,0 +1,26 @@
+use thiserror::Error;
+
+use crate::storage::{filter::FilterError, ContainerError};
+
+use super::wasm_error::WasmError;
+
+#[derive(Debug, Error)]
+pub enum QueryError {
+    #[error("Invalid Filter: {source}")]
+    InvalidFilter {
+        #[from]
+        source: FilterError,
+    },
+    #[error("Unknown table: {0}")]
+    UnknownTable(String),
+    #[error("Storage Error: {source}")]
+    Storage {
+        #[from]
+        source: ContainerError,
+    },
+    #[error("{source}")]
+    Wasm {
+        #[from]
+        source: WasmError,
+    },
+}
@@ -2,12 +2,16 @@ use thiserror::Error;
 
 #[derive(Debug, Error)]
 pub enum WasmError {
-    #[error("Code is invalid")]
-    InvalidCode,
     #[error("Assembly Script Compiler not Found")]
     CompilerNotFound,
     #[error("Compiler Error: {0}")]
     CompilerError(String),
+    #[error("Code was written against ABI version {0}, the server speaks version {}. Fetch the current SDK from /sdk/assemblyscript.ts", super::abi::ABI_VERSION)]
+    AbiMismatch(String),
+    #[error("Module was compiled against ABI version {0}. Recompile it against SDK v{} by uploading it again", super::abi::ABI_VERSION)]
+    ModuleAbiMismatch(String),
+    #[error("Reads columns the table doesn't have: {}. Upload it again with the current column names", .0.join(", "))]
+    MissingColumns(Vec<String>),
     #[error("IO Error")]
     Io {
         #[from]
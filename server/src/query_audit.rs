@@ -0,0 +1,191 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::warn;
+
+use crate::storage::cell::Cell;
+
+///A single execution of a map function
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    ///Unix timestamp the query finished at
+    pub executed_at: u64,
+    pub function: String,
+    ///SHA-256 of the compiled module the query ran, which changes whenever the function gets uploaded again.
+    ///`None` if the module doesn't exist.
+    pub module_sha256: Option<String>,
+    ///Query string of the request, like the filter and the table
+    pub parameters: BTreeMap<String, String>,
+    ///Fingerprint of the request's API key, see `auth::key_fingerprint`
+    pub caller: String,
+    pub duration_ms: u64,
+    ///Rows the map function returned. `None` if the query failed.
+    pub rows: Option<usize>,
+    ///Fingerprint of the returned rows, see `fingerprint`. `None` if the query failed.
+    pub result_sha256: Option<String>,
+    ///Status the query failed with
+    pub error: Option<String>,
+}
+
+///Entries of the audit log to list
+#[derive(Debug, Default, Deserialize)]
+pub struct AuditQuery {
+    ///Only entries executed at or after this unix timestamp
+    pub since: Option<u64>,
+    ///Only entries executed at or before this unix timestamp
+    pub until: Option<u64>,
+    pub function: Option<String>,
+}
+
+///Append-only log of every query execution, kept in `query_audit.jsonl` with one entry per line.
+///It's never truncated, so it answers which data a query saw long after the rows changed.
+#[derive(Debug)]
+pub struct QueryAudit {
+    path: PathBuf,
+    ///Directory of the compiled map functions
+    modules_path: PathBuf,
+    ///Keeps concurrent queries from interleaving their lines
+    file: Mutex<Option<File>>,
+}
+
+///Hashes the rows in order. Each row gets hashed with its columns sorted by name, so the fingerprint only
+///changes if the rows do.
+pub fn fingerprint(rows: &[HashMap<String, Cell>]) -> String {
+    let mut hasher = Sha256::new();
+    for row in rows {
+        let sorted = row.iter().collect::<BTreeMap<_, _>>();
+        let bytes = serde_json::to_vec(&sorted).unwrap_or_default();
+        //The length keeps the boundaries between rows apart
+        hasher.update((bytes.len() as u64).to_le_bytes());
+        hasher.update(bytes);
+    }
+    hex(&hasher.finalize())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+impl QueryAudit {
+    pub fn file_path(db_root_path: &Path) -> PathBuf {
+        db_root_path.join("query_audit.jsonl")
+    }
+
+    pub fn new(db_root_path: &Path, modules_path: &Path) -> Self {
+        Self {
+            path: QueryAudit::file_path(db_root_path),
+            modules_path: modules_path.to_path_buf(),
+            file: Mutex::new(None),
+        }
+    }
+
+    ///Fingerprint of the module currently stored for the function
+    pub fn module_sha256(&self, fn_name: &str) -> Option<String> {
+        let module = fs::read(self.modules_path.join(format!("{}.wat", fn_name))).ok()?;
+        Some(hex(&Sha256::digest(module)))
+    }
+
+    ///Entry for a query which returned the rows, or failed with the error
+    pub fn entry(
+        &self,
+        fn_name: &str,
+        parameters: &HashMap<String, String>,
+        caller: &str,
+        duration: Duration,
+        outcome: Result<&[HashMap<String, Cell>], String>,
+    ) -> AuditEntry {
+        let (rows, result_sha256, error) = match outcome {
+            Ok(rows) => (Some(rows.len()), Some(fingerprint(rows)), None),
+            Err(error) => (None, None, Some(error)),
+        };
+        AuditEntry {
+            executed_at: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+            function: fn_name.to_string(),
+            module_sha256: self.module_sha256(fn_name),
+            parameters: parameters.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect(),
+            caller: caller.to_string(),
+            duration_ms: duration.as_millis() as u64,
+            rows,
+            result_sha256,
+            error,
+        }
+    }
+
+    pub fn record(&self, entry: &AuditEntry) -> io::Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        let mut file = self.file.lock().unwrap();
+        if file.is_none() {
+            *file = Some(OpenOptions::new().create(true).append(true).open(&self.path)?);
+        }
+        //A single write, so a crash at most leaves a torn last line behind
+        file.as_mut().unwrap().write_all(&line)
+    }
+
+    ///Entries matching the query, oldest first. Lines which don't parse, like one torn by a crash, get skipped.
+    pub fn entries(&self, query: &AuditQuery) -> io::Result<Vec<AuditEntry>> {
+        let f = match File::open(&self.path) {
+            Ok(f) => f,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => return Err(err),
+        };
+        let mut entries = vec![];
+        for line in BufReader::new(f).lines() {
+            let entry: AuditEntry = match serde_json::from_str(&line?) {
+                Ok(entry) => entry,
+                Err(err) => {
+                    warn!("Skipping unreadable line of the query audit log: {}", err);
+                    continue;
+                }
+            };
+            let matches = query.since.is_none_or(|since| entry.executed_at >= since)
+                && query.until.is_none_or(|until| entry.executed_at <= until)
+                && query.function.as_ref().is_none_or(|function| &entry.function == function);
+            if matches {
+                entries.push(entry);
+            }
+        }
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    use super::{fingerprint, AuditQuery, QueryAudit};
+    use crate::storage::cell::Cell;
+
+    #[test]
+    fn executions_get_listed_with_their_fingerprint() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("count.wat"), "(module)").unwrap();
+        let audit = QueryAudit::new(dir.path(), dir.path());
+        let rows = vec![HashMap::from([("id".to_string(), Cell::Int(1)), ("url".to_string(), Cell::String("a".into()))])];
+        let params = HashMap::from([("from".to_string(), "0".to_string())]);
+
+        let entry = audit.entry("count", &params, "team-a", Duration::from_millis(5), Ok(&rows));
+        assert_eq!(entry.rows, Some(1));
+        assert_eq!(entry.result_sha256, Some(fingerprint(&rows)));
+        assert!(entry.module_sha256.is_some());
+        audit.record(&entry).unwrap();
+        audit.record(&audit.entry("other", &params, "team-b", Duration::ZERO, Err("404 Not Found".into()))).unwrap();
+
+        let restarted = QueryAudit::new(dir.path(), dir.path());
+        assert_eq!(restarted.entries(&AuditQuery::default()).unwrap().len(), 2);
+        let query = AuditQuery { function: Some("count".into()), ..AuditQuery::default() };
+        assert_eq!(restarted.entries(&query).unwrap(), vec![entry]);
+
+        //Rows with the same cells have the same fingerprint, no matter the column order
+        let reordered = vec![HashMap::from([("url".to_string(), Cell::String("a".into())), ("id".to_string(), Cell::Int(1))])];
+        assert_eq!(fingerprint(&reordered), fingerprint(&rows));
+        assert_ne!(fingerprint(&reordered), fingerprint(&[]));
+    }
+}
@@ -0,0 +1,191 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+use tracing::{info, warn};
+
+///Finished results get deleted this long after the query finished
+pub const RESULT_TTL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ResultStatus {
+    Running,
+    Done,
+    Failed,
+}
+
+///State of a query running in the background, as `GET /results/{id}` reports it
+#[derive(Debug, Clone, Serialize)]
+pub struct ResultInfo {
+    pub id: String,
+    pub fn_name: String,
+    pub status: ResultStatus,
+    pub created_at: u64,
+    pub finished_at: Option<u64>,
+    ///Number of rows, once the query is done
+    pub row_count: usize,
+    pub partial_errors: serde_json::Value,
+    ///Response body the query would have failed with, if it failed
+    pub error: Option<serde_json::Value>,
+    ///Authenticated caller who started the query, the only one who gets to see the result
+    #[serde(skip)]
+    pub owner: Option<String>,
+}
+
+///Results of queries running in the background. Rows get written to `<id>.ndjson`, one JSON object per line,
+///so they can be paged through without holding them in memory. Results don't survive restarts.
+#[derive(Debug)]
+pub struct ResultStore {
+    dir: PathBuf,
+    results: Mutex<HashMap<String, ResultInfo>>,
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+impl ResultStore {
+    pub fn dir_path(db_root_path: &Path) -> PathBuf {
+        db_root_path.join("results")
+    }
+
+    pub fn new(db_root_path: &Path) -> Self {
+        Self {
+            dir: ResultStore::dir_path(db_root_path),
+            results: Mutex::new(HashMap::new()),
+        }
+    }
+
+    ///Deletes the files of results from before the last restart, which can't be looked up anymore
+    pub fn reset(&self) -> io::Result<()> {
+        match fs::remove_dir_all(&self.dir) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+            _ => {}
+        }
+        fs::create_dir_all(&self.dir)
+    }
+
+    pub fn file_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.ndjson", id))
+    }
+
+    ///Registers a running query. Results share the id of the job running the query.
+    pub fn start(&self, id: &str, fn_name: &str, owner: Option<String>) {
+        self.results.lock().unwrap().insert(
+            id.to_string(),
+            ResultInfo {
+                id: id.to_string(),
+                fn_name: fn_name.to_string(),
+                status: ResultStatus::Running,
+                created_at: now(),
+                finished_at: None,
+                row_count: 0,
+                partial_errors: serde_json::Value::Array(vec![]),
+                error: None,
+                owner,
+            },
+        );
+    }
+
+    pub fn finish<R: Serialize>(&self, id: &str, rows: &[R], partial_errors: serde_json::Value) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(self.file_path(id))?);
+        for row in rows {
+            serde_json::to_writer(&mut writer, row)?;
+            writer.write_all(b"\n")?;
+        }
+        writer.flush()?;
+        self.update(id, |info| {
+            info.status = ResultStatus::Done;
+            info.row_count = rows.len();
+            info.partial_errors = partial_errors;
+        });
+        Ok(())
+    }
+
+    pub fn fail(&self, id: &str, error: serde_json::Value) {
+        self.update(id, |info| {
+            info.status = ResultStatus::Failed;
+            info.error = Some(error);
+        });
+    }
+
+    fn update(&self, id: &str, f: impl FnOnce(&mut ResultInfo)) {
+        if let Some(info) = self.results.lock().unwrap().get_mut(id) {
+            f(info);
+            info.finished_at = Some(now());
+        }
+    }
+
+    ///The result, unless another caller than `caller` started the query
+    pub fn info(&self, id: &str, caller: Option<&str>) -> Option<ResultInfo> {
+        self.results.lock().unwrap().get(id).filter(|info| info.owner.as_deref() == caller).cloned()
+    }
+
+    ///Rows `offset..offset + limit` of a finished result
+    pub fn page(&self, id: &str, offset: usize, limit: usize) -> io::Result<Vec<serde_json::Value>> {
+        let reader = BufReader::new(File::open(self.file_path(id))?);
+        let mut rows = vec![];
+        for line in reader.lines().skip(offset).take(limit) {
+            rows.push(serde_json::from_str(&line?)?);
+        }
+        Ok(rows)
+    }
+
+    ///Forgets results which finished more than RESULT_TTL ago and deletes their files
+    pub fn expire(&self) {
+        let cutoff = now().saturating_sub(RESULT_TTL.as_secs());
+        let expired = {
+            let mut results = self.results.lock().unwrap();
+            let expired = results
+                .values()
+                .filter(|info| info.finished_at.is_some_and(|finished_at| finished_at < cutoff))
+                .map(|info| info.id.clone())
+                .collect::<Vec<_>>();
+            for id in &expired {
+                results.remove(id);
+            }
+            expired
+        };
+        for id in expired {
+            info!("Deleting expired query result {}", id);
+            if let Err(err) = fs::remove_file(self.file_path(&id)) {
+                if err.kind() != io::ErrorKind::NotFound {
+                    warn!("Failed to delete query result {}: {}", id, err);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{ResultStatus, ResultStore};
+
+    #[test]
+    fn finished_results_can_be_paged() {
+        let dir = tempfile::tempdir().unwrap();
+        let results = ResultStore::new(dir.path());
+        results.reset().unwrap();
+        let id = "query-1";
+        results.start(id, "query", Some("alice".to_string()));
+        assert_eq!(results.info(id, Some("alice")).unwrap().status, ResultStatus::Running);
+        assert!(results.info(id, Some("mallory")).is_none());
+        assert!(results.info(id, None).is_none());
+
+        let rows = (1..=5).map(|n| json!({ "id": n })).collect::<Vec<_>>();
+        results.finish(id, &rows, json!([])).unwrap();
+
+        let info = results.info(id, Some("alice")).unwrap();
+        assert_eq!(info.status, ResultStatus::Done);
+        assert_eq!(info.row_count, 5);
+        assert_eq!(results.page(id, 3, 10).unwrap(), vec![json!({ "id": 4 }), json!({ "id": 5 })]);
+        assert!(results.info("unknown", Some("alice")).is_none());
+    }
+}
@@ -0,0 +1,172 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+use thiserror::Error;
+use tokio::sync::Notify;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SchemaOperation {
+    Backfill,
+    Compaction,
+    RenameColumn,
+    DropColumn,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SchemaLock {
+    pub operation: SchemaOperation,
+    pub description: String,
+    ///When the lock got taken, or when waiting for it started
+    pub since: u64,
+}
+
+///What `GET /admin/locks` reports
+#[derive(Debug, Serialize)]
+pub struct LockState {
+    pub holder: Option<SchemaLock>,
+    ///Operations queued behind the holder, first in line first
+    pub waiting: Vec<SchemaLock>,
+}
+
+#[derive(Debug, Error)]
+pub enum LockError {
+    #[error("The schema is locked by {} since {}, retry later or pass wait=true", .0.description, .0.since)]
+    Locked(SchemaLock),
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+#[derive(Debug, Default)]
+struct LockTable {
+    holder: Option<SchemaLock>,
+    waiting: VecDeque<(u64, SchemaLock)>,
+}
+
+///Keeps operations rewriting the table layout from running at the same time, e.g. a rename in the middle
+///of a backfill. Only one of them holds the lock, the others get rejected or wait in line. Inserts, updates,
+///deletes and queries don't take it.
+#[derive(Debug, Default)]
+pub struct SchemaLocks {
+    table: Mutex<LockTable>,
+    released: Notify,
+    next_ticket: AtomicU64,
+}
+
+///Releases the schema lock when dropped
+#[derive(Debug)]
+pub struct SchemaLockGuard {
+    locks: Arc<SchemaLocks>,
+}
+
+impl Drop for SchemaLockGuard {
+    fn drop(&mut self) {
+        self.locks.table.lock().unwrap().holder = None;
+        self.locks.released.notify_waiters();
+    }
+}
+
+impl SchemaLocks {
+    ///Takes the lock, unless another operation holds it or waits for it
+    pub fn try_acquire(self: &Arc<Self>, operation: SchemaOperation, description: &str) -> Result<SchemaLockGuard, LockError> {
+        let mut table = self.table.lock().unwrap();
+        if let Some(holder) = table.holder.as_ref().or(table.waiting.front().map(|(_, lock)| lock)) {
+            return Err(LockError::Locked(holder.clone()));
+        }
+        table.holder = Some(SchemaLock {
+            operation,
+            description: description.to_string(),
+            since: now(),
+        });
+        Ok(SchemaLockGuard { locks: self.clone() })
+    }
+
+    ///Waits in line until the lock is free, then takes it
+    pub async fn acquire(self: &Arc<Self>, operation: SchemaOperation, description: &str) -> SchemaLockGuard {
+        let ticket = self.next_ticket.fetch_add(1, Ordering::SeqCst);
+        let lock = SchemaLock {
+            operation,
+            description: description.to_string(),
+            since: now(),
+        };
+        self.table.lock().unwrap().waiting.push_back((ticket, lock));
+        //Leaves the line if the waiting request goes away
+        let mut waiter = Waiter { locks: self.clone(), ticket: Some(ticket) };
+        loop {
+            let released = self.released.notified();
+            tokio::pin!(released);
+            released.as_mut().enable();
+            {
+                let mut table = self.table.lock().unwrap();
+                if table.holder.is_none() && table.waiting.front().is_some_and(|(first, _)| *first == ticket) {
+                    let (_, mut lock) = table.waiting.pop_front().unwrap();
+                    lock.since = now();
+                    table.holder = Some(lock);
+                    waiter.ticket = None;
+                    return SchemaLockGuard { locks: self.clone() };
+                }
+            }
+            released.await;
+        }
+    }
+
+    pub fn state(&self) -> LockState {
+        let table = self.table.lock().unwrap();
+        LockState {
+            holder: table.holder.clone(),
+            waiting: table.waiting.iter().map(|(_, lock)| lock.clone()).collect(),
+        }
+    }
+}
+
+struct Waiter {
+    locks: Arc<SchemaLocks>,
+    ticket: Option<u64>,
+}
+
+impl Drop for Waiter {
+    fn drop(&mut self) {
+        if let Some(ticket) = self.ticket {
+            self.locks.table.lock().unwrap().waiting.retain(|(waiting, _)| *waiting != ticket);
+            //The next one in line may be able to go now
+            self.locks.released.notify_waiters();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::{SchemaLocks, SchemaOperation};
+
+    #[tokio::test]
+    async fn operations_get_rejected_or_wait_in_line() {
+        let locks = Arc::new(SchemaLocks::default());
+        let backfill = locks.try_acquire(SchemaOperation::Backfill, "backfill computed columns").unwrap();
+        assert!(locks.try_acquire(SchemaOperation::RenameColumn, "rename column url").is_err());
+
+        let waiting = locks.clone();
+        let rename = tokio::spawn(async move { waiting.acquire(SchemaOperation::RenameColumn, "rename column url").await });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let state = locks.state();
+        assert_eq!(state.holder.unwrap().operation, SchemaOperation::Backfill);
+        assert_eq!(state.waiting.len(), 1);
+        //Operations in line go first
+        drop(backfill);
+        assert!(locks.try_acquire(SchemaOperation::Compaction, "compact column files").is_err());
+
+        let rename = rename.await.unwrap();
+        let state = locks.state();
+        assert_eq!(state.holder.unwrap().operation, SchemaOperation::RenameColumn);
+        assert!(state.waiting.is_empty());
+        drop(rename);
+        assert!(locks.try_acquire(SchemaOperation::Compaction, "compact column files").is_ok());
+    }
+}
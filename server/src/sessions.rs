@@ -0,0 +1,222 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+use thiserror::Error;
+use tracing::{info, warn};
+
+use crate::web::IndexParams;
+
+///Sessions without a new batch for this long get discarded
+pub const SESSION_TTL: Duration = Duration::from_secs(60 * 60);
+
+///State of a bulk ingestion session, as `GET /ingest/sessions/{id}` reports it
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionInfo {
+    pub id: String,
+    pub created_at: u64,
+    pub last_batch_at: u64,
+    pub batches: usize,
+    ///Rows staged so far. None of them are visible until the session gets committed.
+    pub rows: usize,
+    ///True while the commit is running
+    pub committing: bool,
+}
+
+#[derive(Debug, Error)]
+pub enum SessionError {
+    #[error("Unknown ingest session {0}")]
+    UnknownSession(String),
+    #[error("Ingest session {0} is being committed")]
+    Committing(String),
+    #[error("IO Error: {source}")]
+    IoError {
+        #[from]
+        source: io::Error,
+    },
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+///Rows staged by bulk ingestion sessions. Batches get appended to `<id>.ndjson`, one insert per line,
+///and only reach the table once the whole session gets committed as a single transaction.
+///Sessions don't survive restarts.
+#[derive(Debug)]
+pub struct IngestSessions {
+    dir: PathBuf,
+    sessions: Mutex<HashMap<String, SessionInfo>>,
+    next_id: AtomicU64,
+}
+
+impl IngestSessions {
+    pub fn dir_path(db_root_path: &Path) -> PathBuf {
+        db_root_path.join("sessions")
+    }
+
+    pub fn new(db_root_path: &Path) -> Self {
+        Self {
+            dir: IngestSessions::dir_path(db_root_path),
+            sessions: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(0),
+        }
+    }
+
+    ///Deletes rows staged before the last restart, whose sessions can't be committed anymore
+    pub fn reset(&self) -> io::Result<()> {
+        match fs::remove_dir_all(&self.dir) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+            _ => {}
+        }
+        fs::create_dir_all(&self.dir)
+    }
+
+    fn file_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.ndjson", id))
+    }
+
+    pub fn start(&self) -> io::Result<SessionInfo> {
+        let created_at = now();
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().subsec_nanos();
+        let id = format!("{:x}{:08x}{:x}", created_at, nanos, self.next_id.fetch_add(1, Ordering::SeqCst));
+        File::create(self.file_path(&id))?;
+        let info = SessionInfo {
+            id: id.clone(),
+            created_at,
+            last_batch_at: created_at,
+            batches: 0,
+            rows: 0,
+            committing: false,
+        };
+        self.sessions.lock().unwrap().insert(id, info.clone());
+        Ok(info)
+    }
+
+    pub fn info(&self, id: &str) -> Option<SessionInfo> {
+        self.sessions.lock().unwrap().get(id).cloned()
+    }
+
+    ///Stages the rows of a batch
+    pub fn append(&self, id: &str, rows: &[IndexParams]) -> Result<SessionInfo, SessionError> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let info = sessions.get_mut(id).ok_or_else(|| SessionError::UnknownSession(id.to_string()))?;
+        if info.committing {
+            return Err(SessionError::Committing(id.to_string()));
+        }
+        let mut writer = BufWriter::new(OpenOptions::new().append(true).open(self.file_path(id))?);
+        for row in rows {
+            serde_json::to_writer(&mut writer, row).map_err(io::Error::from)?;
+            writer.write_all(b"\n")?;
+        }
+        writer.flush()?;
+        info.batches += 1;
+        info.rows += rows.len();
+        info.last_batch_at = now();
+        Ok(info.clone())
+    }
+
+    ///Returns all staged rows and rejects further batches until `finish_commit`
+    pub fn begin_commit(&self, id: &str) -> Result<Vec<IndexParams>, SessionError> {
+        {
+            let mut sessions = self.sessions.lock().unwrap();
+            let info = sessions.get_mut(id).ok_or_else(|| SessionError::UnknownSession(id.to_string()))?;
+            if info.committing {
+                return Err(SessionError::Committing(id.to_string()));
+            }
+            info.committing = true;
+        }
+        let rows = File::open(self.file_path(id)).and_then(|f| {
+            let mut rows = vec![];
+            for line in BufReader::new(f).lines() {
+                rows.push(serde_json::from_str(&line?)?);
+            }
+            Ok(rows)
+        });
+        if rows.is_err() {
+            self.finish_commit(id, false);
+        }
+        Ok(rows?)
+    }
+
+    ///Discards a committed session. A failed commit leaves it open, to retry or abort it.
+    pub fn finish_commit(&self, id: &str, committed: bool) {
+        match committed {
+            true => {
+                self.discard(id);
+            }
+            false => {
+                if let Some(info) = self.sessions.lock().unwrap().get_mut(id) {
+                    info.committing = false;
+                }
+            }
+        }
+    }
+
+    ///Forgets the session and deletes its staged rows. False if there was no such session.
+    pub fn discard(&self, id: &str) -> bool {
+        if self.sessions.lock().unwrap().remove(id).is_none() {
+            return false;
+        }
+        if let Err(err) = fs::remove_file(self.file_path(id)) {
+            warn!("Failed to delete rows of ingest session {}: {}", id, err);
+        }
+        true
+    }
+
+    ///Discards sessions which didn't receive a batch for SESSION_TTL
+    pub fn expire(&self) {
+        let cutoff = now().saturating_sub(SESSION_TTL.as_secs());
+        let expired = self
+            .sessions
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|info| !info.committing && info.last_batch_at < cutoff)
+            .map(|info| info.id.clone())
+            .collect::<Vec<_>>();
+        for id in expired {
+            info!("Discarding idle ingest session {}", id);
+            self.discard(&id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{IngestSessions, SessionError};
+    use crate::web::IndexParams;
+
+    #[test]
+    fn staged_rows_are_returned_on_commit() {
+        let dir = tempfile::tempdir().unwrap();
+        let sessions = IngestSessions::new(dir.path());
+        sessions.reset().unwrap();
+        let id = sessions.start().unwrap().id;
+        let row = |n: i64| IndexParams {
+            fields: vec!["n".into()],
+            values: vec![json!(n)],
+        };
+        sessions.append(&id, &[row(1), row(2)]).unwrap();
+        let info = sessions.append(&id, &[row(3)]).unwrap();
+        assert_eq!((info.batches, info.rows), (2, 3));
+
+        let rows = sessions.begin_commit(&id).unwrap();
+        assert_eq!(rows.iter().map(|row| row.values[0].clone()).collect::<Vec<_>>(), vec![json!(1), json!(2), json!(3)]);
+        assert!(matches!(sessions.append(&id, &[row(4)]), Err(SessionError::Committing(_))));
+
+        sessions.finish_commit(&id, false);
+        sessions.append(&id, &[row(4)]).unwrap();
+        assert_eq!(sessions.begin_commit(&id).unwrap().len(), 4);
+        sessions.finish_commit(&id, true);
+        assert!(sessions.info(&id).is_none());
+        assert!(!sessions.discard(&id));
+    }
+}
@@ -1,55 +1,63 @@
-use std::{fs, path::{Path, PathBuf}};
+use std::{io, path::{Path, PathBuf}};
 
 use serde::{Serialize, Deserialize};
-use tracing::error;
+use tracing::warn;
 
 use super::auto_index_error::AutoIndexError;
+use super::checked_file::CheckedFile;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AutoIndex {
     counter: i64,
     #[serde(skip_serializing, skip_deserializing)]
-    file_path: String,
+    file: CheckedFile,
 }
 
 impl AutoIndex {
-    pub fn load_or_new(root_path: &PathBuf) -> Self {
-        let root_path = Path::new(root_path);
-        let file_path = root_path.join("auto_index");
-
-        match fs::read_to_string(file_path.clone()) {
-            Ok(str) => match serde_json::from_str::<Self>(&str) {
-                Ok(mut auto_index) => {
-                    auto_index.file_path = file_path.to_str().unwrap().to_string();
-                    return auto_index;
-                }
-                Err(serde_err) => {
-                    error!("Error while deserializing auto index: {}", serde_err);
-                }
-            },
-            Err(err) => {
-                error!("Failed to load auto index: {}. Setting Index to 0", err);
-            }
-        }
+    pub fn file_path(root_path: &PathBuf) -> PathBuf {
+        Path::new(root_path).join("auto_index")
+    }
 
+    ///Starts counting from 0, ignoring anything stored on disk
+    pub fn new(root_path: &PathBuf) -> Self {
         Self {
             counter: 0,
-            file_path: file_path.to_str().unwrap().to_string(),
+            file: CheckedFile::new(AutoIndex::file_path(root_path)),
+        }
+    }
+
+    ///Loads the last intact counter. Only starts from 0 if no counter was stored yet.
+    pub fn load_or_new(root_path: &PathBuf) -> Result<Self, AutoIndexError> {
+        match CheckedFile::load(AutoIndex::file_path(root_path)) {
+            Ok((file, payload)) => {
+                let mut auto_index = serde_json::from_slice::<Self>(&payload)?;
+                auto_index.file = file;
+                Ok(auto_index)
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                warn!("No auto index found. Setting Index to 0");
+                Ok(AutoIndex::new(root_path))
+            }
+            Err(err) => Err(err.into()),
         }
     }
 
     pub fn next(&mut self) -> i64 {
         self.counter += 1;
-        return self.counter;
+        self.counter
+    }
+
+    pub fn reset_to(&mut self, counter: i64) {
+        self.counter = counter;
     }
 
     pub fn rollback(&mut self) {
         self.counter -= 1;
     }
 
-    pub fn commit(&self) -> Result<(), AutoIndexError> {
-        let j = serde_json::to_string(self)?;
-        fs::write(&self.file_path, j)?;
+    pub fn commit(&mut self) -> Result<(), AutoIndexError> {
+        let j = serde_json::to_vec(self)?;
+        self.file.write(&j)?;
         Ok(())
     }
 
@@ -0,0 +1,195 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::num::NonZeroUsize;
+use std::ops::Deref;
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use nix::sys::mman::{mmap, munmap, MapFlags, ProtFlags};
+
+use crate::config::StorageBackendConfig;
+
+use super::s3_backend::S3Backend;
+
+///Where a table's column files live. Files are addressed by name, relative to the table.
+///Columns only ever append to their file, replace it as a whole, or cut off what a rolled back write appended.
+pub trait StorageBackend: Debug + Send + Sync {
+    ///Length of the file in bytes, `None` if it doesn't exist
+    fn len(&self, name: &str) -> io::Result<Option<u64>>;
+
+    ///Appends the bytes to the end of the file, creating it if it doesn't exist
+    fn append(&self, name: &str, bytes: &[u8]) -> io::Result<()>;
+
+    ///Reads the bytes between the two offsets
+    fn read_range(&self, name: &str, start: u64, end: u64) -> io::Result<Box<dyn Read + Send>>;
+
+    ///Replaces the file's contents. Readers either see the old or the new contents, never a mix.
+    fn replace(&self, name: &str, bytes: &[u8]) -> io::Result<()>;
+
+    ///Cuts the file off after `len` bytes, without writing the bytes in front again, so it works on a full volume
+    fn truncate(&self, name: &str, len: u64) -> io::Result<()>;
+
+    ///Deletes the file. Deleting a file which doesn't exist is not an error.
+    fn remove(&self, name: &str) -> io::Result<()>;
+
+    ///Human readable location of the file, for log and error messages
+    fn location(&self, name: &str) -> String;
+
+    ///Maps the file into memory. `None` if the backend can't, readers fall back to `read_range` then.
+    fn map(&self, _name: &str) -> io::Result<Option<MappedFile>> {
+        Ok(None)
+    }
+}
+
+///Read-only mapping of a whole file, see `StorageBackend::map`.
+///Appending to the file doesn't change what's mapped, replacing it leaves the mapping with the old contents.
+pub struct MappedFile {
+    ptr: *mut std::ffi::c_void,
+    len: usize,
+}
+
+//SAFETY: The mapping is read-only and owned by this struct alone, so sharing it between threads is like sharing a &[u8]
+unsafe impl Send for MappedFile {}
+unsafe impl Sync for MappedFile {}
+
+impl MappedFile {
+    fn open(path: &Path) -> io::Result<Self> {
+        let file = File::open(path)?;
+        let Some(len) = NonZeroUsize::new(file.metadata()?.len() as usize) else {
+            //Empty files can't be mapped
+            return Ok(Self {
+                ptr: std::ptr::null_mut(),
+                len: 0,
+            });
+        };
+        //SAFETY: Files only ever get appended to or replaced by renaming another file over them, never truncated,
+        //so the mapped pages stay backed by the file for as long as the mapping exists
+        let ptr = unsafe { mmap(None, len, ProtFlags::PROT_READ, MapFlags::MAP_PRIVATE, file.as_raw_fd(), 0) }?;
+        Ok(Self { ptr, len: len.get() })
+    }
+}
+
+impl Deref for MappedFile {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self.len {
+            0 => &[],
+            //SAFETY: ptr points to len readable bytes until the mapping gets dropped
+            len => unsafe { std::slice::from_raw_parts(self.ptr as *const u8, len) },
+        }
+    }
+}
+
+impl Drop for MappedFile {
+    fn drop(&mut self) {
+        if self.len > 0 {
+            //SAFETY: Nothing borrows from the mapping anymore
+            let _ = unsafe { munmap(self.ptr, self.len) };
+        }
+    }
+}
+
+impl Debug for MappedFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MappedFile").field("len", &self.len).finish()
+    }
+}
+
+///Opens the backend configured in the schema for the table stored at root_path
+pub fn open(config: &StorageBackendConfig, root_path: &Path) -> io::Result<Arc<dyn StorageBackend>> {
+    match config {
+        StorageBackendConfig::Local => Ok(Arc::new(LocalBackend::new(root_path))),
+        StorageBackendConfig::S3(s3_config) => Ok(Arc::new(S3Backend::new(s3_config, root_path)?)),
+    }
+}
+
+///Files in the table's directory on the local disk. The default.
+#[derive(Debug)]
+pub struct LocalBackend {
+    root_path: PathBuf,
+    ///Append handles stay open, so appending a record doesn't cost an open call
+    appenders: Mutex<HashMap<String, File>>,
+}
+
+impl LocalBackend {
+    pub fn new(root_path: &Path) -> Self {
+        Self {
+            root_path: root_path.to_path_buf(),
+            appenders: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.root_path.join(name)
+    }
+}
+
+impl StorageBackend for LocalBackend {
+    fn len(&self, name: &str) -> io::Result<Option<u64>> {
+        match fs::metadata(self.path(name)) {
+            Ok(metadata) => Ok(Some(metadata.len())),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn append(&self, name: &str, bytes: &[u8]) -> io::Result<()> {
+        let mut appenders = self.appenders.lock().unwrap();
+        let file = match appenders.get_mut(name) {
+            Some(file) => file,
+            None => {
+                let file = OpenOptions::new().create(true).append(true).open(self.path(name))?;
+                appenders.entry(name.to_string()).or_insert(file)
+            }
+        };
+        file.write_all(bytes)
+    }
+
+    fn read_range(&self, name: &str, start: u64, end: u64) -> io::Result<Box<dyn Read + Send>> {
+        let mut file = File::open(self.path(name))?;
+        file.seek(SeekFrom::Start(start))?;
+        Ok(Box::new(file.take(end.saturating_sub(start))))
+    }
+
+    fn replace(&self, name: &str, bytes: &[u8]) -> io::Result<()> {
+        let path = self.path(name);
+        //Write to a temporary file first, so a crash can't leave a half written file behind
+        let tmp_path = path.with_extension("replacing");
+        {
+            let mut tmp = File::create(&tmp_path)?;
+            tmp.write_all(bytes)?;
+            tmp.sync_all()?;
+        }
+        //The open handle still points to the replaced file
+        self.appenders.lock().unwrap().remove(name);
+        fs::rename(&tmp_path, &path)
+    }
+
+    fn truncate(&self, name: &str, len: u64) -> io::Result<()> {
+        //The append handle keeps working, appends go to the new end of the file.
+        //Mappings never reach beyond the cut, as they were taken before the appends which get cut off.
+        let file = OpenOptions::new().write(true).open(self.path(name))?;
+        file.set_len(len)?;
+        file.sync_all()
+    }
+
+    fn remove(&self, name: &str) -> io::Result<()> {
+        self.appenders.lock().unwrap().remove(name);
+        match fs::remove_file(self.path(name)) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+
+    fn location(&self, name: &str) -> String {
+        self.path(name).display().to_string()
+    }
+
+    fn map(&self, name: &str) -> io::Result<Option<MappedFile>> {
+        MappedFile::open(&self.path(name)).map(Some)
+    }
+}
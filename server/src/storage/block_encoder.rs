@@ -0,0 +1,319 @@
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::io;
+use std::io::prelude::*;
+
+use crate::config::ColumnEncoding;
+use crate::storage::ByteString;
+
+use super::cell::Cell;
+use super::data_type::DataType;
+
+///Tag of records holding one cell repeated several times. Their payload is the count, followed by the
+///tag and payload of the cell's own record.
+pub const TAG_RUN: u8 = 0xFE;
+///Tag of records holding consecutive Int or Timestamp cells. Their payload is the count, the tag of the cells,
+///the first and the last value, followed by the difference of every other value to the one before as a zigzag varint.
+pub const TAG_DELTA: u8 = 0xFC;
+///Tag of records holding consecutive cells, each either new or a repeat of one the record held before.
+///Their payload is the count, followed by a varint per cell: 0 for a new cell followed by the tag, length and
+///payload of its own record, n for a repeat of the nth new cell.
+pub const TAG_DICTIONARY: u8 = 0xFB;
+///Delta and dictionary records hold this many cells at most, so merging a cell never copies more than that
+const MAX_PACKED_CELLS: u32 = 1024;
+
+///Turns the cells inserted into a column into records of its file
+pub trait BlockEncoder {
+    ///Tag and payload of a record holding just this cell
+    fn encode(&self, cell: &Cell) -> io::Result<(u8, ByteString)>;
+    ///Tag and payload of a record holding the cells of an existing record followed by this cell.
+    ///`None` if the cell needs a record of its own.
+    fn merge(&self, tag: u8, payload: &[u8], cell: &Cell) -> io::Result<Option<(u8, ByteString)>>;
+}
+
+///One record per cell
+#[derive(Debug)]
+pub struct PlainEncoder;
+
+impl BlockEncoder for PlainEncoder {
+    fn encode(&self, cell: &Cell) -> io::Result<(u8, ByteString)> {
+        let (_checksum, tag_byte, bytes) = cell.to_bytes()?;
+        Ok((tag_byte, bytes))
+    }
+
+    fn merge(&self, _tag: u8, _payload: &[u8], _cell: &Cell) -> io::Result<Option<(u8, ByteString)>> {
+        Ok(None)
+    }
+}
+
+///Collapses consecutive identical cells into a single run record
+#[derive(Debug)]
+pub struct RunLengthEncoder;
+
+impl BlockEncoder for RunLengthEncoder {
+    fn encode(&self, cell: &Cell) -> io::Result<(u8, ByteString)> {
+        //A single cell stays a plain record, runs only pay off from the second one on
+        PlainEncoder.encode(cell)
+    }
+
+    fn merge(&self, tag: u8, payload: &[u8], cell: &Cell) -> io::Result<Option<(u8, ByteString)>> {
+        let (count, run_tag, run_payload) = match tag {
+            TAG_RUN => decode_run(payload)?,
+            _ => (1, tag, payload),
+        };
+        let (cell_tag, cell_payload) = self.encode(cell)?;
+        if cell_tag != run_tag || cell_payload != run_payload || count == u32::MAX {
+            return Ok(None);
+        }
+        Ok(Some((TAG_RUN, encode_run(count + 1, run_tag, run_payload)?)))
+    }
+}
+
+///Stores consecutive Int or Timestamp cells as differences to the previous one, which take a single byte for
+///ids and timestamps increasing in small steps
+#[derive(Debug)]
+pub struct DeltaEncoder;
+
+impl BlockEncoder for DeltaEncoder {
+    fn encode(&self, cell: &Cell) -> io::Result<(u8, ByteString)> {
+        PlainEncoder.encode(cell)
+    }
+
+    fn merge(&self, tag: u8, payload: &[u8], cell: &Cell) -> io::Result<Option<(u8, ByteString)>> {
+        let (cell_tag, _) = self.encode(cell)?;
+        let Some(value) = cell.as_int().copied() else {
+            return Ok(None);
+        };
+        let (count, first, last, deltas) = match tag {
+            TAG_DELTA => {
+                let mut header = payload;
+                let count = header.read_u32::<LittleEndian>()?;
+                if header.read_u8()? != cell_tag || count == MAX_PACKED_CELLS {
+                    return Ok(None);
+                }
+                (count, header.read_i64::<LittleEndian>()?, header.read_i64::<LittleEndian>()?, &payload[21..])
+            }
+            tag if tag == cell_tag => {
+                let first = (&payload[..]).read_i64::<LittleEndian>()?;
+                (1, first, first, &[][..])
+            }
+            _ => return Ok(None),
+        };
+        let Some(delta) = value.checked_sub(last) else {
+            return Ok(None);
+        };
+        let mut bytes = ByteString::with_capacity(21 + deltas.len() + 10);
+        bytes.write_u32::<LittleEndian>(count + 1)?;
+        bytes.write_u8(cell_tag)?;
+        bytes.write_i64::<LittleEndian>(first)?;
+        bytes.write_i64::<LittleEndian>(value)?;
+        bytes.write_all(deltas)?;
+        write_varint(&mut bytes, ((delta << 1) ^ (delta >> 63)) as u64)?;
+        Ok(Some((TAG_DELTA, bytes)))
+    }
+}
+
+///Stores every distinct cell of a record once, and repeats as references to it. Pays off for strings
+///taking only a few distinct values, like status codes or country names.
+#[derive(Debug)]
+pub struct DictionaryEncoder;
+
+impl BlockEncoder for DictionaryEncoder {
+    fn encode(&self, cell: &Cell) -> io::Result<(u8, ByteString)> {
+        PlainEncoder.encode(cell)
+    }
+
+    fn merge(&self, tag: u8, payload: &[u8], cell: &Cell) -> io::Result<Option<(u8, ByteString)>> {
+        let (cell_tag, cell_payload) = self.encode(cell)?;
+        let mut bytes = match tag {
+            TAG_DICTIONARY => payload.to_vec(),
+            TAG_RUN | TAG_DELTA => return Ok(None),
+            tag => {
+                let mut bytes = ByteString::with_capacity(4 + 1 + 5 + payload.len());
+                bytes.write_u32::<LittleEndian>(1)?;
+                write_dictionary_entry(&mut bytes, tag, payload)?;
+                bytes
+            }
+        };
+        let count = (&bytes[..4]).read_u32::<LittleEndian>()?;
+        if count == MAX_PACKED_CELLS {
+            return Ok(None);
+        }
+        let known = read_dictionary(&bytes[4..])?
+            .dictionary
+            .iter()
+            .position(|entry| *entry == (cell_tag, cell_payload.as_slice()));
+        (&mut bytes[..4]).write_u32::<LittleEndian>(count + 1)?;
+        match known {
+            Some(n) => write_varint(&mut bytes, n as u64 + 1)?,
+            None => write_dictionary_entry(&mut bytes, cell_tag, &cell_payload)?,
+        }
+        Ok(Some((TAG_DICTIONARY, bytes)))
+    }
+}
+
+impl ColumnEncoding {
+    pub fn encoder(&self) -> &'static dyn BlockEncoder {
+        match self {
+            ColumnEncoding::Plain | ColumnEncoding::Auto => &PlainEncoder,
+            ColumnEncoding::RunLength => &RunLengthEncoder,
+            ColumnEncoding::Delta => &DeltaEncoder,
+            ColumnEncoding::Dictionary => &DictionaryEncoder,
+        }
+    }
+
+    ///Whether columns of the type get written with the encoding. Others fall back to plain records.
+    pub fn applies_to(&self, data_type: &DataType) -> bool {
+        match self {
+            ColumnEncoding::Plain | ColumnEncoding::Auto => true,
+            ColumnEncoding::RunLength => matches!(data_type, DataType::Int | DataType::Boolean),
+            ColumnEncoding::Delta => matches!(data_type, DataType::Int | DataType::Timestamp),
+            ColumnEncoding::Dictionary => matches!(data_type, DataType::String),
+        }
+    }
+}
+
+fn encode_run(count: u32, tag: u8, payload: &[u8]) -> io::Result<ByteString> {
+    let mut bytes = ByteString::with_capacity(5 + payload.len());
+    bytes.write_u32::<LittleEndian>(count)?;
+    bytes.write_u8(tag)?;
+    bytes.write_all(payload)?;
+    Ok(bytes)
+}
+
+///Splits the payload of a run record into the count and the record of the repeated cell
+pub fn decode_run(mut payload: &[u8]) -> io::Result<(u32, u8, &[u8])> {
+    let count = payload.read_u32::<LittleEndian>()?;
+    let tag = payload.read_u8()?;
+    Ok((count, tag, payload))
+}
+
+fn write_varint(bytes: &mut ByteString, mut value: u64) -> io::Result<()> {
+    while value >= 0x80 {
+        bytes.write_u8(value as u8 | 0x80)?;
+        value >>= 7;
+    }
+    bytes.write_u8(value as u8)
+}
+
+fn read_varint(bytes: &mut &[u8]) -> io::Result<u64> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let byte = bytes.read_u8()?;
+        value |= ((byte & 0x7F) as u64) << shift;
+        if byte < 0x80 {
+            return Ok(value);
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::InvalidData, "Varint is too long"))
+}
+
+fn write_dictionary_entry(bytes: &mut ByteString, tag: u8, payload: &[u8]) -> io::Result<()> {
+    write_varint(bytes, 0)?;
+    bytes.write_u8(tag)?;
+    bytes.write_u32::<LittleEndian>(payload.len() as u32)?;
+    bytes.write_all(payload)
+}
+
+///Entries of a dictionary record, without its count
+struct DictionaryEntries<'a> {
+    ///Tag and payload of every new cell, in order
+    dictionary: Vec<(u8, &'a [u8])>,
+    ///Index into the dictionary of every cell
+    cells: Vec<usize>,
+}
+
+fn read_dictionary(mut entries: &[u8]) -> io::Result<DictionaryEntries<'_>> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Dictionary record refers to a missing entry");
+    let mut dictionary = DictionaryEntries { dictionary: vec![], cells: vec![] };
+    while !entries.is_empty() {
+        match read_varint(&mut entries)? as usize {
+            0 => {
+                let tag = entries.read_u8()?;
+                let len = entries.read_u32::<LittleEndian>()? as usize;
+                let payload = entries.get(..len).ok_or_else(invalid)?;
+                entries = &entries[len..];
+                dictionary.cells.push(dictionary.dictionary.len());
+                dictionary.dictionary.push((tag, payload));
+            }
+            n if n <= dictionary.dictionary.len() => dictionary.cells.push(n - 1),
+            _ => return Err(invalid()),
+        }
+    }
+    Ok(dictionary)
+}
+
+///Whether records with the tag hold several distinct cells, see `decode_packed`
+pub fn is_packed(tag: u8) -> bool {
+    tag == TAG_DELTA || tag == TAG_DICTIONARY
+}
+
+///Cells of a delta or dictionary record
+pub fn decode_packed(tag: u8, payload: &[u8]) -> io::Result<Vec<Cell>> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!("Record with tag {:#x} holds an invalid cell", tag));
+    let decode = |tag: u8, payload: &[u8]| Cell::from_bytes(tag, payload.to_vec()).ok_or_else(invalid);
+    match tag {
+        TAG_DELTA => {
+            let mut bytes = payload;
+            let count = bytes.read_u32::<LittleEndian>()? as usize;
+            let cell_tag = bytes.read_u8()?;
+            let mut value = bytes.read_i64::<LittleEndian>()?;
+            bytes.read_i64::<LittleEndian>()?;
+            let mut cells = Vec::with_capacity(count);
+            cells.push(decode(cell_tag, &value.to_le_bytes())?);
+            while !bytes.is_empty() {
+                let zigzag = read_varint(&mut bytes)?;
+                value = value.wrapping_add((zigzag >> 1) as i64 ^ -((zigzag & 1) as i64));
+                cells.push(decode(cell_tag, &value.to_le_bytes())?);
+            }
+            Ok(cells)
+        }
+        TAG_DICTIONARY => {
+            let entries = read_dictionary(&payload[4..])?;
+            let dictionary = entries
+                .dictionary
+                .iter()
+                .map(|(tag, payload)| decode(*tag, payload))
+                .collect::<io::Result<Vec<_>>>()?;
+            Ok(entries.cells.into_iter().map(|n| dictionary[n].clone()).collect())
+        }
+        _ => Err(invalid()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_packed, TAG_DELTA, TAG_DICTIONARY};
+    use crate::config::ColumnEncoding;
+    use crate::storage::cell::Cell;
+
+    ///Tag and payload of the record the encoding stores the cells in, if they fit into one
+    fn single_record(encoding: ColumnEncoding, cells: &[Cell]) -> Option<(u8, Vec<u8>)> {
+        let encoder = encoding.encoder();
+        let mut record = encoder.encode(&cells[0]).unwrap();
+        for cell in &cells[1..] {
+            record = encoder.merge(record.0, &record.1, cell).unwrap()?;
+        }
+        Some(record)
+    }
+
+    #[test]
+    fn packed_records_return_their_cells() {
+        let timestamps = [1677173440, 1677173441, 1677173441, 1677173400].map(Cell::Timestamp).to_vec();
+        let (tag, payload) = single_record(ColumnEncoding::Delta, &timestamps).unwrap();
+        assert_eq!(tag, TAG_DELTA);
+        //A byte per difference after the first value
+        assert_eq!(payload.len(), 21 + 3);
+        assert_eq!(decode_packed(tag, &payload).unwrap(), timestamps);
+        //The difference overflows
+        assert!(single_record(ColumnEncoding::Delta, &[Cell::Timestamp(-1), Cell::Timestamp(i64::MAX)]).is_none());
+        assert!(single_record(ColumnEncoding::Delta, &[Cell::Int(1), Cell::Timestamp(2)]).is_none());
+
+        let statuses = ["ok", "failed", "ok", "ok", "failed"].map(|status| Cell::String(status.into())).to_vec();
+        let mut cells = statuses.clone();
+        cells.push(Cell::Null);
+        let (tag, payload) = single_record(ColumnEncoding::Dictionary, &cells).unwrap();
+        assert_eq!(tag, TAG_DICTIONARY);
+        assert_eq!(decode_packed(tag, &payload).unwrap(), cells);
+        assert_eq!(payload.len(), 4 + (1 + 1 + 4 + 2) + (1 + 1 + 4 + 6) + 3 + (1 + 1 + 4));
+    }
+}
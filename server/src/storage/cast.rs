@@ -0,0 +1,112 @@
+use chrono::{DateTime, NaiveDateTime, SecondsFormat, Utc};
+
+use super::cell::Cell;
+use super::filter::FilterError;
+
+///Type a value gets converted to at query time, e.g. `cast(points as float)` in `where` expressions
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CastType {
+    Int,
+    Float,
+    String,
+    Boolean,
+    Timestamp,
+}
+
+impl CastType {
+    ///Case-insensitive, `integer`, `double`, `text` and `bool` work as well
+    pub fn parse(name: &str) -> Result<Self, FilterError> {
+        match name.to_ascii_lowercase().as_str() {
+            "int" | "integer" => Ok(CastType::Int),
+            "float" | "double" => Ok(CastType::Float),
+            "string" | "text" => Ok(CastType::String),
+            "bool" | "boolean" => Ok(CastType::Boolean),
+            "timestamp" => Ok(CastType::Timestamp),
+            _ => Err(FilterError::UnknownCastType(name.to_string())),
+        }
+    }
+
+    ///Converts the cell, or returns `None` if its value has no counterpart in the type, like `'abc'` as int.
+    ///Null stays null. Floats get truncated towards zero, strings get trimmed before parsing.
+    pub fn apply(&self, cell: &Cell) -> Option<Cell> {
+        if *cell == Cell::Null {
+            return Some(Cell::Null);
+        }
+        match self {
+            CastType::Int => as_int(cell).map(Cell::Int),
+            CastType::Float => match cell {
+                Cell::Int(value) | Cell::Timestamp(value) => Some(Cell::Float(*value as f64)),
+                Cell::Float(value) => Some(Cell::Float(*value)),
+                Cell::Boolean(value) => Some(Cell::Float(*value as i64 as f64)),
+                Cell::String(value) => value.trim().parse().ok().filter(|value: &f64| value.is_finite()).map(Cell::Float),
+                Cell::Json(value) => value.as_f64().map(Cell::Float),
+                _ => None,
+            },
+            CastType::String => Some(Cell::String(match cell {
+                Cell::String(value) => value.clone(),
+                Cell::Int(value) => value.to_string(),
+                Cell::Float(value) => value.to_string(),
+                Cell::Boolean(value) => value.to_string(),
+                Cell::Timestamp(value) => NaiveDateTime::from_timestamp_opt(*value, 0)
+                    .map(|date| DateTime::<Utc>::from_utc(date, Utc).to_rfc3339_opts(SecondsFormat::Secs, true))
+                    .unwrap_or_else(|| value.to_string()),
+                Cell::Uuid(value) => Cell::format_uuid(*value),
+                Cell::Json(serde_json::Value::String(value)) => value.clone(),
+                cell => serde_json::to_string(cell).ok()?,
+            })),
+            CastType::Boolean => match cell {
+                Cell::Boolean(value) => Some(Cell::Boolean(*value)),
+                Cell::Int(0) => Some(Cell::Boolean(false)),
+                Cell::Int(1) => Some(Cell::Boolean(true)),
+                Cell::String(value) if value.trim().eq_ignore_ascii_case("true") => Some(Cell::Boolean(true)),
+                Cell::String(value) if value.trim().eq_ignore_ascii_case("false") => Some(Cell::Boolean(false)),
+                Cell::Json(value) => value.as_bool().map(Cell::Boolean),
+                _ => None,
+            },
+            CastType::Timestamp => match cell {
+                Cell::String(value) => Cell::parse_timestamp(value.trim())
+                    .or_else(|| value.trim().parse().ok())
+                    .map(Cell::Timestamp),
+                cell => as_int(cell).map(Cell::Timestamp),
+            },
+        }
+    }
+}
+
+fn as_int(cell: &Cell) -> Option<i64> {
+    match cell {
+        Cell::Int(value) | Cell::Timestamp(value) => Some(*value),
+        //`as` would saturate out of range values instead of rejecting them
+        Cell::Float(value) if value.is_finite() && value.abs() < i64::MAX as f64 => Some(value.trunc() as i64),
+        Cell::Boolean(value) => Some(*value as i64),
+        Cell::String(value) => value.trim().parse().ok(),
+        Cell::Json(value) => value.as_i64(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CastType;
+    use crate::storage::cell::Cell;
+
+    #[test]
+    fn casts_values_or_rejects_them() {
+        let int = |cell: Cell| CastType::Int.apply(&cell);
+        assert_eq!(int(Cell::String(" 42 ".into())), Some(Cell::Int(42)));
+        assert_eq!(int(Cell::Float(-2.9)), Some(Cell::Int(-2)));
+        assert_eq!(int(Cell::String("4.2".into())), None);
+        assert_eq!(int(Cell::Float(f64::NAN)), None);
+        assert_eq!(int(Cell::Null), Some(Cell::Null));
+
+        assert_eq!(CastType::Float.apply(&Cell::String("1e3".into())), Some(Cell::Float(1000.0)));
+        assert_eq!(CastType::Float.apply(&Cell::String("inf".into())), None);
+        assert_eq!(CastType::String.apply(&Cell::Timestamp(1677125260)), Some(Cell::String("2023-02-23T04:07:40Z".into())));
+        assert_eq!(CastType::Boolean.apply(&Cell::String("TRUE".into())), Some(Cell::Boolean(true)));
+        assert_eq!(CastType::Boolean.apply(&Cell::Int(2)), None);
+        assert_eq!(CastType::Timestamp.apply(&Cell::String("2023-02-23T04:07:40Z".into())), Some(Cell::Timestamp(1677125260)));
+
+        assert_eq!(CastType::parse("DOUBLE").unwrap(), CastType::Float);
+        assert!(CastType::parse("uuid").is_err());
+    }
+}
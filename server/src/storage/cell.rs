@@ -1,13 +1,28 @@
 use serde::Serialize;
 use std::io::Cursor;
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use chrono::{DateTime, NaiveDateTime, SecondsFormat, Utc};
 
 use super::{ByteString, CRC32};
+use super::data_type::DataType;
 
 const TAG_I64 : u8 = 1;
 const TAG_F64 : u8 = 2;
 const TAG_STR : u8 = 3;
 const TAG_BOOL : u8 = 4;
+const TAG_NULL : u8 = 5;
+const TAG_TIMESTAMP : u8 = 6;
+const TAG_UUID : u8 = 7;
+///String compressed with zstd
+const TAG_STR_ZSTD : u8 = 8;
+///Element count, followed by every element as `tag | length | bytes`
+const TAG_ARRAY : u8 = 9;
+///Serialized JSON text
+const TAG_JSON : u8 = 10;
+
+///Strings from this many bytes on get compressed, unless that doesn't make them any smaller
+const COMPRESSION_THRESHOLD: usize = 1024;
+pub(crate) const COMPRESSION_LEVEL: i32 = 3;
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Cell {
@@ -15,6 +30,16 @@ pub enum Cell {
     Float(f64),
     String(String),
     Boolean(bool),
+    ///No value, only stored in nullable columns
+    Null,
+    ///Seconds since the unix epoch. Shows up as an RFC3339 string in query results.
+    Timestamp(i64),
+    ///Shows up as a hyphenated lowercase string in query results
+    Uuid(u128),
+    ///Elements are never null
+    Array(Vec<Cell>),
+    ///Any JSON value besides null, stored verbatim and returned as is
+    Json(serde_json::Value),
 }
 
 impl Cell {
@@ -31,11 +56,61 @@ impl Cell {
                 }
             },
             serde_json::Value::String(str) => Some(Cell::String(str.into())),
-            serde_json::Value::Array(_) => None,
+            serde_json::Value::Array(values) => values.iter().map(Cell::from_json_value).collect::<Option<_>>().map(Cell::Array),
             serde_json::Value::Object(_) => None,
         }
     }
 
+    ///Like `from_json_value`, but converts the value to the column's type. Timestamp columns accept
+    ///unix timestamps as well as RFC3339 strings.
+    pub fn from_typed_json(json_value: &serde_json::Value, data_type: &DataType) -> Option<Self> {
+        match (data_type, json_value) {
+            (DataType::Timestamp, serde_json::Value::Number(num)) => num.as_i64().map(Cell::Timestamp),
+            (DataType::Timestamp, serde_json::Value::String(str)) => Cell::parse_timestamp(str).map(Cell::Timestamp),
+            (DataType::Uuid, serde_json::Value::String(str)) => Cell::parse_uuid(str).map(Cell::Uuid),
+            (DataType::Array(inner), serde_json::Value::Array(values)) => values
+                .iter()
+                .map(|value| Cell::from_typed_json(value, inner))
+                .collect::<Option<_>>()
+                .map(Cell::Array),
+            (DataType::Json, serde_json::Value::Null) => None,
+            (DataType::Json, value) => Some(Cell::Json(value.clone())),
+            _ => Cell::from_json_value(json_value),
+        }
+    }
+
+    ///Seconds since the unix epoch of an RFC3339 date, e.g. `2023-02-23T09:30:00Z`
+    pub fn parse_timestamp(value: &str) -> Option<i64> {
+        DateTime::parse_from_rfc3339(value).ok().map(|date| date.timestamp())
+    }
+
+    ///Whether storing the RFC3339 date drops fractions of a second, as Timestamp cells only hold whole seconds
+    pub fn drops_fraction(value: &str) -> bool {
+        DateTime::parse_from_rfc3339(value).is_ok_and(|date| date.timestamp_subsec_nanos() != 0)
+    }
+
+    ///Parses the hyphenated form, e.g. `67e55044-10b1-426f-9247-bb680e5fe0c8`
+    pub fn parse_uuid(value: &str) -> Option<u128> {
+        let hyphens_in_place = [8, 13, 18, 23].iter().all(|n| value.as_bytes().get(*n) == Some(&b'-'));
+        let hex = value.replace('-', "");
+        if value.len() != 36 || !hyphens_in_place || hex.len() != 32 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+        u128::from_str_radix(&hex, 16).ok()
+    }
+
+    pub fn format_uuid(value: u128) -> String {
+        let hex = format!("{:032x}", value);
+        format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
+    }
+
+    ///Random version 4 UUID
+    pub fn new_uuid() -> u128 {
+        let random = rand::random::<u128>();
+        //Version 4, RFC 4122 variant
+        (random & !(0xf << 76) & !(0x3 << 62)) | (0x4 << 76) | (0x2 << 62)
+    }
+
     pub fn to_bytes(&self) -> Result<(u32, u8, ByteString), std::io::Error> {
         let (tag_byte, value) = match self {
             Cell::Int(val) => {
@@ -48,6 +123,12 @@ impl Cell {
                 value_buffer.write_f64::<LittleEndian>(val.to_owned())?;
                 (TAG_F64, value_buffer)
             }
+            Cell::String(val) if val.len() >= COMPRESSION_THRESHOLD => {
+                match zstd::bulk::compress(val.as_bytes(), COMPRESSION_LEVEL)? {
+                    compressed if compressed.len() < val.len() => (TAG_STR_ZSTD, compressed),
+                    _ => (TAG_STR, val.as_bytes().to_owned()),
+                }
+            },
             Cell::String(val) => {
                 (TAG_STR, val.as_bytes().to_owned())
             },
@@ -57,6 +138,25 @@ impl Cell {
                 value_buffer.write_i64::<LittleEndian>(bool_value.to_owned())?;
                 (TAG_BOOL, value_buffer)
             }
+            Cell::Null => (TAG_NULL, vec![]),
+            Cell::Timestamp(val) => {
+                let mut value_buffer = Vec::new();
+                value_buffer.write_i64::<LittleEndian>(val.to_owned())?;
+                (TAG_TIMESTAMP, value_buffer)
+            }
+            Cell::Uuid(val) => (TAG_UUID, val.to_be_bytes().to_vec()),
+            Cell::Array(elements) => {
+                let mut value_buffer = Vec::new();
+                value_buffer.write_u32::<LittleEndian>(elements.len() as u32)?;
+                for element in elements {
+                    let (_checksum, tag_byte, bytes) = element.to_bytes()?;
+                    value_buffer.write_u8(tag_byte)?;
+                    value_buffer.write_u32::<LittleEndian>(bytes.len() as u32)?;
+                    value_buffer.extend(bytes);
+                }
+                (TAG_ARRAY, value_buffer)
+            }
+            Cell::Json(val) => (TAG_JSON, serde_json::to_vec(val)?),
         };
 
         let mut tmp = ByteString::with_capacity(1 + value.len());
@@ -69,6 +169,16 @@ impl Cell {
         Ok((checksum, tag_byte, tmp.to_vec()))
     }
 
+    ///Identifies the value in hash maps. Floats aren't hashable, their encoded bytes are. Null has no key.
+    pub fn hash_key(&self) -> Option<(u8, ByteString)> {
+        match self {
+            Cell::Null => None,
+            //Not compressed, that would only slow down lookups
+            Cell::String(val) => Some((TAG_STR, val.as_bytes().to_vec())),
+            cell => cell.to_bytes().ok().map(|(_checksum, tag_byte, bytes)| (tag_byte, bytes)),
+        }
+    }
+
     pub(crate) fn from_bytes(tag_byte: u8, data: Vec<u8>) -> Option<Cell> {
         let mut cursor = Cursor::new(data.clone());
         match tag_byte {
@@ -87,18 +197,49 @@ impl Cell {
                     .map(|val| Some(Cell::String(val)))
                     .unwrap_or(None)
             },
+            TAG_STR_ZSTD => {
+                zstd::stream::decode_all(cursor)
+                    .ok()
+                    .and_then(|val| String::from_utf8(val).ok())
+                    .map(Cell::String)
+            },
+            TAG_ARRAY => {
+                let count = cursor.read_u32::<LittleEndian>().ok()?;
+                let mut elements = Vec::with_capacity(count as usize);
+                for _ in 0..count {
+                    let tag_byte = cursor.read_u8().ok()?;
+                    let len = cursor.read_u32::<LittleEndian>().ok()?;
+                    let mut bytes = vec![0; len as usize];
+                    std::io::Read::read_exact(&mut cursor, &mut bytes).ok()?;
+                    elements.push(Cell::from_bytes(tag_byte, bytes)?);
+                }
+                Some(Cell::Array(elements))
+            },
+            TAG_JSON => serde_json::from_slice(&data).ok().map(Cell::Json),
             TAG_BOOL => {
                 cursor.read_i64::<LittleEndian>()
                     .map(|val| Some(Cell::Boolean(val == 1)))
                     .unwrap_or(None)
             },
+            TAG_NULL => Some(Cell::Null),
+            TAG_TIMESTAMP => {
+                cursor.read_i64::<LittleEndian>()
+                    .map(|val| Some(Cell::Timestamp(val)))
+                    .unwrap_or(None)
+            },
+            TAG_UUID => {
+                cursor.read_u128::<byteorder::BigEndian>()
+                    .map(|val| Some(Cell::Uuid(val)))
+                    .unwrap_or(None)
+            },
             _ => None
         }
     }
 
+    ///Timestamps count as their seconds since the unix epoch
     pub fn as_int(&self) -> Option<&i64> {
         match self {
-            Cell::Int(val) => Some(val),
+            Cell::Int(val) | Cell::Timestamp(val) => Some(val),
             _ => None
         }
     }
@@ -111,8 +252,18 @@ impl Serialize for Cell {
             match self {
                 Cell::Int(val) => serializer.serialize_i64(val.to_owned()),
                 Cell::Float(val) => serializer.serialize_f64(val.to_owned()),
-                Cell::String(str) => serializer.serialize_str(&str),
+                Cell::String(str) => serializer.serialize_str(str),
                 Cell::Boolean(bool) => serializer.serialize_bool(bool.to_owned()),
+                Cell::Null => serializer.serialize_none(),
+                Cell::Timestamp(val) => match NaiveDateTime::from_timestamp_opt(val.to_owned(), 0) {
+                    Some(date) => serializer.serialize_str(
+                        &DateTime::<Utc>::from_utc(date, Utc).to_rfc3339_opts(SecondsFormat::Secs, true),
+                    ),
+                    None => serializer.serialize_i64(val.to_owned()),
+                },
+                Cell::Uuid(val) => serializer.serialize_str(&Cell::format_uuid(val.to_owned())),
+                Cell::Array(elements) => serializer.collect_seq(elements),
+                Cell::Json(val) => val.serialize(serializer),
             }
     }
 }
@@ -0,0 +1,163 @@
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use tracing::{instrument, warn};
+
+use super::CRC32;
+
+///Small file which is replaced as a whole on every write, like `column_layout.json` or `auto_index`.
+///Writes alternate between two copies, `<name>.0` and `<name>.1`, each stored as
+///`checksum | version | payload length | payload`. If a write gets torn, loading falls back to the other copy.
+#[derive(Debug, Default)]
+pub struct CheckedFile {
+    path: PathBuf,
+    ///Version of the newest intact copy. 0 if nothing has been written yet.
+    version: u64,
+}
+
+impl CheckedFile {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path, version: 0 }
+    }
+
+    fn copy_path(&self, version: u64) -> PathBuf {
+        let mut file_name = self.path.file_name().unwrap_or_default().to_os_string();
+        file_name.push(format!(".{}", version % 2));
+        self.path.with_file_name(file_name)
+    }
+
+    ///True if there is anything to load, intact or not
+    pub fn exists(path: &Path) -> bool {
+        let file = CheckedFile::new(path.to_path_buf());
+        path.exists() || file.copy_path(0).exists() || file.copy_path(1).exists()
+    }
+
+    ///True if only a file from before checksums were introduced exists
+    pub fn is_legacy(path: &Path) -> bool {
+        let file = CheckedFile::new(path.to_path_buf());
+        path.exists() && !file.copy_path(0).exists() && !file.copy_path(1).exists()
+    }
+
+    ///Returns the payload of the newest intact copy.
+    ///Fails with `NotFound` if no copy exists and with `InvalidData` if none of them is intact.
+    #[instrument]
+    pub fn load(path: PathBuf) -> io::Result<(Self, Vec<u8>)> {
+        let mut file = CheckedFile::new(path);
+        let mut newest: Option<(u64, Vec<u8>)> = None;
+        let mut found_copy = false;
+
+        for slot in 0..2 {
+            let copy_path = file.copy_path(slot);
+            let bytes = match fs::read(&copy_path) {
+                Ok(bytes) => bytes,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err),
+            };
+            found_copy = true;
+            match CheckedFile::decode(&bytes) {
+                Some((version, payload)) => {
+                    if newest.as_ref().is_none_or(|(newest_version, _)| version > *newest_version) {
+                        newest = Some((version, payload));
+                    }
+                }
+                None => warn!("Ignoring torn or corrupted copy {:?}", copy_path),
+            }
+        }
+
+        if let Some((version, payload)) = newest {
+            file.version = version;
+            return Ok((file, payload));
+        }
+        if found_copy {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("No intact copy of {:?} left", file.path),
+            ));
+        }
+
+        //Files written before checksums were introduced
+        let payload = fs::read(&file.path)?;
+        warn!("Loaded {:?} without checksum. It gets checksummed on the next write", file.path);
+        Ok((file, payload))
+    }
+
+    fn decode(bytes: &[u8]) -> Option<(u64, Vec<u8>)> {
+        let mut bytes = bytes;
+        let saved_checksum = bytes.read_u32::<LittleEndian>().ok()?;
+        let version = bytes.read_u64::<LittleEndian>().ok()?;
+        let payload_len = bytes.read_u32::<LittleEndian>().ok()?;
+        let mut payload = vec![0; payload_len as usize];
+        bytes.read_exact(&mut payload).ok()?;
+
+        if CheckedFile::checksum(version, &payload) != saved_checksum {
+            return None;
+        }
+        Some((version, payload))
+    }
+
+    fn checksum(version: u64, payload: &[u8]) -> u32 {
+        let mut digest = CRC32.digest();
+        digest.update(&version.to_le_bytes());
+        digest.update(payload);
+        digest.finalize()
+    }
+
+    ///Removes both copies, along with a file from before checksums were introduced
+    pub fn remove(self) -> io::Result<()> {
+        for path in [self.copy_path(0), self.copy_path(1), self.path] {
+            match fs::remove_file(&path) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    ///Overwrites the older copy, so the newest one stays intact until this write is complete
+    #[instrument(skip(self, payload))]
+    pub fn write(&mut self, payload: &[u8]) -> io::Result<()> {
+        let version = self.version + 1;
+        let mut record = Vec::with_capacity(16 + payload.len());
+        record.write_u32::<LittleEndian>(CheckedFile::checksum(version, payload))?;
+        record.write_u64::<LittleEndian>(version)?;
+        record.write_u32::<LittleEndian>(payload.len() as u32)?;
+        record.write_all(payload)?;
+
+        let mut f = File::create(self.copy_path(version))?;
+        f.write_all(&record)?;
+        f.sync_data()?;
+        self.version = version;
+
+        if self.path.exists() {
+            fs::remove_file(&self.path)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CheckedFile;
+
+    #[test]
+    fn falls_back_to_previous_copy_after_torn_write() {
+        let root = tempfile::tempdir().unwrap();
+        let path = root.path().join("auto_index");
+        let mut file = CheckedFile::new(path.clone());
+        file.write(b"first").unwrap();
+        file.write(b"second").unwrap();
+
+        let torn_copy = file.copy_path(2);
+        let bytes = std::fs::read(&torn_copy).unwrap();
+        std::fs::write(&torn_copy, &bytes[..bytes.len() - 2]).unwrap();
+
+        let (file, payload) = CheckedFile::load(path.clone()).unwrap();
+        assert_eq!(payload, b"first");
+        assert_eq!(file.version, 1);
+
+        std::fs::write(file.copy_path(1), b"garbage").unwrap();
+        let err = CheckedFile::load(path).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+}
@@ -1,45 +1,339 @@
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
 use std::fs::File;
-use std::fs::OpenOptions;
 use std::io;
 use std::io::prelude::*;
-use std::io::{BufReader, BufWriter, SeekFrom};
+use std::io::BufReader;
+use std::ops::Range;
 use std::path::Path;
 use std::path::PathBuf;
+use std::sync::{Arc, OnceLock};
+use tracing::{debug, warn};
 
+use crate::config::{ColumnCompression, ColumnEncoding};
 use crate::storage::ByteString;
 use crate::storage::CRC32;
 
-use super::cell::Cell;
+use super::backend::{MappedFile, StorageBackend};
+use super::block_encoder::{decode_packed, decode_run, is_packed, TAG_RUN};
+use super::cell::{Cell, COMPRESSION_LEVEL};
+use super::column_entries::ColumnEntries;
 use super::data_type::DataType;
+use super::file_header::FileHeader;
+use super::segments::SegmentManifest;
 
+///Write buffer size used when the schema doesn't configure one
+pub const DEFAULT_WRITE_BUFFER_SIZE: usize = 8 * 1024;
+
+const COLUMN_MAGIC: &[u8; 4] = b"WHCL";
+///Bump whenever the record encoding changes. Files with an older version get migrated on load.
+///Version 2 added tombstones, run-length, delta and dictionary encoded blocks and zstd blocks.
+pub const COLUMN_FORMAT_VERSION: u16 = 2;
+///How many records `read_entries` reads between progress reports
+const PROGRESS_INTERVAL: usize = 100_000;
+///Tag of records marking an earlier record as deleted. Their payload is the position of the deleted record.
+const TAG_TOMBSTONE: u8 = 0xFF;
+///Tag of records holding a zstd compressed block of other records. Their payload starts with a directory
+///of the block, the number of records and their uncompressed size, followed by the compressed records.
+///Cells use tags below the ones of `block_encoder`.
+pub const TAG_ZSTD_BLOCK: u8 = 0xFD;
+///Records compressed together into one block at most. Blocks never span multiple flushes of the write buffer.
+const COMPRESSION_BLOCK_RECORDS: usize = 1024;
+
+///Records read from a column file
+#[derive(Debug, Default)]
+pub struct ColumnRecords {
+    pub cells: ColumnEntries,
+    ///Positions of the deleted cells
+    pub tombstones: Vec<usize>,
+    ///Range of a partial or corrupt record the active segment's file ends with, left behind by a write that got cut off
+    pub torn_tail: Option<Range<u64>>,
+}
+
+///Records of a column which only get read once something accesses its cells, see `SchemaConfig::lazy_columns`
+#[derive(Debug)]
+struct DeferredLoad {
+    ///Segment files along with the range of their records, see `segment_ranges`
+    files: Vec<(String, Range<u64>)>,
+    ///Rows the files hold according to the id column. Loading pads or cuts the column to this many rows.
+    rows: Option<usize>,
+    ///The records followed by the cells inserted before they got read, until the column takes them over
+    records: OnceLock<ColumnRecords>,
+}
+
+enum Record {
+    ///The cell along with the size of its record in the file
+    Cell(Cell, u64),
+    ///A cell repeated this many times, along with the size of the run's record
+    Run(Cell, u32, u64),
+    ///Cells of a delta or dictionary record, along with the size of the record
+    Packed(Vec<Cell>, u64),
+    Tombstone(usize),
+    ///Number of records in the block, their uncompressed bytes and the size of the block's record
+    Block(u32, ByteString, u64),
+    ///Record whose checksum doesn't match, along with the size its header claims
+    Corrupt(u64),
+}
+
+///How a column writes its records, as configured in the schema
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct StorageOptions {
+    pub encoding: ColumnEncoding,
+    pub compression: Option<ColumnCompression>,
+}
+
+///Stored in the header of every column file
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct ColumnMetadata {
+    name: String,
+    data_type: DataType,
+}
 
 #[derive(Debug)]
 pub struct Column {
+    backend: Arc<dyn StorageBackend>,
     name: String,
     data_type: DataType,
-    entries: Vec<Cell>,
-    f: File,
+    entries: ColumnEntries,
+    ///Positions of deleted entries. They stay in `entries`, so positions line up across columns.
+    tombstones: HashSet<usize>,
+    ///Records only reach the backend once they get flushed, or the buffer is full
+    buffer: Vec<u8>,
+    write_buffer_size: usize,
+    ///Length of the active segment's file including records still sitting in the write buffer
+    len: u64,
+    options: StorageOptions,
+    ///Offset of the last record in the write buffer, if it holds cells the next insert may merge with
+    last_record: Option<usize>,
+    ///Id of the segment inserts append to, see `SegmentManifest`
+    segment: u64,
+    ///Ids and file lengths of the full segments in front of the active one
+    closed: Vec<(u64, u64)>,
+    ///Rows dropped along with whole segments. Tombstones in the files count them, the ones in memory don't.
+    dropped_rows: usize,
+    ///Loading maps the segment files instead of decoding all cells up front, see `ColumnEntries`
+    mapped_reads: bool,
+    ///Set while the records stored before opening haven't been taken over. `entries` only holds the cells inserted since.
+    deferred: Option<DeferredLoad>,
+}
+
+///State of a column before appending, see `Column::rollback_to`
+#[derive(Debug)]
+pub struct ColumnSavepoint {
+    rows: usize,
+    segment: u64,
+    len: u64,
+    buffer_len: usize,
+    last_record: Option<usize>,
+    ///Copy of the last buffered record, which later cells may merge into
+    last_record_bytes: Vec<u8>,
 }
 
 impl Column {
-    pub fn new(root_path: &PathBuf, name: String, data_type: DataType) -> Self {
-        let root_path = Path::new(root_path);
-        let file_path = root_path.join(format!("column_{}", name));
-        let f = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .append(true)
-            .open(file_path)
-            .unwrap();
-
-        Self {
-            f,
+    pub fn file_name(name: &str) -> String {
+        format!("column_{}", name)
+    }
+
+    pub fn file_path(root_path: &PathBuf, name: &str) -> PathBuf {
+        Path::new(root_path).join(Column::file_name(name))
+    }
+
+    ///File of one of the column's segments. Segment 0 is the file of tables without segments.
+    pub fn segment_file_name(name: &str, segment: u64) -> String {
+        match segment {
+            0 => Column::file_name(name),
+            segment => format!("{}.seg{}", Column::file_name(name), segment),
+        }
+    }
+
+    pub fn new(backend: Arc<dyn StorageBackend>, name: String, data_type: DataType, write_buffer_size: usize) -> io::Result<Self> {
+        Column::with_segments(backend, name, data_type, write_buffer_size, &SegmentManifest::default())
+    }
+
+    ///Opens the column's files of the segments the manifest lists. Fails if the backend can't tell their lengths.
+    pub fn with_segments(
+        backend: Arc<dyn StorageBackend>,
+        name: String,
+        data_type: DataType,
+        write_buffer_size: usize,
+        segments: &SegmentManifest,
+    ) -> io::Result<Self> {
+        let mut column = Self {
+            backend,
+            buffer: Vec::with_capacity(write_buffer_size),
+            write_buffer_size,
+            len: 0,
             name,
             data_type,
-            entries: vec![],
+            entries: ColumnEntries::default(),
+            tombstones: HashSet::new(),
+            options: StorageOptions::default(),
+            last_record: None,
+            segment: 0,
+            closed: vec![],
+            dropped_rows: 0,
+            mapped_reads: false,
+            deferred: None,
+        };
+        column.open_segments(segments)?;
+        Ok(column)
+    }
+
+    fn open_segments(&mut self, segments: &SegmentManifest) -> io::Result<()> {
+        self.closed = vec![];
+        for segment in &segments.closed {
+            let len = self.backend.len(&Column::segment_file_name(&self.name, segment.id))?.unwrap_or_default();
+            self.closed.push((segment.id, len));
+        }
+        self.segment = segments.active;
+        self.dropped_rows = segments.dropped_rows;
+        self.open_active()
+    }
+
+    ///Starts a new file with the header if the active segment doesn't have one yet
+    fn open_active(&mut self) -> io::Result<()> {
+        self.len = self.backend.len(&self.active_file())?.unwrap_or_default();
+        if self.len == 0 {
+            let header = self.header()?;
+            header.write(&mut self.buffer, COLUMN_MAGIC)?;
+            self.len = header.len();
+        }
+        Ok(())
+    }
+
+    fn active_file(&self) -> String {
+        Column::segment_file_name(&self.name, self.segment)
+    }
+
+    ///Files of all segments, oldest first
+    pub fn file_names(&self) -> Vec<String> {
+        self.closed
+            .iter()
+            .map(|(segment, _)| *segment)
+            .chain([self.segment])
+            .map(|segment| Column::segment_file_name(&self.name, segment))
+            .collect()
+    }
+
+    ///Closes the active segment and continues in a new one with the id
+    pub fn roll_over(&mut self, segment: u64) -> io::Result<()> {
+        self.flush()?;
+        self.closed.push((self.segment, self.len));
+        self.segment = segment;
+        //Left behind by a crash after the segment got dropped, but before its file got removed
+        self.backend.remove(&self.active_file())?;
+        self.open_active()
+    }
+
+    ///Forgets the first count closed segments, which hold the first rows rows.
+    ///Returns their files, which the caller removes once the manifest no longer lists them.
+    pub fn drop_segments(&mut self, count: usize, rows: usize) -> io::Result<Vec<String>> {
+        self.settle()?;
+        match self.deferred.as_mut() {
+            Some(deferred) => {
+                deferred.files.drain(..count);
+                deferred.rows = deferred.rows.map(|stored_rows| stored_rows.saturating_sub(rows));
+            }
+            None => self.entries.drain_front(rows),
+        }
+        self.tombstones = self
+            .tombstones
+            .iter()
+            .filter_map(|position| position.checked_sub(rows))
+            .collect();
+        self.dropped_rows += rows;
+        Ok(self
+            .closed
+            .drain(..count)
+            .map(|(segment, _)| Column::segment_file_name(&self.name, segment))
+            .collect())
+    }
+
+    ///Format version of an existing column file. `None` if it was written before headers existed.
+    pub fn format_version(root_path: &PathBuf, name: &str) -> io::Result<Option<u16>> {
+        let mut f = BufReader::new(File::open(Column::file_path(root_path, name))?);
+        Ok(FileHeader::read(&mut f, COLUMN_MAGIC)?.map(|header| header.version))
+    }
+
+    fn header(&self) -> io::Result<FileHeader> {
+        let metadata = serde_json::to_vec(&ColumnMetadata {
+            name: self.name.clone(),
+            data_type: self.data_type.clone(),
+        })?;
+        Ok(FileHeader::new(COLUMN_FORMAT_VERSION, metadata))
+    }
+
+    fn location(&self) -> String {
+        self.backend.location(&self.active_file())
+    }
+
+    ///Checks the header belongs to this column and was written in a format this version understands
+    fn validate_header(&self, header: &FileHeader) -> io::Result<()> {
+        if header.version > COLUMN_FORMAT_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Column file {} has format version {}, only versions up to {} are supported",
+                    self.location(),
+                    header.version,
+                    COLUMN_FORMAT_VERSION
+                ),
+            ));
         }
+        let metadata: ColumnMetadata = serde_json::from_slice(&header.metadata)?;
+        if metadata.name != self.name || metadata.data_type != self.data_type {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Column file {} belongs to column {} ({:?}), expected {} ({:?})",
+                    self.location(),
+                    metadata.name,
+                    metadata.data_type,
+                    self.name,
+                    self.data_type
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    ///Rewrites the active file so it starts with the current header, keeping its records from records_start on
+    fn migrate(&mut self, records_start: u64) -> io::Result<()> {
+        let file_name = self.active_file();
+        let mut records = vec![];
+        self.backend.read_range(&file_name, records_start, self.len)?.read_to_end(&mut records)?;
+        let mut migrated = self.header()?.to_bytes(COLUMN_MAGIC)?;
+        migrated.extend_from_slice(&records);
+
+        self.backend.replace(&file_name, &migrated)?;
+        self.len = migrated.len() as u64;
+        Ok(())
+    }
+
+    ///Writes the files of all segments under the new name, with the new name in their headers.
+    ///The files under the old name stay until the caller removes them.
+    pub fn rename(&mut self, new_name: &str) -> io::Result<()> {
+        self.materialize()?;
+        self.flush()?;
+        let old_name = std::mem::replace(&mut self.name, new_name.to_string());
+        let header = self.header()?.to_bytes(COLUMN_MAGIC)?;
+        let mut segments = self.closed.iter_mut().map(|(segment, len)| (*segment, len)).collect::<Vec<_>>();
+        segments.push((self.segment, &mut self.len));
+        for (segment, len) in segments {
+            let mut bytes = vec![];
+            self.backend.read_range(&Column::segment_file_name(&old_name, segment), 0, *len)?.read_to_end(&mut bytes)?;
+            let mut records = bytes.as_slice();
+            if FileHeader::read(&mut records, COLUMN_MAGIC)?.is_none() {
+                records = bytes.as_slice();
+            }
+
+            let mut renamed = header.clone();
+            renamed.extend_from_slice(records);
+            self.backend.replace(&Column::segment_file_name(new_name, segment), &renamed)?;
+            *len = renamed.len() as u64;
+        }
+        Ok(())
     }
 
     pub fn name(&self) -> &str {
@@ -50,75 +344,742 @@ impl Column {
         &self.data_type
     }
 
-    pub fn insert(&mut self, cell: Cell) -> io::Result<u64> {
-        let mut f = BufWriter::new(&mut self.f);
-        let (checksum, tag_byte, bytes) = cell.to_bytes()?;
+    pub fn storage_options(&self) -> StorageOptions {
+        self.options
+    }
 
-        let next_byte = SeekFrom::End(0);
-        let current_position = f.seek(SeekFrom::Current(0))?;
+    ///Applies to cells inserted from now on. Records already written keep their encoding and compression.
+    pub fn set_storage_options(&mut self, options: StorageOptions) {
+        self.options = options;
+    }
+
+    pub fn mapped_reads(&self) -> bool {
+        self.mapped_reads
+    }
 
-        f.seek(next_byte)?;
+    ///Applies to the next load. Backends which can't map files fall back to decoding all cells.
+    pub fn set_mapped_reads(&mut self, mapped_reads: bool) {
+        self.mapped_reads = mapped_reads;
+    }
 
-        f.write_u32::<LittleEndian>(checksum)?;
-        f.write_u8(tag_byte)?;
-        f.write_u32::<LittleEndian>(bytes.len() as u32)?;
-        f.write_all(&bytes)?;
+    ///Number of bytes the cell's plain record takes up in the column file
+    pub fn record_size(cell: &Cell) -> io::Result<u64> {
+        let (_checksum, _tag_byte, bytes) = cell.to_bytes()?;
+        Ok(9 + bytes.len() as u64)
+    }
 
+    ///Appends the cell to the write buffer. Returns the file offset the record starts at.
+    pub fn insert(&mut self, cell: Cell) -> io::Result<u64> {
+        self.settle()?;
+        let record_position = match self.merge_into_last_record(&cell, false)? {
+            Some(record_position) => record_position,
+            None => {
+                let (tag_byte, bytes) = self.options.encoding.encoder().encode(&cell)?;
+                let record_position = self.write_record(CRC32.checksum(&bytes), tag_byte, &bytes)?;
+                self.last_record = Some(self.buffer.len() - 9 - bytes.len());
+                record_position
+            }
+        };
         self.entries.push(cell);
+        Ok(record_position)
+    }
+
+    ///Remembers the state of the column, so the cells appended afterwards can be undone with `rollback_to`
+    pub fn savepoint(&mut self) -> io::Result<ColumnSavepoint> {
+        self.settle()?;
+        Ok(ColumnSavepoint {
+            rows: self.entries.len(),
+            segment: self.segment,
+            len: self.len,
+            buffer_len: self.buffer.len(),
+            last_record: self.last_record,
+            last_record_bytes: self.last_record.map(|offset| self.buffer[offset..].to_vec()).unwrap_or_default(),
+        })
+    }
+
+    ///Undoes the appends since the savepoint. Records which reached the file meanwhile get cut off again.
+    ///Segments the column rolled over to since then get removed, continuing in the savepoint's segment.
+    pub fn rollback_to(&mut self, savepoint: ColumnSavepoint) -> io::Result<()> {
+        while self.segment != savepoint.segment {
+            self.backend.remove(&self.active_file())?;
+            let (segment, len) = self.closed.pop().expect("The savepoint's segment got closed since");
+            self.segment = segment;
+            self.len = len;
+            self.buffer.clear();
+        }
+        let file_name = self.active_file();
+        let flushed_len = savepoint.len - savepoint.buffer_len as u64;
+        //Records in front of the last one stay as they were, the last one gets restored from the copy
+        let kept_len = savepoint.last_record.unwrap_or(savepoint.buffer_len);
+        let file_len = self.backend.len(&file_name)?.unwrap_or_default();
+        if self.len - self.buffer.len() as u64 == flushed_len {
+            //The buffer still starts with the savepoint's records. Asking the backend catches a flush which failed halfway.
+            self.buffer.truncate(kept_len);
+        } else {
+            //A flush wrote the savepoint's records to the file, compressed along with the appended ones
+            let mut bytes = vec![];
+            self.backend.read_range(&file_name, flushed_len, file_len)?.read_to_end(&mut bytes)?;
+            let header_len = match flushed_len {
+                0 => self.header()?.len() as usize,
+                _ => 0,
+            };
+            self.buffer = bytes[..header_len].to_vec();
+            self.buffer.extend(Column::unblocked_records(&bytes[header_len..])?);
+            self.buffer.truncate(kept_len);
+        }
+        if file_len > flushed_len {
+            self.backend.truncate(&file_name, flushed_len)?;
+        }
+        self.buffer.extend(savepoint.last_record_bytes);
+        self.entries.truncate(savepoint.rows);
+        self.len = savepoint.len;
+        self.last_record = savepoint.last_record;
+        Ok(())
+    }
+
+    ///Records the bytes hold, with the ones of compressed blocks decompressed again. Stops at a cut off record.
+    fn unblocked_records(mut bytes: &[u8]) -> io::Result<ByteString> {
+        let mut records = vec![];
+        while let Some((_, tag_byte, payload)) = Column::split_record(bytes) {
+            let record_len = 9 + payload.len();
+            match tag_byte {
+                TAG_ZSTD_BLOCK => {
+                    let Record::Block(_, block, _) = Column::decode_record(tag_byte, payload.to_vec())? else {
+                        unreachable!("Compressed blocks decode to blocks");
+                    };
+                    records.extend(block);
+                }
+                _ => records.extend_from_slice(&bytes[..record_len]),
+            }
+            bytes = &bytes[record_len..];
+        }
+        Ok(records)
+    }
+
+    ///Appends the cells to the write buffer and writes them to the file at once, even if they exceed the write buffer size
+    pub fn insert_batch(&mut self, cells: Vec<Cell>) -> io::Result<()> {
+        self.settle()?;
+        for cell in cells {
+            if self.merge_into_last_record(&cell, true)?.is_none() {
+                let (tag_byte, bytes) = self.options.encoding.encoder().encode(&cell)?;
+                self.buffer_record(CRC32.checksum(&bytes), tag_byte, &bytes)?;
+                self.last_record = Some(self.buffer.len() - 9 - bytes.len());
+            }
+            self.entries.push(cell);
+        }
+        if self.buffer.len() > self.write_buffer_size {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    ///Rewrites the last buffered record to also hold the cell, if the encoder allows it.
+    ///Returns the file offset of the rewritten record.
+    fn merge_into_last_record(&mut self, cell: &Cell, batched: bool) -> io::Result<Option<u64>> {
+        let Some(offset) = self.last_record else {
+            return Ok(None);
+        };
+        let tag_byte = self.buffer[offset + 4];
+        let Some((tag_byte, bytes)) = self.options.encoding.encoder().merge(tag_byte, &self.buffer[offset + 9..], cell)? else {
+            return Ok(None);
+        };
+        //Records never outgrow the write buffer. Outside of batches, the records in front of them count as well.
+        let buffered_in_front = if batched { 0 } else { offset };
+        if buffered_in_front + 9 + bytes.len() > self.write_buffer_size {
+            return Ok(None);
+        }
+
+        let old_len = self.buffer.len();
+        self.buffer.truncate(offset);
+        self.buffer.write_u32::<LittleEndian>(CRC32.checksum(&bytes))?;
+        self.buffer.write_u8(tag_byte)?;
+        self.buffer.write_u32::<LittleEndian>(bytes.len() as u32)?;
+        self.buffer.write_all(&bytes)?;
+        self.len = self.len + self.buffer.len() as u64 - old_len as u64;
+        Ok(Some(self.len - (self.buffer.len() - offset) as u64))
+    }
+
+    ///Appends a tombstone for the entry at the position to the write buffer
+    pub fn delete(&mut self, position: usize) -> io::Result<()> {
+        self.settle()?;
+        let mut bytes = vec![];
+        bytes.write_u64::<LittleEndian>((position + self.dropped_rows) as u64)?;
+        self.write_record(CRC32.checksum(&bytes), TAG_TOMBSTONE, &bytes)?;
+        self.last_record = None;
+        self.tombstones.insert(position);
+        Ok(())
+    }
+
+    fn write_record(&mut self, checksum: u32, tag_byte: u8, bytes: &[u8]) -> io::Result<u64> {
+        let record_position = self.len;
+        if !self.buffer.is_empty() && self.buffer.len() + 9 + bytes.len() > self.write_buffer_size {
+            self.flush()?;
+        }
+        self.buffer_record(checksum, tag_byte, bytes)?;
+        Ok(record_position)
+    }
+
+    fn buffer_record(&mut self, checksum: u32, tag_byte: u8, bytes: &[u8]) -> io::Result<()> {
+        self.buffer.write_u32::<LittleEndian>(checksum)?;
+        self.buffer.write_u8(tag_byte)?;
+        self.buffer.write_u32::<LittleEndian>(bytes.len() as u32)?;
+        self.buffer.write_all(bytes)?;
+        self.len += 9 + bytes.len() as u64;
+        Ok(())
+    }
+
+    pub fn tombstones(&self) -> &HashSet<usize> {
+        &self.tombstones
+    }
 
-        Ok(current_position)
+    ///Writes all buffered records to the file
+    pub fn flush(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let file_name = self.active_file();
+        match self.options.compression {
+            Some(ColumnCompression::Zstd) => {
+                //The header of a new segment's file sits in front of the records and stays uncompressed
+                let header_len = match self.len == self.buffer.len() as u64 {
+                    true => self.header()?.len() as usize,
+                    false => 0,
+                };
+                let mut blocks = self.buffer[..header_len].to_vec();
+                blocks.extend(Column::compress_blocks(&self.buffer[header_len..])?);
+                self.backend.append(&file_name, &blocks)?;
+                self.len = self.len - self.buffer.len() as u64 + blocks.len() as u64;
+            }
+            None => self.backend.append(&file_name, &self.buffer)?,
+        }
+        self.buffer.clear();
+        self.last_record = None;
+        Ok(())
+    }
+
+    ///Compresses the records in blocks of up to COMPRESSION_BLOCK_RECORDS.
+    ///Blocks which don't get any smaller stay uncompressed records.
+    fn compress_blocks(mut records: &[u8]) -> io::Result<ByteString> {
+        let mut blocks = vec![];
+        while !records.is_empty() {
+            let mut block_len = 0;
+            let mut count = 0;
+            while block_len < records.len() && count < COMPRESSION_BLOCK_RECORDS {
+                let val_len = (&records[block_len + 5..block_len + 9]).read_u32::<LittleEndian>()?;
+                block_len += 9 + val_len as usize;
+                count += 1;
+            }
+            let (block, rest) = records.split_at(block_len);
+            records = rest;
+
+            let compressed = zstd::bulk::compress(block, COMPRESSION_LEVEL)?;
+            if 9 + 8 + compressed.len() >= block.len() {
+                blocks.extend_from_slice(block);
+                continue;
+            }
+            let mut payload = ByteString::with_capacity(8 + compressed.len());
+            payload.write_u32::<LittleEndian>(count as u32)?;
+            payload.write_u32::<LittleEndian>(block.len() as u32)?;
+            payload.write_all(&compressed)?;
+            blocks.write_u32::<LittleEndian>(CRC32.checksum(&payload))?;
+            blocks.write_u8(TAG_ZSTD_BLOCK)?;
+            blocks.write_u32::<LittleEndian>(payload.len() as u32)?;
+            blocks.write_all(&payload)?;
+        }
+        Ok(blocks)
+    }
+
+    ///Complete segment file holding just these cells, encoded and compressed like inserted ones.
+    ///Once the images replaced the column's files, `install_compacted` switches the column over to them.
+    pub fn compacted_image(&self, cells: &[Cell]) -> io::Result<ByteString> {
+        let mut image = Column {
+            backend: self.backend.clone(),
+            name: self.name.clone(),
+            data_type: self.data_type.clone(),
+            entries: ColumnEntries::default(),
+            tombstones: HashSet::new(),
+            buffer: vec![],
+            //Never flushes, the records get written in one go
+            write_buffer_size: usize::MAX / 2,
+            len: 0,
+            options: self.options,
+            last_record: None,
+            segment: self.segment,
+            closed: vec![],
+            dropped_rows: 0,
+            mapped_reads: false,
+            deferred: None,
+        };
+        for cell in cells {
+            image.insert(cell.clone())?;
+        }
+        let records = std::mem::take(&mut image.buffer);
+        let mut bytes = self.header()?.to_bytes(COLUMN_MAGIC)?;
+        match self.options.compression {
+            Some(ColumnCompression::Zstd) => bytes.extend(Column::compress_blocks(&records)?),
+            None => bytes.extend(records),
+        }
+        Ok(bytes)
+    }
+
+    ///Switches to the cells of the compacted segment files the manifest lists, which replaced the column's files
+    pub fn install_compacted(&mut self, cells: Vec<Cell>, segments: &SegmentManifest) -> io::Result<()> {
+        self.buffer.clear();
+        self.last_record = None;
+        self.deferred = None;
+        self.entries = cells.into();
+        self.tombstones.clear();
+        self.open_segments(segments)
+    }
+
+    ///Stores the cells in an empty column of a table with existing rows, split up like the table's other columns
+    pub fn fill(&mut self, cells: Vec<Cell>, segments: &SegmentManifest) -> io::Result<()> {
+        let mut cells = cells.into_iter();
+        self.closed = vec![];
+        for segment in &segments.closed {
+            let segment_cells = cells.by_ref().take(segment.rows).collect::<Vec<_>>();
+            let image = self.compacted_image(&segment_cells)?;
+            self.backend.replace(&Column::segment_file_name(&self.name, segment.id), &image)?;
+            self.closed.push((segment.id, image.len() as u64));
+            self.entries.extend(segment_cells);
+        }
+        for cell in cells {
+            self.insert(cell)?;
+        }
+        Ok(())
+    }
+
+    ///Number of bytes written to the column but not flushed to the file yet
+    pub fn buffered_bytes(&self) -> usize {
+        self.buffer.len()
     }
 
     pub fn load(&mut self) -> io::Result<()> {
-        let mut f = BufReader::new(&mut self.f);
+        self.deferred = None;
+        let files = self.segment_ranges()?;
+        let records = Column::read_segments(self.backend.as_ref(), &files, self.dropped_rows, self.mapped_reads, |_, _| {})?;
+        if let Some(torn_tail) = records.torn_tail.clone() {
+            self.discard_torn_tail(torn_tail)?;
+        }
+        self.entries = records.cells;
+        self.tombstones = records.tombstones.into_iter().collect();
+        Ok(())
+    }
+
+    ///Validates the headers of the column's files like `load`, but only reads their records once something accesses the cells
+    pub fn defer_load(&mut self) -> io::Result<()> {
+        self.deferred = Some(DeferredLoad {
+            files: self.segment_ranges()?,
+            rows: None,
+            records: OnceLock::new(),
+        });
+        Ok(())
+    }
+
+    ///Rows the files of a column whose load got deferred hold, as counted in the id column
+    pub fn set_stored_rows(&mut self, rows: usize) {
+        if let Some(deferred) = self.deferred.as_mut() {
+            deferred.rows = Some(rows);
+        }
+    }
+
+    ///False while the records of a deferred load haven't been read
+    pub fn is_loaded(&self) -> bool {
+        self.deferred.as_ref().is_none_or(|deferred| deferred.records.get().is_some())
+    }
+
+    fn read_deferred(&self, deferred: &DeferredLoad) -> io::Result<ColumnRecords> {
+        let mut records = Column::read_segments(self.backend.as_ref(), &deferred.files, self.dropped_rows, self.mapped_reads, |_, _| {})?;
+        if let Some(rows) = deferred.rows.filter(|rows| *rows != records.cells.len()) {
+            //Left behind by a crash between writing the columns of a row
+            warn!(
+                "Column {} holds {} rows, the id column {}. Fitting it to the id column, restore the rows using repair --from-wal",
+                self.name,
+                records.cells.len(),
+                rows
+            );
+            records.cells.truncate(rows);
+            records.cells.extend(std::iter::repeat_n(Cell::Null, rows - records.cells.len()));
+            records.tombstones.retain(|position| *position < rows);
+        }
+        records.cells.append(self.entries.to_vec().into());
+        debug!("Loaded deferred column {}: {} rows", self.name, records.cells.len());
+        Ok(records)
+    }
+
+    ///Reads the records of a deferred load right away
+    pub fn materialize(&mut self) -> io::Result<()> {
+        if let Some(deferred) = self.deferred.as_ref().filter(|deferred| deferred.records.get().is_none()) {
+            let records = self.read_deferred(deferred)?;
+            let _ = deferred.records.set(records);
+        }
+        self.settle()
+    }
+
+    ///Takes over the records of a deferred load once they got read, so inserts can append to them
+    fn settle(&mut self) -> io::Result<()> {
+        let Some(deferred) = self.deferred.take_if(|deferred| deferred.records.get().is_some()) else {
+            return Ok(());
+        };
+        //The records already end with the cells inserted before they got read
+        self.entries = ColumnEntries::default();
+        self.install_entries(deferred.records.into_inner().unwrap())
+    }
+
+    ///Cuts a torn write out of the file. Records appended after it got read stay.
+    fn discard_torn_tail(&mut self, torn_tail: Range<u64>) -> io::Result<()> {
+        self.flush()?;
+        let file_name = self.active_file();
+        let mut bytes = vec![];
+        self.backend.read_range(&file_name, 0, self.len)?.read_to_end(&mut bytes)?;
+        let discarded = bytes.drain(torn_tail.start as usize..torn_tail.end as usize).len();
+        warn!(
+            "Column file {} ends in a torn write. Discarding {} bytes at offset {}, restore rows lost with them using repair --from-wal",
+            self.location(),
+            discarded,
+            torn_tail.start
+        );
+        self.backend.replace(&file_name, &bytes)?;
+        self.len = bytes.len() as u64;
+        Ok(())
+    }
+
+    ///Files of all segments along with the range their records take up, oldest first.
+    ///Validates their headers, migrating a file from before headers existed.
+    pub fn segment_ranges(&mut self) -> io::Result<Vec<(String, Range<u64>)>> {
+        let header_len = self.load_header()?;
+        let mut files = vec![];
+        for (segment, len) in &self.closed {
+            let file_name = Column::segment_file_name(&self.name, *segment);
+            let f = self.backend.read_range(&file_name, 0, *len)?;
+            let header = FileHeader::read(&mut BufReader::new(f), COLUMN_MAGIC)?.ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("Column file {} has no header", self.backend.location(&file_name)))
+            })?;
+            self.validate_header(&header)?;
+            files.push((file_name, header.len()..*len));
+        }
+        files.push((self.active_file(), header_len..self.len));
+        Ok(files)
+    }
+
+    ///Validates the active segment's header, migrating files from before headers existed.
+    ///Returns the offset the first record starts at.
+    fn load_header(&mut self) -> io::Result<u64> {
+        self.flush()?;
+        let f = self.backend.read_range(&self.active_file(), 0, self.len)?;
+        let Some(header) = FileHeader::read(&mut BufReader::new(f), COLUMN_MAGIC)? else {
+            warn!("Column file {} has no header. Migrating it to format version {}", self.location(), COLUMN_FORMAT_VERSION);
+            self.migrate(0)?;
+            return Ok(self.header()?.len());
+        };
+        self.validate_header(&header)?;
+        //Records of older versions are still valid, but inserts may append records they didn't know.
+        //Closed segments keep their version, nothing gets appended to them anymore.
+        if header.version < COLUMN_FORMAT_VERSION {
+            warn!("Column file {} has format version {}. Migrating it to format version {}", self.location(), header.version, COLUMN_FORMAT_VERSION);
+            self.migrate(header.len())?;
+            return Ok(self.header()?.len());
+        }
+        Ok(header.len())
+    }
+
+    ///Reads the records of the segment files listed by `segment_ranges` without borrowing the column,
+    ///so it can run while the column keeps accepting inserts. Only the last file, the active segment, may end in a torn write.
+    ///Tombstones of rows dropped along with their segments get left out, the others count from the first row still stored.
+    ///With mapped set, cells get decoded on first access from the mapped files instead, see `ColumnEntries`.
+    pub fn read_segments<F>(
+        backend: &dyn StorageBackend,
+        files: &[(String, Range<u64>)],
+        dropped_rows: usize,
+        mapped: bool,
+        mut progress: F,
+    ) -> io::Result<ColumnRecords>
+    where
+        F: FnMut(usize, u64),
+    {
+        let mut records = ColumnRecords::default();
+        let mut bytes = 0;
+        for (n, (file_name, range)) in files.iter().enumerate() {
+            let rows = records.cells.len();
+            let segment_progress = |segment_rows, segment_bytes| progress(rows + segment_rows, bytes + segment_bytes);
+            let file = match mapped {
+                true => backend.map(file_name)?,
+                false => None,
+            };
+            let segment = match file {
+                Some(file) => Column::index_entries(file, file_name, range.start, range.end, segment_progress)?,
+                None => Column::read_entries(backend, file_name, range.start, range.end, segment_progress)?,
+            };
+            if segment.torn_tail.is_some() && n + 1 < files.len() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Column file {} of a closed segment ends in a torn write", backend.location(file_name)),
+                ));
+            }
+            records.cells.append(segment.cells);
+            records.tombstones.extend(segment.tombstones);
+            records.torn_tail = segment.torn_tail;
+            bytes += range.end - range.start;
+        }
+        records.tombstones = records
+            .tombstones
+            .into_iter()
+            .filter_map(|position| position.checked_sub(dropped_rows))
+            .collect();
+        Ok(records)
+    }
+
+    ///Reads the records of the file between the two offsets.
+    ///`progress` gets called with the rows and bytes read so far about every PROGRESS_INTERVAL rows.
+    ///A partial or corrupt record at the end ends up in `torn_tail`, corrupt records before it are an error.
+    fn read_entries<F>(backend: &dyn StorageBackend, file_name: &str, start: u64, end: u64, mut progress: F) -> io::Result<ColumnRecords>
+    where
+        F: FnMut(usize, u64),
+    {
+        let mut f = BufReader::new(backend.read_range(file_name, start, end)?);
+        let mut records = ColumnRecords::default();
+        let mut bytes = 0;
+        let mut next_progress = PROGRESS_INTERVAL;
 
         loop {
-            let _current_position = f.seek(SeekFrom::Current(0));
-            let maybe_cell = Column::process_record(&mut f);
-
-            let cell = match maybe_cell {
-                Ok(cell) => cell,
-                Err(err) => {
-                    match err.kind() {
-                        io::ErrorKind::UnexpectedEof => {
-                            break;
-                        }
-                        _ => return Err(err),
+            let offset = start + bytes;
+            let record = match Column::process_record(&mut f) {
+                Ok(Record::Corrupt(size)) if offset + size >= end => {
+                    records.torn_tail = Some(offset..end);
+                    break;
+                }
+                Ok(Record::Corrupt(_)) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Column file {} holds a corrupt record at offset {}", file_name, offset),
+                    ));
+                }
+                Ok(record) => record,
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                    if offset < end {
+                        records.torn_tail = Some(offset..end);
                     }
+                    break;
                 }
+                Err(err) => return Err(err),
             };
-            self.entries.push(cell);
+            bytes += Column::add_record(&mut records, record, file_name, offset)?;
+            if records.cells.len() >= next_progress {
+                progress(records.cells.len(), bytes);
+                next_progress = records.cells.len() + PROGRESS_INTERVAL;
+            }
             //TODO: update index
         }
+        progress(records.cells.len(), bytes);
+        Ok(records)
+    }
+
+    ///Like `read_entries`, but only indexes the records of the mapped file. Their cells get decoded on first access.
+    ///Blocks still get decompressed right away, since their records don't exist in the file.
+    fn index_entries<F>(file: MappedFile, file_name: &str, start: u64, end: u64, mut progress: F) -> io::Result<ColumnRecords>
+    where
+        F: FnMut(usize, u64),
+    {
+        let file = Arc::new(file);
+        let mut records = ColumnRecords {
+            cells: ColumnEntries::mapped(file.clone()),
+            ..Default::default()
+        };
+        let mut offset = start;
+        let mut next_progress = PROGRESS_INTERVAL;
+        while offset < end {
+            let bytes = &file[offset as usize..(end as usize).min(file.len())];
+            let Some((checksum, tag_byte, data)) = Column::split_record(bytes) else {
+                records.torn_tail = Some(offset..end);
+                break;
+            };
+            let size = 9 + data.len() as u64;
+            if CRC32.checksum(data) != checksum {
+                if offset + size >= end {
+                    records.torn_tail = Some(offset..end);
+                    break;
+                }
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Column file {} holds a corrupt record at offset {}", file_name, offset),
+                ));
+            }
+            match tag_byte {
+                TAG_TOMBSTONE | TAG_ZSTD_BLOCK => {
+                    let record = Column::decode_record(tag_byte, data.to_vec())?;
+                    Column::add_record(&mut records, record, file_name, offset)?;
+                }
+                //Unlike runs, their cells differ, so they get decoded right away like blocks
+                tag_byte if is_packed(tag_byte) => {
+                    let record = Column::decode_record(tag_byte, data.to_vec())?;
+                    Column::add_record(&mut records, record, file_name, offset)?;
+                }
+                TAG_RUN => records.cells.push_record(offset as usize, decode_run(data)?.0 as usize),
+                _ => records.cells.push_record(offset as usize, 1),
+            }
+            offset += size;
+            if records.cells.len() >= next_progress {
+                progress(records.cells.len(), offset - start);
+                next_progress = records.cells.len() + PROGRESS_INTERVAL;
+            }
+        }
+        progress(records.cells.len(), offset.min(end) - start);
+        Ok(records)
+    }
+
+    ///Checksum, tag and payload of the record the bytes start with. `None` if it's cut off.
+    fn split_record(bytes: &[u8]) -> Option<(u32, u8, &[u8])> {
+        let mut header = bytes.get(..9)?;
+        let checksum = header.read_u32::<LittleEndian>().ok()?;
+        let tag_byte = header.read_u8().ok()?;
+        let val_len = header.read_u32::<LittleEndian>().ok()? as usize;
+        Some((checksum, tag_byte, bytes.get(9..9 + val_len)?))
+    }
+
+    ///Adds the record to the records read so far, along with all records of a block. Returns its size in the file.
+    fn add_record(records: &mut ColumnRecords, record: Record, file_name: &str, offset: u64) -> io::Result<u64> {
+        let Record::Block(count, block, size) = record else {
+            return Ok(Column::add_unblocked_record(records, record));
+        };
+        let mut block = block.as_slice();
+        let mut read = 0;
+        while !block.is_empty() {
+            match Column::process_record(&mut block)? {
+                Record::Block(..) | Record::Corrupt(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Block in column file {} at offset {} is corrupt", file_name, offset),
+                    ));
+                }
+                record => Column::add_unblocked_record(records, record),
+            };
+            read += 1;
+        }
+        if read != count {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Block in column file {} holds {} records, its directory lists {}", file_name, read, count),
+            ));
+        }
+        Ok(size)
+    }
+
+    ///Adds a record outside of blocks to the records read so far. Returns its size in the file.
+    fn add_unblocked_record(records: &mut ColumnRecords, record: Record) -> u64 {
+        match record {
+            //Encoding the cell again would compress large strings a second time
+            Record::Cell(cell, size) => {
+                records.cells.push(cell);
+                size
+            }
+            Record::Run(cell, count, size) => {
+                records.cells.extend(std::iter::repeat_n(cell, count as usize));
+                size
+            }
+            Record::Packed(cells, size) => {
+                records.cells.extend(cells);
+                size
+            }
+            Record::Tombstone(position) => {
+                records.tombstones.push(position);
+                9 + 8
+            }
+            Record::Block(..) | Record::Corrupt(_) => unreachable!("add_record handles blocks, read_entries corrupt records"),
+        }
+    }
+
+    ///Puts records read by `read_entries` in front of the ones inserted meanwhile
+    pub fn install_entries(&mut self, records: ColumnRecords) -> io::Result<()> {
+        if let Some(torn_tail) = records.torn_tail {
+            self.discard_torn_tail(torn_tail)?;
+        }
+        let mut entries = records.cells;
+        entries.append(std::mem::take(&mut self.entries));
+        self.entries = entries;
+        self.tombstones.extend(records.tombstones);
         Ok(())
     }
 
-    fn process_record<R: Read>(f: &mut R) -> io::Result<Cell> {
+    pub fn backend(&self) -> &Arc<dyn StorageBackend> {
+        &self.backend
+    }
+
+    ///Rows dropped along with whole segments, see `SegmentManifest`
+    pub fn dropped_rows(&self) -> usize {
+        self.dropped_rows
+    }
+
+    ///Length of all segment files including records still sitting in the write buffer
+    pub fn file_len(&self) -> u64 {
+        self.closed.iter().map(|(_, len)| len).sum::<u64>() + self.len
+    }
+
+    fn process_record<R: Read>(f: &mut R) -> io::Result<Record> {
         let saved_checksum = f.read_u32::<LittleEndian>()?;
         let tag_byte = f.read_u8()?;
         let val_len = f.read_u32::<LittleEndian>()?;
-        let mut data = ByteString::with_capacity(val_len as usize);
+        //The length of a torn record may be garbage, don't trust it with the allocation
+        let mut data = ByteString::with_capacity((val_len as usize).min(DEFAULT_WRITE_BUFFER_SIZE));
 
         {
             f.by_ref() // <2>
                 .take(val_len as u64)
                 .read_to_end(&mut data)?;
         }
-        debug_assert_eq!(val_len as usize, data.len() as usize);
+        if data.len() < val_len as usize {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Record ends early"));
+        }
 
         let checksum = CRC32.checksum(&data);
         if checksum != saved_checksum {
-            panic!(
-                "data corruption encountered ({:08x} != {:08x})",
-                checksum, saved_checksum
-            );
+            warn!("data corruption encountered ({:08x} != {:08x})", checksum, saved_checksum);
+            return Ok(Record::Corrupt(9 + val_len as u64));
+        }
+        Column::decode_record(tag_byte, data)
+    }
+
+    ///Decodes the payload of a record whose checksum matches
+    fn decode_record(tag_byte: u8, data: ByteString) -> io::Result<Record> {
+        let val_len = data.len();
+        if tag_byte == TAG_TOMBSTONE {
+            return Ok(Record::Tombstone(data.as_slice().read_u64::<LittleEndian>()? as usize));
         }
+        if tag_byte == TAG_ZSTD_BLOCK {
+            let mut directory = data.as_slice();
+            let count = directory.read_u32::<LittleEndian>()?;
+            let uncompressed_len = directory.read_u32::<LittleEndian>()?;
+            let block = zstd::bulk::decompress(directory, uncompressed_len as usize)?;
+            return Ok(Record::Block(count, block, 9 + val_len as u64));
+        }
+        if is_packed(tag_byte) {
+            return Ok(Record::Packed(decode_packed(tag_byte, &data)?, 9 + val_len as u64));
+        }
+        if tag_byte == TAG_RUN {
+            let (count, tag_byte, data) = decode_run(&data)?;
+            return Ok(Record::Run(Cell::from_bytes(tag_byte, data.to_vec()).unwrap(), count, 9 + val_len as u64));
+        }
+        Ok(Record::Cell(Cell::from_bytes(tag_byte, data).unwrap(), 9 + val_len as u64))
+    }
 
-        Ok(Cell::from_bytes(tag_byte, data).unwrap())
+    ///All cells, reading the records of a deferred load first
+    pub fn entries(&self) -> &ColumnEntries {
+        match &self.deferred {
+            Some(deferred) => {
+                let records = deferred.records.get_or_init(|| {
+                    self.read_deferred(deferred)
+                        .unwrap_or_else(|err| panic!("Failed to load column {}: {}", self.name, err))
+                });
+                &records.cells
+            }
+            None => &self.entries,
+        }
     }
+}
 
-    pub fn entries(&self) -> &[Cell] {
-        self.entries.as_ref()
+impl Drop for Column {
+    //Like a BufWriter, write out what's left. There is no one left to report an error to.
+    fn drop(&mut self) {
+        let _ = self.flush();
     }
 }
 
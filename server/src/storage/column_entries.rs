@@ -0,0 +1,246 @@
+use std::ops::Index;
+use std::sync::{Arc, OnceLock};
+
+use byteorder::{LittleEndian, ReadBytesExt};
+
+use super::backend::MappedFile;
+use super::block_encoder::{decode_run, TAG_RUN};
+use super::cell::Cell;
+
+///Cells of a column, by position. Either decoded up front, or decoded on first access from the mapped column files.
+#[derive(Debug)]
+pub enum ColumnEntries {
+    Decoded(Vec<Cell>),
+    Mapped(MappedEntries),
+}
+
+///Record a cell got read from, see `MappedEntries`
+#[derive(Debug, Clone, Copy)]
+struct RecordRef {
+    ///Index into `MappedEntries::files`
+    file: usize,
+    offset: usize,
+}
+
+#[derive(Debug)]
+struct MappedEntry {
+    ///`None` for cells inserted after loading, or read from compressed blocks. Their cell is always set.
+    record: Option<RecordRef>,
+    cell: OnceLock<Cell>,
+}
+
+///Offsets of the records holding the cells in the mapped files. The cells of a run all point to the run's record.
+#[derive(Debug, Default)]
+pub struct MappedEntries {
+    files: Vec<Arc<MappedFile>>,
+    entries: Vec<MappedEntry>,
+}
+
+impl MappedEntries {
+    fn get(&self, n: usize) -> Option<&Cell> {
+        let entry = self.entries.get(n)?;
+        Some(entry.cell.get_or_init(|| {
+            let record = entry.record.expect("Cells without a record get set when added");
+            MappedEntries::decode(&self.files[record.file][record.offset..])
+        }))
+    }
+
+    ///Decodes the cell or run record at the start of the bytes. Its checksum got verified when it got added.
+    fn decode(record: &[u8]) -> Cell {
+        let tag_byte = record[4];
+        let len = (&record[5..9]).read_u32::<LittleEndian>().unwrap() as usize;
+        let data = &record[9..9 + len];
+        match tag_byte {
+            TAG_RUN => {
+                let (_count, tag_byte, data) = decode_run(data).expect("Verified run record");
+                Cell::from_bytes(tag_byte, data.to_vec()).unwrap()
+            }
+            tag_byte => Cell::from_bytes(tag_byte, data.to_vec()).unwrap(),
+        }
+    }
+
+    fn decoded(cells: Vec<Cell>) -> Self {
+        Self {
+            files: vec![],
+            entries: cells
+                .into_iter()
+                .map(|cell| MappedEntry {
+                    record: None,
+                    cell: OnceLock::from(cell),
+                })
+                .collect(),
+        }
+    }
+
+    ///Drops files no entry refers to anymore, so their mappings don't keep removed files around
+    fn release_files(&mut self) {
+        let first = self
+            .entries
+            .iter()
+            .filter_map(|entry| entry.record.map(|record| record.file))
+            .min()
+            .unwrap_or(self.files.len());
+        if first == 0 {
+            return;
+        }
+        self.files.drain(..first);
+        for record in self.entries.iter_mut().filter_map(|entry| entry.record.as_mut()) {
+            record.file -= first;
+        }
+    }
+}
+
+impl Default for ColumnEntries {
+    fn default() -> Self {
+        ColumnEntries::Decoded(vec![])
+    }
+}
+
+impl From<Vec<Cell>> for ColumnEntries {
+    fn from(cells: Vec<Cell>) -> Self {
+        ColumnEntries::Decoded(cells)
+    }
+}
+
+impl ColumnEntries {
+    ///No cells yet, `push_record` adds the ones of records in the mapped file
+    pub fn mapped(file: Arc<MappedFile>) -> Self {
+        ColumnEntries::Mapped(MappedEntries {
+            files: vec![file],
+            entries: vec![],
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            ColumnEntries::Decoded(cells) => cells.len(),
+            ColumnEntries::Mapped(mapped) => mapped.entries.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    ///The cell at the position, decoding it if it wasn't accessed before
+    pub fn get(&self, n: usize) -> Option<&Cell> {
+        match self {
+            ColumnEntries::Decoded(cells) => cells.get(n),
+            ColumnEntries::Mapped(mapped) => mapped.get(n),
+        }
+    }
+
+    pub fn first(&self) -> Option<&Cell> {
+        self.get(0)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Cell> {
+        (0..self.len()).map(|n| &self[n])
+    }
+
+    pub fn to_vec(&self) -> Vec<Cell> {
+        self.iter().cloned().collect()
+    }
+
+    pub fn push(&mut self, cell: Cell) {
+        match self {
+            ColumnEntries::Decoded(cells) => cells.push(cell),
+            ColumnEntries::Mapped(mapped) => mapped.entries.push(MappedEntry {
+                record: None,
+                cell: OnceLock::from(cell),
+            }),
+        }
+    }
+
+    pub fn extend(&mut self, cells: impl IntoIterator<Item = Cell>) {
+        for cell in cells {
+            self.push(cell);
+        }
+    }
+
+    ///Adds count cells stored in the record at the offset of the file the entries got created with
+    pub fn push_record(&mut self, offset: usize, count: usize) {
+        let ColumnEntries::Mapped(mapped) = self else {
+            unreachable!("Only mapped entries refer to records");
+        };
+        let record = Some(RecordRef {
+            file: mapped.files.len() - 1,
+            offset,
+        });
+        mapped.entries.extend((0..count).map(|_| MappedEntry {
+            record,
+            cell: OnceLock::new(),
+        }));
+    }
+
+    pub fn truncate(&mut self, len: usize) {
+        match self {
+            ColumnEntries::Decoded(cells) => cells.truncate(len),
+            ColumnEntries::Mapped(mapped) => {
+                mapped.entries.truncate(len);
+                mapped.release_files();
+            }
+        }
+    }
+
+    ///Removes the first count cells
+    pub fn drain_front(&mut self, count: usize) {
+        match self {
+            ColumnEntries::Decoded(cells) => {
+                cells.drain(..count);
+            }
+            ColumnEntries::Mapped(mapped) => {
+                mapped.entries.drain(..count);
+                mapped.release_files();
+            }
+        }
+    }
+
+    ///Moves the cells of other behind the ones of self
+    pub fn append(&mut self, other: ColumnEntries) {
+        match (&mut *self, other) {
+            (ColumnEntries::Decoded(cells), ColumnEntries::Decoded(mut other)) => cells.append(&mut other),
+            (ColumnEntries::Mapped(mapped), ColumnEntries::Decoded(other)) => mapped.entries.append(&mut MappedEntries::decoded(other).entries),
+            (ColumnEntries::Decoded(cells), ColumnEntries::Mapped(other)) => {
+                let mut mapped = MappedEntries::decoded(std::mem::take(cells));
+                mapped.files = other.files;
+                mapped.entries.extend(other.entries);
+                *self = ColumnEntries::Mapped(mapped);
+            }
+            (ColumnEntries::Mapped(mapped), ColumnEntries::Mapped(other)) => {
+                let files = mapped.files.len();
+                mapped.files.extend(other.files);
+                mapped.entries.extend(other.entries.into_iter().map(|mut entry| {
+                    if let Some(record) = entry.record.as_mut() {
+                        record.file += files;
+                    }
+                    entry
+                }));
+            }
+        }
+    }
+}
+
+impl Index<usize> for ColumnEntries {
+    type Output = Cell;
+
+    fn index(&self, n: usize) -> &Cell {
+        match self.get(n) {
+            Some(cell) => cell,
+            None => panic!("index out of bounds: the len is {} but the index is {}", self.len(), n),
+        }
+    }
+}
+
+impl<T: AsRef<[Cell]> + ?Sized> PartialEq<T> for ColumnEntries {
+    fn eq(&self, other: &T) -> bool {
+        let other = other.as_ref();
+        self.len() == other.len() && self.iter().zip(other).all(|(cell, other)| cell == other)
+    }
+}
+
+impl PartialEq<Vec<Cell>> for &ColumnEntries {
+    fn eq(&self, other: &Vec<Cell>) -> bool {
+        **self == *other
+    }
+}
@@ -23,6 +23,10 @@ impl ColumnFrame {
         self.column_values.push(cell);
     }
 
+    pub fn column_names(&self) -> &[String] {
+        &self.column_names
+    }
+
     pub fn get(&self, column_name: &str) -> Option<&Cell> {
         if let Some(index) = self.column_names.iter().position(|c| c == column_name) {
             let cell = self.column_values.get(index).expect("Encountered ColumnFrame. Internal Index Mismatch");
@@ -0,0 +1,231 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use tracing::warn;
+
+use super::cell::Cell;
+use super::checked_file::CheckedFile;
+use super::column_entries::ColumnEntries;
+use super::ByteString;
+
+///Positions of the rows holding a value, by the value's `Cell::hash_key`
+type Positions = HashMap<(u8, ByteString), Vec<usize>>;
+
+///Positions of the live rows by value, for columns marked `indexed` in the schema. Unlike `KeyIndex`, a value
+///may belong to many rows. Written to `index_<column>` on flush, so it doesn't need to be rebuilt on startup.
+#[derive(Debug)]
+pub struct ColumnIndex {
+    column: String,
+    rows: Positions,
+    ///Rows stored when the index got built or last updated, see `ColumnLayout::check_indexes`
+    covered: usize,
+    file: CheckedFile,
+    ///Set when the index changed since it got written
+    changed: bool,
+}
+
+impl ColumnIndex {
+    pub fn file_path(root_path: &Path, column: &str) -> PathBuf {
+        root_path.join(format!("index_{}", column))
+    }
+
+    ///Removes the indexes of all columns, e.g. after the rows got restored from the write-ahead log
+    pub fn remove_all(root_path: &Path) -> io::Result<()> {
+        for entry in fs::read_dir(root_path)? {
+            let entry = entry?;
+            if entry.file_type()?.is_file() && entry.file_name().to_string_lossy().starts_with("index_") {
+                fs::remove_file(entry.path())?;
+            }
+        }
+        Ok(())
+    }
+
+    ///Empty index, `rebuild` fills it
+    pub fn new(root_path: &Path, column: &str) -> Self {
+        Self {
+            column: column.to_string(),
+            rows: HashMap::new(),
+            covered: 0,
+            file: CheckedFile::new(ColumnIndex::file_path(root_path, column)),
+            changed: true,
+        }
+    }
+
+    ///Reads the index written on the last flush. Returns an empty index if there is none or it's unreadable.
+    pub fn load(root_path: &Path, column: &str) -> io::Result<Self> {
+        let path = ColumnIndex::file_path(root_path, column);
+        let mut index = ColumnIndex::new(root_path, column);
+        if !CheckedFile::exists(&path) {
+            return Ok(index);
+        }
+        let (file, payload) = match CheckedFile::load(path) {
+            Ok(loaded) => loaded,
+            Err(err) if err.kind() == io::ErrorKind::InvalidData => {
+                warn!("Ignoring index of column {}: {}", column, err);
+                return Ok(index);
+            }
+            Err(err) => return Err(err),
+        };
+        index.file = file;
+        match ColumnIndex::decode(&payload) {
+            Ok((covered, rows)) => {
+                index.covered = covered;
+                index.rows = rows;
+                index.changed = false;
+            }
+            Err(err) => warn!("Ignoring index of column {}: {}", column, err),
+        }
+        Ok(index)
+    }
+
+    pub fn column(&self) -> &str {
+        &self.column
+    }
+
+    pub fn covered(&self) -> usize {
+        self.covered
+    }
+
+    ///Positions of the rows holding the value, oldest first. Null isn't indexed.
+    pub fn get(&self, value: &Cell) -> &[usize] {
+        value
+            .hash_key()
+            .and_then(|key| self.rows.get(&key))
+            .map(|positions| positions.as_slice())
+            .unwrap_or_default()
+    }
+
+    pub fn rebuild(&mut self, cells: &ColumnEntries, deleted: &HashSet<usize>) {
+        self.rows.clear();
+        for (position, cell) in cells.iter().enumerate().filter(|(n, _)| !deleted.contains(n)) {
+            if let Some(key) = cell.hash_key() {
+                self.rows.entry(key).or_default().push(position);
+            }
+        }
+        self.covered = cells.len();
+        self.changed = true;
+    }
+
+    ///Adds the row appended at the position
+    pub fn insert(&mut self, value: &Cell, position: usize) {
+        if let Some(key) = value.hash_key() {
+            self.rows.entry(key).or_default().push(position);
+        }
+        self.covered = position + 1;
+        self.changed = true;
+    }
+
+    pub fn remove(&mut self, value: &Cell, position: usize) {
+        let Some(key) = value.hash_key() else {
+            return;
+        };
+        if let Some(positions) = self.rows.get_mut(&key) {
+            positions.retain(|n| *n != position);
+            if positions.is_empty() {
+                self.rows.remove(&key);
+            }
+        }
+        self.changed = true;
+    }
+
+    ///Forgets the rows from the position on, e.g. the ones of a rolled back transaction
+    pub fn forget_from(&mut self, position: usize) {
+        self.rows.retain(|_, positions| {
+            positions.retain(|n| *n < position);
+            !positions.is_empty()
+        });
+        self.covered = self.covered.min(position);
+        self.changed = true;
+    }
+
+    ///Moves the index to the file of the new column name
+    pub fn rename(&mut self, root_path: &Path, to: &str) -> io::Result<()> {
+        let file = std::mem::replace(&mut self.file, CheckedFile::new(ColumnIndex::file_path(root_path, to)));
+        file.remove()?;
+        self.column = to.to_string();
+        self.changed = true;
+        Ok(())
+    }
+
+    ///Removes the index file, once its column got dropped
+    pub fn remove_file(self) -> io::Result<()> {
+        self.file.remove()
+    }
+
+    pub fn persist(&mut self) -> io::Result<()> {
+        if !self.changed {
+            return Ok(());
+        }
+        self.file.write(&self.encode()?)?;
+        self.changed = false;
+        Ok(())
+    }
+
+    ///`covered | values`, followed by every value as `tag | length | bytes | row count | positions`
+    fn encode(&self) -> io::Result<Vec<u8>> {
+        let mut payload = vec![];
+        payload.write_u64::<LittleEndian>(self.covered as u64)?;
+        payload.write_u64::<LittleEndian>(self.rows.len() as u64)?;
+        for ((tag_byte, bytes), positions) in &self.rows {
+            payload.write_u8(*tag_byte)?;
+            payload.write_u32::<LittleEndian>(bytes.len() as u32)?;
+            payload.extend(bytes);
+            payload.write_u32::<LittleEndian>(positions.len() as u32)?;
+            for position in positions {
+                payload.write_u64::<LittleEndian>(*position as u64)?;
+            }
+        }
+        Ok(payload)
+    }
+
+    fn decode(mut payload: &[u8]) -> io::Result<(usize, Positions)> {
+        let covered = payload.read_u64::<LittleEndian>()? as usize;
+        let values = payload.read_u64::<LittleEndian>()?;
+        let mut rows = HashMap::new();
+        for _ in 0..values {
+            let tag_byte = payload.read_u8()?;
+            let mut bytes = vec![0; payload.read_u32::<LittleEndian>()? as usize];
+            payload.read_exact(&mut bytes)?;
+            let count = payload.read_u32::<LittleEndian>()?;
+            let positions = (0..count)
+                .map(|_| payload.read_u64::<LittleEndian>().map(|position| position as usize))
+                .collect::<io::Result<Vec<_>>>()?;
+            rows.insert((tag_byte, bytes), positions);
+        }
+        Ok((covered, rows))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::ColumnIndex;
+    use crate::storage::{cell::Cell, column_entries::ColumnEntries};
+
+    #[test]
+    fn finds_every_row_of_a_value_after_a_restart() {
+        let root = tempfile::tempdir().unwrap();
+        let mut index = ColumnIndex::new(root.path(), "country");
+        let cells = ColumnEntries::from(vec![
+            Cell::String("de".into()),
+            Cell::Null,
+            Cell::String("at".into()),
+            Cell::String("de".into()),
+        ]);
+        index.rebuild(&cells, &HashSet::from([2]));
+        index.insert(&Cell::String("de".into()), 4);
+        index.remove(&Cell::String("de".into()), 0);
+        assert_eq!(index.get(&Cell::String("de".into())), &[3, 4]);
+        assert!(index.get(&Cell::String("at".into())).is_empty());
+        index.persist().unwrap();
+
+        let index = ColumnIndex::load(root.path(), "country").unwrap();
+        assert_eq!(index.covered(), 5);
+        assert_eq!(index.get(&Cell::String("de".into())), &[3, 4]);
+        assert!(index.get(&Cell::Null).is_empty());
+    }
+}
@@ -0,0 +1,281 @@
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use super::cell::Cell;
+use super::data_type::DataType;
+
+///Relative error of the values a histogram reports, see `Histogram`
+const RELATIVE_ACCURACY: f64 = 0.01;
+///Percentiles `GET /stats/columns/{name}/histogram` reports unless the request lists others
+pub const DEFAULT_PERCENTILES: [f64; 4] = [50.0, 90.0, 95.0, 99.0];
+
+///Shape of a column's cells, as `GET /stats` reports it. Covers every stored cell, including the ones of
+///deleted rows, until compaction or retention drops them.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ColumnStats {
+    pub count: u64,
+    pub null_count: u64,
+    ///Smallest value. Only tracked for numbers, strings, booleans, timestamps and UUIDs.
+    pub min: Option<Cell>,
+    pub max: Option<Cell>,
+    ///Distribution of the Int and Float cells. Too large for `GET /stats`, so only written to `column_stats.json`.
+    #[serde(skip)]
+    pub histogram: Option<Histogram>,
+}
+
+///Approximate distribution of numeric values. Every value falls into the bucket whose bounds grow by a factor of
+///`(1 + RELATIVE_ACCURACY) / (1 - RELATIVE_ACCURACY)`, so percentiles are off by at most 1% of their value,
+///no matter how large the range of values is. Buckets only exist once a value falls into them.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Histogram {
+    pub count: u64,
+    ///Values above zero by bucket, bucket `i` holds values in `(GAMMA^(i-1), GAMMA^i]`
+    positive: BTreeMap<i32, u64>,
+    ///Values below zero by the bucket of their absolute value
+    negative: BTreeMap<i32, u64>,
+    zeros: u64,
+}
+
+///Count of the values between lower and upper
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HistogramBucket {
+    pub lower: f64,
+    pub upper: f64,
+    pub count: u64,
+}
+
+///Histogram of a column as `GET /stats/columns/{name}/histogram` reports it
+#[derive(Debug, Serialize)]
+pub struct ColumnHistogram {
+    pub column: String,
+    ///Numbers stored in the column, including the ones of deleted rows, see `ColumnStats`
+    pub count: u64,
+    pub min: Option<Cell>,
+    pub max: Option<Cell>,
+    ///Approximate value of each requested percentile, by name like `p95`
+    pub percentiles: BTreeMap<String, f64>,
+    ///Buckets holding values, in ascending order
+    pub buckets: Vec<HistogramBucket>,
+}
+
+fn gamma() -> f64 {
+    (1.0 + RELATIVE_ACCURACY) / (1.0 - RELATIVE_ACCURACY)
+}
+
+impl Histogram {
+    pub fn add(&mut self, value: f64) {
+        if value.is_nan() {
+            return;
+        }
+        self.count += 1;
+        if value == 0.0 {
+            self.zeros += 1;
+            return;
+        }
+        let bucket = value.abs().ln() / gamma().ln();
+        let buckets = if value > 0.0 { &mut self.positive } else { &mut self.negative };
+        *buckets.entry(bucket.ceil() as i32).or_default() += 1;
+    }
+
+    ///Buckets holding values, in ascending order
+    pub fn buckets(&self) -> Vec<HistogramBucket> {
+        let bounds = |bucket: i32| (gamma().powi(bucket - 1), gamma().powi(bucket));
+        let negative = self.negative.iter().rev().map(|(bucket, count)| {
+            let (lower, upper) = bounds(*bucket);
+            HistogramBucket { lower: -upper, upper: -lower, count: *count }
+        });
+        let zeros = (self.zeros > 0).then_some(HistogramBucket { lower: 0.0, upper: 0.0, count: self.zeros });
+        let positive = self.positive.iter().map(|(bucket, count)| {
+            let (lower, upper) = bounds(*bucket);
+            HistogramBucket { lower, upper, count: *count }
+        });
+        negative.chain(zeros).chain(positive).collect()
+    }
+
+    ///Approximate value below which the percentage of values lie, None if there are no values.
+    ///Each bucket stands for the value in its middle, which lies within `RELATIVE_ACCURACY` of all of its values.
+    pub fn percentile(&self, percentage: f64) -> Option<f64> {
+        let rank = (percentage.clamp(0.0, 100.0) / 100.0 * self.count.checked_sub(1)? as f64).round() as u64;
+        let mut seen = 0;
+        for bucket in self.buckets() {
+            seen += bucket.count;
+            if seen > rank {
+                return Some(match bucket.upper {
+                    upper if upper > 0.0 => 2.0 * upper / (1.0 + gamma()),
+                    _ => 2.0 * bucket.lower / (1.0 + gamma()),
+                });
+            }
+        }
+        None
+    }
+}
+
+///`ColumnStats` as stored in `column_stats.json`. Values only get their type back from the column's data type.
+#[derive(Deserialize)]
+pub(crate) struct StoredStats {
+    count: u64,
+    null_count: u64,
+    min: Option<serde_json::Value>,
+    max: Option<serde_json::Value>,
+    #[serde(default)]
+    histogram: Option<Histogram>,
+}
+
+///`ColumnStats` along with its histogram, as written to `column_stats.json`
+#[derive(Serialize)]
+pub(crate) struct PersistedStats<'a> {
+    #[serde(flatten)]
+    stats: &'a ColumnStats,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    histogram: &'a Option<Histogram>,
+}
+
+impl<'a> From<&'a ColumnStats> for PersistedStats<'a> {
+    fn from(stats: &'a ColumnStats) -> Self {
+        PersistedStats { stats, histogram: &stats.histogram }
+    }
+}
+
+///Statistics of all columns of the table
+#[derive(Debug, Serialize)]
+pub struct TableStats {
+    ///Stored rows, including deleted ones
+    pub rows: usize,
+    pub deleted_rows: usize,
+    pub columns: BTreeMap<String, ColumnStats>,
+}
+
+impl ColumnStats {
+    pub fn from_cells<'a>(cells: impl IntoIterator<Item = &'a Cell>) -> Self {
+        let mut stats = ColumnStats::default();
+        for cell in cells {
+            stats.add(cell);
+        }
+        stats
+    }
+
+    pub(crate) fn from_stored(stored: StoredStats, data_type: &DataType) -> Self {
+        let value = |value: Option<serde_json::Value>| value.and_then(|value| Cell::from_typed_json(&value, data_type));
+        ColumnStats {
+            count: stored.count,
+            null_count: stored.null_count,
+            min: value(stored.min),
+            max: value(stored.max),
+            histogram: stored.histogram,
+        }
+    }
+
+    ///False for statistics of numbers written by versions without histograms, which have to be rebuilt
+    pub(crate) fn has_histogram(&self) -> bool {
+        let numeric = |cell: &Option<Cell>| matches!(cell, Some(Cell::Int(_) | Cell::Float(_)));
+        self.histogram.is_some() || !(numeric(&self.min) || numeric(&self.max))
+    }
+
+    pub fn histogram(&self, column_name: &str, percentiles: &[f64]) -> Option<ColumnHistogram> {
+        let histogram = self.histogram.as_ref()?;
+        Some(ColumnHistogram {
+            column: column_name.to_string(),
+            count: histogram.count,
+            min: self.min.clone(),
+            max: self.max.clone(),
+            percentiles: percentiles
+                .iter()
+                .filter_map(|percentage| Some((format!("p{}", percentage), histogram.percentile(*percentage)?)))
+                .collect(),
+            buckets: histogram.buckets(),
+        })
+    }
+
+    pub fn add(&mut self, cell: &Cell) {
+        self.count += 1;
+        if *cell == Cell::Null {
+            self.null_count += 1;
+            return;
+        }
+        match cell {
+            Cell::Int(value) => self.histogram.get_or_insert_default().add(*value as f64),
+            Cell::Float(value) => self.histogram.get_or_insert_default().add(*value),
+            _ => {}
+        }
+        if compare(cell, cell).is_none() {
+            return;
+        }
+        if self.min.as_ref().is_none_or(|min| compare(cell, min) == Some(Ordering::Less)) {
+            self.min = Some(cell.clone());
+        }
+        if self.max.as_ref().is_none_or(|max| compare(cell, max) == Some(Ordering::Greater)) {
+            self.max = Some(cell.clone());
+        }
+    }
+}
+
+///Order of two values of the same column. None for values without an order, like NaN, arrays and JSON.
+fn compare(left: &Cell, right: &Cell) -> Option<Ordering> {
+    match (left, right) {
+        (Cell::Int(left), Cell::Int(right)) | (Cell::Timestamp(left), Cell::Timestamp(right)) => Some(left.cmp(right)),
+        (Cell::Float(left), Cell::Float(right)) => left.partial_cmp(right),
+        (Cell::Int(left), Cell::Float(right)) => (*left as f64).partial_cmp(right),
+        (Cell::Float(left), Cell::Int(right)) => left.partial_cmp(&(*right as f64)),
+        (Cell::String(left), Cell::String(right)) => Some(left.cmp(right)),
+        (Cell::Boolean(left), Cell::Boolean(right)) => Some(left.cmp(right)),
+        (Cell::Uuid(left), Cell::Uuid(right)) => Some(left.cmp(right)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ColumnStats, Histogram, PersistedStats, StoredStats};
+    use crate::storage::cell::Cell;
+    use crate::storage::data_type::DataType;
+
+    #[test]
+    fn tracks_range_and_nulls() {
+        let cells = vec![Cell::Float(2.5), Cell::Null, Cell::Float(f64::NAN), Cell::Int(-1), Cell::Float(7.0)];
+        let stats = ColumnStats::from_cells(&cells);
+        assert_eq!(stats.count, 5);
+        assert_eq!(stats.null_count, 1);
+        assert_eq!(stats.min, Some(Cell::Int(-1)));
+        assert_eq!(stats.max, Some(Cell::Float(7.0)));
+
+        let arrays = ColumnStats::from_cells(&[Cell::Array(vec![Cell::Int(1)])]);
+        assert_eq!((arrays.count, arrays.min, arrays.max), (1, None, None));
+    }
+
+    #[test]
+    fn stored_stats_keep_their_type() {
+        let stats = ColumnStats::from_cells(&[Cell::Timestamp(1677125260), Cell::Timestamp(1677125200)]);
+        let stored: StoredStats = serde_json::from_str(&serde_json::to_string(&stats).unwrap()).unwrap();
+        assert_eq!(ColumnStats::from_stored(stored, &DataType::Timestamp), stats);
+
+        let numbers = ColumnStats::from_cells(&[Cell::Int(3), Cell::Int(-8)]);
+        let stored: StoredStats = serde_json::from_slice(&serde_json::to_vec(&PersistedStats::from(&numbers)).unwrap()).unwrap();
+        assert_eq!(ColumnStats::from_stored(stored, &DataType::Int), numbers);
+        let stored: StoredStats = serde_json::from_slice(&serde_json::to_vec(&numbers).unwrap()).unwrap();
+        assert!(!ColumnStats::from_stored(stored, &DataType::Int).has_histogram());
+    }
+
+    #[test]
+    fn histograms_estimate_percentiles() {
+        let mut histogram = Histogram::default();
+        for value in 1..=1000 {
+            histogram.add(value as f64);
+        }
+        histogram.add(f64::NAN);
+        assert_eq!(histogram.count, 1000);
+        let p95 = histogram.percentile(95.0).unwrap();
+        assert!((p95 - 950.0).abs() <= 950.0 * 0.01, "p95 was {}", p95);
+        assert!((histogram.percentile(0.0).unwrap() - 1.0).abs() <= 0.01);
+        assert_eq!(histogram.buckets().iter().map(|bucket| bucket.count).sum::<u64>(), 1000);
+
+        let mixed = ColumnStats::from_cells(&[Cell::Int(-5), Cell::Int(0), Cell::Float(5.0), Cell::Null]);
+        let report = mixed.histogram("points", &[50.0]).unwrap();
+        assert_eq!(report.percentiles["p50"], 0.0);
+        assert!(report.buckets.first().unwrap().upper < 0.0);
+        assert!(report.buckets.windows(2).all(|pair| pair[0].upper <= pair[1].lower));
+        assert!(Histogram::default().percentile(50.0).is_none());
+        assert!(ColumnStats::from_cells(&[Cell::String("a".into())]).histogram("url", &[50.0]).is_none());
+    }
+}
@@ -0,0 +1,80 @@
+use serde::{Deserialize, Serialize};
+use std::io::{self, Read};
+use tracing::warn;
+
+use super::backend::StorageBackend;
+use super::segments::SegmentManifest;
+
+///Holds the `StagedCompaction` whose rewritten files are all staged, but may not be swapped in yet.
+///As long as it exists, startup finishes the swap, so a crash never leaves some columns compacted and others not.
+pub const MARKER_FILE: &str = "compaction";
+
+///What a compaction run removed
+#[derive(Debug, Clone, Serialize)]
+pub struct CompactionReport {
+    ///Unix timestamp the run finished at
+    pub ran_at: i64,
+    ///Deleted rows and replaced versions of updated rows dropped from the column files
+    pub rows: usize,
+    ///Size of all column files before and after the run
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+}
+
+///Compaction whose rewritten files are all staged. Recorded in the marker, so startup can finish it.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StagedCompaction {
+    ///Segment files which get replaced by their staged file
+    pub files: Vec<String>,
+    ///Segments of the rewritten columns
+    pub segments: SegmentManifest,
+    ///Files of segments which no longer exist once the rewritten ones are in place
+    pub obsolete: Vec<String>,
+}
+
+///Name the rewritten segment file is staged under until it gets swapped in
+pub fn staged_name(file_name: &str) -> String {
+    format!("{}.compacted", file_name)
+}
+
+///Records that all rewritten files are staged. From here on the compaction gets finished.
+pub fn mark_staged(backend: &dyn StorageBackend, staged: &StagedCompaction) -> io::Result<()> {
+    backend.replace(MARKER_FILE, &serde_json::to_vec(staged)?)
+}
+
+///Replaces every file with its staged file.
+///Files swapped in before a crash are skipped, their staged file is already gone.
+pub fn swap_in(backend: &dyn StorageBackend, files: &[String]) -> io::Result<()> {
+    for file_name in files {
+        let staged = staged_name(file_name);
+        let Some(len) = backend.len(&staged)? else {
+            continue;
+        };
+        let mut bytes = vec![];
+        backend.read_range(&staged, 0, len)?.read_to_end(&mut bytes)?;
+        backend.replace(file_name, &bytes)?;
+        backend.remove(&staged)?;
+    }
+    Ok(())
+}
+
+///Compaction a crash interrupted after its files got staged, if any.
+///Staged files without a marker belong to a compaction which never finished staging, and get removed.
+pub fn pending(backend: &dyn StorageBackend, files: &[String]) -> io::Result<Option<StagedCompaction>> {
+    let Some(len) = backend.len(MARKER_FILE)? else {
+        for file_name in files {
+            backend.remove(&staged_name(file_name))?;
+        }
+        return Ok(None);
+    };
+    let mut bytes = vec![];
+    backend.read_range(MARKER_FILE, 0, len)?.read_to_end(&mut bytes)?;
+    let staged: StagedCompaction = serde_json::from_slice(&bytes)?;
+    warn!("Finishing a compaction which got interrupted. Swapping in {} column files", staged.files.len());
+    Ok(Some(staged))
+}
+
+///Removes the marker once the rewritten files are in place and the layout lists their segments
+pub fn finish(backend: &dyn StorageBackend) -> io::Result<()> {
+    backend.remove(MARKER_FILE)
+}
@@ -0,0 +1,66 @@
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use tracing::instrument;
+
+///Exclusive lock on a data directory, held until dropped.
+///Keeps a second process from appending to the same column files.
+#[derive(Debug)]
+pub struct DataDirLock {
+    _file: File,
+}
+
+impl DataDirLock {
+    pub fn file_path(root_path: &Path) -> PathBuf {
+        root_path.join("LOCK")
+    }
+
+    ///Fails with `WouldBlock` if another process holds the lock.
+    ///The lock is released by the OS when the process exits, so a crash never leaves a stale lock behind.
+    #[instrument]
+    pub fn acquire(root_path: &PathBuf) -> io::Result<Self> {
+        fs::create_dir_all(root_path)?;
+        let path = DataDirLock::file_path(root_path);
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path)?;
+
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                let holder = fs::read_to_string(&path).unwrap_or_default();
+                return Err(io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    format!(
+                        "{} is in use by another warenhaus process (pid {})",
+                        root_path.display(),
+                        holder.trim()
+                    ),
+                ));
+            }
+            Err(TryLockError::Error(err)) => return Err(err),
+        }
+
+        //Only for diagnostics, the lock itself is what keeps other processes out
+        file.set_len(0)?;
+        write!(file, "{}", std::process::id())?;
+        Ok(Self { _file: file })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DataDirLock;
+
+    #[test]
+    fn second_lock_gets_refused_until_first_is_released() {
+        let dir = tempfile::tempdir().unwrap();
+        let root_path = dir.path().join("db");
+
+        let lock = DataDirLock::acquire(&root_path).unwrap();
+        let err = DataDirLock::acquire(&root_path).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::WouldBlock);
+
+        drop(lock);
+        assert!(DataDirLock::acquire(&root_path).is_ok());
+    }
+}
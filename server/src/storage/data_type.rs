@@ -2,6 +2,8 @@ use std::fmt::Display;
 
 use crate::config::DataTypeConfig;
 
+use super::cell::Cell;
+
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
@@ -11,6 +13,14 @@ pub enum DataType {
     Float,
     String,
     Boolean,
+    ///Stored as seconds since the unix epoch, see `Cell::Timestamp`
+    Timestamp,
+    ///Stored as 16 bytes, see `Cell::Uuid`
+    Uuid,
+    ///List of values of the inner type, see `Cell::Array`
+    Array(Box<DataType>),
+    ///Any JSON value, see `Cell::Json`
+    Json,
 }
 
 impl DataType {
@@ -20,6 +30,30 @@ impl DataType {
             DataType::Float => other.is_f64(),
             DataType::String => other.is_string(),
             DataType::Boolean => other.is_boolean(),
+            DataType::Timestamp => other.is_i64() || other.as_str().is_some_and(|value| Cell::parse_timestamp(value).is_some()),
+            DataType::Uuid => other.as_str().is_some_and(|value| Cell::parse_uuid(value).is_some()),
+            DataType::Array(inner) => other.as_array().is_some_and(|values| values.iter().all(|value| inner.is_compatible(value))),
+            DataType::Json => !other.is_null(),
+        }
+    }
+
+    ///Type of a column which would store the value. `None` for null and arrays mixing types.
+    pub fn infer(value: &Value) -> Option<DataType> {
+        match value {
+            Value::Number(number) if number.is_i64() => Some(DataType::Int),
+            Value::Number(number) if number.is_f64() => Some(DataType::Float),
+            Value::String(_) => Some(DataType::String),
+            Value::Bool(_) => Some(DataType::Boolean),
+            Value::Object(_) => Some(DataType::Json),
+            //Typed after the first element, the others have to match it
+            Value::Array(values) => {
+                let inner = DataType::infer(values.first()?)?;
+                match values.iter().all(|value| inner.is_compatible(value)) {
+                    true => Some(DataType::Array(Box::new(inner))),
+                    false => None,
+                }
+            }
+            _ => None,
         }
     }
 }
@@ -31,6 +65,10 @@ impl Display for DataType {
             DataType::Float => write!(f, "Float"),
             DataType::String => write!(f, "String"),
             DataType::Boolean => write!(f, "bool"),
+            DataType::Timestamp => write!(f, "Timestamp"),
+            DataType::Uuid => write!(f, "Uuid"),
+            DataType::Array(inner) => write!(f, "Array<{}>", inner),
+            DataType::Json => write!(f, "Json"),
         }
     }
 }
@@ -42,6 +80,10 @@ impl From<DataTypeConfig> for DataType {
             DataTypeConfig::Float => DataType::Float, 
             DataTypeConfig::String => DataType::String,
             DataTypeConfig::Boolean => DataType::Boolean,
+            DataTypeConfig::Timestamp => DataType::Timestamp,
+            DataTypeConfig::Uuid => DataType::Uuid,
+            DataTypeConfig::Array(inner) => DataType::Array(Box::new((*inner).into())),
+            DataTypeConfig::Json => DataType::Json,
         }
     }
 }
@@ -0,0 +1,84 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use super::cell::Cell;
+use super::ByteString;
+
+///Key values of recently inserted rows, along with their ids. A row arriving again with the same key
+///within the window is a redelivery, which gets answered with the id of the stored row instead of being stored again.
+///Only kept in memory, so the window starts empty after a restart.
+#[derive(Debug)]
+pub struct DedupeWindow {
+    window: Duration,
+    ids: HashMap<(u8, ByteString), i64>,
+    ///Keys in the order they got recorded, to expire them
+    recorded: VecDeque<(Instant, (u8, ByteString))>,
+    ///Rows dropped since startup
+    dropped: u64,
+}
+
+impl DedupeWindow {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            ids: HashMap::new(),
+            recorded: VecDeque::new(),
+            dropped: 0,
+        }
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some((recorded_at, _)) = self.recorded.front() {
+            if now.duration_since(*recorded_at) < self.window {
+                break;
+            }
+            let (_, key) = self.recorded.pop_front().unwrap();
+            self.ids.remove(&key);
+        }
+    }
+
+    ///Id of the row which got inserted with the key within the window. Counts the row as dropped.
+    pub fn duplicate_of(&mut self, key: &Cell, now: Instant) -> Option<i64> {
+        self.expire(now);
+        let id = self.ids.get(&key.hash_key()?).copied();
+        if id.is_some() {
+            self.dropped += 1;
+        }
+        id
+    }
+
+    pub fn record(&mut self, key: &Cell, id: i64, now: Instant) {
+        let Some(key) = key.hash_key() else {
+            return;
+        };
+        if self.ids.insert(key.clone(), id).is_none() {
+            self.recorded.push_back((now, key));
+        }
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::DedupeWindow;
+    use crate::storage::cell::Cell;
+
+    #[test]
+    fn keys_expire_after_the_window() {
+        let start = Instant::now();
+        let mut window = DedupeWindow::new(Duration::from_secs(60));
+        window.record(&Cell::String("event-1".into()), 1, start);
+        window.record(&Cell::Null, 2, start);
+
+        assert_eq!(window.duplicate_of(&Cell::String("event-1".into()), start + Duration::from_secs(59)), Some(1));
+        assert_eq!(window.duplicate_of(&Cell::String("event-2".into()), start), None);
+        assert_eq!(window.duplicate_of(&Cell::Null, start), None);
+        assert_eq!(window.duplicate_of(&Cell::String("event-1".into()), start + Duration::from_secs(60)), None);
+        assert_eq!(window.dropped(), 1);
+    }
+}
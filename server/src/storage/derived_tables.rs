@@ -0,0 +1,65 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use tracing::instrument;
+
+use super::{Container, ContainerError};
+
+///Directory below the data directory holding the derived tables
+pub const DIR_NAME: &str = "tables";
+
+///Tables filled with query results. Each one lives in its own directory below `tables/`
+///and has the same columns as the table it was derived from.
+#[derive(Debug)]
+pub struct DerivedTables {
+    root_path: PathBuf,
+    tables: HashMap<String, Container>,
+}
+
+impl DerivedTables {
+    pub fn new(db_root_path: &PathBuf) -> Self {
+        Self {
+            root_path: Path::new(db_root_path).join(DIR_NAME),
+            tables: HashMap::new(),
+        }
+    }
+
+    ///Only letters, digits, `_` and `-`, so names can't escape the tables directory
+    pub fn is_valid_name(name: &str) -> bool {
+        !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    }
+
+    fn table_path(&self, name: &str) -> PathBuf {
+        self.root_path.join(name)
+    }
+
+    ///Opens an existing derived table
+    #[instrument(skip(self, source))]
+    pub fn get(&mut self, source: &Container, name: &str) -> Result<Option<&Container>, ContainerError> {
+        if !self.tables.contains_key(name) && !self.table_path(name).exists() {
+            return Ok(None);
+        }
+        self.get_or_create(source, name).map(|table| Some(&*table))
+    }
+
+    ///Opens the derived table, creating it with the columns of source if it doesn't exist yet
+    #[instrument(skip(self, source))]
+    pub fn get_or_create(&mut self, source: &Container, name: &str) -> Result<&mut Container, ContainerError> {
+        if !self.tables.contains_key(name) {
+            let table = source.derive(&self.table_path(name))?;
+            self.tables.insert(name.to_string(), table);
+        }
+        Ok(self.tables.get_mut(name).unwrap())
+    }
+
+    ///Writes the buffered records of all open tables to disk
+    pub fn flush(&mut self) -> Result<(), ContainerError> {
+        for table in self.tables.values_mut() {
+            table.flush()?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,101 @@
+use std::collections::{HashMap, HashSet};
+
+use serde::Serialize;
+
+use super::cell::Cell;
+use super::column_entries::ColumnEntries;
+
+///Gaps beyond this many only count towards `missing_ids`
+const MAX_REPORTED_GAPS: usize = 100;
+
+///Ids from `from` up to and including `to`
+#[derive(Debug, Serialize, PartialEq)]
+pub struct IdRange {
+    pub from: i64,
+    pub to: i64,
+}
+
+///Findings of a scan over the id column, as `GET /admin/diagnostics` reports them
+#[derive(Debug, Serialize)]
+pub struct IdDiagnostics {
+    ///Live rows scanned
+    pub rows: usize,
+    ///Last id the auto index handed out. Rows with a higher id point to a lost auto index update.
+    pub committed_seq: i64,
+    pub max_id: Option<i64>,
+    ///Ids missing between the smallest and the largest id. Deletes leave gaps as well.
+    pub gaps: Vec<IdRange>,
+    pub missing_ids: u64,
+    ///Ids held by more than one live row
+    pub duplicates: Vec<i64>,
+    ///Rows stored after a row with a higher id. Updated rows move to the end, so they show up here too.
+    pub out_of_order: usize,
+    ///Rows without an integer id
+    pub invalid: usize,
+}
+
+impl IdDiagnostics {
+    ///Scans the ids of the live rows in the order they are stored
+    pub fn scan(ids: &ColumnEntries, deleted: &HashSet<usize>, committed_seq: i64) -> Self {
+        let mut diagnostics = IdDiagnostics {
+            rows: 0,
+            committed_seq,
+            max_id: None,
+            gaps: vec![],
+            missing_ids: 0,
+            duplicates: vec![],
+            out_of_order: 0,
+            invalid: 0,
+        };
+        let mut counts: HashMap<i64, usize> = HashMap::new();
+        for (_, cell) in ids.iter().enumerate().filter(|(n, _)| !deleted.contains(n)) {
+            diagnostics.rows += 1;
+            let Cell::Int(id) = cell else {
+                diagnostics.invalid += 1;
+                continue;
+            };
+            if diagnostics.max_id.is_some_and(|max_id| *id < max_id) {
+                diagnostics.out_of_order += 1;
+            }
+            diagnostics.max_id = diagnostics.max_id.max(Some(*id));
+            *counts.entry(*id).or_default() += 1;
+        }
+
+        let mut sorted = counts.keys().copied().collect::<Vec<_>>();
+        sorted.sort_unstable();
+        for pair in sorted.windows(2) {
+            if pair[1] - pair[0] > 1 {
+                diagnostics.missing_ids += (pair[1] - pair[0] - 1) as u64;
+                if diagnostics.gaps.len() < MAX_REPORTED_GAPS {
+                    diagnostics.gaps.push(IdRange {
+                        from: pair[0] + 1,
+                        to: pair[1] - 1,
+                    });
+                }
+            }
+        }
+        diagnostics.duplicates = sorted.into_iter().filter(|id| counts[id] > 1).collect();
+        diagnostics
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::{IdDiagnostics, IdRange};
+    use crate::storage::{cell::Cell, column_entries::ColumnEntries};
+
+    #[test]
+    fn scan_finds_gaps_duplicates_and_reordered_ids() {
+        let ids = ColumnEntries::from([1, 2, 5, 3, 5, 9].map(Cell::Int).to_vec());
+        let diagnostics = IdDiagnostics::scan(&ids, &HashSet::from([5]), 5);
+
+        assert_eq!(diagnostics.rows, 5);
+        assert_eq!(diagnostics.max_id, Some(5));
+        assert_eq!(diagnostics.gaps, vec![IdRange { from: 4, to: 4 }]);
+        assert_eq!(diagnostics.missing_ids, 1);
+        assert_eq!(diagnostics.duplicates, vec![5]);
+        assert_eq!(diagnostics.out_of_order, 1);
+    }
+}
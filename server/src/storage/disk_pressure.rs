@@ -0,0 +1,86 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use nix::sys::statvfs::statvfs;
+use tracing::{info, warn};
+
+use crate::config::DiskPressureConfig;
+
+use super::ContainerError;
+
+///How long a measurement of the free space stays valid, so inserts don't cost a syscall each
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+///How long inserts get turned away after a slow sync. The next insert getting through measures again.
+const SLOW_SYNC_BACKOFF: Duration = Duration::from_secs(1);
+
+///Keeps inserts from running the volume full. An append cut off by a full disk leaves a torn record behind.
+#[derive(Debug)]
+pub struct DiskPressure {
+    config: DiskPressureConfig,
+    root_path: PathBuf,
+    ///Free bytes at the last check, and when it happened
+    free_bytes: Option<(u64, Instant)>,
+    ///Duration of the last sync that took too long, and when it finished
+    slow_sync: Option<(Duration, Instant)>,
+    ///True while inserts get rejected for lack of space, so only changes get logged
+    paused: bool,
+}
+
+impl DiskPressure {
+    pub fn new(config: DiskPressureConfig, root_path: &Path) -> Self {
+        Self {
+            config,
+            root_path: root_path.to_path_buf(),
+            free_bytes: None,
+            slow_sync: None,
+            paused: false,
+        }
+    }
+
+    ///Fails while the volume is nearly full, or shortly after a slow sync
+    pub fn check(&mut self) -> Result<(), ContainerError> {
+        let free_bytes = self.free_bytes()?;
+        let paused = free_bytes < self.config.min_free_bytes;
+        if paused != self.paused {
+            match paused {
+                true => warn!("Only {} bytes left on the volume, pausing inserts", free_bytes),
+                false => info!("{} bytes free on the volume again, resuming inserts", free_bytes),
+            }
+            self.paused = paused;
+        }
+        if paused {
+            return Err(ContainerError::DiskFull(free_bytes));
+        }
+
+        match self.slow_sync {
+            Some((latency, finished)) if finished.elapsed() < SLOW_SYNC_BACKOFF => {
+                Err(ContainerError::SlowDisk(latency.as_millis() as u64))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn free_bytes(&mut self) -> io::Result<u64> {
+        if let Some((free_bytes, checked)) = self.free_bytes {
+            if checked.elapsed() < CHECK_INTERVAL {
+                return Ok(free_bytes);
+            }
+        }
+        let stats = statvfs(&self.root_path).map_err(io::Error::from)?;
+        let free_bytes = stats.blocks_available() as u64 * stats.fragment_size() as u64;
+        self.free_bytes = Some((free_bytes, Instant::now()));
+        Ok(free_bytes)
+    }
+
+    ///Remembers syncs of the write-ahead log which took longer than allowed
+    pub fn record_sync(&mut self, latency: Duration) {
+        let Some(max_sync_latency_ms) = self.config.max_sync_latency_ms else {
+            return;
+        };
+        if latency > Duration::from_millis(max_sync_latency_ms) {
+            warn!("Syncing the write-ahead log took {}ms, turning away inserts for a moment", latency.as_millis());
+            self.slow_sync = Some((latency, Instant::now()));
+        }
+    }
+}
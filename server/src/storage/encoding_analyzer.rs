@@ -0,0 +1,82 @@
+use std::io;
+
+use crate::config::ColumnEncoding;
+
+use super::cell::Cell;
+use super::data_type::DataType;
+
+///Encodings a column set to Auto may end up with, in order of preference if they take up the same space
+const CANDIDATES: [ColumnEncoding; 4] = [
+    ColumnEncoding::Plain,
+    ColumnEncoding::RunLength,
+    ColumnEncoding::Delta,
+    ColumnEncoding::Dictionary,
+];
+///The sample consists of this many stretches of consecutive cells, spread evenly over the column
+const SAMPLE_WINDOWS: usize = 8;
+///Cells per stretch. Runs, deltas and repeats only show up among consecutive cells.
+const WINDOW_CELLS: usize = 512;
+
+///Picks the encoding which stores a sample of the cells in the fewest bytes
+pub fn choose_encoding(data_type: &DataType, cells: &[Cell]) -> io::Result<ColumnEncoding> {
+    let sample = sample(cells);
+    let mut best = (ColumnEncoding::Plain, u64::MAX);
+    for encoding in CANDIDATES.into_iter().filter(|encoding| encoding.applies_to(data_type)) {
+        let size = sample.iter().map(|window| encoded_size(encoding, window)).sum::<io::Result<u64>>()?;
+        if size < best.1 {
+            best = (encoding, size);
+        }
+    }
+    Ok(best.0)
+}
+
+///Stretches of consecutive cells, all of them if there are few enough
+fn sample(cells: &[Cell]) -> Vec<&[Cell]> {
+    if cells.len() <= SAMPLE_WINDOWS * WINDOW_CELLS {
+        return vec![cells];
+    }
+    let step = cells.len() / SAMPLE_WINDOWS;
+    (0..SAMPLE_WINDOWS).map(|n| &cells[n * step..n * step + WINDOW_CELLS]).collect()
+}
+
+///Bytes the records of the cells take up in a column file written with the encoding
+pub fn encoded_size(encoding: ColumnEncoding, cells: &[Cell]) -> io::Result<u64> {
+    let encoder = encoding.encoder();
+    let mut size = 0;
+    let mut last: Option<(u8, Vec<u8>)> = None;
+    for cell in cells {
+        let merged = match &last {
+            Some((tag, payload)) => encoder.merge(*tag, payload, cell)?,
+            None => None,
+        };
+        if merged.is_none() {
+            size += last.as_ref().map(|(_, payload)| 9 + payload.len() as u64).unwrap_or_default();
+        }
+        last = Some(match merged {
+            Some(record) => record,
+            None => encoder.encode(cell)?,
+        });
+    }
+    Ok(size + last.map(|(_, payload)| 9 + payload.len() as u64).unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{choose_encoding, encoded_size};
+    use crate::config::ColumnEncoding;
+    use crate::storage::cell::Cell;
+    use crate::storage::data_type::DataType;
+
+    #[test]
+    fn picks_the_smallest_encoding_for_the_cells() {
+        let ids = (1..=10_000).map(Cell::Int).collect::<Vec<_>>();
+        assert_eq!(choose_encoding(&DataType::Int, &ids).unwrap(), ColumnEncoding::Delta);
+        assert!(encoded_size(ColumnEncoding::Delta, &ids).unwrap() * 5 < encoded_size(ColumnEncoding::Plain, &ids).unwrap());
+
+        let flags = (0..1000).map(|n| Cell::Boolean(n < 900)).collect::<Vec<_>>();
+        assert_eq!(choose_encoding(&DataType::Boolean, &flags).unwrap(), ColumnEncoding::RunLength);
+        let statuses = (0..1000).map(|n| Cell::String(["ok", "not found", "failed"][n % 3].into())).collect::<Vec<_>>();
+        assert_eq!(choose_encoding(&DataType::String, &statuses).unwrap(), ColumnEncoding::Dictionary);
+        assert_eq!(choose_encoding(&DataType::Float, &vec![Cell::Float(1.0); 10]).unwrap(), ColumnEncoding::Plain);
+    }
+}
@@ -0,0 +1,544 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+use super::cast::CastType;
+use super::cell::Cell;
+use super::column_entries::ColumnEntries;
+use super::filter::FilterError;
+
+///Boolean expression over the columns of a row, e.g. `points / comments > 2 AND url LIKE '%github%'`.
+///Evaluated natively, left to right, skipping the right side of `AND`/`OR` once the left side decides the result.
+///Null, and comparisons between incompatible types, count as false. Casts of values which don't convert are null.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expression {
+    Literal(Cell),
+    Column(String),
+    Negate(Box<Expression>),
+    Not(Box<Expression>),
+    Binary(Box<Expression>, Operator, Box<Expression>),
+    And(Box<Expression>, Box<Expression>),
+    Or(Box<Expression>, Box<Expression>),
+    Like(Box<Expression>, String),
+    IsNull(Box<Expression>, bool),
+    Cast(Box<Expression>, CastType),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operator {
+    Add,
+    Subtract,
+    Multiply,
+    ///Always divides as floats, so `points / comments` doesn't round
+    Divide,
+    Remainder,
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+impl Expression {
+    pub fn parse(input: &str) -> Result<Self, FilterError> {
+        let tokens = tokenize(input)?;
+        let mut parser = Parser {
+            tokens,
+            position: 0,
+            depth: 0,
+            operators: 0,
+        };
+        let expression = parser.or()?;
+        match parser.peek() {
+            None => Ok(expression),
+            Some(token) => Err(invalid(format!("Unexpected {:?}", token))),
+        }
+    }
+
+    ///Names of all columns the expression reads
+    pub fn columns(&self) -> Vec<&str> {
+        let mut columns = vec![];
+        self.collect_columns(&mut columns);
+        columns.sort();
+        columns.dedup();
+        columns
+    }
+
+    fn collect_columns<'a>(&'a self, columns: &mut Vec<&'a str>) {
+        match self {
+            Expression::Literal(_) => {}
+            Expression::Column(name) => columns.push(name),
+            Expression::Negate(inner)
+            | Expression::Not(inner)
+            | Expression::Like(inner, _)
+            | Expression::IsNull(inner, _)
+            | Expression::Cast(inner, _) => inner.collect_columns(columns),
+            Expression::Binary(left, _, right) | Expression::And(left, right) | Expression::Or(left, right) => {
+                left.collect_columns(columns);
+                right.collect_columns(columns);
+            }
+        }
+    }
+
+    ///True if the row at position n matches. `columns` needs to hold every column returned by `columns()`.
+    pub fn matches(&self, columns: &HashMap<&str, &ColumnEntries>, n: usize) -> bool {
+        is_true(&self.evaluate(columns, n))
+    }
+
+    fn evaluate(&self, columns: &HashMap<&str, &ColumnEntries>, n: usize) -> Cell {
+        match self {
+            Expression::Literal(cell) => cell.clone(),
+            //Timestamps compare and compute like their unix seconds, UUIDs like their hyphenated string
+            Expression::Column(name) => match &columns[name.as_str()][n] {
+                Cell::Timestamp(value) => Cell::Int(*value),
+                Cell::Uuid(value) => Cell::String(Cell::format_uuid(*value)),
+                cell => cell.clone(),
+            },
+            Expression::Negate(inner) => match inner.evaluate(columns, n) {
+                Cell::Int(value) => Cell::Int(value.wrapping_neg()),
+                Cell::Float(value) => Cell::Float(-value),
+                _ => Cell::Null,
+            },
+            Expression::Not(inner) => Cell::Boolean(!inner.matches(columns, n)),
+            Expression::And(left, right) => Cell::Boolean(left.matches(columns, n) && right.matches(columns, n)),
+            Expression::Or(left, right) => Cell::Boolean(left.matches(columns, n) || right.matches(columns, n)),
+            Expression::Binary(left, operator, right) => {
+                binary(&left.evaluate(columns, n), *operator, &right.evaluate(columns, n))
+            }
+            Expression::Like(inner, pattern) => match inner.evaluate(columns, n) {
+                Cell::String(value) => {
+                    Cell::Boolean(like(&value.chars().collect::<Vec<_>>(), &pattern.chars().collect::<Vec<_>>()))
+                }
+                _ => Cell::Null,
+            },
+            Expression::IsNull(inner, negated) => Cell::Boolean((inner.evaluate(columns, n) == Cell::Null) != *negated),
+            Expression::Cast(inner, cast_type) => match cast_type.apply(&inner.evaluate(columns, n)) {
+                Some(Cell::Timestamp(value)) => Cell::Int(value),
+                Some(cell) => cell,
+                None => Cell::Null,
+            },
+        }
+    }
+}
+
+fn is_true(cell: &Cell) -> bool {
+    matches!(cell, Cell::Boolean(true))
+}
+
+fn as_float(cell: &Cell) -> Option<f64> {
+    match cell {
+        Cell::Int(value) => Some(*value as f64),
+        Cell::Float(value) => Some(*value),
+        _ => None,
+    }
+}
+
+fn compare(left: &Cell, right: &Cell) -> Option<Ordering> {
+    match (left, right) {
+        (Cell::Int(left), Cell::Int(right)) => Some(left.cmp(right)),
+        (Cell::String(left), Cell::String(right)) => Some(left.cmp(right)),
+        (Cell::Boolean(left), Cell::Boolean(right)) => Some(left.cmp(right)),
+        _ => as_float(left)?.partial_cmp(&as_float(right)?),
+    }
+}
+
+fn binary(left: &Cell, operator: Operator, right: &Cell) -> Cell {
+    let ordering = || compare(left, right);
+    match operator {
+        Operator::Equal => ordering().map(|ordering| Cell::Boolean(ordering.is_eq())).unwrap_or(Cell::Null),
+        Operator::NotEqual => ordering().map(|ordering| Cell::Boolean(ordering.is_ne())).unwrap_or(Cell::Null),
+        Operator::Less => ordering().map(|ordering| Cell::Boolean(ordering.is_lt())).unwrap_or(Cell::Null),
+        Operator::LessOrEqual => ordering().map(|ordering| Cell::Boolean(ordering.is_le())).unwrap_or(Cell::Null),
+        Operator::Greater => ordering().map(|ordering| Cell::Boolean(ordering.is_gt())).unwrap_or(Cell::Null),
+        Operator::GreaterOrEqual => ordering().map(|ordering| Cell::Boolean(ordering.is_ge())).unwrap_or(Cell::Null),
+        Operator::Divide => match (as_float(left), as_float(right)) {
+            (Some(dividend), Some(divisor)) if divisor != 0.0 => Cell::Float(dividend / divisor),
+            _ => Cell::Null,
+        },
+        Operator::Add | Operator::Subtract | Operator::Multiply | Operator::Remainder => match (left, right) {
+            (Cell::Int(left), Cell::Int(right)) => {
+                let result = match operator {
+                    Operator::Add => left.checked_add(*right),
+                    Operator::Subtract => left.checked_sub(*right),
+                    Operator::Multiply => left.checked_mul(*right),
+                    _ => left.checked_rem(*right),
+                };
+                result.map(Cell::Int).unwrap_or(Cell::Null)
+            }
+            _ => match (as_float(left), as_float(right)) {
+                (Some(left), Some(right)) => Cell::Float(match operator {
+                    Operator::Add => left + right,
+                    Operator::Subtract => left - right,
+                    Operator::Multiply => left * right,
+                    _ => left % right,
+                }),
+                _ => Cell::Null,
+            },
+        },
+    }
+}
+
+///SQL LIKE, `%` matches any number of characters and `_` exactly one
+fn like(value: &[char], pattern: &[char]) -> bool {
+    //Positions in the pattern which can be reached after each character of the value
+    let mut reachable = vec![false; pattern.len() + 1];
+    reachable[0] = true;
+    for p in 0..pattern.len() {
+        reachable[p + 1] = reachable[p] && pattern[p] == '%';
+    }
+    for c in value {
+        let mut next = vec![false; pattern.len() + 1];
+        for p in 0..pattern.len() {
+            next[p + 1] = match pattern[p] {
+                '%' => next[p] || reachable[p + 1] || reachable[p],
+                '_' => reachable[p],
+                expected => reachable[p] && expected == *c,
+            };
+        }
+        reachable = next;
+    }
+    reachable[pattern.len()]
+}
+
+fn invalid(message: String) -> FilterError {
+    FilterError::InvalidExpression(message)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Identifier(String),
+    Keyword(&'static str),
+    Literal(Cell),
+    Symbol(&'static str),
+}
+
+const KEYWORDS: [&str; 8] = ["AND", "OR", "NOT", "LIKE", "IS", "NULL", "TRUE", "FALSE"];
+///Longer symbols first, so `<=` doesn't get read as `<` and `=`
+const SYMBOLS: [&str; 14] = ["<=", ">=", "!=", "<>", "<", ">", "=", "+", "-", "*", "/", "%", "(", ")"];
+
+fn tokenize(input: &str) -> Result<Vec<Token>, FilterError> {
+    let chars = input.chars().collect::<Vec<_>>();
+    let mut tokens = vec![];
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c == '\'' {
+            //Quotes inside strings are doubled, like in SQL
+            let mut value = String::new();
+            i += 1;
+            loop {
+                match (chars.get(i), chars.get(i + 1)) {
+                    (Some('\''), Some('\'')) => {
+                        value.push('\'');
+                        i += 2;
+                    }
+                    (Some('\''), _) => break,
+                    (Some(c), _) => {
+                        value.push(*c);
+                        i += 1;
+                    }
+                    (None, _) => return Err(invalid("Unterminated string".to_string())),
+                }
+            }
+            i += 1;
+            tokens.push(Token::Literal(Cell::String(value)));
+        } else if c.is_ascii_digit() {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                i += 1;
+            }
+            let number = chars[start..i].iter().collect::<String>();
+            let cell = match number.contains('.') {
+                true => number.parse().map(Cell::Float).ok(),
+                false => number.parse().map(Cell::Int).ok(),
+            };
+            tokens.push(Token::Literal(cell.ok_or_else(|| invalid(format!("Invalid number {}", number)))?));
+        } else if c.is_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            let word = chars[start..i].iter().collect::<String>();
+            match KEYWORDS.iter().find(|keyword| keyword.eq_ignore_ascii_case(&word)) {
+                Some(keyword) => tokens.push(Token::Keyword(keyword)),
+                None => tokens.push(Token::Identifier(word)),
+            }
+        } else {
+            let rest = chars[i..].iter().take(2).collect::<String>();
+            let symbol = SYMBOLS
+                .iter()
+                .find(|symbol| rest.starts_with(**symbol))
+                .ok_or_else(|| invalid(format!("Unexpected character {}", c)))?;
+            tokens.push(Token::Symbol(symbol));
+            i += symbol.len();
+        }
+    }
+    Ok(tokens)
+}
+
+///Levels of parentheses, `NOT`, minus signs and casts an expression may nest. Parsing and evaluating recurse
+///once per level, so deeper expressions would overflow the stack.
+const MAX_DEPTH: usize = 64;
+///Operators an expression may hold. Evaluating recurses once per operator chained to the left, e.g. with
+///`a = 1 OR a = 2 OR ...`.
+const MAX_OPERATORS: usize = 1024;
+
+///Recursive descent, from the loosest binding operator to the tightest
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+    ///Levels of the expression being parsed, see `MAX_DEPTH`
+    depth: usize,
+    ///Operators parsed so far, see `MAX_OPERATORS`
+    operators: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn accept(&mut self, expected: &Token) -> bool {
+        if self.peek() == Some(expected) {
+            self.position += 1;
+            return true;
+        }
+        false
+    }
+
+    ///Enters the next level of the expression, leave it again with `ascend`
+    fn descend(&mut self) -> Result<(), FilterError> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return Err(invalid(format!("Expression is nested deeper than {} levels", MAX_DEPTH)));
+        }
+        Ok(())
+    }
+
+    fn ascend(&mut self) {
+        self.depth -= 1;
+    }
+
+    fn count_operator(&mut self) -> Result<(), FilterError> {
+        self.operators += 1;
+        if self.operators > MAX_OPERATORS {
+            return Err(invalid(format!("Expression holds more than {} operators", MAX_OPERATORS)));
+        }
+        Ok(())
+    }
+
+    fn or(&mut self) -> Result<Expression, FilterError> {
+        let mut left = self.and()?;
+        while self.accept(&Token::Keyword("OR")) {
+            self.count_operator()?;
+            left = Expression::Or(Box::new(left), Box::new(self.and()?));
+        }
+        Ok(left)
+    }
+
+    fn and(&mut self) -> Result<Expression, FilterError> {
+        let mut left = self.not()?;
+        while self.accept(&Token::Keyword("AND")) {
+            self.count_operator()?;
+            left = Expression::And(Box::new(left), Box::new(self.not()?));
+        }
+        Ok(left)
+    }
+
+    fn not(&mut self) -> Result<Expression, FilterError> {
+        if self.accept(&Token::Keyword("NOT")) {
+            self.descend()?;
+            let inner = self.not()?;
+            self.ascend();
+            return Ok(Expression::Not(Box::new(inner)));
+        }
+        self.comparison()
+    }
+
+    fn comparison(&mut self) -> Result<Expression, FilterError> {
+        let left = self.sum()?;
+        if self.accept(&Token::Keyword("LIKE")) {
+            return match self.next() {
+                Some(Token::Literal(Cell::String(pattern))) => Ok(Expression::Like(Box::new(left), pattern)),
+                _ => Err(invalid("LIKE expects a string pattern".to_string())),
+            };
+        }
+        if self.accept(&Token::Keyword("IS")) {
+            let negated = self.accept(&Token::Keyword("NOT"));
+            if !self.accept(&Token::Keyword("NULL")) {
+                return Err(invalid("IS expects NULL or NOT NULL".to_string()));
+            }
+            return Ok(Expression::IsNull(Box::new(left), negated));
+        }
+        let operator = match self.peek() {
+            Some(Token::Symbol("=")) => Operator::Equal,
+            Some(Token::Symbol("!=")) | Some(Token::Symbol("<>")) => Operator::NotEqual,
+            Some(Token::Symbol("<")) => Operator::Less,
+            Some(Token::Symbol("<=")) => Operator::LessOrEqual,
+            Some(Token::Symbol(">")) => Operator::Greater,
+            Some(Token::Symbol(">=")) => Operator::GreaterOrEqual,
+            _ => return Ok(left),
+        };
+        self.position += 1;
+        self.count_operator()?;
+        Ok(Expression::Binary(Box::new(left), operator, Box::new(self.sum()?)))
+    }
+
+    fn sum(&mut self) -> Result<Expression, FilterError> {
+        let mut left = self.product()?;
+        loop {
+            let operator = match self.peek() {
+                Some(Token::Symbol("+")) => Operator::Add,
+                Some(Token::Symbol("-")) => Operator::Subtract,
+                _ => return Ok(left),
+            };
+            self.position += 1;
+            self.count_operator()?;
+            left = Expression::Binary(Box::new(left), operator, Box::new(self.product()?));
+        }
+    }
+
+    fn product(&mut self) -> Result<Expression, FilterError> {
+        let mut left = self.unary()?;
+        loop {
+            let operator = match self.peek() {
+                Some(Token::Symbol("*")) => Operator::Multiply,
+                Some(Token::Symbol("/")) => Operator::Divide,
+                Some(Token::Symbol("%")) => Operator::Remainder,
+                _ => return Ok(left),
+            };
+            self.position += 1;
+            self.count_operator()?;
+            left = Expression::Binary(Box::new(left), operator, Box::new(self.unary()?));
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expression, FilterError> {
+        if self.accept(&Token::Symbol("-")) {
+            self.descend()?;
+            let inner = self.unary()?;
+            self.ascend();
+            return Ok(Expression::Negate(Box::new(inner)));
+        }
+        match self.next() {
+            Some(Token::Literal(cell)) => Ok(Expression::Literal(cell)),
+            //Not a keyword, so columns named cast keep working
+            Some(Token::Identifier(name)) if name.eq_ignore_ascii_case("cast") && self.accept(&Token::Symbol("(")) => {
+                self.descend()?;
+                let cast = self.cast()?;
+                self.ascend();
+                Ok(cast)
+            }
+            Some(Token::Identifier(name)) => Ok(Expression::Column(name)),
+            Some(Token::Keyword("TRUE")) => Ok(Expression::Literal(Cell::Boolean(true))),
+            Some(Token::Keyword("FALSE")) => Ok(Expression::Literal(Cell::Boolean(false))),
+            Some(Token::Keyword("NULL")) => Ok(Expression::Literal(Cell::Null)),
+            Some(Token::Symbol("(")) => {
+                self.descend()?;
+                let inner = self.or()?;
+                if !self.accept(&Token::Symbol(")")) {
+                    return Err(invalid("Missing )".to_string()));
+                }
+                self.ascend();
+                Ok(inner)
+            }
+            Some(token) => Err(invalid(format!("Unexpected {:?}", token))),
+            None => Err(invalid("Unexpected end of expression".to_string())),
+        }
+    }
+
+    ///`cast(<expression> as <type>)`, following the opening parenthesis
+    fn cast(&mut self) -> Result<Expression, FilterError> {
+        let inner = self.or()?;
+        if !matches!(self.next(), Some(Token::Identifier(word)) if word.eq_ignore_ascii_case("as")) {
+            return Err(invalid("cast expects AS followed by a type".to_string()));
+        }
+        let cast_type = match self.next() {
+            Some(Token::Identifier(name)) => CastType::parse(&name)?,
+            _ => return Err(invalid("cast expects AS followed by a type".to_string())),
+        };
+        if !self.accept(&Token::Symbol(")")) {
+            return Err(invalid("Missing )".to_string()));
+        }
+        Ok(Expression::Cast(Box::new(inner), cast_type))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::Expression;
+    use crate::storage::{cell::Cell, column_entries::ColumnEntries};
+
+    fn matching(expression: &str, columns: &HashMap<&str, &ColumnEntries>, rows: usize) -> Vec<usize> {
+        let expression = Expression::parse(expression).unwrap();
+        (0..rows).filter(|n| expression.matches(columns, *n)).collect()
+    }
+
+    #[test]
+    fn evaluates_expressions_across_columns() {
+        let urls = ColumnEntries::from(vec![
+            Cell::String("https://github.com/rust-lang".into()),
+            Cell::String("https://news.ycombinator.com".into()),
+            Cell::String("https://github.com/it's".into()),
+        ]);
+        let points = ColumnEntries::from(vec![Cell::Int(100), Cell::Int(30), Cell::Int(10)]);
+        let comments = ColumnEntries::from(vec![Cell::Int(20), Cell::Int(0), Cell::Null]);
+        let columns = HashMap::from([("url", &urls), ("points", &points), ("comments", &comments)]);
+
+        assert_eq!(matching("points / comments > 2", &columns, 3), vec![0]);
+        assert_eq!(matching("url LIKE '%github%' AND points > 50", &columns, 3), vec![0]);
+        assert_eq!(matching("url like '%it''s' or (points - 10) * 2 = 40", &columns, 3), vec![1, 2]);
+        assert_eq!(matching("comments IS NULL OR NOT points % 20 = 0", &columns, 3), vec![1, 2]);
+        assert_eq!(matching("url LIKE 'https://____.%'", &columns, 3), vec![1]);
+    }
+
+    #[test]
+    fn casts_values_which_landed_with_the_wrong_type() {
+        let points = ColumnEntries::from(vec![Cell::String("100".into()), Cell::String(" 7 ".into()), Cell::String("n/a".into())]);
+        let columns = HashMap::from([("points", &points), ("cast", &points)]);
+
+        assert_eq!(matching("CAST(points AS int) > 50", &columns, 3), vec![0]);
+        assert_eq!(matching("cast(points as float) / 2 = 3.5", &columns, 3), vec![1]);
+        assert_eq!(matching("cast(points as int) IS NULL", &columns, 3), vec![2]);
+        assert_eq!(matching("cast = '100'", &columns, 3), vec![0]);
+        for expression in ["cast(points int)", "cast(points as uuid)", "cast(points as int"] {
+            assert!(Expression::parse(expression).is_err(), "{}", expression);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_expressions() {
+        for expression in ["points >", "(points > 1", "url LIKE 5", "points ? 2", "'open"] {
+            assert!(Expression::parse(expression).is_err(), "{}", expression);
+        }
+        assert_eq!(Expression::parse("b > a + a").unwrap().columns(), vec!["a", "b"]);
+    }
+
+    #[test]
+    fn rejects_expressions_nested_too_deeply() {
+        let nested = |levels: usize| format!("{}points{} > 1", "(".repeat(levels), ")".repeat(levels));
+        assert!(Expression::parse(&nested(64)).is_ok());
+        for expression in [nested(100_000), format!("{}1 = 1", "-".repeat(100_000)), format!("{}TRUE", "NOT ".repeat(100_000))] {
+            let err = Expression::parse(&expression).unwrap_err();
+            assert!(err.to_string().contains("nested deeper than 64 levels"), "{}", err);
+        }
+
+        //Chained operators nest as well, to the left
+        let chained = |operators: usize| vec!["FALSE"; operators].join(" OR ") + " OR TRUE";
+        assert!(Expression::parse(&chained(1024)).unwrap().matches(&HashMap::new(), 0));
+        for expression in [chained(100_000), format!("points = {}", vec!["1"; 100_000].join(" + "))] {
+            let err = Expression::parse(&expression).unwrap_err();
+            assert!(err.to_string().contains("more than 1024 operators"), "{}", err);
+        }
+    }
+}
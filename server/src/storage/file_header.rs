@@ -0,0 +1,74 @@
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::io::{self, Read, Write};
+
+///Header at the start of column files and the write-ahead log.
+///Stored as `magic | format version | metadata length | metadata`.
+#[derive(Debug, PartialEq)]
+pub struct FileHeader {
+    pub version: u16,
+    pub metadata: Vec<u8>,
+}
+
+impl FileHeader {
+    pub fn new(version: u16, metadata: Vec<u8>) -> Self {
+        Self { version, metadata }
+    }
+
+    ///Number of bytes the header takes up in the file
+    pub fn len(&self) -> u64 {
+        10 + self.metadata.len() as u64
+    }
+
+    pub fn write<W: Write>(&self, w: &mut W, magic: &[u8; 4]) -> io::Result<()> {
+        w.write_all(magic)?;
+        w.write_u16::<LittleEndian>(self.version)?;
+        w.write_u32::<LittleEndian>(self.metadata.len() as u32)?;
+        w.write_all(&self.metadata)
+    }
+
+    pub fn to_bytes(&self, magic: &[u8; 4]) -> io::Result<Vec<u8>> {
+        let mut bytes = Vec::with_capacity(self.len() as usize);
+        self.write(&mut bytes, magic)?;
+        Ok(bytes)
+    }
+
+    ///Reads the header from the start of a file.
+    ///Returns `None` for files written before headers existed, which start right with their first record.
+    pub fn read<R: Read>(r: &mut R, magic: &[u8; 4]) -> io::Result<Option<Self>> {
+        let mut saved_magic = [0; 4];
+        match r.read_exact(&mut saved_magic) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err),
+        }
+        if &saved_magic != magic {
+            return Ok(None);
+        }
+        let version = r.read_u16::<LittleEndian>()?;
+        let metadata_len = r.read_u32::<LittleEndian>()?;
+        let mut metadata = vec![0; metadata_len as usize];
+        r.read_exact(&mut metadata)?;
+        Ok(Some(Self { version, metadata }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FileHeader;
+
+    const MAGIC: &[u8; 4] = b"TEST";
+
+    #[test]
+    fn header_round_trips() {
+        let header = FileHeader::new(3, b"{}".to_vec());
+        let bytes = header.to_bytes(MAGIC).unwrap();
+        assert_eq!(header.len(), bytes.len() as u64);
+        assert_eq!(Some(header), FileHeader::read(&mut bytes.as_slice(), MAGIC).unwrap());
+    }
+
+    #[test]
+    fn files_without_magic_have_no_header() {
+        assert_eq!(None, FileHeader::read(&mut [1u8, 2, 3, 4, 5].as_slice(), MAGIC).unwrap());
+        assert_eq!(None, FileHeader::read(&mut [1u8, 2].as_slice(), MAGIC).unwrap());
+    }
+}
@@ -0,0 +1,204 @@
+use std::collections::HashMap;
+
+use thiserror::Error;
+
+use super::cell::Cell;
+use super::data_type::DataType;
+use super::expression::Expression;
+
+///Prefix of query parameters which require a column to equal a value, e.g. `eq.url=https://google.com`
+const EQUALS_PREFIX: &str = "eq.";
+///Query parameter holding an expression over multiple columns, see `Expression`
+const WHERE_PARAM: &str = "where";
+///Query parameter naming the column `from` and `to` apply to, instead of `timestamp`
+const TIME_COLUMN_PARAM: &str = "time_column";
+///Query parameter reading the table as it was at an earlier point, see `AsOf`
+const AS_OF_PARAM: &str = "as_of";
+
+#[derive(Debug, Error)]
+pub enum FilterError {
+    #[error("Invalid value for {0}: {1}. Expected a unix timestamp or an RFC3339 date")]
+    InvalidBound(String, String),
+    #[error("Column {0} does not exist")]
+    UnknownColumn(String),
+    #[error("Invalid value for {0}: {1}. Expected {2}")]
+    InvalidValue(String, String, DataType),
+    #[error("Filtering by time requires a timestamp column")]
+    MissingTimestampColumn,
+    #[error("Column {0} can't be filtered by time. Expected unix timestamps, got {1}")]
+    InvalidTimeColumn(String, DataType),
+    #[error("Invalid value for {0}: {1}. Expected a number between 0 and 1")]
+    InvalidSample(String, String),
+    #[error("Invalid value for seed: {0}. Expected an unsigned integer")]
+    InvalidSeed(String),
+    #[error("Invalid where expression: {0}")]
+    InvalidExpression(String),
+    #[error("Invalid value for as_of: {0}. Expected a sequence number or an RFC3339 date")]
+    InvalidAsOf(String),
+    #[error("as_of reaches further back than the history of {0} seconds")]
+    OutsideHistory(u64),
+    #[error("as_of only applies to the main table")]
+    AsOfDerivedTable,
+    #[error("Unknown cast type {0}. Expected int, float, string, boolean or timestamp")]
+    UnknownCastType(String),
+    #[error("Invalid cursor: {0}")]
+    InvalidCursor(String),
+    #[error("The cursor expired, as the table got compacted since. Start over without a cursor")]
+    ExpiredCursor,
+    #[error("Column {0} has no index. Mark it as indexed in schema.json")]
+    NotIndexed(String),
+    #[error("The table has no primary key. Set primary_key in schema.json")]
+    NoPrimaryKey,
+    #[error("as_of doesn't apply to pages of rows, use /rows/stream instead")]
+    AsOfPage,
+}
+
+///Point in the table's history a query reads
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AsOf {
+    ///Right after the row with this id got committed
+    Seq(i64),
+    ///Right before the first row committed after this unix timestamp
+    Time(i64),
+}
+
+impl AsOf {
+    ///Sequence numbers are plain integers, points in time RFC3339 dates
+    pub fn parse(value: &str) -> Result<Self, FilterError> {
+        value
+            .parse()
+            .map(AsOf::Seq)
+            .ok()
+            .or_else(|| Cell::parse_timestamp(value).map(AsOf::Time))
+            .ok_or_else(|| FilterError::InvalidAsOf(value.to_string()))
+    }
+
+    ///True if the row got committed after this point
+    pub fn is_before(&self, values: &[(String, Cell)]) -> bool {
+        let (column, point) = match self {
+            AsOf::Seq(seq) => ("id", seq),
+            AsOf::Time(timestamp) => ("timestamp", timestamp),
+        };
+        values
+            .iter()
+            .find(|(column_name, _)| column_name == column)
+            .and_then(|(_, cell)| cell.as_int())
+            .is_some_and(|value| value > point)
+    }
+}
+
+///Cheap native predicates a query declares up front. Rows not matching them never reach the map function.
+#[derive(Debug, Default)]
+pub struct QueryFilter {
+    ///Only rows with a timestamp >= from
+    pub from: Option<i64>,
+    ///Only rows with a timestamp < to
+    pub to: Option<i64>,
+    ///Int column holding the timestamps `from` and `to` apply to, `timestamp` if not set
+    pub time_column: Option<String>,
+    ///Only rows where the column holds exactly this value
+    pub equals: Vec<(String, String)>,
+    ///Only this fraction of rows, picked at random
+    pub sample: Option<f64>,
+    ///Picks the sampled rows. The same seed always samples the same rows.
+    pub seed: u64,
+    ///Only rows for which the expression is true
+    pub expression: Option<Expression>,
+    ///Reads the table as it was at this point instead of its current state
+    pub as_of: Option<AsOf>,
+}
+
+impl QueryFilter {
+    pub fn from_query(params: &HashMap<String, String>) -> Result<Self, FilterError> {
+        let bound = |name: &str| -> Result<Option<i64>, FilterError> {
+            params
+                .get(name)
+                .map(|value| {
+                    value
+                        .parse()
+                        .ok()
+                        .or_else(|| Cell::parse_timestamp(value))
+                        .ok_or_else(|| FilterError::InvalidBound(name.to_string(), value.to_string()))
+                })
+                .transpose()
+        };
+
+        let mut equals = params
+            .iter()
+            .filter_map(|(key, value)| {
+                key.strip_prefix(EQUALS_PREFIX)
+                    .map(|column| (column.to_string(), value.to_string()))
+            })
+            .collect::<Vec<_>>();
+        equals.sort();
+
+        let sample = params
+            .get("sample")
+            .map(|value| match value.parse::<f64>() {
+                Ok(sample) if sample > 0.0 && sample <= 1.0 => Ok(sample),
+                _ => Err(FilterError::InvalidSample("sample".to_string(), value.to_string())),
+            })
+            .transpose()?;
+        let seed = params
+            .get("seed")
+            .map(|value| value.parse().map_err(|_| FilterError::InvalidSeed(value.to_string())))
+            .transpose()?
+            .unwrap_or_default();
+        let expression = params.get(WHERE_PARAM).map(|value| Expression::parse(value)).transpose()?;
+
+        Ok(Self {
+            from: bound("from")?,
+            to: bound("to")?,
+            time_column: params.get(TIME_COLUMN_PARAM).cloned(),
+            equals,
+            sample,
+            seed,
+            expression,
+            as_of: params.get(AS_OF_PARAM).map(|value| AsOf::parse(value)).transpose()?,
+        })
+    }
+
+    pub fn has_time_range(&self) -> bool {
+        self.from.is_some() || self.to.is_some()
+    }
+
+    pub fn in_time_range(&self, timestamp: i64) -> bool {
+        self.from.is_none_or(|from| timestamp >= from) && self.to.is_none_or(|to| timestamp < to)
+    }
+
+    ///Decides by the row's id, so a row stays sampled or not no matter which other rows exist
+    pub fn in_sample(&self, id: i64) -> bool {
+        let Some(sample) = self.sample else {
+            return true;
+        };
+        //splitmix64, spreads consecutive ids evenly across the whole range
+        let mut hash = self.seed ^ (id as u64);
+        hash = hash.wrapping_add(0x9e3779b97f4a7c15);
+        hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d049bb133111eb);
+        hash ^= hash >> 31;
+        (hash as f64 / u64::MAX as f64) < sample
+    }
+}
+
+///Parses a query parameter into a cell of the column's type
+pub fn parse_cell(column: &str, value: &str, data_type: &DataType) -> Result<Cell, FilterError> {
+    let invalid = || FilterError::InvalidValue(column.to_string(), value.to_string(), data_type.clone());
+    match data_type {
+        DataType::Int => value.parse().map(Cell::Int).map_err(|_| invalid()),
+        DataType::Float => value.parse().map(Cell::Float).map_err(|_| invalid()),
+        DataType::Boolean => value.parse().map(Cell::Boolean).map_err(|_| invalid()),
+        DataType::String => Ok(Cell::String(value.to_string())),
+        DataType::Timestamp => value
+            .parse()
+            .ok()
+            .or_else(|| Cell::parse_timestamp(value))
+            .map(Cell::Timestamp)
+            .ok_or_else(invalid),
+        DataType::Uuid => Cell::parse_uuid(value).map(Cell::Uuid).ok_or_else(invalid),
+        DataType::Array(_) | DataType::Json => serde_json::from_str(value)
+            .ok()
+            .and_then(|value| Cell::from_typed_json(&value, data_type))
+            .ok_or_else(invalid),
+    }
+}
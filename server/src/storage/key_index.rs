@@ -0,0 +1,84 @@
+use std::collections::{HashMap, HashSet};
+
+use super::cell::Cell;
+use super::column_entries::ColumnEntries;
+use super::ByteString;
+
+///Positions of the live rows by the value of the unique key column, see `SchemaConfig::unique_key`, or of a column
+///marked `unique`.
+///Only kept in memory and rebuilt from the column whenever positions shift.
+#[derive(Debug)]
+pub struct KeyIndex {
+    column: String,
+    positions: HashMap<(u8, ByteString), usize>,
+}
+
+impl KeyIndex {
+    pub fn new(column: &str) -> Self {
+        Self {
+            column: column.to_string(),
+            positions: HashMap::new(),
+        }
+    }
+
+    pub fn column(&self) -> &str {
+        &self.column
+    }
+
+    pub fn rename(&mut self, to: &str) {
+        self.column = to.to_string();
+    }
+
+    pub fn rebuild(&mut self, cells: &ColumnEntries, deleted: &HashSet<usize>) {
+        self.positions.clear();
+        for (position, cell) in cells.iter().enumerate().filter(|(n, _)| !deleted.contains(n)) {
+            self.insert(cell, position);
+        }
+    }
+
+    pub fn get(&self, key: &Cell) -> Option<usize> {
+        self.positions.get(&key.hash_key()?).copied()
+    }
+
+    pub fn insert(&mut self, key: &Cell, position: usize) {
+        if let Some(key) = key.hash_key() {
+            self.positions.insert(key, position);
+        }
+    }
+
+    ///Forgets the keys of the rows from the position on, e.g. the ones of a rolled back transaction
+    pub fn forget_from(&mut self, position: usize) {
+        self.positions.retain(|_, key_position| *key_position < position);
+    }
+
+    ///Forgets the key, unless it already points to a newer row
+    pub fn remove(&mut self, key: &Cell, position: usize) {
+        if let Some(key) = key.hash_key() {
+            if self.positions.get(&key) == Some(&position) {
+                self.positions.remove(&key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::KeyIndex;
+    use crate::storage::{cell::Cell, column_entries::ColumnEntries};
+
+    #[test]
+    fn rebuild_skips_deleted_rows_and_nulls() {
+        let mut index = KeyIndex::new("sku");
+        let cells = ColumnEntries::from(vec![Cell::String("a".into()), Cell::Null, Cell::String("b".into())]);
+        index.rebuild(&cells, &HashSet::from([2]));
+        assert_eq!(index.get(&Cell::String("a".into())), Some(0));
+        assert_eq!(index.get(&Cell::String("b".into())), None);
+        assert_eq!(index.get(&Cell::Null), None);
+
+        index.insert(&Cell::String("a".into()), 3);
+        index.remove(&Cell::String("a".into()), 0);
+        assert_eq!(index.get(&Cell::String("a".into())), Some(3));
+    }
+}
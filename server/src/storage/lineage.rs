@@ -0,0 +1,42 @@
+use super::cell::Cell;
+use super::data_type::DataType;
+
+///Hidden system columns holding where a row came from. Query results leave them out unless asked for.
+pub const LINEAGE_COLUMNS: [(&str, DataType); 5] = [
+    ("_source", DataType::String),
+    ("_kafka_topic", DataType::String),
+    ("_kafka_partition", DataType::Int),
+    ("_kafka_offset", DataType::Int),
+    ("_api_key", DataType::String),
+];
+
+///Ingest metadata of a row. Missing values are stored as null.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Lineage {
+    ///Name of the system which sent the row, e.g. `kafka`
+    pub source: Option<String>,
+    pub kafka_topic: Option<String>,
+    pub kafka_partition: Option<i64>,
+    pub kafka_offset: Option<i64>,
+    ///Fingerprint of the producer's API key, see `auth::key_fingerprint`
+    pub api_key: Option<String>,
+}
+
+impl Lineage {
+    pub fn is_lineage_column(column_name: &str) -> bool {
+        LINEAGE_COLUMNS.iter().any(|(name, _)| *name == column_name)
+    }
+
+    pub fn cell(&self, column_name: &str) -> Cell {
+        let string_cell = |value: &Option<String>| value.clone().map(Cell::String).unwrap_or(Cell::Null);
+        let int_cell = |value: &Option<i64>| value.map(Cell::Int).unwrap_or(Cell::Null);
+        match column_name {
+            "_source" => string_cell(&self.source),
+            "_kafka_topic" => string_cell(&self.kafka_topic),
+            "_kafka_partition" => int_cell(&self.kafka_partition),
+            "_kafka_offset" => int_cell(&self.kafka_offset),
+            "_api_key" => string_cell(&self.api_key),
+            _ => Cell::Null,
+        }
+    }
+}
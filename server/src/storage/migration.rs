@@ -0,0 +1,176 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use tracing::{info, instrument};
+
+use super::auto_index::AutoIndex;
+use super::backend::{LocalBackend, StorageBackend};
+use super::checked_file::CheckedFile;
+use super::column::{Column, COLUMN_FORMAT_VERSION, DEFAULT_WRITE_BUFFER_SIZE};
+use super::wal::{Wal, WAL_FORMAT_VERSION};
+use super::{ColumnLayout, ContainerError, LayoutFile};
+
+///Outcome of upgrading a data directory to the current on-disk format
+#[derive(Debug, Default)]
+pub struct MigrationReport {
+    ///Rewritten files, relative to the data directory
+    pub migrated: Vec<PathBuf>,
+    ///Number of files which already were in the current format
+    pub up_to_date: usize,
+}
+
+impl MigrationReport {
+    fn record(&mut self, root_path: &Path, path: &Path, migrated: bool) {
+        if migrated {
+            let relative_path = path.strip_prefix(root_path).unwrap_or(path);
+            info!("Migrated {}", relative_path.display());
+            self.migrated.push(relative_path.to_path_buf());
+        } else {
+            self.up_to_date += 1;
+        }
+    }
+}
+
+///Copies the data directory to backup_path, then upgrades the files of the table and of all derived tables
+///to the current on-disk format. The server must not be running on the same data directory meanwhile.
+#[instrument]
+pub fn migrate_storage(root_path: &PathBuf, backup_path: &Path) -> Result<MigrationReport, ContainerError> {
+    if backup_path.exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("Backup {} already exists", backup_path.display()),
+        )
+        .into());
+    }
+    if backup_path.starts_with(root_path) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "The backup can't be stored inside the data directory",
+        )
+        .into());
+    }
+    info!("Backing up {} to {}", root_path.display(), backup_path.display());
+    copy_dir(root_path, backup_path)?;
+
+    let mut table_paths = vec![root_path.clone()];
+    let tables_path = root_path.join("tables");
+    if tables_path.exists() {
+        for entry in fs::read_dir(&tables_path)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                table_paths.push(entry.path());
+            }
+        }
+    }
+
+    let mut report = MigrationReport::default();
+    for table_path in table_paths {
+        migrate_table(root_path, &table_path, &mut report)?;
+    }
+    Ok(report)
+}
+
+fn migrate_table(root_path: &Path, table_path: &PathBuf, report: &mut MigrationReport) -> Result<(), ContainerError> {
+    let layout_path = ColumnLayout::file_path(table_path);
+    let legacy_layout = CheckedFile::is_legacy(&layout_path);
+    let (mut layout_file, bytes) = CheckedFile::load(layout_path.clone())?;
+    let layout = serde_json::from_slice::<LayoutFile>(&bytes).map_err(io::Error::from)?.columns();
+    if legacy_layout {
+        layout_file.write(&bytes)?;
+    }
+    report.record(root_path, &layout_path, legacy_layout);
+
+    //Column files in other backends get migrated when their table gets opened
+    let backend: Arc<dyn StorageBackend> = Arc::new(LocalBackend::new(table_path));
+    let column_count = layout.len();
+    for (n, (column_name, data_type)) in layout.into_iter().enumerate() {
+        let column_path = Column::file_path(table_path, &column_name);
+        if !column_path.exists() {
+            continue;
+        }
+        let outdated = Column::format_version(table_path, &column_name)? != Some(COLUMN_FORMAT_VERSION);
+        if outdated {
+            //Loading a column upgrades its file in place
+            let mut column = Column::new(backend.clone(), column_name.clone(), data_type, DEFAULT_WRITE_BUFFER_SIZE)?;
+            column.load()?;
+            info!(
+                "Column {} ({}/{}): {} rows",
+                column_name,
+                n + 1,
+                column_count,
+                column.entries().len()
+            );
+        }
+        report.record(root_path, &column_path, outdated);
+    }
+
+    let wal_path = Wal::file_path(table_path);
+    if wal_path.exists() {
+        let outdated = Wal::format_version(table_path)? != Some(WAL_FORMAT_VERSION);
+        if outdated {
+            Wal::open(table_path)?;
+        }
+        report.record(root_path, &wal_path, outdated);
+    }
+
+    let auto_index_path = AutoIndex::file_path(table_path);
+    let legacy_auto_index = CheckedFile::is_legacy(&auto_index_path);
+    if legacy_auto_index {
+        AutoIndex::load_or_new(table_path)?.commit()?;
+    }
+    report.record(root_path, &auto_index_path, legacy_auto_index);
+    Ok(())
+}
+
+fn copy_dir(from: &Path, to: &Path) -> io::Result<()> {
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &target)?;
+        } else {
+            fs::copy(entry.path(), target)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    use super::migrate_storage;
+    use crate::storage::backend::LocalBackend;
+    use crate::storage::column::{Column, COLUMN_FORMAT_VERSION};
+    use crate::storage::file_header::FileHeader;
+
+    #[test]
+    fn upgrades_legacy_files_and_keeps_backup() {
+        let dir = tempfile::tempdir().unwrap();
+        let root_path = dir.path().join("db");
+        fs::create_dir(&root_path).unwrap();
+        let column = Column::new(Arc::new(LocalBackend::new(&root_path)), "url".into(), crate::storage::data_type::DataType::String, 16).unwrap();
+        drop(column);
+        fs::write(root_path.join("column_layout.json"), br#"[["url","String"]]"#).unwrap();
+        //A header without any records, stripped down to what files looked like before headers existed
+        let column_path = root_path.join("column_url");
+        let header = FileHeader::read(&mut fs::File::open(&column_path).unwrap(), b"WHCL").unwrap().unwrap();
+        fs::write(&column_path, &fs::read(&column_path).unwrap()[header.len() as usize..]).unwrap();
+
+        let backup_path = dir.path().join("backup");
+        let report = migrate_storage(&root_path, &backup_path).unwrap();
+
+        assert_eq!(
+            report.migrated,
+            vec![PathBuf::from("column_layout.json"), PathBuf::from("column_url")]
+        );
+        assert_eq!(Column::format_version(&root_path, "url").unwrap(), Some(COLUMN_FORMAT_VERSION));
+        assert!(fs::read(backup_path.join("column_url")).unwrap().is_empty());
+        assert!(backup_path.join("column_layout.json").exists());
+    }
+}
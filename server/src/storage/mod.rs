@@ -1,14 +1,52 @@
 mod auto_index;
 pub mod auto_index_error;
+pub mod backend;
+pub mod block_encoder;
+pub mod cast;
+mod checked_file;
+mod file_header;
+pub mod migration;
+pub mod page_cursor;
 pub mod column;
+pub mod column_index;
+pub mod column_entries;
+pub mod column_stats;
+pub mod compaction;
 pub mod cell;
 pub mod data_type;
 pub mod column_frame;
+pub mod data_dir_lock;
+pub mod dedupe;
+pub mod encoding_analyzer;
+pub mod diagnostics;
+pub mod derived_tables;
+pub mod disk_pressure;
+pub mod expression;
+pub mod filter;
+pub mod key_index;
+pub mod lineage;
+pub mod replica;
+pub mod retention;
+pub mod parquet;
+pub mod row_stream;
+pub mod s3_backend;
+mod schema_fingerprint;
+pub mod segments;
+pub mod server_columns;
+pub mod snapshot_restore;
+pub mod upsert_conflicts;
+pub mod wal;
+pub mod wal_error;
+pub mod wal_shipping;
+pub mod warmup;
 
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs;
 use std::path::{Path, PathBuf};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use crc::{CRC_32_CKSUM, Crc};
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use tokio::sync::mpsc::Sender;
 use tracing::log::warn;
@@ -16,24 +54,60 @@ use tracing::{debug, instrument};
 use tracing::{error, info};
 
 use crate::command::Command;
-use crate::config::SchemaConfig;
+use crate::config::{
+    ColumnConfig, ColumnEncoding, DataTypeConfig, Docs, FlushPolicy, PartitionPeriod, SchemaConfig, ServerComputed,
+    StorageBackendConfig,
+};
+use crate::metrics::StorageMetrics;
 use crate::storage::cell::Cell;
+use crate::warnings::{Warning, WarningKind};
 use crate::web::IndexParams;
 
 use self::auto_index::AutoIndex;
 use self::auto_index_error::AutoIndexError;
+use self::backend::StorageBackend;
+use self::checked_file::CheckedFile;
+use self::compaction::{CompactionReport, StagedCompaction};
+use self::cast::CastType;
+use self::column_entries::ColumnEntries;
+use self::page_cursor::PageCursor;
+use self::column_stats::{ColumnHistogram, ColumnStats, PersistedStats, StoredStats, TableStats};
 use self::column_frame::ColumnFrame;
-use self::{column::Column, data_type::DataType};
+use self::dedupe::DedupeWindow;
+use self::upsert_conflicts::UpsertConflicts;
+use self::disk_pressure::DiskPressure;
+use self::diagnostics::IdDiagnostics;
+use self::derived_tables::DerivedTables;
+use self::filter::{parse_cell, AsOf, FilterError, QueryFilter};
+use self::key_index::KeyIndex;
+use self::column_index::ColumnIndex;
+use self::lineage::{Lineage, LINEAGE_COLUMNS};
+use self::retention::{ColumnRetention, RetentionReport};
+use self::schema_fingerprint::SchemaFingerprint;
+use self::segments::{ColumnSegment, SegmentManifest, ZoneMap};
+use self::wal::{Wal, WalRecord};
+use self::wal_error::WalError;
+use self::warmup::{LoadedColumns, StartupTracker, WarmupPlan};
+use self::{column::{Column, ColumnSavepoint, StorageOptions, DEFAULT_WRITE_BUFFER_SIZE}, data_type::DataType};
 
 pub type ByteString = Vec<u8>;
 pub const CRC32: Crc<u32> = Crc::<u32>::new(&CRC_32_CKSUM);
+///Rows `matching_rows_from` filters at a time, at least as many as the page holds
+const PAGE_SCAN_ROWS: usize = 4096;
 
 #[derive(Debug, Error)]
 pub enum ContainerError {
     #[error("Fields are not present in index")]
     InvalidFields(Vec<String>),
-    #[error("Invalid Data Type. Expected {1}, Got {0}")]
-    InvalidDataType(serde_json::Value, DataType),
+    #[error("Fields were provided more than once: {0:?}")]
+    DuplicateFields(Vec<String>),
+    #[error("Invalid Data Type for {0}. Expected {2}, Got {1}")]
+    InvalidDataType(String, serde_json::Value, DataType),
+    #[error("Fields don't match the schema. Reserved: {reserved:?}, missing: {missing:?}")]
+    SchemaMismatch {
+        reserved: Vec<String>,
+        missing: Vec<String>,
+    },
     #[error("Number of fields ({0}) does not match number of provided values ({1}).")]
     FieldCountMismatch(usize, usize),
     #[error("IO Error")]
@@ -48,71 +122,588 @@ pub enum ContainerError {
         #[from]
         source: AutoIndexError,
     },
+    #[error("Rejected by before insert hook: {0}")]
+    RejectedByHook(String),
+    #[error("Before insert hook failed: {0}")]
+    HookFailed(String),
+    #[error("Transaction aborted at row {row}: {source}")]
+    TransactionAborted {
+        row: usize,
+        source: Box<ContainerError>,
+    },
+    #[error("No retention policy configured")]
+    RetentionNotConfigured,
+    #[error("Columns are still being loaded")]
+    WarmingUp,
+    #[error("No row with id {0}")]
+    UnknownRow(i64),
+    #[error("Unknown column {0}")]
+    UnknownColumn(String),
+    #[error("The value of shard key {0} can't change, the row would belong to another node")]
+    ShardKeyChanged(String),
+    #[error("Another row already holds this value of unique key {0}")]
+    DuplicateKey(String),
+    #[error("Another row already holds the value {value} in unique column {column}")]
+    UniqueViolation { column: String, value: String },
+    #[error("Repairs require the server to run with MAINTENANCE_MODE=true")]
+    NotInMaintenanceMode,
+    #[error("Can't rename column {column}: {reason}")]
+    InvalidRename {
+        column: String,
+        reason: String,
+    },
+    #[error("Write-ahead log Error: {source}")]
+    WalError {
+        #[from]
+        source: WalError,
+    },
+    #[error("Only {0} bytes left on the volume, inserts are paused")]
+    DiskFull(u64),
+    #[error("Syncing to disk took {0}ms, inserts are slowed down")]
+    SlowDisk(u64),
+    #[error("Can't compute column {column}: {reason}")]
+    InvalidServerColumn {
+        column: String,
+        reason: String,
+    },
+    #[error("Primary key {0} has to be a column of the schema which isn't nullable")]
+    InvalidPrimaryKey(String),
+    #[error("Column {0} holds no numbers. Only Int and Float columns have a histogram")]
+    NoHistogram(String),
+    #[error("Can't drop column {column}: {reason}")]
+    InvalidDrop {
+        column: String,
+        reason: String,
+    },
+    #[error("The stored columns don't fit schema.json: {}", .0.join("; "))]
+    SchemaConflict(Vec<String>),
+}
+
+///Describes why a single field of an insert got rejected
+#[derive(Debug, Serialize)]
+#[serde(tag = "reason")]
+pub enum FieldError {
+    UnknownField {
+        field: String,
+    },
+    DuplicateField {
+        field: String,
+    },
+    ///The database fills this column itself
+    ReservedField {
+        field: String,
+    },
+    MissingField {
+        field: String,
+    },
+    InvalidDataType {
+        field: String,
+        expected: DataType,
+        got: serde_json::Value,
+    },
+}
+
+impl ContainerError {
+    ///Machine readable name of the error
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ContainerError::InvalidFields(_) => "InvalidFields",
+            ContainerError::DuplicateFields(_) => "DuplicateFields",
+            ContainerError::SchemaMismatch { .. } => "SchemaMismatch",
+            ContainerError::InvalidDataType(..) => "InvalidDataType",
+            ContainerError::FieldCountMismatch(..) => "FieldCountMismatch",
+            ContainerError::IoError { .. } => "IoError",
+            ContainerError::MissingTimestampColumn => "MissingTimestampColumn",
+            ContainerError::IndexError { .. } => "IndexError",
+            ContainerError::RejectedByHook(_) => "RejectedByHook",
+            ContainerError::HookFailed(_) => "HookFailed",
+            ContainerError::TransactionAborted { .. } => "TransactionAborted",
+            ContainerError::RetentionNotConfigured => "RetentionNotConfigured",
+            ContainerError::WarmingUp => "WarmingUp",
+            ContainerError::UnknownRow(_) => "UnknownRow",
+            ContainerError::UnknownColumn(_) => "UnknownColumn",
+            ContainerError::ShardKeyChanged(_) => "ShardKeyChanged",
+            ContainerError::DuplicateKey(_) => "DuplicateKey",
+            ContainerError::UniqueViolation { .. } => "UniqueViolation",
+            ContainerError::NotInMaintenanceMode => "NotInMaintenanceMode",
+            ContainerError::InvalidRename { .. } => "InvalidRename",
+            ContainerError::WalError { .. } => "WalError",
+            ContainerError::DiskFull(_) => "DiskFull",
+            ContainerError::SlowDisk(_) => "SlowDisk",
+            ContainerError::InvalidServerColumn { .. } => "InvalidServerColumn",
+            ContainerError::InvalidPrimaryKey(_) => "InvalidPrimaryKey",
+            ContainerError::NoHistogram(_) => "NoHistogram",
+            ContainerError::InvalidDrop { .. } => "InvalidDrop",
+            ContainerError::SchemaConflict(_) => "SchemaConflict",
+        }
+    }
+
+    ///True if the insert itself was faulty, false if storing it failed
+    pub fn is_client_error(&self) -> bool {
+        if let ContainerError::TransactionAborted { source, .. } = self {
+            return source.is_client_error();
+        }
+        matches!(
+            self,
+            ContainerError::InvalidFields(_)
+                | ContainerError::DuplicateFields(_)
+                | ContainerError::SchemaMismatch { .. }
+                | ContainerError::InvalidDataType(..)
+                | ContainerError::FieldCountMismatch(..)
+                | ContainerError::RejectedByHook(_)
+                | ContainerError::ShardKeyChanged(_)
+                | ContainerError::DuplicateKey(_)
+                | ContainerError::UniqueViolation { .. }
+        )
+    }
+
+    ///True if the row clashes with a stored row, which is answered with `409`
+    pub fn is_conflict(&self) -> bool {
+        if let ContainerError::TransactionAborted { source, .. } = self {
+            return source.is_conflict();
+        }
+        matches!(self, ContainerError::UniqueViolation { .. })
+    }
+
+    pub fn field_errors(&self) -> Vec<FieldError> {
+        match self {
+            ContainerError::InvalidFields(fields) => fields
+                .iter()
+                .map(|field| FieldError::UnknownField {
+                    field: field.to_string(),
+                })
+                .collect(),
+            ContainerError::DuplicateFields(fields) => fields
+                .iter()
+                .map(|field| FieldError::DuplicateField {
+                    field: field.to_string(),
+                })
+                .collect(),
+            ContainerError::SchemaMismatch { reserved, missing } => reserved
+                .iter()
+                .map(|field| FieldError::ReservedField {
+                    field: field.to_string(),
+                })
+                .chain(missing.iter().map(|field| FieldError::MissingField {
+                    field: field.to_string(),
+                }))
+                .collect(),
+            ContainerError::InvalidDataType(field, got, expected) => vec![FieldError::InvalidDataType {
+                field: field.to_string(),
+                expected: expected.clone(),
+                got: got.clone(),
+            }],
+            ContainerError::TransactionAborted { source, .. } => source.field_errors(),
+            _ => vec![],
+        }
+    }
+
+    ///Position of the row which aborted a transaction
+    pub fn failed_row(&self) -> Option<usize> {
+        match self {
+            ContainerError::TransactionAborted { row, .. } => Some(*row),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug)]
 struct ColumnLayout {
     db_root_path: PathBuf,
+    backend: Arc<dyn StorageBackend>,
+    write_buffer_size: usize,
     columns: Vec<Column>,
     column_names_ordered: Vec<(String, DataType)>,
+    ///Descriptions of the table and its columns, see `document`
+    docs: Docs,
+    column_docs: BTreeMap<String, Docs>,
+    ///Encodings and compression the schema configures, see `encode`
+    storage_options: HashMap<String, StorageOptions>,
+    layout_file: CheckedFile,
+    key_index: Option<KeyIndex>,
+    ///Values of the columns marked `unique` in the schema, see `find_unique`
+    unique_indexes: Vec<KeyIndex>,
+    ///Indexes of the columns marked `indexed` in the schema
+    indexes: Vec<ColumnIndex>,
+    ///Segment files every column consists of
+    segments: SegmentManifest,
+    ///Rows after which the active segment gets closed, see `roll_over_if_full`
+    segment_rows: Option<usize>,
+    ///Period whose rows each segment holds, see `roll_over_if_new_period`
+    partition_by: Option<PartitionPeriod>,
+    ///Period of the latest timestamp in the active segment. Looked up again from the rows if not known.
+    active_period: Option<i64>,
+    ///Columns map their files when loading, see `SchemaConfig::mmap_reads`
+    mmap_reads: bool,
+    ///Only the id column gets read when loading, see `SchemaConfig::lazy_columns`
+    lazy_columns: bool,
+    ///Statistics of every column, updated on commit and written to `column_stats.json` on flush
+    stats: BTreeMap<String, ColumnStats>,
+    stats_file: CheckedFile,
+    ///Set when the statistics changed since they got written
+    stats_changed: bool,
+}
+
+///Contents of `column_layout.json`. Tables without docs keep the plain list of columns older versions wrote.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+pub(crate) enum LayoutFile {
+    Columns(Vec<(String, DataType)>),
+    Documented {
+        columns: Vec<(String, DataType)>,
+        docs: Docs,
+        column_docs: BTreeMap<String, Docs>,
+        #[serde(default)]
+        segments: SegmentManifest,
+    },
+}
+
+impl LayoutFile {
+    pub(crate) fn columns(self) -> Vec<(String, DataType)> {
+        match self {
+            LayoutFile::Columns(columns) | LayoutFile::Documented { columns, .. } => columns,
+        }
+    }
 }
 
 impl ColumnLayout {
-    fn new(db_root_path: &PathBuf) -> Self {
+    fn new(db_root_path: &PathBuf, write_buffer_size: usize, backend: Arc<dyn StorageBackend>) -> Self {
         Self {
             db_root_path: db_root_path.into(),
+            backend,
+            write_buffer_size,
             columns: vec![],
             column_names_ordered: vec![],
+            docs: Docs::default(),
+            column_docs: BTreeMap::new(),
+            storage_options: HashMap::new(),
+            layout_file: CheckedFile::new(ColumnLayout::file_path(db_root_path)),
+            key_index: None,
+            unique_indexes: vec![],
+            indexes: vec![],
+            segments: SegmentManifest::default(),
+            segment_rows: None,
+            partition_by: None,
+            active_period: None,
+            mmap_reads: false,
+            lazy_columns: false,
+            stats: BTreeMap::new(),
+            stats_file: CheckedFile::new(ColumnLayout::stats_path(db_root_path)),
+            stats_changed: false,
         }
     }
 
+    pub fn new_column(&self, name: &str, data_type: DataType) -> Result<Column, std::io::Error> {
+        let options = self.storage_options(name, &data_type);
+        let mut column = Column::with_segments(self.backend.clone(), name.to_string(), data_type, self.write_buffer_size, &self.segments)?;
+        column.set_storage_options(options);
+        Ok(column)
+    }
+
     #[instrument(skip(self))]
     pub fn insert_column(&mut self, new_column: Column) -> Result<(), std::io::Error> {
         self.column_names_ordered.push((
             new_column.name().to_string(),
             new_column.data_type().clone(),
         ));
+        self.stats.insert(new_column.name().to_string(), ColumnStats::from_cells(new_column.entries().iter()));
+        self.stats_changed = true;
         self.columns.push(new_column);
         Ok(())
     }
 
-    #[instrument(skip(self))]
-    pub fn load(&mut self) -> Result<(), std::io::Error> {
-        let root_path = Path::new(&self.db_root_path);
-        let file_path = root_path.join("column_layout.json");
+    pub fn file_path(db_root_path: &PathBuf) -> PathBuf {
+        Path::new(db_root_path).join("column_layout.json")
+    }
+
+    fn stats_path(db_root_path: &PathBuf) -> PathBuf {
+        Path::new(db_root_path).join("column_stats.json")
+    }
+
+    ///Reads the statistics written on the last flush. Missing or unreadable ones get rebuilt by `check_stats`.
+    fn load_stats(&mut self) -> Result<(), std::io::Error> {
+        let path = ColumnLayout::stats_path(&self.db_root_path);
+        if !CheckedFile::exists(&path) {
+            return Ok(());
+        }
+        let (stats_file, bytes) = match CheckedFile::load(path) {
+            Ok(loaded) => loaded,
+            Err(err) if err.kind() == std::io::ErrorKind::InvalidData => {
+                warn!("Ignoring column_stats.json: {}", err);
+                return Ok(());
+            }
+            Err(err) => return Err(err),
+        };
+        self.stats_file = stats_file;
+        let mut stored: BTreeMap<String, StoredStats> = match serde_json::from_slice(&bytes) {
+            Ok(stored) => stored,
+            Err(err) => {
+                warn!("Ignoring column_stats.json: {}", err);
+                return Ok(());
+            }
+        };
+        for (column_name, data_type) in &self.column_names_ordered {
+            let Some(column_stats) = stored.remove(column_name) else {
+                continue;
+            };
+            let column_stats = ColumnStats::from_stored(column_stats, data_type);
+            if column_stats.has_histogram() {
+                self.stats.insert(column_name.to_string(), column_stats);
+            }
+        }
+        Ok(())
+    }
+
+    ///Rebuilds the statistics of columns which don't cover every row, e.g. after a crash before the last flush.
+    ///Only runs once the entries of all rows are in place.
+    fn check_stats(&mut self) {
+        let rows = self.row_count() as u64;
+        for column in &self.columns {
+            if self.stats.get(column.name()).is_some_and(|column_stats| column_stats.count == rows) {
+                continue;
+            }
+            info!("Rebuilding statistics of column {}", column.name());
+            self.stats.insert(column.name().to_string(), ColumnStats::from_cells(column.entries().iter()));
+            self.stats_changed = true;
+        }
+    }
+
+    fn persist_stats(&mut self) -> Result<(), std::io::Error> {
+        let persisted = self.stats.iter().map(|(name, stats)| (name, PersistedStats::from(stats))).collect::<BTreeMap<_, _>>();
+        self.stats_file.write(&serde_json::to_vec(&persisted)?)?;
+        self.stats_changed = false;
+        Ok(())
+    }
 
-        let bytes = fs::read(file_path)?;
+    ///Opens all columns of the stored layout. Unless load_entries is set, their records only get read
+    ///once the returned plan runs.
+    #[instrument(skip(self))]
+    pub fn load(&mut self, load_entries: bool) -> Result<WarmupPlan, std::io::Error> {
+        let mut plan = WarmupPlan::default();
+        let (layout_file, bytes) = CheckedFile::load(ColumnLayout::file_path(&self.db_root_path))?;
+        self.layout_file = layout_file;
         let file_contents = String::from_utf8(bytes)
             .expect("Failed to load column_layout.json. Expected utf-8, got corrupted format");
-        self.column_names_ordered = serde_json::from_str(&file_contents)?;
+        (self.column_names_ordered, self.docs, self.column_docs, self.segments) = match serde_json::from_str(&file_contents)? {
+            LayoutFile::Columns(columns) => (columns, Docs::default(), BTreeMap::new(), SegmentManifest::default()),
+            LayoutFile::Documented { columns, docs, column_docs, segments } => (columns, docs, column_docs, segments),
+        };
+        self.load_stats()?;
+        let files = self
+            .column_names_ordered
+            .iter()
+            .flat_map(|(name, _)| self.column_files(name))
+            .collect::<Vec<_>>();
+        if let Some(staged) = compaction::pending(self.backend.as_ref(), &files)? {
+            self.finish_compaction(staged)?;
+        }
         for (column_name, data_type) in &self.column_names_ordered {
-            let mut c = Column::new(
-                &self.db_root_path,
+            let mut c = Column::with_segments(
+                self.backend.clone(),
                 column_name.to_string(),
                 data_type.to_owned(),
-            );
-            c.load()?;
+                self.write_buffer_size,
+                &self.segments,
+            )?;
+            c.set_mapped_reads(self.mmap_reads);
+            if self.lazy_columns && column_name != "id" {
+                c.defer_load()?;
+            } else if load_entries {
+                c.load()?;
+            } else {
+                plan.add(&mut c)?;
+            }
             self.columns.push(c);
         }
+        if load_entries {
+            self.set_stored_rows();
+            self.check_stats();
+        }
 
-        Ok(())
+        Ok(plan)
     }
 
     #[instrument(skip(self))]
-    pub fn persist_layout(&self) -> Result<(), std::io::Error> {
-        let json = serde_json::to_string(&self.column_names_ordered).unwrap();
+    pub fn persist_layout(&mut self) -> Result<(), std::io::Error> {
+        let json = match self.docs.is_empty() && self.column_docs.is_empty() && self.segments.is_empty() {
+            true => serde_json::to_vec(&self.column_names_ordered),
+            false => serde_json::to_vec(&LayoutFile::Documented {
+                columns: self.column_names_ordered.clone(),
+                docs: self.docs.clone(),
+                column_docs: self.column_docs.clone(),
+                segments: self.segments.clone(),
+            }),
+        };
+        self.layout_file.write(&json.unwrap())?;
+        Ok(())
+    }
+
+    ///Tells columns whose load got deferred how many rows the id column read from its files
+    fn set_stored_rows(&mut self) {
+        let Some(rows) = self.find_column("id").filter(|ids| ids.is_loaded()).map(|ids| ids.entries().len()) else {
+            return;
+        };
+        for column in self.columns.iter_mut() {
+            column.set_stored_rows(rows);
+        }
+    }
+
+    ///Files of the column's segments, oldest first
+    fn column_files(&self, column_name: &str) -> Vec<String> {
+        self.segments
+            .closed
+            .iter()
+            .map(|segment| segment.id)
+            .chain([self.segments.active])
+            .map(|segment| Column::segment_file_name(column_name, segment))
+            .collect()
+    }
+
+    ///Closes the active segment of every column once it holds segment_rows rows
+    pub fn roll_over_if_full(&mut self) -> Result<(), std::io::Error> {
+        let Some(segment_rows) = self.segment_rows else {
+            return Ok(());
+        };
+        let rows = self.row_count() - self.segments.closed_rows();
+        if rows < segment_rows.max(1) {
+            return Ok(());
+        }
+        self.close_active_segment(rows)
+    }
+
+    ///Closes the active segment before a row whose timestamp falls into a later period than the segment's rows,
+    ///so every segment only holds rows of a single hour or day, see `SchemaConfig::partition_by`.
+    ///Rows without a timestamp stay in the active segment.
+    pub fn roll_over_if_new_period(&mut self, values: &[(String, Cell)]) -> Result<(), std::io::Error> {
+        let Some(partition_by) = self.partition_by else {
+            return Ok(());
+        };
+        let Some(timestamp) = values.iter().find(|(name, _)| name == "timestamp").and_then(|(_, cell)| cell.as_int()) else {
+            return Ok(());
+        };
+        let period = partition_by.period(*timestamp);
+        let closed_rows = self.segments.closed_rows();
+        let rows = self.row_count() - closed_rows;
+        if self.active_period.is_none() {
+            self.active_period = self
+                .timestamp_column()
+                .and_then(|column| ZoneMap::from_cells((closed_rows..closed_rows + rows).map(|n| &column.entries()[n])))
+                .map(|zone| partition_by.period(zone.max));
+        }
+        if rows > 0 && self.active_period.is_some_and(|active_period| period > active_period) {
+            self.close_active_segment(rows)?;
+        }
+        self.active_period = self.active_period.max(Some(period));
+        Ok(())
+    }
 
-        let root_path = Path::new(&self.db_root_path);
-        let file_path = root_path.join("column_layout.json");
+    ///Hour or day the row belongs to, if the table is partitioned and the row has a timestamp
+    fn period(&self, values: &[(String, Cell)]) -> Option<i64> {
+        let timestamp = values.iter().find(|(name, _)| name == "timestamp").and_then(|(_, cell)| cell.as_int())?;
+        Some(self.partition_by?.period(*timestamp))
+    }
 
-        fs::write(file_path, json)?;
+    ///The layout lists the new segment before any column writes to it
+    fn close_active_segment(&mut self, rows: usize) -> Result<(), std::io::Error> {
+        self.flush()?;
+        let segment = self.segments.next_id();
+        let closed_rows = self.segments.closed_rows();
+        let timestamps = self
+            .timestamp_column()
+            .and_then(|column| ZoneMap::from_cells((closed_rows..closed_rows + rows).map(|n| &column.entries()[n])));
+        self.segments.closed.push(ColumnSegment {
+            id: self.segments.active,
+            rows,
+            timestamps,
+        });
+        self.segments.active = segment;
+        self.active_period = None;
+        self.persist_layout()?;
+        for column in self.columns.iter_mut() {
+            column.roll_over(segment)?;
+        }
+        debug!("Closed a segment of {} rows, continuing in segment {}", rows, segment);
         Ok(())
     }
 
+    ///Swaps in the staged files of a compaction and switches the layout over to its segments
+    fn finish_compaction(&mut self, staged: StagedCompaction) -> Result<(), std::io::Error> {
+        compaction::swap_in(self.backend.as_ref(), &staged.files)?;
+        self.segments = staged.segments;
+        self.active_period = None;
+        self.persist_layout()?;
+        for file_name in &staged.obsolete {
+            self.backend.remove(file_name)?;
+        }
+        compaction::finish(self.backend.as_ref())
+    }
+
+    ///Takes over the docs from the schema, persisting them if they changed
+    pub fn document(&mut self, config: &SchemaConfig) -> Result<(), std::io::Error> {
+        let column_docs = config
+            .columns
+            .iter()
+            .filter(|column_config| !column_config.docs.is_empty())
+            .map(|column_config| (column_config.name.clone(), column_config.docs.clone()))
+            .collect::<BTreeMap<_, _>>();
+        if self.docs == config.docs && self.column_docs == column_docs {
+            return Ok(());
+        }
+        self.docs = config.docs.clone();
+        self.column_docs = column_docs;
+        self.persist_layout()
+    }
+
+    ///Applies the encodings and compression the schema configures to the open columns.
+    ///Columns added later get theirs in `new_column`, see `storage_options`.
+    pub fn encode(&mut self, config: &SchemaConfig) {
+        self.storage_options = config
+            .columns
+            .iter()
+            .map(|column_config| {
+                let options = StorageOptions {
+                    encoding: column_config.encoding,
+                    compression: column_config.compression,
+                };
+                (column_config.name.clone(), options)
+            })
+            .collect();
+        let options = self
+            .columns
+            .iter()
+            .map(|column| self.storage_options(column.name(), column.data_type()))
+            .collect::<Vec<_>>();
+        for (column, options) in self.columns.iter_mut().zip(options) {
+            column.set_storage_options(options);
+        }
+    }
+
+    ///Options the column writes its records with. Columns set to Auto, like the system columns, use the encoding
+    ///the latest compaction picked. Encodings which don't apply to the column's type fall back to plain records.
+    fn storage_options(&self, column_name: &str, data_type: &DataType) -> StorageOptions {
+        let mut options = self.storage_options.get(column_name).copied().unwrap_or_default();
+        if options.encoding == ColumnEncoding::Auto {
+            options.encoding = self.segments.encodings.get(column_name).copied().unwrap_or_default();
+        }
+        if !options.encoding.applies_to(data_type) {
+            options.encoding = ColumnEncoding::Plain;
+        }
+        options
+    }
+
+    ///Whether compactions pick the column's encoding
+    fn is_auto_encoded(&self, column_name: &str) -> bool {
+        self.storage_options.get(column_name).copied().unwrap_or_default().encoding == ColumnEncoding::Auto
+    }
+
     pub fn len(&self) -> usize {
         self.columns.len()
     }
 
+    pub fn layout(&self) -> &[(String, DataType)] {
+        self.column_names_ordered.as_ref()
+    }
+
     pub fn column_names(&self) -> Vec<String> {
         self.columns
             .iter()
@@ -120,6 +711,11 @@ impl ColumnLayout {
             .collect::<Vec<_>>()
     }
 
+    ///Segments get closed by row count or period, see `roll_over_if_full` and `roll_over_if_new_period`
+    pub fn is_segmented(&self) -> bool {
+        self.segment_rows.is_some() || self.partition_by.is_some()
+    }
+
     pub fn timestamp_column(&self) -> Option<&Column> {
         self.columns.iter().find(|c| c.name() == "timestamp")
     }
@@ -130,9 +726,80 @@ impl ColumnLayout {
             .find(|column| column.name() == column_name)
     }
 
+    ///Keeps track of the rows by the value of the column, see `find_key`
+    pub fn index_key(&mut self, column_name: &str) {
+        self.key_index = Some(KeyIndex::new(column_name));
+        self.rebuild_key_index();
+    }
+
+    ///Rejects inserts of values the column already holds, see `find_unique`
+    pub fn index_unique(&mut self, column_name: &str) {
+        self.unique_indexes.push(KeyIndex::new(column_name));
+        self.rebuild_key_index();
+    }
+
+    ///Rebuilds the key index and the indexes of unique columns
+    fn rebuild_key_index(&mut self) {
+        let deleted = self.deleted_rows();
+        for key_index in self.key_index.iter_mut().chain(self.unique_indexes.iter_mut()) {
+            if let Some(column) = self.columns.iter().find(|column| column.name() == key_index.column()) {
+                key_index.rebuild(column.entries(), &deleted);
+            }
+        }
+    }
+
+    ///Position of the live row holding the value in the unique column, or None if the column isn't unique
+    pub fn find_unique(&self, column_name: &str, value: &Cell) -> Option<usize> {
+        self.unique_indexes.iter().find(|index| index.column() == column_name)?.get(value)
+    }
+
+    fn has_unique_columns(&self) -> bool {
+        !self.unique_indexes.is_empty()
+    }
+
+    fn is_unique(&self, column_name: &str) -> bool {
+        self.unique_indexes.iter().any(|index| index.column() == column_name)
+    }
+
+    ///Keeps track of the rows by the value of the column, see `find_indexed`. Reuses the index written on
+    ///the last flush, `check_indexes` rebuilds it if it doesn't cover every row.
+    pub fn add_index(&mut self, column_name: &str) -> Result<(), std::io::Error> {
+        self.indexes.push(ColumnIndex::load(&self.db_root_path, column_name)?);
+        Ok(())
+    }
+
+    ///Rebuilds the indexes which don't cover every row, or all of them if forced, e.g. after rows moved.
+    ///Only runs once the entries of all rows are in place.
+    fn check_indexes(&mut self, force: bool) {
+        let rows = self.row_count();
+        let deleted = self.deleted_rows();
+        for index in self.indexes.iter_mut().filter(|index| force || index.covered() != rows) {
+            if let Some(column) = self.columns.iter().find(|column| column.name() == index.column()) {
+                info!("Rebuilding index of column {}", column.name());
+                index.rebuild(column.entries(), &deleted);
+            }
+        }
+    }
+
+    ///Positions of the live rows holding the value, or None if the column has no index
+    pub fn find_indexed(&self, column_name: &str, value: &Cell) -> Option<Vec<usize>> {
+        let index = self.indexes.iter().find(|index| index.column() == column_name)?;
+        let deleted = self.deleted_rows();
+        Some(index.get(value).iter().copied().filter(|n| !deleted.contains(n)).collect())
+    }
+
+    ///Position of the live row holding the value in the indexed key column
+    pub fn find_key(&self, key: &Cell) -> Option<usize> {
+        self.key_index.as_ref()?.get(key)
+    }
+
     #[instrument(skip(self))]
     pub fn commit(&mut self, values: Vec<(String, Cell)>) -> Result<(), ContainerError> {
+        let position = self.columns[0].entries().len();
+        self.index_row(&values, position);
         for (column_name, cell) in values {
+            self.stats.entry(column_name.clone()).or_default().add(&cell);
+            self.stats_changed = true;
             let db_column = self
                 .columns
                 .iter_mut()
@@ -143,500 +810,5622 @@ impl ColumnLayout {
         Ok(())
     }
 
-    #[instrument(skip(self))]
-    pub fn all_rows(&self) -> Vec<ColumnFrame> {
-        let reference_length = self.columns[0].entries().len();
-        let length_check_passed = self
-            .columns
-            .iter()
-            .all(|c| c.entries().len() == reference_length);
-        if !length_check_passed {
-            panic!("Columns Corrupted. Not all columns contain the same number of entries");
+    ///Appends the rows with a single write per column, see `Column::insert_batch`
+    pub fn commit_batch(&mut self, rows: Vec<Vec<(String, Cell)>>) -> Result<(), ContainerError> {
+        let first_position = self.columns[0].entries().len();
+        let mut cells: HashMap<String, Vec<Cell>> = HashMap::new();
+        for (offset, values) in rows.into_iter().enumerate() {
+            self.index_row(&values, first_position + offset);
+            for (column_name, cell) in values {
+                self.stats.entry(column_name.clone()).or_default().add(&cell);
+                self.stats_changed = true;
+                cells.entry(column_name).or_default().push(cell);
+            }
+        }
+        for column in self.columns.iter_mut() {
+            if let Some(cells) = cells.remove(column.name()) {
+                column.insert_batch(cells)?;
+            }
         }
+        Ok(())
+    }
 
-        let mut rows = vec![];
+    ///Remembers the state of all columns, so appends can be undone with `rollback_to`
+    pub fn savepoint(&mut self) -> Result<LayoutSavepoint, std::io::Error> {
+        Ok(LayoutSavepoint {
+            rows: self.columns[0].entries().len(),
+            closed_segments: self.segments.closed.len(),
+            active_segment: self.segments.active,
+            columns: self.columns.iter_mut().map(|column| column.savepoint()).collect::<Result<_, _>>()?,
+            stats: self.stats.clone(),
+        })
+    }
 
-        for n in 0..reference_length {
-            let mut frame = ColumnFrame::new();
-            for column in &self.columns {
-                let cell = column.entries().get(n).unwrap();
-                frame.insert(column.name(), cell.to_owned());
+    ///Undoes the appends since the savepoint, including the ones which only made it into some of the columns.
+    ///Segments closed since then get reopened, so the rows in front of the savepoint end up where they were.
+    ///Every column gets rolled back even if one of them fails, the first error gets returned afterwards.
+    pub fn rollback_to(&mut self, savepoint: LayoutSavepoint) -> Result<(), std::io::Error> {
+        let mut result = Ok(());
+        if self.segments.active != savepoint.active_segment {
+            self.segments.closed.truncate(savepoint.closed_segments);
+            self.segments.active = savepoint.active_segment;
+            self.active_period = None;
+            result = self.persist_layout();
+        }
+        for key_index in self.key_index.iter_mut().chain(self.unique_indexes.iter_mut()) {
+            key_index.forget_from(savepoint.rows);
+        }
+        for index in self.indexes.iter_mut() {
+            index.forget_from(savepoint.rows);
+        }
+        for (column, column_savepoint) in self.columns.iter_mut().zip(savepoint.columns) {
+            if let Err(err) = column.rollback_to(column_savepoint) {
+                error!("Failed to roll back column {}: {}", column.name(), err);
+                result = result.and(Err(err));
             }
-            rows.push(frame);
         }
-
-        rows
+        self.stats = savepoint.stats;
+        self.stats_changed = true;
+        result
     }
-}
 
+    fn index_row(&mut self, values: &[(String, Cell)], position: usize) {
+        for key_index in self.key_index.iter_mut().chain(self.unique_indexes.iter_mut()) {
+            if let Some((_, key)) = values.iter().find(|(column_name, _)| column_name == key_index.column()) {
+                key_index.insert(key, position);
+            }
+        }
+        for index in self.indexes.iter_mut() {
+            if let Some((_, value)) = values.iter().find(|(column_name, _)| column_name == index.column()) {
+                index.insert(value, position);
+            }
+        }
+    }
 
-#[derive(Debug)]
-pub struct Container {
-    config: SchemaConfig,
-    columns: ColumnLayout,
-    index_counter: AutoIndex,
-}
+    pub fn flush(&mut self) -> Result<(), std::io::Error> {
+        for column in self.columns.iter_mut() {
+            column.flush()?;
+        }
+        if self.stats_changed {
+            self.persist_stats()?;
+        }
+        for index in self.indexes.iter_mut() {
+            index.persist()?;
+        }
+        Ok(())
+    }
 
-impl Container {
-    #[instrument]
-    pub fn new(root_path: &PathBuf, config: SchemaConfig) -> Result<Self, ContainerError> {
-        let index_counter = AutoIndex::load_or_new(root_path);
-        let mut column_layout = ColumnLayout::new(root_path);
+    pub fn buffered_bytes(&self) -> Vec<(String, usize)> {
+        self.columns
+            .iter()
+            .map(|column| (column.name().to_string(), column.buffered_bytes()))
+            .collect()
+    }
 
-        info!("Try loading column layout");
-        let column_layout_load_result = column_layout.load();
-        if let Err(err) = column_layout_load_result {
-            if err.kind() == std::io::ErrorKind::NotFound {
-                warn!("Column layout not found. Starting from scratch");
-                column_layout.insert_column(Column::new(root_path, "id".into(), DataType::Int))?;
-                for column_config in config.columns.iter() {
-                    let mut c: Column = Column::new(
-                        root_path,
-                        column_config.name.to_string(),
-                        column_config.data_type.to_owned().into(),
-                    );
-                    c.load()?;
-                    column_layout.insert_column(c)?;
-                }
-                if config.add_timestamp_column {
-                    info!(
-                        add_timestamp_column = config.add_timestamp_column,
-                        "Adding Timestamp Column"
-                    );
-                    let mut ts_column = Column::new(root_path, "timestamp".into(), DataType::Int);
-                    ts_column.load()?;
-                    column_layout.insert_column(ts_column)?;
-                }
-                info!("Persisting new column layout");
-                column_layout.persist_layout()?;
-            } else {
-                return Err(err.into());
-            }
+    fn row_count(&self) -> usize {
+        //Counting a column whose load got deferred would read it
+        let mut loaded = self.columns.iter().filter(|column| column.is_loaded());
+        let reference_length = loaded.next().map(|column| column.entries().len()).unwrap_or_default();
+        let length_check_passed = loaded.all(|c| c.entries().len() == reference_length);
+        if !length_check_passed {
+            panic!("Columns Corrupted. Not all columns contain the same number of entries");
         }
+        reference_length
+    }
 
-        Ok(Self {
-            columns: column_layout,
-            config,
-            index_counter,
-        })
+    ///Positions of the rows deleted by a tombstone. Columns added after a delete lack its tombstones,
+    ///so a row counts as deleted once any column has one for it.
+    fn deleted_rows(&self) -> HashSet<usize> {
+        self.columns
+            .iter()
+            .flat_map(|column| column.tombstones().iter().copied())
+            .collect()
     }
 
-    #[instrument(skip(self))]
-    fn validate_fields(&self, params: &IndexParams) -> Result<(), ContainerError> {
-        let param_field_count = if self.config.add_timestamp_column {
-            debug!("Validate Param Field Count. Adding Timestamp Column");
-            params.fields.len() + 2 // +1 for timestamp, +1 for id
-        } else {
-            params.fields.len() + 1 //+1 for id
-        };
+    ///Positions of all rows which aren't deleted
+    fn live_rows(&self) -> Vec<usize> {
+        let deleted = self.deleted_rows();
+        (0..self.row_count()).filter(|n| !deleted.contains(n)).collect()
+    }
 
-        if self.columns.len() != param_field_count {
-            return Err(ContainerError::FieldCountMismatch(
-                self.columns.len(),
-                params.fields.len(),
-            ));
-        }
+    ///Position of the live row with the id
+    fn find_row(&self, id: i64) -> Option<usize> {
+        let ids = self.find_column("id")?.entries();
+        let deleted = self.deleted_rows();
+        (0..ids.len()).find(|n| ids[*n] == Cell::Int(id) && !deleted.contains(n))
+    }
 
-        if self.config.add_timestamp_column {
-            if params.fields.iter().find(|column_name| column_name == &"timestamp").is_some() {
-                return Err(ContainerError::InvalidFields(vec!["timestamp".into()]))
+    ///Renames the column and its files. The layout switches to the new name before the old files get removed,
+    ///so a crash in between leaves the column intact.
+    pub fn rename_column(&mut self, from: &str, to: &str) -> Result<(), std::io::Error> {
+        let column = self
+            .columns
+            .iter_mut()
+            .find(|column| column.name() == from)
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, format!("Unknown column {}", from)))?;
+        let old_files = column.file_names();
+        column.rename(to)?;
+        for (column_name, _) in self.column_names_ordered.iter_mut() {
+            if column_name == from {
+                *column_name = to.to_string();
             }
         }
-
-        let column_names = self.columns.column_names();
-        let invalid_fields = params
-            .fields
-            .iter()
-            .filter(|f| !column_names.contains(f))
-            .map(|f| f.to_string())
-            .collect::<Vec<_>>();
-
-        if invalid_fields.len() > 0 {
-            return Err(ContainerError::InvalidFields(invalid_fields));
+        if let Some(docs) = self.column_docs.remove(from) {
+            self.column_docs.insert(to.to_string(), docs);
         }
-
-        if params.fields.len() != params.values.len() {
-            return Err(ContainerError::FieldCountMismatch(
-                params.fields.len(),
-                params.values.len(),
-            ));
+        if let Some(options) = self.storage_options.remove(from) {
+            self.storage_options.insert(to.to_string(), options);
+        }
+        if let Some(encoding) = self.segments.encodings.remove(from) {
+            self.segments.encodings.insert(to.to_string(), encoding);
+        }
+        if let Some(column_stats) = self.stats.remove(from) {
+            self.stats.insert(to.to_string(), column_stats);
+            self.stats_changed = true;
+        }
+        for key_index in self.key_index.iter_mut().chain(self.unique_indexes.iter_mut()) {
+            if key_index.column() == from {
+                key_index.rename(to);
+            }
+        }
+        for index in self.indexes.iter_mut().filter(|index| index.column() == from) {
+            index.rename(&self.db_root_path, to)?;
+        }
+        self.persist_layout()?;
+        for file_name in old_files {
+            self.backend.remove(&file_name)?;
         }
-
         Ok(())
     }
 
+    ///Removes the column along with its files and index. The layout stops listing the column before its files
+    ///get removed, so a crash in between leaves the files behind but the table intact.
+    pub fn drop_column(&mut self, column_name: &str) -> Result<(), std::io::Error> {
+        let position = self
+            .columns
+            .iter()
+            .position(|column| column.name() == column_name)
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, format!("Unknown column {}", column_name)))?;
+        let column = self.columns.remove(position);
+        self.column_names_ordered.retain(|(name, _)| name != column_name);
+        self.column_docs.remove(column_name);
+        self.storage_options.remove(column_name);
+        self.segments.encodings.remove(column_name);
+        if self.stats.remove(column_name).is_some() {
+            self.stats_changed = true;
+        }
+        self.unique_indexes.retain(|key_index| key_index.column() != column_name);
+        let (dropped_indexes, indexes) = std::mem::take(&mut self.indexes)
+            .into_iter()
+            .partition::<Vec<_>, _>(|index| index.column() == column_name);
+        self.indexes = indexes;
+        self.persist_layout()?;
+        for index in dropped_indexes {
+            index.remove_file()?;
+        }
+        for file_name in column.file_names() {
+            self.backend.remove(&file_name)?;
+        }
+        Ok(())
+    }
+
+    ///Appends a tombstone for the row at the position to every column
+    pub fn delete_row(&mut self, position: usize) -> Result<(), std::io::Error> {
+        for key_index in self.key_index.iter_mut().chain(self.unique_indexes.iter_mut()) {
+            if let Some(column) = self.columns.iter().find(|column| column.name() == key_index.column()) {
+                key_index.remove(&column.entries()[position], position);
+            }
+        }
+        for index in self.indexes.iter_mut() {
+            if let Some(column) = self.columns.iter().find(|column| column.name() == index.column()) {
+                index.remove(&column.entries()[position], position);
+            }
+        }
+        for column in self.columns.iter_mut() {
+            column.delete(position)?;
+        }
+        Ok(())
+    }
+
+    ///Rewrites the id column, giving the rows at the positions new ids. Tombstones are written again,
+    ///so deleted rows stay deleted.
+    pub fn reassign_ids(&mut self, new_ids: &[(usize, i64)]) -> Result<(), std::io::Error> {
+        let new_ids = new_ids.iter().copied().collect::<HashMap<_, _>>();
+        let Some(column) = self.columns.iter_mut().find(|column| column.name() == "id") else {
+            return Ok(());
+        };
+        let cells = column
+            .entries()
+            .iter()
+            .enumerate()
+            .map(|(n, cell)| new_ids.get(&n).map(|id| Cell::Int(*id)).unwrap_or_else(|| cell.to_owned()))
+            .collect::<Vec<_>>();
+        let tombstones = column.tombstones().clone();
+        //Otherwise dropping the old column writes its buffer into the new file
+        column.flush()?;
+        for file_name in column.file_names() {
+            self.backend.remove(&file_name)?;
+        }
+        let mut rewritten = Column::with_segments(self.backend.clone(), "id".to_string(), DataType::Int, self.write_buffer_size, &self.segments)?;
+        rewritten.set_storage_options(column.storage_options());
+        rewritten.fill(cells, &self.segments)?;
+        for position in tombstones {
+            rewritten.delete(position)?;
+        }
+        rewritten.flush()?;
+        *column = rewritten;
+        self.stats.remove("id");
+        self.check_stats();
+        if self.indexes.iter().any(|index| index.column() == "id") {
+            self.check_indexes(true);
+        }
+        Ok(())
+    }
+
+    ///Deletes the row at the position and appends the new version in its place
+    pub fn replace_row(&mut self, position: usize, values: Vec<(String, Cell)>) -> Result<(), ContainerError> {
+        self.delete_row(position)?;
+        self.commit(values)
+    }
+
+    ///Evaluates the filter column by column and returns the positions of all matching rows
     #[instrument(skip(self))]
-    pub fn index(&mut self, params: IndexParams) -> Result<(), ContainerError> {
-        self.validate_fields(&params)?;
+    pub fn matching_rows(&self, filter: &QueryFilter) -> Result<Vec<usize>, FilterError> {
+        //An indexed column compared for equality narrows down the rows without a scan
+        for (column_name, value) in &filter.equals {
+            if let Some(column) = self.find_column(column_name) {
+                let expected = parse_cell(column_name, value, column.data_type())?;
+                if let Some(candidates) = self.find_indexed(column_name, &expected) {
+                    return self.filter_rows(filter, candidates);
+                }
+            }
+        }
+        self.filter_rows(filter, self.live_rows())
+    }
 
-        let mut to_be_inserted = vec![];
+    ///Positions of up to limit matching rows from position start on. Only scans rows until the limit is reached,
+    ///a window of `PAGE_SCAN_ROWS` at a time. Also returns the position the next page starts at.
+    pub fn matching_rows_from(&self, filter: &QueryFilter, start: usize, limit: usize) -> Result<(Vec<usize>, usize), FilterError> {
+        let deleted = self.deleted_rows();
+        let row_count = self.row_count();
+        let mut matching = vec![];
+        let mut next = start.min(row_count);
+        while next < row_count && matching.len() < limit {
+            let end = (next + PAGE_SCAN_ROWS.max(limit)).min(row_count);
+            let window = (next..end).filter(|n| !deleted.contains(n)).collect();
+            for n in self.filter_rows(filter, window)? {
+                if matching.len() == limit {
+                    return Ok((matching, n));
+                }
+                matching.push(n);
+            }
+            next = end;
+        }
+        Ok((matching, next))
+    }
 
-        to_be_inserted.push(("id".to_string(), Cell::Int(self.index_counter.next())));
+    ///Keeps the positions of the rows matching the filter
+    fn filter_rows(&self, filter: &QueryFilter, mut matching: Vec<usize>) -> Result<Vec<usize>, FilterError> {
+        if filter.has_time_range() {
+            //Segments whose timestamps all lie outside the range get skipped without reading their cells
+            if filter.time_column.as_deref().is_none_or(|column_name| column_name == "timestamp") {
+                let pruned = self.segments.pruned_rows(filter.from, filter.to);
+                if !pruned.is_empty() {
+                    debug!("Skipping {} segments outside the time range", pruned.len());
+                    matching.retain(|n| !pruned.iter().any(|rows| rows.contains(n)));
+                }
+            }
+            let timestamps = match &filter.time_column {
+                Some(column_name) => {
+                    let column = self
+                        .find_column(column_name)
+                        .ok_or_else(|| FilterError::UnknownColumn(column_name.to_string()))?;
+                    if !matches!(column.data_type(), DataType::Int | DataType::Timestamp) {
+                        return Err(FilterError::InvalidTimeColumn(column_name.to_string(), column.data_type().clone()));
+                    }
+                    column
+                }
+                None => self.timestamp_column().ok_or(FilterError::MissingTimestampColumn)?,
+            }
+            .entries();
+            matching.retain(|n| match &timestamps[*n] {
+                Cell::Int(timestamp) | Cell::Timestamp(timestamp) => filter.in_time_range(*timestamp),
+                _ => false,
+            });
+        }
 
-        if self.config.add_timestamp_column {
-            let timestamp = SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_secs();
-            if let Some(_timestamp_column) = self.columns.timestamp_column() {
-                to_be_inserted.push(("timestamp".to_string(), Cell::Int(timestamp as i64)));
-            } else {
-                error!(
-                    "Failed to insert timestamp for {:?} params. Couldn't find Column",
-                    params
-                );
-                return Err(ContainerError::MissingTimestampColumn);
+        for (column_name, value) in &filter.equals {
+            let column = self
+                .find_column(column_name)
+                .ok_or_else(|| FilterError::UnknownColumn(column_name.to_string()))?;
+            let expected = parse_cell(column_name, value, column.data_type())?;
+            let entries = column.entries();
+            matching.retain(|n| entries[*n] == expected);
+        }
+
+        if let Some(expression) = &filter.expression {
+            let mut columns = HashMap::new();
+            for column_name in expression.columns() {
+                let column = self
+                    .find_column(column_name)
+                    .ok_or_else(|| FilterError::UnknownColumn(column_name.to_string()))?;
+                columns.insert(column_name, column.entries());
             }
+            matching.retain(|n| expression.matches(&columns, *n));
         }
 
-        for (index, column_name) in params.fields.iter().enumerate() {
-            let column_value = params.values.get(index).unwrap();
-            let db_column = self.columns.find_column(column_name).unwrap();
-            let db_column_data_type = db_column.data_type().clone();
-            if db_column.data_type().is_compatible(column_value) {
-                debug!("Store value {} for column {}", column_value, column_name);
-                //We assume this conversion always works because we checked in the if statement above if the type is compatible
-                let cell = Cell::from_json_value(column_value).unwrap();
-                to_be_inserted.push((column_name.to_owned(), cell));
-            } else {
-                self.rollback();
-                return Err(ContainerError::InvalidDataType(
-                    column_value.clone(),
-                    db_column_data_type,
-                ));
+        if filter.sample.is_some() {
+            let ids = self.find_column("id").unwrap().entries();
+            matching.retain(|n| matches!(ids[*n], Cell::Int(id) if filter.in_sample(id)));
+        }
+
+        Ok(matching)
+    }
+
+    ///Positions of all live rows with a timestamp before the cutoff
+    pub fn expired_rows(&self, cutoff: i64) -> Result<Vec<usize>, ContainerError> {
+        let timestamps = self
+            .timestamp_column()
+            .ok_or(ContainerError::MissingTimestampColumn)?
+            .entries();
+        Ok(self
+            .live_rows()
+            .into_iter()
+            .filter(|n| matches!(timestamps[*n], Cell::Int(timestamp) | Cell::Timestamp(timestamp) if timestamp < cutoff))
+            .collect())
+    }
+
+    ///Bytes each column file spends on the rows at the given positions
+    pub fn bytes_per_column(&self, positions: &[usize]) -> Result<Vec<ColumnRetention>, std::io::Error> {
+        let mut columns = vec![];
+        for column in &self.columns {
+            let mut bytes = 0;
+            for n in positions {
+                bytes += Column::record_size(&column.entries()[*n])?;
             }
+            columns.push(ColumnRetention {
+                column: column.name().to_string(),
+                bytes,
+            });
         }
+        Ok(columns)
+    }
 
-        self.commit(to_be_inserted)?;
+    ///Rewrites every column file, dropping the rows at the given positions and all deleted rows.
+    ///The rewritten files hold no tombstones and get split into full segments again. All of them get staged
+    ///before the first one replaces its segment's file, see `compaction`.
+    #[instrument(skip(self, positions))]
+    pub fn remove_rows(&mut self, positions: &[usize]) -> Result<(), std::io::Error> {
+        let mut removed = self.deleted_rows();
+        removed.extend(positions);
+        let kept_rows = (0..self.row_count()).filter(|n| !removed.contains(n)).collect::<Vec<_>>();
+        let kept_timestamps = match self.timestamp_column() {
+            Some(column) => kept_rows.iter().map(|n| column.entries()[*n].to_owned()).collect(),
+            None => vec![Cell::Null; kept_rows.len()],
+        };
+        let mut staged = StagedCompaction {
+            files: vec![],
+            segments: self.segments.rewritten(&segments::closed_segment_rows(
+                &kept_timestamps,
+                self.segment_rows,
+                self.partition_by,
+            )),
+            obsolete: vec![],
+        };
+        let auto_encoded = self
+            .columns
+            .iter()
+            .map(|column| self.is_auto_encoded(column.name()))
+            .collect::<Vec<_>>();
+        let mut compacted = vec![];
+        for (column, auto_encoded) in self.columns.iter_mut().zip(auto_encoded) {
+            let kept = kept_rows.iter().map(|n| column.entries()[*n].to_owned()).collect::<Vec<_>>();
+            //Otherwise dropping the column later appends its buffer to the rewritten file
+            column.flush()?;
+            if auto_encoded {
+                let encoding = encoding_analyzer::choose_encoding(column.data_type(), &kept)?;
+                if encoding != column.storage_options().encoding {
+                    info!("Compaction encodes column {} as {:?}", column.name(), encoding);
+                }
+                column.set_storage_options(StorageOptions { encoding, ..column.storage_options() });
+                staged.segments.encodings.insert(column.name().to_string(), encoding);
+            }
+            let mut rest = kept.as_slice();
+            let mut segments = vec![];
+            for segment in &staged.segments.closed {
+                let (cells, tail) = rest.split_at(segment.rows);
+                segments.push((segment.id, cells));
+                rest = tail;
+            }
+            segments.push((staged.segments.active, rest));
+            for (segment, cells) in segments {
+                let file_name = Column::segment_file_name(column.name(), segment);
+                self.backend.replace(&compaction::staged_name(&file_name), &column.compacted_image(cells)?)?;
+                staged.files.push(file_name);
+            }
+            compacted.push(kept);
+        }
+        for column in &self.columns {
+            let obsolete = column.file_names().into_iter().filter(|file_name| !staged.files.contains(file_name));
+            staged.obsolete.extend(obsolete);
+        }
+        if let Some(n) = self.columns.iter().position(|column| column.name() == "timestamp") {
+            staged.segments.set_timestamps(&compacted[n]);
+        }
+        compaction::mark_staged(self.backend.as_ref(), &staged)?;
+        self.finish_compaction(staged)?;
+        for (column, kept) in self.columns.iter_mut().zip(compacted) {
+            column.install_compacted(kept, &self.segments)?;
+        }
+        self.rebuild_key_index();
+        self.check_indexes(true);
+        self.check_stats();
         Ok(())
     }
 
-    #[instrument(skip(self))]
-    fn commit(&mut self, values: Vec<(String, Cell)>) -> Result<(), ContainerError> {
-        self.columns.commit(values)?;
-        self.index_counter.commit()?;
+    ///Drops the closed segments at the front whose rows are all expired or deleted, then deletes the remaining
+    ///expired rows by tombstones. Unlike `remove_rows` it rewrites no files.
+    #[instrument(skip(self, positions))]
+    pub fn expire_rows(&mut self, positions: &[usize]) -> Result<(), std::io::Error> {
+        let mut removed = self.deleted_rows();
+        removed.extend(positions);
+        let mut count = 0;
+        let mut rows = 0;
+        for segment in &self.segments.closed {
+            if !(rows..rows + segment.rows).all(|n| removed.contains(&n)) {
+                break;
+            }
+            count += 1;
+            rows += segment.rows;
+        }
+        if count > 0 {
+            info!("Dropping {} segments holding {} rows", count, rows);
+            //The layout stops listing the segments before their files get removed
+            self.segments.closed.drain(..count);
+            self.segments.dropped_rows += rows;
+            self.persist_layout()?;
+            for column in self.columns.iter_mut() {
+                for file_name in column.drop_segments(count, rows)? {
+                    self.backend.remove(&file_name)?;
+                }
+            }
+            self.rebuild_key_index();
+            self.check_indexes(true);
+            self.check_stats();
+        }
+        for position in positions.iter().filter(|position| **position >= rows) {
+            self.delete_row(position - rows)?;
+        }
         Ok(())
     }
 
-    #[instrument(skip(self))]
-    fn rollback(&mut self) {
-        self.index_counter.rollback();
+    ///Size of all column files, including records still sitting in write buffers
+    pub fn file_bytes(&self) -> u64 {
+        self.columns.iter().map(|column| column.file_len()).sum()
     }
 
+    ///All rows which aren't deleted
     #[instrument(skip(self))]
-    pub async fn query(&self, tx: Sender<Command>) {
-        for row in self.columns.all_rows() {
-            match tx.send(Command::QueryRow { row }).await {
-                Ok(()) => {
-                    debug!("Successfully sent row");
-                }
-                Err(err) => {
-                    error!("SendError: {}", err);
-                }
+    pub fn all_rows(&self) -> Vec<ColumnFrame> {
+        self.rows(self.live_rows())
+    }
+
+    ///Assembles the rows at the given positions
+    pub fn rows(&self, positions: Vec<usize>) -> Vec<ColumnFrame> {
+        self.rows_with_columns(positions, None)
+    }
+
+    ///Like `rows`, but only with the listed columns, along with `id` and `timestamp`. All columns if not set.
+    pub fn rows_with_columns(&self, positions: Vec<usize>, columns: Option<&[String]>) -> Vec<ColumnFrame> {
+        let columns = self
+            .columns
+            .iter()
+            .filter(|column| {
+                columns.is_none_or(|names| {
+                    matches!(column.name(), "id" | "timestamp") || names.iter().any(|name| name == column.name())
+                })
+            })
+            .collect::<Vec<_>>();
+        let mut rows = vec![];
+
+        for n in positions {
+            let mut frame = ColumnFrame::new();
+            for column in &columns {
+                let cell = column.entries().get(n).unwrap();
+                frame.insert(column.name(), cell.to_owned());
             }
+            rows.push(frame);
         }
+
+        rows
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use std::path::{Path, PathBuf};
 
-    use serde_json::json;
+#[derive(Debug)]
+pub struct Container {
+    config: SchemaConfig,
+    columns: ColumnLayout,
+    index_counter: AutoIndex,
+    wal: Wal,
+    ///Computed columns from the schema which don't exist in the table yet, because it already had rows
+    pending_computed_columns: Vec<ColumnConfig>,
+    last_retention_report: Option<RetentionReport>,
+    ///False while the records stored before startup are still being read, see `open_cold`
+    warm: bool,
+    dedupe: Option<DedupeWindow>,
+    ///Fresh inserts and conflicts per key column, see `count_fresh`
+    upserts: UpsertConflicts,
+    ///Rows sent with ack=received which failed to get stored, see `count_unacknowledged_failure`
+    unacknowledged_failures: u64,
+    disk_pressure: Option<DiskPressure>,
+    ///Holds the fingerprint of `config`, see `SchemaFingerprint`
+    schema_fingerprint: CheckedFile,
+}
 
-    use super::Container;
-    use crate::{
-        config::{ColumnConfig, DataTypeConfig, SchemaConfig},
-        storage::cell::Cell,
-        web::IndexParams,
-    };
+///State of the columns before appending, see `ColumnLayout::rollback_to`
+#[derive(Debug)]
+pub struct LayoutSavepoint {
+    rows: usize,
+    closed_segments: usize,
+    active_segment: u64,
+    columns: Vec<ColumnSavepoint>,
+    stats: BTreeMap<String, ColumnStats>,
+}
+
+///Rows matching a filter, along with the names of the columns they hold
+#[derive(Debug)]
+pub struct RowScan {
+    pub columns: Vec<String>,
+    pub rows: Vec<ColumnFrame>,
+}
+
+///Page of rows, for `GET /rows`
+#[derive(Debug)]
+pub struct RowPage {
+    pub rows: Vec<ColumnFrame>,
+    ///Continues with the rows after this page, including rows appended meanwhile
+    pub cursor: PageCursor,
+    ///False once the page reached the last stored row
+    pub has_more: bool,
+}
 
-    pub fn initialize() {
-        let _ = std::fs::remove_file("/tmp/column_url");
-        let _ = std::fs::remove_file("/tmp/column_timestamp");
-        let _ = std::fs::remove_file("/tmp/column_points");
-        let _ = std::fs::remove_file("/tmp/column_id");
-        let _ = std::fs::remove_file("/tmp/auto_index");
-        let _ = std::fs::remove_file("/tmp/column_layout.json");
+#[derive(Debug, Serialize)]
+pub struct DistinctValues {
+    pub column: String,
+    pub values: Vec<Cell>,
+    ///True if the column holds more distinct values than the limit allowed to return
+    pub truncated: bool,
+    ///Values which didn't convert to the requested cast type. Only set if the request asked for a cast.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub failed_casts: Option<usize>,
+    ///Rows looked at until the limit was reached, for usage accounting
+    #[serde(skip)]
+    pub scanned: usize,
+}
+
+///A column as `/schema` describes it
+#[derive(Debug, Serialize)]
+pub struct ColumnSchema {
+    pub name: String,
+    pub data_type: DataType,
+    pub nullable: bool,
+    ///Filled by the database, like `id` or `timestamp`
+    pub system: bool,
+    ///Added by schema inference instead of being declared in `schema.json`
+    pub inferred: bool,
+    #[serde(flatten)]
+    pub docs: Docs,
+}
+
+///The main table as `GET /schema/table` describes it
+#[derive(Debug, Serialize)]
+pub struct TableSchema {
+    #[serde(flatten)]
+    pub docs: Docs,
+    pub columns: Vec<ColumnSchema>,
+}
+
+///Ids of a transaction's rows. Rows whose key was already stored get the id of the stored row.
+#[derive(Debug)]
+pub struct CommittedTransaction {
+    pub ids: Vec<i64>,
+    ///Write sequence number after the transaction, see `Container::write_seq`
+    pub seq: i64,
+    ///Rows dropped as redeliveries, see `UpsertConflicts`
+    pub conflicts: usize,
+    ///Values of the stored rows which lost precision, see `coerced_values`
+    pub warnings: Vec<Warning>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BackfillReport {
+    pub columns: Vec<String>,
+    pub rows: usize,
+}
+
+///Columns rebuilt from the write-ahead log, see `Container::replay_wal`
+struct Replayed {
+    columns: ColumnLayout,
+    rows: usize,
+    last_id: i64,
+}
+
+impl Container {
+    #[instrument]
+    pub fn new(root_path: &PathBuf, config: SchemaConfig) -> Result<Self, ContainerError> {
+        let (container, _) = Container::open(root_path, config, true)?;
+        Ok(container)
     }
 
-    fn schema_config_with_timestamp() -> SchemaConfig {
-        let columns = vec![ColumnConfig {
-            name: "url".into(),
-            data_type: DataTypeConfig::String,
-        }];
-        SchemaConfig {
-            columns,
-            add_timestamp_column: true,
-        }
+    ///Opens the table without reading the stored records. It accepts inserts right away,
+    ///everything else needs to wait until the returned plan ran and `finish_warmup` got its result.
+    #[instrument]
+    pub fn open_cold(root_path: &PathBuf, config: SchemaConfig) -> Result<(Self, WarmupPlan), ContainerError> {
+        Container::open(root_path, config, false)
     }
 
-    fn schema_config_without_timestamp() -> SchemaConfig {
-        let columns = vec![ColumnConfig {
-            name: "url".into(),
-            data_type: DataTypeConfig::String,
-        }];
-        SchemaConfig {
-            columns,
-            add_timestamp_column: false,
-        }
+    ///Opens a snapshot taken by `ReplicaSnapshots`. Unlike `open_cold`, a snapshot lacking its layout
+    ///or column files is an error instead of an empty table.
+    pub fn open_replica_cold(snapshot_path: &PathBuf, config: SchemaConfig) -> Result<(Self, WarmupPlan), ContainerError> {
+        let backend = backend::open(&config.storage_backend, snapshot_path)?;
+        ColumnLayout::new(snapshot_path, config.write_buffer_size, backend).load(false)?;
+        Container::open(snapshot_path, config, false)
     }
 
-    fn schema_config_with_timestamp_and_two_columns() -> SchemaConfig {
-        let columns = vec![
-            ColumnConfig {
-                name: "url".into(),
-                data_type: DataTypeConfig::String,
-            },
-            ColumnConfig {
-                name: "points".into(),
-                data_type: DataTypeConfig::Int,
-            },
-        ];
-        SchemaConfig {
-            columns,
-            add_timestamp_column: true,
-        }
+    ///Opens and loads a snapshot taken by `ReplicaSnapshots`, see `open_replica_cold`.
+    ///Rows which didn't make it into all columns of the snapshot get cut off.
+    #[instrument(skip(config))]
+    pub fn open_replica(snapshot_path: &PathBuf, config: SchemaConfig) -> Result<Self, ContainerError> {
+        let (mut container, plan) = Container::open_replica_cold(snapshot_path, config)?;
+        let mut loaded = plan.run(&StartupTracker::new())?;
+        loaded.trim_to_shortest();
+        container.finish_warmup(loaded)?;
+        Ok(container)
     }
 
-    #[test]
-    fn insert_a_record_with_auto_timestamp_column() {
-        initialize();
-        let mut container = Container::new(&Path::new("/tmp").to_path_buf(), schema_config_with_timestamp()).unwrap(); 
+    fn open(root_path: &PathBuf, config: SchemaConfig, load_entries: bool) -> Result<(Self, WarmupPlan), ContainerError> {
+        let index_counter = AutoIndex::load_or_new(root_path)?;
+        let backend = backend::open(&config.storage_backend, root_path)?;
+        let mut column_layout = ColumnLayout::new(root_path, config.write_buffer_size, backend);
+        if config.mmap_reads && !matches!(config.storage_backend, StorageBackendConfig::Local) {
+            warn!("mmap_reads only applies to tables stored on the local disk, decoding all cells on load instead");
+        }
+        column_layout.mmap_reads = config.mmap_reads;
+        column_layout.lazy_columns = config.lazy_columns;
 
-        let params = IndexParams {
-            fields: vec!["url".into()],
-            values: vec![serde_json::Value::String("https://google.com".into())],
+        info!("Try loading column layout");
+        let column_layout_load_result = column_layout.load(load_entries);
+        let plan = match column_layout_load_result {
+            Ok(plan) => plan,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                warn!("Column layout not found. Starting from scratch");
+                column_layout.insert_column(column_layout.new_column("id", DataType::Int)?)?;
+                for column_config in config.columns.iter() {
+                    let mut c: Column = column_layout.new_column(
+                        &column_config.name,
+                        column_config.data_type.to_owned().into(),
+                    )?;
+                    c.load()?;
+                    column_layout.insert_column(c)?;
+                }
+                if config.add_timestamp_column {
+                    info!(
+                        add_timestamp_column = config.add_timestamp_column,
+                        "Adding Timestamp Column"
+                    );
+                    let mut ts_column = column_layout.new_column("timestamp", DataType::Timestamp)?;
+                    ts_column.load()?;
+                    column_layout.insert_column(ts_column)?;
+                }
+                info!("Persisting new column layout");
+                column_layout.persist_layout()?;
+                WarmupPlan::default()
+            }
+            Err(err) => return Err(err.into()),
         };
-        container.index(params).unwrap();
 
-        let ts_column = container.columns.find_column("timestamp").unwrap();
-        let url_column = container.columns.find_column("url").unwrap();
+        let schema_fingerprint = Container::check_schema(root_path, &config, &column_layout)?;
+        column_layout.document(&config)?;
+        column_layout.encode(&config);
+        column_layout.segment_rows = config.segment_rows;
+        column_layout.partition_by = config.partition_by;
+        if config.partition_by.is_some() && !config.add_timestamp_column {
+            warn!("partition_by has no effect without add_timestamp_column");
+        }
+
+        let mut wal = Wal::open(root_path)?;
+        if wal.is_empty() {
+            info!("Starting new write-ahead log");
+            wal.append_layout(column_layout.layout())?;
+        }
+
+        let pending_computed_columns = config
+            .columns
+            .iter()
+            .filter(|column_config| column_config.computed && column_layout.find_column(&column_config.name).is_none())
+            .cloned()
+            .collect::<Vec<_>>();
+        for column_config in &pending_computed_columns {
+            warn!(
+                "Computed column {} doesn't exist yet. Run a backfill to add it to existing rows",
+                column_config.name
+            );
+        }
+        for column_config in config.columns.iter().filter(|c| !c.computed && column_layout.find_column(&c.name).is_none()) {
+            warn!(
+                "Column {} from the schema doesn't exist in the table. If it got renamed or dropped, update schema.json as well",
+                column_config.name
+            );
+        }
+
+        //Checked up front, so a typo in schema.json doesn't only show up once an insert leaves out the column
+        for column_config in &config.columns {
+            if column_config.auto_generate && !matches!(column_config.data_type, DataTypeConfig::Uuid) {
+                warn!("Column {} isn't a Uuid column, auto_generate has no effect", column_config.name);
+            }
+            if !column_config.encoding.applies_to(&column_config.data_type.to_owned().into()) {
+                warn!(
+                    "Column {} is a {:?} column, {:?} encoding has no effect",
+                    column_config.name, column_config.data_type, column_config.encoding
+                );
+            }
+            if let Some(default) = &column_config.default {
+                let data_type: DataType = column_config.data_type.to_owned().into();
+                if !(data_type.is_compatible(default) || default.is_null() && column_config.nullable) {
+                    return Err(ContainerError::InvalidDataType(column_config.name.to_string(), default.clone(), data_type));
+                }
+            }
+            if let Some(kind) = &column_config.server_computed {
+                Container::check_server_column(&config, column_config, kind)?;
+            }
+        }
+        if let Some(primary_key) = &config.primary_key {
+            if !config.columns.iter().any(|column_config| &column_config.name == primary_key && !column_config.nullable) {
+                return Err(ContainerError::InvalidPrimaryKey(primary_key.to_string()));
+            }
+        }
+
+        let dedupe = config
+            .dedupe
+            .as_ref()
+            .map(|dedupe_config| DedupeWindow::new(Duration::from_secs(dedupe_config.window_secs)));
+        let disk_pressure = config
+            .disk_pressure
+            .clone()
+            .map(|disk_pressure_config| DiskPressure::new(disk_pressure_config, root_path));
+        let mut container = Self {
+            columns: column_layout,
+            dedupe,
+            upserts: UpsertConflicts::default(),
+            unacknowledged_failures: 0,
+            disk_pressure,
+            config,
+            index_counter,
+            wal,
+            pending_computed_columns,
+            last_retention_report: None,
+            warm: load_entries,
+            schema_fingerprint,
+        };
+        if let Some(unique_key) = &container.config.unique_key {
+            match container.columns.find_column(unique_key) {
+                Some(_) => container.columns.index_key(unique_key),
+                None => warn!("Unique key {} doesn't exist in the table, inserts won't replace rows", unique_key),
+            }
+        }
+        //The primary key rejects values another row holds, like a unique column
+        let is_unique =
+            |column_config: &ColumnConfig| column_config.unique || container.config.primary_key.as_ref() == Some(&column_config.name);
+        for column_config in container.config.columns.iter().filter(|column_config| is_unique(column_config)) {
+            match container.columns.find_column(&column_config.name) {
+                Some(_) => container.columns.index_unique(&column_config.name),
+                None => warn!("Unique column {} doesn't exist in the table", column_config.name),
+            }
+        }
+        for column_config in container.config.columns.iter().filter(|column_config| column_config.indexed) {
+            match container.columns.find_column(&column_config.name) {
+                Some(_) => container.columns.add_index(&column_config.name)?,
+                None => warn!("Indexed column {} doesn't exist in the table", column_config.name),
+            }
+        }
+        if container.warm {
+            container.columns.check_indexes(false);
+        }
+        if container.warm && container.config.lineage {
+            container.add_lineage_columns()?;
+        }
+        Ok((container, plan))
+    }
+
+    ///Installs the records read by the warm-up plan in front of the rows inserted meanwhile
+    #[instrument(skip(self, loaded))]
+    pub fn finish_warmup(&mut self, loaded: LoadedColumns) -> Result<(), ContainerError> {
+        let inserted_meanwhile = self.columns.find_column("id").map(|ids| ids.entries().len()).unwrap_or_default();
+        for (column_name, records) in loaded.0 {
+            if let Some(column) = self.columns.columns.iter_mut().find(|column| column.name() == column_name) {
+                column.install_entries(records)?;
+            }
+        }
+        if self.columns.lazy_columns {
+            let stored_rows = self.columns.row_count() - inserted_meanwhile;
+            for column in self.columns.columns.iter_mut() {
+                column.set_stored_rows(stored_rows);
+            }
+        }
+        //Rows inserted meanwhile moved behind the loaded ones
+        self.columns.rebuild_key_index();
+        self.columns.check_indexes(inserted_meanwhile > 0);
+        self.columns.check_stats();
+        self.warm = true;
+        if self.config.lineage {
+            self.add_lineage_columns()?;
+        }
+        Ok(())
+    }
+
+    pub fn is_warm(&self) -> bool {
+        self.warm
+    }
+
+    ///Rebuilds all column files, `column_layout.json` and the auto index purely from the write-ahead log.
+    ///Returns the number of restored rows.
+    ///Refuses to run if the log doesn't reach back to the first row, the rows before it would get lost.
+    #[instrument]
+    pub fn repair_from_wal(root_path: &PathBuf, backend_config: &StorageBackendConfig) -> Result<usize, ContainerError> {
+        let records = Wal::read_all(root_path)?;
+        Container::check_wal_history(&records, root_path)?;
+        let backend = backend::open(backend_config, root_path)?;
+        let replayed = Container::replay_wal(records, root_path, backend, |_| false)?;
+        let mut column_layout = replayed.columns;
+        column_layout.flush()?;
+        column_layout.persist_layout()?;
+        //Positions of the rows may have changed, indexes get rebuilt on the next start
+        ColumnIndex::remove_all(root_path)?;
+
+        let mut index_counter = AutoIndex::new(root_path);
+        index_counter.reset_to(replayed.last_id);
+        index_counter.commit()?;
+
+        Ok(replayed.rows)
+    }
+
+    ///Makes sure the log holds every row ever written, by checking that it starts at row id 1.
+    ///A log without any rows is only complete if the auto index hasn't handed out an id yet.
+    fn check_wal_history(records: &[WalRecord], root_path: &PathBuf) -> Result<(), WalError> {
+        let id_of = |values: &Vec<(String, Cell)>| match values.iter().find(|(column_name, _)| column_name == "id") {
+            Some((_, Cell::Int(id))) => Some(*id),
+            _ => None,
+        };
+        let first_id = records.iter().find_map(|record| match record {
+            WalRecord::Row(values) => id_of(values),
+            WalRecord::Transaction(rows) => rows.first().and_then(id_of),
+            _ => None,
+        });
+        match first_id {
+            Some(1) => Ok(()),
+            Some(id) => Err(WalError::MissingRows(id)),
+            None => {
+                //A corrupted counter can't tell, the log is all there is to repair from then
+                let counter = AutoIndex::load_or_new(root_path).map(|index| index.counter()).unwrap_or_default();
+                if counter > 0 {
+                    Err(WalError::MissingRows(counter + 1))
+                } else {
+                    Ok(())
+                }
+            }
+        }
+    }
+
+    ///Rebuilds the columns in root_path from the log records, up to the first row for which `stop` returns true.
+    ///Transactions stop as a whole.
+    fn replay_wal<F>(
+        records: Vec<WalRecord>,
+        root_path: &PathBuf,
+        backend: Arc<dyn StorageBackend>,
+        stop: F,
+    ) -> Result<Replayed, ContainerError>
+    where
+        F: Fn(&[(String, Cell)]) -> bool,
+    {
+        let mut column_layout: Option<ColumnLayout> = None;
+        let mut restored_rows = 0;
+        let mut last_id = 0;
+
+        //Replays the log in order, so every row gets checked against the columns existing at its time
+        for record in records {
+            match record {
+                WalRecord::Layout(layout) => {
+                    if column_layout.is_some() {
+                        continue;
+                    }
+                    let mut new_layout = ColumnLayout::new(root_path, DEFAULT_WRITE_BUFFER_SIZE, backend.clone());
+                    for (column_name, data_type) in layout {
+                        let column = Container::empty_column(&new_layout, &column_name, data_type)?;
+                        new_layout.insert_column(column)?;
+                    }
+                    column_layout = Some(new_layout);
+                }
+                WalRecord::Column(column_name, data_type, cells) => {
+                    let column_layout = column_layout.as_mut().ok_or(WalError::MissingLayout)?;
+                    let mut column = Container::empty_column(column_layout, &column_name, data_type)?;
+                    for cell in cells {
+                        column.insert(cell)?;
+                    }
+                    column_layout.insert_column(column)?;
+                }
+                WalRecord::Expire(cutoff) => {
+                    let column_layout = column_layout.as_mut().ok_or(WalError::MissingLayout)?;
+                    let expired = column_layout.expired_rows(cutoff)?;
+                    column_layout.remove_rows(&expired)?;
+                    restored_rows -= expired.len();
+                }
+                WalRecord::Rename(from, to) => {
+                    let column_layout = column_layout.as_mut().ok_or(WalError::MissingLayout)?;
+                    column_layout.rename_column(&from, &to)?;
+                }
+                WalRecord::Drop(column_name) => {
+                    let column_layout = column_layout.as_mut().ok_or(WalError::MissingLayout)?;
+                    column_layout.drop_column(&column_name)?;
+                }
+                WalRecord::Update(_, values) if stop(&values) => break,
+                WalRecord::Update(id, values) => {
+                    let column_layout = column_layout.as_mut().ok_or(WalError::MissingLayout)?;
+                    if let Some(position) = column_layout.find_row(id) {
+                        column_layout.delete_row(position)?;
+                        Container::replay_row(column_layout, values, restored_rows, &mut last_id)?;
+                    }
+                }
+                WalRecord::Reassign(new_ids) => {
+                    let column_layout = column_layout.as_mut().ok_or(WalError::MissingLayout)?;
+                    column_layout.reassign_ids(&new_ids)?;
+                    last_id = new_ids.iter().map(|(_, id)| *id).fold(last_id, i64::max);
+                }
+                WalRecord::Delete(id) => {
+                    let column_layout = column_layout.as_mut().ok_or(WalError::MissingLayout)?;
+                    if let Some(position) = column_layout.find_row(id) {
+                        column_layout.delete_row(position)?;
+                        restored_rows -= 1;
+                    }
+                }
+                WalRecord::Transaction(rows) if rows.iter().any(|values| stop(values)) => break,
+                WalRecord::Transaction(rows) => {
+                    let column_layout = column_layout.as_mut().ok_or(WalError::MissingLayout)?;
+                    for values in rows {
+                        Container::replay_row(column_layout, values, restored_rows, &mut last_id)?;
+                        restored_rows += 1;
+                    }
+                }
+                WalRecord::Row(values) if stop(&values) => break,
+                WalRecord::Row(values) => {
+                    let column_layout = column_layout.as_mut().ok_or(WalError::MissingLayout)?;
+                    Container::replay_row(column_layout, values, restored_rows, &mut last_id)?;
+                    restored_rows += 1;
+                }
+            }
+        }
+
+        Ok(Replayed {
+            columns: column_layout.ok_or(WalError::MissingLayout)?,
+            rows: restored_rows,
+            last_id,
+        })
+    }
+
+    fn replay_row(
+        column_layout: &mut ColumnLayout,
+        values: Vec<(String, Cell)>,
+        row_number: usize,
+        last_id: &mut i64,
+    ) -> Result<(), ContainerError> {
+        let layout = column_layout.layout();
+        let matches_layout = values.len() == layout.len()
+            && layout.iter().all(|(column_name, _)| values.iter().any(|(name, _)| name == column_name));
+        if !matches_layout {
+            return Err(WalError::LayoutMismatch(row_number).into());
+        }
+        if let Some((_, Cell::Int(id))) = values.iter().find(|(column_name, _)| column_name == "id") {
+            *last_id = (*last_id).max(*id);
+        }
+        column_layout.commit(values)?;
+        Ok(())
+    }
+
+    ///Creates a column, throwing away whatever a previous column of the same name left on disk
+    fn empty_column(column_layout: &ColumnLayout, column_name: &str, data_type: DataType) -> Result<Column, ContainerError> {
+        info!("Rebuilding column {}", column_name);
+        for file_name in column_layout.column_files(column_name) {
+            column_layout.backend.remove(&file_name)?;
+        }
+        Ok(column_layout.new_column(column_name, data_type)?)
+    }
+
+    fn unknown_fields(&self, params: &IndexParams) -> Vec<String> {
+        let column_names = self.columns.column_names();
+        params
+            .fields
+            .iter()
+            .filter(|f| !column_names.contains(f))
+            .map(|f| f.to_string())
+            .collect::<Vec<_>>()
+    }
+
+    ///Columns the database fills on its own. Clients must not provide values for them.
+    fn reserved_columns(&self) -> Vec<&str> {
+        let mut reserved_columns = vec!["id"];
+        if self.config.add_timestamp_column {
+            reserved_columns.push("timestamp");
+        }
+        reserved_columns.extend(self.server_columns().map(|(column_name, _)| column_name));
+        //Lineage columns stay system columns even after lineage got turned off again
+        for (column_name, _) in LINEAGE_COLUMNS {
+            if self.columns.find_column(column_name).is_some() {
+                reserved_columns.push(column_name);
+            }
+        }
+        reserved_columns
+    }
+
+    ///Server computed columns of the schema which exist in the table, along with how to compute them
+    fn server_columns(&self) -> impl Iterator<Item = (&str, &ServerComputed)> {
+        self.config.columns.iter().filter_map(|column_config| {
+            let kind = column_config.server_computed.as_ref()?;
+            self.columns.find_column(&column_config.name)?;
+            Some((column_config.name.as_str(), kind))
+        })
+    }
+
+    ///Sets the server computed columns of the row from its other cells
+    fn fill_server_columns(&self, row: &mut Vec<(String, Cell)>) {
+        for (column_name, kind) in self.server_columns() {
+            let cell = server_columns::compute(kind, row);
+            match row.iter_mut().find(|(name, _)| name == column_name) {
+                Some((_, existing)) => *existing = cell,
+                None => row.push((column_name.to_string(), cell)),
+            }
+        }
+    }
+
+    fn is_nullable(&self, column_name: &str) -> bool {
+        self.is_inferred(column_name)
+            || self
+                .config
+                .columns
+                .iter()
+                .any(|column_config| column_config.nullable && column_config.name == column_name)
+    }
+
+    ///Compares schema.json against the schema the table last ran with. Changes the stored columns fit get logged
+    ///and become the new fingerprint, others refuse the start. Tables without a fingerprint get one.
+    fn check_schema(root_path: &Path, config: &SchemaConfig, column_layout: &ColumnLayout) -> Result<CheckedFile, ContainerError> {
+        let (mut file, stored) = SchemaFingerprint::load(root_path)?;
+        let fingerprint = SchemaFingerprint::of(config);
+        match stored {
+            Some(stored) if stored.sha256 == fingerprint.sha256 => return Ok(file),
+            Some(stored) => {
+                let conflicts = fingerprint.conflicts(column_layout);
+                if !conflicts.is_empty() {
+                    return Err(ContainerError::SchemaConflict(conflicts));
+                }
+                for change in fingerprint.changes(&stored) {
+                    info!("Schema changed since the last start: {}", change);
+                }
+            }
+            None => info!("Recording the schema fingerprint"),
+        }
+        fingerprint.write(&mut file)?;
+        Ok(file)
+    }
+
+    ///Checks the schema provides what the server computed column is computed from
+    fn check_server_column(config: &SchemaConfig, column_config: &ColumnConfig, kind: &ServerComputed) -> Result<(), ContainerError> {
+        let invalid = |reason: String| ContainerError::InvalidServerColumn {
+            column: column_config.name.to_string(),
+            reason,
+        };
+        if !matches!(column_config.data_type, DataTypeConfig::String) {
+            return Err(invalid("server computed columns hold strings, its data_type has to be String".into()));
+        }
+        match kind {
+            ServerComputed::IngestDate if !config.add_timestamp_column => Err(invalid("IngestDate requires add_timestamp_column".into())),
+            ServerComputed::IngestDate => Ok(()),
+            ServerComputed::RowHash(columns) => {
+                let is_known = |column_name: &str| {
+                    ["id", "timestamp"].contains(&column_name)
+                        || config
+                            .columns
+                            .iter()
+                            .any(|other| other.name == column_name && other.server_computed.is_none())
+                };
+                match columns.iter().find(|column_name| !is_known(column_name)) {
+                    Some(column_name) => Err(invalid(format!("RowHash column {} isn't a column the row gets stored with", column_name))),
+                    None => Ok(()),
+                }
+            }
+        }
+    }
+
+    ///Columns which are neither declared in the schema nor system columns got added by schema inference.
+    ///They stay nullable after inference gets turned off again.
+    fn is_inferred(&self, column_name: &str) -> bool {
+        !self.reserved_columns().contains(&column_name)
+            && !self.config.columns.iter().any(|column_config| column_config.name == column_name)
+    }
+
+    ///With schema inference enabled, adds a column for every unknown field of the row.
+    ///Unknown fields holding null get dropped, since every existing row already lacks them.
+    ///Unless `create` is set, fields which would add a column get dropped instead, so the row can be validated without changing the schema.
+    fn infer_columns(&mut self, params: IndexParams, create: bool) -> Result<IndexParams, ContainerError> {
+        let mut inferred = vec![];
+        self.infer_column_types(&params, &mut inferred)?;
+        let (mut params, inferred_values) = self.split_inferred(params, &inferred);
+        if create {
+            self.add_inferred_columns(&inferred)?;
+            for (field, value) in inferred_values {
+                params.fields.push(field);
+                params.values.push(value);
+            }
+        }
+        Ok(params)
+    }
+
+    fn is_unknown_field(&self, field: &str) -> bool {
+        self.columns.find_column(field).is_none()
+            && !self.reserved_columns().contains(&field)
+            && !self.pending_computed_columns.iter().any(|column_config| column_config.name == field)
+    }
+
+    ///Adds every unknown field of the row which would become a column to `inferred`, typed after its value.
+    ///Fields already listed keep the type they were first inferred with.
+    fn infer_column_types(&self, params: &IndexParams, inferred: &mut Vec<(String, DataType)>) -> Result<(), ContainerError> {
+        if !self.config.infer_schema {
+            return Ok(());
+        }
+        //Arrays and objects can't be stored in any column
+        let uninferable = params
+            .fields
+            .iter()
+            .zip(&params.values)
+            .filter(|(field, value)| self.is_unknown_field(field) && !value.is_null() && DataType::infer(value).is_none())
+            .map(|(field, _)| field.to_string())
+            .collect::<Vec<_>>();
+        if !uninferable.is_empty() {
+            return Err(ContainerError::InvalidFields(uninferable));
+        }
+
+        for (field, value) in params.fields.iter().zip(&params.values) {
+            if !self.is_unknown_field(field) || inferred.iter().any(|(name, _)| name == field) {
+                continue;
+            }
+            if let Some(data_type) = DataType::infer(value) {
+                inferred.push((field.to_string(), data_type));
+            }
+        }
+        Ok(())
+    }
+
+    ///Splits the fields of inferred columns off the row. Other unknown fields holding null get dropped.
+    fn split_inferred(&self, params: IndexParams, inferred: &[(String, DataType)]) -> (IndexParams, Vec<(String, serde_json::Value)>) {
+        if !self.config.infer_schema {
+            return (params, vec![]);
+        }
+        let mut fields = vec![];
+        let mut values = vec![];
+        let mut inferred_values = vec![];
+        for (field, value) in params.fields.into_iter().zip(params.values) {
+            if !self.is_unknown_field(&field) {
+                fields.push(field);
+                values.push(value);
+            } else if inferred.iter().any(|(name, _)| name == &field) {
+                inferred_values.push((field, value));
+            }
+        }
+        (IndexParams { fields, values }, inferred_values)
+    }
+
+    ///Adds the inferred columns, holding null for every existing row
+    fn add_inferred_columns(&mut self, inferred: &[(String, DataType)]) -> Result<(), ContainerError> {
+        if inferred.is_empty() {
+            return Ok(());
+        }
+        //Existing rows get null, which requires all of them to be loaded
+        if !self.warm {
+            return Err(ContainerError::WarmingUp);
+        }
+        let row_count = self.columns.row_count();
+        for (field, data_type) in inferred {
+            info!("Inferred new column {} ({})", field, data_type);
+            self.add_column(field, data_type.clone(), vec![Cell::Null; row_count])?;
+        }
+        self.columns.persist_layout()?;
+        Ok(())
+    }
+
+    ///Cells of a row for all inferred columns, null for the ones it leaves out
+    fn inferred_cells(
+        inferred_values: Vec<(String, serde_json::Value)>,
+        inferred: &[(String, DataType)],
+    ) -> Result<Vec<(String, Cell)>, ContainerError> {
+        let mut duplicate_fields = inferred_values
+            .iter()
+            .enumerate()
+            .filter(|(index, (field, _))| inferred_values[..*index].iter().any(|(seen, _)| seen == field))
+            .map(|(_, (field, _))| field.to_string())
+            .collect::<Vec<_>>();
+        duplicate_fields.sort();
+        duplicate_fields.dedup();
+        if !duplicate_fields.is_empty() {
+            return Err(ContainerError::DuplicateFields(duplicate_fields));
+        }
+
+        inferred
+            .iter()
+            .map(|(column_name, data_type)| match inferred_values.iter().find(|(field, _)| field == column_name) {
+                None | Some((_, serde_json::Value::Null)) => Ok((column_name.to_string(), Cell::Null)),
+                Some((_, value)) if data_type.is_compatible(value) => {
+                    Ok((column_name.to_string(), Cell::from_typed_json(value, data_type).unwrap()))
+                }
+                Some((_, value)) => Err(ContainerError::InvalidDataType(column_name.to_string(), value.clone(), data_type.clone())),
+            })
+            .collect()
+    }
+
+    ///Changes whenever columns of the schema get added, renamed, dropped or change their type
+    pub fn schema_version(&self) -> String {
+        SchemaFingerprint::of(&self.config).sha256
+    }
+
+    ///Columns of the list the table doesn't have
+    pub fn missing_columns(&self, columns: &[String]) -> Vec<String> {
+        columns
+            .iter()
+            .filter(|column_name| self.columns.find_column(column_name).is_none())
+            .cloned()
+            .collect()
+    }
+
+    ///Describes all columns of the table, including system columns, in the configured column order
+    pub fn schema(&self) -> TableSchema {
+        let reserved_columns = self.reserved_columns();
+        let mut columns = self
+            .columns
+            .layout()
+            .iter()
+            .map(|(column_name, data_type)| ColumnSchema {
+                name: column_name.to_string(),
+                data_type: data_type.clone(),
+                nullable: self.is_nullable(column_name) || Lineage::is_lineage_column(column_name),
+                system: reserved_columns.contains(&column_name.as_str()),
+                inferred: self.is_inferred(column_name),
+                docs: self.columns.column_docs.get(column_name).cloned().unwrap_or_default(),
+            })
+            .collect::<Vec<_>>();
+        let order = &self.config.column_order;
+        columns.sort_by_key(|column| order.iter().position(|name| name == &column.name).unwrap_or(order.len()));
+        TableSchema {
+            docs: self.columns.docs.clone(),
+            columns,
+        }
+    }
+
+    ///Renames a column of the main table. Declared columns keep their settings under the new name,
+    ///until the next restart reads schema.json again.
+    #[instrument(skip(self))]
+    pub fn rename_column(&mut self, from: &str, to: &str) -> Result<(), ContainerError> {
+        if !self.warm {
+            return Err(ContainerError::WarmingUp);
+        }
+        if self.columns.find_column(from).is_none() {
+            return Err(ContainerError::UnknownColumn(from.to_string()));
+        }
+        let invalid_rename = |reason: &str| ContainerError::InvalidRename {
+            column: from.to_string(),
+            reason: reason.to_string(),
+        };
+        let reserved_columns = self.reserved_columns();
+        if reserved_columns.contains(&from) {
+            return Err(invalid_rename("system columns keep their names"));
+        }
+        if self.config.shard_key.as_deref() == Some(from) {
+            return Err(invalid_rename("it is the shard key"));
+        }
+        if self.config.columns.iter().any(|column_config| column_config.computed && column_config.name == from) {
+            return Err(invalid_rename("the before insert hook fills computed columns by name"));
+        }
+        if !DerivedTables::is_valid_name(to) {
+            return Err(invalid_rename("names may only contain letters, digits, _ and -"));
+        }
+        if self.columns.find_column(to).is_some()
+            || reserved_columns.contains(&to)
+            || Lineage::is_lineage_column(to)
+            || self.pending_computed_columns.iter().any(|column_config| column_config.name == to)
+        {
+            return Err(invalid_rename(&format!("column {} already exists", to)));
+        }
+
+        self.wal.append_rename(from, to)?;
+        self.columns.rename_column(from, to)?;
+        for column_config in self.config.columns.iter_mut().filter(|column_config| column_config.name == from) {
+            column_config.name = to.to_string();
+        }
+        for column_name in self.config.column_order.iter_mut().filter(|column_name| *column_name == from) {
+            *column_name = to.to_string();
+        }
+        if self.config.unique_key.as_deref() == Some(from) {
+            self.config.unique_key = Some(to.to_string());
+        }
+        if self.config.primary_key.as_deref() == Some(from) {
+            self.config.primary_key = Some(to.to_string());
+        }
+        if let Some(dedupe_config) = self.config.dedupe.as_mut().filter(|dedupe_config| dedupe_config.key_column == from) {
+            dedupe_config.key_column = to.to_string();
+        }
+        //Row hashes only cover the values, so they stay the same under the new name
+        for column_config in self.config.columns.iter_mut() {
+            if let Some(ServerComputed::RowHash(columns)) = column_config.server_computed.as_mut() {
+                for column_name in columns.iter_mut().filter(|column_name| *column_name == from) {
+                    *column_name = to.to_string();
+                }
+            }
+        }
+        self.upserts.rename(from, to);
+        SchemaFingerprint::of(&self.config).write(&mut self.schema_fingerprint)?;
+        Ok(())
+    }
+
+    ///Drops a column of the main table along with its files. Stored rows lose their values of the column.
+    #[instrument(skip(self))]
+    pub fn drop_column(&mut self, column_name: &str) -> Result<(), ContainerError> {
+        if !self.warm {
+            return Err(ContainerError::WarmingUp);
+        }
+        if self.columns.find_column(column_name).is_none() {
+            return Err(ContainerError::UnknownColumn(column_name.to_string()));
+        }
+        let invalid_drop = |reason: &str| ContainerError::InvalidDrop {
+            column: column_name.to_string(),
+            reason: reason.to_string(),
+        };
+        if self.reserved_columns().contains(&column_name) {
+            return Err(invalid_drop("system columns can't be dropped"));
+        }
+        let keys = [
+            (self.config.shard_key.as_deref(), "it is the shard key"),
+            (self.config.unique_key.as_deref(), "it is the unique key"),
+            (self.config.primary_key.as_deref(), "it is the primary key"),
+            (self.config.dedupe.as_ref().map(|dedupe_config| dedupe_config.key_column.as_str()), "it is the dedupe key"),
+        ];
+        if let Some((_, reason)) = keys.iter().find(|(key, _)| *key == Some(column_name)) {
+            return Err(invalid_drop(reason));
+        }
+        if self.config.columns.iter().any(|column_config| column_config.computed && column_config.name == column_name) {
+            return Err(invalid_drop("the before insert hook fills computed columns by name"));
+        }
+        let hashed = |kind: &ServerComputed| matches!(kind, ServerComputed::RowHash(columns) if columns.iter().any(|c| c == column_name));
+        if self.server_columns().any(|(_, kind)| hashed(kind)) {
+            return Err(invalid_drop("a RowHash column hashes it"));
+        }
+
+        self.wal.append_drop(column_name)?;
+        self.columns.drop_column(column_name)?;
+        self.config.columns.retain(|column_config| column_config.name != column_name);
+        self.config.column_order.retain(|name| name != column_name);
+        SchemaFingerprint::of(&self.config).write(&mut self.schema_fingerprint)?;
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    fn validate_fields(&self, params: &IndexParams) -> Result<(), ContainerError> {
+        let mut seen_fields = HashSet::new();
+        let mut duplicate_fields = params
+            .fields
+            .iter()
+            .filter(|field| !seen_fields.insert(field.as_str()))
+            .map(|field| field.to_string())
+            .collect::<Vec<_>>();
+        duplicate_fields.sort();
+        duplicate_fields.dedup();
+
+        if !duplicate_fields.is_empty() {
+            return Err(ContainerError::DuplicateFields(duplicate_fields));
+        }
+
+        if self.config.strict_fields {
+            let unknown_fields = self.unknown_fields(params);
+            if !unknown_fields.is_empty() {
+                return Err(ContainerError::InvalidFields(unknown_fields));
+            }
+        }
+
+        let reserved_columns = self.reserved_columns();
+        let reserved = params
+            .fields
+            .iter()
+            .filter(|field| reserved_columns.contains(&field.as_str()))
+            .map(|field| field.to_string())
+            .collect::<Vec<_>>();
+        let missing = self
+            .columns
+            .column_names()
+            .into_iter()
+            .filter(|column_name| !reserved_columns.contains(&column_name.as_str()) && !params.fields.contains(column_name))
+            .collect::<Vec<_>>();
+        let (omitted_nullable, missing): (Vec<_>, Vec<_>) =
+            missing.into_iter().partition(|column_name| self.is_nullable(column_name));
+
+        if !reserved.is_empty() || !missing.is_empty() {
+            return Err(ContainerError::SchemaMismatch { reserved, missing });
+        }
+
+        debug!("Validate Param Field Count. Reserved Columns: {:?}", reserved_columns);
+        if self.columns.len() != params.fields.len() + reserved_columns.len() + omitted_nullable.len() {
+            return Err(ContainerError::FieldCountMismatch(
+                self.columns.len(),
+                params.fields.len(),
+            ));
+        }
+
+        let invalid_fields = self.unknown_fields(params);
+        if !invalid_fields.is_empty() {
+            return Err(ContainerError::InvalidFields(invalid_fields));
+        }
+
+        if params.fields.len() != params.values.len() {
+            return Err(ContainerError::FieldCountMismatch(
+                params.fields.len(),
+                params.values.len(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    ///Stores a new row. Returns the row's id, which doubles as commit sequence number.
+    #[instrument(skip(self))]
+    pub fn index(&mut self, params: IndexParams) -> Result<i64, ContainerError> {
+        self.index_with_lineage(params, &Lineage::default())
+    }
+
+    ///Stores a new row along with where it came from
+    #[instrument(skip(self))]
+    pub fn index_with_lineage(&mut self, params: IndexParams, lineage: &Lineage) -> Result<i64, ContainerError> {
+        self.check_disk_pressure()?;
+        let params = self.infer_columns(params, true)?;
+        let key = self.dedupe_key(&params);
+        if let Some(id) = self.duplicate_of(key.as_ref()) {
+            debug!("Dropping redelivered row {}", id);
+            self.count_dedupe_conflict();
+            return Ok(id);
+        }
+        if let Some(position) = self.existing_row(&params)? {
+            let id = self.id_at(position);
+            self.replace_row(position, id, params)?;
+            self.count_unique_key_conflict();
+            return Ok(id);
+        }
+        let to_be_inserted = self.prepare_row(params, lineage)?;
+        if let Err(err) = self.check_unique_columns(&to_be_inserted, None, &mut HashSet::new()) {
+            self.rollback();
+            return Err(err);
+        }
+        let id = self.index_counter.counter();
+        self.commit(to_be_inserted)?;
+        self.record_key(key, id);
+        self.count_fresh();
+        Ok(id)
+    }
+
+    ///Counts an inserted row as fresh for the dedupe key column and the unique key
+    fn count_fresh(&mut self) {
+        let dedupe_column = self.config.dedupe.as_ref().map(|dedupe_config| dedupe_config.key_column.as_str());
+        if let Some(column_name) = dedupe_column {
+            self.upserts.record_fresh(column_name);
+        }
+        if let Some(unique_key) = self.config.unique_key.as_deref().filter(|unique_key| Some(*unique_key) != dedupe_column) {
+            self.upserts.record_fresh(unique_key);
+        }
+    }
+
+    ///Counts rows which failed after their client got `202` for ack=received, so it never learned about it
+    pub fn count_unacknowledged_failure(&mut self, rows: usize) {
+        self.unacknowledged_failures += rows as u64;
+    }
+
+    fn count_dedupe_conflict(&mut self) {
+        if let Some(dedupe_config) = &self.config.dedupe {
+            self.upserts.record_conflict(&dedupe_config.key_column);
+        }
+    }
+
+    fn count_unique_key_conflict(&mut self) {
+        if let Some(unique_key) = &self.config.unique_key {
+            self.upserts.record_conflict(unique_key);
+        }
+    }
+
+    ///Value of the field, converted to the type of the column of the same name
+    fn field_value(&self, params: &IndexParams, column_name: &str) -> Option<Cell> {
+        let position = params.fields.iter().position(|field| field == column_name)?;
+        let data_type = self.columns.find_column(column_name)?.data_type();
+        Cell::from_typed_json(params.values.get(position)?, data_type)
+    }
+
+    ///Value of the dedupe key column in the row, if deduplication is configured
+    fn dedupe_key(&self, params: &IndexParams) -> Option<Cell> {
+        self.field_value(params, &self.config.dedupe.as_ref()?.key_column)
+    }
+
+    ///Position of the row holding the same unique key as the insert, which the insert replaces
+    fn existing_row(&self, params: &IndexParams) -> Result<Option<usize>, ContainerError> {
+        let Some(unique_key) = &self.config.unique_key else {
+            return Ok(None);
+        };
+        //Rows stored before startup aren't indexed yet
+        if !self.warm {
+            return Err(ContainerError::WarmingUp);
+        }
+        Ok(self.field_value(params, unique_key).and_then(|key| self.columns.find_key(&key)))
+    }
+
+    ///Rejects rows of a transaction which would replace a row, since its log record can only append rows
+    fn check_unique_key(&self, params: &IndexParams, unique_keys: &mut HashSet<(u8, ByteString)>) -> Result<(), ContainerError> {
+        let Some(unique_key) = &self.config.unique_key else {
+            return Ok(());
+        };
+        if self.existing_row(params)?.is_some() {
+            return Err(ContainerError::DuplicateKey(unique_key.to_string()));
+        }
+        let key = self.field_value(params, unique_key).and_then(|key| key.hash_key());
+        if key.is_some_and(|key| !unique_keys.insert(key)) {
+            return Err(ContainerError::DuplicateKey(unique_key.to_string()));
+        }
+        Ok(())
+    }
+
+    ///Rejects values of unique columns which another live row, or an earlier row of the same transaction, holds.
+    ///The row at the position `replaced` doesn't count, it's the old version of an updated row.
+    fn check_unique_columns(
+        &self,
+        values: &[(String, Cell)],
+        replaced: Option<usize>,
+        transaction_values: &mut HashSet<(String, (u8, ByteString))>,
+    ) -> Result<(), ContainerError> {
+        if !self.columns.has_unique_columns() {
+            return Ok(());
+        }
+        //Rows stored before startup aren't indexed yet
+        if !self.warm {
+            return Err(ContainerError::WarmingUp);
+        }
+        for (column_name, cell) in values.iter().filter(|(column_name, _)| self.columns.is_unique(column_name)) {
+            let held_by_other_row = self.columns.find_unique(column_name, cell).is_some_and(|position| Some(position) != replaced);
+            let held_by_transaction = cell.hash_key().is_some_and(|key| !transaction_values.insert((column_name.to_string(), key)));
+            if held_by_other_row || held_by_transaction {
+                return Err(ContainerError::UniqueViolation {
+                    column: column_name.to_string(),
+                    value: serde_json::to_string(cell).unwrap_or_default(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn id_at(&self, position: usize) -> i64 {
+        match self.columns.find_column("id").map(|ids| &ids.entries()[position]) {
+            Some(Cell::Int(id)) => *id,
+            _ => panic!("Columns Corrupted. Row {} has no id", position),
+        }
+    }
+
+    ///Id of the row which arrived with the same key within the dedupe window
+    fn duplicate_of(&mut self, key: Option<&Cell>) -> Option<i64> {
+        self.dedupe.as_mut()?.duplicate_of(key?, Instant::now())
+    }
+
+    fn record_key(&mut self, key: Option<Cell>, id: i64) {
+        if let (Some(dedupe), Some(key)) = (self.dedupe.as_mut(), key) {
+            dedupe.record(&key, id, Instant::now());
+        }
+    }
+
+    ///Warnings about values which lose precision when converted to their column's type. Timestamp columns only
+    ///hold whole seconds, so dates with fractions of a second get cut.
+    pub fn coerced_values(&self, params: &IndexParams) -> Vec<Warning> {
+        let mut warnings = vec![];
+        for (field, value) in params.fields.iter().zip(&params.values) {
+            let Some(column) = self.columns.find_column(field) else {
+                continue;
+            };
+            let dates = match (column.data_type(), value) {
+                (DataType::Timestamp, serde_json::Value::String(date)) => vec![date.as_str()],
+                (DataType::Array(inner), serde_json::Value::Array(values)) if **inner == DataType::Timestamp => {
+                    values.iter().filter_map(|value| value.as_str()).collect()
+                }
+                _ => vec![],
+            };
+            for date in dates.into_iter().filter(|date| Cell::drops_fraction(date)) {
+                warnings.push(Warning::new(
+                    WarningKind::Coerced,
+                    format!("{}: {} got cut to whole seconds", field, date),
+                ));
+            }
+        }
+        warnings
+    }
+
+    ///Runs the same checks as `index`, without storing the row or using up an id
+    #[instrument(skip(self))]
+    pub fn validate(&mut self, params: IndexParams) -> Result<(), ContainerError> {
+        let params = self.infer_columns(params, false)?;
+        let values = self.prepare_row(params, &Lineage::default())?;
+        self.rollback();
+        self.check_unique_columns(&values, None, &mut HashSet::new())
+    }
+
+    ///Stores rows of a bulk load. All rows get validated before any of them is written, then they get committed
+    ///with a single write per column and one update of the id counter. Returns the ids of the stored rows.
+    #[instrument(skip(self, rows))]
+    pub fn index_batch(&mut self, rows: Vec<IndexParams>) -> Result<Vec<i64>, ContainerError> {
+        Ok(self.index_transaction(rows)?.ids)
+    }
+
+    ///Stores either all rows or none of them. Returns the ids of the stored rows.
+    #[instrument(skip(self))]
+    pub fn index_transaction(&mut self, rows: Vec<IndexParams>) -> Result<CommittedTransaction, ContainerError> {
+        self.index_transaction_with_lineage(rows, &Lineage::default())
+    }
+
+    ///Stores either all rows or none of them, all with the same lineage
+    #[instrument(skip(self))]
+    pub fn index_transaction_with_lineage(
+        &mut self,
+        rows: Vec<IndexParams>,
+        lineage: &Lineage,
+    ) -> Result<CommittedTransaction, ContainerError> {
+        self.check_disk_pressure()?;
+        //Rows stored before startup aren't indexed yet
+        if self.config.unique_key.is_some() && !self.warm {
+            return Err(ContainerError::WarmingUp);
+        }
+        //Columns only get added once every row turned out valid, all of them hold a cell for every inferred column
+        let mut inferred = vec![];
+        for params in &rows {
+            self.infer_column_types(params, &mut inferred)?;
+        }
+        if !inferred.is_empty() && !self.warm {
+            return Err(ContainerError::WarmingUp);
+        }
+        let rows = rows
+            .into_iter()
+            .map(|params| self.split_inferred(params, &inferred))
+            .collect::<Vec<_>>();
+        let last_committed_id = self.index_counter.counter();
+        let mut prepared_rows = vec![];
+        let mut ids = vec![];
+        //Keys of this transaction's rows, which only enter the dedupe window once it got committed
+        let mut keys: Vec<(Cell, i64)> = vec![];
+        let mut unique_keys = HashSet::new();
+        let mut unique_values = HashSet::new();
+        let mut conflicts = 0;
+        let mut warnings = vec![];
+        for (row, (params, inferred_values)) in rows.into_iter().enumerate() {
+            let key = self.dedupe_key(&params);
+            let duplicate_of = self.duplicate_of(key.as_ref()).or_else(|| {
+                keys.iter()
+                    .find(|(transaction_key, _)| Some(transaction_key) == key.as_ref())
+                    .map(|(_, id)| *id)
+            });
+            if let Some(id) = duplicate_of {
+                ids.push(id);
+                conflicts += 1;
+                continue;
+            }
+            let coerced = self.coerced_values(&params);
+            let prepared = self
+                .check_unique_key(&params, &mut unique_keys)
+                .and_then(|_| Container::inferred_cells(inferred_values, &inferred))
+                .and_then(|cells| {
+                    self.prepare_row(params, lineage).map(|mut values| {
+                        values.extend(cells);
+                        values
+                    })
+                })
+                .and_then(|values| self.check_unique_columns(&values, None, &mut unique_values).map(|_| values));
+            match prepared {
+                Ok(values) => {
+                    let id = self.index_counter.counter();
+                    ids.push(id);
+                    if let Some(key) = key {
+                        keys.push((key, id));
+                    }
+                    warnings.extend(coerced.into_iter().map(|warning| Warning {
+                        message: format!("Row {}: {}", row, warning.message),
+                        ..warning
+                    }));
+                    prepared_rows.push(values)
+                }
+                Err(err) => {
+                    self.index_counter.reset_to(last_committed_id);
+                    if matches!(err, ContainerError::DuplicateKey(_)) {
+                        self.count_unique_key_conflict();
+                    }
+                    return Err(ContainerError::TransactionAborted {
+                        row,
+                        source: Box::new(err),
+                    });
+                }
+            }
+        }
+
+        for _ in 0..conflicts {
+            self.count_dedupe_conflict();
+        }
+        if prepared_rows.is_empty() {
+            return Ok(CommittedTransaction { ids, seq: self.write_seq(), conflicts, warnings });
+        }
+        if let Err(err) = self.add_inferred_columns(&inferred) {
+            self.index_counter.reset_to(last_committed_id);
+            return Err(err);
+        }
+        let prepared_count = prepared_rows.len();
+        self.commit_batch(prepared_rows)?;
+        for (key, id) in keys {
+            self.record_key(Some(key), id);
+        }
+        for _ in 0..prepared_count {
+            self.count_fresh();
+        }
+        Ok(CommittedTransaction { ids, seq: self.write_seq(), conflicts, warnings })
+    }
+
+    ///Validates the row and assigns it the next id, which gets rolled back if the row turns out to be invalid
+    fn prepare_row(&mut self, params: IndexParams, lineage: &Lineage) -> Result<Vec<(String, Cell)>, ContainerError> {
+        let params = self.with_defaults(self.without_pending_columns(params));
+        self.validate_fields(&params)?;
+
+        let mut to_be_inserted = vec![];
+
+        let id = self.index_counter.next();
+        to_be_inserted.push(("id".to_string(), Cell::Int(id)));
+
+        if self.config.add_timestamp_column {
+            let timestamp = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs();
+            if let Some(timestamp_column) = self.columns.timestamp_column() {
+                //Tables created before the timestamp type existed keep an Int column
+                let cell = match timestamp_column.data_type() {
+                    DataType::Timestamp => Cell::Timestamp(timestamp as i64),
+                    _ => Cell::Int(timestamp as i64),
+                };
+                to_be_inserted.push(("timestamp".to_string(), cell));
+            } else {
+                error!(
+                    "Failed to insert timestamp for {:?} params. Couldn't find Column",
+                    params
+                );
+                return Err(ContainerError::MissingTimestampColumn);
+            }
+        }
+
+        for (column_name, _) in LINEAGE_COLUMNS {
+            if self.columns.find_column(column_name).is_some() {
+                let cell = if self.config.lineage { lineage.cell(column_name) } else { Cell::Null };
+                to_be_inserted.push((column_name.to_string(), cell));
+            }
+        }
+
+        for (index, column_name) in params.fields.iter().enumerate() {
+            let column_value = params.values.get(index).unwrap();
+            let db_column = self.columns.find_column(column_name).unwrap();
+            let db_column_data_type = db_column.data_type().clone();
+            if column_value.is_null() && self.is_nullable(column_name) {
+                to_be_inserted.push((column_name.to_owned(), Cell::Null));
+            } else if db_column.data_type().is_compatible(column_value) {
+                debug!("Store value {} for column {}", column_value, column_name);
+                //We assume this conversion always works because we checked in the if statement above if the type is compatible
+                let cell = Cell::from_typed_json(column_value, &db_column_data_type).unwrap();
+                to_be_inserted.push((column_name.to_owned(), cell));
+            } else {
+                self.rollback();
+                return Err(ContainerError::InvalidDataType(
+                    column_name.to_string(),
+                    column_value.clone(),
+                    db_column_data_type,
+                ));
+            }
+        }
+
+        self.fill_server_columns(&mut to_be_inserted);
+
+        //Omitted nullable columns are stored as null, so every column still holds a cell for every row
+        for column_name in self.columns.column_names() {
+            if !to_be_inserted.iter().any(|(name, _)| name == &column_name) && self.is_nullable(&column_name) {
+                to_be_inserted.push((column_name, Cell::Null));
+            }
+        }
+
+        Ok(to_be_inserted)
+    }
+
+    ///Opens the table stored at root_path, creating it with this table's columns if it doesn't exist yet.
+    ///Derived tables neither run hooks nor enforce retention.
+    #[instrument(skip(self))]
+    pub fn derive(&self, root_path: &PathBuf) -> Result<Container, ContainerError> {
+        if !CheckedFile::exists(&ColumnLayout::file_path(root_path)) {
+            info!("Creating derived table");
+            fs::create_dir_all(root_path)?;
+            let backend = backend::open(&self.config.storage_backend, root_path)?;
+            let mut column_layout = ColumnLayout::new(root_path, self.config.write_buffer_size, backend);
+            for (column_name, data_type) in self.columns.layout() {
+                column_layout.insert_column(column_layout.new_column(column_name, data_type.to_owned())?)?;
+            }
+            column_layout.persist_layout()?;
+        }
+
+        Container::new(root_path, self.derived_config())
+    }
+
+    ///Config of tables holding rows which already went through this table's inserts
+    fn derived_config(&self) -> SchemaConfig {
+        SchemaConfig {
+            columns: self
+                .config
+                .columns
+                .iter()
+                .map(|column_config| ColumnConfig {
+                    computed: false,
+                    server_computed: None,
+                    indexed: false,
+                    unique: false,
+                    ..column_config.clone()
+                })
+                .collect(),
+            before_insert_hook: None,
+            shard_key: None,
+            retention_secs: None,
+            history_secs: None,
+            dedupe: None,
+            unique_key: None,
+            primary_key: None,
+            ..self.config.clone()
+        }
+    }
+
+    ///Rejects points the table can't be read at
+    pub fn check_as_of(&self, as_of: AsOf) -> Result<(), FilterError> {
+        let AsOf::Time(timestamp) = as_of else {
+            return Ok(());
+        };
+        if !self.config.add_timestamp_column {
+            return Err(FilterError::MissingTimestampColumn);
+        }
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        match self.config.history_secs {
+            Some(history_secs) if timestamp < now.saturating_sub(history_secs) as i64 => Err(FilterError::OutsideHistory(history_secs)),
+            _ => Ok(()),
+        }
+    }
+
+    ///Rebuilds the table as it was at the point by replaying the write-ahead log into `as_of/`,
+    ///replacing whatever an earlier call left there. Opened like a derived table.
+    #[instrument(skip(self))]
+    pub fn as_of(&self, as_of: AsOf) -> Result<Container, ContainerError> {
+        let root_path = self.columns.db_root_path.join("as_of");
+        if root_path.exists() {
+            fs::remove_dir_all(&root_path)?;
+        }
+        fs::create_dir_all(&root_path)?;
+        let backend = backend::open(&self.config.storage_backend, &root_path)?;
+        let records = Wal::read_all(&self.columns.db_root_path)?;
+        let mut column_layout = Container::replay_wal(records, &root_path, backend, |values| as_of.is_before(values))?.columns;
+        column_layout.flush()?;
+        column_layout.persist_layout()?;
+        drop(column_layout);
+        Container::new(&root_path, self.derived_config())
+    }
+
+    ///Appends rows read from a table with the same columns, keeping their ids and timestamps
+    #[instrument(skip(self, rows))]
+    pub fn append_rows(&mut self, rows: Vec<ColumnFrame>) -> Result<usize, ContainerError> {
+        let row_count = rows.len();
+        for row in rows {
+            let column_names = self.columns.column_names();
+            let unknown_fields = row
+                .column_names()
+                .iter()
+                .filter(|column_name| !column_names.contains(column_name))
+                .cloned()
+                .collect::<Vec<_>>();
+            if !unknown_fields.is_empty() {
+                return Err(ContainerError::InvalidFields(unknown_fields));
+            }
+
+            let mut values = vec![];
+            let mut missing = vec![];
+            for column_name in column_names {
+                match row.get(&column_name) {
+                    Some(cell) => values.push((column_name, cell.to_owned())),
+                    None => missing.push(column_name),
+                }
+            }
+            if !missing.is_empty() {
+                return Err(ContainerError::SchemaMismatch {
+                    reserved: vec![],
+                    missing,
+                });
+            }
+
+            if let Some((_, Cell::Int(id))) = values.iter().find(|(column_name, _)| column_name == "id") {
+                if *id > self.index_counter.counter() {
+                    self.index_counter.reset_to(*id);
+                }
+            }
+            self.commit(values)?;
+        }
+        Ok(row_count)
+    }
+
+    ///Drops values for computed columns which don't exist until the next backfill
+    fn without_pending_columns(&self, params: IndexParams) -> IndexParams {
+        if self.pending_computed_columns.is_empty() {
+            return params;
+        }
+        let (fields, values) = params
+            .fields
+            .into_iter()
+            .zip(params.values)
+            .filter(|(field, _)| !self.pending_computed_columns.iter().any(|c| &c.name == field))
+            .unzip();
+        IndexParams { fields, values }
+    }
+
+    ///Adds the schema's default, or a generated UUID, for every column the insert leaves out
+    fn with_defaults(&self, mut params: IndexParams) -> IndexParams {
+        for column_config in &self.config.columns {
+            if params.fields.contains(&column_config.name) || self.columns.find_column(&column_config.name).is_none() {
+                continue;
+            }
+            let data_type: DataType = column_config.data_type.to_owned().into();
+            let value = match &column_config.default {
+                _ if column_config.auto_generate && data_type == DataType::Uuid => {
+                    serde_json::Value::String(Cell::format_uuid(Cell::new_uuid()))
+                }
+                Some(default) => default.clone(),
+                None => continue,
+            };
+            params.fields.push(column_config.name.to_string());
+            params.values.push(value);
+        }
+        params
+    }
+
+    ///Adds all pending computed columns, filling them for existing rows with the values `compute` returns.
+    ///`compute` receives each row the way a client would have inserted it.
+    #[instrument(skip(self, compute))]
+    pub fn backfill_computed_columns<F>(&mut self, mut compute: F) -> Result<BackfillReport, ContainerError>
+    where
+        F: FnMut(IndexParams) -> Result<IndexParams, ContainerError>,
+    {
+        if !self.warm {
+            return Err(ContainerError::WarmingUp);
+        }
+        let pending = self.pending_computed_columns.clone();
+        //Deleted rows need a cell as well, to keep the new columns in line with the existing ones
+        let rows = self.columns.rows((0..self.columns.row_count()).collect());
+        let reserved_columns = self.reserved_columns();
+        let mut computed_cells: Vec<Vec<Cell>> = vec![Vec::with_capacity(rows.len()); pending.len()];
+
+        for row in &rows {
+            let mut row = row.to_view_object();
+            let mut params = IndexParams {
+                fields: vec![],
+                values: vec![],
+            };
+            for (column_name, _) in self.columns.layout() {
+                if reserved_columns.contains(&column_name.as_str()) {
+                    continue;
+                }
+                let cell = row.remove(column_name).unwrap();
+                params.fields.push(column_name.to_owned());
+                params.values.push(serde_json::to_value(cell).unwrap());
+            }
+
+            let computed = compute(params)?;
+            for (n, column_config) in pending.iter().enumerate() {
+                let data_type: DataType = column_config.data_type.to_owned().into();
+                let value = computed
+                    .fields
+                    .iter()
+                    .position(|field| field == &column_config.name)
+                    .and_then(|position| computed.values.get(position))
+                    .filter(|value| !value.is_null());
+                let value = match value {
+                    Some(value) => value,
+                    None if column_config.nullable => {
+                        computed_cells[n].push(Cell::Null);
+                        continue;
+                    }
+                    None => {
+                        return Err(ContainerError::SchemaMismatch {
+                            reserved: vec![],
+                            missing: vec![column_config.name.to_owned()],
+                        })
+                    }
+                };
+                if !data_type.is_compatible(value) {
+                    return Err(ContainerError::InvalidDataType(
+                        column_config.name.to_owned(),
+                        value.clone(),
+                        data_type,
+                    ));
+                }
+                computed_cells[n].push(Cell::from_typed_json(value, &data_type).unwrap());
+            }
+        }
+
+        for (column_config, cells) in pending.iter().zip(computed_cells) {
+            info!("Backfilling computed column {}", column_config.name);
+            self.add_column(&column_config.name, column_config.data_type.to_owned().into(), cells)?;
+        }
+        self.columns.persist_layout()?;
+        self.pending_computed_columns.clear();
+
+        Ok(BackfillReport {
+            columns: pending.into_iter().map(|column_config| column_config.name).collect(),
+            rows: rows.len(),
+        })
+    }
+
+    ///Adds a column to a table with existing rows, holding one cell per existing row.
+    ///The caller persists the layout.
+    fn add_column(&mut self, column_name: &str, data_type: DataType, cells: Vec<Cell>) -> Result<(), ContainerError> {
+        self.wal.append_column(column_name, &data_type, &cells)?;
+        let mut column = Container::empty_column(&self.columns, column_name, data_type)?;
+        column.fill(cells, &self.columns.segments)?;
+        column.flush()?;
+        self.columns.insert_column(column)?;
+        Ok(())
+    }
+
+    ///Adds the lineage columns which don't exist yet. Existing rows get null.
+    #[instrument(skip(self))]
+    fn add_lineage_columns(&mut self) -> Result<(), ContainerError> {
+        let missing = LINEAGE_COLUMNS
+            .iter()
+            .filter(|(column_name, _)| self.columns.find_column(column_name).is_none())
+            .collect::<Vec<_>>();
+        if missing.is_empty() {
+            return Ok(());
+        }
+        let row_count = self.columns.row_count();
+        for (column_name, data_type) in missing {
+            info!("Adding lineage column {}", column_name);
+            self.add_column(column_name, data_type.clone(), vec![Cell::Null; row_count])?;
+        }
+        self.columns.persist_layout()?;
+        Ok(())
+    }
+
+    ///Deletes all rows older than the configured retention. A dry run only reports what would get deleted.
+    pub fn retention(&mut self, dry_run: bool) -> Result<RetentionReport, ContainerError> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        self.retention_at(now as i64, dry_run)
+    }
+
+    #[instrument(skip(self))]
+    fn retention_at(&mut self, now: i64, dry_run: bool) -> Result<RetentionReport, ContainerError> {
+        let retention_secs = self.config.retention_secs.ok_or(ContainerError::RetentionNotConfigured)?;
+        if !self.warm {
+            return Err(ContainerError::WarmingUp);
+        }
+        let cutoff = now - retention_secs as i64;
+        let expired = self.columns.expired_rows(cutoff)?;
+        let columns = self.columns.bytes_per_column(&expired)?;
+        let report = RetentionReport {
+            dry_run,
+            ran_at: now,
+            cutoff,
+            rows: expired.len(),
+            bytes: columns.iter().map(|column| column.bytes).sum(),
+            columns,
+        };
+
+        if dry_run {
+            return Ok(report);
+        }
+        if !expired.is_empty() {
+            info!("Retention deletes {} rows older than {}", expired.len(), cutoff);
+            self.wal.append_expire(cutoff)?;
+            if self.columns.is_segmented() {
+                self.columns.expire_rows(&expired)?;
+            } else {
+                self.columns.remove_rows(&expired)?;
+            }
+        }
+        self.last_retention_report = Some(report.clone());
+        Ok(report)
+    }
+
+    ///Rewrites the column files without deleted rows and replaced versions of updated rows, once there are
+    ///at least min_deleted_rows of them. Deletes and updates are logged by id, so the write-ahead log
+    ///doesn't need to know rows moved.
+    #[instrument(skip(self))]
+    pub fn compact(&mut self, min_deleted_rows: usize) -> Result<CompactionReport, ContainerError> {
+        if !self.warm {
+            return Err(ContainerError::WarmingUp);
+        }
+        let mut rows = self.columns.deleted_rows().len();
+        let bytes_before = self.columns.file_bytes();
+        if rows > 0 && rows >= min_deleted_rows {
+            info!("Compaction drops {} deleted rows", rows);
+            self.columns.remove_rows(&[])?;
+        } else {
+            rows = 0;
+        }
+        let ran_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        Ok(CompactionReport {
+            ran_at: ran_at as i64,
+            rows,
+            bytes_before,
+            bytes_after: self.columns.file_bytes(),
+        })
+    }
+
+    ///Deletes the row with the id. It stays in the column files, marked by tombstones, until retention or compaction rewrites them.
+    #[instrument(skip(self))]
+    pub fn delete(&mut self, id: i64) -> Result<(), ContainerError> {
+        if !self.warm {
+            return Err(ContainerError::WarmingUp);
+        }
+        let position = self.columns.find_row(id).ok_or(ContainerError::UnknownRow(id))?;
+        self.wal.append_delete(id)?;
+        self.columns.delete_row(position)?;
+        if self.config.flush_policy == FlushPolicy::EveryCommit {
+            self.columns.flush()?;
+        }
+        Ok(())
+    }
+
+    ///Replaces the values of the row with the id, checked like an insert. The new version gets appended
+    ///with the old version's system columns, like its id and timestamp, and the old version gets tombstones.
+    #[instrument(skip(self))]
+    pub fn update(&mut self, id: i64, params: IndexParams) -> Result<(), ContainerError> {
+        if !self.warm {
+            return Err(ContainerError::WarmingUp);
+        }
+        let position = self.columns.find_row(id).ok_or(ContainerError::UnknownRow(id))?;
+        self.check_disk_pressure()?;
+        let params = self.infer_columns(params, true)?;
+        self.replace_row(position, id, params)
+    }
+
+    ///Stores a new version of the row at the position, which keeps the id and the other system columns
+    fn replace_row(&mut self, position: usize, id: i64, params: IndexParams) -> Result<(), ContainerError> {
+        let mut values = self.prepare_row(params, &Lineage::default())?;
+        //The new version keeps the id, prepare_row handed out a new one
+        self.rollback();
+
+        let old_version = self.columns.rows(vec![position]).remove(0);
+        let reserved_columns = self.reserved_columns();
+        for (column_name, cell) in values.iter_mut() {
+            if reserved_columns.contains(&column_name.as_str()) {
+                if let Some(old_cell) = old_version.get(column_name) {
+                    *cell = old_cell.to_owned();
+                }
+            }
+        }
+        //From the new values, but the old timestamp
+        self.fill_server_columns(&mut values);
+        if let Some(shard_key) = &self.config.shard_key {
+            let new_value = values.iter().find(|(column_name, _)| column_name == shard_key).map(|(_, cell)| cell);
+            if new_value != old_version.get(shard_key) {
+                return Err(ContainerError::ShardKeyChanged(shard_key.to_string()));
+            }
+        }
+
+        if let Some(unique_key) = &self.config.unique_key {
+            let key = values.iter().find(|(column_name, _)| column_name == unique_key).map(|(_, cell)| cell);
+            if key.and_then(|key| self.columns.find_key(key)).is_some_and(|other| other != position) {
+                return Err(ContainerError::DuplicateKey(unique_key.to_string()));
+            }
+        }
+        self.check_unique_columns(&values, Some(position), &mut HashSet::new())?;
+
+        //A single record, so a crash either keeps both the tombstones and the new version or neither
+        self.wal.append_update(id, &values)?;
+        self.roll_over_if_new_period(&values)?;
+        self.columns.replace_row(position, values)?;
+        self.roll_over_if_full()?;
+        if self.config.flush_policy == FlushPolicy::EveryCommit {
+            self.columns.flush()?;
+        }
+        Ok(())
+    }
+
+    ///Scans the id column for gaps, duplicates and ids stored out of order
+    pub fn id_diagnostics(&self) -> Result<IdDiagnostics, ContainerError> {
+        if !self.warm {
+            return Err(ContainerError::WarmingUp);
+        }
+        let empty = ColumnEntries::default();
+        let ids = self.columns.find_column("id").map(|column| column.entries()).unwrap_or(&empty);
+        Ok(IdDiagnostics::scan(ids, &self.columns.deleted_rows(), self.committed_seq()))
+    }
+
+    ///Gives every live row holding the same id as a row stored before it a new id.
+    ///Returns the diagnostics after the repair.
+    #[instrument(skip(self))]
+    pub fn reassign_duplicate_ids(&mut self) -> Result<IdDiagnostics, ContainerError> {
+        let diagnostics = self.id_diagnostics()?;
+        if diagnostics.duplicates.is_empty() {
+            return Ok(diagnostics);
+        }
+        //New ids must not collide with ids handed out before the auto index lost track of them
+        let last_id = diagnostics.max_id.unwrap_or_default().max(self.committed_seq());
+        self.index_counter.reset_to(last_id);
+
+        let deleted = self.columns.deleted_rows();
+        let empty = ColumnEntries::default();
+        let ids = self.columns.find_column("id").map(|column| column.entries()).unwrap_or(&empty);
+        let mut seen = HashSet::new();
+        let duplicate_positions = (0..ids.len())
+            .filter(|n| !deleted.contains(n))
+            .filter(|n| matches!(ids[*n], Cell::Int(id) if !seen.insert(id)))
+            .collect::<Vec<_>>();
+        let new_ids = duplicate_positions
+            .into_iter()
+            .map(|n| (n, self.index_counter.next()))
+            .collect::<Vec<_>>();
+        warn!("Reassigning ids of {} rows with duplicate ids", new_ids.len());
+
+        self.wal.append_reassign(&new_ids)?;
+        self.columns.reassign_ids(&new_ids)?;
+        self.index_counter.commit()?;
+        self.id_diagnostics()
+    }
+
+    ///Report of the last retention run which actually deleted data
+    pub fn last_retention_report(&self) -> Option<RetentionReport> {
+        self.last_retention_report.clone()
+    }
+
+    ///Bytes of the write-ahead log, see `WalShipper::ship`
+    pub fn wal_len(&self) -> u64 {
+        self.wal.len()
+    }
+
+    ///Sequence number of the last committed row
+    pub fn committed_seq(&self) -> i64 {
+        self.index_counter.counter()
+    }
+
+    ///Sequence number of the last committed write, handed out to clients to read their writes.
+    ///Unlike `committed_seq` it also grows with updates and deletes, it's the length of the write-ahead log.
+    pub fn write_seq(&self) -> i64 {
+        self.wal.len() as i64
+    }
+
+    #[instrument(skip(self))]
+    fn commit(&mut self, values: Vec<(String, Cell)>) -> Result<(), ContainerError> {
+        self.roll_over_if_new_period(&values)?;
+        self.append_atomically(1, |container| {
+            container.wal.append_row(&values)?;
+            container.columns.commit(values)?;
+            container.finish_append()
+        })?;
+        self.roll_over_if_full()
+    }
+
+    ///Stores validated rows with a single log record, a single write per column and one update of the id counter
+    fn commit_batch(&mut self, rows: Vec<Vec<(String, Cell)>>) -> Result<(), ContainerError> {
+        self.append_atomically(rows.len(), |container| {
+            //A single record, so a crash either keeps the whole batch in the log or none of it
+            container.wal.append_transaction(&rows)?;
+            container.commit_runs(rows)?;
+            container.finish_append()
+        })?;
+        self.roll_over_if_full()
+    }
+
+    ///Appends runs of rows from the same period, so a partitioned table rolls over between them.
+    ///A rollback reopens the segment the first run went into.
+    fn commit_runs(&mut self, rows: Vec<Vec<(String, Cell)>>) -> Result<(), ContainerError> {
+        let mut rows = rows.into_iter().peekable();
+        while let Some(first) = rows.next() {
+            let period = self.columns.period(&first);
+            self.roll_over_if_new_period(&first)?;
+            let mut run = vec![first];
+            while let Some(values) = rows.next_if(|values| self.columns.period(values) == period) {
+                run.push(values);
+            }
+            self.columns.commit_batch(run)?;
+        }
+        Ok(())
+    }
+
+    fn finish_append(&mut self) -> Result<(), ContainerError> {
+        if self.config.flush_policy == FlushPolicy::EveryCommit {
+            self.columns.flush()?;
+        }
+        self.index_counter.commit()?;
+        Ok(())
+    }
+
+    ///Runs the appends of rows which already got their ids. If any of them fails, the log, the columns and
+    ///the id counter get rolled back, so none of the rows stays behind, not even in some of the columns.
+    fn append_atomically<F>(&mut self, rows: usize, append: F) -> Result<(), ContainerError>
+    where
+        F: FnOnce(&mut Self) -> Result<(), ContainerError>,
+    {
+        let wal_len = self.wal.len();
+        let savepoint = self.columns.savepoint()?;
+        let last_committed_id = self.index_counter.counter() - rows as i64;
+        let Err(err) = append(self) else {
+            return Ok(());
+        };
+        error!("Rolling back {} rows: {}", rows, err);
+        self.index_counter.reset_to(last_committed_id);
+        //Both get rolled back even if the other one fails
+        let wal_result = self.wal.truncate(wal_len);
+        self.columns.rollback_to(savepoint)?;
+        wal_result?;
+        Err(err)
+    }
+
+    ///Rows stored before startup aren't counted until the columns are warm, so segments only roll over afterwards
+    fn roll_over_if_full(&mut self) -> Result<(), ContainerError> {
+        if self.warm {
+            self.columns.roll_over_if_full()?;
+        }
+        Ok(())
+    }
+
+    fn roll_over_if_new_period(&mut self, values: &[(String, Cell)]) -> Result<(), ContainerError> {
+        if self.warm {
+            self.columns.roll_over_if_new_period(values)?;
+        }
+        Ok(())
+    }
+
+    ///Waits until every committed row is on disk, at least in the write-ahead log
+    #[instrument(skip(self))]
+    pub fn sync(&mut self) -> Result<(), ContainerError> {
+        let started = Instant::now();
+        self.wal.sync()?;
+        if let Some(disk_pressure) = self.disk_pressure.as_mut() {
+            disk_pressure.record_sync(started.elapsed());
+        }
+        Ok(())
+    }
+
+    ///Rejects writes while the volume is nearly full or slow, see `DiskPressure`
+    fn check_disk_pressure(&mut self) -> Result<(), ContainerError> {
+        match self.disk_pressure.as_mut() {
+            Some(disk_pressure) => disk_pressure.check(),
+            None => Ok(()),
+        }
+    }
+
+    ///Writes all buffered column records to disk
+    #[instrument(skip(self))]
+    pub fn flush(&mut self) -> Result<(), ContainerError> {
+        self.columns.flush()?;
+        Ok(())
+    }
+
+    pub fn snapshots_path(db_root_path: &Path) -> PathBuf {
+        db_root_path.join("snapshots")
+    }
+
+    ///Copies the table files and the write-ahead log into a new directory below `snapshots`, named after the current time,
+    ///and returns its path. Commands of the storage layer run one at a time, so no insert lands while the files get copied.
+    #[instrument(skip(self))]
+    pub fn snapshot(&mut self) -> Result<PathBuf, ContainerError> {
+        if !matches!(self.config.storage_backend, StorageBackendConfig::Local) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "The column files aren't stored on the local disk, back up the bucket instead",
+            )
+            .into());
+        }
+        self.flush()?;
+        self.wal.sync()?;
+        let snapshots_path = Container::snapshots_path(&self.columns.db_root_path);
+        fs::create_dir_all(&snapshots_path)?;
+        let path = snapshots_path.join(chrono::Utc::now().format("%Y%m%dT%H%M%S%.3fZ").to_string());
+        fs::create_dir(&path)?;
+        //With the log, rows of a restored snapshot can still be repaired with repair --from-wal
+        let copied = replica::copy_table_files(&self.columns.db_root_path, &path)
+            .and_then(|bytes| Ok(bytes + fs::copy(Wal::file_path(&self.columns.db_root_path), Wal::file_path(&path))?));
+        match copied {
+            Ok(bytes) => {
+                info!("Copied {} bytes into snapshot {}", bytes, path.display());
+                Ok(path)
+            }
+            Err(err) => {
+                //Half copied snapshots are of no use for a restore
+                if let Err(remove_err) = fs::remove_dir_all(&path) {
+                    warn!("Failed to delete incomplete snapshot {}: {}", path.display(), remove_err);
+                }
+                Err(err.into())
+            }
+        }
+    }
+
+    ///Statistics of every column, see `ColumnStats`
+    pub fn stats(&self) -> Result<TableStats, ContainerError> {
+        if !self.warm {
+            return Err(ContainerError::WarmingUp);
+        }
+        Ok(TableStats {
+            rows: self.columns.row_count(),
+            deleted_rows: self.columns.deleted_rows().len(),
+            columns: self.columns.stats.clone(),
+        })
+    }
+
+    ///Distribution and percentiles of an Int or Float column, see `column_stats::Histogram`
+    pub fn histogram(&self, column_name: &str, percentiles: &[f64]) -> Result<ColumnHistogram, ContainerError> {
+        if !self.warm {
+            return Err(ContainerError::WarmingUp);
+        }
+        let column_stats = self
+            .columns
+            .stats
+            .get(column_name)
+            .ok_or_else(|| ContainerError::UnknownColumn(column_name.to_string()))?;
+        match column_stats.histogram(column_name, percentiles) {
+            Some(histogram) => Ok(histogram),
+            None => Err(ContainerError::NoHistogram(column_name.to_string())),
+        }
+    }
+
+    pub fn metrics(&self) -> StorageMetrics {
+        StorageMetrics {
+            column_buffered_bytes: self.columns.buffered_bytes(),
+            deduplicated_rows: self.dedupe.as_ref().map(|dedupe| dedupe.dropped()).unwrap_or_default(),
+            key_conflicts: self.upserts.snapshot(),
+            unacknowledged_failures: self.unacknowledged_failures,
+        }
+    }
+
+    #[instrument(skip(self))]
+    fn rollback(&mut self) {
+        self.index_counter.rollback();
+    }
+
+    ///All columns of the rows at the given positions
+    pub fn rows(&self, positions: Vec<usize>) -> Vec<ColumnFrame> {
+        self.columns.rows(positions)
+    }
+
+    ///Unique values of a column among the rows matching the filter, in order of first appearance
+    #[instrument(skip(self))]
+    ///All rows matching the filter, for `GET /rows/stream`
+    pub fn scan(&self, filter: &QueryFilter) -> Result<RowScan, FilterError> {
+        let positions = self.columns.matching_rows(filter)?;
+        Ok(RowScan {
+            columns: self.columns.column_names(),
+            rows: self.columns.rows(positions),
+        })
+    }
+
+    ///Up to limit rows matching the filter, from the cursor on. Paging through the table this way only scans
+    ///the rows of each page, unlike skipping rows of a full scan.
+    pub fn page(&self, filter: &QueryFilter, cursor: Option<PageCursor>, limit: usize) -> Result<RowPage, FilterError> {
+        let segments = &self.columns.segments;
+        let row_count = self.columns.row_count();
+        let start = match cursor {
+            None => 0,
+            Some(cursor) if cursor.generation != segments.generation => return Err(FilterError::ExpiredCursor),
+            Some(cursor) => segments
+                .position(cursor.segment, cursor.offset)
+                .filter(|position| *position <= row_count)
+                .ok_or_else(|| FilterError::InvalidCursor(cursor.encode()))?,
+        };
+        let (positions, next) = self.columns.matching_rows_from(filter, start, limit)?;
+        let (segment, offset) = segments.locate(next);
+        Ok(RowPage {
+            rows: self.columns.rows(positions),
+            cursor: PageCursor {
+                generation: segments.generation,
+                segment,
+                offset,
+            },
+            has_more: next < row_count,
+        })
+    }
+
+    ///All live rows holding the value in the indexed column
+    pub fn lookup(&self, column_name: &str, value: &str) -> Result<Vec<ColumnFrame>, FilterError> {
+        let column = self
+            .columns
+            .find_column(column_name)
+            .ok_or_else(|| FilterError::UnknownColumn(column_name.to_string()))?;
+        let expected = parse_cell(column_name, value, column.data_type())?;
+        let positions = self
+            .columns
+            .find_indexed(column_name, &expected)
+            .ok_or_else(|| FilterError::NotIndexed(column_name.to_string()))?;
+        Ok(self.columns.rows(positions))
+    }
+
+    ///The live row holding the value in the primary key column, or None if there is none
+    pub fn find_by_key(&self, value: &str) -> Result<Option<ColumnFrame>, FilterError> {
+        let primary_key = self.config.primary_key.as_deref().ok_or(FilterError::NoPrimaryKey)?;
+        let column = self
+            .columns
+            .find_column(primary_key)
+            .ok_or_else(|| FilterError::UnknownColumn(primary_key.to_string()))?;
+        let key = parse_cell(primary_key, value, column.data_type())?;
+        Ok(self.columns.find_unique(primary_key, &key).and_then(|position| self.columns.rows(vec![position]).pop()))
+    }
+
+    ///With cast set, values get converted before they are compared. Values which don't convert are left out.
+    pub fn distinct(&self, column_name: &str, limit: usize, filter: &QueryFilter, cast: Option<CastType>) -> Result<DistinctValues, FilterError> {
+        let column = self
+            .columns
+            .find_column(column_name)
+            .ok_or_else(|| FilterError::UnknownColumn(column_name.to_string()))?;
+        let entries = column.entries();
+
+        let mut seen = HashSet::new();
+        let mut values = vec![];
+        let mut truncated = false;
+        let mut failed_casts = 0;
+        let mut scanned = 0;
+        for n in self.columns.matching_rows(filter)? {
+            scanned += 1;
+            let cell = match cast {
+                Some(cast_type) => match cast_type.apply(&entries[n]) {
+                    Some(cell) => cell,
+                    None => {
+                        failed_casts += 1;
+                        continue;
+                    }
+                },
+                None => entries[n].to_owned(),
+            };
+            //Floats aren't hashable, their encoded bytes are
+            let Ok((_checksum, tag_byte, bytes)) = cell.to_bytes() else {
+                continue;
+            };
+            if !seen.insert((tag_byte, bytes)) {
+                continue;
+            }
+            if values.len() == limit {
+                truncated = true;
+                break;
+            }
+            values.push(cell);
+        }
+
+        Ok(DistinctValues {
+            column: column_name.to_string(),
+            values,
+            truncated,
+            failed_casts: cast.map(|_| failed_casts),
+            scanned,
+        })
+    }
+
+    ///Streams all rows matching the filter to tx, along with their positions.
+    ///With `columns` set, the rows only hold those columns besides `id` and `timestamp`, `rows` assembles them in full.
+    #[instrument(skip(self, tx))]
+    pub async fn query(&self, tx: Sender<Command>, filter: &QueryFilter, columns: Option<&[String]>) -> Result<(), FilterError> {
+        let positions = self.columns.matching_rows(filter)?;
+        debug!("Filter matched {} rows", positions.len());
+        let rows = self.columns.rows_with_columns(positions.clone(), columns);
+        for (position, row) in positions.into_iter().zip(rows) {
+            match tx.send(Command::QueryRow { position, row }).await {
+                Ok(()) => {
+                    debug!("Successfully sent row");
+                }
+                Err(err) => {
+                    error!("SendError: {}", err);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::MetadataExt;
+    use std::path::Path;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use chrono::NaiveDateTime;
+    use serde_json::json;
+    use tempfile::TempDir;
+
+    use super::{
+        backend::{LocalBackend, StorageBackend},
+        cast::CastType,
+        checked_file::CheckedFile,
+        column::{Column, COLUMN_FORMAT_VERSION, DEFAULT_WRITE_BUFFER_SIZE},
+        column_entries::ColumnEntries,
+        column_frame::ColumnFrame,
+        column_index::ColumnIndex,
+        compaction::{self, StagedCompaction},
+        data_type::DataType,
+        expression::Expression,
+        filter::{AsOf, FilterError, QueryFilter},
+        lineage::Lineage,
+        page_cursor::PageCursor,
+        replica::ReplicaSnapshots,
+        schema_fingerprint::SchemaFingerprint,
+        segments::{SegmentManifest, ZoneMap},
+        upsert_conflicts::KeyConflicts,
+        warmup::StartupTracker,
+        wal::{Wal, WAL_FORMAT_VERSION},
+        wal_error::WalError,
+        ColumnLayout, Container, ContainerError, FieldError, LayoutFile,
+    };
+    use crate::{
+        config::{
+            ColumnCompression, ColumnConfig, ColumnEncoding, DataTypeConfig, DedupeConfig, DiskPressureConfig, Docs, FlushPolicy, PartitionPeriod,
+            SchemaConfig,
+            ServerComputed, StorageBackendConfig,
+        },
+        storage::cell::Cell,
+        warnings::WarningKind,
+        web::IndexParams,
+    };
+
+    pub fn initialize() -> TempDir {
+        tempfile::tempdir().unwrap()
+    }
+
+    fn schema_config_with_timestamp() -> SchemaConfig {
+        let columns = vec![ColumnConfig {
+            name: "url".into(),
+            data_type: DataTypeConfig::String,
+            computed: false,
+            nullable: false,
+            default: None,
+            auto_generate: false,
+            server_computed: None,
+            indexed: false,
+            unique: false,
+            encoding: ColumnEncoding::Plain,
+            compression: None,
+            docs: Docs::default(),
+        }];
+        SchemaConfig {
+            columns,
+            add_timestamp_column: true,
+            shard_key: None,
+            strict_fields: false,
+            write_buffer_size: DEFAULT_WRITE_BUFFER_SIZE,
+            flush_policy: FlushPolicy::EveryCommit,
+            before_insert_hook: None,
+            retention_secs: None,
+            history_secs: None,
+            lineage: false,
+            storage_backend: StorageBackendConfig::Local,
+            infer_schema: false,
+            column_order: vec![],
+            dedupe: None,
+            unique_key: None,
+            primary_key: None,
+            auth: None,
+            max_response_bytes: None,
+            disk_pressure: None,
+            compaction: None,
+            wal_shipping: None,
+            segment_rows: None,
+            partition_by: None,
+            mmap_reads: false,
+            lazy_columns: false,
+            docs: Docs::default(),
+        }
+    }
+
+    fn schema_config_without_timestamp() -> SchemaConfig {
+        let columns = vec![ColumnConfig {
+            name: "url".into(),
+            data_type: DataTypeConfig::String,
+            computed: false,
+            nullable: false,
+            default: None,
+            auto_generate: false,
+            server_computed: None,
+            indexed: false,
+            unique: false,
+            encoding: ColumnEncoding::Plain,
+            compression: None,
+            docs: Docs::default(),
+        }];
+        SchemaConfig {
+            columns,
+            add_timestamp_column: false,
+            shard_key: None,
+            strict_fields: false,
+            write_buffer_size: DEFAULT_WRITE_BUFFER_SIZE,
+            flush_policy: FlushPolicy::EveryCommit,
+            before_insert_hook: None,
+            retention_secs: None,
+            history_secs: None,
+            lineage: false,
+            storage_backend: StorageBackendConfig::Local,
+            infer_schema: false,
+            column_order: vec![],
+            dedupe: None,
+            unique_key: None,
+            primary_key: None,
+            auth: None,
+            max_response_bytes: None,
+            disk_pressure: None,
+            compaction: None,
+            wal_shipping: None,
+            segment_rows: None,
+            partition_by: None,
+            mmap_reads: false,
+            lazy_columns: false,
+            docs: Docs::default(),
+        }
+    }
+
+    fn schema_config_with_computed_domain() -> SchemaConfig {
+        let mut config = schema_config_with_timestamp();
+        config.columns.push(ColumnConfig {
+            name: "domain".into(),
+            data_type: DataTypeConfig::String,
+            computed: true,
+            nullable: false,
+            default: None,
+            auto_generate: false,
+            server_computed: None,
+            indexed: false,
+            unique: false,
+            encoding: ColumnEncoding::Plain,
+            compression: None,
+            docs: Docs::default(),
+        });
+        config
+    }
+    fn schema_config_with_timestamp_and_two_columns() -> SchemaConfig {
+        let columns = vec![
+            ColumnConfig {
+                name: "url".into(),
+                data_type: DataTypeConfig::String,
+                computed: false,
+                nullable: false,
+                default: None,
+                auto_generate: false,
+                server_computed: None,
+                indexed: false,
+                unique: false,
+                encoding: ColumnEncoding::Plain,
+                compression: None,
+                docs: Docs::default(),
+            },
+            ColumnConfig {
+                name: "points".into(),
+                data_type: DataTypeConfig::Int,
+                computed: false,
+                nullable: false,
+                default: None,
+                auto_generate: false,
+                server_computed: None,
+                indexed: false,
+                unique: false,
+                encoding: ColumnEncoding::Plain,
+                compression: None,
+                docs: Docs::default(),
+            },
+        ];
+        SchemaConfig {
+            columns,
+            add_timestamp_column: true,
+            shard_key: None,
+            strict_fields: false,
+            write_buffer_size: DEFAULT_WRITE_BUFFER_SIZE,
+            flush_policy: FlushPolicy::EveryCommit,
+            before_insert_hook: None,
+            retention_secs: None,
+            history_secs: None,
+            lineage: false,
+            storage_backend: StorageBackendConfig::Local,
+            infer_schema: false,
+            column_order: vec![],
+            dedupe: None,
+            unique_key: None,
+            primary_key: None,
+            auth: None,
+            max_response_bytes: None,
+            disk_pressure: None,
+            compaction: None,
+            wal_shipping: None,
+            segment_rows: None,
+            partition_by: None,
+            mmap_reads: false,
+            lazy_columns: false,
+            docs: Docs::default(),
+        }
+    }
+
+    #[test]
+    fn insert_a_record_with_auto_timestamp_column() {
+        let root = initialize();
+        let mut container = Container::new(&root.path().to_path_buf(), schema_config_with_timestamp()).unwrap(); 
+
+        let params = IndexParams {
+            fields: vec!["url".into()],
+            values: vec![serde_json::Value::String("https://google.com".into())],
+        };
+        container.index(params).unwrap();
+
+        let ts_column = container.columns.find_column("timestamp").unwrap();
+        let url_column = container.columns.find_column("url").unwrap();
+
+        assert_eq!(
+            ts_column.entries().len(),
+            1,
+            "Timestamp not found: {:?}",
+            ts_column.entries()
+        );
+        assert_eq!(url_column.entries().len(), 1);
+
+        let url_cell = url_column.entries().first().unwrap();
+        if let Cell::String(str) = url_cell {
+            assert_eq!(str, "https://google.com");
+        } else {
+            panic!("Failed to retrieve URL from column: {:?}", url_cell);
+        }
+    }
+
+    #[test]
+    fn insert_a_record_without_auto_timestamp_column() {
+        let root = initialize();
+        let mut container =
+            Container::new(&root.path().to_path_buf(), schema_config_without_timestamp()).unwrap();
+
+        let params = IndexParams {
+            fields: vec!["url".into()],
+            values: vec![serde_json::Value::String("https://google.com".into())],
+        };
+        container.index(params).unwrap();
+
+        let ts_column = container.columns.find_column("timestamp");
+        let url_column = container.columns.find_column("url").unwrap();
+        assert!(
+            ts_column.is_none(),
+            "Wasn't expecting timestamp column, yet it is present: {:?}",
+            ts_column
+        );
+        assert_eq!(
+            url_column.entries().len(),
+            1,
+            "was expecting one url, found more than one"
+        );
+
+        let url_cell = url_column.entries().first().unwrap();
+        if let Cell::String(str) = url_cell {
+            assert_eq!(str, "https://google.com");
+        } else {
+            panic!("Failed to retrieve URL from column: {:?}", url_cell);
+        }
+    }
+
+    #[test]
+    fn fail_on_null_value() {
+        let root = initialize();
+        let mut container =
+            Container::new(&root.path().to_path_buf(), schema_config_without_timestamp()).unwrap();
+
+        let params = IndexParams {
+            fields: vec!["url".into()],
+            values: vec![serde_json::Value::Null],
+        };
+        let result = container.index(params);
+        assert!(
+            result.is_err(),
+            "Was expecting error on insert. Got {:?}",
+            result
+        );
+
+        let url_column = container.columns.find_column("url").unwrap();
+        assert_eq!(
+            url_column.entries().len(),
+            0,
+            "was expecting no url, found: {:?}",
+            url_column.entries()
+        );
+    }
+
+    #[test]
+    fn reject_insert_when_data_type_is_incompatible() {
+        let root = initialize();
+        let mut container =
+            Container::new(&root.path().to_path_buf(), schema_config_without_timestamp()).unwrap();
+        let params = IndexParams {
+            fields: vec!["url".into()],
+            values: vec![json!(2342)],
+        };
+
+        let result = container.index(params);
+        assert!(
+            result.is_err(),
+            "Was expecting error on insert. Got {:?}",
+            result
+        );
+
+        let url_column = container.columns.find_column("url").unwrap();
+        assert_eq!(
+            url_column.entries().len(),
+            0,
+            "was expecting no url, found: {:?}",
+            url_column.entries()
+        );
+    }
+
+    #[test]
+    fn reject_insert_for_all_cells_when_one_cell_fails() {
+        let root = initialize();
+        let mut container = Container::new(
+            &root.path().to_path_buf(),
+            schema_config_with_timestamp_and_two_columns(),
+        )
+        .unwrap();
+        let params = IndexParams {
+            fields: vec!["url".into(), "points".into()],
+            values: vec!["https://google.com".into(), serde_json::Value::Null],
+        };
+
+        let result = container.index(params);
+        assert!(
+            result.is_err(),
+            "Was expecting error on insert. Got {:?}",
+            result
+        );
+
+        let url_column = container.columns.find_column("url").unwrap();
+        assert_eq!(
+            url_column.entries().len(),
+            0,
+            "was expecting no url, found: {:?}",
+            url_column.entries()
+        );
+
+        let points_column = container.columns.find_column("points").unwrap();
+        assert_eq!(
+            points_column.entries().len(),
+            0,
+            "was expecting no points, found: {:?}",
+            points_column.entries()
+        );
+
+        let timestamp_column = container.columns.find_column("timestamp").unwrap();
+        assert_eq!(
+            timestamp_column.entries().len(),
+            0,
+            "was expecting no timestamp, found: {:?}",
+            timestamp_column.entries()
+        );
+    }
+
+    #[test]
+    fn rejected_insert_rolls_back_auto_index() {
+        let root = initialize();
+        let mut container = Container::new(
+            &root.path().to_path_buf(),
+            schema_config_with_timestamp_and_two_columns(),
+        )
+        .unwrap();
+        let params = IndexParams {
+            fields: vec!["url".into(), "points".into()],
+            values: vec!["https://google.com".into(), serde_json::Value::Null],
+        };
+
+        assert_eq!(container.index_counter.counter(), 0);
+
+        let result = container.index(params);
+        assert!(
+            result.is_err(),
+            "Was expecting error on insert. Got {:?}",
+            result
+        );
+        assert_eq!(container.index_counter.counter(), 0);
+
+        let id_column = container.columns.find_column("id").unwrap();
+        assert_eq!(
+            id_column.entries().len(),
+            0,
+            "Was expecting zero entries in id column"
+        );
+    }
+
+    #[test]
+    fn successful_insert_increases_counter() {
+        let root = initialize();
+        let mut container = Container::new(
+            &root.path().to_path_buf(),
+            schema_config_with_timestamp_and_two_columns(),
+        )
+        .unwrap();
+        let params = IndexParams {
+            fields: vec!["url".into(), "points".into()],
+            values: vec!["https://google.com".into(), 54.into()],
+        };
+
+        assert_eq!(container.index_counter.counter(), 0);
+
+        let result = container.index(params);
+
+        assert!(result.is_ok(), "Expected Insert to be successful");
+
+        let id_column = container.columns.find_column("id").unwrap();
+
+        let inserted_value = id_column.entries().first().unwrap();
+        assert_eq!(inserted_value, &Cell::Int(1));
+
+        //Index starts counting at 0, therefore we expect the next id to be 1
+        assert_eq!(
+            container.index_counter.counter(),
+            1,
+            "Expected Index Counter to have increased after commit"
+        );
+    }
+
+    #[test]
+    fn reject_timestamp_value_when_autotimestamp_is_on() {
+        let root = initialize();
+        let mut container = Container::new(
+            &root.path().to_path_buf(),
+            schema_config_with_timestamp_and_two_columns(),
+        )
+        .unwrap();
+        let params = IndexParams {
+            fields: vec!["url".into(), "timestamp".into()],
+            values: vec!["https://google.com".into(), 54.into()],
+        };
+
+        let result = container.index(params);
+
+        assert!(result.is_err(), "Expected Insert to fail");
+    }
+
+    #[test]
+    fn server_computed_columns_get_filled_on_insert() {
+        let root = initialize();
+        let root_path = root.path().to_path_buf();
+        let mut config = schema_config_with_timestamp_and_two_columns();
+        for (name, kind) in [
+            ("ingest_date", ServerComputed::IngestDate),
+            ("row_hash", ServerComputed::RowHash(vec!["url".into(), "points".into()])),
+        ] {
+            config.columns.push(ColumnConfig {
+                name: name.into(),
+                data_type: DataTypeConfig::String,
+                computed: false,
+                nullable: false,
+                default: None,
+                auto_generate: false,
+                server_computed: Some(kind),
+                indexed: false,
+                unique: false,
+                encoding: ColumnEncoding::Plain,
+                compression: None,
+                docs: Docs::default(),
+            });
+        }
+        let mut container = Container::new(&root_path, config.clone()).unwrap();
+        let id = container.index(IndexParams {
+            fields: vec!["url".into(), "points".into()],
+            values: vec!["https://google.com".into(), 5.into()],
+        }).unwrap();
+        let err = container.index(IndexParams {
+            fields: vec!["url".into(), "points".into(), "row_hash".into()],
+            values: vec!["https://google.com".into(), 5.into(), "forged".into()],
+        }).unwrap_err();
+        assert!(matches!(err, ContainerError::SchemaMismatch { ref reserved, .. } if reserved == &vec!["row_hash".to_string()]));
+
+        let row = container.columns.all_rows().remove(0);
+        let timestamp = match row.get("timestamp") {
+            Some(Cell::Timestamp(timestamp)) => *timestamp,
+            cell => panic!("Unexpected timestamp {:?}", cell),
+        };
+        let date = NaiveDateTime::from_timestamp_opt(timestamp, 0).unwrap().date().format("%Y-%m-%d").to_string();
+        assert_eq!(row.get("ingest_date"), Some(&Cell::String(date)));
+        let hash = row.get("row_hash").unwrap().to_owned();
+
+        //Updates hash the new values
+        container.update(id, IndexParams {
+            fields: vec!["url".into(), "points".into()],
+            values: vec!["https://google.com".into(), 6.into()],
+        }).unwrap();
+        let updated = container.columns.all_rows().remove(0);
+        assert_ne!(updated.get("row_hash"), Some(&hash));
+
+        container.rename_column("points", "score").unwrap();
+        container.update(id, IndexParams {
+            fields: vec!["url".into(), "score".into()],
+            values: vec!["https://google.com".into(), 5.into()],
+        }).unwrap();
+        assert_eq!(container.columns.all_rows().remove(0).get("row_hash"), Some(&hash));
+
+        config.columns[1].name = "score".into();
+        config.columns[3].server_computed = Some(ServerComputed::RowHash(vec!["title".into()]));
+        drop(container);
+        assert!(matches!(Container::new(&root_path, config), Err(ContainerError::InvalidServerColumn { .. })));
+    }
+
+    #[test]
+    fn reserved_and_missing_fields_are_listed_together() {
+        let root = initialize();
+        let mut container = Container::new(
+            &root.path().to_path_buf(),
+            schema_config_with_timestamp_and_two_columns(),
+        )
+        .unwrap();
+        let params = IndexParams {
+            fields: vec!["url".into(), "timestamp".into(), "id".into()],
+            values: vec!["https://google.com".into(), 54.into(), 1.into()],
+        };
+
+        let err = container.index(params).unwrap_err();
+
+        assert!(err.is_client_error());
+        assert!(
+            matches!(err, ContainerError::SchemaMismatch { ref reserved, ref missing }
+                if reserved == &vec!["timestamp".to_string(), "id".to_string()] && missing == &vec!["points".to_string()]),
+            "{:?}",
+            err
+        );
+        assert_eq!(container.index_counter.counter(), 0);
+    }
+
+    #[test]
+    fn repair_rebuilds_columns_from_wal() {
+        let root = initialize();
+        let root_path = root.path().to_path_buf();
+        let mut container = Container::new(&root_path, schema_config_with_timestamp_and_two_columns()).unwrap();
+        for points in [1, 2] {
+            let params = IndexParams {
+                fields: vec!["url".into(), "points".into()],
+                values: vec!["https://google.com".into(), points.into()],
+            };
+            container.index(params).unwrap();
+        }
+        drop(container);
+
+        std::fs::write(root_path.join("column_points"), b"garbage").unwrap();
+        std::fs::remove_file(root_path.join("column_layout.json.1")).unwrap();
+
+        let restored_rows = Container::repair_from_wal(&root_path, &StorageBackendConfig::Local).unwrap();
+        assert_eq!(restored_rows, 2);
+
+        let container = Container::new(&root_path, schema_config_with_timestamp_and_two_columns()).unwrap();
+        let points_column = container.columns.find_column("points").unwrap();
+        assert_eq!(points_column.entries(), &[Cell::Int(1), Cell::Int(2)]);
+        assert_eq!(container.index_counter.counter(), 2);
+    }
+
+    #[test]
+    fn repair_refuses_wal_without_the_first_rows() {
+        let root = initialize();
+        let root_path = root.path().to_path_buf();
+        let mut container = Container::new(&root_path, schema_config_with_timestamp_and_two_columns()).unwrap();
+        for points in [1, 2] {
+            let params = IndexParams {
+                fields: vec!["url".into(), "points".into()],
+                values: vec!["https://google.com".into(), points.into()],
+            };
+            container.index(params).unwrap();
+        }
+        drop(container);
+        std::fs::remove_file(Wal::file_path(&root_path)).unwrap();
+        drop(Container::new(&root_path, schema_config_with_timestamp_and_two_columns()).unwrap());
+
+        let err = Container::repair_from_wal(&root_path, &StorageBackendConfig::Local).unwrap_err();
+        assert!(matches!(err, ContainerError::WalError { source: WalError::MissingRows(3) }), "{:?}", err);
+
+        let mut container = Container::new(&root_path, schema_config_with_timestamp_and_two_columns()).unwrap();
+        container.index(IndexParams {
+            fields: vec!["url".into(), "points".into()],
+            values: vec!["https://google.com".into(), 3.into()],
+        }).unwrap();
+        drop(container);
+
+        let err = Container::repair_from_wal(&root_path, &StorageBackendConfig::Local).unwrap_err();
+        assert!(matches!(err, ContainerError::WalError { source: WalError::MissingRows(3) }), "{:?}", err);
+
+        let container = Container::new(&root_path, schema_config_with_timestamp_and_two_columns()).unwrap();
+        let points_column = container.columns.find_column("points").unwrap();
+        assert_eq!(points_column.entries(), &[Cell::Int(1), Cell::Int(2), Cell::Int(3)]);
+    }
+
+    #[test]
+    fn renamed_columns_keep_their_data() {
+        let root = initialize();
+        let root_path = root.path().to_path_buf();
+        let mut config = schema_config_with_timestamp_and_two_columns();
+        config.column_order = vec!["points".into(), "url".into()];
+        let mut container = Container::new(&root_path, config.clone()).unwrap();
+        for points in [1, 2] {
+            let params = IndexParams {
+                fields: vec!["url".into(), "points".into()],
+                values: vec!["https://google.com".into(), points.into()],
+            };
+            container.index(params).unwrap();
+        }
+
+        assert!(matches!(container.rename_column("id", "key"), Err(ContainerError::InvalidRename { .. })));
+        assert!(matches!(container.rename_column("points", "url"), Err(ContainerError::InvalidRename { .. })));
+        assert!(matches!(container.rename_column("votes", "score"), Err(ContainerError::UnknownColumn(_))));
+        container.rename_column("points", "score").unwrap();
+        let column_names = container.schema().columns.into_iter().map(|column| column.name).collect::<Vec<_>>();
+        assert_eq!(column_names, vec!["score", "url", "id", "timestamp"]);
+        container.index(IndexParams {
+            fields: vec!["url".into(), "score".into()],
+            values: vec!["https://github.com".into(), 3.into()],
+        }).unwrap();
+        drop(container);
+        assert!(!root_path.join("column_points").exists());
+        assert!(matches!(Container::new(&root_path, config.clone()), Err(ContainerError::SchemaConflict(_))));
+
+        config.columns[1].name = "score".into();
+        let container = Container::new(&root_path, config.clone()).unwrap();
+        let score_column = container.columns.find_column("score").unwrap();
+        assert_eq!(score_column.entries(), &[Cell::Int(1), Cell::Int(2), Cell::Int(3)]);
+        drop(container);
+
+        assert_eq!(Container::repair_from_wal(&root_path, &StorageBackendConfig::Local).unwrap(), 3);
+        let container = Container::new(&root_path, config).unwrap();
+        assert_eq!(container.columns.find_column("score").unwrap().entries().len(), 3);
+    }
+
+    #[test]
+    fn dropped_columns_lose_their_files() {
+        let root = initialize();
+        let root_path = root.path().to_path_buf();
+        let mut config = schema_config_with_timestamp_and_two_columns();
+        config.columns[1].indexed = true;
+        let mut container = Container::new(&root_path, config.clone()).unwrap();
+        container.index(IndexParams {
+            fields: vec!["url".into(), "points".into()],
+            values: vec!["https://google.com".into(), 1.into()],
+        }).unwrap();
+
+        assert!(matches!(container.drop_column("timestamp"), Err(ContainerError::InvalidDrop { .. })));
+        assert!(matches!(container.drop_column("votes"), Err(ContainerError::UnknownColumn(_))));
+        container.drop_column("points").unwrap();
+        let column_names = container.schema().columns.into_iter().map(|column| column.name).collect::<Vec<_>>();
+        assert_eq!(column_names, vec!["id", "url", "timestamp"]);
+        container.index(IndexParams {
+            fields: vec!["url".into()],
+            values: vec!["https://github.com".into()],
+        }).unwrap();
+        drop(container);
+        assert!(!root_path.join("column_points").exists());
+        assert!(!ColumnIndex::file_path(&root_path, "points").exists());
+
+        config.columns.retain(|column_config| column_config.name != "points");
+        let container = Container::new(&root_path, config.clone()).unwrap();
+        assert_eq!(container.columns.all_rows().len(), 2);
+        drop(container);
+        assert_eq!(Container::repair_from_wal(&root_path, &StorageBackendConfig::Local).unwrap(), 2);
+        let container = Container::new(&root_path, config).unwrap();
+        assert!(container.columns.find_column("points").is_none());
+    }
+
+    #[test]
+    fn starts_with_a_schema_the_table_does_not_fit_get_refused() {
+        let root = initialize();
+        let root_path = root.path().to_path_buf();
+        let config = schema_config_with_timestamp_and_two_columns();
+        let mut container = Container::new(&root_path, config.clone()).unwrap();
+        container.drop_column("points").unwrap();
+        drop(container);
+        let err = Container::new(&root_path, config.clone()).unwrap_err();
+        assert!(matches!(&err, ContainerError::SchemaConflict(conflicts) if conflicts[0].starts_with("Column points")));
+        assert_eq!(err.kind(), "SchemaConflict");
+
+        let mut changed_type = config.clone();
+        changed_type.columns.retain(|column_config| column_config.name != "points");
+        changed_type.columns[0].data_type = DataTypeConfig::Int;
+        assert!(matches!(Container::new(&root_path, changed_type), Err(ContainerError::SchemaConflict(_))));
+
+        let mut nullable = config;
+        nullable.columns.retain(|column_config| column_config.name != "points");
+        nullable.columns[0].nullable = true;
+        drop(Container::new(&root_path, nullable.clone()).unwrap());
+        let (_, stored) = SchemaFingerprint::load(&root_path).unwrap();
+        assert_eq!(stored, Some(SchemaFingerprint::of(&nullable)));
+    }
+
+    #[test]
+    fn schema_docs_get_persisted_in_the_layout() {
+        let root = initialize();
+        let root_path = root.path().to_path_buf();
+        let config: SchemaConfig = serde_json::from_value(json!({
+            "description": "Page views",
+            "tags": ["web"],
+            "add_timestamp_column": true,
+            "columns": [{ "name": "url", "data_type": "String", "description": "Page the view came from", "tags": ["pii"] }]
+        }))
+        .unwrap();
+        let mut container = Container::new(&root_path, config.clone()).unwrap();
+        let schema = serde_json::to_value(container.schema()).unwrap();
+        assert_eq!(schema["description"], json!("Page views"));
+        assert_eq!(schema["tags"], json!(["web"]));
+        assert_eq!(schema["columns"][1]["description"], json!("Page the view came from"));
+        assert_eq!(schema["columns"][1]["tags"], json!(["pii"]));
+        assert!(schema["columns"][0].get("description").is_none());
+
+        container.rename_column("url", "page").unwrap();
+        assert_eq!(container.schema().columns[1].docs.tags, vec!["pii".to_string()]);
+        drop(container);
+        let layout = || CheckedFile::load(ColumnLayout::file_path(&root_path)).unwrap().1;
+        assert!(matches!(serde_json::from_slice(&layout()).unwrap(), LayoutFile::Documented { .. }));
+
+        let undocumented = SchemaConfig {
+            docs: Docs::default(),
+            columns: vec![],
+            ..config
+        };
+        let container = Container::new(&root_path, undocumented).unwrap();
+        assert!(container.schema().columns.iter().all(|column| column.docs.is_empty()));
+        drop(container);
+        assert!(serde_json::from_slice::<Vec<(String, DataType)>>(&layout()).is_ok());
+    }
+
+    #[test]
+    fn accepts_inserts_while_warming_up() {
+        let root = initialize();
+        let root_path = root.path().to_path_buf();
+        let params = |url: &str| IndexParams {
+            fields: vec!["url".into()],
+            values: vec![url.into()],
+        };
+        let mut container = Container::new(&root_path, schema_config_with_timestamp()).unwrap();
+        container.index(params("https://google.com")).unwrap();
+        drop(container);
+
+        let (mut container, plan) = Container::open_cold(&root_path, schema_config_with_timestamp()).unwrap();
+        assert!(!container.is_warm());
+        container.index(params("https://github.com")).unwrap();
+
+        let startup = StartupTracker::new();
+        let loaded = plan.run(&startup).unwrap();
+        assert_eq!(startup.progress().bytes_loaded, startup.progress().bytes_total);
+        container.finish_warmup(loaded).unwrap();
+
+        assert!(container.is_warm());
+        let urls = container.columns.find_column("url").unwrap();
+        assert_eq!(
+            urls.entries(),
+            &[Cell::String("https://google.com".into()), Cell::String("https://github.com".into())]
+        );
+        let ids = container.columns.find_column("id").unwrap();
+        assert_eq!(ids.entries(), &[Cell::Int(1), Cell::Int(2)]);
+    }
+
+    #[test]
+    fn lineage_columns_get_added_to_existing_rows() {
+        let root = initialize();
+        let root_path = root.path().to_path_buf();
+        let params = || IndexParams {
+            fields: vec!["url".into()],
+            values: vec!["https://google.com".into()],
+        };
+        let mut container = Container::new(&root_path, schema_config_with_timestamp()).unwrap();
+        container.index(params()).unwrap();
+        drop(container);
+
+        let mut config = schema_config_with_timestamp();
+        config.lineage = true;
+        let mut container = Container::new(&root_path, config).unwrap();
+        let lineage = Lineage {
+            source: Some("kafka".into()),
+            kafka_topic: Some("clicks".into()),
+            kafka_partition: Some(2),
+            kafka_offset: Some(1337),
+            api_key: None,
+        };
+        container.index_with_lineage(params(), &lineage).unwrap();
+
+        let topics = container.columns.find_column("_kafka_topic").unwrap();
+        assert_eq!(topics.entries(), &[Cell::Null, Cell::String("clicks".into())]);
+        let api_keys = container.columns.find_column("_api_key").unwrap();
+        assert_eq!(api_keys.entries(), &[Cell::Null, Cell::Null]);
+
+        let err = container
+            .index(IndexParams {
+                fields: vec!["url".into(), "_source".into()],
+                values: vec!["https://google.com".into(), "spoofed".into()],
+            })
+            .unwrap_err();
+        assert!(matches!(err, ContainerError::SchemaMismatch { ref reserved, .. } if reserved == &vec!["_source".to_string()]), "{:?}", err);
+    }
+
+    #[test]
+    fn migrates_files_without_format_header() {
+        let root = initialize();
+        let root_path = root.path().to_path_buf();
+        let mut container = Container::new(&root_path, schema_config_with_timestamp_and_two_columns()).unwrap();
+        let params = IndexParams {
+            fields: vec!["url".into(), "points".into()],
+            values: vec!["https://google.com".into(), 5.into()],
+        };
+        container.index(params).unwrap();
+        drop(container);
+
+        //Strip the headers, so the files look like they were written before headers existed
+        let points_path = root_path.join("column_points");
+        let wal_path = root_path.join("wal");
+        let column_header = super::file_header::FileHeader::read(&mut std::fs::File::open(&points_path).unwrap(), b"WHCL").unwrap().unwrap();
+        let points_file = std::fs::read(&points_path).unwrap();
+        std::fs::write(&points_path, &points_file[column_header.len() as usize..]).unwrap();
+        let wal_header = super::file_header::FileHeader::read(&mut std::fs::File::open(&wal_path).unwrap(), b"WHWL").unwrap().unwrap();
+        let wal_file = std::fs::read(&wal_path).unwrap();
+        std::fs::write(&wal_path, &wal_file[wal_header.len() as usize..]).unwrap();
+
+        let container = Container::new(&root_path, schema_config_with_timestamp_and_two_columns()).unwrap();
+        assert_eq!(container.columns.find_column("points").unwrap().entries(), &[Cell::Int(5)]);
+        assert_eq!(std::fs::read(&points_path).unwrap(), points_file);
+        assert_eq!(std::fs::read(&wal_path).unwrap(), wal_file);
+    }
+
+    #[test]
+    fn migrates_files_of_older_format_versions() {
+        let root = initialize();
+        let root_path = root.path().to_path_buf();
+        let mut container = Container::new(&root_path, schema_config_with_timestamp_and_two_columns()).unwrap();
+        container.index(IndexParams {
+            fields: vec!["url".into(), "points".into()],
+            values: vec!["https://google.com".into(), 5.into()],
+        }).unwrap();
+        drop(container);
+
+        //The format version follows the four magic bytes
+        let set_version = |path: &Path, version: u16| {
+            let mut file = std::fs::read(path).unwrap();
+            file[4..6].copy_from_slice(&version.to_le_bytes());
+            std::fs::write(path, file).unwrap();
+        };
+        let points_path = root_path.join("column_points");
+        set_version(&points_path, 1);
+        set_version(&Wal::file_path(&root_path), 1);
+
+        let container = Container::new(&root_path, schema_config_with_timestamp_and_two_columns()).unwrap();
+        assert_eq!(container.columns.find_column("points").unwrap().entries(), &[Cell::Int(5)]);
+        drop(container);
+        assert_eq!(Column::format_version(&root_path, "points").unwrap(), Some(COLUMN_FORMAT_VERSION));
+        assert_eq!(Wal::format_version(&root_path).unwrap(), Some(WAL_FORMAT_VERSION));
+        assert_eq!(Wal::read_all(&root_path).unwrap().len(), 2);
+
+        //An older server would find the current version this much newer than its own
+        set_version(&points_path, COLUMN_FORMAT_VERSION + 1);
+        let err = Container::new(&root_path, schema_config_with_timestamp_and_two_columns()).unwrap_err();
+        assert!(matches!(err, ContainerError::IoError { .. }), "{:?}", err);
+        set_version(&points_path, COLUMN_FORMAT_VERSION);
+
+        set_version(&Wal::file_path(&root_path), WAL_FORMAT_VERSION + 1);
+        assert!(matches!(Wal::open(&root_path), Err(WalError::UnsupportedVersion(version)) if version == WAL_FORMAT_VERSION + 1));
+        assert!(matches!(Wal::read_all(&root_path), Err(WalError::UnsupportedVersion(_))));
+    }
+
+    #[test]
+    fn rejects_wal_records_of_unknown_kinds() {
+        let root = initialize();
+        let root_path = root.path().to_path_buf();
+        drop(Container::new(&root_path, schema_config_with_timestamp_and_two_columns()).unwrap());
+
+        //checksum | kind | payload length | payload
+        let payload = [0u8; 8];
+        let mut record = super::CRC32.checksum(&payload).to_le_bytes().to_vec();
+        record.push(0xEE);
+        record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        record.extend_from_slice(&payload);
+        let mut wal = std::fs::OpenOptions::new().append(true).open(Wal::file_path(&root_path)).unwrap();
+        std::io::Write::write_all(&mut wal, &record).unwrap();
+
+        assert!(matches!(Wal::read_all(&root_path), Err(WalError::Io { .. })));
+    }
+
+    #[test]
+    fn rejects_column_file_of_another_column() {
+        let root = initialize();
+        let root_path = root.path().to_path_buf();
+        let container = Container::new(&root_path, schema_config_with_timestamp_and_two_columns()).unwrap();
+        drop(container);
+
+        std::fs::copy(root_path.join("column_url"), root_path.join("column_points")).unwrap();
+        let err = Container::new(&root_path, schema_config_with_timestamp_and_two_columns()).unwrap_err();
+        assert!(matches!(err, ContainerError::IoError { .. }), "{:?}", err);
+    }
+
+    #[test]
+    fn backfill_adds_computed_column_to_existing_rows() {
+        let root = initialize();
+        let root_path = root.path().to_path_buf();
+        let mut container = Container::new(&root_path, schema_config_with_timestamp()).unwrap();
+        for url in ["https://google.com/search", "https://github.com/schultyy"] {
+            let params = IndexParams {
+                fields: vec!["url".into()],
+                values: vec![url.into()],
+            };
+            container.index(params).unwrap();
+        }
+        drop(container);
+
+        let with_domain = |mut params: IndexParams| {
+            let url = params.values[0].as_str().unwrap().to_string();
+            params.fields.push("domain".into());
+            params.values.push(url.split('/').nth(2).unwrap().into());
+            Ok(params)
+        };
+
+        let mut container = Container::new(&root_path, schema_config_with_computed_domain()).unwrap();
+        let report = container.backfill_computed_columns(with_domain).unwrap();
+        assert_eq!(report.columns, vec!["domain".to_string()]);
+        assert_eq!(report.rows, 2);
+        container.index(with_domain(IndexParams {
+            fields: vec!["url".into()],
+            values: vec!["https://crates.io/".into()],
+        }).unwrap()).unwrap();
+        drop(container);
+
+        std::fs::remove_file(root_path.join("column_domain")).unwrap();
+        std::fs::remove_file(root_path.join("column_layout.json.1")).unwrap();
+        assert_eq!(Container::repair_from_wal(&root_path, &StorageBackendConfig::Local).unwrap(), 3);
+
+        let container = Container::new(&root_path, schema_config_with_computed_domain()).unwrap();
+        let domain_column = container.columns.find_column("domain").unwrap();
+        assert_eq!(
+            domain_column.entries(),
+            &[
+                Cell::String("google.com".into()),
+                Cell::String("github.com".into()),
+                Cell::String("crates.io".into())
+            ]
+        );
+        assert!(container.pending_computed_columns.is_empty());
+    }
+
+    #[test]
+    fn retention_preview_deletes_nothing() {
+        let root = initialize();
+        let root_path = root.path().to_path_buf();
+        let mut config = schema_config_with_timestamp();
+        config.retention_secs = Some(60);
+        let mut container = Container::new(&root_path, config).unwrap();
+        for url in ["https://google.com", "https://github.com"] {
+            let params = IndexParams {
+                fields: vec!["url".into()],
+                values: vec![url.into()],
+            };
+            container.index(params).unwrap();
+        }
+        let in_an_hour = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64 + 3600;
+
+        let preview = container.retention_at(in_an_hour, true).unwrap();
+        assert_eq!(preview.rows, 2);
+        assert!(preview.bytes > 0);
+        assert_eq!(container.columns.find_column("url").unwrap().entries().len(), 2);
+        assert!(container.last_retention_report().is_none());
+
+        let report = container.retention_at(in_an_hour, false).unwrap();
+        assert_eq!(report.bytes, preview.bytes);
+        assert_eq!(container.columns.find_column("url").unwrap().entries().len(), 0);
+        assert_eq!(container.last_retention_report().unwrap().rows, 2);
+
+        container.index(IndexParams {
+            fields: vec!["url".into()],
+            values: vec!["https://crates.io".into()],
+        }).unwrap();
+        drop(container);
+
+        assert_eq!(Container::repair_from_wal(&root_path, &StorageBackendConfig::Local).unwrap(), 1);
+    }
+
+    ///Local files, but replacing a column file fails once `swaps` of them got replaced, like a crash would
+    #[derive(Debug)]
+    struct CrashingBackend {
+        local: LocalBackend,
+        swaps: std::sync::atomic::AtomicUsize,
+    }
+
+    impl StorageBackend for CrashingBackend {
+        fn len(&self, name: &str) -> std::io::Result<Option<u64>> {
+            self.local.len(name)
+        }
+
+        fn append(&self, name: &str, bytes: &[u8]) -> std::io::Result<()> {
+            self.local.append(name, bytes)
+        }
+
+        fn read_range(&self, name: &str, start: u64, end: u64) -> std::io::Result<Box<dyn std::io::Read + Send>> {
+            self.local.read_range(name, start, end)
+        }
+
+        fn replace(&self, name: &str, bytes: &[u8]) -> std::io::Result<()> {
+            let swapped = name.starts_with("column_") && !name.ends_with(&compaction::staged_name(""));
+            if swapped && self.swaps.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |swaps| swaps.checked_sub(1)).is_err() {
+                return Err(std::io::Error::other("crashed"));
+            }
+            self.local.replace(name, bytes)
+        }
+
+        fn truncate(&self, name: &str, len: u64) -> std::io::Result<()> {
+            self.local.truncate(name, len)
+        }
+
+        fn remove(&self, name: &str) -> std::io::Result<()> {
+            self.local.remove(name)
+        }
+
+        fn location(&self, name: &str) -> String {
+            self.local.location(name)
+        }
+    }
+
+    #[test]
+    fn retention_finishes_rewriting_the_columns_after_a_crash() {
+        let root = initialize();
+        let root_path = root.path().to_path_buf();
+        let mut config = schema_config_with_timestamp();
+        config.retention_secs = Some(60);
+        let mut container = Container::new(&root_path, config.clone()).unwrap();
+        for url in ["https://google.com", "https://github.com"] {
+            container.index(IndexParams {
+                fields: vec!["url".into()],
+                values: vec![url.into()],
+            }).unwrap();
+        }
+        let in_an_hour = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64 + 3600;
+
+        //The id column's file gets replaced, the crash hits before the others are
+        container.columns.backend = Arc::new(CrashingBackend {
+            local: LocalBackend::new(&root_path),
+            swaps: 1.into(),
+        });
+        assert!(container.retention_at(in_an_hour, false).is_err());
+        drop(container);
+        assert!(root_path.join(compaction::MARKER_FILE).exists());
+
+        let container = Container::new(&root_path, config).unwrap();
+        for column in container.columns.columns.iter() {
+            assert!(column.entries().is_empty(), "{} still holds rows", column.name());
+        }
+        assert!(!root_path.join(compaction::MARKER_FILE).exists());
+    }
+
+    #[test]
+    fn updates_and_deletes_advance_the_write_seq() {
+        let root = initialize();
+        let root_path = root.path().to_path_buf();
+        let mut container = Container::new(&root_path, schema_config_with_timestamp()).unwrap();
+        container.index(IndexParams {
+            fields: vec!["url".into()],
+            values: vec!["https://google.com".into()],
+        }).unwrap();
+        let inserted = container.write_seq();
+
+        container.update(1, IndexParams {
+            fields: vec!["url".into()],
+            values: vec!["https://github.com".into()],
+        }).unwrap();
+        let updated = container.write_seq();
+        assert!(updated > inserted);
+        assert!(container.update(2, IndexParams {
+            fields: vec!["url".into()],
+            values: vec!["https://crates.io".into()],
+        }).is_err());
+        assert_eq!(container.write_seq(), updated);
+
+        container.delete(1).unwrap();
+        let deleted = container.write_seq();
+        assert!(deleted > updated);
+        assert_eq!(container.committed_seq(), 1);
+        drop(container);
+
+        let container = Container::new(&root_path, schema_config_with_timestamp()).unwrap();
+        assert_eq!(container.write_seq(), deleted);
+    }
+
+    #[test]
+    fn deleted_rows_stay_deleted_after_restart_and_repair() {
+        let root = initialize();
+        let root_path = root.path().to_path_buf();
+        let mut container = Container::new(&root_path, schema_config_with_timestamp()).unwrap();
+        for url in ["https://google.com", "https://github.com", "https://crates.io"] {
+            let params = IndexParams {
+                fields: vec!["url".into()],
+                values: vec![url.into()],
+            };
+            container.index(params).unwrap();
+        }
+        container.delete(2).unwrap();
+        assert!(matches!(container.delete(2), Err(ContainerError::UnknownRow(2))));
+        let urls = |container: &Container| {
+            container
+                .columns
+                .all_rows()
+                .iter()
+                .map(|row| row.get("url").unwrap().to_owned())
+                .collect::<Vec<_>>()
+        };
+        let expected = vec![Cell::String("https://google.com".into()), Cell::String("https://crates.io".into())];
+        assert_eq!(urls(&container), expected);
+        drop(container);
+
+        let container = Container::new(&root_path, schema_config_with_timestamp()).unwrap();
+        assert_eq!(urls(&container), expected);
+        assert_eq!(container.columns.matching_rows(&QueryFilter::default()).unwrap(), vec![0, 2]);
+        drop(container);
+
+        assert_eq!(Container::repair_from_wal(&root_path, &StorageBackendConfig::Local).unwrap(), 2);
+        let container = Container::new(&root_path, schema_config_with_timestamp()).unwrap();
+        assert_eq!(urls(&container), expected);
+    }
+
+    #[test]
+    fn column_stats_get_updated_on_insert_and_persisted() {
+        let root = initialize();
+        let root_path = root.path().to_path_buf();
+        let mut container = Container::new(&root_path, schema_config_with_timestamp()).unwrap();
+        for url in ["https://github.com", "https://crates.io", "https://google.com"] {
+            let params = IndexParams {
+                fields: vec!["url".into()],
+                values: vec![url.into()],
+            };
+            container.index(params).unwrap();
+        }
+        container.delete(1).unwrap();
+        let stats = container.stats().unwrap();
+        assert_eq!((stats.rows, stats.deleted_rows), (3, 1));
+        let urls = &stats.columns["url"];
+        assert_eq!((urls.count, urls.null_count), (3, 0));
+        assert_eq!(urls.min, Some(Cell::String("https://crates.io".into())));
+        assert_eq!(urls.max, Some(Cell::String("https://google.com".into())));
+        assert_eq!(stats.columns["id"].max, Some(Cell::Int(3)));
+        container.flush().unwrap();
+        drop(container);
+
+        let container = Container::new(&root_path, schema_config_with_timestamp()).unwrap();
+        assert_eq!(container.stats().unwrap().columns, stats.columns);
+        assert_eq!(container.histogram("id", &[50.0]).unwrap().percentiles["p50"].round(), 2.0);
+        assert!(matches!(container.histogram("url", &[50.0]), Err(ContainerError::NoHistogram(_))));
+        assert!(matches!(container.histogram("votes", &[50.0]), Err(ContainerError::UnknownColumn(_))));
+        drop(container);
+
+        //Statistics which don't cover every row get rebuilt
+        let (mut stats_file, _) = CheckedFile::load(ColumnLayout::stats_path(&root_path)).unwrap();
+        stats_file.write(b"{}").unwrap();
+        let container = Container::new(&root_path, schema_config_with_timestamp()).unwrap();
+        assert_eq!(container.stats().unwrap().columns, stats.columns);
+    }
+
+    #[test]
+    fn indexed_columns_find_rows_without_a_scan() {
+        let root = initialize();
+        let root_path = root.path().to_path_buf();
+        let mut config = schema_config_with_timestamp_and_two_columns();
+        config.columns[0].indexed = true;
+        let mut container = Container::new(&root_path, config.clone()).unwrap();
+        for (url, points) in [("https://google.com", 1), ("https://bing.com", 2), ("https://google.com", 3), ("https://google.com", 4)] {
+            let params = IndexParams {
+                fields: vec!["url".into(), "points".into()],
+                values: vec![url.into(), points.into()],
+            };
+            container.index(params).unwrap();
+        }
+        container.delete(1).unwrap();
+        let points = |rows: Vec<ColumnFrame>| rows.iter().map(|row| row.get("points").cloned().unwrap()).collect::<Vec<_>>();
+        assert_eq!(points(container.lookup("url", "https://google.com").unwrap()), vec![Cell::Int(3), Cell::Int(4)]);
+        assert!(container.lookup("url", "https://duckduckgo.com").unwrap().is_empty());
+        assert!(matches!(container.lookup("points", "3"), Err(FilterError::NotIndexed(..))));
+
+        let filter = QueryFilter {
+            equals: vec![("url".into(), "https://google.com".into())],
+            expression: Some(Expression::parse("points > 3").unwrap()),
+            ..Default::default()
+        };
+        assert_eq!(container.columns.matching_rows(&filter).unwrap(), vec![3]);
+        container.flush().unwrap();
+        drop(container);
+
+        assert!(CheckedFile::exists(&ColumnIndex::file_path(&root_path, "url")));
+        let mut container = Container::new(&root_path, config).unwrap();
+        assert_eq!(container.columns.indexes[0].covered(), 4);
+        assert_eq!(points(container.lookup("url", "https://google.com").unwrap()), vec![Cell::Int(3), Cell::Int(4)]);
+
+        container.rename_column("url", "link").unwrap();
+        assert!(!CheckedFile::exists(&ColumnIndex::file_path(&root_path, "url")));
+        assert_eq!(points(container.lookup("link", "https://bing.com").unwrap()), vec![Cell::Int(2)]);
+    }
+
+    #[test]
+    fn as_of_reads_the_table_at_an_earlier_point() {
+        let root = initialize();
+        let root_path = root.path().to_path_buf();
+        let mut config = schema_config_with_timestamp();
+        config.history_secs = Some(60);
+        let mut container = Container::new(&root_path, config).unwrap();
+        for url in ["https://google.com", "https://github.com", "https://crates.io"] {
+            let params = IndexParams {
+                fields: vec!["url".into()],
+                values: vec![url.into()],
+            };
+            container.index(params).unwrap();
+        }
+        container.delete(2).unwrap();
+        let urls = |container: &Container| {
+            container
+                .columns
+                .all_rows()
+                .iter()
+                .map(|row| row.get("url").unwrap().to_owned())
+                .collect::<Vec<_>>()
+        };
+
+        let past = container.as_of(AsOf::Seq(2)).unwrap();
+        let expected = vec![Cell::String("https://google.com".into()), Cell::String("https://github.com".into())];
+        assert_eq!(urls(&past), expected);
+        drop(past);
+        assert_eq!(urls(&container.as_of(AsOf::Seq(3)).unwrap()), urls(&container));
+        assert_eq!(urls(&container.as_of(AsOf::Seq(0)).unwrap()), vec![]);
+        assert_eq!(container.columns.all_rows().len(), 2);
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+        assert_eq!(urls(&container.as_of(AsOf::Time(now - 10)).unwrap()), vec![]);
+        container.check_as_of(AsOf::Time(now - 10)).unwrap();
+        assert!(matches!(container.check_as_of(AsOf::Time(now - 3600)), Err(FilterError::OutsideHistory(60))));
+        assert!(matches!(AsOf::parse("yesterday"), Err(FilterError::InvalidAsOf(_))));
+        assert_eq!(AsOf::parse("1970-01-01T00:01:00Z").unwrap(), AsOf::Time(60));
+    }
+
+    #[test]
+    fn updated_rows_keep_id_and_timestamp() {
+        let root = initialize();
+        let root_path = root.path().to_path_buf();
+        let mut container = Container::new(&root_path, schema_config_with_timestamp_and_two_columns()).unwrap();
+        for points in [1, 2] {
+            let params = IndexParams {
+                fields: vec!["url".into(), "points".into()],
+                values: vec!["https://google.com".into(), points.into()],
+            };
+            container.index(params).unwrap();
+        }
+        let timestamp = container.columns.find_column("timestamp").unwrap().entries()[0].clone();
+
+        let update = |url: serde_json::Value, points: serde_json::Value| IndexParams {
+            fields: vec!["url".into(), "points".into()],
+            values: vec![url, points],
+        };
+        assert!(matches!(
+            container.update(1, update("https://github.com".into(), "many".into())),
+            Err(ContainerError::InvalidDataType(..))
+        ));
+        assert!(matches!(
+            container.update(3, update("https://github.com".into(), 5.into())),
+            Err(ContainerError::UnknownRow(3))
+        ));
+        container.update(1, update("https://github.com".into(), 5.into())).unwrap();
+        assert_eq!(container.committed_seq(), 2);
+
+        let rows = |container: &Container| {
+            container
+                .columns
+                .all_rows()
+                .iter()
+                .map(|row| {
+                    (
+                        row.get("id").unwrap().to_owned(),
+                        row.get("url").unwrap().to_owned(),
+                        row.get("timestamp").unwrap().to_owned(),
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+        let updated = (Cell::Int(1), Cell::String("https://github.com".into()), timestamp);
+        assert_eq!(rows(&container)[1], updated);
+        drop(container);
+
+        let container = Container::new(&root_path, schema_config_with_timestamp_and_two_columns()).unwrap();
+        assert_eq!(rows(&container).len(), 2);
+        assert_eq!(rows(&container)[1], updated);
+        drop(container);
+
+        assert_eq!(Container::repair_from_wal(&root_path, &StorageBackendConfig::Local).unwrap(), 2);
+        let container = Container::new(&root_path, schema_config_with_timestamp_and_two_columns()).unwrap();
+        assert_eq!(rows(&container)[1], updated);
+    }
+
+    #[test]
+    fn redelivered_rows_are_dropped_within_the_dedupe_window() {
+        let root = initialize();
+        let root_path = root.path().to_path_buf();
+        let config = SchemaConfig {
+            dedupe: Some(DedupeConfig {
+                key_column: "url".into(),
+                window_secs: 600,
+            }),
+            ..schema_config_with_timestamp_and_two_columns()
+        };
+        let mut container = Container::new(&root_path, config).unwrap();
+        let row = |url: &str, points: i64| IndexParams {
+            fields: vec!["url".into(), "points".into()],
+            values: vec![url.into(), points.into()],
+        };
+        assert_eq!(container.index(row("https://google.com", 1)).unwrap(), 1);
+        assert_eq!(container.index(row("https://google.com", 2)).unwrap(), 1);
+        let committed = container
+            .index_transaction(vec![
+                row("https://github.com", 3),
+                row("https://google.com", 4),
+                row("https://github.com", 5),
+            ])
+            .unwrap();
+        assert_eq!(committed.ids, vec![2, 1, 2]);
+        assert_eq!(committed.conflicts, 2);
+        assert_eq!(container.columns.all_rows().len(), 2);
+        assert_eq!(container.metrics().deduplicated_rows, 2);
+        let conflicts = KeyConflicts { fresh: 2, conflicts: 3 };
+        assert_eq!(container.metrics().key_conflicts, vec![("url".to_string(), conflicts)]);
+    }
+
+    #[test]
+    fn unacknowledged_failures_show_up_in_the_metrics() {
+        let root = initialize();
+        let mut container = Container::new(&root.path().to_path_buf(), schema_config_with_timestamp()).unwrap();
+        container.count_unacknowledged_failure(1);
+        container.count_unacknowledged_failure(3);
+        assert_eq!(container.metrics().unacknowledged_failures, 4);
+        assert!(container.metrics().render().contains("warenhaus_unacknowledged_failures_total 4\n"));
+    }
+
+    #[test]
+    fn inserts_replace_rows_with_the_same_unique_key() {
+        let root = initialize();
+        let root_path = root.path().to_path_buf();
+        let config = || SchemaConfig {
+            unique_key: Some("url".into()),
+            ..schema_config_with_timestamp_and_two_columns()
+        };
+        let mut container = Container::new(&root_path, config()).unwrap();
+        let row = |url: &str, points: i64| IndexParams {
+            fields: vec!["url".into(), "points".into()],
+            values: vec![url.into(), points.into()],
+        };
+        assert_eq!(container.index(row("https://google.com", 1)).unwrap(), 1);
+        assert_eq!(container.index(row("https://github.com", 2)).unwrap(), 2);
+        assert_eq!(container.index(row("https://google.com", 3)).unwrap(), 1);
+        assert!(matches!(
+            container.index_transaction(vec![row("https://example.com", 4), row("https://github.com", 5)]),
+            Err(ContainerError::TransactionAborted { row: 1, .. })
+        ));
+        assert!(matches!(
+            container.update(2, row("https://google.com", 6)),
+            Err(ContainerError::DuplicateKey(_))
+        ));
+
+        let points = |container: &Container| {
+            container
+                .columns
+                .all_rows()
+                .iter()
+                .map(|row| (row.get("id").unwrap().to_owned(), row.get("points").unwrap().to_owned()))
+                .collect::<Vec<_>>()
+        };
+        //Replaced rows move to the end
+        let expected = vec![(Cell::Int(2), Cell::Int(2)), (Cell::Int(1), Cell::Int(3))];
+        assert_eq!(points(&container), expected);
+        //The replacing insert and the rejected transaction
+        let conflicts = KeyConflicts { fresh: 2, conflicts: 2 };
+        assert_eq!(container.metrics().key_conflicts, vec![("url".to_string(), conflicts)]);
+        drop(container);
+
+        let mut container = Container::new(&root_path, config()).unwrap();
+        assert_eq!(points(&container), expected);
+        assert_eq!(container.index(row("https://github.com", 7)).unwrap(), 2);
+        assert_eq!(points(&container), vec![(Cell::Int(1), Cell::Int(3)), (Cell::Int(2), Cell::Int(7))]);
+    }
+
+    #[test]
+    fn unique_columns_reject_values_held_by_another_row() {
+        let root = initialize();
+        let root_path = root.path().to_path_buf();
+        let mut config = schema_config_with_timestamp_and_two_columns();
+        config.columns[0].unique = true;
+        let mut container = Container::new(&root_path, config.clone()).unwrap();
+        let row = |url: &str, points: i64| IndexParams {
+            fields: vec!["url".into(), "points".into()],
+            values: vec![url.into(), points.into()],
+        };
+        assert_eq!(container.index(row("https://google.com", 1)).unwrap(), 1);
+        assert_eq!(container.index(row("https://github.com", 2)).unwrap(), 2);
+        let err = container.index(row("https://google.com", 3)).unwrap_err();
+        assert!(matches!(&err, ContainerError::UniqueViolation { column, .. } if column == "url"));
+        assert!(err.is_conflict());
+        assert!(container.validate(row("https://github.com", 3)).is_err());
+        let err = container
+            .index_transaction(vec![row("https://example.com", 4), row("https://example.com", 5)])
+            .unwrap_err();
+        assert_eq!(err.failed_row(), Some(1));
+        assert!(err.is_conflict());
+
+        //Updates may keep their own value, but not take another row's
+        container.update(1, row("https://google.com", 6)).unwrap();
+        assert!(matches!(container.update(1, row("https://github.com", 7)), Err(ContainerError::UniqueViolation { .. })));
+        container.delete(2).unwrap();
+        assert_eq!(container.index(row("https://github.com", 8)).unwrap(), 3);
+        drop(container);
+
+        let mut container = Container::new(&root_path, config).unwrap();
+        assert!(matches!(container.index(row("https://github.com", 9)), Err(ContainerError::UniqueViolation { .. })));
+        assert_eq!(container.index(row("https://example.com", 10)).unwrap(), 4);
+    }
+
+    #[test]
+    fn rows_are_found_by_primary_key() {
+        let root = initialize();
+        let root_path = root.path().to_path_buf();
+        let mut config = schema_config_with_timestamp_and_two_columns();
+        let container = Container::new(&root_path, config.clone()).unwrap();
+        assert!(matches!(container.find_by_key("https://google.com"), Err(FilterError::NoPrimaryKey)));
+        drop(container);
+
+        config.primary_key = Some("url".into());
+        let mut container = Container::new(&root_path, config.clone()).unwrap();
+        let row = |url: &str, points: i64| IndexParams {
+            fields: vec!["url".into(), "points".into()],
+            values: vec![url.into(), points.into()],
+        };
+        assert_eq!(container.index(row("https://google.com", 1)).unwrap(), 1);
+        assert!(matches!(container.index(row("https://google.com", 2)), Err(ContainerError::UniqueViolation { .. })));
+        let found = container.find_by_key("https://google.com").unwrap().unwrap();
+        assert_eq!(found.get("points"), Some(&Cell::Int(1)));
+        assert!(container.find_by_key("https://github.com").unwrap().is_none());
+
+        container.delete(1).unwrap();
+        assert!(container.find_by_key("https://google.com").unwrap().is_none());
+        drop(container);
+
+        config.columns[0].nullable = true;
+        assert!(matches!(Container::new(&root_path, config), Err(ContainerError::InvalidPrimaryKey(_))));
+    }
+
+    #[test]
+    fn duplicate_ids_get_reassigned() {
+        let root = initialize();
+        let root_path = root.path().to_path_buf();
+        let mut container = Container::new(&root_path, schema_config_with_timestamp_and_two_columns()).unwrap();
+        let row = |points: i64| IndexParams {
+            fields: vec!["url".into(), "points".into()],
+            values: vec!["https://google.com".into(), points.into()],
+        };
+        container.index(row(1)).unwrap();
+        container.index(row(2)).unwrap();
+        //The auto index losing its last update hands out id 2 again
+        container.index_counter.reset_to(1);
+        container.index(row(3)).unwrap();
+
+        let diagnostics = container.id_diagnostics().unwrap();
+        assert_eq!(diagnostics.duplicates, vec![2]);
+        let diagnostics = container.reassign_duplicate_ids().unwrap();
+        assert!(diagnostics.duplicates.is_empty());
+        assert_eq!(diagnostics.committed_seq, 3);
+
+        let ids = |container: &Container| container.columns.find_column("id").unwrap().entries().to_vec();
+        let expected = vec![Cell::Int(1), Cell::Int(2), Cell::Int(3)];
+        assert_eq!(ids(&container), expected);
+        drop(container);
+
+        assert_eq!(Container::repair_from_wal(&root_path, &StorageBackendConfig::Local).unwrap(), 3);
+        let container = Container::new(&root_path, schema_config_with_timestamp_and_two_columns()).unwrap();
+        assert_eq!(ids(&container), expected);
+        assert_eq!(container.committed_seq(), 3);
+    }
+
+    #[test]
+    fn replica_snapshots_cut_off_rows_missing_from_some_columns() {
+        let root = initialize();
+        let primary_path = root.path().join("primary");
+        std::fs::create_dir(&primary_path).unwrap();
+        let mut container = Container::new(&primary_path, schema_config_with_timestamp()).unwrap();
+        for url in ["https://google.com", "https://google.com/maps"] {
+            let params = IndexParams {
+                fields: vec!["url".into()],
+                values: vec![url.into()],
+            };
+            container.index(params).unwrap();
+        }
+        container.flush().unwrap();
+        //The primary got to append a third id before the snapshot copied the other columns
+        let mut id_column = container.columns.columns.remove(0);
+        id_column.insert(Cell::Int(3)).unwrap();
+        id_column.flush().unwrap();
+
+        let mut snapshots = ReplicaSnapshots::new(&primary_path, &root.path().join("replica"));
+        snapshots.reset().unwrap();
+        let snapshot_path = snapshots.take().unwrap();
+        let replica = Container::open_replica(&snapshot_path, schema_config_with_timestamp()).unwrap();
+        assert!(replica.is_warm());
+        assert_eq!(replica.id_diagnostics().unwrap().rows, 2);
+
+        let next_snapshot_path = snapshots.take().unwrap();
+        snapshots.remove_all_but(&next_snapshot_path);
+        assert!(!snapshot_path.exists());
+        assert!(next_snapshot_path.exists());
+    }
+
+    #[test]
+    fn snapshots_hold_the_rows_buffered_when_taken() {
+        let root = initialize();
+        let root_path = root.path().to_path_buf();
+        let mut container = Container::new(&root_path, schema_config_with_timestamp()).unwrap();
+        let url_params = |url: &str| IndexParams {
+            fields: vec!["url".into()],
+            values: vec![url.into()],
+        };
+        container.index(url_params("https://google.com")).unwrap();
+        container.index(url_params("https://google.com/maps")).unwrap();
+
+        let snapshot_path = container.snapshot().unwrap();
+        assert!(snapshot_path.starts_with(Container::snapshots_path(&root_path)));
+        container.index(url_params("https://google.com/mail")).unwrap();
+
+        let mut restored = Container::new(&snapshot_path, schema_config_with_timestamp()).unwrap();
+        assert_eq!(restored.id_diagnostics().unwrap().rows, 2);
+        assert_eq!(restored.index(url_params("https://google.com/mail")).unwrap(), 3);
+    }
+
+    #[test]
+    fn derived_table_keeps_ids_of_appended_rows() {
+        let root = initialize();
+        let root_path = root.path().to_path_buf();
+        let mut container = Container::new(&root_path, schema_config_with_timestamp_and_two_columns()).unwrap();
+        for points in [1, 2, 3] {
+            let params = IndexParams {
+                fields: vec!["url".into(), "points".into()],
+                values: vec!["https://google.com".into(), points.into()],
+            };
+            container.index(params).unwrap();
+        }
+
+        let rows = container.columns.rows(vec![1, 2]);
+        let mut derived = container.derive(&root_path.join("tables").join("top")).unwrap();
+        assert_eq!(derived.append_rows(rows).unwrap(), 2);
+
+        assert_eq!(derived.columns.layout(), container.columns.layout());
+        assert_eq!(derived.columns.find_column("id").unwrap().entries(), &[Cell::Int(2), Cell::Int(3)]);
+        assert_eq!(derived.committed_seq(), 3);
+        assert_eq!(container.columns.find_column("id").unwrap().entries().len(), 3);
+    }
+
+    #[test]
+    fn transaction_stores_all_rows_or_none() {
+        let root = initialize();
+        let root_path = root.path().to_path_buf();
+        let mut container = Container::new(&root_path, schema_config_with_timestamp_and_two_columns()).unwrap();
+        let row = |points: serde_json::Value| IndexParams {
+            fields: vec!["url".into(), "points".into()],
+            values: vec!["https://google.com".into(), points],
+        };
+
+        let err = container
+            .index_transaction(vec![row(1.into()), row("many".into())])
+            .unwrap_err();
+        assert_eq!(err.failed_row(), Some(1));
+        assert!(err.is_client_error());
+        assert_eq!(container.columns.find_column("points").unwrap().entries().len(), 0);
+        assert_eq!(container.committed_seq(), 0);
+
+        let committed = container.index_transaction(vec![row(1.into()), row(2.into())]).unwrap();
+        assert_eq!(committed.ids, vec![1, 2]);
+        assert_eq!(committed.conflicts, 0);
+        drop(container);
+
+        assert_eq!(Container::repair_from_wal(&root_path, &StorageBackendConfig::Local).unwrap(), 2);
+        let container = Container::new(&root_path, schema_config_with_timestamp_and_two_columns()).unwrap();
+        assert_eq!(container.columns.find_column("points").unwrap().entries(), &[Cell::Int(1), Cell::Int(2)]);
+        assert_eq!(container.committed_seq(), 2);
+    }
+
+    #[test]
+    fn batches_get_validated_before_any_row_is_written() {
+        let root = initialize();
+        let root_path = root.path().to_path_buf();
+        let mut config = schema_config_with_timestamp_and_two_columns();
+        config.write_buffer_size = 256;
+        config.columns[1].encoding = ColumnEncoding::Delta;
+        let mut container = Container::new(&root_path, config.clone()).unwrap();
+        let row = |n: usize, points: serde_json::Value| IndexParams {
+            fields: vec!["url".into(), "points".into()],
+            values: vec![format!("https://example.com/{}", n).into(), points],
+        };
+
+        let err = container.index_batch(vec![row(0, 1.into()), row(1, "many".into())]).unwrap_err();
+        assert_eq!(err.failed_row(), Some(1));
+        assert_eq!(container.committed_seq(), 0);
+
+        let ids = container.index_batch((0..100).map(|n| row(n, (n as i64 * 10).into())).collect()).unwrap();
+        assert_eq!(ids, (1..=100).collect::<Vec<i64>>());
+        //The batch exceeds the write buffer, so it went to the files right away
+        assert!(std::fs::metadata(Column::file_path(&root_path, "url")).unwrap().len() > 100 * 20);
+        drop(container);
+
+        let container = Container::new(&root_path, config).unwrap();
+        let points = (0..100).map(|n| Cell::Int(n * 10)).collect::<Vec<_>>();
+        assert_eq!(container.columns.find_column("points").unwrap().entries(), points.as_slice());
+        assert_eq!(container.columns.find_column("url").unwrap().entries()[99], Cell::String("https://example.com/99".into()));
+        assert_eq!(container.committed_seq(), 100);
+    }
+
+    #[test]
+    fn failed_appends_get_rolled_back_from_the_log_and_all_columns() {
+        let root = initialize();
+        let root_path = root.path().to_path_buf();
+        let mut config = schema_config_with_timestamp_and_two_columns();
+        config.write_buffer_size = 64;
+        config.columns[0].indexed = true;
+        let mut container = Container::new(&root_path, config.clone()).unwrap();
+        let row = |n: i64| IndexParams {
+            fields: vec!["url".into(), "points".into()],
+            values: vec![format!("https://example.com/{}", n).into(), n.into()],
+        };
+        container.index_transaction(vec![row(1), row(2)]).unwrap();
+
+        let rows = (3..=10).map(|n| container.prepare_row(row(n), &Lineage::default()).unwrap()).collect::<Vec<_>>();
+        let err = container
+            .append_atomically(rows.len(), |container| {
+                container.wal.append_transaction(&rows)?;
+                container.columns.commit_batch(rows)?;
+                container.columns.flush()?;
+                //Stands in for a write failing after the rows reached the columns and the files
+                Err(ContainerError::WarmingUp)
+            })
+            .unwrap_err();
+        assert!(matches!(err, ContainerError::WarmingUp));
+        assert_eq!(container.committed_seq(), 2);
+        assert_eq!(container.columns.find_column("points").unwrap().entries(), &[Cell::Int(1), Cell::Int(2)]);
+        assert_eq!(container.lookup("url", "https://example.com/5").unwrap().len(), 0);
+
+        assert_eq!(container.index(row(3)).unwrap(), 3);
+        drop(container);
+        let container = Container::new(&root_path, config.clone()).unwrap();
+        let points = [Cell::Int(1), Cell::Int(2), Cell::Int(3)];
+        assert_eq!(container.columns.find_column("points").unwrap().entries(), &points);
+        assert_eq!(container.columns.find_column("url").unwrap().entries().len(), 3);
+        drop(container);
+        assert_eq!(Container::repair_from_wal(&root_path, &StorageBackendConfig::Local).unwrap(), 3);
+    }
+
+    #[test]
+    fn flush_policies_decide_when_buffered_records_reach_the_files() {
+        let root = initialize();
+        let root_path = root.path().to_path_buf();
+        let url_file_len = || std::fs::metadata(Column::file_path(&root_path, "url")).unwrap().len();
+        let row = |n: i64| IndexParams {
+            fields: vec!["url".into(), "points".into()],
+            values: vec![format!("https://example.com/{}", n).into(), n.into()],
+        };
+
+        let mut config = schema_config_with_timestamp_and_two_columns();
+        config.write_buffer_size = 128;
+        let mut container = Container::new(&root_path, config.clone()).unwrap();
+        let empty_len = url_file_len();
+        container.index(row(1)).unwrap();
+        assert!(url_file_len() > empty_len);
+        assert_eq!(container.columns.find_column("url").unwrap().buffered_bytes(), 0);
+        drop(container);
+
+        config.flush_policy = FlushPolicy::WhenFull;
+        let mut container = Container::new(&root_path, config.clone()).unwrap();
+        let flushed_len = url_file_len();
+        container.index(row(2)).unwrap();
+        assert_eq!(url_file_len(), flushed_len);
+        let buffered = container.columns.find_column("url").unwrap().buffered_bytes();
+        assert!(buffered > 0);
+
+        //Records which don't fit into the buffer anymore push the buffered ones to the file
+        let mut n = 3;
+        while url_file_len() == flushed_len {
+            container.index(row(n)).unwrap();
+            n += 1;
+            assert!(container.columns.find_column("url").unwrap().buffered_bytes() <= config.write_buffer_size);
+        }
+        assert!(url_file_len() >= flushed_len + buffered as u64);
+
+        container.flush().unwrap();
+        assert_eq!(container.columns.find_column("url").unwrap().buffered_bytes(), 0);
+        container.index(row(n)).unwrap();
+        //Shutting down writes out what's left
+        drop(container);
+
+        let container = Container::new(&root_path, config).unwrap();
+        let urls = container.columns.find_column("url").unwrap().entries();
+        assert_eq!(urls.len(), n as usize);
+        assert_eq!(urls[n as usize - 1], Cell::String(format!("https://example.com/{}", n)));
+    }
+
+    #[test]
+    fn columns_fail_to_open_when_the_backend_fails() {
+        let root = initialize();
+        let not_a_directory = root.path().join("table");
+        std::fs::write(&not_a_directory, b"").unwrap();
+        let backend = Arc::new(LocalBackend::new(&not_a_directory));
+        let err = Column::new(backend, "url".into(), DataType::String, DEFAULT_WRITE_BUFFER_SIZE).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotADirectory);
+    }
+
+    #[test]
+    fn rollbacks_restore_merged_records_and_flushed_blocks() {
+        let root = initialize();
+        let root_path = root.path().to_path_buf();
+        let mut config = schema_config_with_timestamp_and_two_columns();
+        config.write_buffer_size = 256;
+        config.flush_policy = FlushPolicy::WhenFull;
+        config.columns[0].compression = Some(ColumnCompression::Zstd);
+        config.columns[1].encoding = ColumnEncoding::Delta;
+        let mut container = Container::new(&root_path, config.clone()).unwrap();
+        let row = |n: i64| IndexParams {
+            fields: vec!["url".into(), "points".into()],
+            values: vec![format!("https://example.com/{}", n).into(), n.into()],
+        };
+        for n in 1..=3 {
+            container.index(row(n)).unwrap();
+        }
+
+        //Merges into the last record of the buffer without flushing it
+        let values = container.prepare_row(row(4), &Lineage::default()).unwrap();
+        container
+            .append_atomically(1, |container| {
+                container.columns.commit(values)?;
+                Err(ContainerError::WarmingUp)
+            })
+            .unwrap_err();
+        //Flushes the buffered records, compressed along with the appended ones
+        let rows = (4..=40).map(|n| container.prepare_row(row(n), &Lineage::default()).unwrap()).collect::<Vec<_>>();
+        let points_file = root_path.join(container.columns.find_column("points").unwrap().file_names().pop().unwrap());
+        let inode = |path: &Path| std::fs::metadata(path).unwrap().ino();
+        let points_inode = inode(&points_file);
+        container
+            .append_atomically(rows.len(), |container| {
+                container.columns.commit_batch(rows)?;
+                container.columns.flush()?;
+                Err(ContainerError::WarmingUp)
+            })
+            .unwrap_err();
+        //Cut off in place rather than written anew, which would need space on a full volume
+        assert_eq!(inode(&points_file), points_inode);
+        let points = [1, 2, 3, 4].map(Cell::Int);
+        assert_eq!(container.columns.find_column("points").unwrap().entries(), &points[..3]);
+
+        container.index(row(4)).unwrap();
+        assert_eq!(container.columns.find_column("points").unwrap().entries(), &points);
+        drop(container);
+        let container = Container::new(&root_path, config.clone()).unwrap();
+        assert_eq!(container.columns.find_column("points").unwrap().entries(), &points);
+        let urls = (1..=4).map(|n| Cell::String(format!("https://example.com/{}", n))).collect::<Vec<_>>();
+        assert_eq!(container.columns.find_column("url").unwrap().entries(), urls.as_slice());
+    }
+
+    #[test]
+    fn nullable_columns_read_back_as_null() {
+        let root = initialize();
+        let mut config = schema_config_with_timestamp();
+        config.columns.push(ColumnConfig {
+            name: "title".into(),
+            data_type: DataTypeConfig::String,
+            computed: false,
+            nullable: true,
+            default: None,
+            auto_generate: false,
+            server_computed: None,
+            indexed: false,
+            unique: false,
+            encoding: ColumnEncoding::Plain,
+            compression: None,
+            docs: Docs::default(),
+        });
+        let mut container = Container::new(&root.path().to_path_buf(), config).unwrap();
+        container.index(IndexParams {
+            fields: vec!["url".into(), "title".into()],
+            values: vec!["https://google.com".into(), serde_json::Value::Null],
+        }).unwrap();
+        container.index(IndexParams {
+            fields: vec!["url".into()],
+            values: vec!["https://bing.com".into()],
+        }).unwrap();
+
+        let rows = container.columns.all_rows();
+        assert_eq!(rows.len(), 2);
+        for row in rows {
+            let row = serde_json::to_value(row.to_view_object()).unwrap();
+            assert_eq!(row["title"], serde_json::Value::Null);
+        }
+
+        let err = container.index(IndexParams {
+            fields: vec!["title".into()],
+            values: vec!["Bing".into()],
+        }).unwrap_err();
+        assert!(matches!(err, ContainerError::SchemaMismatch { ref missing, .. } if missing == &vec!["url".to_string()]));
+    }
+
+    #[test]
+    fn omitted_columns_get_their_default() {
+        let root = initialize();
+        let mut config = schema_config_with_timestamp_and_two_columns();
+        config.columns[1].default = Some(json!(0));
+        let mut container = Container::new(&root.path().to_path_buf(), config.clone()).unwrap();
+        container.index(IndexParams {
+            fields: vec!["url".into()],
+            values: vec!["https://google.com".into()],
+        }).unwrap();
+        container.index(IndexParams {
+            fields: vec!["url".into(), "points".into()],
+            values: vec!["https://bing.com".into(), 5.into()],
+        }).unwrap();
+        assert_eq!(container.columns.find_column("points").unwrap().entries(), &[Cell::Int(0), Cell::Int(5)]);
+        drop(container);
+
+        config.columns[1].default = Some(json!("none"));
+        let err = Container::new(&root.path().to_path_buf(), config).unwrap_err();
+        assert!(matches!(err, ContainerError::InvalidDataType(ref column, ..) if column == "points"));
+    }
+
+    #[test]
+    fn timestamp_columns_accept_rfc3339_dates() {
+        let root = initialize();
+        let mut config = schema_config_with_timestamp();
+        config.columns.push(ColumnConfig {
+            name: "published_at".into(),
+            data_type: DataTypeConfig::Timestamp,
+            computed: false,
+            nullable: false,
+            default: None,
+            auto_generate: false,
+            server_computed: None,
+            indexed: false,
+            unique: false,
+            encoding: ColumnEncoding::Plain,
+            compression: None,
+            docs: Docs::default(),
+        });
+        let mut container = Container::new(&root.path().to_path_buf(), config).unwrap();
+        for published_at in [json!("2023-02-23T05:07:40+01:00"), json!(1677125261)] {
+            container.index(IndexParams {
+                fields: vec!["url".into(), "published_at".into()],
+                values: vec!["https://google.com".into(), published_at],
+            }).unwrap();
+        }
+        let err = container.index(IndexParams {
+            fields: vec!["url".into(), "published_at".into()],
+            values: vec!["https://google.com".into(), json!("yesterday")],
+        }).unwrap_err();
+        assert!(matches!(err, ContainerError::InvalidDataType(..)));
+
+        assert_eq!(
+            container.columns.find_column("published_at").unwrap().entries(),
+            &[Cell::Timestamp(1677125260), Cell::Timestamp(1677125261)]
+        );
+        assert!(matches!(container.columns.find_column("timestamp").unwrap().entries()[0], Cell::Timestamp(_)));
+        let row = serde_json::to_value(container.columns.all_rows()[0].to_view_object()).unwrap();
+        assert_eq!(row["published_at"], json!("2023-02-23T04:07:40Z"));
+
+        let filter = QueryFilter {
+            from: Some(1677125261),
+            time_column: Some("published_at".into()),
+            expression: Some(Expression::parse("published_at > 1677125260").unwrap()),
+            ..Default::default()
+        };
+        assert_eq!(container.columns.matching_rows(&filter).unwrap(), vec![1]);
+    }
+
+    #[test]
+    fn dates_with_fractions_of_a_second_get_warned_about() {
+        let root = initialize();
+        let mut config = schema_config_with_timestamp();
+        config.columns.push(ColumnConfig {
+            name: "published_at".into(),
+            data_type: DataTypeConfig::Timestamp,
+            computed: false,
+            nullable: false,
+            default: None,
+            auto_generate: false,
+            server_computed: None,
+            indexed: false,
+            unique: false,
+            encoding: ColumnEncoding::Plain,
+            compression: None,
+            docs: Docs::default(),
+        });
+        let mut container = Container::new(&root.path().to_path_buf(), config).unwrap();
+        let params = |published_at: serde_json::Value| IndexParams {
+            fields: vec!["url".into(), "published_at".into()],
+            values: vec!["https://google.com".into(), published_at],
+        };
+        assert!(container.coerced_values(&params(json!("2023-02-23T05:07:40+01:00"))).is_empty());
+        assert!(container.coerced_values(&params(json!(1677125261))).is_empty());
+        let warnings = container.coerced_values(&params(json!("2023-02-23T05:07:40.250+01:00")));
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].kind, WarningKind::Coerced);
+
+        let committed = container
+            .index_transaction(vec![params(json!("2023-02-23T05:07:40Z")), params(json!("2023-02-23T05:07:40.5Z"))])
+            .unwrap();
+        assert_eq!(committed.warnings.len(), 1);
+        assert!(committed.warnings[0].message.starts_with("Row 1: published_at"));
+    }
+
+    #[test]
+    fn large_strings_get_compressed() {
+        let root = initialize();
+        let root_path = root.path().to_path_buf();
+        let html = "<li><a href=\"https://google.com\">Google</a></li>".repeat(100);
+        let mut container = Container::new(&root_path, schema_config_with_timestamp()).unwrap();
+        for url in [html.as_str(), "https://google.com"] {
+            container.index(IndexParams {
+                fields: vec!["url".into()],
+                values: vec![url.into()],
+            }).unwrap();
+        }
+        drop(container);
+
+        let file_len = std::fs::metadata(root_path.join("column_url")).unwrap().len();
+        assert!(file_len < html.len() as u64 / 4, "{} bytes stored for {} bytes of html", file_len, html.len());
+        let container = Container::new(&root_path, schema_config_with_timestamp()).unwrap();
+        assert_eq!(
+            container.columns.find_column("url").unwrap().entries(),
+            &[Cell::String(html), Cell::String("https://google.com".into())]
+        );
+    }
+
+    #[test]
+    fn array_columns_store_lists() {
+        let root = initialize();
+        let root_path = root.path().to_path_buf();
+        let mut config = schema_config_with_timestamp();
+        config.columns.push(ColumnConfig {
+            name: "tags".into(),
+            data_type: DataTypeConfig::Array(Box::new(DataTypeConfig::String)),
+            computed: false,
+            nullable: false,
+            default: None,
+            auto_generate: false,
+            server_computed: None,
+            indexed: false,
+            unique: false,
+            encoding: ColumnEncoding::Plain,
+            compression: None,
+            docs: Docs::default(),
+        });
+        let mut container = Container::new(&root_path, config.clone()).unwrap();
+        for tags in [json!(["search", "maps"]), json!([])] {
+            container.index(IndexParams {
+                fields: vec!["url".into(), "tags".into()],
+                values: vec!["https://google.com".into(), tags],
+            }).unwrap();
+        }
+        for tags in [json!(["search", 1]), json!("search")] {
+            let err = container.index(IndexParams {
+                fields: vec!["url".into(), "tags".into()],
+                values: vec!["https://google.com".into(), tags],
+            }).unwrap_err();
+            assert!(matches!(err, ContainerError::InvalidDataType(..)));
+        }
+        drop(container);
+
+        let container = Container::new(&root_path, config).unwrap();
+        let tags = vec![Cell::String("search".into()), Cell::String("maps".into())];
+        assert_eq!(
+            container.columns.find_column("tags").unwrap().entries(),
+            &[Cell::Array(tags), Cell::Array(vec![])]
+        );
+        let row = serde_json::to_value(container.columns.all_rows()[0].to_view_object()).unwrap();
+        assert_eq!(row["tags"], json!(["search", "maps"]));
+
+        let filter = QueryFilter {
+            equals: vec![("tags".into(), r#"["search","maps"]"#.into())],
+            ..Default::default()
+        };
+        assert_eq!(container.columns.matching_rows(&filter).unwrap(), vec![0]);
+    }
+
+    #[test]
+    fn json_columns_store_values_verbatim() {
+        let root = initialize();
+        let root_path = root.path().to_path_buf();
+        let mut config = schema_config_with_timestamp();
+        config.columns.push(ColumnConfig {
+            name: "payload".into(),
+            data_type: DataTypeConfig::Json,
+            computed: false,
+            nullable: false,
+            default: None,
+            auto_generate: false,
+            server_computed: None,
+            indexed: false,
+            unique: false,
+            encoding: ColumnEncoding::Plain,
+            compression: None,
+            docs: Docs::default(),
+        });
+        let mut container = Container::new(&root_path, config.clone()).unwrap();
+        let payloads = [json!({"user": {"id": 7, "tags": ["a", null]}, "ok": true}), json!("plain"), json!(1.5)];
+        for payload in payloads.clone() {
+            container.index(IndexParams {
+                fields: vec!["url".into(), "payload".into()],
+                values: vec!["https://google.com".into(), payload],
+            }).unwrap();
+        }
+        let err = container.index(IndexParams {
+            fields: vec!["url".into(), "payload".into()],
+            values: vec!["https://google.com".into(), serde_json::Value::Null],
+        }).unwrap_err();
+        assert!(matches!(err, ContainerError::InvalidDataType(..)));
+        drop(container);
+
+        let container = Container::new(&root_path, config).unwrap();
+        let rows = container
+            .columns
+            .all_rows()
+            .iter()
+            .map(|row| serde_json::to_value(row.to_view_object()).unwrap()["payload"].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(rows, payloads);
+        assert_eq!(DataType::infer(&payloads[0]), Some(DataType::Json));
+
+        let filter = QueryFilter {
+            equals: vec![("payload".into(), r#""plain""#.into())],
+            ..Default::default()
+        };
+        assert_eq!(container.columns.matching_rows(&filter).unwrap(), vec![1]);
+    }
+
+    #[test]
+    fn run_length_encoding_collapses_repeated_values() {
+        let root = initialize();
+        let root_path = root.path().to_path_buf();
+        let mut config = schema_config_with_timestamp();
+        config.flush_policy = FlushPolicy::WhenFull;
+        config.columns.push(ColumnConfig {
+            name: "status".into(),
+            data_type: DataTypeConfig::Int,
+            computed: false,
+            nullable: false,
+            default: None,
+            auto_generate: false,
+            server_computed: None,
+            indexed: false,
+            unique: false,
+            encoding: ColumnEncoding::RunLength,
+            compression: None,
+            docs: Docs::default(),
+        });
+        let mut container = Container::new(&root_path, config.clone()).unwrap();
+        let statuses = (0..100).map(|n| if n < 60 { 200 } else { 404 }).collect::<Vec<i64>>();
+        for status in &statuses {
+            container.index(IndexParams {
+                fields: vec!["url".into(), "status".into()],
+                values: vec!["https://google.com".into(), json!(status)],
+            }).unwrap();
+        }
+        let id = container.columns.find_column("id").unwrap().entries()[10].as_int().unwrap();
+        container.delete(*id).unwrap();
+        let status_column = container.columns.find_column("status").unwrap();
+        let plain_len = statuses.iter().map(|status| Column::record_size(&Cell::Int(*status)).unwrap()).sum::<u64>();
+        assert!(status_column.file_len() < plain_len);
+        drop(container);
+
+        let container = Container::new(&root_path, config).unwrap();
+        let status_column = container.columns.find_column("status").unwrap();
+        assert_eq!(status_column.entries(), statuses.iter().map(|status| Cell::Int(*status)).collect::<Vec<_>>());
+        assert!(status_column.tombstones().contains(&10));
+    }
+
+    #[test]
+    fn compressed_columns_read_back_their_blocks() {
+        let root = initialize();
+        let root_path = root.path().to_path_buf();
+        let mut config = schema_config_with_timestamp();
+        config.flush_policy = FlushPolicy::WhenFull;
+        config.write_buffer_size = 64 * 1024;
+        config.columns[0].compression = Some(ColumnCompression::Zstd);
+        let mut container = Container::new(&root_path, config.clone()).unwrap();
+        let urls = (0..500).map(|n| format!("https://google.com/search?page={}", n % 7)).collect::<Vec<_>>();
+        for url in &urls {
+            container.index(IndexParams {
+                fields: vec!["url".into()],
+                values: vec![json!(url)],
+            }).unwrap();
+        }
+        let id = container.columns.find_column("id").unwrap().entries()[3].as_int().copied().unwrap();
+        container.delete(id).unwrap();
+        drop(container);
+
+        let container = Container::new(&root_path, config).unwrap();
+        let url_column = container.columns.find_column("url").unwrap();
+        let plain_len = urls.iter().map(|url| Column::record_size(&Cell::String(url.clone())).unwrap()).sum::<u64>();
+        assert!(url_column.file_len() < plain_len / 4);
+        assert_eq!(url_column.entries(), urls.into_iter().map(Cell::String).collect::<Vec<_>>());
+        assert!(url_column.tombstones().contains(&3));
+    }
+
+    #[test]
+    fn pruned_rows_only_hold_the_requested_columns() {
+        let root = initialize();
+        let root_path = root.path().to_path_buf();
+        let mut container = Container::new(&root_path, schema_config_with_timestamp()).unwrap();
+        container.index(IndexParams {
+            fields: vec!["url".into()],
+            values: vec!["https://google.com".into()],
+        }).unwrap();
+
+        let pruned = container.columns.rows_with_columns(vec![0], Some(&[]));
+        assert_eq!(pruned[0].get("url"), None);
+        assert!(pruned[0].get("id").is_some() && pruned[0].get("timestamp").is_some());
+        let with_url = container.columns.rows_with_columns(vec![0], Some(&["url".to_string()]));
+        assert_eq!(with_url[0].get("url"), Some(&Cell::String("https://google.com".into())));
+        assert_eq!(container.rows(vec![0])[0].get("url"), Some(&Cell::String("https://google.com".into())));
+    }
+
+    #[test]
+    fn torn_writes_get_cut_off_on_load() {
+        let root = initialize();
+        let root_path = root.path().to_path_buf();
+        let mut container = Container::new(&root_path, schema_config_with_timestamp()).unwrap();
+        for url in ["https://google.com", "https://google.com/maps"] {
+            container.index(IndexParams {
+                fields: vec!["url".into()],
+                values: vec![url.into()],
+            }).unwrap();
+        }
+        drop(container);
+        let column_path = root_path.join(Column::file_name("url"));
+        let intact = std::fs::read(&column_path).unwrap();
+
+        //The header of a record whose payload never made it to disk
+        let mut torn = intact.clone();
+        torn.extend_from_slice(&[0xde, 0xad, 0xbe, 0xef, 3, 64, 0, 0, 0, b'h']);
+        std::fs::write(&column_path, &torn).unwrap();
+        let mut container = Container::new(&root_path, schema_config_with_timestamp()).unwrap();
+        assert_eq!(std::fs::read(&column_path).unwrap(), intact);
+        container.index(IndexParams {
+            fields: vec!["url".into()],
+            values: vec!["https://google.com/search".into()],
+        }).unwrap();
+        drop(container);
+        let container = Container::new(&root_path, schema_config_with_timestamp()).unwrap();
+        assert_eq!(container.columns.find_column("url").unwrap().entries().len(), 3);
+        drop(container);
+
+        //A corrupt record followed by intact ones isn't a torn write
+        let mut corrupt = std::fs::read(&column_path).unwrap();
+        let first_record = intact.len() - (9 + "https://google.com".len()) - (9 + "https://google.com/maps".len());
+        corrupt[first_record] ^= 0xff;
+        std::fs::write(&column_path, &corrupt).unwrap();
+        assert!(Container::new(&root_path, schema_config_with_timestamp()).is_err());
+    }
+
+    #[test]
+    fn inserts_pause_while_the_disk_is_under_pressure() {
+        let root = initialize();
+        let root_path = root.path().to_path_buf();
+        let params = || IndexParams {
+            fields: vec!["url".into()],
+            values: vec!["https://google.com".into()],
+        };
+        let mut config = schema_config_with_timestamp();
+        config.disk_pressure = Some(DiskPressureConfig {
+            min_free_bytes: u64::MAX,
+            max_sync_latency_ms: None,
+        });
+        let mut container = Container::new(&root_path, config.clone()).unwrap();
+        assert!(matches!(container.index(params()), Err(ContainerError::DiskFull(_))));
+        assert!(matches!(container.index_transaction(vec![params()]), Err(ContainerError::DiskFull(_))));
+        drop(container);
+
+        config.disk_pressure = Some(DiskPressureConfig {
+            min_free_bytes: 0,
+            max_sync_latency_ms: Some(0),
+        });
+        let mut container = Container::new(&root_path, config).unwrap();
+        container.index(params()).unwrap();
+        container.disk_pressure.as_mut().unwrap().record_sync(std::time::Duration::from_millis(5));
+        assert!(matches!(container.index(params()), Err(ContainerError::SlowDisk(5))));
+        assert_eq!(container.committed_seq(), 1);
+    }
+
+    #[test]
+    fn uuid_columns_get_generated_when_left_out() {
+        let root = initialize();
+        let root_path = root.path().to_path_buf();
+        let mut config = schema_config_with_timestamp();
+        config.columns.push(ColumnConfig {
+            name: "event_id".into(),
+            data_type: DataTypeConfig::Uuid,
+            computed: false,
+            nullable: false,
+            default: None,
+            auto_generate: true,
+            server_computed: None,
+            indexed: false,
+            unique: false,
+            encoding: ColumnEncoding::Plain,
+            compression: None,
+            docs: Docs::default(),
+        });
+        let mut container = Container::new(&root_path, config.clone()).unwrap();
+        let given = "67e55044-10b1-426f-9247-bb680e5fe0c8";
+        container.index(IndexParams {
+            fields: vec!["url".into(), "event_id".into()],
+            values: vec!["https://google.com".into(), json!(given)],
+        }).unwrap();
+        container.index(IndexParams {
+            fields: vec!["url".into()],
+            values: vec!["https://google.com".into()],
+        }).unwrap();
+        let err = container.index(IndexParams {
+            fields: vec!["url".into(), "event_id".into()],
+            values: vec!["https://google.com".into(), json!("67e55044-10b1-426f-9247")],
+        }).unwrap_err();
+        assert!(matches!(err, ContainerError::InvalidDataType(..)));
+        drop(container);
+
+        let container = Container::new(&root_path, config).unwrap();
+        let event_ids = container.columns.find_column("event_id").unwrap().entries().to_vec();
+        assert_eq!(event_ids[0], Cell::Uuid(Cell::parse_uuid(given).unwrap()));
+        let Cell::Uuid(generated) = event_ids[1] else {
+            panic!("Expected a generated UUID, got {:?}", event_ids[1]);
+        };
+        assert_ne!(event_ids[0], event_ids[1]);
+        assert_eq!(generated >> 76 & 0xf, 4);
+        let row = serde_json::to_value(container.columns.all_rows()[0].to_view_object()).unwrap();
+        assert_eq!(row["event_id"], json!(given));
+
+        let filter = QueryFilter {
+            expression: Some(Expression::parse(&format!("event_id = '{}'", given)).unwrap()),
+            ..Default::default()
+        };
+        assert_eq!(container.columns.matching_rows(&filter).unwrap(), vec![0]);
+    }
+
+    #[test]
+    fn infer_schema_adds_nullable_columns() {
+        let root = initialize();
+        let root_path = root.path().to_path_buf();
+        let mut config = schema_config_with_timestamp();
+        config.infer_schema = true;
+        let mut container = Container::new(&root_path, config.clone()).unwrap();
+        container.index(IndexParams {
+            fields: vec!["url".into()],
+            values: vec!["https://google.com".into()],
+        }).unwrap();
+
+        container.validate(IndexParams {
+            fields: vec!["url".into(), "score".into()],
+            values: vec!["https://bing.com".into(), json!(1.5)],
+        }).unwrap();
+        assert!(container.columns.find_column("score").is_none());
 
+        container.index(IndexParams {
+            fields: vec!["url".into(), "score".into(), "referrer".into()],
+            values: vec!["https://bing.com".into(), json!(1.5), serde_json::Value::Null],
+        }).unwrap();
+        let err = container.index(IndexParams {
+            fields: vec!["url".into(), "meta".into()],
+            values: vec!["https://yahoo.com".into(), json!(["search", 1])],
+        }).unwrap_err();
+        assert!(matches!(err, ContainerError::InvalidFields(ref fields) if fields == &vec!["meta".to_string()]));
+        container.index(IndexParams {
+            fields: vec!["url".into(), "tags".into()],
+            values: vec!["https://yahoo.com".into(), json!(["search"])],
+        }).unwrap();
         assert_eq!(
-            ts_column.entries().len(),
-            1,
-            "Timestamp not found: {:?}",
-            ts_column.entries()
+            container.columns.find_column("tags").unwrap().data_type(),
+            &DataType::Array(Box::new(DataType::String))
         );
-        assert_eq!(url_column.entries().len(), 1);
+        drop(container);
 
-        let url_cell = url_column.entries().get(0).unwrap();
-        if let Cell::String(str) = url_cell {
-            assert_eq!(str, "https://google.com");
-        } else {
-            assert!(false, "Failed to retrieve URL from column: {:?}", url_cell);
-        }
+        let mut container = Container::new(&root_path, config).unwrap();
+        let score = container.schema().columns.into_iter().find(|column| column.name == "score").unwrap();
+        assert_eq!(score.data_type, DataType::Float);
+        assert!(score.nullable && score.inferred);
+        assert!(container.columns.find_column("referrer").is_none());
+        container.index(IndexParams {
+            fields: vec!["url".into()],
+            values: vec!["https://duckduckgo.com".into()],
+        }).unwrap();
+        let scores = container.columns.find_column("score").unwrap().entries().to_vec();
+        assert_eq!(scores, vec![Cell::Null, Cell::Float(1.5), Cell::Null, Cell::Null]);
     }
 
     #[test]
-    fn insert_a_record_without_auto_timestamp_column() {
-        initialize();
-        let mut container =
-            Container::new(&Path::new("/tmp").to_path_buf(), schema_config_without_timestamp()).unwrap();
-
-        let params = IndexParams {
-            fields: vec!["url".into()],
-            values: vec![serde_json::Value::String("https://google.com".into())],
+    fn transactions_only_infer_columns_once_all_rows_are_valid() {
+        let root = initialize();
+        let root_path = root.path().to_path_buf();
+        let mut config = schema_config_with_timestamp();
+        config.infer_schema = true;
+        let mut container = Container::new(&root_path, config).unwrap();
+        let row = |url: &str, score: serde_json::Value| IndexParams {
+            fields: vec!["url".into(), "score".into()],
+            values: vec![url.into(), score],
         };
-        container.index(params).unwrap();
 
-        let ts_column = container.columns.find_column("timestamp");
-        let url_column = container.columns.find_column("url").unwrap();
+        let err = container.index_transaction(vec![row("https://google.com", json!(1.5)), IndexParams {
+            fields: vec!["score".into()],
+            values: vec![json!(2.5)],
+        }]).unwrap_err();
+        assert!(matches!(err, ContainerError::TransactionAborted { row: 1, .. }), "{:?}", err);
+        assert!(container.columns.find_column("score").is_none());
+
+        let err = container.index_transaction(vec![row("https://google.com", json!(1.5)), row("https://bing.com", json!("high"))]).unwrap_err();
         assert!(
-            ts_column.is_none(),
-            "Wasn't expecting timestamp column, yet it is present: {:?}",
-            ts_column
-        );
-        assert_eq!(
-            url_column.entries().len(),
-            1,
-            "was expecting one url, found more than one"
+            matches!(err, ContainerError::TransactionAborted { row: 1, ref source } if matches!(**source, ContainerError::InvalidDataType(..))),
+            "{:?}",
+            err
         );
+        assert!(container.columns.find_column("score").is_none());
+        assert_eq!(container.index_counter.counter(), 0);
 
-        let url_cell = url_column.entries().get(0).unwrap();
-        if let Cell::String(str) = url_cell {
-            assert_eq!(str, "https://google.com");
-        } else {
-            assert!(false, "Failed to retrieve URL from column: {:?}", url_cell);
-        }
+        let committed = container.index_transaction(vec![
+            IndexParams {
+                fields: vec!["url".into()],
+                values: vec!["https://google.com".into()],
+            },
+            row("https://bing.com", json!(1.5)),
+        ]).unwrap();
+        assert_eq!(committed.ids, vec![1, 2]);
+        let scores = container.columns.find_column("score").unwrap().entries().to_vec();
+        assert_eq!(scores, vec![Cell::Null, Cell::Float(1.5)]);
     }
 
     #[test]
-    fn fail_on_null_value() {
-        initialize();
-        let mut container =
-            Container::new(&Path::new("/tmp").to_path_buf(), schema_config_without_timestamp()).unwrap();
-
-        let params = IndexParams {
-            fields: vec!["url".into()],
-            values: vec![serde_json::Value::Null],
+    fn validate_does_not_store_or_use_up_ids() {
+        let root = initialize();
+        let mut container = Container::new(
+            &root.path().to_path_buf(),
+            schema_config_with_timestamp_and_two_columns(),
+        )
+        .unwrap();
+        let row = |points: serde_json::Value| IndexParams {
+            fields: vec!["url".into(), "points".into()],
+            values: vec!["https://google.com".into(), points],
         };
-        let result = container.index(params);
-        assert!(
-            result.is_err(),
-            "Was expecting error on insert. Got {:?}",
-            result
-        );
 
-        let url_column = container.columns.find_column("url").unwrap();
-        assert_eq!(
-            url_column.entries().len(),
-            0,
-            "was expecting no url, found: {:?}",
-            url_column.entries()
-        );
+        container.validate(row(1.into())).unwrap();
+        assert!(matches!(container.validate(row("many".into())), Err(ContainerError::InvalidDataType(..))));
+        assert_eq!(container.columns.find_column("url").unwrap().entries().len(), 0);
+
+        assert_eq!(container.index(row(1.into())).unwrap(), 1);
     }
 
     #[test]
-    fn reject_insert_when_data_type_is_incompatible() {
-        initialize();
-        let mut container =
-            Container::new(&Path::new("/tmp").to_path_buf(), schema_config_without_timestamp()).unwrap();
+    fn incompatible_data_type_names_the_failing_field() {
+        let root = initialize();
+        let mut container = Container::new(
+            &root.path().to_path_buf(),
+            schema_config_with_timestamp_and_two_columns(),
+        )
+        .unwrap();
         let params = IndexParams {
-            fields: vec!["url".into()],
-            values: vec![json!(2342)],
+            fields: vec!["url".into(), "points".into()],
+            values: vec!["https://google.com".into(), "many".into()],
         };
 
-        let result = container.index(params);
-        assert!(
-            result.is_err(),
-            "Was expecting error on insert. Got {:?}",
-            result
-        );
+        let err = container.index(params).unwrap_err();
 
-        let url_column = container.columns.find_column("url").unwrap();
-        assert_eq!(
-            url_column.entries().len(),
-            0,
-            "was expecting no url, found: {:?}",
-            url_column.entries()
-        );
+        assert!(err.is_client_error());
+        match err.field_errors().as_slice() {
+            [FieldError::InvalidDataType { field, expected, .. }] => {
+                assert_eq!(field, "points");
+                assert_eq!(expected, &DataType::Int);
+            }
+            field_errors => panic!("Unexpected field errors: {:?}", field_errors),
+        }
     }
 
     #[test]
-    fn reject_insert_for_all_cells_when_one_cell_fails() {
-        initialize();
+    fn reject_duplicate_fields() {
+        let root = initialize();
         let mut container = Container::new(
-            &Path::new("/tmp").to_path_buf(),
+            &root.path().to_path_buf(),
             schema_config_with_timestamp_and_two_columns(),
         )
         .unwrap();
         let params = IndexParams {
-            fields: vec!["url".into(), "points".into()],
-            values: vec!["https://google.com".into(), serde_json::Value::Null],
+            fields: vec!["url".into(), "url".into()],
+            values: vec!["https://google.com".into(), "https://bing.com".into()],
         };
 
-        let result = container.index(params);
-        assert!(
-            result.is_err(),
-            "Was expecting error on insert. Got {:?}",
-            result
-        );
+        let err = container.index(params).unwrap_err();
 
-        let url_column = container.columns.find_column("url").unwrap();
-        assert_eq!(
-            url_column.entries().len(),
-            0,
-            "was expecting no url, found: {:?}",
-            url_column.entries()
-        );
+        assert!(matches!(err, ContainerError::DuplicateFields(ref fields) if fields == &vec!["url".to_string()]), "{:?}", err);
+        assert_eq!(container.columns.find_column("url").unwrap().entries().len(), 0);
+    }
 
-        let points_column = container.columns.find_column("points").unwrap();
-        assert_eq!(
-            points_column.entries().len(),
-            0,
-            "was expecting no points, found: {:?}",
-            points_column.entries()
-        );
+    #[test]
+    fn strict_fields_lists_unknown_fields() {
+        let root = initialize();
+        let mut config = schema_config_with_timestamp_and_two_columns();
+        config.strict_fields = true;
+        let mut container = Container::new(&root.path().to_path_buf(), config).unwrap();
+        let params = IndexParams {
+            fields: vec!["url".into(), "points".into(), "title".into()],
+            values: vec!["https://google.com".into(), 5.into(), "Google".into()],
+        };
 
-        let timestamp_column = container.columns.find_column("timestamp").unwrap();
-        assert_eq!(
-            timestamp_column.entries().len(),
-            0,
-            "was expecting no timestamp, found: {:?}",
-            timestamp_column.entries()
-        );
+        let err = container.index(params).unwrap_err();
+
+        assert!(matches!(err, ContainerError::InvalidFields(ref fields) if fields == &vec!["title".to_string()]), "{:?}", err);
     }
 
     #[test]
-    fn rejected_insert_rolls_back_auto_index() {
-        initialize();
+    fn filter_prunes_rows_before_assembling_them() {
+        let root = initialize();
         let mut container = Container::new(
-            &Path::new("/tmp").to_path_buf(),
+            &root.path().to_path_buf(),
             schema_config_with_timestamp_and_two_columns(),
         )
         .unwrap();
-        let params = IndexParams {
-            fields: vec!["url".into(), "points".into()],
-            values: vec!["https://google.com".into(), serde_json::Value::Null],
+        for (url, points) in [("https://google.com", 1), ("https://bing.com", 2), ("https://google.com", 3)] {
+            let params = IndexParams {
+                fields: vec!["url".into(), "points".into()],
+                values: vec![url.into(), points.into()],
+            };
+            container.index(params).unwrap();
+        }
+
+        let filter = QueryFilter {
+            from: Some(0),
+            to: None,
+            equals: vec![("url".into(), "https://google.com".into())],
+            ..Default::default()
         };
+        assert_eq!(container.columns.matching_rows(&filter).unwrap(), vec![0, 2]);
 
-        assert_eq!(container.index_counter.counter(), 0);
+        let filter = QueryFilter {
+            to: Some(0),
+            ..Default::default()
+        };
+        assert!(container.columns.matching_rows(&filter).unwrap().is_empty());
 
-        let result = container.index(params);
-        assert!(
-            result.is_err(),
-            "Was expecting error on insert. Got {:?}",
-            result
-        );
-        assert_eq!(container.index_counter.counter(), 0);
+        let filter = QueryFilter {
+            equals: vec![("points".into(), "many".into())],
+            ..Default::default()
+        };
+        assert!(matches!(container.columns.matching_rows(&filter), Err(FilterError::InvalidValue(..))));
 
-        let id_column = container.columns.find_column("id").unwrap();
+        let filter = QueryFilter {
+            expression: Some(Expression::parse("url LIKE '%google%' AND points * 2 > id").unwrap()),
+            ..Default::default()
+        };
+        assert_eq!(container.columns.matching_rows(&filter).unwrap(), vec![0, 2]);
+
+        let filter = QueryFilter {
+            expression: Some(Expression::parse("votes > 1").unwrap()),
+            ..Default::default()
+        };
+        assert!(matches!(container.columns.matching_rows(&filter), Err(FilterError::UnknownColumn(..))));
+
+        let filter = QueryFilter {
+            from: Some(2),
+            time_column: Some("points".into()),
+            ..Default::default()
+        };
+        assert_eq!(container.columns.matching_rows(&filter).unwrap(), vec![1, 2]);
+
+        let filter = QueryFilter {
+            from: Some(2),
+            time_column: Some("url".into()),
+            ..Default::default()
+        };
+        assert!(matches!(container.columns.matching_rows(&filter), Err(FilterError::InvalidTimeColumn(..))));
+    }
+
+    #[test]
+    fn sampling_is_deterministic_for_a_seed() {
+        let root = initialize();
+        let mut container = Container::new(&root.path().to_path_buf(), schema_config_with_timestamp()).unwrap();
+        for _ in 0..1000 {
+            let params = IndexParams {
+                fields: vec!["url".into()],
+                values: vec!["https://google.com".into()],
+            };
+            container.index(params).unwrap();
+        }
+
+        let filter = QueryFilter {
+            sample: Some(0.1),
+            seed: 42,
+            ..Default::default()
+        };
+        let sampled = container.columns.matching_rows(&filter).unwrap();
+        assert!((50..150).contains(&sampled.len()), "sampled {} rows", sampled.len());
+        assert_eq!(container.columns.matching_rows(&filter).unwrap(), sampled);
+
+        let other_seed = QueryFilter { seed: 7, ..filter };
+        assert_ne!(container.columns.matching_rows(&other_seed).unwrap(), sampled);
+    }
+
+    #[test]
+    fn distinct_stops_at_limit() {
+        let root = initialize();
+        let mut container = Container::new(&root.path().to_path_buf(), schema_config_with_timestamp()).unwrap();
+        for url in ["https://google.com", "https://bing.com", "https://google.com", "https://duckduckgo.com"] {
+            let params = IndexParams {
+                fields: vec!["url".into()],
+                values: vec![url.into()],
+            };
+            container.index(params).unwrap();
+        }
+
+        let all = container.distinct("url", 10, &QueryFilter::default(), None).unwrap();
+        assert_eq!(all.values.len(), 3);
+        assert!(!all.truncated);
+        assert_eq!(all.scanned, 4);
+
+        let limited = container.distinct("url", 2, &QueryFilter::default(), None).unwrap();
         assert_eq!(
-            id_column.entries().len(),
-            0,
-            "Was expecting zero entries in id column"
+            limited.values,
+            vec![Cell::String("https://google.com".into()), Cell::String("https://bing.com".into())]
         );
+        assert!(limited.truncated);
+        assert_eq!(limited.failed_casts, None);
+        //Stops at the first value beyond the limit
+        assert_eq!(limited.scanned, 4);
+
+        let ids = container.distinct("id", 2, &QueryFilter::default(), Some(CastType::String)).unwrap();
+        assert_eq!(ids.values, vec![Cell::String("1".into()), Cell::String("2".into())]);
+        let not_numbers = container.distinct("url", 10, &QueryFilter::default(), Some(CastType::Int)).unwrap();
+        assert!(not_numbers.values.is_empty());
+        assert_eq!(not_numbers.failed_casts, Some(4));
     }
 
     #[test]
-    fn successful_insert_increases_counter() {
-        initialize();
-        let mut container = Container::new(
-            &Path::new("/tmp").to_path_buf(),
-            schema_config_with_timestamp_and_two_columns(),
-        )
-        .unwrap();
-        let params = IndexParams {
-            fields: vec!["url".into(), "points".into()],
-            values: vec!["https://google.com".into(), 54.into()],
+    fn compaction_drops_deleted_rows_and_finishes_after_a_crash() {
+        let root = initialize();
+        let root_path = root.path().to_path_buf();
+        let mut container = Container::new(&root_path, schema_config_with_timestamp()).unwrap();
+        for url in ["https://google.com", "https://github.com", "https://crates.io"] {
+            container.index(IndexParams {
+                fields: vec!["url".into()],
+                values: vec![url.into()],
+            }).unwrap();
+        }
+        container.delete(2).unwrap();
+        container.update(3, IndexParams {
+            fields: vec!["url".into()],
+            values: vec!["https://docs.rs".into()],
+        }).unwrap();
+        let urls = |container: &Container| {
+            container
+                .columns
+                .all_rows()
+                .iter()
+                .map(|row| row.get("url").unwrap().to_owned())
+                .collect::<Vec<_>>()
         };
+        let expected = vec![Cell::String("https://google.com".into()), Cell::String("https://docs.rs".into())];
 
-        assert_eq!(container.index_counter.counter(), 0);
+        assert_eq!(container.compact(3).unwrap().rows, 0);
+        let report = container.compact(0).unwrap();
+        assert_eq!(report.rows, 2);
+        assert!(report.bytes_after < report.bytes_before);
+        assert_eq!(urls(&container), expected);
+        assert_eq!(container.columns.find_row(3), Some(1));
+        drop(container);
+        let mut container = Container::new(&root_path, schema_config_with_timestamp()).unwrap();
+        assert_eq!(urls(&container), expected);
+        assert!(container.columns.deleted_rows().is_empty());
 
-        let result = container.index(params);
+        //Crash after staging the rewritten files, before any of them got swapped in
+        container.delete(1).unwrap();
+        container.flush().unwrap();
+        let backend = container.columns.backend.clone();
+        let mut staged = StagedCompaction {
+            files: vec![],
+            segments: SegmentManifest::default(),
+            obsolete: vec![],
+        };
+        for column in container.columns.columns.iter() {
+            let kept = column.entries().iter().skip(1).cloned().collect::<Vec<_>>();
+            let file_name = Column::file_name(column.name());
+            backend.replace(&compaction::staged_name(&file_name), &column.compacted_image(&kept).unwrap()).unwrap();
+            staged.files.push(file_name);
+        }
+        compaction::mark_staged(backend.as_ref(), &staged).unwrap();
+        drop(container);
+        //Snapshots copy the marker, so replicas finish the compaction as well
+        let mut snapshots = ReplicaSnapshots::new(&root_path, &root.path().join("replica"));
+        snapshots.reset().unwrap();
+        let replica = Container::open_replica(&snapshots.take().unwrap(), schema_config_with_timestamp()).unwrap();
+        assert_eq!(urls(&replica), vec![Cell::String("https://docs.rs".into())]);
+        assert!(replica.columns.deleted_rows().is_empty());
+        let container = Container::new(&root_path, schema_config_with_timestamp()).unwrap();
+        assert_eq!(urls(&container), vec![Cell::String("https://docs.rs".into())]);
+        assert!(container.columns.deleted_rows().is_empty());
+        assert!(!root_path.join(compaction::staged_name(&Column::file_name("url"))).exists());
+    }
 
-        assert!(result.is_ok(), "Expected Insert to be successful");
+    #[test]
+    fn compaction_picks_encodings_for_auto_columns() {
+        let root = initialize();
+        let root_path = root.path().to_path_buf();
+        let mut config = schema_config_with_timestamp_and_two_columns();
+        config.columns[0].encoding = ColumnEncoding::Auto;
+        let mut container = Container::new(&root_path, config.clone()).unwrap();
+        for points in 0..300 {
+            container.index(IndexParams {
+                fields: vec!["url".into(), "points".into()],
+                values: vec![["https://google.com", "https://github.com"][points % 2].into(), points.into()],
+            }).unwrap();
+        }
+        container.delete(1).unwrap();
+        let rows = |container: &Container| container.columns.all_rows().iter().map(|row| row.to_view_object()).collect::<Vec<_>>();
+        let expected = rows(&container);
 
+        let report = container.compact(0).unwrap();
+        assert!(report.bytes_after * 2 < report.bytes_before, "{:?}", report);
+        let encodings = &container.columns.segments.encodings;
+        assert_eq!(encodings.get("id"), Some(&ColumnEncoding::Delta));
+        assert_eq!(encodings.get("timestamp"), Some(&ColumnEncoding::Delta));
+        assert_eq!(encodings.get("url"), Some(&ColumnEncoding::Dictionary));
+        assert_eq!(encodings.get("points"), None);
+        assert_eq!(rows(&container), expected);
+        container.index(IndexParams {
+            fields: vec!["url".into(), "points".into()],
+            values: vec!["https://google.com".into(), 300.into()],
+        }).unwrap();
+        let expected = rows(&container);
+        drop(container);
+
+        config.mmap_reads = true;
+        let container = Container::new(&root_path, config).unwrap();
+        assert_eq!(rows(&container), expected);
         let id_column = container.columns.find_column("id").unwrap();
+        assert_eq!(id_column.storage_options().encoding, ColumnEncoding::Delta);
+    }
 
-        let inserted_value = id_column.entries().first().unwrap();
-        assert_eq!(inserted_value, &Cell::Int(1));
+    #[test]
+    fn segments_roll_over_and_get_dropped_as_a_whole() {
+        let root = initialize();
+        let root_path = root.path().to_path_buf();
+        let mut config = schema_config_with_timestamp();
+        config.segment_rows = Some(2);
+        config.retention_secs = Some(60);
+        let mut container = Container::new(&root_path, config.clone()).unwrap();
+        for n in 1..=5 {
+            container.index(IndexParams {
+                fields: vec!["url".into()],
+                values: vec![format!("https://example.com/{}", n).into()],
+            }).unwrap();
+        }
+        let ids = |container: &Container| {
+            container
+                .columns
+                .all_rows()
+                .iter()
+                .map(|row| row.get("id").unwrap().to_owned())
+                .collect::<Vec<_>>()
+        };
+        let closed = |container: &Container| {
+            container
+                .columns
+                .segments
+                .closed
+                .iter()
+                .map(|segment| (segment.id, segment.rows, segment.timestamps.is_some()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(closed(&container), vec![(0, 2, true), (1, 2, true)]);
+        for file_name in ["column_url", "column_url.seg1", "column_url.seg2", "column_id.seg2"] {
+            assert!(root_path.join(file_name).exists(), "{} is missing", file_name);
+        }
 
-        //Index starts counting at 0, therefore we expect the next id to be 1
+        //The first segment only holds expired and deleted rows, the second one keeps a live row
+        container.delete(2).unwrap();
+        container.columns.expire_rows(&[0, 2]).unwrap();
+        assert!(!root_path.join("column_url").exists());
+        assert_eq!(ids(&container), vec![Cell::Int(4), Cell::Int(5)]);
+        drop(container);
+        let mut container = Container::new(&root_path, config.clone()).unwrap();
+        assert_eq!(ids(&container), vec![Cell::Int(4), Cell::Int(5)]);
+        assert_eq!(container.columns.deleted_rows(), [0].into());
+        assert_eq!(container.columns.segments.dropped_rows, 2);
+
+        let report = container.compact(0).unwrap();
+        assert_eq!(report.rows, 1);
+        assert_eq!(closed(&container), vec![(3, 2, true)]);
+        assert!(!root_path.join("column_url.seg1").exists());
+        drop(container);
+        let mut container = Container::new(&root_path, config.clone()).unwrap();
+        assert_eq!(ids(&container), vec![Cell::Int(4), Cell::Int(5)]);
+
+        let in_an_hour = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64 + 3600;
+        assert_eq!(container.retention_at(in_an_hour, false).unwrap().rows, 2);
+        assert!(container.columns.segments.closed.is_empty());
+        container.index(IndexParams {
+            fields: vec!["url".into()],
+            values: vec!["https://crates.io".into()],
+        }).unwrap();
+        drop(container);
+        let container = Container::new(&root_path, config).unwrap();
+        assert_eq!(ids(&container), vec![Cell::Int(6)]);
+    }
+
+    #[test]
+    fn page_cursors_continue_after_appends_and_dropped_segments() {
+        let root = initialize();
+        let mut config = schema_config_with_timestamp();
+        config.segment_rows = Some(2);
+        let mut container = Container::new(&root.path().to_path_buf(), config).unwrap();
+        let insert = |container: &mut Container, count: usize| {
+            for n in 0..count {
+                container.index(IndexParams {
+                    fields: vec!["url".into()],
+                    values: vec![format!("https://example.com/{}", n).into()],
+                }).unwrap();
+            }
+        };
+        let page = |container: &Container, cursor: Option<PageCursor>| {
+            let page = container.page(&QueryFilter::default(), cursor, 2).unwrap();
+            let ids = page.rows.iter().map(|row| row.get("id").unwrap().to_owned()).collect::<Vec<_>>();
+            (ids, page.cursor, page.has_more)
+        };
+        insert(&mut container, 5);
+
+        let (ids, first_cursor, has_more) = page(&container, None);
+        assert_eq!((ids, has_more), (vec![Cell::Int(1), Cell::Int(2)], true));
+        container.delete(3).unwrap();
+        let (ids, cursor, has_more) = page(&container, Some(first_cursor));
+        assert_eq!((ids, has_more), (vec![Cell::Int(4), Cell::Int(5)], false));
+
+        insert(&mut container, 2);
+        let (ids, _, _) = page(&container, Some(cursor));
+        assert_eq!(ids, vec![Cell::Int(6), Cell::Int(7)]);
+
+        //Dropping the first segment shifts all positions, the cursor still points at the same row
+        container.columns.expire_rows(&[0, 1]).unwrap();
+        let (ids, _, _) = page(&container, Some(first_cursor));
+        assert_eq!(ids, vec![Cell::Int(4), Cell::Int(5)]);
+
+        container.compact(0).unwrap();
+        assert!(matches!(
+            container.page(&QueryFilter::default(), Some(cursor), 2),
+            Err(FilterError::ExpiredCursor)
+        ));
+        let equals = QueryFilter {
+            equals: vec![("id".into(), "7".into())],
+            ..Default::default()
+        };
+        let (ids, next) = container.columns.matching_rows_from(&equals, 0, 2).unwrap();
+        assert_eq!((ids.len(), next), (1, container.columns.row_count()));
+    }
+
+    #[test]
+    fn zone_maps_skip_segments_outside_the_time_range() {
+        let root = initialize();
+        let mut config = schema_config_with_timestamp();
+        config.segment_rows = Some(2);
+        let mut container = Container::new(&root.path().to_path_buf(), config).unwrap();
+        for n in 1..=5 {
+            container.columns.commit(vec![
+                ("id".into(), Cell::Int(n)),
+                ("url".into(), Cell::String(format!("https://example.com/{}", n))),
+                ("timestamp".into(), Cell::Timestamp(n * 100)),
+            ]).unwrap();
+            container.columns.roll_over_if_full().unwrap();
+        }
+        let in_range = |container: &Container, from: i64, to: i64| {
+            let filter = QueryFilter {
+                from: Some(from),
+                to: Some(to),
+                ..Default::default()
+            };
+            container.columns.matching_rows(&filter).unwrap()
+        };
         assert_eq!(
-            container.index_counter.counter(),
-            1,
-            "Expected Index Counter to have increased after commit"
+            container.columns.segments.closed.iter().map(|segment| segment.timestamps).collect::<Vec<_>>(),
+            vec![Some(ZoneMap { min: 100, max: 200 }), Some(ZoneMap { min: 300, max: 400 })]
         );
+        assert_eq!(in_range(&container, 150, 450), vec![1, 2, 3]);
+
+        //Only the zone map decides whether a segment gets read
+        container.columns.segments.closed[1].timestamps = Some(ZoneMap { min: 0, max: 1 });
+        assert_eq!(in_range(&container, 150, 1000), vec![1, 4]);
     }
 
     #[test]
-    fn reject_timestamp_value_when_autotimestamp_is_on() {
-        initialize();
-        let mut container = Container::new(
-            &Path::new("/tmp").to_path_buf(),
-            schema_config_with_timestamp_and_two_columns(),
-        )
-        .unwrap();
-        let params = IndexParams {
-            fields: vec!["url".into(), "timestamp".into()],
-            values: vec!["https://google.com".into(), 54.into()],
+    fn partitioned_segments_hold_a_single_period() {
+        let root = initialize();
+        let root_path = root.path().to_path_buf();
+        let mut config = schema_config_with_timestamp();
+        config.partition_by = Some(PartitionPeriod::Hour);
+        let mut container = Container::new(&root_path, config.clone()).unwrap();
+        let hour = 60 * 60;
+        for (n, timestamp) in [10, 20, hour, hour + 5, 3 * hour].into_iter().enumerate() {
+            let values = vec![
+                ("id".into(), Cell::Int(n as i64 + 1)),
+                ("url".into(), Cell::String(format!("https://example.com/{}", n))),
+                ("timestamp".into(), Cell::Timestamp(timestamp)),
+            ];
+            container.columns.roll_over_if_new_period(&values).unwrap();
+            container.columns.commit(values).unwrap();
+        }
+        let closed = |container: &Container| {
+            container.columns.segments.closed.iter().map(|segment| segment.rows).collect::<Vec<_>>()
         };
+        assert_eq!(closed(&container), vec![2, 2]);
+        assert!(root_path.join("column_url.seg1").exists());
 
-        let result = container.index(params);
+        //Compaction keeps the periods apart
+        container.delete(2).unwrap();
+        container.compact(0).unwrap();
+        assert_eq!(closed(&container), vec![1, 2]);
 
-        assert!(result.is_err(), "Expected Insert to fail");
+        //Retention drops the first hour without rewriting any files
+        container.columns.expire_rows(&[0]).unwrap();
+        assert_eq!(closed(&container), vec![2]);
+        assert_eq!(container.columns.segments.dropped_rows, 1);
+        assert!(container.columns.is_segmented());
+    }
+
+    #[test]
+    fn transactions_straddling_periods_get_split_and_rolled_back_across_segments() {
+        let root = initialize();
+        let root_path = root.path().to_path_buf();
+        let mut config = schema_config_with_timestamp();
+        config.partition_by = Some(PartitionPeriod::Hour);
+        let mut container = Container::new(&root_path, config.clone()).unwrap();
+        let hour = 60 * 60;
+        let rows = |container: &mut Container, timestamps: &[i64]| {
+            timestamps
+                .iter()
+                .map(|timestamp| {
+                    let params = IndexParams {
+                        fields: vec!["url".into()],
+                        values: vec![format!("https://example.com/{}", timestamp).into()],
+                    };
+                    let mut values = container.prepare_row(params, &Lineage::default()).unwrap();
+                    values.retain(|(name, _)| name != "timestamp");
+                    values.push(("timestamp".into(), Cell::Timestamp(*timestamp)));
+                    values
+                })
+                .collect::<Vec<_>>()
+        };
+        let closed = |container: &Container| {
+            container.columns.segments.closed.iter().map(|segment| segment.rows).collect::<Vec<_>>()
+        };
+
+        let batch = rows(&mut container, &[10, 20, hour, hour + 5]);
+        container.commit_batch(batch).unwrap();
+        assert_eq!(closed(&container), vec![2]);
+        assert_eq!(container.columns.segments.active, 1);
+
+        //The failing batch rolls over twice before it fails
+        let batch = rows(&mut container, &[2 * hour, 3 * hour]);
+        let err = container
+            .append_atomically(batch.len(), |container| {
+                container.wal.append_transaction(&batch)?;
+                container.commit_runs(batch)?;
+                Err(ContainerError::WarmingUp)
+            })
+            .unwrap_err();
+        assert!(matches!(err, ContainerError::WarmingUp));
+        assert_eq!(closed(&container), vec![2]);
+        assert_eq!(container.columns.segments.active, 1);
+        assert!(!root_path.join("column_url.seg2").exists());
+        assert_eq!(container.columns.row_count(), 4);
+
+        let batch = rows(&mut container, &[hour + 10, 2 * hour]);
+        container.commit_batch(batch).unwrap();
+        assert_eq!(closed(&container), vec![2, 3]);
+        drop(container);
+        let container = Container::new(&root_path, config.clone()).unwrap();
+        assert_eq!(closed(&container), vec![2, 3]);
+        assert_eq!(container.columns.row_count(), 6);
+        let ids = container.columns.find_column("id").unwrap().entries().to_vec();
+        assert_eq!(ids, [1, 2, 3, 4, 5, 6].map(Cell::Int));
+    }
+
+    #[test]
+    fn lazy_columns_get_read_on_first_access() {
+        let root = initialize();
+        let root_path = root.path().to_path_buf();
+        let mut config = schema_config_with_timestamp_and_two_columns();
+        config.lazy_columns = true;
+        let mut container = Container::new(&root_path, config.clone()).unwrap();
+        for (url, points) in [("https://google.com", 1), ("https://github.com", 2), ("https://crates.io", 3)] {
+            container.index(IndexParams {
+                fields: vec!["url".into(), "points".into()],
+                values: vec![url.into(), points.into()],
+            }).unwrap();
+        }
+        container.delete(2).unwrap();
+        drop(container);
+
+        let (mut container, plan) = Container::open_cold(&root_path, config.clone()).unwrap();
+        container.index(IndexParams {
+            fields: vec!["url".into(), "points".into()],
+            values: vec!["https://docs.rs".into(), 4.into()],
+        }).unwrap();
+        container.finish_warmup(plan.run(&StartupTracker::new()).unwrap()).unwrap();
+        assert!(container.columns.find_column("id").unwrap().is_loaded());
+        assert!(!container.columns.find_column("points").unwrap().is_loaded());
+        assert_eq!(container.columns.row_count(), 4);
+        assert_eq!(container.columns.deleted_rows(), [1].into());
+
+        assert_eq!(container.columns.find_column("points").unwrap().entries(), &[1, 2, 3, 4].map(Cell::Int));
+        assert!(!container.columns.find_column("url").unwrap().is_loaded());
+        //Inserts after the first access append to the records read
+        container.index(IndexParams {
+            fields: vec!["url".into(), "points".into()],
+            values: vec!["https://lib.rs".into(), 5.into()],
+        }).unwrap();
+        let urls = container.columns.all_rows().iter().map(|row| row.get("url").unwrap().to_owned()).collect::<Vec<_>>();
+        assert_eq!(urls, ["https://google.com", "https://crates.io", "https://docs.rs", "https://lib.rs"].map(|url| Cell::String(url.into())));
+        drop(container);
+
+        let container = Container::new(&root_path, config).unwrap();
+        assert!(!container.columns.find_column("points").unwrap().is_loaded());
+        assert_eq!(container.columns.find_column("points").unwrap().entries(), &[1, 2, 3, 4, 5].map(Cell::Int));
+    }
+
+    #[test]
+    fn mapped_reads_decode_cells_on_access() {
+        let root = initialize();
+        let root_path = root.path().to_path_buf();
+        let mut config = schema_config_with_timestamp_and_two_columns();
+        config.columns[0].compression = Some(ColumnCompression::Zstd);
+        config.columns[1].encoding = ColumnEncoding::RunLength;
+        config.segment_rows = Some(4);
+        config.mmap_reads = true;
+        let mut container = Container::new(&root_path, config.clone()).unwrap();
+        let urls = (0..10).map(|n| Cell::String(format!("https://example.com/{}", n % 3))).collect::<Vec<_>>();
+        let points = (0..10).map(|n| Cell::Int(n / 5)).collect::<Vec<_>>();
+        for n in 0..10 {
+            container.index(IndexParams {
+                fields: vec!["url".into(), "points".into()],
+                values: vec![format!("https://example.com/{}", n % 3).into(), json!(n / 5)],
+            }).unwrap();
+        }
+        container.delete(6).unwrap();
+        drop(container);
+
+        let mut container = Container::new(&root_path, config.clone()).unwrap();
+        let points_column = container.columns.find_column("points").unwrap();
+        assert!(matches!(points_column.entries(), ColumnEntries::Mapped(_)));
+        assert_eq!(points_column.entries(), points);
+        assert_eq!(container.columns.find_column("url").unwrap().entries(), urls);
+        assert_eq!(container.columns.deleted_rows(), [5].into());
+
+        //Rows inserted after loading sit next to the mapped ones, and dropping segments releases their files
+        container.index(IndexParams {
+            fields: vec!["url".into(), "points".into()],
+            values: vec!["https://crates.io".into(), json!(7)],
+        }).unwrap();
+        container.columns.expire_rows(&[0, 1, 2, 3]).unwrap();
+        let points_column = container.columns.find_column("points").unwrap();
+        assert_eq!(points_column.entries().len(), 7);
+        assert_eq!(points_column.entries()[0], Cell::Int(0));
+        assert_eq!(points_column.entries()[6], Cell::Int(7));
+        drop(container);
+
+        let container = Container::new(&root_path, config).unwrap();
+        let points_column = container.columns.find_column("points").unwrap();
+        assert_eq!(points_column.entries(), &[0, 1, 1, 1, 1, 1, 7].map(Cell::Int));
+        assert_eq!(container.columns.deleted_rows(), [1].into());
     }
 }
@@ -0,0 +1,531 @@
+use super::cell::Cell;
+use super::column_frame::ColumnFrame;
+use super::ByteString;
+
+pub const PARQUET_CONTENT_TYPE: &str = "application/vnd.apache.parquet";
+
+///Starts and ends every Parquet file
+pub const PARQUET_MAGIC: &[u8; 4] = b"PAR1";
+
+const CREATED_BY: &str = concat!("warenhaus version ", env!("CARGO_PKG_VERSION"));
+
+//Thrift compact protocol types
+const THRIFT_I32: u8 = 5;
+const THRIFT_I64: u8 = 6;
+const THRIFT_BINARY: u8 = 8;
+const THRIFT_LIST: u8 = 9;
+const THRIFT_STRUCT: u8 = 12;
+
+//Values of the enums in parquet.thrift
+const TYPE_BOOLEAN: i32 = 0;
+const TYPE_INT64: i32 = 2;
+const TYPE_DOUBLE: i32 = 5;
+const TYPE_BYTE_ARRAY: i32 = 6;
+const REPETITION_OPTIONAL: i32 = 1;
+const CONVERTED_UTF8: i32 = 0;
+const CONVERTED_TIMESTAMP_MILLIS: i32 = 9;
+const ENCODING_PLAIN: i32 = 0;
+const ENCODING_RLE: i32 = 3;
+const CODEC_UNCOMPRESSED: i32 = 0;
+const PAGE_DATA: i32 = 0;
+
+///Parquet type a column gets exported as
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ParquetType {
+    Int64,
+    Double,
+    Boolean,
+    ///Milliseconds since the unix epoch, as Parquet has no timestamps in seconds
+    Timestamp,
+    ///Strings, and everything without a Parquet type of its own: UUIDs in their hyphenated form, arrays and
+    ///JSON as JSON text. Columns holding cells of different types, which map functions may return, end up as
+    ///strings as well.
+    Utf8,
+}
+
+impl ParquetType {
+    fn of(cell: &Cell) -> Option<ParquetType> {
+        match cell {
+            Cell::Null => None,
+            Cell::Int(_) => Some(ParquetType::Int64),
+            Cell::Float(_) => Some(ParquetType::Double),
+            Cell::Boolean(_) => Some(ParquetType::Boolean),
+            Cell::Timestamp(_) => Some(ParquetType::Timestamp),
+            Cell::String(_) | Cell::Uuid(_) | Cell::Array(_) | Cell::Json(_) => Some(ParquetType::Utf8),
+        }
+    }
+
+    fn physical_type(&self) -> i32 {
+        match self {
+            ParquetType::Int64 | ParquetType::Timestamp => TYPE_INT64,
+            ParquetType::Double => TYPE_DOUBLE,
+            ParquetType::Boolean => TYPE_BOOLEAN,
+            ParquetType::Utf8 => TYPE_BYTE_ARRAY,
+        }
+    }
+
+    fn converted_type(&self) -> Option<i32> {
+        match self {
+            ParquetType::Timestamp => Some(CONVERTED_TIMESTAMP_MILLIS),
+            ParquetType::Utf8 => Some(CONVERTED_UTF8),
+            _ => None,
+        }
+    }
+}
+
+///Column chunk of a row group which got written already, for the footer
+#[derive(Debug)]
+struct WrittenChunk {
+    offset: u64,
+    size: u64,
+    num_values: usize,
+}
+
+///Writes rows as a Parquet file, one row group at a time, so the file can be sent while it gets written.
+///All columns are optional and stored uncompressed in a single plain encoded data page per row group.
+///
+///The file starts with `PARQUET_MAGIC`, followed by every chunk `row_group` returns and the one of `finish`.
+#[derive(Debug)]
+pub struct ParquetWriter {
+    columns: Vec<(String, ParquetType)>,
+    row_groups: Vec<(usize, Vec<WrittenChunk>)>,
+    ///Bytes of the file written so far
+    written: u64,
+}
+
+impl ParquetWriter {
+    ///Picks each column's type from the cells the rows hold for it. Columns without any values become strings.
+    pub fn new(column_names: &[String], rows: &[ColumnFrame]) -> Self {
+        let columns = column_names
+            .iter()
+            .map(|column_name| {
+                let mut types = rows.iter().filter_map(|row| row.get(column_name).and_then(ParquetType::of));
+                let parquet_type = match types.next() {
+                    Some(first) if types.all(|parquet_type| parquet_type == first) => first,
+                    _ => ParquetType::Utf8,
+                };
+                (column_name.to_owned(), parquet_type)
+            })
+            .collect();
+        Self {
+            columns,
+            row_groups: vec![],
+            written: PARQUET_MAGIC.len() as u64,
+        }
+    }
+
+    ///Encodes the rows as the next row group
+    pub fn row_group(&mut self, rows: &[ColumnFrame]) -> ByteString {
+        let mut out = ByteString::new();
+        let mut chunks = vec![];
+        for (column_name, parquet_type) in &self.columns {
+            let cells = rows.iter().map(|row| row.get(column_name).unwrap_or(&Cell::Null)).collect::<Vec<_>>();
+            let page = encode_page(&cells, *parquet_type);
+            let mut header = Compact::new();
+            header.i32(1, PAGE_DATA);
+            header.i32(2, page.len() as i32);
+            header.i32(3, page.len() as i32);
+            header.begin_struct(5);
+            header.i32(1, cells.len() as i32);
+            header.i32(2, ENCODING_PLAIN);
+            header.i32(3, ENCODING_RLE);
+            header.i32(4, ENCODING_RLE);
+            header.end_struct();
+            let header = header.finish();
+
+            chunks.push(WrittenChunk {
+                offset: self.written + out.len() as u64,
+                size: (header.len() + page.len()) as u64,
+                num_values: cells.len(),
+            });
+            out.extend_from_slice(&header);
+            out.extend_from_slice(&page);
+        }
+        self.written += out.len() as u64;
+        self.row_groups.push((rows.len(), chunks));
+        out
+    }
+
+    ///The footer, which describes the columns and where the row groups got written to
+    pub fn finish(self) -> ByteString {
+        let mut metadata = Compact::new();
+        metadata.i32(1, 1);
+        metadata.list(2, THRIFT_STRUCT, self.columns.len() + 1);
+        metadata.begin_element();
+        metadata.binary(4, b"schema");
+        metadata.i32(5, self.columns.len() as i32);
+        metadata.end_struct();
+        for (column_name, parquet_type) in &self.columns {
+            metadata.begin_element();
+            metadata.i32(1, parquet_type.physical_type());
+            metadata.i32(3, REPETITION_OPTIONAL);
+            metadata.binary(4, column_name.as_bytes());
+            if let Some(converted_type) = parquet_type.converted_type() {
+                metadata.i32(6, converted_type);
+            }
+            metadata.end_struct();
+        }
+        metadata.i64(3, self.row_groups.iter().map(|(rows, _)| *rows as i64).sum());
+        metadata.list(4, THRIFT_STRUCT, self.row_groups.len());
+        for (rows, chunks) in &self.row_groups {
+            metadata.begin_element();
+            metadata.list(1, THRIFT_STRUCT, chunks.len());
+            for ((column_name, parquet_type), chunk) in self.columns.iter().zip(chunks) {
+                metadata.begin_element();
+                metadata.i64(2, chunk.offset as i64);
+                metadata.begin_struct(3);
+                metadata.i32(1, parquet_type.physical_type());
+                metadata.list(2, THRIFT_I32, 2);
+                metadata.i32_element(ENCODING_PLAIN);
+                metadata.i32_element(ENCODING_RLE);
+                metadata.list(3, THRIFT_BINARY, 1);
+                metadata.binary_element(column_name.as_bytes());
+                metadata.i32(4, CODEC_UNCOMPRESSED);
+                metadata.i64(5, chunk.num_values as i64);
+                metadata.i64(6, chunk.size as i64);
+                metadata.i64(7, chunk.size as i64);
+                metadata.i64(9, chunk.offset as i64);
+                metadata.end_struct();
+                metadata.end_struct();
+            }
+            metadata.i64(2, chunks.iter().map(|chunk| chunk.size as i64).sum());
+            metadata.i64(3, *rows as i64);
+            metadata.end_struct();
+        }
+        metadata.binary(6, CREATED_BY.as_bytes());
+        let mut out = metadata.finish();
+        let len = out.len() as u32;
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(PARQUET_MAGIC);
+        out
+    }
+}
+
+///Definition levels, prefixed by their length, followed by the values which aren't null
+fn encode_page(cells: &[&Cell], parquet_type: ParquetType) -> ByteString {
+    //A single bit packed run of the RLE hybrid encoding, padded to groups of 8 levels
+    let mut levels = ByteString::new();
+    let groups = cells.len().div_ceil(8);
+    write_varint(&mut levels, ((groups as u64) << 1) | 1);
+    levels.extend(pack_bits(cells.iter().map(|cell| !matches!(cell, Cell::Null))));
+
+    let mut page = ByteString::new();
+    page.extend_from_slice(&(levels.len() as u32).to_le_bytes());
+    page.extend_from_slice(&levels);
+    let values = cells.iter().filter(|cell| !matches!(cell, Cell::Null));
+    match parquet_type {
+        ParquetType::Boolean => page.extend(pack_bits(values.map(|cell| matches!(cell, Cell::Boolean(true))))),
+        _ => {
+            for cell in values {
+                match (parquet_type, cell) {
+                    (ParquetType::Int64, Cell::Int(value)) => page.extend_from_slice(&value.to_le_bytes()),
+                    (ParquetType::Timestamp, Cell::Timestamp(value)) => {
+                        page.extend_from_slice(&value.saturating_mul(1000).to_le_bytes())
+                    }
+                    (ParquetType::Double, Cell::Float(value)) => page.extend_from_slice(&value.to_le_bytes()),
+                    (_, cell) => {
+                        let text = match cell {
+                            Cell::String(value) => value.to_owned(),
+                            Cell::Uuid(value) => Cell::format_uuid(*value),
+                            cell => serde_json::to_string(cell).unwrap_or_default(),
+                        };
+                        page.extend_from_slice(&(text.len() as u32).to_le_bytes());
+                        page.extend_from_slice(text.as_bytes());
+                    }
+                }
+            }
+        }
+    }
+    page
+}
+
+///Least significant bit first, the last byte padded with zeros
+fn pack_bits(bits: impl Iterator<Item = bool>) -> ByteString {
+    let mut bytes = ByteString::new();
+    for (n, bit) in bits.enumerate() {
+        if n % 8 == 0 {
+            bytes.push(0);
+        }
+        if bit {
+            *bytes.last_mut().unwrap() |= 1 << (n % 8);
+        }
+    }
+    bytes
+}
+
+fn write_varint(out: &mut ByteString, mut value: u64) {
+    while value >= 0x80 {
+        out.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+///Writes a struct in the Thrift compact protocol, which Parquet encodes its metadata in
+struct Compact {
+    out: ByteString,
+    ///Id of the last field written per nested struct, as field ids are encoded as the difference to it
+    last_field_ids: Vec<i16>,
+}
+
+impl Compact {
+    fn new() -> Self {
+        Self {
+            out: ByteString::new(),
+            last_field_ids: vec![0],
+        }
+    }
+
+    fn field(&mut self, id: i16, field_type: u8) {
+        let last_id = self.last_field_ids.last_mut().unwrap();
+        match id - *last_id {
+            delta @ 1..=15 => self.out.push(((delta as u8) << 4) | field_type),
+            _ => {
+                self.out.push(field_type);
+                write_varint(&mut self.out, zigzag(id as i64));
+            }
+        }
+        *last_id = id;
+    }
+
+    fn i32(&mut self, id: i16, value: i32) {
+        self.field(id, THRIFT_I32);
+        self.i32_element(value);
+    }
+
+    fn i64(&mut self, id: i16, value: i64) {
+        self.field(id, THRIFT_I64);
+        write_varint(&mut self.out, zigzag(value));
+    }
+
+    fn binary(&mut self, id: i16, value: &[u8]) {
+        self.field(id, THRIFT_BINARY);
+        self.binary_element(value);
+    }
+
+    fn begin_struct(&mut self, id: i16) {
+        self.field(id, THRIFT_STRUCT);
+        self.begin_element();
+    }
+
+    fn end_struct(&mut self) {
+        self.out.push(0);
+        self.last_field_ids.pop();
+    }
+
+    fn list(&mut self, id: i16, element_type: u8, len: usize) {
+        self.field(id, THRIFT_LIST);
+        if len < 15 {
+            self.out.push(((len as u8) << 4) | element_type);
+        } else {
+            self.out.push(0xf0 | element_type);
+            write_varint(&mut self.out, len as u64);
+        }
+    }
+
+    ///Starts a struct inside a list, end it with `end_struct`
+    fn begin_element(&mut self) {
+        self.last_field_ids.push(0);
+    }
+
+    fn i32_element(&mut self, value: i32) {
+        write_varint(&mut self.out, zigzag(value as i64));
+    }
+
+    fn binary_element(&mut self, value: &[u8]) {
+        write_varint(&mut self.out, value.len() as u64);
+        self.out.extend_from_slice(value);
+    }
+
+    fn finish(mut self) -> ByteString {
+        self.end_struct();
+        self.out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ParquetWriter, PARQUET_MAGIC};
+    use crate::storage::{cell::Cell, column_frame::ColumnFrame, ByteString};
+
+    #[derive(Debug, Clone, PartialEq)]
+    enum Thrift {
+        Int(i64),
+        Binary(Vec<u8>),
+        List(Vec<Thrift>),
+        Struct(Vec<(i16, Thrift)>),
+    }
+
+    impl Thrift {
+        fn field(&self, id: i16) -> &Thrift {
+            match self {
+                Thrift::Struct(fields) => &fields.iter().find(|(field_id, _)| *field_id == id).unwrap().1,
+                _ => panic!("Not a struct"),
+            }
+        }
+
+        fn int(&self) -> i64 {
+            match self {
+                Thrift::Int(value) => *value,
+                _ => panic!("Not an int"),
+            }
+        }
+
+        fn list(&self) -> &[Thrift] {
+            match self {
+                Thrift::List(values) => values,
+                _ => panic!("Not a list"),
+            }
+        }
+    }
+
+    fn varint(bytes: &mut &[u8]) -> u64 {
+        let mut value = 0;
+        for shift in (0..).step_by(7) {
+            let byte = bytes[0];
+            *bytes = &bytes[1..];
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+        value
+    }
+
+    fn zigzag_varint(bytes: &mut &[u8]) -> i64 {
+        let value = varint(bytes);
+        (value >> 1) as i64 ^ -((value & 1) as i64)
+    }
+
+    ///Reads a value of the Thrift compact protocol, consuming its bytes
+    fn read(bytes: &mut &[u8], value_type: u8) -> Thrift {
+        match value_type {
+            5 | 6 => Thrift::Int(zigzag_varint(bytes)),
+            8 => {
+                let len = varint(bytes) as usize;
+                let (value, rest) = bytes.split_at(len);
+                *bytes = rest;
+                Thrift::Binary(value.to_vec())
+            }
+            9 => {
+                let header = bytes[0];
+                *bytes = &bytes[1..];
+                let len = match header >> 4 {
+                    15 => varint(bytes) as usize,
+                    len => len as usize,
+                };
+                Thrift::List((0..len).map(|_| read(bytes, header & 0x0f)).collect())
+            }
+            12 => {
+                let mut fields = vec![];
+                let mut last_id = 0;
+                loop {
+                    let header = bytes[0];
+                    *bytes = &bytes[1..];
+                    if header == 0 {
+                        return Thrift::Struct(fields);
+                    }
+                    last_id = match header >> 4 {
+                        0 => zigzag_varint(bytes) as i16,
+                        delta => last_id + delta as i16,
+                    };
+                    fields.push((last_id, read(bytes, header & 0x0f)));
+                }
+            }
+            other => panic!("Unexpected type {}", other),
+        }
+    }
+
+    #[test]
+    fn rows_read_back_from_parquet_files() {
+        let column_names = vec!["id".to_string(), "url".to_string(), "published_at".to_string()];
+        let rows = (1..=20)
+            .map(|id| {
+                let mut row = ColumnFrame::new();
+                row.insert("id", Cell::Int(id));
+                //Every third row lacks a url
+                if id % 3 != 0 {
+                    row.insert("url", Cell::String(format!("https://google.com/{}", id)));
+                }
+                row.insert("published_at", Cell::Timestamp(1677125260 + id));
+                row
+            })
+            .collect::<Vec<_>>();
+        let mut writer = ParquetWriter::new(&column_names, &rows);
+        let mut file = PARQUET_MAGIC.to_vec();
+        for row_group in rows.chunks(8) {
+            file.extend(writer.row_group(row_group));
+        }
+        file.extend(writer.finish());
+
+        assert!(file.ends_with(PARQUET_MAGIC));
+        let footer_len = u32::from_le_bytes(file[file.len() - 8..file.len() - 4].try_into().unwrap()) as usize;
+        let mut footer = &file[file.len() - 8 - footer_len..file.len() - 8];
+        let metadata = read(&mut footer, 12);
+        assert!(footer.is_empty());
+        assert_eq!(metadata.field(3).int(), 20);
+        let schema = metadata.field(2).list();
+        assert_eq!(schema.len(), 4);
+        assert_eq!(schema[2].field(4), &Thrift::Binary(b"url".to_vec()));
+        //Strings are UTF-8 byte arrays, timestamps milliseconds
+        assert_eq!((schema[2].field(1).int(), schema[2].field(6).int()), (6, 0));
+        assert_eq!((schema[3].field(1).int(), schema[3].field(6).int()), (2, 9));
+        let row_groups = metadata.field(4).list();
+        assert_eq!(row_groups.iter().map(|row_group| row_group.field(3).int()).collect::<Vec<_>>(), vec![8, 8, 4]);
+
+        //The url chunk of the second row group, holding rows 9 to 16
+        let chunk = row_groups[1].field(1).list()[1].field(3);
+        let mut page = &file[chunk.field(9).int() as usize..];
+        let header = read(&mut page, 12);
+        assert_eq!(header.field(5).field(1).int(), 8);
+        let levels_len = u32::from_le_bytes(page[..4].try_into().unwrap()) as usize;
+        //One bit packed group of levels, rows 9, 12 and 15 are null
+        assert_eq!(&page[4..4 + levels_len], &[0b11, 0b10110110]);
+        let mut values = &page[4 + levels_len..header.field(2).int() as usize];
+        let mut urls = vec![];
+        while !values.is_empty() {
+            let len = u32::from_le_bytes(values[..4].try_into().unwrap()) as usize;
+            urls.push(String::from_utf8(values[4..4 + len].to_vec()).unwrap());
+            values = &values[4 + len..];
+        }
+        assert_eq!(urls.len(), 5);
+        assert_eq!(urls[0], "https://google.com/10");
+    }
+
+    ///Rows holding every type, nulls included, spread over two row groups
+    fn fixture() -> ByteString {
+        let column_names = ["id", "score", "active", "published_at", "url", "tags"].map(String::from);
+        let rows = (1..=6)
+            .map(|id| {
+                let mut row = ColumnFrame::new();
+                row.insert("id", Cell::Int(id));
+                row.insert("score", Cell::Float(id as f64 / 2.0));
+                row.insert("active", Cell::Boolean(id % 2 == 0));
+                row.insert("published_at", Cell::Timestamp(1677125260 + id));
+                if id != 3 {
+                    row.insert("url", Cell::String(format!("https://google.com/{}", id)));
+                }
+                row.insert("tags", Cell::Array(vec![Cell::String("news".into()), Cell::Int(id)]));
+                row
+            })
+            .collect::<Vec<_>>();
+        let mut writer = ParquetWriter::new(&column_names, &rows);
+        let mut file = PARQUET_MAGIC.to_vec();
+        for row_group in rows.chunks(4) {
+            file.extend(writer.row_group(row_group));
+        }
+        file.extend(writer.finish());
+        file
+    }
+
+    ///The fixture is the file other Parquet readers check, see `tests/fixtures/README.md`.
+    ///Output changes need the fixture replaced and checked again.
+    #[test]
+    fn files_match_the_fixture() {
+        assert_eq!(fixture(), include_bytes!("../../tests/fixtures/export.parquet"));
+    }
+}
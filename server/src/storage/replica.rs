@@ -0,0 +1,149 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use tracing::{info, instrument, warn};
+
+use super::auto_index::AutoIndex;
+use super::checked_file::CheckedFile;
+use super::compaction;
+use super::{ColumnLayout, LayoutFile};
+
+///Attempts at copying the table files while the primary rewrites them, before giving up
+const MAX_COPY_ATTEMPTS: usize = 5;
+
+///Copies of a primary's table, which query nodes open. The primary keeps appending while a snapshot
+///gets copied, so `column_layout.json` gets copied first, then the column files and the auto index.
+///Records only some columns of a snapshot hold get cut off when opening it, see `Container::open_replica`.
+///Copies which overlap with a compaction get taken again, see `copy_table_files`.
+#[derive(Debug)]
+pub struct ReplicaSnapshots {
+    ///Data directory of the primary, e.g. a shared volume or a copy synced from object storage
+    source: PathBuf,
+    dir: PathBuf,
+    next_id: u64,
+}
+
+impl ReplicaSnapshots {
+    pub fn dir_path(db_root_path: &Path) -> PathBuf {
+        db_root_path.join("replica")
+    }
+
+    pub fn new(source: &Path, db_root_path: &Path) -> Self {
+        Self {
+            source: source.to_path_buf(),
+            dir: ReplicaSnapshots::dir_path(db_root_path),
+            next_id: 0,
+        }
+    }
+
+    ///Deletes snapshots taken before the last restart
+    pub fn reset(&self) -> io::Result<()> {
+        match fs::remove_dir_all(&self.dir) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+            _ => {}
+        }
+        fs::create_dir_all(&self.dir)
+    }
+
+    ///Copies the table files of the source into a new snapshot directory and returns its path
+    #[instrument(skip(self))]
+    pub fn take(&mut self) -> io::Result<PathBuf> {
+        let path = self.dir.join(format!("snapshot-{}", self.next_id));
+        self.next_id += 1;
+        fs::create_dir_all(&path)?;
+
+        let bytes = copy_table_files(&self.source, &path)?;
+        info!("Copied {} bytes from {} into {}", bytes, self.source.display(), path.display());
+        Ok(path)
+    }
+
+    ///Deletes all snapshots but the one in use
+    pub fn remove_all_but(&self, current: &Path) {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(err) => {
+                warn!("Failed to list replica snapshots: {}", err);
+                return;
+            }
+        };
+        for path in entries.filter_map(|entry| entry.ok()).map(|entry| entry.path()) {
+            if path != current {
+                if let Err(err) = fs::remove_dir_all(&path) {
+                    warn!("Failed to delete replica snapshot {}: {}", path.display(), err);
+                }
+            }
+        }
+    }
+}
+
+///Copies `column_layout.json` first, then the compaction marker, the column files and the auto index from source
+///into target. Returns the number of bytes copied. A compaction running meanwhile replaces column files, so the
+///copy gets taken again if the layout's generation or the marker changed, or if a file vanished while copying.
+///A copied marker makes opening the copy finish the compaction, like on the primary.
+pub fn copy_table_files(source: &Path, target: &Path) -> io::Result<u64> {
+    for attempt in 1..=MAX_COPY_ATTEMPTS {
+        let state = rewrite_state(source)?;
+        match copy_files(source, target) {
+            Ok(bytes) if rewrite_state(source)? == state => return Ok(bytes),
+            Ok(_) => {}
+            Err(err) if err.kind() == io::ErrorKind::NotFound && attempt < MAX_COPY_ATTEMPTS => {}
+            Err(err) => return Err(err),
+        }
+        warn!("Column files of {} got rewritten while copying them, copying them again", source.display());
+        for entry in fs::read_dir(target)? {
+            fs::remove_file(entry?.path())?;
+        }
+    }
+    Err(io::Error::other(format!(
+        "Column files of {} got rewritten during {} attempts to copy them",
+        source.display(),
+        MAX_COPY_ATTEMPTS
+    )))
+}
+
+///The layout's generation and the compaction marker, which change whenever a compaction replaces column files
+fn rewrite_state(source: &Path) -> io::Result<(u64, Option<Vec<u8>>)> {
+    let (_, bytes) = CheckedFile::load(ColumnLayout::file_path(&source.to_path_buf()))?;
+    let generation = match serde_json::from_slice(&bytes)? {
+        LayoutFile::Columns(_) => 0,
+        LayoutFile::Documented { segments, .. } => segments.generation,
+    };
+    let marker = match fs::read(source.join(compaction::MARKER_FILE)) {
+        Ok(bytes) => Some(bytes),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+        Err(err) => return Err(err),
+    };
+    Ok((generation, marker))
+}
+
+fn copy_files(source: &Path, target: &Path) -> io::Result<u64> {
+    let layout_name = ColumnLayout::file_path(&source.to_path_buf()).file_name().unwrap_or_default().to_os_string();
+    let auto_index_name = AutoIndex::file_path(&source.to_path_buf()).file_name().unwrap_or_default().to_os_string();
+    let mut layout_files = vec![];
+    let mut table_files = vec![];
+    for entry in fs::read_dir(source)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_file() {
+            continue;
+        }
+        let name = entry.file_name();
+        let name_str = name.to_string_lossy();
+        if name_str.starts_with(&*layout_name.to_string_lossy()) || name_str == compaction::MARKER_FILE {
+            layout_files.push(name);
+        } else if name_str.starts_with("column_") || name_str.starts_with(&*auto_index_name.to_string_lossy()) {
+            table_files.push(name);
+        }
+    }
+    if layout_files.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} holds no column layout", source.display()),
+        ));
+    }
+    let mut bytes = 0;
+    for name in layout_files.iter().chain(table_files.iter()) {
+        bytes += fs::copy(source.join(name), target.join(name))?;
+    }
+    Ok(bytes)
+}
@@ -0,0 +1,21 @@
+use serde::Serialize;
+
+///What a retention run deleted, or would delete on a dry run.
+///Every column is stored in a single file, so the bytes get reported per column file.
+#[derive(Debug, Clone, Serialize)]
+pub struct RetentionReport {
+    pub dry_run: bool,
+    ///Unix timestamp the run got evaluated at
+    pub ran_at: i64,
+    ///Rows with a timestamp before the cutoff are expired
+    pub cutoff: i64,
+    pub rows: usize,
+    pub bytes: u64,
+    pub columns: Vec<ColumnRetention>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ColumnRetention {
+    pub column: String,
+    pub bytes: u64,
+}
@@ -0,0 +1,115 @@
+use byteorder::{LittleEndian, WriteBytesExt};
+use std::io::{self, Write};
+
+use super::cell::Cell;
+use super::column_frame::ColumnFrame;
+use super::ByteString;
+
+///Content type of the binary row format. Clients ask for it in the Accept header of `GET /rows/stream`.
+pub const BINARY_ROWS_CONTENT_TYPE: &str = "application/vnd.warenhaus.rows";
+
+const ROWS_MAGIC: &[u8; 4] = b"WHRS";
+///Bump whenever the layout of the header or the rows changes
+pub const ROWS_FORMAT_VERSION: u16 = 1;
+
+///Starts a binary row stream: magic, version, the number of columns and their names as `length | utf-8 bytes`
+pub fn encode_header(column_names: &[String]) -> io::Result<ByteString> {
+    let mut bytes = ByteString::new();
+    bytes.write_all(ROWS_MAGIC)?;
+    bytes.write_u16::<LittleEndian>(ROWS_FORMAT_VERSION)?;
+    bytes.write_u32::<LittleEndian>(column_names.len() as u32)?;
+    for column_name in column_names {
+        bytes.write_u32::<LittleEndian>(column_name.len() as u32)?;
+        bytes.write_all(column_name.as_bytes())?;
+    }
+    Ok(bytes)
+}
+
+///Appends the row's cells in the order of the header's columns, prefixed by their total length.
+///Every cell is `tag | length | bytes`, like a record of a column file without the checksum.
+pub fn encode_row(column_names: &[String], row: &ColumnFrame, out: &mut ByteString) -> io::Result<()> {
+    let start = out.len();
+    out.write_u32::<LittleEndian>(0)?;
+    for column_name in column_names {
+        let (_checksum, tag_byte, bytes) = row.get(column_name).unwrap_or(&Cell::Null).to_bytes()?;
+        out.write_u8(tag_byte)?;
+        out.write_u32::<LittleEndian>(bytes.len() as u32)?;
+        out.write_all(&bytes)?;
+    }
+    let len = (out.len() - start - 4) as u32;
+    out[start..start + 4].copy_from_slice(&len.to_le_bytes());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use byteorder::{LittleEndian, ReadBytesExt};
+    use std::io::{self, Read};
+
+    use super::{encode_header, encode_row, ROWS_FORMAT_VERSION, ROWS_MAGIC};
+    use crate::storage::{cell::Cell, column_frame::ColumnFrame};
+
+    ///Reads a whole stream back into the column names and the cells of every row
+    fn decode(mut bytes: &[u8]) -> io::Result<(Vec<String>, Vec<Vec<Cell>>)> {
+        let mut magic = [0; 4];
+        bytes.read_exact(&mut magic)?;
+        let version = bytes.read_u16::<LittleEndian>()?;
+        if &magic != ROWS_MAGIC || version > ROWS_FORMAT_VERSION {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Not a row stream this version understands"));
+        }
+        let mut column_names = vec![];
+        for _ in 0..bytes.read_u32::<LittleEndian>()? {
+            let mut name = vec![0; bytes.read_u32::<LittleEndian>()? as usize];
+            bytes.read_exact(&mut name)?;
+            column_names.push(String::from_utf8(name).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?);
+        }
+
+        let mut rows = vec![];
+        while !bytes.is_empty() {
+            let mut row = vec![0; bytes.read_u32::<LittleEndian>()? as usize];
+            bytes.read_exact(&mut row)?;
+            let mut row = row.as_slice();
+            let mut cells = vec![];
+            while !row.is_empty() {
+                let tag_byte = row.read_u8()?;
+                let mut cell = vec![0; row.read_u32::<LittleEndian>()? as usize];
+                row.read_exact(&mut cell)?;
+                cells.push(
+                    Cell::from_bytes(tag_byte, cell)
+                        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("Unknown cell tag {}", tag_byte)))?,
+                );
+            }
+            rows.push(cells);
+        }
+        Ok((column_names, rows))
+    }
+
+    #[test]
+    fn rows_read_back_from_the_binary_format() {
+        let column_names = vec!["id".to_string(), "url".to_string(), "tags".to_string()];
+        let mut stream = encode_header(&column_names).unwrap();
+        let mut row = ColumnFrame::new();
+        row.insert("url", Cell::String("https://google.com".into()));
+        row.insert("id", Cell::Int(1));
+        row.insert("tags", Cell::Array(vec![Cell::Boolean(true), Cell::Boolean(false)]));
+        encode_row(&column_names, &row, &mut stream).unwrap();
+        let mut row = ColumnFrame::new();
+        row.insert("id", Cell::Int(2));
+        encode_row(&column_names, &row, &mut stream).unwrap();
+
+        let (decoded_names, rows) = decode(&stream).unwrap();
+        assert_eq!(decoded_names, column_names);
+        assert_eq!(
+            rows,
+            vec![
+                vec![
+                    Cell::Int(1),
+                    Cell::String("https://google.com".into()),
+                    Cell::Array(vec![Cell::Boolean(true), Cell::Boolean(false)])
+                ],
+                vec![Cell::Int(2), Cell::Null, Cell::Null],
+            ]
+        );
+        assert!(decode(&stream[..stream.len() - 1]).is_err());
+    }
+}
@@ -0,0 +1,150 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::instrument;
+
+use crate::config::SchemaConfig;
+
+use super::checked_file::CheckedFile;
+use super::data_type::DataType;
+use super::ColumnLayout;
+
+///What the stored rows rely on about a column of `schema.json`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ColumnSignature {
+    pub name: String,
+    pub data_type: DataType,
+    pub nullable: bool,
+    pub computed: bool,
+}
+
+///Columns of the schema a table last ran with, along with their hash.
+///Persisted in `schema_fingerprint.json`, so a start with a `schema.json` the stored columns don't fit
+///gets refused instead of failing inserts later on.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SchemaFingerprint {
+    pub sha256: String,
+    pub columns: Vec<ColumnSignature>,
+}
+
+impl SchemaFingerprint {
+    pub fn file_path(root_path: &Path) -> PathBuf {
+        root_path.join("schema_fingerprint.json")
+    }
+
+    pub fn of(config: &SchemaConfig) -> Self {
+        let mut columns = config
+            .columns
+            .iter()
+            .map(|column_config| ColumnSignature {
+                name: column_config.name.to_string(),
+                data_type: column_config.data_type.to_owned().into(),
+                nullable: column_config.nullable,
+                computed: column_config.computed,
+            })
+            .collect::<Vec<_>>();
+        //The order of the columns in schema.json doesn't matter to the stored rows
+        columns.sort_by(|a, b| a.name.cmp(&b.name));
+        let sha256 = format!("{:x}", Sha256::digest(serde_json::to_vec(&columns).unwrap_or_default()));
+        Self { sha256, columns }
+    }
+
+    ///Returns the file along with the fingerprint stored in it, if any.
+    ///Tables created before fingerprints were introduced don't have one yet.
+    #[instrument]
+    pub fn load(root_path: &Path) -> io::Result<(CheckedFile, Option<Self>)> {
+        let path = SchemaFingerprint::file_path(root_path);
+        if !CheckedFile::exists(&path) {
+            return Ok((CheckedFile::new(path), None));
+        }
+        let (file, payload) = CheckedFile::load(path)?;
+        let fingerprint = serde_json::from_slice(&payload).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        Ok((file, Some(fingerprint)))
+    }
+
+    pub fn write(&self, file: &mut CheckedFile) -> io::Result<()> {
+        let payload = serde_json::to_vec_pretty(self).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        file.write(&payload)
+    }
+
+    ///Changes since the stored fingerprint which the table copes with, like added or removed columns
+    pub fn changes(&self, stored: &SchemaFingerprint) -> Vec<String> {
+        let mut changes = Vec::new();
+        for column in &self.columns {
+            match stored.columns.iter().find(|stored_column| stored_column.name == column.name) {
+                None => changes.push(format!("Column {} was added", column.name)),
+                Some(stored_column) if stored_column.nullable != column.nullable => changes.push(format!(
+                    "Column {} {}",
+                    column.name,
+                    if column.nullable { "became nullable" } else { "isn't nullable anymore, stored rows may still hold null" }
+                )),
+                Some(_) => {}
+            }
+        }
+        for stored_column in stored.columns.iter().filter(|stored_column| !self.columns.iter().any(|c| c.name == stored_column.name)) {
+            changes.push(format!("Column {} was removed, the table keeps it as a nullable column", stored_column.name));
+        }
+        changes
+    }
+
+    ///Reasons why the stored columns don't fit the schema, empty if they do.
+    ///Computed columns may be missing, a backfill adds them.
+    pub fn conflicts(&self, columns: &ColumnLayout) -> Vec<String> {
+        let mut conflicts = Vec::new();
+        for column in &self.columns {
+            match columns.find_column(&column.name) {
+                Some(stored_column) if stored_column.data_type() != &column.data_type => conflicts.push(format!(
+                    "Column {} is stored as {}, but schema.json declares {}",
+                    column.name,
+                    stored_column.data_type(),
+                    column.data_type
+                )),
+                None if !column.computed => conflicts.push(format!(
+                    "Column {} from schema.json doesn't exist in the table. If it got renamed or dropped, update schema.json as well",
+                    column.name
+                )),
+                _ => {}
+            }
+        }
+        conflicts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::SchemaFingerprint;
+    use crate::config::SchemaConfig;
+
+    #[test]
+    fn fingerprints_ignore_the_column_order() {
+        let config: SchemaConfig = serde_json::from_value(json!({
+            "add_timestamp_column": false,
+            "columns": [
+                { "name": "url", "data_type": "String" },
+                { "name": "points", "data_type": "Int", "nullable": true }
+            ]
+        }))
+        .unwrap();
+        let mut reordered = config.clone();
+        reordered.columns.reverse();
+        assert_eq!(SchemaFingerprint::of(&config), SchemaFingerprint::of(&reordered));
+
+        let mut changed = config.clone();
+        changed.columns[1].nullable = false;
+        changed.columns.push(reordered.columns[1].clone());
+        changed.columns[2].name = "title".into();
+        let fingerprint = SchemaFingerprint::of(&changed);
+        assert_ne!(fingerprint.sha256, SchemaFingerprint::of(&config).sha256);
+        assert_eq!(
+            fingerprint.changes(&SchemaFingerprint::of(&config)),
+            vec![
+                "Column points isn't nullable anymore, stored rows may still hold null",
+                "Column title was added"
+            ]
+        );
+    }
+}
@@ -0,0 +1,245 @@
+use std::ops::Range;
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::{ColumnEncoding, PartitionPeriod};
+
+use super::cell::Cell;
+
+///Full segment of a column, holding `rows` rows. The segments of all columns hold the same rows,
+///so dropping a segment drops whole rows.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ColumnSegment {
+    pub id: u64,
+    pub rows: usize,
+    ///Range of the segment's `timestamp` column. Segments closed by versions without zone maps have none
+    ///until a compaction rewrites them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamps: Option<ZoneMap>,
+}
+
+///Smallest and largest value of a column within a segment, so filters can skip the segment as a whole
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct ZoneMap {
+    pub min: i64,
+    pub max: i64,
+}
+
+impl ZoneMap {
+    ///Covers the timestamps among the cells. Other cells never match a time range, so they are left out.
+    pub fn from_cells<'a>(cells: impl IntoIterator<Item = &'a Cell>) -> Option<Self> {
+        cells
+            .into_iter()
+            .filter_map(|cell| cell.as_int().copied())
+            .fold(None, |zone: Option<ZoneMap>, value| match zone {
+                Some(zone) => Some(ZoneMap { min: zone.min.min(value), max: zone.max.max(value) }),
+                None => Some(ZoneMap { min: value, max: value }),
+            })
+    }
+
+    ///True if any value may lie in `from..to`
+    pub fn overlaps(&self, from: Option<i64>, to: Option<i64>) -> bool {
+        from.is_none_or(|from| self.max >= from) && to.is_none_or(|to| self.min < to)
+    }
+}
+
+///Which segment files make up every column, stored in `column_layout.json`.
+///Columns append to their active segment, which gets closed once it holds `segment_rows` rows.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct SegmentManifest {
+    ///Full segments, oldest first
+    pub closed: Vec<ColumnSegment>,
+    ///Segment 0 is the `column_<name>` file tables without segments keep appending to
+    pub active: u64,
+    ///Rows dropped along with whole segments. Tombstones count positions from the first row ever stored.
+    pub dropped_rows: usize,
+    ///Bumped whenever rows move to other positions, e.g. by a compaction. Page cursors of an earlier
+    ///generation no longer point at the same rows.
+    #[serde(default)]
+    pub generation: u64,
+    ///Encodings the latest compaction picked for the columns the schema sets to Auto, see `encoding_analyzer`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub encodings: BTreeMap<String, ColumnEncoding>,
+}
+
+impl SegmentManifest {
+    pub fn is_empty(&self) -> bool {
+        self == &SegmentManifest::default()
+    }
+
+    ///Rows in all full segments
+    pub fn closed_rows(&self) -> usize {
+        self.closed.iter().map(|segment| segment.rows).sum()
+    }
+
+    ///Id no segment used so far
+    pub fn next_id(&self) -> u64 {
+        self.closed.iter().map(|segment| segment.id).fold(self.active, u64::max) + 1
+    }
+
+    ///Manifest of rows written from scratch, e.g. by a compaction. Full segments holding closed_rows rows
+    ///get new ids, the remaining rows stay in the active segment, see `closed_segment_rows`.
+    pub fn rewritten(&self, closed_rows: &[usize]) -> SegmentManifest {
+        let next_id = self.next_id();
+        let closed = closed_rows
+            .iter()
+            .zip(next_id..)
+            .map(|(rows, id)| ColumnSegment { id, rows: *rows, timestamps: None })
+            .collect();
+        SegmentManifest {
+            closed,
+            active: self.active,
+            dropped_rows: 0,
+            generation: self.generation + 1,
+            encodings: self.encodings.clone(),
+        }
+    }
+
+    ///Sets the zone maps of the closed segments from the timestamps of all rows, oldest first
+    pub fn set_timestamps(&mut self, timestamps: &[Cell]) {
+        let mut rest = timestamps;
+        for segment in self.closed.iter_mut() {
+            let (cells, tail) = rest.split_at(segment.rows.min(rest.len()));
+            segment.timestamps = ZoneMap::from_cells(cells);
+            rest = tail;
+        }
+    }
+
+    ///Positions of the rows in closed segments whose timestamps all lie outside `from..to`
+    pub fn pruned_rows(&self, from: Option<i64>, to: Option<i64>) -> Vec<Range<usize>> {
+        let mut pruned = vec![];
+        let mut start = 0;
+        for segment in &self.closed {
+            if segment.timestamps.is_some_and(|zone| !zone.overlaps(from, to)) {
+                pruned.push(start..start + segment.rows);
+            }
+            start += segment.rows;
+        }
+        pruned
+    }
+
+    ///Segment holding the row at the position, and the row's offset in it. Positions past the last row
+    ///point behind the rows of the active segment.
+    pub fn locate(&self, position: usize) -> (u64, usize) {
+        let mut start = 0;
+        for segment in &self.closed {
+            if position < start + segment.rows {
+                return (segment.id, position - start);
+            }
+            start += segment.rows;
+        }
+        (self.active, position - start)
+    }
+
+    ///Position of the row at the offset of the segment, see `locate`. Segments dropped since hold no rows
+    ///anymore, so their rows continue at the first remaining one. None for segments which never existed.
+    pub fn position(&self, segment: u64, offset: usize) -> Option<usize> {
+        let mut start = 0;
+        for closed in &self.closed {
+            if closed.id == segment {
+                return (offset <= closed.rows).then_some(start + offset);
+            }
+            start += closed.rows;
+        }
+        let first = self.closed.first().map(|closed| closed.id).unwrap_or(self.active);
+        match segment {
+            segment if segment == self.active => Some(start + offset),
+            segment if segment < first => Some(0),
+            _ => None,
+        }
+    }
+}
+
+///Rows of the full segments the rows with these timestamps get split into when written from scratch.
+///A segment closes once it holds segment_rows rows, or before a row of a later period, like new rows do.
+///The rows after the last full segment are left for the active segment.
+pub fn closed_segment_rows(
+    timestamps: &[Cell],
+    segment_rows: Option<usize>,
+    partition_by: Option<PartitionPeriod>,
+) -> Vec<usize> {
+    let segment_rows = segment_rows.map(|segment_rows| segment_rows.max(1));
+    let mut closed = vec![];
+    let mut rows = 0;
+    let mut period = None;
+    for timestamp in timestamps {
+        let row_period = partition_by.zip(timestamp.as_int()).map(|(partition_by, timestamp)| partition_by.period(*timestamp));
+        if rows > 0 && row_period.is_some_and(|row_period| period.is_some_and(|period| row_period > period)) {
+            closed.push(rows);
+            rows = 0;
+            period = None;
+        }
+        rows += 1;
+        period = period.max(row_period);
+        if segment_rows.is_some_and(|segment_rows| rows == segment_rows) {
+            closed.push(rows);
+            rows = 0;
+            period = None;
+        }
+    }
+    closed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{closed_segment_rows, ColumnSegment, SegmentManifest, ZoneMap};
+    use crate::config::PartitionPeriod;
+    use crate::storage::cell::Cell;
+
+    #[test]
+    fn positions_survive_dropped_segments() {
+        let mut segments = SegmentManifest {
+            closed: vec![
+                ColumnSegment { id: 1, rows: 3, timestamps: None },
+                ColumnSegment { id: 2, rows: 3, timestamps: None },
+            ],
+            active: 3,
+            ..Default::default()
+        };
+        assert_eq!(segments.locate(1), (1, 1));
+        assert_eq!(segments.locate(3), (2, 0));
+        assert_eq!(segments.locate(7), (3, 1));
+        assert_eq!(segments.position(3, 1), Some(7));
+
+        segments.closed.remove(0);
+        assert_eq!(segments.position(2, 0), Some(0));
+        assert_eq!(segments.position(1, 2), Some(0));
+        assert_eq!(segments.position(3, 1), Some(4));
+        assert_eq!(segments.position(2, 4), None);
+        assert_eq!(segments.position(9, 0), None);
+        assert_eq!(segments.rewritten(&[3]).generation, 1);
+    }
+
+    #[test]
+    fn zone_maps_prune_segments_outside_the_time_range() {
+        let mut segments = SegmentManifest {
+            closed: vec![
+                ColumnSegment { id: 0, rows: 2, timestamps: None },
+                ColumnSegment { id: 1, rows: 2, timestamps: None },
+            ],
+            active: 2,
+            ..Default::default()
+        };
+        segments.set_timestamps(&[Cell::Timestamp(100), Cell::Timestamp(50), Cell::Null, Cell::Int(300)]);
+        assert_eq!(segments.closed[0].timestamps, Some(ZoneMap { min: 50, max: 100 }));
+        assert_eq!(segments.closed[1].timestamps, Some(ZoneMap { min: 300, max: 300 }));
+
+        assert_eq!(segments.pruned_rows(Some(200), None), vec![0..2]);
+        assert_eq!(segments.pruned_rows(None, Some(100)), vec![2..4]);
+        assert_eq!(segments.pruned_rows(Some(100), Some(101)), vec![2..4]);
+        assert!(segments.pruned_rows(Some(50), Some(301)).is_empty());
+    }
+
+    #[test]
+    fn rewritten_rows_get_split_by_size_and_period() {
+        let hour = 60 * 60;
+        let timestamps = [0, 10, hour, hour + 1, hour + 2, 5 * hour].map(Cell::Timestamp);
+        assert_eq!(closed_segment_rows(&timestamps, Some(4), None), vec![4]);
+        assert_eq!(closed_segment_rows(&timestamps, None, Some(PartitionPeriod::Hour)), vec![2, 3]);
+        assert_eq!(closed_segment_rows(&timestamps, Some(2), Some(PartitionPeriod::Hour)), vec![2, 2, 1]);
+        assert!(closed_segment_rows(&timestamps, None, Some(PartitionPeriod::Day)).is_empty());
+        assert!(closed_segment_rows(&[Cell::Null, Cell::Null], None, Some(PartitionPeriod::Hour)).is_empty());
+    }
+}
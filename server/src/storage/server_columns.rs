@@ -0,0 +1,67 @@
+use chrono::NaiveDateTime;
+use sha2::{Digest, Sha256};
+
+use crate::config::ServerComputed;
+
+use super::cell::Cell;
+
+///Value of a server computed column, given the other cells of the row
+pub fn compute(kind: &ServerComputed, row: &[(String, Cell)]) -> Cell {
+    let value = |column_name: &str| {
+        row.iter()
+            .find(|(name, _)| name == column_name)
+            .map(|(_, cell)| cell)
+            .unwrap_or(&Cell::Null)
+    };
+    match kind {
+        ServerComputed::IngestDate => match value("timestamp") {
+            Cell::Timestamp(timestamp) | Cell::Int(timestamp) => NaiveDateTime::from_timestamp_opt(*timestamp, 0)
+                .map(|date_time| Cell::String(date_time.date().format("%Y-%m-%d").to_string()))
+                .unwrap_or(Cell::Null),
+            _ => Cell::Null,
+        },
+        ServerComputed::RowHash(columns) => {
+            let mut hasher = Sha256::new();
+            for column_name in columns {
+                //Tag and length keep e.g. "ab", "c" apart from "a", "bc"
+                let (_checksum, tag_byte, bytes) = value(column_name).to_bytes().unwrap_or_default();
+                hasher.update([tag_byte]);
+                hasher.update((bytes.len() as u32).to_le_bytes());
+                hasher.update(bytes);
+            }
+            Cell::String(hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::compute;
+    use crate::config::ServerComputed;
+    use crate::storage::cell::Cell;
+
+    #[test]
+    fn computes_ingest_date_and_row_hash() {
+        let row = vec![
+            ("timestamp".to_string(), Cell::Timestamp(1677125260)),
+            ("url".to_string(), Cell::String("ab".into())),
+            ("path".to_string(), Cell::String("c".into())),
+        ];
+        assert_eq!(compute(&ServerComputed::IngestDate, &row), Cell::String("2023-02-23".into()));
+
+        let hash = |columns: &[&str], row: &[(String, Cell)]| {
+            compute(&ServerComputed::RowHash(columns.iter().map(|c| c.to_string()).collect()), row)
+        };
+        let Cell::String(url_and_path) = hash(&["url", "path"], &row) else {
+            panic!("Row hashes are strings");
+        };
+        assert_eq!(url_and_path.len(), 64);
+        let shifted = vec![
+            ("url".to_string(), Cell::String("a".into())),
+            ("path".to_string(), Cell::String("bc".into())),
+        ];
+        assert_ne!(hash(&["url", "path"], &shifted), Cell::String(url_and_path.clone()));
+        assert_ne!(hash(&["path", "url"], &row), Cell::String(url_and_path.clone()));
+        assert_eq!(hash(&["url", "path"], &row), Cell::String(url_and_path));
+    }
+}
@@ -0,0 +1,212 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use tracing::{info, instrument, warn};
+
+use crate::config::{SchemaConfig, StorageBackendConfig};
+
+use super::data_dir_lock::DataDirLock;
+use super::replica;
+use super::schema_fingerprint::SchemaFingerprint;
+use super::wal::Wal;
+use super::{Container, ContainerError};
+
+///Files in the data directory which belong to the node rather than the table. Restoring leaves them in place.
+const NODE_FILES: [&str; 4] = ["usage.json", "jobs.json", "alerts.json", "query_audit.jsonl"];
+
+///Outcome of restoring a snapshot
+#[derive(Debug)]
+pub struct RestoreReport {
+    ///Live rows of the restored table
+    pub rows: usize,
+    ///Table files which got replaced, relative to the data directory. They got moved to the backup.
+    pub replaced: Vec<PathBuf>,
+}
+
+///Checks the snapshot taken by `Container::snapshot`, then replaces the table files in root_path with it.
+///The snapshot gets copied into a staging directory next to root_path and opened there first, which checks the
+///checksums of all records and whether the columns fit the schema. Only if that succeeds, the current table files
+///get moved to backup_path and the staged ones take their place. Files of the node, like usage counters,
+///and directories like derived tables stay as they are. The server must not be running on root_path meanwhile.
+#[instrument]
+pub fn restore_snapshot(
+    snapshot_path: &Path,
+    root_path: &PathBuf,
+    config: &SchemaConfig,
+    backup_path: &Path,
+) -> Result<RestoreReport, ContainerError> {
+    if config.storage_backend != StorageBackendConfig::Local {
+        return Err(io::Error::new(io::ErrorKind::Unsupported, "Only tables stored on the local disk can be restored from a snapshot").into());
+    }
+    if backup_path.exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("Backup {} already exists", backup_path.display()),
+        )
+        .into());
+    }
+    if backup_path.starts_with(root_path) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "The backup can't be stored inside the data directory",
+        )
+        .into());
+    }
+
+    let mut staging_name = root_path.file_name().unwrap_or_default().to_os_string();
+    staging_name.push(".restoring");
+    let staging_path = root_path.with_file_name(staging_name);
+    //Left behind by a restore which failed before
+    match fs::remove_dir_all(&staging_path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err.into()),
+        _ => {}
+    }
+    fs::create_dir_all(&staging_path)?;
+    let rows = match stage(snapshot_path, &staging_path, config) {
+        Ok(rows) => rows,
+        Err(err) => {
+            if let Err(remove_err) = fs::remove_dir_all(&staging_path) {
+                warn!("Failed to delete {}: {}", staging_path.display(), remove_err);
+            }
+            return Err(err);
+        }
+    };
+
+    info!("Moving the table files of {} to {}", root_path.display(), backup_path.display());
+    fs::create_dir_all(backup_path)?;
+    let lock_path = DataDirLock::file_path(root_path);
+    let mut replaced = vec![];
+    for entry in fs::read_dir(root_path)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        if !entry.file_type()?.is_file() || entry.path() == lock_path || NODE_FILES.iter().any(|node_file| name == *node_file) {
+            continue;
+        }
+        fs::rename(entry.path(), backup_path.join(&name))?;
+        replaced.push(PathBuf::from(name));
+    }
+    for entry in fs::read_dir(&staging_path)? {
+        let entry = entry?;
+        fs::rename(entry.path(), root_path.join(entry.file_name()))?;
+    }
+    fs::remove_dir(&staging_path)?;
+    Ok(RestoreReport { rows, replaced })
+}
+
+///Copies the snapshot into staging_path and opens it there. Returns the number of live rows.
+fn stage(snapshot_path: &Path, staging_path: &Path, config: &SchemaConfig) -> Result<usize, ContainerError> {
+    let bytes = replica::copy_table_files(snapshot_path, staging_path)?;
+    let snapshot_wal = Wal::file_path(&snapshot_path.to_path_buf());
+    if snapshot_wal.exists() {
+        fs::copy(snapshot_wal, Wal::file_path(&staging_path.to_path_buf()))?;
+    } else {
+        warn!("The snapshot holds no write-ahead log, the restored table starts a new one");
+    }
+    info!("Copied {} bytes of {} to {}", bytes, snapshot_path.display(), staging_path.display());
+
+    let file_lens = file_lens(staging_path)?;
+    //Every column gets read, so every record gets its checksum checked
+    let mut check_config = config.clone();
+    check_config.lazy_columns = false;
+    let container = Container::new(&staging_path.to_path_buf(), check_config)?;
+    //Opening only checks the schema against the one the snapshot last ran with, if it recorded one
+    let conflicts = SchemaFingerprint::of(config).conflicts(&container.columns);
+    if !conflicts.is_empty() {
+        return Err(ContainerError::SchemaConflict(conflicts));
+    }
+    let rows = container.id_diagnostics()?.rows;
+    drop(container);
+    //Opening cuts torn writes off the column files. A snapshot of flushed columns doesn't hold any, so it got damaged.
+    for (name, len) in file_lens {
+        let checked_len = fs::metadata(staging_path.join(&name))?.len();
+        if checked_len < len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} of the snapshot ends in {} corrupt bytes", name, len - checked_len),
+            )
+            .into());
+        }
+    }
+    Ok(rows)
+}
+
+fn file_lens(path: &Path) -> io::Result<HashMap<String, u64>> {
+    let mut lens = HashMap::new();
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        lens.insert(entry.file_name().to_string_lossy().to_string(), entry.metadata()?.len());
+    }
+    Ok(lens)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::restore_snapshot;
+    use crate::config::SchemaConfig;
+    use crate::storage::Container;
+    use crate::web::IndexParams;
+
+    fn config() -> SchemaConfig {
+        serde_json::from_value(serde_json::json!({
+            "add_timestamp_column": false,
+            "columns": [{ "name": "url", "data_type": "String" }]
+        }))
+        .unwrap()
+    }
+
+    fn url_params(url: &str) -> IndexParams {
+        IndexParams {
+            fields: vec!["url".into()],
+            values: vec![url.into()],
+        }
+    }
+
+    #[test]
+    fn restores_checked_snapshots_and_keeps_the_replaced_table() {
+        let root = tempfile::tempdir().unwrap();
+        let root_path = root.path().join("db");
+        fs::create_dir(&root_path).unwrap();
+        fs::write(root_path.join("usage.json"), "{}").unwrap();
+        let mut container = Container::new(&root_path, config()).unwrap();
+        container.index(url_params("https://google.com")).unwrap();
+        container.index(url_params("https://google.com/maps")).unwrap();
+        let snapshot_path = container.snapshot().unwrap();
+        container.index(url_params("https://google.com/mail")).unwrap();
+        drop(container);
+
+        //Snapshots don't fit a schema whose columns the table doesn't have
+        let mut other_config = config();
+        other_config.columns[0].name = "link".into();
+        assert!(restore_snapshot(&snapshot_path, &root_path, &other_config, &root.path().join("backup")).is_err());
+        assert!(!root.path().join("backup").exists());
+        assert!(!root.path().join("db.restoring").exists());
+
+        let report = restore_snapshot(&snapshot_path, &root_path, &config(), &root.path().join("backup")).unwrap();
+        assert_eq!(report.rows, 2);
+        assert!(root.path().join("backup").join("column_url").exists());
+        assert!(root_path.join("usage.json").exists());
+        assert!(snapshot_path.exists());
+        let mut restored = Container::new(&root_path, config()).unwrap();
+        assert_eq!(restored.id_diagnostics().unwrap().rows, 2);
+        assert_eq!(restored.index(url_params("https://google.com/mail")).unwrap(), 3);
+        drop(restored);
+        assert_eq!(Container::repair_from_wal(&root_path, &config().storage_backend).unwrap(), 3);
+
+        //A corrupt record in the middle of a column file
+        let damaged_path = root.path().join("damaged");
+        fs::create_dir(&damaged_path).unwrap();
+        for entry in fs::read_dir(&snapshot_path).unwrap() {
+            let entry = entry.unwrap();
+            fs::copy(entry.path(), damaged_path.join(entry.file_name())).unwrap();
+        }
+        let mut column = fs::read(damaged_path.join("column_url")).unwrap();
+        let first_url = column.windows(b"google.com".len()).position(|bytes| bytes == b"google.com").unwrap();
+        column[first_url] ^= 0xff;
+        fs::write(damaged_path.join("column_url"), column).unwrap();
+        assert!(restore_snapshot(&damaged_path, &root_path, &config(), &root.path().join("backup2")).is_err());
+    }
+}
@@ -0,0 +1,38 @@
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+///How inserts turned out for one key column, i.e. the `unique_key` or the dedupe window's `key_column`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize)]
+pub struct KeyConflicts {
+    ///Rows whose key wasn't stored yet
+    pub fresh: u64,
+    ///Rows whose key was already stored, and which got dropped, replaced a row or were rejected
+    pub conflicts: u64,
+}
+
+///Outcome of inserts per key column since startup, for `GET /metrics`
+#[derive(Debug, Default)]
+pub struct UpsertConflicts {
+    columns: BTreeMap<String, KeyConflicts>,
+}
+
+impl UpsertConflicts {
+    pub fn record_fresh(&mut self, column_name: &str) {
+        self.columns.entry(column_name.to_string()).or_default().fresh += 1;
+    }
+
+    pub fn record_conflict(&mut self, column_name: &str) {
+        self.columns.entry(column_name.to_string()).or_default().conflicts += 1;
+    }
+
+    pub fn rename(&mut self, from: &str, to: &str) {
+        if let Some(counts) = self.columns.remove(from) {
+            self.columns.insert(to.to_string(), counts);
+        }
+    }
+
+    pub fn snapshot(&self) -> Vec<(String, KeyConflicts)> {
+        self.columns.iter().map(|(column_name, counts)| (column_name.clone(), *counts)).collect()
+    }
+}
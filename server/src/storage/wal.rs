@@ -0,0 +1,407 @@
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use tracing::{instrument, warn};
+
+use super::cell::Cell;
+use super::data_type::DataType;
+use super::file_header::FileHeader;
+use super::wal_error::WalError;
+use super::CRC32;
+
+const KIND_LAYOUT: u8 = 1;
+const KIND_ROW: u8 = 2;
+const KIND_COLUMN: u8 = 3;
+const KIND_EXPIRE: u8 = 4;
+const KIND_TRANSACTION: u8 = 5;
+const KIND_DELETE: u8 = 6;
+const KIND_RENAME: u8 = 7;
+const KIND_UPDATE: u8 = 8;
+const KIND_REASSIGN: u8 = 9;
+const KIND_DROP: u8 = 10;
+
+const WAL_MAGIC: &[u8; 4] = b"WHWL";
+///Bump whenever the record encoding changes. Logs with an older version get migrated on open.
+///Version 2 added deletes, renames, updates, reassigned ids and dropped columns.
+pub const WAL_FORMAT_VERSION: u16 = 2;
+
+#[derive(Debug)]
+pub enum WalRecord {
+    Layout(Vec<(String, DataType)>),
+    Row(Vec<(String, Cell)>),
+    ///A column added to a table with existing rows, holding one cell per existing row
+    Column(String, DataType, Vec<Cell>),
+    ///Retention deleted all rows with a timestamp before this cutoff
+    Expire(i64),
+    ///Rows which were committed together
+    Transaction(Vec<Vec<(String, Cell)>>),
+    ///The row with this id got deleted
+    Delete(i64),
+    ///A column got renamed from the first to the second name
+    Rename(String, String),
+    ///The row with this id got replaced by a new version
+    Update(i64, Vec<(String, Cell)>),
+    ///The rows at these positions got new ids, to resolve duplicates
+    Reassign(Vec<(usize, i64)>),
+    ///The column with this name got dropped
+    Drop(String),
+}
+
+///Append-only log every committed row is written to before it reaches the column files.
+///The log starts with a header holding its format version.
+///Each record is stored as `checksum | kind | payload length | payload`.
+#[derive(Debug)]
+pub struct Wal {
+    f: File,
+    len: u64,
+    header_len: u64,
+}
+
+impl Wal {
+    pub fn file_path(root_path: &PathBuf) -> PathBuf {
+        Path::new(root_path).join("wal")
+    }
+
+    pub fn open(root_path: &PathBuf) -> Result<Self, WalError> {
+        let file_path = Wal::file_path(root_path);
+        let mut f = Wal::open_file(&file_path)?;
+        let header = FileHeader::new(WAL_FORMAT_VERSION, vec![]);
+        let mut len = f.metadata()?.len();
+
+        if len == 0 {
+            header.write(&mut f, WAL_MAGIC)?;
+            len = header.len();
+        } else {
+            let records_start = match FileHeader::read(&mut BufReader::new(File::open(&file_path)?), WAL_MAGIC)? {
+                Some(saved_header) => {
+                    Wal::validate_header(&saved_header)?;
+                    //Appending records an older version didn't know requires the log to say it has the current one
+                    if saved_header.version < WAL_FORMAT_VERSION {
+                        warn!(
+                            "Write-ahead log has format version {}. Migrating it to format version {}",
+                            saved_header.version, WAL_FORMAT_VERSION
+                        );
+                        Some(saved_header.len())
+                    } else {
+                        None
+                    }
+                }
+                None => {
+                    warn!("Write-ahead log has no header. Migrating it to format version {}", WAL_FORMAT_VERSION);
+                    Some(0)
+                }
+            };
+            if let Some(records_start) = records_start {
+                let mut migrated = header.to_bytes(WAL_MAGIC)?;
+                migrated.extend_from_slice(&fs::read(&file_path)?[records_start as usize..]);
+                let tmp_path = file_path.with_extension("migrating");
+                {
+                    let mut tmp = File::create(&tmp_path)?;
+                    tmp.write_all(&migrated)?;
+                    tmp.sync_all()?;
+                }
+                fs::rename(&tmp_path, &file_path)?;
+                f = Wal::open_file(&file_path)?;
+                len = migrated.len() as u64;
+            }
+        }
+
+        Ok(Self {
+            f,
+            len,
+            header_len: header.len(),
+        })
+    }
+
+    ///Format version of the existing log. `None` if it was written before headers existed.
+    pub fn format_version(root_path: &PathBuf) -> io::Result<Option<u16>> {
+        let mut f = BufReader::new(File::open(Wal::file_path(root_path))?);
+        Ok(FileHeader::read(&mut f, WAL_MAGIC)?.map(|header| header.version))
+    }
+
+    fn open_file(file_path: &Path) -> io::Result<File> {
+        OpenOptions::new().read(true).create(true).append(true).open(file_path)
+    }
+
+    fn validate_header(header: &FileHeader) -> Result<(), WalError> {
+        if header.version > WAL_FORMAT_VERSION {
+            return Err(WalError::UnsupportedVersion(header.version));
+        }
+        Ok(())
+    }
+
+    ///Whether the log holds no records yet
+    pub fn is_empty(&self) -> bool {
+        self.len <= self.header_len
+    }
+
+    ///Length of the log, to cut records off again with `truncate`
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    ///Cuts off the records appended after the log had the length, e.g. the ones of a rolled back transaction
+    #[instrument(skip(self))]
+    pub fn truncate(&mut self, len: u64) -> io::Result<()> {
+        self.f.set_len(len)?;
+        self.len = len;
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    pub fn append_layout(&mut self, layout: &[(String, DataType)]) -> Result<(), WalError> {
+        let payload = serde_json::to_vec(layout)?;
+        self.append(KIND_LAYOUT, payload)?;
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    pub fn append_row(&mut self, values: &[(String, Cell)]) -> Result<(), WalError> {
+        let mut payload = vec![];
+        Wal::encode_row(&mut payload, values)?;
+        self.append(KIND_ROW, payload)?;
+        Ok(())
+    }
+
+    #[instrument(skip(self, rows))]
+    pub fn append_transaction(&mut self, rows: &[Vec<(String, Cell)>]) -> Result<(), WalError> {
+        let mut payload = vec![];
+        payload.write_u32::<LittleEndian>(rows.len() as u32)?;
+        for values in rows {
+            Wal::encode_row(&mut payload, values)?;
+        }
+        self.append(KIND_TRANSACTION, payload)?;
+        Ok(())
+    }
+
+    fn encode_row(payload: &mut Vec<u8>, values: &[(String, Cell)]) -> io::Result<()> {
+        payload.write_u16::<LittleEndian>(values.len() as u16)?;
+        for (column_name, cell) in values {
+            Wal::encode_name(payload, column_name)?;
+            Wal::encode_cell(payload, cell)?;
+        }
+        Ok(())
+    }
+
+    #[instrument(skip(self, cells))]
+    pub fn append_column(&mut self, column_name: &str, data_type: &DataType, cells: &[Cell]) -> Result<(), WalError> {
+        let mut payload = vec![];
+        Wal::encode_name(&mut payload, column_name)?;
+        Wal::encode_name(&mut payload, &serde_json::to_string(data_type)?)?;
+        payload.write_u32::<LittleEndian>(cells.len() as u32)?;
+        for cell in cells {
+            Wal::encode_cell(&mut payload, cell)?;
+        }
+        self.append(KIND_COLUMN, payload)?;
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    pub fn append_expire(&mut self, cutoff: i64) -> Result<(), WalError> {
+        let mut payload = vec![];
+        payload.write_i64::<LittleEndian>(cutoff)?;
+        self.append(KIND_EXPIRE, payload)?;
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    pub fn append_delete(&mut self, id: i64) -> Result<(), WalError> {
+        let mut payload = vec![];
+        payload.write_i64::<LittleEndian>(id)?;
+        self.append(KIND_DELETE, payload)?;
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    pub fn append_update(&mut self, id: i64, values: &[(String, Cell)]) -> Result<(), WalError> {
+        let mut payload = vec![];
+        payload.write_i64::<LittleEndian>(id)?;
+        Wal::encode_row(&mut payload, values)?;
+        self.append(KIND_UPDATE, payload)?;
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    pub fn append_reassign(&mut self, new_ids: &[(usize, i64)]) -> Result<(), WalError> {
+        let mut payload = vec![];
+        payload.write_u32::<LittleEndian>(new_ids.len() as u32)?;
+        for (position, id) in new_ids {
+            payload.write_u64::<LittleEndian>(*position as u64)?;
+            payload.write_i64::<LittleEndian>(*id)?;
+        }
+        self.append(KIND_REASSIGN, payload)?;
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    pub fn append_rename(&mut self, from: &str, to: &str) -> Result<(), WalError> {
+        let mut payload = vec![];
+        Wal::encode_name(&mut payload, from)?;
+        Wal::encode_name(&mut payload, to)?;
+        self.append(KIND_RENAME, payload)?;
+        Ok(())
+    }
+
+    pub fn append_drop(&mut self, column_name: &str) -> Result<(), WalError> {
+        let mut payload = vec![];
+        Wal::encode_name(&mut payload, column_name)?;
+        self.append(KIND_DROP, payload)?;
+        Ok(())
+    }
+
+    fn encode_name(payload: &mut Vec<u8>, name: &str) -> io::Result<()> {
+        payload.write_u16::<LittleEndian>(name.len() as u16)?;
+        payload.write_all(name.as_bytes())
+    }
+
+    fn encode_cell(payload: &mut Vec<u8>, cell: &Cell) -> io::Result<()> {
+        let (_checksum, tag_byte, bytes) = cell.to_bytes()?;
+        payload.write_u8(tag_byte)?;
+        payload.write_u32::<LittleEndian>(bytes.len() as u32)?;
+        payload.write_all(&bytes)
+    }
+
+    fn append(&mut self, kind: u8, payload: Vec<u8>) -> io::Result<()> {
+        //Assemble the whole record first, so it reaches the file with a single write
+        let mut record = Vec::with_capacity(9 + payload.len());
+        record.write_u32::<LittleEndian>(CRC32.checksum(&payload))?;
+        record.write_u8(kind)?;
+        record.write_u32::<LittleEndian>(payload.len() as u32)?;
+        record.write_all(&payload)?;
+        self.f.write_all(&record)?;
+        self.len += record.len() as u64;
+        Ok(())
+    }
+
+    ///Waits until all appended records reached the disk
+    #[instrument(skip(self))]
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.f.sync_data()
+    }
+
+    ///Reads all intact records. Reading stops at the first torn or corrupted record.
+    #[instrument]
+    pub fn read_all(root_path: &PathBuf) -> Result<Vec<WalRecord>, WalError> {
+        let mut f = BufReader::new(File::open(Wal::file_path(root_path))?);
+        match FileHeader::read(&mut f, WAL_MAGIC)? {
+            Some(header) => Wal::validate_header(&header)?,
+            //Logs from before headers existed start right with their first record
+            None => f = BufReader::new(File::open(Wal::file_path(root_path))?),
+        }
+        let mut records = vec![];
+
+        loop {
+            match Wal::read_record(&mut f) {
+                Ok(Some(record)) => records.push(record),
+                Ok(None) => {
+                    warn!("Discarding corrupted record at the end of the write-ahead log");
+                    break;
+                }
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(err) => return Err(err.into()),
+            }
+        }
+
+        Ok(records)
+    }
+
+    fn read_record<R: Read>(f: &mut R) -> io::Result<Option<WalRecord>> {
+        let saved_checksum = f.read_u32::<LittleEndian>()?;
+        let kind = f.read_u8()?;
+        let payload_len = f.read_u32::<LittleEndian>()?;
+        let mut payload = vec![0; payload_len as usize];
+        f.read_exact(&mut payload)?;
+
+        if CRC32.checksum(&payload) != saved_checksum {
+            return Ok(None);
+        }
+
+        match kind {
+            KIND_LAYOUT => Ok(serde_json::from_slice(&payload).ok().map(WalRecord::Layout)),
+            KIND_ROW => Ok(Wal::decode_row(&mut payload.as_slice()).ok().map(WalRecord::Row)),
+            KIND_TRANSACTION => Ok(Wal::decode_transaction(&payload).ok().map(WalRecord::Transaction)),
+            KIND_COLUMN => Ok(Wal::decode_column(&payload).ok()),
+            KIND_EXPIRE => Ok(payload.as_slice().read_i64::<LittleEndian>().ok().map(WalRecord::Expire)),
+            KIND_DELETE => Ok(payload.as_slice().read_i64::<LittleEndian>().ok().map(WalRecord::Delete)),
+            KIND_RENAME => Ok(Wal::decode_rename(&payload).ok()),
+            KIND_UPDATE => Ok(Wal::decode_update(&payload).ok()),
+            KIND_REASSIGN => Ok(Wal::decode_reassign(&payload).ok()),
+            KIND_DROP => Ok(Wal::decode_name(&mut payload.as_slice()).ok().map(WalRecord::Drop)),
+            //An intact record of an unknown kind was written by a newer version, discarding it would lose the rest of the log
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, format!("Write-ahead log record of unknown kind {}", kind))),
+        }
+    }
+
+    fn decode_row(payload: &mut &[u8]) -> io::Result<Vec<(String, Cell)>> {
+        let cell_count = payload.read_u16::<LittleEndian>()?;
+        let mut values = Vec::with_capacity(cell_count as usize);
+        for _ in 0..cell_count {
+            let name = Wal::decode_name(payload)?;
+            let cell = Wal::decode_cell(payload)?;
+            values.push((name, cell));
+        }
+        Ok(values)
+    }
+
+    fn decode_transaction(payload: &[u8]) -> io::Result<Vec<Vec<(String, Cell)>>> {
+        let mut payload = payload;
+        let row_count = payload.read_u32::<LittleEndian>()?;
+        let mut rows = Vec::with_capacity(row_count as usize);
+        for _ in 0..row_count {
+            rows.push(Wal::decode_row(&mut payload)?);
+        }
+        Ok(rows)
+    }
+
+    fn decode_column(payload: &[u8]) -> io::Result<WalRecord> {
+        let mut payload = payload;
+        let name = Wal::decode_name(&mut payload)?;
+        let data_type = serde_json::from_str(&Wal::decode_name(&mut payload)?)?;
+        let cell_count = payload.read_u32::<LittleEndian>()?;
+        let mut cells = Vec::with_capacity(cell_count as usize);
+        for _ in 0..cell_count {
+            cells.push(Wal::decode_cell(&mut payload)?);
+        }
+        Ok(WalRecord::Column(name, data_type, cells))
+    }
+
+    fn decode_update(payload: &[u8]) -> io::Result<WalRecord> {
+        let mut payload = payload;
+        let id = payload.read_i64::<LittleEndian>()?;
+        Ok(WalRecord::Update(id, Wal::decode_row(&mut payload)?))
+    }
+
+    fn decode_reassign(payload: &[u8]) -> io::Result<WalRecord> {
+        let mut payload = payload;
+        let count = payload.read_u32::<LittleEndian>()?;
+        let mut new_ids = vec![];
+        for _ in 0..count {
+            let position = payload.read_u64::<LittleEndian>()? as usize;
+            new_ids.push((position, payload.read_i64::<LittleEndian>()?));
+        }
+        Ok(WalRecord::Reassign(new_ids))
+    }
+
+    fn decode_rename(payload: &[u8]) -> io::Result<WalRecord> {
+        let mut payload = payload;
+        let from = Wal::decode_name(&mut payload)?;
+        let to = Wal::decode_name(&mut payload)?;
+        Ok(WalRecord::Rename(from, to))
+    }
+
+    fn decode_name(payload: &mut &[u8]) -> io::Result<String> {
+        let name_len = payload.read_u16::<LittleEndian>()?;
+        let mut name = vec![0; name_len as usize];
+        payload.read_exact(&mut name)?;
+        String::from_utf8(name).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    fn decode_cell(payload: &mut &[u8]) -> io::Result<Cell> {
+        let tag_byte = payload.read_u8()?;
+        let val_len = payload.read_u32::<LittleEndian>()?;
+        let mut data = vec![0; val_len as usize];
+        payload.read_exact(&mut data)?;
+        Cell::from_bytes(tag_byte, data).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Invalid cell"))
+    }
+}
@@ -0,0 +1,23 @@
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum WalError {
+    #[error("JSON Error")]
+    Json {
+        #[from]
+        source: serde_json::Error,
+    },
+    #[error("IO Error")]
+    Io {
+        #[from]
+        source: std::io::Error,
+    },
+    #[error("Write-ahead log does not contain a column layout")]
+    MissingLayout,
+    #[error("Row {0} in write-ahead log does not match column layout")]
+    LayoutMismatch(usize),
+    #[error("Write-ahead log is missing the rows before id {0}, they were written before the log existed")]
+    MissingRows(i64),
+    #[error("Write-ahead log has format version {0}, which this version of warenhaus can't read")]
+    UnsupportedVersion(u16),
+}
@@ -0,0 +1,205 @@
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::{info, instrument};
+
+use super::backend::StorageBackend;
+use super::wal::Wal;
+
+///Remote file listing the shipped chunks
+const MANIFEST_NAME: &str = "wal_manifest.json";
+///Upper bound for the bytes of a single chunk, larger backlogs get shipped in several
+const MAX_CHUNK_BYTES: u64 = 8 * 1024 * 1024;
+
+///A range of the write-ahead log stored as its own object
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ShippedChunk {
+    ///Position of the chunk's first byte in the log
+    pub offset: u64,
+    pub len: u64,
+    ///Hex encoded SHA-256 of the chunk's bytes
+    pub sha256: String,
+    ///Unix timestamp of the upload
+    pub shipped_at: i64,
+}
+
+///The chunks which got shipped so far, in log order. Chunks only count once they're listed here.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ShippingManifest {
+    pub chunks: Vec<ShippedChunk>,
+}
+
+impl ShippingManifest {
+    ///Bytes of the log covered by the chunks
+    pub fn shipped_len(&self) -> u64 {
+        self.chunks.last().map(|chunk| chunk.offset + chunk.len).unwrap_or_default()
+    }
+}
+
+///Copies the bytes appended to the write-ahead log to a remote backend, one chunk per run.
+///Chunks are named after their offset and the manifest only gets replaced once a chunk is stored,
+///so a failed run leaves the remote copy as it was and simply gets retried.
+#[derive(Debug)]
+pub struct WalShipper {
+    root_path: PathBuf,
+    remote: Arc<dyn StorageBackend>,
+    manifest: ShippingManifest,
+}
+
+fn chunk_name(offset: u64) -> String {
+    format!("wal_chunk_{:020}", offset)
+}
+
+fn sha256(bytes: &[u8]) -> String {
+    Sha256::digest(bytes).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn read_manifest(remote: &dyn StorageBackend) -> io::Result<ShippingManifest> {
+    let Some(len) = remote.len(MANIFEST_NAME)? else {
+        return Ok(ShippingManifest::default());
+    };
+    let mut bytes = vec![];
+    remote.read_range(MANIFEST_NAME, 0, len)?.read_to_end(&mut bytes)?;
+    serde_json::from_slice(&bytes).map_err(io::Error::other)
+}
+
+impl WalShipper {
+    ///Continues where the manifest of the remote copy ends
+    pub fn new(root_path: &Path, remote: Arc<dyn StorageBackend>) -> io::Result<Self> {
+        let manifest = read_manifest(remote.as_ref())?;
+        Ok(Self {
+            root_path: root_path.to_path_buf(),
+            remote,
+            manifest,
+        })
+    }
+
+    pub fn manifest(&self) -> &ShippingManifest {
+        &self.manifest
+    }
+
+    ///Uploads the log since the last run, up to `committed_len`. Returns the number of shipped bytes.
+    ///The log may be written meanwhile, but must not get cut off below `committed_len`, see `Command::WalLen`.
+    ///A record cut off at the end of a chunk continues in the next one.
+    #[instrument(skip(self))]
+    pub fn ship(&mut self, committed_len: u64) -> io::Result<u64> {
+        let mut f = File::open(Wal::file_path(&self.root_path))?;
+        let mut offset = self.manifest.shipped_len();
+        if committed_len < offset {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "The write-ahead log holds {} bytes, but {} were shipped already. It doesn't belong to the remote copy",
+                    committed_len, offset
+                ),
+            ));
+        }
+        let start = offset;
+        while offset < committed_len {
+            let len = (committed_len - offset).min(MAX_CHUNK_BYTES);
+            let mut bytes = vec![0; len as usize];
+            f.seek(SeekFrom::Start(offset))?;
+            f.read_exact(&mut bytes)?;
+
+            self.remote.replace(&chunk_name(offset), &bytes)?;
+            let mut manifest = self.manifest.clone();
+            manifest.chunks.push(ShippedChunk {
+                offset,
+                len,
+                sha256: sha256(&bytes),
+                shipped_at: chrono::Utc::now().timestamp(),
+            });
+            self.remote.replace(MANIFEST_NAME, &serde_json::to_vec(&manifest).map_err(io::Error::other)?)?;
+            self.manifest = manifest;
+            offset += len;
+        }
+        Ok(offset - start)
+    }
+}
+
+///Downloads the shipped chunks into the write-ahead log of the table stored at root_path, checking every chunk
+///against its checksum. With `until` set, only chunks shipped at or before that unix timestamp get restored.
+///Returns the number of restored bytes.
+#[instrument(skip(remote))]
+pub fn restore(root_path: &PathBuf, remote: &dyn StorageBackend, until: Option<i64>) -> io::Result<u64> {
+    let wal_path = Wal::file_path(root_path);
+    if wal_path.exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} already exists. Restore into an empty data directory", wal_path.display()),
+        ));
+    }
+    let manifest = read_manifest(remote)?;
+    let mut restored = vec![];
+    for chunk in manifest.chunks.iter().take_while(|chunk| until.is_none_or(|until| chunk.shipped_at <= until)) {
+        let mut bytes = vec![];
+        remote.read_range(&chunk_name(chunk.offset), 0, chunk.len)?.read_to_end(&mut bytes)?;
+        if bytes.len() as u64 != chunk.len || sha256(&bytes) != chunk.sha256 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Chunk {} doesn't match its checksum", remote.location(&chunk_name(chunk.offset))),
+            ));
+        }
+        restored.extend_from_slice(&bytes);
+    }
+    info!("Restoring {} of {} shipped bytes", restored.len(), manifest.shipped_len());
+    fs::create_dir_all(root_path)?;
+    //Written aside first, so an interrupted restore doesn't leave a partial log behind
+    let tmp_path = wal_path.with_extension("restoring");
+    fs::write(&tmp_path, &restored)?;
+    File::open(&tmp_path)?.sync_all()?;
+    fs::rename(&tmp_path, &wal_path)?;
+    Ok(restored.len() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tempfile::tempdir;
+
+    use super::{restore, WalShipper};
+    use crate::storage::backend::{LocalBackend, StorageBackend};
+    use crate::storage::cell::Cell;
+    use crate::storage::wal::{Wal, WalRecord};
+
+    #[test]
+    fn shipped_logs_get_restored() {
+        let table = tempdir().unwrap();
+        let table_path = table.path().to_path_buf();
+        let remote_dir = tempdir().unwrap();
+        let remote: Arc<dyn StorageBackend> = Arc::new(LocalBackend::new(remote_dir.path()));
+
+        let mut wal = Wal::open(&table_path).unwrap();
+        wal.append_row(&[("id".into(), Cell::Int(1))]).unwrap();
+        let mut shipper = WalShipper::new(&table_path, remote.clone()).unwrap();
+        assert!(shipper.ship(wal.len()).unwrap() > 0);
+        assert_eq!(shipper.ship(wal.len()).unwrap(), 0);
+        wal.append_row(&[("id".into(), Cell::Int(2))]).unwrap();
+        //Appended by a command which may still roll it back
+        let committed_len = wal.len();
+        wal.append_row(&[("id".into(), Cell::Int(3))]).unwrap();
+
+        //A restarted shipper continues after the chunks of the manifest
+        let mut shipper = WalShipper::new(&table_path, remote.clone()).unwrap();
+        assert!(shipper.ship(committed_len).unwrap() > 0);
+        assert_eq!(shipper.manifest().chunks.len(), 2);
+        assert_eq!(shipper.manifest().shipped_len(), committed_len);
+        wal.truncate(committed_len).unwrap();
+
+        let restored = tempdir().unwrap();
+        let restored_path = restored.path().to_path_buf();
+        restore(&restored_path, remote.as_ref(), None).unwrap();
+        assert!(matches!(Wal::read_all(&restored_path).unwrap().as_slice(), [WalRecord::Row(_), WalRecord::Row(_)]));
+        assert!(restore(&restored_path, remote.as_ref(), None).is_err());
+
+        //Chunks which don't match their checksum fail the restore
+        remote.replace(&super::chunk_name(0), b"torn").unwrap();
+        let corrupted = tempdir().unwrap();
+        assert!(restore(&corrupted.path().to_path_buf(), remote.as_ref(), None).is_err());
+    }
+}
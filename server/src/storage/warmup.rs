@@ -0,0 +1,202 @@
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use serde::Serialize;
+use tracing::{info, instrument};
+
+use super::backend::StorageBackend;
+use super::column::{Column, ColumnRecords};
+
+///Load progress of a single column
+#[derive(Debug, Clone, Serialize)]
+pub struct ColumnProgress {
+    pub column: String,
+    pub bytes_total: u64,
+    pub bytes_loaded: u64,
+    pub rows_loaded: usize,
+    pub done: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StartupProgress {
+    ///False until all columns are loaded. Writes get accepted before, reads don't.
+    pub ready: bool,
+    pub elapsed_secs: u64,
+    pub bytes_total: u64,
+    pub bytes_loaded: u64,
+    ///Estimated seconds until all columns are loaded, extrapolated from the bytes loaded so far
+    pub eta_secs: Option<u64>,
+    pub columns: Vec<ColumnProgress>,
+}
+
+///Shared between the warm-up thread, which reports progress, and the web layer, which serves it
+#[derive(Debug)]
+pub struct StartupTracker {
+    started: Instant,
+    ready: Mutex<bool>,
+    columns: Mutex<Vec<ColumnProgress>>,
+}
+
+impl Default for StartupTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StartupTracker {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            ready: Mutex::new(false),
+            columns: Mutex::new(vec![]),
+        }
+    }
+
+    pub fn is_ready(&self) -> bool {
+        *self.ready.lock().unwrap()
+    }
+
+    pub fn set_ready(&self) {
+        *self.ready.lock().unwrap() = true;
+        info!("Startup finished after {}s", self.started.elapsed().as_secs());
+    }
+
+    fn start(&self, plan: &WarmupPlan) {
+        *self.columns.lock().unwrap() = plan
+            .columns
+            .iter()
+            .map(|column| ColumnProgress {
+                column: column.name.to_string(),
+                bytes_total: column.bytes(),
+                bytes_loaded: 0,
+                rows_loaded: 0,
+                done: false,
+            })
+            .collect();
+    }
+
+    fn update(&self, n: usize, rows_loaded: usize, bytes_loaded: u64, done: bool) {
+        let mut columns = self.columns.lock().unwrap();
+        let column = &mut columns[n];
+        column.rows_loaded = rows_loaded;
+        column.bytes_loaded = bytes_loaded;
+        column.done = done;
+    }
+
+    pub fn progress(&self) -> StartupProgress {
+        let columns = self.columns.lock().unwrap().clone();
+        let bytes_total = columns.iter().map(|column| column.bytes_total).sum::<u64>();
+        let bytes_loaded = columns.iter().map(|column| column.bytes_loaded).sum::<u64>();
+        let elapsed = self.started.elapsed();
+        let ready = self.is_ready();
+        let eta_secs = match ready {
+            true => Some(0),
+            false if bytes_loaded > 0 => {
+                let remaining = bytes_total.saturating_sub(bytes_loaded) as f64 / bytes_loaded as f64;
+                Some((elapsed.as_secs_f64() * remaining).ceil() as u64)
+            }
+            false => None,
+        };
+        StartupProgress {
+            ready,
+            elapsed_secs: elapsed.as_secs(),
+            bytes_total,
+            bytes_loaded,
+            eta_secs,
+            columns,
+        }
+    }
+}
+
+///Records read by a warm-up plan, per column
+pub struct LoadedColumns(pub Vec<(String, ColumnRecords)>);
+
+impl LoadedColumns {
+    ///Drops the records beyond the row count of the shortest column. Columns copied one after the other
+    ///while rows got inserted can be ahead of each other.
+    pub fn trim_to_shortest(&mut self) {
+        let Some(rows) = self.0.iter().map(|(_, records)| records.cells.len()).min() else {
+            return;
+        };
+        for (_, records) in self.0.iter_mut() {
+            records.cells.truncate(rows);
+            records.tombstones.retain(|position| *position < rows);
+        }
+    }
+}
+
+impl std::fmt::Debug for LoadedColumns {
+    //Only the row counts, the records themselves would flood the logs
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_map()
+            .entries(self.0.iter().map(|(column, records)| (column, records.cells.len())))
+            .finish()
+    }
+}
+
+#[derive(Debug)]
+struct ColumnWarmup {
+    name: String,
+    backend: Arc<dyn StorageBackend>,
+    ///Segment files along with the range of their records, see `Column::segment_ranges`
+    files: Vec<(String, Range<u64>)>,
+    dropped_rows: usize,
+    mapped_reads: bool,
+}
+
+impl ColumnWarmup {
+    fn bytes(&self) -> u64 {
+        self.files.iter().map(|(_, range)| range.end - range.start).sum()
+    }
+}
+
+///Columns whose records still need to be read into memory after opening a table
+#[derive(Debug, Default)]
+pub struct WarmupPlan {
+    columns: Vec<ColumnWarmup>,
+}
+
+impl WarmupPlan {
+    ///Everything the column's segment files held when it got opened. Records inserted afterwards are already in memory.
+    pub fn add(&mut self, column: &mut Column) -> std::io::Result<()> {
+        self.columns.push(ColumnWarmup {
+            name: column.name().to_string(),
+            backend: column.backend().clone(),
+            files: column.segment_ranges()?,
+            dropped_rows: column.dropped_rows(),
+            mapped_reads: column.mapped_reads(),
+        });
+        Ok(())
+    }
+
+    ///Reads all columns, reporting progress to the tracker. Blocks until done.
+    #[instrument(skip(self, tracker))]
+    pub fn run(self, tracker: &StartupTracker) -> std::io::Result<LoadedColumns> {
+        tracker.start(&self);
+        let column_count = self.columns.len();
+        let mut loaded = vec![];
+        for (n, column) in self.columns.into_iter().enumerate() {
+            let records = Column::read_segments(
+                column.backend.as_ref(),
+                &column.files,
+                column.dropped_rows,
+                column.mapped_reads,
+                |rows, bytes| tracker.update(n, rows, bytes, false),
+            )?;
+            tracker.update(n, records.cells.len(), column.bytes(), true);
+            let progress = tracker.progress();
+            info!(
+                "Loaded column {} ({}/{}): {} rows, {} bytes. ETA {}s",
+                column.name,
+                n + 1,
+                column_count,
+                records.cells.len(),
+                column.bytes(),
+                progress.eta_secs.unwrap_or_default()
+            );
+            loaded.push((column.name, records));
+        }
+        Ok(LoadedColumns(loaded))
+    }
+}
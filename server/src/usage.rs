@@ -0,0 +1,152 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+
+///Usage of requests which don't carry an API key gets attributed to this key
+const ANONYMOUS_KEY: &str = "anonymous";
+
+///Load a single API key caused on this node
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct KeyUsage {
+    pub rows_inserted: u64,
+    ///Size of the inserted rows, encoded as JSON
+    pub bytes_ingested: u64,
+    pub queries_run: u64,
+    ///Rows passed to map functions
+    pub rows_scanned: u64,
+}
+
+///Usage counters per fingerprint of an API key, see `auth::key_fingerprint`. They get persisted to `usage.json` periodically, so they survive restarts.
+#[derive(Debug)]
+pub struct UsageTracker {
+    path: PathBuf,
+    keys: Mutex<BTreeMap<String, KeyUsage>>,
+    ///Set whenever a counter changes after the last persist
+    dirty: AtomicBool,
+}
+
+impl UsageTracker {
+    pub fn file_path(db_root_path: &Path) -> PathBuf {
+        db_root_path.join("usage.json")
+    }
+
+    pub fn new(db_root_path: &Path) -> Self {
+        Self {
+            path: UsageTracker::file_path(db_root_path),
+            keys: Mutex::new(BTreeMap::new()),
+            dirty: AtomicBool::new(false),
+        }
+    }
+
+    ///Continues counting from the persisted counters, if there are any
+    pub fn load(&self) -> io::Result<()> {
+        let bytes = match fs::read(&self.path) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        *self.keys.lock().unwrap() = serde_json::from_slice(&bytes)?;
+        Ok(())
+    }
+
+    pub fn record_insert(&self, api_key: &str, rows: u64, bytes: u64) {
+        self.update(api_key, |usage| {
+            usage.rows_inserted += rows;
+            usage.bytes_ingested += bytes;
+        });
+    }
+
+    pub fn record_query(&self, api_key: &str, rows_scanned: u64) {
+        self.update(api_key, |usage| {
+            usage.queries_run += 1;
+            usage.rows_scanned += rows_scanned;
+        });
+    }
+
+    fn update(&self, api_key: &str, f: impl FnOnce(&mut KeyUsage)) {
+        let mut keys = self.keys.lock().unwrap();
+        f(keys.entry(api_key.to_string()).or_default());
+        self.dirty.store(true, Ordering::SeqCst);
+    }
+
+    pub fn snapshot(&self) -> BTreeMap<String, KeyUsage> {
+        self.keys.lock().unwrap().clone()
+    }
+
+    ///Writes the counters to disk, unless nothing changed since the last time
+    pub fn persist(&self) -> io::Result<()> {
+        if !self.dirty.swap(false, Ordering::SeqCst) {
+            return Ok(());
+        }
+        let json = serde_json::to_vec(&self.snapshot())?;
+        //Replace the file as a whole, so a crash can't leave half written counters behind
+        let tmp_path = self.path.with_extension("json.tmp");
+        let result = fs::write(&tmp_path, json).and_then(|_| fs::rename(&tmp_path, &self.path));
+        if result.is_err() {
+            self.dirty.store(true, Ordering::SeqCst);
+        }
+        result
+    }
+}
+
+///Usage tracker bound to the API key of a single request
+#[derive(Debug, Clone)]
+pub struct RequestUsage {
+    tracker: Arc<UsageTracker>,
+    api_key: String,
+}
+
+impl RequestUsage {
+    pub fn new(tracker: Arc<UsageTracker>, api_key: Option<String>) -> Self {
+        Self {
+            tracker,
+            api_key: api_key.unwrap_or_else(|| ANONYMOUS_KEY.to_string()),
+        }
+    }
+
+    pub fn record_insert(&self, rows: u64, bytes: u64) {
+        self.tracker.record_insert(&self.api_key, rows, bytes);
+    }
+
+    pub fn record_query(&self, rows_scanned: u64) {
+        self.tracker.record_query(&self.api_key, rows_scanned);
+    }
+
+    pub fn api_key(&self) -> &str {
+        &self.api_key
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{KeyUsage, UsageTracker};
+
+    #[test]
+    fn counters_survive_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let usage = UsageTracker::new(dir.path());
+        usage.record_insert("team-a", 2, 100);
+        usage.record_query("team-a", 50);
+        usage.record_query("team-b", 0);
+        usage.persist().unwrap();
+
+        let restarted = UsageTracker::new(dir.path());
+        restarted.load().unwrap();
+        let keys = restarted.snapshot();
+        assert_eq!(
+            keys["team-a"],
+            KeyUsage {
+                rows_inserted: 2,
+                bytes_ingested: 100,
+                queries_run: 1,
+                rows_scanned: 50,
+            }
+        );
+        assert_eq!(keys["team-b"].queries_run, 1);
+    }
+}
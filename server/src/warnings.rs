@@ -0,0 +1,78 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WarningKind {
+    ///Rows were left out to stay within `max_response_bytes`
+    Truncated,
+    ///A value got converted to the column's type and lost precision doing so
+    Coerced,
+}
+
+impl WarningKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            WarningKind::Truncated => "truncated",
+            WarningKind::Coerced => "coerced",
+        }
+    }
+}
+
+///Problem with a request which succeeded nonetheless. Responses list them in `warnings`, so clients learn about
+///them before they turn into errors.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Warning {
+    pub kind: WarningKind,
+    pub message: String,
+}
+
+impl Warning {
+    pub fn new(kind: WarningKind, message: impl Into<String>) -> Self {
+        Self {
+            kind,
+            message: message.into(),
+        }
+    }
+}
+
+///Warnings per kind since startup, exported as `warenhaus_warnings_total`
+#[derive(Debug, Default)]
+pub struct WarningCounters {
+    counts: Mutex<BTreeMap<WarningKind, u64>>,
+}
+
+impl WarningCounters {
+    pub fn record(&self, warnings: &[Warning]) {
+        let mut counts = self.counts.lock().unwrap();
+        for warning in warnings {
+            *counts.entry(warning.kind).or_default() += 1;
+        }
+    }
+
+    pub fn counts(&self) -> Vec<(WarningKind, u64)> {
+        self.counts.lock().unwrap().iter().map(|(kind, count)| (*kind, *count)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Warning, WarningCounters, WarningKind};
+
+    #[test]
+    fn counts_warnings_per_kind() {
+        let counters = WarningCounters::default();
+        counters.record(&[
+            Warning::new(WarningKind::Coerced, "a"),
+            Warning::new(WarningKind::Truncated, "b"),
+            Warning::new(WarningKind::Coerced, "c"),
+        ]);
+        assert_eq!(counters.counts(), vec![(WarningKind::Truncated, 1), (WarningKind::Coerced, 2)]);
+        assert_eq!(
+            serde_json::to_value(Warning::new(WarningKind::Truncated, "b")).unwrap(),
+            serde_json::json!({ "kind": "truncated", "message": "b" })
+        );
+    }
+}
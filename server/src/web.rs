@@ -1,16 +1,49 @@
-use crate::{command::Command, storage::cell::Cell};
+use crate::cluster::membership::{GossipEntry, Membership};
+use crate::cluster::role::NodeRole;
+use crate::cluster::secret::{ClusterSecret, CLUSTER_SECRET_HEADER};
+use crate::cluster::seq_token::SeqToken;
+use crate::cluster::shard_router::{Route, ShardRouter};
+use crate::storage::{CommittedTransaction, ContainerError, FieldError, TableSchema};
+use crate::{command::{AckMode, Command}, storage::cell::Cell};
+use crate::metrics::{render_query_metrics, render_warning_metrics};
+use crate::query::abi::ASSEMBLYSCRIPT_SDK;
+use crate::query::admission::{QueryAdmission, QueueFull};
+use crate::query::map_result::{MapResult, PartialError};
+use crate::query::query_error::QueryError;
 use crate::query::wasm_error::WasmError;
+use crate::storage::derived_tables::DerivedTables;
+use crate::storage::cast::CastType;
+use crate::storage::column_stats::DEFAULT_PERCENTILES;
+use crate::auth::{key_fingerprint, AuthProvider, API_KEY_FINGERPRINT_HEADER, API_KEY_HEADER};
+use crate::storage::filter::{FilterError, QueryFilter};
+use crate::storage::page_cursor::PageCursor;
+use crate::storage::lineage::Lineage;
+use crate::storage::parquet::{ParquetWriter, PARQUET_CONTENT_TYPE, PARQUET_MAGIC};
+use crate::storage::{column_frame::ColumnFrame, row_stream};
+use crate::storage::warmup::StartupTracker;
+use crate::jobs::{JobError, JobKind, JobRegistry};
+use crate::schema_locks::{SchemaLockGuard, SchemaLocks, SchemaOperation};
+use crate::results::{ResultStatus, ResultStore};
+use crate::sessions::{IngestSessions, SessionError};
+use crate::usage::{RequestUsage, UsageTracker};
+use crate::query_audit::{AuditQuery, QueryAudit};
+use crate::alerts::{AlertError, AlertRule, AlertStore};
+use crate::lifecycle::Shutdown;
+use crate::warnings::{Warning, WarningCounters, WarningKind};
 use bytes::BufMut;
 use futures::TryStreamExt;
 use reqwest::StatusCode;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use tokio::sync::oneshot;
-use std::{convert::Infallible, collections::HashMap};
-use tracing::error;
+use percent_encoding::percent_decode_str;
+use std::{convert::Infallible, collections::HashMap, future::Future, net::SocketAddr, sync::{Arc, Mutex, RwLock}, time::{Duration, Instant}};
+use tracing::{debug, error};
 use warp::multipart::{FormData, Part};
 
 use tokio::sync::mpsc::Sender;
-use warp::{Filter, Rejection};
+use warp::path::FullPath;
+use warp::reply::Response;
+use warp::{http::HeaderMap, Filter, Rejection, Reply};
 
 fn with_tx(
     tx: Sender<Command>,
@@ -18,30 +51,552 @@ fn with_tx(
     warp::any().map(move || tx.clone())
 }
 
-#[derive(Debug, Deserialize)]
+///Set on inserts one node forwards to another, so the receiving node stores them without routing again
+const FORWARDED_HEADER: &str = "x-warenhaus-forwarded";
+
+///Carries the sequence token of a successful insert
+const SEQ_HEADER: &str = "x-warenhaus-seq";
+///Reads carrying a sequence token only get served once the write behind it is visible
+const MIN_SEQ_HEADER: &str = "x-warenhaus-min-seq";
+const MIN_SEQ_MAX_WAIT: Duration = Duration::from_secs(2);
+const MIN_SEQ_POLL_INTERVAL: Duration = Duration::from_millis(50);
+///Values returned by `/distinct` unless the request sets a limit
+const DEFAULT_DISTINCT_LIMIT: usize = 1000;
+///Rows `GET /rows/stream` encodes at a time
+const STREAM_CHUNK_ROWS: usize = 1000;
+///Query parameter selecting the derived table a map function reads from
+const TABLE_PARAM: &str = "table";
+///Identifies the client usage gets attributed to
+///Selects when an insert gets acknowledged: durable, applied (default) or received
+const ACK_HEADER: &str = "x-warenhaus-ack";
+///Lineage of inserted rows, stored if the schema enables `lineage`
+const SOURCE_HEADER: &str = "x-warenhaus-source";
+const KAFKA_TOPIC_HEADER: &str = "x-warenhaus-kafka-topic";
+const KAFKA_PARTITION_HEADER: &str = "x-warenhaus-kafka-partition";
+const KAFKA_OFFSET_HEADER: &str = "x-warenhaus-kafka-offset";
+///Query parameter which adds the lineage columns to query results
+const LINEAGE_PARAM: &str = "lineage";
+///Query parameter which runs a query in the background, see `ResultStore`
+const ASYNC_PARAM: &str = "async";
+///Rows per page of a background query's result unless the request sets a limit
+const DEFAULT_RESULT_PAGE_SIZE: usize = 1000;
+///Query parameter lowering the configured `max_response_bytes` for one request
+const MAX_RESPONSE_BYTES_PARAM: &str = "max_response_bytes";
+///Query parameter continuing a truncated response, see `ResponseBudget`
+const CURSOR_PARAM: &str = "cursor";
+///Query parameter naming the map function whose rows `GET /export/parquet` exports
+const MAP_FN_PARAM: &str = "map_fn";
+///Rows per row group of Parquet exports
+const PARQUET_ROW_GROUP_ROWS: usize = 10_000;
+///Rows the map function failed on, which a Parquet export leaves out
+const PARTIAL_ERRORS_HEADER: &str = "x-warenhaus-partial-errors";
+
+///Node wide trackers the handlers share
+#[derive(Debug)]
+pub struct NodeState {
+    pub role: NodeRole,
+    pub usage: Arc<UsageTracker>,
+    pub startup: Arc<StartupTracker>,
+    pub results: Arc<ResultStore>,
+    pub jobs: Arc<JobRegistry>,
+    pub schema_locks: Arc<SchemaLocks>,
+    pub sessions: Arc<IngestSessions>,
+    pub audit: Arc<QueryAudit>,
+    pub alerts: Arc<AlertStore>,
+    ///Warnings returned to clients, for `/metrics`
+    pub warnings: Arc<WarningCounters>,
+    ///Configured cap on the rows of a query response, see `ResponseBudget`
+    pub max_response_bytes: Option<usize>,
+    ///Checks the credentials of requests to the public endpoints, see `reject_unauthenticated`
+    pub auth: Option<Arc<dyn AuthProvider>>,
+    ///Authenticates requests other nodes send. Gossip is refused without it.
+    pub cluster_secret: Option<ClusterSecret>,
+    ///Caps the queries running at the same time
+    pub admission: Arc<QueryAdmission>,
+}
+
+fn with_router(
+    router: Arc<RwLock<ShardRouter>>,
+) -> impl Filter<Extract = (Arc<RwLock<ShardRouter>>,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || router.clone())
+}
+
+fn with_admission(
+    admission: Arc<QueryAdmission>,
+) -> impl Filter<Extract = (Arc<QueryAdmission>,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || admission.clone())
+}
+
+fn with_results(
+    results: Arc<ResultStore>,
+) -> impl Filter<Extract = (Arc<ResultStore>,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || results.clone())
+}
+
+fn with_sessions(
+    sessions: Arc<IngestSessions>,
+) -> impl Filter<Extract = (Arc<IngestSessions>,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || sessions.clone())
+}
+
+fn with_schema_locks(
+    schema_locks: Arc<SchemaLocks>,
+) -> impl Filter<Extract = (Arc<SchemaLocks>,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || schema_locks.clone())
+}
+
+fn with_audit(audit: Arc<QueryAudit>) -> impl Filter<Extract = (Arc<QueryAudit>,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || audit.clone())
+}
+
+fn with_alerts(alerts: Arc<AlertStore>) -> impl Filter<Extract = (Arc<AlertStore>,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || alerts.clone())
+}
+
+fn with_warnings(
+    warnings: Arc<WarningCounters>,
+) -> impl Filter<Extract = (Arc<WarningCounters>,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || warnings.clone())
+}
+
+///Who runs a query, for usage accounting, the audit log and the warnings it gets
+#[derive(Debug, Clone)]
+struct QueryCaller {
+    usage: RequestUsage,
+    audit: Arc<QueryAudit>,
+    warnings: Arc<WarningCounters>,
+    ///Authenticated name of the caller, see `with_caller`
+    name: Option<String>,
+}
+
+fn with_query_caller(
+    usage: Arc<UsageTracker>,
+    audit: Arc<QueryAudit>,
+    warnings: Arc<WarningCounters>,
+    auth: Option<Arc<dyn AuthProvider>>,
+) -> impl Filter<Extract = (QueryCaller,), Error = Rejection> + Clone {
+    with_usage(usage)
+        .and(with_caller(auth))
+        .map(move |usage, name| QueryCaller { usage, audit: audit.clone(), warnings: warnings.clone(), name })
+}
+
+fn with_jobs(
+    jobs: Arc<JobRegistry>,
+) -> impl Filter<Extract = (Arc<JobRegistry>,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || jobs.clone())
+}
+
+///Paths of the routes which change stored rows, which query nodes don't serve
+const WRITE_PATHS: &[&str] = &[
+    "/index",
+    "/transaction",
+    "/ingest/",
+    "/schema/columns/",
+    "/admin/backfill",
+    "/admin/compact",
+    "/admin/diagnostics/repair",
+];
+
+///Reads below `/index`, which query nodes serve nonetheless
+const KEY_LOOKUP_PATH: &str = "/index/by-key/";
+
+///On query nodes, answers requests which would change stored rows. Doesn't match anything on primaries.
+fn reject_writes(role: NodeRole) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    warp::path::full().and_then(move |path: FullPath| async move {
+        let writes = WRITE_PATHS.iter().any(|prefix| path.as_str().starts_with(prefix)) && !path.as_str().starts_with(KEY_LOOKUP_PATH);
+        if role != NodeRole::Query || !writes {
+            return Err(warp::reject::not_found());
+        }
+        let json = warp::reply::json(&"This is a query node, send writes to the primary".to_string());
+        Ok(warp::reply::with_status(json, StatusCode::MISDIRECTED_REQUEST).into_response())
+    })
+}
+
+///Whether the request came from another node of the cluster, i.e. carries the cluster secret
+fn with_node_request(cluster_secret: Option<ClusterSecret>) -> impl Filter<Extract = (bool,), Error = std::convert::Infallible> + Clone {
+    warp::header::headers_cloned().map(move |headers: HeaderMap| cluster_secret.as_ref().is_some_and(|secret| secret.verify(&headers)))
+}
+
+///Answers requests without valid credentials with `401`. Doesn't match anything if they are valid, if they come from
+///another node, or if no authentication is configured.
+fn reject_unauthenticated(
+    auth: Option<Arc<dyn AuthProvider>>,
+    cluster_secret: Option<ClusterSecret>,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    warp::path::full().and(warp::header::headers_cloned()).and(with_node_request(cluster_secret)).and_then(move |path: FullPath, headers: HeaderMap, node_request: bool| {
+        let auth = auth.clone().filter(|_| !node_request);
+        async move {
+            let Some(auth) = auth else {
+                return Err(warp::reject::not_found());
+            };
+            match auth.authenticate(&headers) {
+                Ok(caller) => {
+                    debug!("Authenticated {} for {}", caller, path.as_str());
+                    Err(warp::reject::not_found())
+                }
+                Err(err) => {
+                    let json = warp::reply::json(&err.to_string());
+                    Ok(warp::reply::with_status(json, StatusCode::UNAUTHORIZED).into_response())
+                }
+            }
+        }
+    })
+}
+
+///Name of the authenticated caller, `None` if no authentication is configured
+fn with_caller(auth: Option<Arc<dyn AuthProvider>>) -> impl Filter<Extract = (Option<String>,), Error = std::convert::Infallible> + Clone {
+    warp::header::headers_cloned().map(move |headers: HeaderMap| auth.as_ref().and_then(|auth| auth.authenticate(&headers).ok()))
+}
+
+///Whether an insert arrived from another node, along with the secret to authenticate forwarded inserts with
+#[derive(Debug)]
+struct Forwarding {
+    forwarded: bool,
+    cluster_secret: Option<ClusterSecret>,
+}
+
+///Only trusts the forwarded header on requests carrying the cluster secret. Clients sending it themselves
+///would otherwise store rows on a node which doesn't own them.
+fn with_forwarding(cluster_secret: Option<ClusterSecret>) -> impl Filter<Extract = (Forwarding,), Error = Rejection> + Clone {
+    warp::header::optional::<String>(FORWARDED_HEADER)
+        .and(with_node_request(cluster_secret.clone()))
+        .map(move |forwarded: Option<String>, node_request: bool| Forwarding {
+            forwarded: forwarded.is_some() && node_request,
+            cluster_secret: cluster_secret.clone(),
+        })
+}
+
+///Only matches requests asking to run the query in the background
+fn with_async_query() -> impl Filter<Extract = (HashMap<String, String>,), Error = Rejection> + Clone {
+    warp::query::<HashMap<String, String>>().and_then(|params: HashMap<String, String>| async move {
+        match params.get(ASYNC_PARAM).is_some_and(|value| value == "true") {
+            true => Ok(params),
+            false => Err(warp::reject()),
+        }
+    })
+}
+
+fn with_usage_tracker(
+    usage: Arc<UsageTracker>,
+) -> impl Filter<Extract = (Arc<UsageTracker>,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || usage.clone())
+}
+
+///The min seq header of a read, see `await_min_seq`
+#[derive(Debug)]
+struct MinSeq {
+    router: Arc<RwLock<ShardRouter>>,
+    header: Option<String>,
+    ///Raw query string of the read, which a redirect passes on
+    query: Option<String>,
+}
+
+fn with_min_seq(router: Arc<RwLock<ShardRouter>>) -> impl Filter<Extract = (MinSeq,), Error = Rejection> + Clone {
+    warp::header::optional::<String>(MIN_SEQ_HEADER)
+        .and(warp::query::raw().map(Some).or(warp::any().map(|| None)).unify())
+        .map(move |header, query| MinSeq { router: router.clone(), header, query })
+}
+
+///Caps how many bytes of rows a response serializes, so a single request can't produce gigabytes of JSON.
+///Rows beyond the budget are left out, and the response carries a cursor to continue with.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+struct ResponseBudget {
+    max_bytes: Option<usize>,
+    ///Rows earlier responses already returned
+    cursor: usize,
+}
+
+impl ResponseBudget {
+    ///Requests may lower the configured limit, but never raise it
+    fn from_query(configured: Option<usize>, params: &HashMap<String, String>) -> Result<Self, String> {
+        let parse = |name: &str| {
+            params
+                .get(name)
+                .map(|value| value.parse::<usize>().map_err(|_| format!("Invalid value for {}: {}. Expected an unsigned integer", name, value)))
+                .transpose()
+        };
+        let max_bytes = match (configured, parse(MAX_RESPONSE_BYTES_PARAM)?) {
+            (Some(configured), Some(requested)) => Some(configured.min(requested)),
+            (configured, requested) => configured.or(requested),
+        };
+        Ok(Self {
+            max_bytes,
+            cursor: parse(CURSOR_PARAM)?.unwrap_or_default(),
+        })
+    }
+
+    ///Rows from the cursor on, as many as fit into the budget. A row larger than the whole budget still gets
+    ///returned on its own, so clients always make progress. Also returns the cursor of the first row left out.
+    fn take<T: Serialize>(&self, rows: Vec<T>) -> (Vec<T>, Option<usize>) {
+        let mut used = 0;
+        let mut taken = vec![];
+        for (n, row) in rows.into_iter().enumerate().skip(self.cursor) {
+            //Plus one for the separating comma
+            let size = serde_json::to_vec(&row).map(|bytes| bytes.len() + 1).unwrap_or_default();
+            if self.max_bytes.is_some_and(|max_bytes| !taken.is_empty() && used + size > max_bytes) {
+                return (taken, Some(n));
+            }
+            used += size;
+            taken.push(row);
+        }
+        (taken, None)
+    }
+}
+
+fn with_response_budget(
+    max_response_bytes: Option<usize>,
+) -> impl Filter<Extract = (Result<ResponseBudget, String>,), Error = Rejection> + Clone {
+    warp::query::<HashMap<String, String>>().map(move |params| ResponseBudget::from_query(max_response_bytes, &params))
+}
+
+///Attributes the request's usage to the fingerprint of its API key
+fn with_usage(usage: Arc<UsageTracker>) -> impl Filter<Extract = (RequestUsage,), Error = Rejection> + Clone {
+    warp::header::optional::<String>(API_KEY_HEADER)
+        .map(move |api_key: Option<String>| RequestUsage::new(usage.clone(), api_key.as_deref().map(key_fingerprint)))
+}
+
+///Collects the lineage headers of an insert. Only the fingerprint of the API key gets stored. Inserts another
+///node forwarded carry the fingerprint it took.
+fn with_lineage(cluster_secret: Option<ClusterSecret>) -> impl Filter<Extract = (Lineage,), Error = Rejection> + Clone {
+    warp::header::optional::<String>(SOURCE_HEADER)
+        .and(warp::header::optional::<String>(KAFKA_TOPIC_HEADER))
+        .and(warp::header::optional::<i64>(KAFKA_PARTITION_HEADER))
+        .and(warp::header::optional::<i64>(KAFKA_OFFSET_HEADER))
+        .and(warp::header::optional::<String>(API_KEY_HEADER))
+        .and(warp::header::optional::<String>(API_KEY_FINGERPRINT_HEADER))
+        .and(with_node_request(cluster_secret))
+        .map(
+            |source, kafka_topic, kafka_partition, kafka_offset, api_key: Option<String>, fingerprint, node_request| Lineage {
+                source,
+                kafka_topic,
+                kafka_partition,
+                kafka_offset,
+                api_key: match node_request {
+                    true => fingerprint,
+                    false => api_key.as_deref().map(key_fingerprint),
+                },
+            },
+        )
+}
+
+fn parse_ack(ack: Option<String>) -> Result<AckMode, String> {
+    ack.as_deref().map(str::parse::<AckMode>).transpose().map(Option::unwrap_or_default)
+}
+
+///Reply to inserts sent with ack=received. They got queued, but may still get rejected.
+fn accepted() -> Response {
+    warp::reply::with_status(warp::reply::json(&"accepted"), StatusCode::ACCEPTED).into_response()
+}
+
+///Bytes a row gets accounted with
+fn ingested_bytes(params: &IndexParams) -> u64 {
+    serde_json::to_vec(params).map(|json| json.len() as u64).unwrap_or_default()
+}
+
+fn with_startup(
+    startup: Arc<StartupTracker>,
+) -> impl Filter<Extract = (Arc<StartupTracker>,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || startup.clone())
+}
+
+fn with_membership(
+    membership: Arc<Mutex<Membership>>,
+) -> impl Filter<Extract = (Arc<Mutex<Membership>>,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || membership.clone())
+}
+
+#[derive(Debug, Deserialize, Serialize)]
 pub struct IndexParams {
     pub fields: Vec<String>,
     pub values: Vec<serde_json::Value>,
 }
 
 #[derive(Debug, Deserialize)]
-pub struct MapFnParams {
-    ///Map Fn Name
+#[serde(rename_all = "snake_case")]
+pub enum Operation {
+    Insert(IndexParams),
+}
+
+///Operations which get committed together, or not at all
+#[derive(Debug, Deserialize)]
+pub struct TransactionParams {
+    pub operations: Vec<Operation>,
+}
+
+impl TransactionParams {
+    pub fn into_rows(self) -> Vec<IndexParams> {
+        self.operations
+            .into_iter()
+            .map(|operation| match operation {
+                Operation::Insert(params) => params,
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RenameColumnParams {
+    ///New name of the column
     pub name: String,
-    ///AssemblyScript Source Code
-    pub source_code: String,
+}
+
+#[derive(Debug, Serialize)]
+struct TransactionReport {
+    ids: Vec<i64>,
+    ///Rows whose key was already stored
+    conflicts: usize,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    warnings: Vec<Warning>,
+}
+
+///Response body of an insert which succeeded with warnings. Inserts without any answer `"ok"`.
+#[derive(Debug, Serialize)]
+struct InsertedWithWarnings {
+    status: &'static str,
+    warnings: Vec<Warning>,
+}
+
+#[derive(Debug, Serialize)]
+struct MaterializeReport {
+    table: String,
+    rows: usize,
+    partial_errors: Vec<PartialError>,
+}
+
+#[derive(Debug, Serialize)]
+struct SnapshotReport {
+    ///Directory holding the copied table files
+    path: String,
+}
+
+#[derive(Debug, Serialize)]
+struct QueryResponse {
+    rows: Vec<HashMap<String, Cell>>,
+    ///Rows the map function failed on. They are missing from rows.
+    partial_errors: Vec<PartialError>,
+    ///True if rows were left out to stay within `max_response_bytes`
+    truncated: bool,
+    ///Passed as `cursor`, continues with the first row left out
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cursor: Option<usize>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    warnings: Vec<Warning>,
+}
+
+///Response body of a rejected insert
+#[derive(Debug, Serialize)]
+struct InsertErrorBody {
+    error: String,
+    kind: &'static str,
+    fields: Vec<FieldError>,
+    ///Row of a transaction which caused it to abort
+    #[serde(skip_serializing_if = "Option::is_none")]
+    row: Option<usize>,
+}
+
+impl From<&ContainerError> for InsertErrorBody {
+    fn from(err: &ContainerError) -> Self {
+        Self {
+            error: err.to_string(),
+            kind: err.kind(),
+            fields: err.field_errors(),
+            row: err.failed_row(),
+        }
+    }
+}
+
+async fn forward_index(
+    node: &str,
+    ack: AckMode,
+    forwarding: &Forwarding,
+    lineage: &Lineage,
+    index_params: &IndexParams,
+) -> Result<(StatusCode, Option<String>, serde_json::Value), reqwest::Error> {
+    let mut request = reqwest::Client::new()
+        .post(format!("{}/index", node))
+        .header(FORWARDED_HEADER, "1")
+        .header(ACK_HEADER, ack.to_string())
+        .header("Content-Type", "application/json");
+    let lineage_headers = [
+        (SOURCE_HEADER, lineage.source.clone()),
+        (KAFKA_TOPIC_HEADER, lineage.kafka_topic.clone()),
+        (KAFKA_PARTITION_HEADER, lineage.kafka_partition.map(|partition| partition.to_string())),
+        (KAFKA_OFFSET_HEADER, lineage.kafka_offset.map(|offset| offset.to_string())),
+        (API_KEY_FINGERPRINT_HEADER, lineage.api_key.clone()),
+    ];
+    for (header, value) in lineage_headers {
+        if let Some(value) = value {
+            request = request.header(header, value);
+        }
+    }
+    //Authenticates the request, so the client's credentials don't have to travel along
+    if let Some(secret) = &forwarding.cluster_secret {
+        request = request.header(CLUSTER_SECRET_HEADER, secret.value());
+    }
+    let response = request
+        .body(serde_json::to_string(index_params).unwrap())
+        .send()
+        .await?;
+    let status = response.status();
+    let seq_token = response
+        .headers()
+        .get(SEQ_HEADER)
+        .and_then(|token| token.to_str().ok())
+        .map(|token| token.to_string());
+    let body = response.text().await?;
+    let json = serde_json::from_str(&body).unwrap_or(serde_json::Value::String(body));
+    Ok((status, seq_token, json))
 }
 
 #[tracing::instrument]
 async fn index_handler(
     tx: Sender<Command>,
+    router: Arc<RwLock<ShardRouter>>,
+    usage: RequestUsage,
+    forwarding: Forwarding,
+    ack: Option<String>,
+    lineage: Lineage,
     index_params: IndexParams,
-) -> Result<impl warp::Reply, Infallible> {
+) -> Result<Response, Infallible> {
+    let ack = match parse_ack(ack) {
+        Ok(ack) => ack,
+        Err(message) => {
+            return Ok(warp::reply::with_status(warp::reply::json(&message), StatusCode::BAD_REQUEST).into_response())
+        }
+    };
+    //Usage is accounted on the node the client sent the row to
+    let bytes = ingested_bytes(&index_params);
+    if !forwarding.forwarded {
+        let route = router.read().unwrap().route(&index_params);
+        if let Route::Remote(node) = route {
+            let forward_result = forward_index(&node, ack, &forwarding, &lineage, &index_params).await;
+            if matches!(&forward_result, Ok((status, _, _)) if status.is_success()) {
+                usage.record_insert(1, bytes);
+            }
+            return match forward_result {
+                Ok((status, Some(seq_token), body)) => {
+                    let reply = warp::reply::with_status(warp::reply::json(&body), status);
+                    Ok(warp::reply::with_header(reply, SEQ_HEADER, seq_token).into_response())
+                }
+                Ok((status, None, body)) => {
+                    Ok(warp::reply::with_status(warp::reply::json(&body), status).into_response())
+                }
+                Err(err) => {
+                    error!("Failed to forward insert to {}: {}", node, err);
+                    let json = warp::reply::json(&"Shard Unavailable".to_string());
+                    Ok(warp::reply::with_status(json, StatusCode::BAD_GATEWAY).into_response())
+                }
+            };
+        }
+    }
+
     let (resp_tx, resp_rx) = oneshot::channel();
 
     if let Err(err) = tx
         .send(Command::Index {
             params: index_params,
+            ack,
+            lineage,
             responder: resp_tx,
         })
         .await
@@ -51,21 +606,46 @@ async fn index_handler(
         return Ok(warp::reply::with_status(
             json,
             StatusCode::INTERNAL_SERVER_ERROR,
-        ));
+        ).into_response());
+    }
+
+    if ack == AckMode::Received {
+        if !forwarding.forwarded {
+            usage.record_insert(1, bytes);
+        }
+        return Ok(accepted());
     }
 
     match resp_rx.await {
         Ok(result) => match result {
-            Ok(()) => {
-                let json = warp::reply::json(&"ok");
-                Ok(warp::reply::with_status(json, StatusCode::OK))
+            Ok((seq, warnings)) => {
+                if !forwarding.forwarded {
+                    usage.record_insert(1, bytes);
+                }
+                let json = match warnings.is_empty() {
+                    true => warp::reply::json(&"ok"),
+                    false => warp::reply::json(&InsertedWithWarnings { status: "ok", warnings }),
+                };
+                let seq_token = SeqToken {
+                    seq,
+                    node: router.read().unwrap().local_node().to_string(),
+                };
+                let reply = warp::reply::with_status(json, StatusCode::OK);
+                Ok(warp::reply::with_header(reply, SEQ_HEADER, seq_token.to_string()).into_response())
             }
+            //Adding an inferred column has to wait until all existing rows are loaded
+            Err(ContainerError::WarmingUp) => Ok(warming_up()),
+            Err(err @ (ContainerError::DiskFull(_) | ContainerError::SlowDisk(_))) => Ok(disk_pressure(&err)),
             Err(err) => {
-                let json = warp::reply::json(&format!("{}", err));
-                Ok(warp::reply::with_status(
-                    json,
-                    StatusCode::UNPROCESSABLE_ENTITY,
-                ))
+                let status = if err.is_conflict() {
+                    StatusCode::CONFLICT
+                } else if err.is_client_error() {
+                    StatusCode::UNPROCESSABLE_ENTITY
+                } else {
+                    StatusCode::INTERNAL_SERVER_ERROR
+                };
+                let json = warp::reply::json(&InsertErrorBody::from(&err));
+                Ok(warp::reply::with_status(json, status).into_response())
             }
         },
         Err(err) => {
@@ -77,8 +657,335 @@ async fn index_handler(
             return Ok(warp::reply::with_status(
                 json,
                 StatusCode::INTERNAL_SERVER_ERROR,
-            ));
+            ).into_response());
+        }
+    }
+}
+
+async fn committed_seq(tx: &Sender<Command>) -> Option<i64> {
+    let (resp_tx, resp_rx) = oneshot::channel();
+    tx.send(Command::CommittedSeq { responder: resp_tx }).await.ok()?;
+    resp_rx.await.ok()
+}
+
+///Holds a read back until this node has committed the write the client saw,
+///or sends it to the node the write went to.
+///Returns None once the read can be served here.
+async fn await_min_seq(fn_name: &str, tx: &Sender<Command>, min_seq: MinSeq) -> Option<Response> {
+    let MinSeq { router, header, query } = min_seq;
+    let min_seq = header?;
+    let Some(token) = SeqToken::parse(&min_seq) else {
+        let json = warp::reply::json(&format!("Invalid {} header: {}", MIN_SEQ_HEADER, min_seq));
+        return Some(warp::reply::with_status(json, StatusCode::BAD_REQUEST).into_response());
+    };
+
+    if token.node != router.read().unwrap().local_node() {
+        debug!("Redirecting read to {} which committed seq {}", token.node, token.seq);
+        let location = match query {
+            Some(query) => format!("{}/query/{}?{}", token.node, fn_name, query),
+            None => format!("{}/query/{}", token.node, fn_name),
+        };
+        let reply = warp::reply::with_status(warp::reply::reply(), StatusCode::TEMPORARY_REDIRECT);
+        return Some(warp::reply::with_header(reply, "Location", location).into_response());
+    }
+
+    let started = Instant::now();
+    loop {
+        match committed_seq(tx).await {
+            Some(committed) if committed >= token.seq => return None,
+            Some(_) if started.elapsed() < MIN_SEQ_MAX_WAIT => {
+                tokio::time::sleep(MIN_SEQ_POLL_INTERVAL).await;
+            }
+            _ => {
+                let json = warp::reply::json(&format!("Sequence {} not committed yet", token.seq));
+                let reply = warp::reply::with_status(json, StatusCode::SERVICE_UNAVAILABLE);
+                return Some(warp::reply::with_header(reply, "Retry-After", "1").into_response());
+            }
+        }
+    }
+}
+
+///Reports whether an insert would be accepted, without storing it
+#[tracing::instrument]
+async fn validate_handler(tx: Sender<Command>, index_params: IndexParams) -> Result<Response, Infallible> {
+    let (resp_tx, resp_rx) = oneshot::channel();
+
+    if let Err(err) = tx.send(Command::Validate { params: index_params, responder: resp_tx }).await {
+        error!("Error while trying to validate data: {}", err);
+        return Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response());
+    }
+
+    match resp_rx.await {
+        Ok(Ok(())) => Ok(warp::reply::json(&"ok").into_response()),
+        Ok(Err(err)) => {
+            let status = if err.is_conflict() {
+                StatusCode::CONFLICT
+            } else if err.is_client_error() {
+                StatusCode::UNPROCESSABLE_ENTITY
+            } else {
+                StatusCode::INTERNAL_SERVER_ERROR
+            };
+            let json = warp::reply::json(&InsertErrorBody::from(&err));
+            Ok(warp::reply::with_status(json, status).into_response())
+        }
+        Err(err) => {
+            error!("Failed to receive validation result: {}", err);
+            Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response())
+        }
+    }
+}
+
+///Replaces the values of a single row of the main table stored on this node
+#[tracing::instrument]
+async fn update_handler(
+    id: i64,
+    tx: Sender<Command>,
+    router: Arc<RwLock<ShardRouter>>,
+    index_params: IndexParams,
+) -> Result<Response, Infallible> {
+    let (resp_tx, resp_rx) = oneshot::channel();
+
+    let command = Command::Update {
+        id,
+        params: index_params,
+        responder: resp_tx,
+    };
+    if let Err(err) = tx.send(command).await {
+        error!("Error while trying to update row {}: {}", id, err);
+        return Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response());
+    }
+
+    match resp_rx.await {
+        Ok(Ok(seq)) => Ok(written(seq, &router)),
+        Ok(Err(ContainerError::WarmingUp)) => Ok(warming_up()),
+        Ok(Err(err @ (ContainerError::DiskFull(_) | ContainerError::SlowDisk(_)))) => Ok(disk_pressure(&err)),
+        Ok(Err(err)) => {
+            let status = match err {
+                ContainerError::UnknownRow(_) => StatusCode::NOT_FOUND,
+                ContainerError::ShardKeyChanged(_) | ContainerError::DuplicateKey(_) => StatusCode::CONFLICT,
+                _ if err.is_conflict() => StatusCode::CONFLICT,
+                _ if err.is_client_error() => StatusCode::UNPROCESSABLE_ENTITY,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            let json = warp::reply::json(&InsertErrorBody::from(&err));
+            Ok(warp::reply::with_status(json, status).into_response())
+        }
+        Err(err) => {
+            error!("Failed to receive answer after updating row {}: {}", id, err);
+            Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response())
+        }
+    }
+}
+
+///Answers an update or delete with the sequence token to read it back with
+fn written(seq: i64, router: &RwLock<ShardRouter>) -> Response {
+    let seq_token = SeqToken {
+        seq,
+        node: router.read().unwrap().local_node().to_string(),
+    };
+    warp::reply::with_header(StatusCode::NO_CONTENT, SEQ_HEADER, seq_token.to_string()).into_response()
+}
+
+///Deletes a single row of the main table stored on this node
+#[tracing::instrument]
+async fn delete_handler(id: i64, tx: Sender<Command>, router: Arc<RwLock<ShardRouter>>) -> Result<Response, Infallible> {
+    let (resp_tx, resp_rx) = oneshot::channel();
+
+    if let Err(err) = tx.send(Command::Delete { id, responder: resp_tx }).await {
+        error!("Error while trying to delete row {}: {}", id, err);
+        return Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response());
+    }
+
+    match resp_rx.await {
+        Ok(Ok(seq)) => Ok(written(seq, &router)),
+        Ok(Err(ContainerError::WarmingUp)) => Ok(warming_up()),
+        Ok(Err(err)) => {
+            let status = match err {
+                ContainerError::UnknownRow(_) => StatusCode::NOT_FOUND,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            let json = warp::reply::json(&InsertErrorBody::from(&err));
+            Ok(warp::reply::with_status(json, status).into_response())
+        }
+        Err(err) => {
+            error!("Failed to receive answer after deleting row {}: {}", id, err);
+            Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response())
+        }
+    }
+}
+
+#[tracing::instrument]
+async fn transaction_handler(
+    tx: Sender<Command>,
+    router: Arc<RwLock<ShardRouter>>,
+    usage: RequestUsage,
+    ack: Option<String>,
+    lineage: Lineage,
+    transaction: TransactionParams,
+) -> Result<Response, Infallible> {
+    let ack = match parse_ack(ack) {
+        Ok(ack) => ack,
+        Err(message) => {
+            return Ok(warp::reply::with_status(warp::reply::json(&message), StatusCode::BAD_REQUEST).into_response())
+        }
+    };
+    let rows = transaction.into_rows();
+    if let Some(conflict) = spans_shards(&router, &rows) {
+        return Ok(conflict);
+    }
+    Ok(run_transaction(tx, router, usage, ack, lineage, rows).await)
+}
+
+///Conflict reply if some of the rows are stored on another node
+fn spans_shards(router: &RwLock<ShardRouter>, rows: &[IndexParams]) -> Option<Response> {
+    let router = router.read().unwrap();
+    if rows.iter().all(|params| router.route(params) == Route::Local) {
+        return None;
+    }
+    let json = warp::reply::json(&"Transactions may only contain rows stored on this node".to_string());
+    Some(warp::reply::with_status(json, StatusCode::CONFLICT).into_response())
+}
+
+///Commits the rows as a single transaction
+async fn run_transaction(
+    tx: Sender<Command>,
+    router: Arc<RwLock<ShardRouter>>,
+    usage: RequestUsage,
+    ack: AckMode,
+    lineage: Lineage,
+    rows: Vec<IndexParams>,
+) -> Response {
+    let row_count = rows.len() as u64;
+    let bytes = rows.iter().map(ingested_bytes).sum::<u64>();
+    let (resp_tx, resp_rx) = oneshot::channel();
+
+    if let Err(err) = tx.send(Command::Transaction { rows, ack, lineage, responder: resp_tx }).await {
+        error!("Error while trying to run transaction: {}", err);
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+
+    if ack == AckMode::Received {
+        usage.record_insert(row_count, bytes);
+        return accepted();
+    }
+
+    match resp_rx.await {
+        Ok(Ok(CommittedTransaction { ids, seq, conflicts, warnings })) => {
+            usage.record_insert(ids.len() as u64, bytes);
+            let seq_token = SeqToken {
+                seq,
+                node: router.read().unwrap().local_node().to_string(),
+            };
+            let reply = warp::reply::json(&TransactionReport { ids, conflicts, warnings });
+            warp::reply::with_header(reply, SEQ_HEADER, seq_token.to_string()).into_response()
+        }
+        Ok(Err(ContainerError::WarmingUp)) => warming_up(),
+        Ok(Err(err @ (ContainerError::DiskFull(_) | ContainerError::SlowDisk(_)))) => disk_pressure(&err),
+        Ok(Err(err)) => {
+            let status = if err.is_conflict() {
+                StatusCode::CONFLICT
+            } else if err.is_client_error() {
+                StatusCode::UNPROCESSABLE_ENTITY
+            } else {
+                StatusCode::INTERNAL_SERVER_ERROR
+            };
+            let json = warp::reply::json(&InsertErrorBody::from(&err));
+            warp::reply::with_status(json, status).into_response()
+        }
+        Err(err) => {
+            error!("Failed to receive answer from storage layer after transaction: {}", err);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+fn session_error(err: SessionError) -> Response {
+    let status = match err {
+        SessionError::UnknownSession(_) => StatusCode::NOT_FOUND,
+        SessionError::Committing(_) => StatusCode::CONFLICT,
+        SessionError::IoError { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    warp::reply::with_status(warp::reply::json(&err.to_string()), status).into_response()
+}
+
+async fn start_session(sessions: Arc<IngestSessions>) -> Result<Response, Infallible> {
+    match sessions.start() {
+        Ok(info) => {
+            let location = format!("/ingest/sessions/{}", info.id);
+            let reply = warp::reply::with_status(warp::reply::json(&info), StatusCode::CREATED);
+            Ok(warp::reply::with_header(reply, "location", location).into_response())
+        }
+        Err(err) => {
+            error!("Failed to start ingest session: {}", err);
+            Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response())
+        }
+    }
+}
+
+async fn session_info(id: String, sessions: Arc<IngestSessions>) -> Result<Response, Infallible> {
+    match sessions.info(&id) {
+        Some(info) => Ok(warp::reply::json(&info).into_response()),
+        None => Ok(session_error(SessionError::UnknownSession(id))),
+    }
+}
+
+///Stages a batch. Rows get validated once the session is committed.
+#[tracing::instrument]
+async fn append_session_batch(
+    id: String,
+    router: Arc<RwLock<ShardRouter>>,
+    sessions: Arc<IngestSessions>,
+    batch: TransactionParams,
+) -> Result<Response, Infallible> {
+    let rows = batch.into_rows();
+    if let Some(conflict) = spans_shards(&router, &rows) {
+        return Ok(conflict);
+    }
+    match sessions.append(&id, &rows) {
+        Ok(info) => Ok(warp::reply::json(&info).into_response()),
+        Err(err) => Ok(session_error(err)),
+    }
+}
+
+///Commits all staged batches as a single transaction. A failed commit keeps the session, so it can be retried or aborted.
+#[tracing::instrument]
+async fn commit_session(
+    id: String,
+    tx: Sender<Command>,
+    router: Arc<RwLock<ShardRouter>>,
+    usage: RequestUsage,
+    sessions: Arc<IngestSessions>,
+    ack: Option<String>,
+    lineage: Lineage,
+) -> Result<Response, Infallible> {
+    let ack = match parse_ack(ack) {
+        Ok(AckMode::Received) => {
+            let message = "Sessions can't be committed with ack=received".to_string();
+            return Ok(warp::reply::with_status(warp::reply::json(&message), StatusCode::BAD_REQUEST).into_response());
+        }
+        Ok(ack) => ack,
+        Err(message) => {
+            return Ok(warp::reply::with_status(warp::reply::json(&message), StatusCode::BAD_REQUEST).into_response())
         }
+    };
+    let rows = match sessions.begin_commit(&id) {
+        Ok(rows) => rows,
+        Err(err) => return Ok(session_error(err)),
+    };
+    let reply = run_transaction(tx, router, usage, ack, lineage, rows).await;
+    sessions.finish_commit(&id, reply.status().is_success());
+    Ok(reply)
+}
+
+async fn abort_session(id: String, sessions: Arc<IngestSessions>) -> Result<Response, Infallible> {
+    if let Some(info) = sessions.info(&id) {
+        if info.committing {
+            return Ok(session_error(SessionError::Committing(id)));
+        }
+    }
+    match sessions.discard(&id) {
+        true => Ok(StatusCode::NO_CONTENT.into_response()),
+        false => Ok(session_error(SessionError::UnknownSession(id))),
     }
 }
 
@@ -150,8 +1057,8 @@ async fn add_map_function(
                 );
 
                 match err {
-                    WasmError::InvalidCode => {
-                        let json = warp::reply::json(&"Invalid Code".to_string());
+                    WasmError::AbiMismatch(_) | WasmError::ModuleAbiMismatch(_) | WasmError::MissingColumns(_) => {
+                        let json = warp::reply::json(&err.to_string());
                         return Ok(warp::reply::with_status(
                             json,
                             StatusCode::UNPROCESSABLE_ENTITY,
@@ -192,85 +1099,2157 @@ async fn add_map_function(
 #[tracing::instrument]
 async fn execute_map_fn(
     fn_name: String,
+    query_params: HashMap<String, String>,
     tx: Sender<Command>,
-) -> Result<impl warp::Reply, Infallible> {
-    let (resp_tx, resp_rx) = oneshot::channel();
+    min_seq: MinSeq,
+    admission: Arc<QueryAdmission>,
+    caller: QueryCaller,
+    budget: Result<ResponseBudget, String>,
+) -> Result<Response, Infallible> {
+    if let Some(response) = await_min_seq(&fn_name, &tx, min_seq).await {
+        return Ok(response);
+    }
+    let budget = match budget {
+        Ok(budget) => budget,
+        Err(err) => return Ok(warp::reply::with_status(warp::reply::json(&err), StatusCode::BAD_REQUEST).into_response()),
+    };
 
-    if let Err(err) = tx
-        .send(Command::InvokeMap {
-            fn_name: fn_name.to_string(),
-            responder: resp_tx,
-        })
-        .await
-    {
-        error!(
-            "Error while trying to execute map function {}: {}",
-            fn_name, err
-        );
-        let json = warp::reply::json(&"Internal Server Error".to_string());
-        return Ok(warp::reply::with_status(
-            json,
-            StatusCode::INTERNAL_SERVER_ERROR,
-        ));
+    let table = query_params.get(TABLE_PARAM).cloned();
+    if let Some(response) = table.as_deref().and_then(reject_invalid_table_name) {
+        return Ok(response);
     }
 
-    match resp_rx.await {
-        Ok(execution_result) => match execution_result {
-            Ok(rows) => {
-                // TODO: Convert column frames into something that's easy to print
-                // and readable
-                let rows : Vec<HashMap<String, Cell>> = rows.iter().map(|r| r.to_view_object()).collect();
-                let json = warp::reply::json(&rows);
-                return Ok(warp::reply::with_status(json, StatusCode::OK));
-            }
-            Err(wasm_err) => {
-                error!("Failed to execute query: {}", wasm_err);
-                let json = warp::reply::json(&"Internal Server Error".to_string());
-                return Ok(warp::reply::with_status(
-                    json,
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                ));
+    let filter = match QueryFilter::from_query(&query_params) {
+        Ok(filter) => filter,
+        Err(err) => {
+            let json = warp::reply::json(&err.to_string());
+            return Ok(warp::reply::with_status(json, StatusCode::BAD_REQUEST).into_response());
+        }
+    };
+
+    let _permit = match admission.admit().await {
+        Ok(permit) => permit,
+        Err(queue_full) => return Ok(reject_query(&fn_name, queue_full)),
+    };
+
+    match invoke_audited(&fn_name, table, filter, &tx, &caller, &query_params).await {
+        Ok(result) => {
+            caller.usage.record_query(result.scanned as u64);
+            let (rows, cursor) = budget.take(view_rows(&result, &query_params));
+            let warnings = truncation_warnings(cursor, "cursor");
+            caller.warnings.record(&warnings);
+            let json = warp::reply::json(&QueryResponse {
+                rows,
+                partial_errors: result.partial_errors,
+                truncated: cursor.is_some(),
+                cursor,
+                warnings,
+            });
+            Ok(warp::reply::with_status(json, StatusCode::OK).into_response())
+        }
+        Err(response) => Ok(response),
+    }
+}
+
+///Warns that rows from `left_out` on are missing from the response, if any are
+fn truncation_warnings(left_out: Option<usize>, continue_param: &str) -> Vec<Warning> {
+    left_out
+        .map(|n| {
+            let message = format!("Rows were left out to stay within max_response_bytes, continue with {}={}", continue_param, n);
+            Warning::new(WarningKind::Truncated, message)
+        })
+        .into_iter()
+        .collect()
+}
+
+///Rows the way query responses return them. Lineage columns only stay if the query asked for them.
+fn view_rows(result: &MapResult, query_params: &HashMap<String, String>) -> Vec<HashMap<String, Cell>> {
+    // TODO: Convert column frames into something that's easy to print
+    // and readable
+    let with_lineage = query_params.get(LINEAGE_PARAM).is_some_and(|value| value == "true");
+    view_frames(&result.rows, with_lineage)
+}
+
+fn view_frames(rows: &[ColumnFrame], with_lineage: bool) -> Vec<HashMap<String, Cell>> {
+    rows.iter()
+        .map(|r| {
+            let mut row = r.to_view_object();
+            if !with_lineage {
+                row.retain(|column_name, _| !Lineage::is_lineage_column(column_name));
+            }
+            row
+        })
+        .collect()
+}
+
+///Starts the query in the background and answers right away with the id to fetch its result from
+#[tracing::instrument]
+async fn start_async_query(
+    fn_name: String,
+    query_params: HashMap<String, String>,
+    tx: Sender<Command>,
+    admission: Arc<QueryAdmission>,
+    caller: QueryCaller,
+    results: Arc<ResultStore>,
+    jobs: Arc<JobRegistry>,
+) -> Result<Response, Infallible> {
+    let table = query_params.get(TABLE_PARAM).cloned();
+    if let Some(response) = table.as_deref().and_then(reject_invalid_table_name) {
+        return Ok(response);
+    }
+
+    let filter = match QueryFilter::from_query(&query_params) {
+        Ok(filter) => filter,
+        Err(err) => {
+            let json = warp::reply::json(&err.to_string());
+            return Ok(warp::reply::with_status(json, StatusCode::BAD_REQUEST).into_response());
+        }
+    };
+
+    let id = jobs.start(JobKind::Query, format!("query {}", fn_name));
+    results.start(&id, &fn_name, caller.name.clone());
+    let info = results.info(&id, caller.name.as_deref());
+    let job_id = id.clone();
+    jobs.run(&id, async move {
+        let id = job_id;
+        let _permit = match admission.admit().await {
+            Ok(permit) => permit,
+            Err(queue_full) => {
+                error!("Rejecting query {}: {} running, {} queued", fn_name, queue_full.running, queue_full.queued);
+                results.fail(&id, serde_json::to_value(&queue_full).unwrap_or_default());
+                return Err("Too many concurrent queries".to_string());
+            }
+        };
+
+        match invoke_audited(&fn_name, table, filter, &tx, &caller, &query_params).await {
+            Ok(result) => {
+                caller.usage.record_query(result.scanned as u64);
+                let rows = view_rows(&result, &query_params);
+                let partial_errors = serde_json::to_value(&result.partial_errors).unwrap_or_default();
+                if let Err(err) = results.finish(&id, &rows, partial_errors) {
+                    error!("Failed to store result {} of query {}: {}", id, fn_name, err);
+                    results.fail(&id, serde_json::Value::String("Failed to store result".to_string()));
+                    return Err(err.to_string());
+                }
+                Ok(())
+            }
+            Err(response) => {
+                let status = response.status();
+                let body = warp::hyper::body::to_bytes(response.into_body()).await.unwrap_or_default();
+                let error = serde_json::from_slice(&body).unwrap_or_else(|_| serde_json::Value::String(status.to_string()));
+                results.fail(&id, error);
+                Err(status.to_string())
+            }
+        }
+    });
+
+    let reply = warp::reply::with_status(warp::reply::json(&info), StatusCode::ACCEPTED);
+    Ok(warp::reply::with_header(reply, "location", format!("/results/{}", id)).into_response())
+}
+
+#[derive(Debug, Serialize)]
+struct ResultPage {
+    #[serde(flatten)]
+    info: crate::results::ResultInfo,
+    offset: usize,
+    rows: Vec<serde_json::Value>,
+    ///True if rows of the page were left out to stay within `max_response_bytes`
+    truncated: bool,
+    ///Offset of the first row left out
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_offset: Option<usize>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    warnings: Vec<Warning>,
+}
+
+///Status of a background query, and a page of its rows once it is done
+#[tracing::instrument]
+async fn query_result(
+    id: String,
+    query_params: HashMap<String, String>,
+    caller: Option<String>,
+    results: Arc<ResultStore>,
+    budget: Result<ResponseBudget, String>,
+    warnings: Arc<WarningCounters>,
+) -> Result<Response, Infallible> {
+    let budget = match budget {
+        Ok(budget) => ResponseBudget { cursor: 0, ..budget },
+        Err(err) => return Ok(warp::reply::with_status(warp::reply::json(&err), StatusCode::BAD_REQUEST).into_response()),
+    };
+    let Some(info) = results.info(&id, caller.as_deref()) else {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    };
+    match info.status {
+        ResultStatus::Running => {
+            return Ok(warp::reply::with_status(warp::reply::json(&info), StatusCode::ACCEPTED).into_response())
+        }
+        ResultStatus::Failed => return Ok(warp::reply::json(&info).into_response()),
+        ResultStatus::Done => {}
+    }
+
+    let offset = query_params.get("offset").and_then(|value| value.parse().ok()).unwrap_or(0);
+    let limit = query_params
+        .get("limit")
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(DEFAULT_RESULT_PAGE_SIZE);
+    match results.page(&id, offset, limit) {
+        Ok(rows) => {
+            let (rows, left_out) = budget.take(rows);
+            let next_offset = left_out.map(|n| offset + n);
+            let page_warnings = truncation_warnings(next_offset, "offset");
+            warnings.record(&page_warnings);
+            let page = ResultPage { info, offset, rows, truncated: next_offset.is_some(), next_offset, warnings: page_warnings };
+            Ok(warp::reply::json(&page).into_response())
+        }
+        Err(err) => {
+            error!("Failed to read result {}: {}", id, err);
+            Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response())
+        }
+    }
+}
+
+///All rows of a finished background query as newline delimited JSON
+#[tracing::instrument]
+async fn query_result_file(id: String, caller: Option<String>, results: Arc<ResultStore>) -> Result<Response, Infallible> {
+    let Some(info) = results.info(&id, caller.as_deref()) else {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    };
+    if info.status != ResultStatus::Done {
+        let status = match info.status {
+            ResultStatus::Running => StatusCode::ACCEPTED,
+            _ => StatusCode::CONFLICT,
+        };
+        return Ok(warp::reply::with_status(warp::reply::json(&info), status).into_response());
+    }
+
+    match tokio::fs::read(results.file_path(&id)).await {
+        Ok(bytes) => {
+            let reply = warp::reply::with_header(bytes, "content-type", "application/x-ndjson");
+            let disposition = format!("attachment; filename=\"{}.ndjson\"", id);
+            Ok(warp::reply::with_header(reply, "content-disposition", disposition).into_response())
+        }
+        Err(err) => {
+            error!("Failed to read result {}: {}", id, err);
+            Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response())
+        }
+    }
+}
+
+///Runs the map function and appends all rows it selects to a derived table
+#[tracing::instrument]
+async fn materialize_map_fn(
+    fn_name: String,
+    table: String,
+    query_params: HashMap<String, String>,
+    tx: Sender<Command>,
+    admission: Arc<QueryAdmission>,
+    caller: QueryCaller,
+    jobs: Arc<JobRegistry>,
+) -> Result<Response, Infallible> {
+    if let Some(response) = reject_invalid_table_name(&table) {
+        return Ok(response);
+    }
+
+    let filter = match QueryFilter::from_query(&query_params) {
+        Ok(filter) => filter,
+        Err(err) => {
+            let json = warp::reply::json(&err.to_string());
+            return Ok(warp::reply::with_status(json, StatusCode::BAD_REQUEST).into_response());
+        }
+    };
+
+    let description = format!("query {} into {}", fn_name, table);
+    let (id, handle) = jobs.spawn(JobKind::Materialize, description, async move {
+        let _permit = match admission.admit().await {
+            Ok(permit) => permit,
+            Err(queue_full) => return reject_query(&fn_name, queue_full),
+        };
+        match invoke_audited(&fn_name, None, filter, &tx, &caller, &query_params).await {
+            Ok(result) => {
+                caller.usage.record_query(result.scanned as u64);
+                append_rows(table, result, &tx).await
+            }
+            Err(response) => response,
+        }
+    });
+    Ok(handle.await.ok().flatten().unwrap_or_else(|| job_cancelled(&id)))
+}
+
+///Answer to a request whose job got cancelled while the request waited for it
+fn job_cancelled(id: &str) -> Response {
+    let json = warp::reply::json(&format!("Job {} was cancelled", id));
+    warp::reply::with_status(json, StatusCode::CONFLICT).into_response()
+}
+
+async fn append_rows(table: String, result: MapResult, tx: &Sender<Command>) -> Response {
+    let MapResult { rows, partial_errors, .. } = result;
+
+    let (resp_tx, resp_rx) = oneshot::channel();
+    if let Err(err) = tx
+        .send(Command::AppendRows {
+            table: table.to_string(),
+            rows,
+            responder: resp_tx,
+        })
+        .await
+    {
+        error!("Error while trying to write into table {}: {}", table, err);
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+
+    match resp_rx.await {
+        Ok(Ok(rows)) => warp::reply::json(&MaterializeReport {
+            table,
+            rows,
+            partial_errors,
+        })
+        .into_response(),
+        Ok(Err(err)) => {
+            let status = if err.is_client_error() {
+                StatusCode::CONFLICT
+            } else {
+                StatusCode::INTERNAL_SERVER_ERROR
+            };
+            let json = warp::reply::json(&InsertErrorBody::from(&err));
+            warp::reply::with_status(json, status).into_response()
+        }
+        Err(err) => {
+            error!("Failed to receive answer after writing into table {}: {}", table, err);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+///Unique values of a column, e.g. to offer them in a filter dropdown
+#[tracing::instrument]
+async fn distinct(
+    query_params: HashMap<String, String>,
+    tx: Sender<Command>,
+    admission: Arc<QueryAdmission>,
+    usage: RequestUsage,
+) -> Result<Response, Infallible> {
+    let bad_request = |message: String| {
+        let json = warp::reply::json(&message);
+        Ok(warp::reply::with_status(json, StatusCode::BAD_REQUEST).into_response())
+    };
+
+    let Some(column) = query_params.get("column").cloned() else {
+        return bad_request("Missing query parameter column".to_string());
+    };
+    let limit = match query_params.get("limit").map(|limit| limit.parse::<usize>()) {
+        None => DEFAULT_DISTINCT_LIMIT,
+        Some(Ok(limit)) => limit,
+        Some(Err(_)) => return bad_request("Invalid value for limit. Expected an unsigned integer".to_string()),
+    };
+    let cast = match query_params.get("cast").map(|cast| CastType::parse(cast)).transpose() {
+        Ok(cast) => cast,
+        Err(err) => return bad_request(err.to_string()),
+    };
+    let filter = match QueryFilter::from_query(&query_params) {
+        Ok(filter) => filter,
+        Err(err) => return bad_request(err.to_string()),
+    };
+
+    let _permit = match admission.admit().await {
+        Ok(permit) => permit,
+        Err(queue_full) => return Ok(reject_query("distinct", queue_full)),
+    };
+
+    let (resp_tx, resp_rx) = oneshot::channel();
+    if let Err(err) = tx
+        .send(Command::Distinct {
+            column,
+            limit,
+            filter,
+            cast,
+            responder: resp_tx,
+        })
+        .await
+    {
+        error!("Error while trying to collect distinct values: {}", err);
+        return Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response());
+    }
+
+    match resp_rx.await {
+        Ok(Ok(distinct_values)) => {
+            usage.record_query(distinct_values.scanned as u64);
+            Ok(warp::reply::json(&distinct_values).into_response())
+        }
+        Ok(Err(QueryError::Storage { source: ContainerError::WarmingUp })) => Ok(warming_up()),
+        Ok(Err(QueryError::InvalidFilter { source })) => bad_request(source.to_string()),
+        Ok(Err(err)) => bad_request(err.to_string()),
+        Err(err) => {
+            error!("Failed to receive distinct values: {}", err);
+            Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response())
+        }
+    }
+}
+
+///Wire formats of `GET /rows/stream`, picked by the Accept header
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum RowFormat {
+    ///One JSON object per line, the default
+    Ndjson,
+    ///See `storage::row_stream`
+    Binary,
+}
+
+impl RowFormat {
+    fn from_accept(accept: Option<&str>) -> Self {
+        match accept.is_some_and(|accept| accept.contains(row_stream::BINARY_ROWS_CONTENT_TYPE)) {
+            true => RowFormat::Binary,
+            false => RowFormat::Ndjson,
+        }
+    }
+
+    fn content_type(&self) -> &'static str {
+        match self {
+            RowFormat::Ndjson => "application/x-ndjson",
+            RowFormat::Binary => row_stream::BINARY_ROWS_CONTENT_TYPE,
+        }
+    }
+
+    fn header(&self, columns: &[String]) -> std::io::Result<Vec<u8>> {
+        match self {
+            RowFormat::Ndjson => Ok(vec![]),
+            RowFormat::Binary => row_stream::encode_header(columns),
+        }
+    }
+
+    fn encode_row(&self, columns: &[String], row: &ColumnFrame, out: &mut Vec<u8>) -> std::io::Result<()> {
+        match self {
+            RowFormat::Ndjson => {
+                let object = columns
+                    .iter()
+                    .map(|column| (column.as_str(), row.get(column).unwrap_or(&Cell::Null)))
+                    .collect::<HashMap<_, _>>();
+                serde_json::to_writer(&mut *out, &object)?;
+                out.push(b'\n');
+                Ok(())
+            }
+            RowFormat::Binary => row_stream::encode_row(columns, row, out),
+        }
+    }
+}
+
+///Streams all rows matching the filter, STREAM_CHUNK_ROWS at a time. Bulk consumers ask for the binary format
+///with `Accept: application/vnd.warenhaus.rows`, which skips turning every cell into JSON.
+#[tracing::instrument]
+async fn stream_rows(
+    query_params: HashMap<String, String>,
+    accept: Option<String>,
+    tx: Sender<Command>,
+    admission: Arc<QueryAdmission>,
+    usage: RequestUsage,
+) -> Result<Response, Infallible> {
+    let bad_request = |message: String| {
+        let json = warp::reply::json(&message);
+        Ok(warp::reply::with_status(json, StatusCode::BAD_REQUEST).into_response())
+    };
+    let filter = match QueryFilter::from_query(&query_params) {
+        Ok(filter) => filter,
+        Err(err) => return bad_request(err.to_string()),
+    };
+    let format = RowFormat::from_accept(accept.as_deref());
+    let with_lineage = query_params.get(LINEAGE_PARAM).is_some_and(|value| value == "true");
+
+    let _permit = match admission.admit().await {
+        Ok(permit) => permit,
+        Err(queue_full) => return Ok(reject_query("rows stream", queue_full)),
+    };
+
+    let (resp_tx, resp_rx) = oneshot::channel();
+    if let Err(err) = tx.send(Command::ScanRows { filter, responder: resp_tx }).await {
+        error!("Error while trying to scan rows: {}", err);
+        return Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response());
+    }
+
+    let scan = match resp_rx.await {
+        Ok(Ok(scan)) => scan,
+        Ok(Err(QueryError::Storage { source: ContainerError::WarmingUp })) => return Ok(warming_up()),
+        Ok(Err(err)) => return bad_request(err.to_string()),
+        Err(err) => {
+            error!("Failed to receive rows: {}", err);
+            return Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response());
+        }
+    };
+    usage.record_query(scan.rows.len() as u64);
+
+    let columns = scan
+        .columns
+        .into_iter()
+        .filter(|column| with_lineage || !Lineage::is_lineage_column(column))
+        .collect::<Vec<_>>();
+    let header = match format.header(&columns) {
+        Ok(header) => header,
+        Err(err) => {
+            error!("Failed to encode rows: {}", err);
+            return Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response());
+        }
+    };
+    //Rows get encoded while the body is sent, so the encoded stream never sits in memory as a whole
+    let mut rows = scan.rows.into_iter().peekable();
+    let chunks = std::iter::from_fn(move || {
+        rows.peek()?;
+        let mut chunk = vec![];
+        for row in rows.by_ref().take(STREAM_CHUNK_ROWS) {
+            if let Err(err) = format.encode_row(&columns, &row, &mut chunk) {
+                return Some(Err(err));
+            }
+        }
+        Some(Ok(chunk))
+    });
+    let body = warp::hyper::Body::wrap_stream(futures::stream::iter(std::iter::once(Ok(header)).chain(chunks)));
+    Ok(warp::reply::with_header(Response::new(body), "content-type", format.content_type()).into_response())
+}
+
+///Rows of the table, or those a map function returns if the request names one in `map_fn`, as a Parquet file
+#[tracing::instrument]
+async fn export_parquet(
+    query_params: HashMap<String, String>,
+    tx: Sender<Command>,
+    admission: Arc<QueryAdmission>,
+    caller: QueryCaller,
+) -> Result<Response, Infallible> {
+    let bad_request = |message: String| {
+        let json = warp::reply::json(&message);
+        Ok(warp::reply::with_status(json, StatusCode::BAD_REQUEST).into_response())
+    };
+    let filter = match QueryFilter::from_query(&query_params) {
+        Ok(filter) => filter,
+        Err(err) => return bad_request(err.to_string()),
+    };
+    let table = query_params.get(TABLE_PARAM).cloned();
+    if let Some(response) = table.as_deref().and_then(reject_invalid_table_name) {
+        return Ok(response);
+    }
+    let with_lineage = query_params.get(LINEAGE_PARAM).is_some_and(|value| value == "true");
+
+    let _permit = match admission.admit().await {
+        Ok(permit) => permit,
+        Err(queue_full) => return Ok(reject_query("parquet export", queue_full)),
+    };
+
+    let (columns, rows, partial_errors) = match query_params.get(MAP_FN_PARAM) {
+        Some(fn_name) => match invoke_audited(fn_name, table, filter, &tx, &caller, &query_params).await {
+            Ok(result) => {
+                caller.usage.record_query(result.scanned as u64);
+                //Map functions may return different columns for every row
+                let mut columns: Vec<String> = vec![];
+                for row in &result.rows {
+                    for column in row.column_names() {
+                        if !columns.contains(column) {
+                            columns.push(column.to_owned());
+                        }
+                    }
+                }
+                (columns, result.rows, result.partial_errors.len())
+            }
+            Err(response) => return Ok(response),
+        },
+        None if table.is_some() => return bad_request(format!("{} only applies to exports of a {}", TABLE_PARAM, MAP_FN_PARAM)),
+        None => {
+            let (resp_tx, resp_rx) = oneshot::channel();
+            if let Err(err) = tx.send(Command::ScanRows { filter, responder: resp_tx }).await {
+                error!("Error while trying to scan rows: {}", err);
+                return Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response());
+            }
+            match resp_rx.await {
+                Ok(Ok(scan)) => {
+                    caller.usage.record_query(scan.rows.len() as u64);
+                    (scan.columns, scan.rows, 0)
+                }
+                Ok(Err(QueryError::Storage { source: ContainerError::WarmingUp })) => return Ok(warming_up()),
+                Ok(Err(err)) => return bad_request(err.to_string()),
+                Err(err) => {
+                    error!("Failed to receive rows: {}", err);
+                    return Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response());
+                }
+            }
+        }
+    };
+
+    let columns = columns
+        .into_iter()
+        .filter(|column| with_lineage || !Lineage::is_lineage_column(column))
+        .collect::<Vec<_>>();
+    let mut writer = Some(ParquetWriter::new(&columns, &rows));
+    //Row groups get encoded while the body is sent, the footer follows the last one
+    let mut rows = rows.into_iter().peekable();
+    let chunks = std::iter::from_fn(move || {
+        if rows.peek().is_some() {
+            let row_group = rows.by_ref().take(PARQUET_ROW_GROUP_ROWS).collect::<Vec<_>>();
+            return writer.as_mut().map(|writer| writer.row_group(&row_group));
+        }
+        writer.take().map(ParquetWriter::finish)
+    });
+    let body = std::iter::once(PARQUET_MAGIC.to_vec()).chain(chunks).map(Ok::<_, Infallible>);
+    let reply = warp::reply::with_header(Response::new(warp::hyper::Body::wrap_stream(futures::stream::iter(body))), "content-type", PARQUET_CONTENT_TYPE);
+    let reply = warp::reply::with_header(reply, "content-disposition", "attachment; filename=\"export.parquet\"");
+    Ok(warp::reply::with_header(reply, PARTIAL_ERRORS_HEADER, partial_errors.to_string()).into_response())
+}
+
+#[derive(Debug, Serialize)]
+struct RowPageResponse {
+    rows: Vec<HashMap<String, Cell>>,
+    ///Passed as `cursor`, continues after this page. Also continues with rows appended later.
+    cursor: String,
+    has_more: bool,
+}
+
+///Page of the rows matching the filters, without running a map function. Continues where the page of
+///the cursor ended, see `PageCursor`.
+#[tracing::instrument]
+async fn page_rows(
+    query_params: HashMap<String, String>,
+    tx: Sender<Command>,
+    admission: Arc<QueryAdmission>,
+    usage: RequestUsage,
+) -> Result<Response, Infallible> {
+    let bad_request = |message: String| {
+        let json = warp::reply::json(&message);
+        Ok(warp::reply::with_status(json, StatusCode::BAD_REQUEST).into_response())
+    };
+    let limit = match query_params.get("limit").map(|limit| limit.parse::<usize>()) {
+        None => DEFAULT_RESULT_PAGE_SIZE,
+        Some(Ok(limit)) if limit > 0 => limit,
+        Some(_) => return bad_request("Invalid value for limit. Expected a positive integer".to_string()),
+    };
+    let cursor = match query_params.get(CURSOR_PARAM).map(|cursor| PageCursor::decode(cursor)).transpose() {
+        Ok(cursor) => cursor,
+        Err(err) => return bad_request(err.to_string()),
+    };
+    let filter = match QueryFilter::from_query(&query_params) {
+        Ok(filter) => filter,
+        Err(err) => return bad_request(err.to_string()),
+    };
+    let with_lineage = query_params.get(LINEAGE_PARAM).is_some_and(|value| value == "true");
+
+    let _permit = match admission.admit().await {
+        Ok(permit) => permit,
+        Err(queue_full) => return Ok(reject_query("rows page", queue_full)),
+    };
+
+    let (resp_tx, resp_rx) = oneshot::channel();
+    if let Err(err) = tx
+        .send(Command::PageRows {
+            filter,
+            cursor,
+            limit,
+            responder: resp_tx,
+        })
+        .await
+    {
+        error!("Error while trying to page rows: {}", err);
+        return Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response());
+    }
+
+    match resp_rx.await {
+        Ok(Ok(page)) => {
+            usage.record_query(page.rows.len() as u64);
+            Ok(warp::reply::json(&RowPageResponse {
+                rows: view_frames(&page.rows, with_lineage),
+                cursor: page.cursor.encode(),
+                has_more: page.has_more,
+            })
+            .into_response())
+        }
+        Ok(Err(QueryError::Storage { source: ContainerError::WarmingUp })) => Ok(warming_up()),
+        Ok(Err(QueryError::InvalidFilter { source: FilterError::ExpiredCursor })) => {
+            let json = warp::reply::json(&FilterError::ExpiredCursor.to_string());
+            Ok(warp::reply::with_status(json, StatusCode::GONE).into_response())
+        }
+        Ok(Err(err)) => bad_request(err.to_string()),
+        Err(err) => {
+            error!("Failed to receive page of rows: {}", err);
+            Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response())
+        }
+    }
+}
+
+///Rows holding the value in an indexed column, found without scanning the table
+async fn lookup(
+    query_params: HashMap<String, String>,
+    tx: Sender<Command>,
+    admission: Arc<QueryAdmission>,
+    usage: RequestUsage,
+) -> Result<Response, Infallible> {
+    let bad_request = |message: String| {
+        let json = warp::reply::json(&message);
+        Ok(warp::reply::with_status(json, StatusCode::BAD_REQUEST).into_response())
+    };
+    let (Some(column), Some(value)) = (query_params.get("column"), query_params.get("value")) else {
+        return bad_request("Expected the column and value parameters".to_string());
+    };
+    let with_lineage = query_params.get(LINEAGE_PARAM).is_some_and(|value| value == "true");
+
+    let _permit = match admission.admit().await {
+        Ok(permit) => permit,
+        Err(queue_full) => return Ok(reject_query("lookup", queue_full)),
+    };
+
+    let (resp_tx, resp_rx) = oneshot::channel();
+    if let Err(err) = tx
+        .send(Command::Lookup {
+            column: column.to_string(),
+            value: value.to_string(),
+            responder: resp_tx,
+        })
+        .await
+    {
+        error!("Error while trying to look up rows: {}", err);
+        return Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response());
+    }
+
+    match resp_rx.await {
+        Ok(Ok(rows)) => {
+            usage.record_query(rows.len() as u64);
+            Ok(warp::reply::json(&view_frames(&rows, with_lineage)).into_response())
+        }
+        Ok(Err(QueryError::Storage { source: ContainerError::WarmingUp })) => Ok(warming_up()),
+        Ok(Err(err)) => bad_request(err.to_string()),
+        Err(err) => {
+            error!("Failed to receive looked up rows: {}", err);
+            Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response())
+        }
+    }
+}
+
+///The row holding the value in the primary key column
+async fn find_by_key(
+    key: String,
+    query_params: HashMap<String, String>,
+    tx: Sender<Command>,
+    admission: Arc<QueryAdmission>,
+    usage: RequestUsage,
+) -> Result<Response, Infallible> {
+    let bad_request = |message: String| {
+        let json = warp::reply::json(&message);
+        Ok(warp::reply::with_status(json, StatusCode::BAD_REQUEST).into_response())
+    };
+    //Keys may hold characters which aren't allowed in a path, like the slashes of a URL
+    let Ok(key) = percent_decode_str(&key).decode_utf8() else {
+        return bad_request("Expected the key to be percent encoded UTF-8".to_string());
+    };
+    let with_lineage = query_params.get(LINEAGE_PARAM).is_some_and(|value| value == "true");
+
+    let _permit = match admission.admit().await {
+        Ok(permit) => permit,
+        Err(queue_full) => return Ok(reject_query("key lookup", queue_full)),
+    };
+
+    let (resp_tx, resp_rx) = oneshot::channel();
+    if let Err(err) = tx
+        .send(Command::FindByKey {
+            value: key.to_string(),
+            responder: resp_tx,
+        })
+        .await
+    {
+        error!("Error while trying to find row by key: {}", err);
+        return Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response());
+    }
+
+    match resp_rx.await {
+        Ok(Ok(Some(row))) => {
+            usage.record_query(1);
+            Ok(warp::reply::json(&view_frames(&[row], with_lineage).pop()).into_response())
+        }
+        Ok(Ok(None)) => Ok(StatusCode::NOT_FOUND.into_response()),
+        Ok(Err(QueryError::Storage { source: ContainerError::WarmingUp })) => Ok(warming_up()),
+        Ok(Err(err)) => bad_request(err.to_string()),
+        Err(err) => {
+            error!("Failed to receive row found by key: {}", err);
+            Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response())
+        }
+    }
+}
+
+fn reject_invalid_table_name(table: &str) -> Option<Response> {
+    if DerivedTables::is_valid_name(table) {
+        return None;
+    }
+    let json = warp::reply::json(&format!("Invalid table name: {}", table));
+    Some(warp::reply::with_status(json, StatusCode::BAD_REQUEST).into_response())
+}
+
+///Reply to reads arriving before all stored records are loaded
+fn warming_up() -> Response {
+    let json = warp::reply::json(&"Columns are still being loaded, see /startup/progress".to_string());
+    let reply = warp::reply::with_status(json, StatusCode::SERVICE_UNAVAILABLE);
+    warp::reply::with_header(reply, "Retry-After", "5").into_response()
+}
+
+///Inserts get turned away before the volume runs full, see `storage::disk_pressure`
+fn disk_pressure(err: &ContainerError) -> Response {
+    let (status, retry_after) = match err {
+        ContainerError::DiskFull(_) => (StatusCode::INSUFFICIENT_STORAGE, "30"),
+        _ => (StatusCode::SERVICE_UNAVAILABLE, "1"),
+    };
+    let reply = warp::reply::with_status(warp::reply::json(&InsertErrorBody::from(err)), status);
+    warp::reply::with_header(reply, "Retry-After", retry_after).into_response()
+}
+
+fn reject_query(fn_name: &str, queue_full: QueueFull) -> Response {
+    error!("Rejecting query {}: {} running, {} queued", fn_name, queue_full.running, queue_full.queued);
+    let reply = warp::reply::with_status(warp::reply::json(&queue_full), StatusCode::SERVICE_UNAVAILABLE);
+    warp::reply::with_header(reply, "Retry-After", "1").into_response()
+}
+
+///Runs the map function against the main table, or the given derived table
+///Runs the map function like `invoke_map` and records the execution in the audit log
+async fn invoke_audited(
+    fn_name: &str,
+    table: Option<String>,
+    filter: QueryFilter,
+    tx: &Sender<Command>,
+    caller: &QueryCaller,
+    query_params: &HashMap<String, String>,
+) -> Result<MapResult, Response> {
+    let started = Instant::now();
+    let result = invoke_map(fn_name, table, filter, tx).await;
+    //The fingerprint covers the whole result, including rows a response leaves out and lineage columns
+    let outcome = match &result {
+        Ok(result) => Ok(view_frames(&result.rows, true)),
+        Err(response) => Err(response.status().to_string()),
+    };
+    let outcome = outcome.as_deref().map_err(String::clone);
+    let entry = caller.audit.entry(fn_name, query_params, caller.usage.api_key(), started.elapsed(), outcome);
+    if let Err(err) = caller.audit.record(&entry) {
+        error!("Failed to record query {} in the audit log: {}", fn_name, err);
+    }
+    result
+}
+
+async fn invoke_map(
+    fn_name: &str,
+    table: Option<String>,
+    filter: QueryFilter,
+    tx: &Sender<Command>,
+) -> Result<MapResult, Response> {
+    let (resp_tx, resp_rx) = oneshot::channel();
+
+    if let Err(err) = tx
+        .send(Command::InvokeMap {
+            fn_name: fn_name.to_string(),
+            table,
+            filter,
+            responder: resp_tx,
+        })
+        .await
+    {
+        error!(
+            "Error while trying to execute map function {}: {}",
+            fn_name, err
+        );
+        let json = warp::reply::json(&"Internal Server Error".to_string());
+        return Err(warp::reply::with_status(
+            json,
+            StatusCode::INTERNAL_SERVER_ERROR,
+        ).into_response());
+    }
+
+    match resp_rx.await {
+        Ok(execution_result) => match execution_result {
+            Ok(rows) => Ok(rows),
+            Err(QueryError::InvalidFilter { source }) => {
+                let json = warp::reply::json(&source.to_string());
+                Err(warp::reply::with_status(json, StatusCode::BAD_REQUEST).into_response())
+            }
+            Err(QueryError::Storage { source: ContainerError::WarmingUp }) => Err(warming_up()),
+            Err(QueryError::UnknownTable(table)) => {
+                let json = warp::reply::json(&format!("Unknown table: {}", table));
+                Err(warp::reply::with_status(json, StatusCode::NOT_FOUND).into_response())
+            }
+            Err(QueryError::Wasm { source: err @ WasmError::MissingColumns(_) }) => {
+                let json = warp::reply::json(&err.to_string());
+                Err(warp::reply::with_status(json, StatusCode::CONFLICT).into_response())
+            }
+            Err(err) => {
+                error!("Failed to execute query: {}", err);
+                let json = warp::reply::json(&"Internal Server Error".to_string());
+                Err(warp::reply::with_status(
+                    json,
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                ).into_response())
             }
         },
         Err(recv_err) => {
             error!("Failed to receive execution result: {}", recv_err);
             let json = warp::reply::json(&"Internal Server Error".to_string());
-            return Ok(warp::reply::with_status(
+            Err(warp::reply::with_status(
                 json,
                 StatusCode::INTERNAL_SERVER_ERROR,
-            ));
+            ).into_response())
+        }
+    }
+}
+
+#[tracing::instrument]
+async fn metrics(tx: Sender<Command>, admission: Arc<QueryAdmission>, warnings: Arc<WarningCounters>) -> Result<Response, Infallible> {
+    let (resp_tx, resp_rx) = oneshot::channel();
+
+    if let Err(err) = tx.send(Command::Metrics { responder: resp_tx }).await {
+        error!("Error while trying to collect metrics: {}", err);
+        return Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response());
+    }
+
+    match resp_rx.await {
+        Ok(storage_metrics) => {
+            let mut body = storage_metrics.render();
+            body.push_str(&render_query_metrics(&admission));
+            body.push_str(&render_warning_metrics(&warnings));
+            Ok(body.into_response())
+        }
+        Err(err) => {
+            error!("Failed to receive metrics: {}", err);
+            Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response())
+        }
+    }
+}
+
+///Healthy as long as the storage layer answers
+#[tracing::instrument]
+async fn healthz(tx: Sender<Command>) -> Result<Response, Infallible> {
+    match committed_seq(&tx).await {
+        Some(_) => Ok("ok".into_response()),
+        None => Ok(StatusCode::SERVICE_UNAVAILABLE.into_response()),
+    }
+}
+
+#[tracing::instrument]
+async fn backfill(
+    query_params: HashMap<String, String>,
+    tx: Sender<Command>,
+    jobs: Arc<JobRegistry>,
+    schema_locks: Arc<SchemaLocks>,
+) -> Result<Response, Infallible> {
+    let description = "backfill computed columns";
+    let lock = match lock_schema(&schema_locks, &query_params, SchemaOperation::Backfill, description).await {
+        Ok(lock) => lock,
+        Err(response) => return Ok(response),
+    };
+    let (id, handle) = jobs.spawn(JobKind::Backfill, description.to_string(), async move {
+        let _lock = lock;
+        run_backfill(tx).await
+    });
+    Ok(handle.await.ok().flatten().unwrap_or_else(|| job_cancelled(&id)))
+}
+
+///Takes the schema lock for the operation, or waits for it with `wait=true`. Answers `409` if it's taken.
+async fn lock_schema(
+    schema_locks: &Arc<SchemaLocks>,
+    query_params: &HashMap<String, String>,
+    operation: SchemaOperation,
+    description: &str,
+) -> Result<SchemaLockGuard, Response> {
+    if query_params.get("wait").is_some_and(|wait| wait == "true") {
+        return Ok(schema_locks.acquire(operation, description).await);
+    }
+    schema_locks.try_acquire(operation, description).map_err(|err| {
+        let json = warp::reply::json(&err.to_string());
+        warp::reply::with_status(json, StatusCode::CONFLICT).into_response()
+    })
+}
+
+///Operation holding the schema lock and the ones waiting for it
+#[tracing::instrument]
+async fn schema_lock_state(schema_locks: Arc<SchemaLocks>) -> Result<impl warp::Reply, Infallible> {
+    Ok(warp::reply::json(&schema_locks.state()))
+}
+
+async fn run_backfill(tx: Sender<Command>) -> Response {
+    let (resp_tx, resp_rx) = oneshot::channel();
+
+    if let Err(err) = tx.send(Command::Backfill { responder: resp_tx }).await {
+        error!("Error while trying to start backfill: {}", err);
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+
+    match resp_rx.await {
+        Ok(Ok(report)) => warp::reply::json(&report).into_response(),
+        Ok(Err(ContainerError::WarmingUp)) => warming_up(),
+        Ok(Err(err)) => {
+            let status = if err.is_client_error() {
+                StatusCode::UNPROCESSABLE_ENTITY
+            } else {
+                StatusCode::INTERNAL_SERVER_ERROR
+            };
+            let json = warp::reply::json(&InsertErrorBody::from(&err));
+            warp::reply::with_status(json, status).into_response()
+        }
+        Err(err) => {
+            error!("Failed to receive backfill report: {}", err);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+///Compacts the column files right away, no matter how few rows got deleted
+async fn compact(
+    query_params: HashMap<String, String>,
+    tx: Sender<Command>,
+    jobs: Arc<JobRegistry>,
+    schema_locks: Arc<SchemaLocks>,
+) -> Result<Response, Infallible> {
+    let description = "compact column files";
+    let lock = match lock_schema(&schema_locks, &query_params, SchemaOperation::Compaction, description).await {
+        Ok(lock) => lock,
+        Err(response) => return Ok(response),
+    };
+    let (id, handle) = jobs.spawn(JobKind::Compaction, description.to_string(), async move {
+        let _lock = lock;
+        run_compaction(tx).await
+    });
+    Ok(handle.await.ok().flatten().unwrap_or_else(|| job_cancelled(&id)))
+}
+
+async fn run_compaction(tx: Sender<Command>) -> Response {
+    let (resp_tx, resp_rx) = oneshot::channel();
+
+    if let Err(err) = tx.send(Command::Compact { min_deleted_rows: 0, responder: resp_tx }).await {
+        error!("Error while trying to start compaction: {}", err);
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+
+    match resp_rx.await {
+        Ok(Ok(report)) => warp::reply::json(&report).into_response(),
+        Ok(Err(ContainerError::WarmingUp)) => warming_up(),
+        Ok(Err(err)) => {
+            let json = warp::reply::json(&InsertErrorBody::from(&err));
+            warp::reply::with_status(json, StatusCode::INTERNAL_SERVER_ERROR).into_response()
+        }
+        Err(err) => {
+            error!("Failed to receive compaction report: {}", err);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+#[tracing::instrument]
+async fn retention(tx: Sender<Command>, dry_run: bool) -> Result<Response, Infallible> {
+    let (resp_tx, resp_rx) = oneshot::channel();
+
+    if let Err(err) = tx.send(Command::Retention { dry_run, responder: resp_tx }).await {
+        error!("Error while trying to run retention: {}", err);
+        return Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response());
+    }
+
+    match resp_rx.await {
+        Ok(Ok(report)) => Ok(warp::reply::json(&report).into_response()),
+        Ok(Err(ContainerError::WarmingUp)) => Ok(warming_up()),
+        Ok(Err(err)) => {
+            let status = match err {
+                ContainerError::RetentionNotConfigured | ContainerError::MissingTimestampColumn => StatusCode::CONFLICT,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            let json = warp::reply::json(&InsertErrorBody::from(&err));
+            Ok(warp::reply::with_status(json, status).into_response())
+        }
+        Err(err) => {
+            error!("Failed to receive retention report: {}", err);
+            Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response())
+        }
+    }
+}
+
+#[tracing::instrument]
+async fn last_retention_report(tx: Sender<Command>) -> Result<Response, Infallible> {
+    let (resp_tx, resp_rx) = oneshot::channel();
+
+    if let Err(err) = tx.send(Command::LastRetentionReport { responder: resp_tx }).await {
+        error!("Error while trying to fetch retention report: {}", err);
+        return Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response());
+    }
+
+    match resp_rx.await {
+        Ok(Some(report)) => Ok(warp::reply::json(&report).into_response()),
+        Ok(None) => Ok(StatusCode::NOT_FOUND.into_response()),
+        Err(err) => {
+            error!("Failed to receive retention report: {}", err);
+            Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response())
+        }
+    }
+}
+
+///Scans the id column for symptoms of lost or repeated auto index updates.
+///With `repair` set, rows with duplicate ids get new ids, which requires maintenance mode.
+#[tracing::instrument]
+async fn diagnostics(tx: Sender<Command>, repair: bool) -> Result<Response, Infallible> {
+    let (resp_tx, resp_rx) = oneshot::channel();
+    let command = match repair {
+        true => Command::RepairIds { responder: resp_tx },
+        false => Command::Diagnostics { responder: resp_tx },
+    };
+
+    if let Err(err) = tx.send(command).await {
+        error!("Error while trying to run diagnostics: {}", err);
+        return Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response());
+    }
+
+    match resp_rx.await {
+        Ok(Ok(diagnostics)) => Ok(warp::reply::json(&diagnostics).into_response()),
+        Ok(Err(ContainerError::WarmingUp)) => Ok(warming_up()),
+        Ok(Err(err)) => {
+            let status = match err {
+                ContainerError::NotInMaintenanceMode => StatusCode::CONFLICT,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            let json = warp::reply::json(&InsertErrorBody::from(&err));
+            Ok(warp::reply::with_status(json, status).into_response())
+        }
+        Err(err) => {
+            error!("Failed to receive diagnostics: {}", err);
+            Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response())
+        }
+    }
+}
+
+///Copies the table files into a new snapshot directory, so operators can back up the table without stopping the server
+#[tracing::instrument]
+async fn snapshot(tx: Sender<Command>) -> Result<Response, Infallible> {
+    let (resp_tx, resp_rx) = oneshot::channel();
+
+    if let Err(err) = tx.send(Command::Snapshot { responder: resp_tx }).await {
+        error!("Error while trying to take a snapshot: {}", err);
+        return Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response());
+    }
+
+    match resp_rx.await {
+        Ok(Ok(path)) => {
+            let report = SnapshotReport { path: path.display().to_string() };
+            Ok(warp::reply::with_status(warp::reply::json(&report), StatusCode::CREATED).into_response())
+        }
+        Ok(Err(err)) => {
+            let json = warp::reply::json(&InsertErrorBody::from(&err));
+            Ok(warp::reply::with_status(json, StatusCode::INTERNAL_SERVER_ERROR).into_response())
+        }
+        Err(err) => {
+            error!("Failed to receive snapshot path: {}", err);
+            Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response())
+        }
+    }
+}
+
+///Count, null count and value range of every column
+#[tracing::instrument]
+async fn stats(tx: Sender<Command>) -> Result<Response, Infallible> {
+    let (resp_tx, resp_rx) = oneshot::channel();
+
+    if let Err(err) = tx.send(Command::Stats { responder: resp_tx }).await {
+        error!("Error while trying to fetch column statistics: {}", err);
+        return Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response());
+    }
+
+    match resp_rx.await {
+        Ok(Ok(stats)) => Ok(warp::reply::json(&stats).into_response()),
+        Ok(Err(ContainerError::WarmingUp)) => Ok(warming_up()),
+        Ok(Err(err)) => {
+            let json = warp::reply::json(&InsertErrorBody::from(&err));
+            Ok(warp::reply::with_status(json, StatusCode::INTERNAL_SERVER_ERROR).into_response())
+        }
+        Err(err) => {
+            error!("Failed to receive column statistics: {}", err);
+            Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response())
+        }
+    }
+}
+
+async fn fetch_schema(tx: Sender<Command>) -> Option<TableSchema> {
+    let (resp_tx, resp_rx) = oneshot::channel();
+
+    if let Err(err) = tx.send(Command::Schema { responder: resp_tx }).await {
+        error!("Error while trying to fetch schema: {}", err);
+        return None;
+    }
+
+    match resp_rx.await {
+        Ok(schema) => Some(schema),
+        Err(err) => {
+            error!("Failed to receive schema: {}", err);
+            None
+        }
+    }
+}
+
+///Columns of the main table, including inferred and system columns
+#[tracing::instrument]
+async fn schema(tx: Sender<Command>) -> Result<Response, Infallible> {
+    match fetch_schema(tx).await {
+        Some(schema) => Ok(warp::reply::json(&schema.columns).into_response()),
+        None => Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response()),
+    }
+}
+
+///Distribution and percentiles of a numeric column, e.g. `?percentiles=50,95,99.9`
+#[tracing::instrument]
+async fn histogram(
+    column: String,
+    query_params: HashMap<String, String>,
+    tx: Sender<Command>,
+) -> Result<Response, Infallible> {
+    let bad_request = |message: String| {
+        let json = warp::reply::json(&message);
+        Ok(warp::reply::with_status(json, StatusCode::BAD_REQUEST).into_response())
+    };
+    let percentiles = match query_params.get("percentiles") {
+        None => DEFAULT_PERCENTILES.to_vec(),
+        Some(percentiles) => match percentiles
+            .split(',')
+            .map(|percentile| percentile.trim().parse::<f64>().ok().filter(|percentile| (0.0..=100.0).contains(percentile)))
+            .collect::<Option<Vec<_>>>()
+        {
+            Some(percentiles) => percentiles,
+            None => return bad_request(format!("Invalid value for percentiles: {}. Expected numbers between 0 and 100", percentiles)),
+        },
+    };
+
+    let (resp_tx, resp_rx) = oneshot::channel();
+    let command = Command::Histogram {
+        column: column.clone(),
+        percentiles,
+        responder: resp_tx,
+    };
+    if let Err(err) = tx.send(command).await {
+        error!("Error while trying to fetch the histogram of column {}: {}", column, err);
+        return Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response());
+    }
+
+    match resp_rx.await {
+        Ok(Ok(histogram)) => Ok(warp::reply::json(&histogram).into_response()),
+        Ok(Err(ContainerError::WarmingUp)) => Ok(warming_up()),
+        Ok(Err(err)) => {
+            let status = match err {
+                ContainerError::UnknownColumn(_) => StatusCode::NOT_FOUND,
+                ContainerError::NoHistogram(_) => StatusCode::BAD_REQUEST,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            let json = warp::reply::json(&InsertErrorBody::from(&err));
+            Ok(warp::reply::with_status(json, status).into_response())
+        }
+        Err(err) => {
+            error!("Failed to receive the histogram of column {}: {}", column, err);
+            Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response())
+        }
+    }
+}
+
+///Description and tags of the main table, along with its columns
+#[tracing::instrument]
+async fn table_schema(tx: Sender<Command>) -> Result<Response, Infallible> {
+    match fetch_schema(tx).await {
+        Some(schema) => Ok(warp::reply::json(&schema).into_response()),
+        None => Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response()),
+    }
+}
+
+///Jobs of this node, oldest first
+#[tracing::instrument]
+async fn list_jobs(jobs: Arc<JobRegistry>) -> Result<impl warp::Reply, Infallible> {
+    Ok(warp::reply::json(&jobs.list()))
+}
+
+#[tracing::instrument]
+async fn job_info(id: String, jobs: Arc<JobRegistry>) -> Result<Response, Infallible> {
+    match jobs.info(&id) {
+        Some(job) => Ok(warp::reply::json(&job).into_response()),
+        None => Ok(StatusCode::NOT_FOUND.into_response()),
+    }
+}
+
+#[tracing::instrument]
+async fn cancel_job(id: String, jobs: Arc<JobRegistry>, results: Arc<ResultStore>) -> Result<Response, Infallible> {
+    match jobs.cancel(&id) {
+        Ok(job) => {
+            //Background queries share the id of their job
+            results.fail(&id, serde_json::Value::String(format!("Job {} was cancelled", id)));
+            Ok(warp::reply::json(&job).into_response())
+        }
+        Err(err) => {
+            let status = match err {
+                JobError::UnknownJob(_) => StatusCode::NOT_FOUND,
+                JobError::NotRunning(_) => StatusCode::CONFLICT,
+            };
+            Ok(warp::reply::with_status(warp::reply::json(&err.to_string()), status).into_response())
+        }
+    }
+}
+
+#[tracing::instrument]
+async fn list_alerts(alerts: Arc<AlertStore>) -> Result<impl warp::Reply, Infallible> {
+    Ok(warp::reply::json(&alerts.list()))
+}
+
+#[tracing::instrument]
+async fn alert_info(name: String, alerts: Arc<AlertStore>) -> Result<Response, Infallible> {
+    match alerts.info(&name) {
+        Some(alert) => Ok(warp::reply::json(&alert).into_response()),
+        None => Ok(StatusCode::NOT_FOUND.into_response()),
+    }
+}
+
+fn alert_error_response(err: AlertError) -> Response {
+    let status = match err {
+        AlertError::UnknownAlert(_) => StatusCode::NOT_FOUND,
+        AlertError::InvalidRule(_) => StatusCode::BAD_REQUEST,
+        AlertError::Io(ref err) => {
+            error!("Failed to store alert rules: {}", err);
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    };
+    warp::reply::with_status(warp::reply::json(&err.to_string()), status).into_response()
+}
+
+///Creates or replaces an alert rule
+#[tracing::instrument]
+async fn put_alert(name: String, rule: AlertRule, alerts: Arc<AlertStore>) -> Result<Response, Infallible> {
+    match alerts.put(&name, rule) {
+        Ok(()) => Ok(StatusCode::NO_CONTENT.into_response()),
+        Err(err) => Ok(alert_error_response(err)),
+    }
+}
+
+#[tracing::instrument]
+async fn delete_alert(name: String, alerts: Arc<AlertStore>) -> Result<Response, Infallible> {
+    match alerts.remove(&name) {
+        Ok(()) => Ok(StatusCode::NO_CONTENT.into_response()),
+        Err(err) => Ok(alert_error_response(err)),
+    }
+}
+
+///Renames a column of the main table, including its file
+#[tracing::instrument]
+async fn rename_column(
+    name: String,
+    query_params: HashMap<String, String>,
+    params: RenameColumnParams,
+    tx: Sender<Command>,
+    schema_locks: Arc<SchemaLocks>,
+) -> Result<Response, Infallible> {
+    let description = format!("rename column {} to {}", name, params.name);
+    let _lock = match lock_schema(&schema_locks, &query_params, SchemaOperation::RenameColumn, &description).await {
+        Ok(lock) => lock,
+        Err(response) => return Ok(response),
+    };
+    let (resp_tx, resp_rx) = oneshot::channel();
+
+    let command = Command::RenameColumn {
+        from: name.clone(),
+        to: params.name,
+        responder: resp_tx,
+    };
+    if let Err(err) = tx.send(command).await {
+        error!("Error while trying to rename column {}: {}", name, err);
+        return Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response());
+    }
+
+    match resp_rx.await {
+        Ok(Ok(())) => Ok(StatusCode::NO_CONTENT.into_response()),
+        Ok(Err(ContainerError::WarmingUp)) => Ok(warming_up()),
+        Ok(Err(err)) => {
+            let status = match err {
+                ContainerError::UnknownColumn(_) => StatusCode::NOT_FOUND,
+                ContainerError::InvalidRename { .. } => StatusCode::CONFLICT,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            let json = warp::reply::json(&InsertErrorBody::from(&err));
+            Ok(warp::reply::with_status(json, status).into_response())
+        }
+        Err(err) => {
+            error!("Failed to receive answer after renaming column {}: {}", name, err);
+            Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response())
+        }
+    }
+}
+
+///Drops a column of the main table, including its files
+#[tracing::instrument]
+async fn drop_column(
+    name: String,
+    query_params: HashMap<String, String>,
+    tx: Sender<Command>,
+    schema_locks: Arc<SchemaLocks>,
+) -> Result<Response, Infallible> {
+    let description = format!("drop column {}", name);
+    let _lock = match lock_schema(&schema_locks, &query_params, SchemaOperation::DropColumn, &description).await {
+        Ok(lock) => lock,
+        Err(response) => return Ok(response),
+    };
+    let (resp_tx, resp_rx) = oneshot::channel();
+
+    let command = Command::DropColumn {
+        name: name.clone(),
+        responder: resp_tx,
+    };
+    if let Err(err) = tx.send(command).await {
+        error!("Error while trying to drop column {}: {}", name, err);
+        return Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response());
+    }
+
+    match resp_rx.await {
+        Ok(Ok(())) => Ok(StatusCode::NO_CONTENT.into_response()),
+        Ok(Err(ContainerError::WarmingUp)) => Ok(warming_up()),
+        Ok(Err(err)) => {
+            let status = match err {
+                ContainerError::UnknownColumn(_) => StatusCode::NOT_FOUND,
+                ContainerError::InvalidDrop { .. } => StatusCode::CONFLICT,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            let json = warp::reply::json(&InsertErrorBody::from(&err));
+            Ok(warp::reply::with_status(json, status).into_response())
+        }
+        Err(err) => {
+            error!("Failed to receive answer after dropping column {}: {}", name, err);
+            Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response())
+        }
+    }
+}
+
+///How far loading the stored records got
+#[tracing::instrument]
+async fn startup_progress(startup: Arc<StartupTracker>) -> Result<impl warp::Reply, Infallible> {
+    Ok(warp::reply::json(&startup.progress()))
+}
+
+///Counters of every API key seen on this node
+#[tracing::instrument]
+async fn usage_report(usage: Arc<UsageTracker>) -> Result<impl warp::Reply, Infallible> {
+    Ok(warp::reply::json(&usage.snapshot()))
+}
+
+///Query executions recorded in the audit log, oldest first
+#[tracing::instrument]
+async fn audit_report(query: AuditQuery, audit: Arc<QueryAudit>) -> Result<Response, Infallible> {
+    match tokio::task::spawn_blocking(move || audit.entries(&query)).await {
+        Ok(Ok(entries)) => Ok(warp::reply::json(&entries).into_response()),
+        Ok(Err(err)) => {
+            error!("Failed to read the query audit log: {}", err);
+            Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response())
         }
+        Err(err) => {
+            error!("Reading the query audit log panicked: {}", err);
+            Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response())
+        }
+    }
+}
+
+#[tracing::instrument]
+async fn cluster_members(membership: Arc<Mutex<Membership>>) -> Result<impl warp::Reply, Infallible> {
+    let members = membership.lock().unwrap().members();
+    Ok(warp::reply::json(&members))
+}
+
+#[tracing::instrument]
+async fn cluster_gossip(
+    node_request: bool,
+    membership: Arc<Mutex<Membership>>,
+    digest: Vec<GossipEntry>,
+) -> Result<Response, Infallible> {
+    //Members receive inserts, so only nodes knowing the cluster secret may announce them
+    if !node_request {
+        let json = warp::reply::json(&"Gossip needs the cluster secret".to_string());
+        return Ok(warp::reply::with_status(json, StatusCode::FORBIDDEN).into_response());
     }
+    let mut membership = membership.lock().unwrap();
+    membership.merge(digest);
+    Ok(warp::reply::json(&membership.digest()).into_response())
 }
 
 #[tracing::instrument]
-pub async fn web_handler(tx: Sender<Command>) {
-    let root = warp::path::end().map(|| "root");
+///Binds the public and the admin listener. The returned future serves both until shutdown.
+///Binds the public listener and the admin listener, which serve until `shutdown` completes
+pub fn web_handler(
+    tx: Sender<Command>,
+    ingest_tx: Sender<Command>,
+    router: Arc<RwLock<ShardRouter>>,
+    membership: Arc<Mutex<Membership>>,
+    state: NodeState,
+    admin_addr: SocketAddr,
+    shutdown: Shutdown,
+) -> Result<impl Future<Output = ()>, warp::Error> {
+    let (endpoints, admin_endpoints) = endpoints(tx, ingest_tx, router, membership, state);
+    let mut public_shutdown = shutdown.clone();
+    let (_, public) = warp::serve(endpoints).try_bind_with_graceful_shutdown(([0, 0, 0, 0], 3030), async move { public_shutdown.wait().await })?;
+    let mut admin_shutdown = shutdown;
+    let (_, admin) = warp::serve(admin_endpoints).try_bind_with_graceful_shutdown(admin_addr, async move { admin_shutdown.wait().await })?;
+    Ok(async move {
+        tokio::join!(public, admin);
+    })
+}
+
+///The endpoints of the public listener and those of the admin listener
+fn endpoints(
+    tx: Sender<Command>,
+    ingest_tx: Sender<Command>,
+    router: Arc<RwLock<ShardRouter>>,
+    membership: Arc<Mutex<Membership>>,
+    state: NodeState,
+) -> (
+    impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone + Send + Sync + 'static,
+    impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone + Send + Sync + 'static,
+) {
+    let NodeState { role, usage, startup, results, jobs, schema_locks, sessions, audit, alerts, warnings, max_response_bytes, auth, cluster_secret, admission } = state;
+    let root = warp::path::end().map(|| "root".to_string());
     let log = warp::log("warenhaus");
+    //Inserts take the ingest lane of the storage layer, see `CommandLanes`
     let index_data = warp::path!("index")
-        .and(with_tx(tx.clone()))
+        .and(with_tx(ingest_tx.clone()))
+        .and(with_router(router.clone()))
+        .and(with_usage(usage.clone()))
+        .and(with_forwarding(cluster_secret.clone()))
+        .and(warp::header::optional::<String>(ACK_HEADER))
+        .and(with_lineage(cluster_secret.clone()))
         .and(warp::post())
         .and(warp::body::json())
         .and_then(index_handler);
 
+    let validate_data = warp::path!("index" / "validate")
+        .and(with_tx(tx.clone()))
+        .and(warp::post())
+        .and(warp::body::json())
+        .and_then(validate_handler);
+
+    let update_data = warp::path!("index" / i64)
+        .and(warp::put())
+        .and(with_tx(tx.clone()))
+        .and(with_router(router.clone()))
+        .and(warp::body::json())
+        .and_then(update_handler);
+
+    let delete_data = warp::path!("index" / i64)
+        .and(warp::delete())
+        .and(with_tx(tx.clone()))
+        .and(with_router(router.clone()))
+        .and_then(delete_handler);
+
+    let transaction = warp::path!("transaction")
+        .and(with_tx(ingest_tx.clone()))
+        .and(with_router(router.clone()))
+        .and(with_usage(usage.clone()))
+        .and(warp::header::optional::<String>(ACK_HEADER))
+        .and(with_lineage(cluster_secret.clone()))
+        .and(warp::post())
+        .and(warp::body::json())
+        .and_then(transaction_handler);
+
+    let start_session_handler = warp::path!("ingest" / "sessions")
+        .and(warp::post())
+        .and(with_sessions(sessions.clone()))
+        .and_then(start_session);
+
+    let session_info_handler = warp::path!("ingest" / "sessions" / String)
+        .and(warp::get())
+        .and(with_sessions(sessions.clone()))
+        .and_then(session_info);
+
+    let session_batch_handler = warp::path!("ingest" / "sessions" / String / "batches")
+        .and(warp::post())
+        .and(with_router(router.clone()))
+        .and(with_sessions(sessions.clone()))
+        .and(warp::body::json())
+        .and_then(append_session_batch);
+
+    let commit_session_handler = warp::path!("ingest" / "sessions" / String / "commit")
+        .and(warp::post())
+        .and(with_tx(ingest_tx))
+        .and(with_router(router.clone()))
+        .and(with_usage(usage.clone()))
+        .and(with_sessions(sessions.clone()))
+        .and(warp::header::optional::<String>(ACK_HEADER))
+        .and(with_lineage(cluster_secret.clone()))
+        .and_then(commit_session);
+
+    let abort_session_handler = warp::path!("ingest" / "sessions" / String)
+        .and(warp::delete())
+        .and(with_sessions(sessions))
+        .and_then(abort_session);
+
     let add_map_fn = warp::path!("add_map" / String)
         .and(warp::multipart::form().max_length(5_000_000))
         .and(with_tx(tx.clone()))
         .and(warp::post())
         .and_then(add_map_function);
 
+    let add_hook = warp::path!("add_hook" / String)
+        .and(warp::multipart::form().max_length(5_000_000))
+        .and(with_tx(tx.clone()))
+        .and(warp::post())
+        .and_then(add_map_function);
+
     let execute_map_fn_handler = warp::path!("query" / String)
         .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
         .and(with_tx(tx.clone()))
+        .and(with_min_seq(router.clone()))
+        .and(with_admission(admission.clone()))
+        .and(with_query_caller(usage.clone(), audit.clone(), warnings.clone(), auth.clone()))
+        .and(with_response_budget(max_response_bytes))
         .and_then(execute_map_fn);
 
+    let async_query_handler = warp::path!("query" / String)
+        .and(warp::get())
+        .and(with_async_query())
+        .and(with_tx(tx.clone()))
+        .and(with_admission(admission.clone()))
+        .and(with_query_caller(usage.clone(), audit.clone(), warnings.clone(), auth.clone()))
+        .and(with_results(results.clone()))
+        .and(with_jobs(jobs.clone()))
+        .and_then(start_async_query);
+
+    let query_result_handler = warp::path!("results" / String)
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(with_caller(auth.clone()))
+        .and(with_results(results.clone()))
+        .and(with_response_budget(max_response_bytes))
+        .and(with_warnings(warnings.clone()))
+        .and_then(query_result);
+
+    let query_result_file_handler = warp::path!("results" / String / "file")
+        .and(warp::get())
+        .and(with_caller(auth.clone()))
+        .and(with_results(results.clone()))
+        .and_then(query_result_file);
+
+    let materialize_map_fn_handler = warp::path!("query" / String / "into" / String)
+        .and(warp::post())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(with_tx(tx.clone()))
+        .and(with_admission(admission.clone()))
+        .and(with_query_caller(usage.clone(), audit.clone(), warnings.clone(), auth.clone()))
+        .and(with_jobs(jobs.clone()))
+        .and_then(materialize_map_fn);
+
+    let distinct_handler = warp::path!("distinct")
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(with_tx(tx.clone()))
+        .and(with_admission(admission.clone()))
+        .and(with_usage(usage.clone()))
+        .and_then(distinct);
+
+    let page_rows_handler = warp::path!("rows")
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(with_tx(tx.clone()))
+        .and(with_admission(admission.clone()))
+        .and(with_usage(usage.clone()))
+        .and_then(page_rows);
+
+    let lookup_handler = warp::path!("lookup")
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(with_tx(tx.clone()))
+        .and(with_admission(admission.clone()))
+        .and(with_usage(usage.clone()))
+        .and_then(lookup);
+
+    let find_by_key_handler = warp::path!("index" / "by-key" / String)
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(with_tx(tx.clone()))
+        .and(with_admission(admission.clone()))
+        .and(with_usage(usage.clone()))
+        .and_then(find_by_key);
+
+    let stream_rows_handler = warp::path!("rows" / "stream")
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(warp::header::optional::<String>("accept"))
+        .and(with_tx(tx.clone()))
+        .and(with_admission(admission.clone()))
+        .and(with_usage(usage.clone()))
+        .and_then(stream_rows);
+
+    let export_parquet_handler = warp::path!("export" / "parquet")
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(with_tx(tx.clone()))
+        .and(with_admission(admission.clone()))
+        .and(with_query_caller(usage.clone(), audit.clone(), warnings.clone(), auth.clone()))
+        .and_then(export_parquet);
+
+    let metrics_handler = warp::path!("metrics")
+        .and(warp::get())
+        .and(with_tx(tx.clone()))
+        .and(with_admission(admission))
+        .and(with_warnings(warnings))
+        .and_then(metrics);
+
+    let healthz_handler = warp::path!("healthz")
+        .and(warp::get())
+        .and(with_tx(tx.clone()))
+        .and_then(healthz);
+
+    let backfill_handler = warp::path!("admin" / "backfill")
+        .and(warp::post())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(with_tx(tx.clone()))
+        .and(with_jobs(jobs.clone()))
+        .and(with_schema_locks(schema_locks.clone()))
+        .and_then(backfill);
+
+    let compact_handler = warp::path!("admin" / "compact")
+        .and(warp::post())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(with_tx(tx.clone()))
+        .and(with_jobs(jobs.clone()))
+        .and(with_schema_locks(schema_locks.clone()))
+        .and_then(compact);
+
+    let schema_locks_handler = warp::path!("admin" / "locks")
+        .and(warp::get())
+        .and(with_schema_locks(schema_locks.clone()))
+        .and_then(schema_lock_state);
+
+    let retention_preview_handler = warp::path!("admin" / "retention" / "preview")
+        .and(warp::post())
+        .and(with_tx(tx.clone()))
+        .and(warp::any().map(|| true))
+        .and_then(retention);
+
+    let retention_report_handler = warp::path!("admin" / "retention" / "report")
+        .and(warp::get())
+        .and(with_tx(tx.clone()))
+        .and_then(last_retention_report);
+
+    let diagnostics_handler = warp::path!("admin" / "diagnostics")
+        .and(warp::get())
+        .and(with_tx(tx.clone()))
+        .and(warp::any().map(|| false))
+        .and_then(diagnostics);
+
+    let repair_ids_handler = warp::path!("admin" / "diagnostics" / "repair")
+        .and(warp::post())
+        .and(with_tx(tx.clone()))
+        .and(warp::any().map(|| true))
+        .and_then(diagnostics);
+
+    let snapshot_handler = warp::path!("admin" / "snapshot")
+        .and(warp::post())
+        .and(with_tx(tx.clone()))
+        .and_then(snapshot);
+
+    let rename_column_handler = warp::path!("schema" / "columns" / String / "rename")
+        .and(warp::post())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(warp::body::json())
+        .and(with_tx(tx.clone()))
+        .and(with_schema_locks(schema_locks.clone()))
+        .and_then(rename_column);
+
+    let drop_column_handler = warp::path!("schema" / "columns" / String)
+        .and(warp::delete())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(with_tx(tx.clone()))
+        .and(with_schema_locks(schema_locks))
+        .and_then(drop_column);
+
+    let list_jobs_handler = warp::path!("jobs")
+        .and(warp::get())
+        .and(with_jobs(jobs.clone()))
+        .and_then(list_jobs);
+
+    let job_info_handler = warp::path!("jobs" / String)
+        .and(warp::get())
+        .and(with_jobs(jobs.clone()))
+        .and_then(job_info);
+
+    let cancel_job_handler = warp::path!("jobs" / String)
+        .and(warp::delete())
+        .and(with_jobs(jobs))
+        .and(with_results(results))
+        .and_then(cancel_job);
+
+    let list_alerts_handler = warp::path!("alerts")
+        .and(warp::get())
+        .and(with_alerts(alerts.clone()))
+        .and_then(list_alerts);
+
+    let alert_info_handler = warp::path!("alerts" / String)
+        .and(warp::get())
+        .and(with_alerts(alerts.clone()))
+        .and_then(alert_info);
+
+    let put_alert_handler = warp::path!("alerts" / String)
+        .and(warp::put())
+        .and(warp::body::json())
+        .and(with_alerts(alerts.clone()))
+        .and_then(put_alert);
+
+    let delete_alert_handler = warp::path!("alerts" / String)
+        .and(warp::delete())
+        .and(with_alerts(alerts))
+        .and_then(delete_alert);
+
+    let sdk_handler = warp::path!("sdk" / "assemblyscript.ts")
+        .and(warp::get())
+        .map(|| warp::reply::with_header(ASSEMBLYSCRIPT_SDK.to_string(), "content-type", "text/plain; charset=utf-8"));
+
+    let schema_handler = warp::path!("schema")
+        .and(warp::get())
+        .and(with_tx(tx.clone()))
+        .and_then(schema);
+
+    let stats_handler = warp::path!("stats")
+        .and(warp::get())
+        .and(with_tx(tx.clone()))
+        .and_then(stats);
+
+    let histogram_handler = warp::path!("stats" / "columns" / String / "histogram")
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(with_tx(tx.clone()))
+        .and_then(histogram);
+
+    let table_schema_handler = warp::path!("schema" / "table")
+        .and(warp::get())
+        .and(with_tx(tx.clone()))
+        .and_then(table_schema);
+
+    let startup_progress_handler = warp::path!("startup" / "progress")
+        .and(warp::get())
+        .and(with_startup(startup))
+        .and_then(startup_progress);
+
+    let usage_handler = warp::path!("admin" / "usage")
+        .and(warp::get())
+        .and(with_usage_tracker(usage))
+        .and_then(usage_report);
+
+    let audit_handler = warp::path!("admin" / "audit")
+        .and(warp::get())
+        .and(warp::query::<AuditQuery>())
+        .and(with_audit(audit))
+        .and_then(audit_report);
+
+    let cluster_members_handler = warp::path!("cluster" / "members")
+        .and(warp::get())
+        .and(with_membership(membership.clone()))
+        .and_then(cluster_members);
+
+    let cluster_gossip_handler = warp::path!("cluster" / "gossip")
+        .and(warp::post())
+        .and(with_node_request(cluster_secret.clone()))
+        .and(with_membership(membership))
+        .and(warp::body::json())
+        .and_then(cluster_gossip);
+
     let endpoints = warp::any()
         .and(
-            root.or(add_map_fn)
+            reject_unauthenticated(auth, cluster_secret)
+                .or(root)
+                .or(reject_writes(role))
                 .or(index_data)
-                .or(execute_map_fn_handler),
+                .or(validate_data)
+                .or(update_data)
+                .or(delete_data)
+                .or(transaction)
+                .or(start_session_handler)
+                .or(session_info_handler)
+                .or(session_batch_handler)
+                .or(commit_session_handler)
+                .or(abort_session_handler)
+                .or(async_query_handler)
+                .or(execute_map_fn_handler)
+                .or(query_result_handler)
+                .or(query_result_file_handler)
+                .or(materialize_map_fn_handler)
+                .or(distinct_handler)
+                .or(stream_rows_handler)
+                .or(export_parquet_handler)
+                .or(page_rows_handler)
+                .or(lookup_handler)
+                .or(find_by_key_handler)
+                .or(schema_handler)
+                .or(table_schema_handler)
+                .or(stats_handler)
+                .or(histogram_handler)
+                .or(sdk_handler)
+                .or(startup_progress_handler)
+                .or(cluster_members_handler)
+                .or(cluster_gossip_handler),
         )
         .with(log);
 
-    warp::serve(endpoints).run(([0, 0, 0, 0], 3030)).await;
+    //Operations which change how rows get stored or which code runs on the server, or expose internals, are only reachable via the admin listener
+    let admin_endpoints = warp::any()
+        .and(
+            reject_writes(role)
+                .or(metrics_handler)
+                .or(healthz_handler)
+                .or(add_map_fn)
+                .or(add_hook)
+                .or(backfill_handler)
+                .or(compact_handler)
+                .or(retention_preview_handler)
+                .or(retention_report_handler)
+                .or(diagnostics_handler)
+                .or(repair_ids_handler)
+                .or(snapshot_handler)
+                .or(rename_column_handler)
+                .or(drop_column_handler)
+                .or(schema_locks_handler)
+                .or(usage_handler)
+                .or(audit_handler)
+                .or(list_jobs_handler)
+                .or(job_info_handler)
+                .or(cancel_job_handler)
+                .or(list_alerts_handler)
+                .or(alert_info_handler)
+                .or(put_alert_handler)
+                .or(delete_alert_handler),
+        )
+        .with(warp::log("warenhaus::admin"));
+
+    (endpoints, admin_endpoints)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use std::sync::{Arc, Mutex, RwLock};
+
+    use serde_json::json;
+    use warp::http::StatusCode;
+    use warp::{Filter, Reply};
+
+    use super::{
+        await_min_seq, endpoints, index_handler, query_result_file, with_caller, with_forwarding, with_lineage, with_min_seq,
+        with_results, with_router, with_tx, with_usage, NodeState, ResponseBudget, ACK_HEADER, FORWARDED_HEADER, MIN_SEQ_HEADER,
+        SEQ_HEADER,
+    };
+    use crate::alerts::AlertStore;
+    use crate::auth::{key_fingerprint, ApiKeyProvider, API_KEY_FINGERPRINT_HEADER, API_KEY_HEADER};
+    use crate::cluster::membership::Membership;
+    use crate::cluster::role::NodeRole;
+    use crate::cluster::secret::{ClusterSecret, CLUSTER_SECRET_HEADER};
+    use crate::cluster::shard_router::ShardRouter;
+    use crate::command::{AckMode, Command};
+    use crate::jobs::JobRegistry;
+    use crate::query::admission::QueryAdmission;
+    use crate::query_audit::QueryAudit;
+    use crate::results::ResultStore;
+    use crate::schema_locks::SchemaLocks;
+    use crate::sessions::IngestSessions;
+    use crate::storage::warmup::StartupTracker;
+    use crate::storage::ContainerError;
+    use crate::usage::UsageTracker;
+    use crate::warnings::WarningCounters;
+
+    #[test]
+    fn response_budget_leaves_out_rows_beyond_the_limit() {
+        let params = |pairs: &[(&str, &str)]| {
+            pairs.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect::<HashMap<_, _>>()
+        };
+        let budget = ResponseBudget::from_query(Some(100), &params(&[("max_response_bytes", "10")])).unwrap();
+        assert_eq!(budget.max_bytes, Some(10));
+        assert_eq!(ResponseBudget::from_query(Some(100), &params(&[("max_response_bytes", "1000")])).unwrap().max_bytes, Some(100));
+        assert!(ResponseBudget::from_query(None, &params(&[("cursor", "next")])).is_err());
+
+        //Every row takes 5 bytes including the comma
+        let rows = vec!["aa", "bb", "cc", "dd"];
+        assert_eq!(budget.take(rows.clone()), (vec!["aa", "bb"], Some(2)));
+        let budget = ResponseBudget { cursor: 2, ..budget };
+        assert_eq!(budget.take(rows.clone()), (vec!["cc", "dd"], None));
+        let budget = ResponseBudget { max_bytes: Some(1), cursor: 0 };
+        assert_eq!(budget.take(rows.clone()), (vec!["aa"], Some(1)));
+        assert_eq!(ResponseBudget::default().take(rows.clone()).0.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn reads_wait_for_the_min_seq_or_get_redirected_to_its_node() {
+        let (tx, mut rx) = tokio::sync::mpsc::channel(16);
+        //Storage which committed up to seq 5
+        tokio::spawn(async move {
+            while let Some(command) = rx.recv().await {
+                if let Command::CommittedSeq { responder } = command {
+                    responder.send(5).unwrap();
+                }
+            }
+        });
+        let router = Arc::new(RwLock::new(ShardRouter::new("http://node-a:3030".into(), None, vec![])));
+        let filter = warp::path!("query" / String)
+            .and(with_min_seq(router))
+            .and(with_tx(tx))
+            .then(|fn_name: String, min_seq, tx| async move {
+                await_min_seq(&fn_name, &tx, min_seq).await.unwrap_or_else(|| "served".into_response())
+            });
+        let read = |min_seq: Option<&str>, path: &str| {
+            let mut request = warp::test::request().path(path);
+            if let Some(min_seq) = min_seq {
+                request = request.header(MIN_SEQ_HEADER, min_seq);
+            }
+            request.reply(&filter)
+        };
+
+        assert_eq!(read(None, "/query/top").await.body(), "served");
+        assert_eq!(read(Some("5@http://node-a:3030"), "/query/top").await.body(), "served");
+        assert_eq!(read(Some("five"), "/query/top").await.status(), StatusCode::BAD_REQUEST);
+
+        let redirect = read(Some("9@http://node-b:3030"), "/query/top?table=views&limit=10").await;
+        assert_eq!(redirect.status(), StatusCode::TEMPORARY_REDIRECT);
+        assert_eq!(redirect.headers()["location"], "http://node-b:3030/query/top?table=views&limit=10");
+        let redirect = read(Some("9@http://node-b:3030"), "/query/top").await;
+        assert_eq!(redirect.headers()["location"], "http://node-b:3030/query/top");
+
+        let pending = read(Some("6@http://node-a:3030"), "/query/top").await;
+        assert_eq!(pending.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(pending.headers()["retry-after"], "1");
+    }
+
+    #[tokio::test]
+    async fn inserts_get_acknowledged_according_to_the_ack_header() {
+        let (tx, mut rx) = tokio::sync::mpsc::channel(16);
+        let acks = Arc::new(Mutex::new(vec![]));
+        let storage_acks = acks.clone();
+        //Storage which fails rows sent with ack=received, and stores all others at seq 7
+        tokio::spawn(async move {
+            while let Some(command) = rx.recv().await {
+                if let Command::Index { ack, responder, .. } = command {
+                    storage_acks.lock().unwrap().push(ack);
+                    let result = match ack {
+                        AckMode::Received => Err(ContainerError::RejectedByHook("no".into())),
+                        _ => Ok((7, vec![])),
+                    };
+                    let _ = responder.send(result);
+                }
+            }
+        });
+        let root = tempfile::tempdir().unwrap();
+        let router = Arc::new(RwLock::new(ShardRouter::new("http://node-a:3030".into(), None, vec![])));
+        let filter = warp::path!("index")
+            .and(with_tx(tx))
+            .and(with_router(router))
+            .and(with_usage(Arc::new(UsageTracker::new(root.path()))))
+            .and(with_forwarding(None))
+            .and(warp::header::optional::<String>(ACK_HEADER))
+            .and(with_lineage(None))
+            .and(warp::post())
+            .and(warp::body::json())
+            .and_then(index_handler);
+        let insert = |ack: Option<&str>| {
+            let mut request = warp::test::request()
+                .method("POST")
+                .path("/index")
+                .json(&json!({"fields": ["url"], "values": ["https://google.com"]}));
+            if let Some(ack) = ack {
+                request = request.header(ACK_HEADER, ack);
+            }
+            request.reply(&filter)
+        };
+
+        let received = insert(Some("received")).await;
+        assert_eq!(received.status(), StatusCode::ACCEPTED);
+        assert!(received.headers().get(SEQ_HEADER).is_none());
+        let durable = insert(Some("durable")).await;
+        assert_eq!(durable.status(), StatusCode::OK);
+        assert_eq!(durable.headers()[SEQ_HEADER], "7@http://node-a:3030");
+        assert_eq!(insert(None).await.status(), StatusCode::OK);
+        assert_eq!(insert(Some("eventually")).await.status(), StatusCode::BAD_REQUEST);
+
+        assert_eq!(*acks.lock().unwrap(), vec![AckMode::Received, AckMode::Durable, AckMode::Applied]);
+    }
+
+    #[tokio::test]
+    async fn only_nodes_knowing_the_cluster_secret_forward_inserts() {
+        let filter = with_forwarding(Some(ClusterSecret::new("s3cret".into())));
+        let forwarded = |secret: Option<&str>| {
+            let mut request = warp::test::request().header(FORWARDED_HEADER, "1");
+            if let Some(secret) = secret {
+                request = request.header(CLUSTER_SECRET_HEADER, secret);
+            }
+            request
+        };
+        assert!(forwarded(Some("s3cret")).filter(&filter).await.unwrap().forwarded);
+        assert!(!forwarded(Some("guess")).filter(&filter).await.unwrap().forwarded);
+        assert!(!forwarded(None).filter(&filter).await.unwrap().forwarded);
+        assert!(!forwarded(Some("s3cret")).filter(&with_forwarding(None)).await.unwrap().forwarded);
+    }
+
+    #[tokio::test]
+    async fn lineage_only_holds_fingerprints_of_api_keys() {
+        let filter = with_lineage(Some(ClusterSecret::new("s3cret".into())));
+        let lineage = warp::test::request().header(API_KEY_HEADER, "k3y").filter(&filter).await.unwrap();
+        assert_eq!(lineage.api_key, Some(key_fingerprint("k3y")));
+        //Only other nodes pass fingerprints on
+        let spoofed = warp::test::request().header(API_KEY_FINGERPRINT_HEADER, "sha256:0").filter(&filter).await.unwrap();
+        assert_eq!(spoofed.api_key, None);
+        let forwarded = warp::test::request()
+            .header(API_KEY_FINGERPRINT_HEADER, key_fingerprint("k3y"))
+            .header(CLUSTER_SECRET_HEADER, "s3cret")
+            .filter(&filter)
+            .await
+            .unwrap();
+        assert_eq!(forwarded.api_key, Some(key_fingerprint("k3y")));
+    }
+
+    #[tokio::test]
+    async fn results_are_only_served_to_the_caller_who_started_the_query() {
+        let dir = tempfile::tempdir().unwrap();
+        let results = Arc::new(ResultStore::new(dir.path()));
+        results.reset().unwrap();
+        results.start("q1", "query", Some(key_fingerprint("alice")));
+        results.finish("q1", &[json!({ "id": 1 })], json!([])).unwrap();
+
+        let auth = Some(Arc::new(ApiKeyProvider::new(vec!["alice".into(), "mallory".into()])) as _);
+        let filter = warp::path!("results" / String / "file")
+            .and(with_caller(auth))
+            .and(with_results(results))
+            .and_then(query_result_file);
+        let fetch = |api_key: &'static str| warp::test::request().path("/results/q1/file").header(API_KEY_HEADER, api_key).reply(&filter);
+        assert_eq!(fetch("alice").await.status(), StatusCode::OK);
+        assert_eq!(fetch("mallory").await.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn admin_endpoints_are_only_served_by_the_admin_listener() {
+        let root = tempfile::tempdir().unwrap();
+        let (tx, _rx) = tokio::sync::mpsc::channel(16);
+        let state = NodeState {
+            role: NodeRole::Primary,
+            usage: Arc::new(UsageTracker::new(root.path())),
+            startup: Arc::new(StartupTracker::new()),
+            results: Arc::new(ResultStore::new(root.path())),
+            jobs: Arc::new(JobRegistry::new(root.path())),
+            schema_locks: Arc::new(SchemaLocks::default()),
+            sessions: Arc::new(IngestSessions::new(root.path())),
+            audit: Arc::new(QueryAudit::new(root.path(), root.path())),
+            alerts: Arc::new(AlertStore::new(root.path())),
+            warnings: Arc::new(WarningCounters::default()),
+            max_response_bytes: None,
+            auth: None,
+            cluster_secret: None,
+            admission: Arc::new(QueryAdmission::new(1, 1)),
+        };
+        let router = Arc::new(RwLock::new(ShardRouter::new("http://node-a:3030".into(), None, vec![])));
+        let membership = Arc::new(Mutex::new(Membership::new("http://node-a:3030".into(), vec![])));
+        let (public, admin) = endpoints(tx.clone(), tx, router, membership, state);
+
+        let admin_routes = [
+            ("GET", "/metrics"),
+            ("GET", "/healthz"),
+            ("POST", "/add_map/query"),
+            ("POST", "/add_hook/extract_domain"),
+            ("POST", "/admin/backfill"),
+            ("POST", "/admin/compact"),
+            ("GET", "/admin/locks"),
+            ("GET", "/admin/usage"),
+            ("GET", "/jobs"),
+            ("GET", "/alerts"),
+            ("DELETE", "/schema/columns/url"),
+        ];
+        for (method, path) in admin_routes {
+            let response = warp::test::request().method(method).path(path).reply(&public).await;
+            assert_eq!(response.status(), StatusCode::NOT_FOUND, "{} {} is served publicly", method, path);
+        }
+
+        let locks = warp::test::request().path("/admin/locks").reply(&admin).await;
+        assert_eq!(locks.status(), StatusCode::OK);
+        //Gets past routing, but lacks the uploaded module
+        let upload = warp::test::request().method("POST").path("/add_map/query").reply(&admin).await;
+        assert_ne!(upload.status(), StatusCode::NOT_FOUND);
+        assert_eq!(warp::test::request().path("/").reply(&public).await.status(), StatusCode::OK);
+    }
 }